@@ -0,0 +1,272 @@
+//! Delivery acks: the receipt the server returns for each stored batch
+//! (see [`common::receipt`]), kept in `state_dir/acks/` as
+//! `<seq, 20 digits>.json`, or `e<epoch>-<seq>.json` past the first chain
+//! epoch, both 20 digits. A kept ack proves the server stored that batch at
+//! `issued_at_ms`, whatever it stores now.
+//!
+//! `--verify-acks` checks every kept ack against the server key history
+//! (`/server-keys`) and the batch the server now stores at its position, so a
+//! silent deletion or a rollback shows up as `missing` or `hash_differs`.
+//! The ack files are also what `cli fork-check --receipts-dir` reads.
+
+use anyhow::{Context, Result, anyhow};
+use common::batch::LogBatch;
+use common::hex::hex_decode_fixed;
+use common::receipt::{Receipt, ReceiptStanding};
+use ed25519_dalek::VerifyingKey;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub fn acks_dir(state_dir: &Path) -> PathBuf {
+    state_dir.join("acks")
+}
+
+/// Why `receipt` is not an ack for `batch`, if it is not.
+pub fn mismatch(receipt: &Receipt, batch: &LogBatch) -> Option<&'static str> {
+    if receipt.agent_id != batch.agent_id {
+        Some("agent_id")
+    } else if receipt.epoch != batch.epoch {
+        Some("epoch")
+    } else if receipt.seq != batch.seq {
+        Some("seq")
+    } else if receipt.hash != batch.compute_hash() {
+        Some("hash")
+    } else {
+        None
+    }
+}
+
+/// `<seq>.json`, or `e<epoch>-<seq>.json` past epoch 0, both 20 digits, so
+/// names sort in (epoch, seq) order. The spool names its files the same way.
+pub fn file_name(epoch: u64, seq: u64) -> String {
+    match epoch {
+        0 => format!("{seq:020}.json"),
+        epoch => format!("e{epoch:020}-{seq:020}.json"),
+    }
+}
+
+/// Writes `receipt` to the acks dir, through a temporary file so a crash
+/// never leaves half an ack. A resend's ack replaces the earlier copy,
+/// which the server issued identically.
+pub fn persist(state_dir: &Path, receipt: &Receipt) -> Result<()> {
+    let dir = acks_dir(state_dir);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(file_name(receipt.epoch, receipt.seq));
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(receipt)?)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// Every kept ack, in (epoch, seq) order.
+pub fn load(state_dir: &Path) -> Result<Vec<Receipt>> {
+    let dir = acks_dir(state_dir);
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .with_context(|| format!("cannot read {}", dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "json"));
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| {
+            let raw = fs::read(path)?;
+            serde_json::from_slice(&raw)
+                .with_context(|| format!("{} is not an ack", path.display()))
+        })
+        .collect()
+}
+
+/// A `/server-keys` entry.
+#[derive(Debug, Deserialize)]
+pub struct ServerKey {
+    pub id: i64,
+    pub public_key: String,
+    pub created_at_ms: i64,
+    pub retired_at_ms: Option<i64>,
+}
+
+/// The stored batch of a `/batches` row.
+#[derive(Deserialize)]
+struct StoredBatch {
+    batch: LogBatch,
+    hash: [u8; 32],
+}
+
+/// `intact`, `hash_differs` or `missing` for an ack the server signed with
+/// the key active at `issued_at_ms`; otherwise `unverifiable` and why.
+/// `stored_hash` is the hash the server stores at the ack's position.
+pub fn judge(
+    receipt: &Receipt,
+    keys: &[ServerKey],
+    stored_hash: Option<&[u8; 32]>,
+) -> (&'static str, Option<String>) {
+    let Some(key) = keys.iter().find(|key| key.id == receipt.key_id) else {
+        return (
+            "unverifiable",
+            Some(format!("unknown server key {}", receipt.key_id)),
+        );
+    };
+    let at = receipt.issued_at_ms as i64;
+    if at < key.created_at_ms || key.retired_at_ms.is_some_and(|retired| at > retired) {
+        return (
+            "unverifiable",
+            Some(format!("server key {} was not active at {at}", key.id)),
+        );
+    }
+    let verifying = hex_decode_fixed(&key.public_key)
+        .ok()
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok());
+    if !verifying.is_some_and(|verifying| receipt.verify(&verifying)) {
+        return (
+            "unverifiable",
+            Some(format!(
+                "signature does not verify with server key {}",
+                key.id
+            )),
+        );
+    }
+    (ReceiptStanding::of(receipt, stored_hash).as_str(), None)
+}
+
+async fn stored_hash(
+    client: &reqwest::Client,
+    server_url: &str,
+    receipt: &Receipt,
+) -> Result<Option<[u8; 32]>> {
+    let rows: Vec<StoredBatch> = client
+        .get(format!("{server_url}/batches"))
+        .query(&[
+            ("agent_id", receipt.agent_id.clone()),
+            ("epoch", receipt.epoch.to_string()),
+            ("since_seq", receipt.seq.to_string()),
+            ("limit", "1".to_string()),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(rows
+        .into_iter()
+        .find(|row| row.batch.position() == receipt.position())
+        .map(|row| row.hash))
+}
+
+/// `--verify-acks`: checks the acks kept in `state_dir` and prints one line
+/// per ack that is not intact. Returns whether all of them are.
+pub async fn verify(server_url: &str, state_dir: &Path) -> Result<bool> {
+    let acks = load(state_dir)?;
+    if acks.is_empty() {
+        return Err(anyhow!("no acks in {}", acks_dir(state_dir).display()));
+    }
+    let client = reqwest::Client::new();
+    let keys: Vec<ServerKey> = client
+        .get(format!("{server_url}/server-keys"))
+        .send()
+        .await?
+        .error_for_status()
+        .context("the server keeps no receipt keys")?
+        .json()
+        .await?;
+
+    let mut intact = 0;
+    for receipt in &acks {
+        let stored = stored_hash(&client, server_url, receipt).await?;
+        match judge(receipt, &keys, stored.as_ref()) {
+            ("intact", _) => intact += 1,
+            (status, Some(detail)) => println!("  ✗ {}: {status} ({detail})", label(receipt)),
+            (status, None) => println!("  ✗ {}: {status}", label(receipt)),
+        }
+    }
+    println!(
+        "Checked {} acks: {intact} intact, {} not",
+        acks.len(),
+        acks.len() - intact
+    );
+    Ok(intact == acks.len())
+}
+
+/// `seq N`, or `epoch E seq N` past the first epoch.
+fn label(receipt: &Receipt) -> String {
+    match receipt.epoch {
+        0 => format!("seq {}", receipt.seq),
+        epoch => format!("epoch {epoch} seq {}", receipt.seq),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::hex::hex_encode;
+    use common::testutil::{build_chain, start_epoch};
+    use ed25519_dalek::SigningKey;
+
+    #[test]
+    fn acks_are_kept_per_seq_and_judged_against_the_key_history() {
+        let agent_key = SigningKey::from_bytes(&[3; 32]);
+        let mut chain = build_chain(&agent_key, "agent-test", 3);
+        start_epoch(&mut chain, &agent_key);
+        let server_key = SigningKey::from_bytes(&[9; 32]);
+        let receipts: Vec<Receipt> = chain
+            .iter()
+            .zip(1..)
+            .map(|(batch, batch_id)| {
+                Receipt::issue(
+                    &server_key,
+                    1,
+                    batch_id,
+                    &batch.agent_id,
+                    batch.position(),
+                    batch.compute_hash(),
+                    1_000 + batch.seq,
+                )
+            })
+            .collect();
+        assert_eq!(mismatch(&receipts[0], &chain[0]), None);
+        assert_eq!(mismatch(&receipts[0], &chain[1]), Some("seq"));
+        assert_eq!(mismatch(&receipts[3], &chain[0]), Some("epoch"));
+        assert_eq!(label(&receipts[3]), "epoch 1 seq 1");
+
+        let state_dir = std::env::temp_dir().join(format!("agent-acks-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&state_dir);
+        for receipt in receipts.iter().rev() {
+            persist(&state_dir, receipt).unwrap();
+        }
+        // A resend's ack overwrites the first copy.
+        persist(&state_dir, &receipts[1]).unwrap();
+        // Epoch 1's seq 1 sorts after epoch 0's seq 3.
+        assert_eq!(load(&state_dir).unwrap(), receipts);
+        assert!(
+            acks_dir(&state_dir)
+                .join(format!("e{:020}-{:020}.json", 1, 1))
+                .exists()
+        );
+
+        let keys = [ServerKey {
+            id: 1,
+            public_key: hex_encode(server_key.verifying_key().as_bytes()),
+            created_at_ms: 0,
+            retired_at_ms: Some(1_002),
+        }];
+        let first = &receipts[0];
+        assert_eq!(judge(first, &keys, Some(&first.hash)), ("intact", None));
+        assert_eq!(judge(first, &keys, Some(&[0; 32])).0, "hash_differs");
+        assert_eq!(judge(first, &keys, None).0, "missing");
+        // Seq 3 was acked after key 1 was retired.
+        assert_eq!(
+            judge(&receipts[2], &keys, Some(&receipts[2].hash)),
+            (
+                "unverifiable",
+                Some("server key 1 was not active at 1003".into())
+            )
+        );
+        let forged = Receipt {
+            issued_at_ms: 1_000,
+            ..receipts[1].clone()
+        };
+        assert_eq!(judge(&forged, &keys, Some(&forged.hash)).0, "unverifiable");
+        let _ = fs::remove_dir_all(&state_dir);
+    }
+}
@@ -0,0 +1,98 @@
+//! `--backpressure drop|pause`: what the agent does with a batch that failed
+//! to send (retries exhausted or `--batch-timeout-ms` hit).
+//!
+//! - `drop` (default): the batch's lines are dropped and reading goes on, so
+//!   memory stays at one batch however long the server is down, but the
+//!   lines of every failed batch are lost.
+//! - `pause`: after `--pause-after-failures` (default 3) failed batches in a
+//!   row, the agent holds the failed batch and stops reading. It resends the
+//!   same batch with a growing wait, up to [`MAX_PROBE_WAIT`], until the
+//!   server takes it, then reads on. The source position is only committed
+//!   once a batch is sent, so a restart during the pause reads the held
+//!   lines again. Memory stays at one batch, and nothing past the pause
+//!   point is lost while the source keeps it (a rotated-away file does not).
+//!
+//! Pausing and resuming are logged as `[backpressure]` lines and shown by
+//! the `logchain_agent_paused` gauge.
+
+use crate::metrics::AgentMetrics;
+use std::fmt;
+use std::time::Instant;
+use tokio::time::Duration;
+
+pub const DEFAULT_PAUSE_AFTER: u32 = 3;
+/// The longest wait between resends of a held batch.
+pub const MAX_PROBE_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    #[default]
+    Drop,
+    Pause,
+}
+
+impl Policy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "drop" => Some(Self::Drop),
+            "pause" => Some(Self::Pause),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Drop => "drop",
+            Self::Pause => "pause",
+        })
+    }
+}
+
+/// Failed batches in a row, and since when reading is paused.
+#[derive(Debug, Default)]
+pub struct Backpressure {
+    failures: u32,
+    paused_since: Option<Instant>,
+}
+
+impl Backpressure {
+    /// Records a failed batch. True when it must be held and resent rather
+    /// than dropped; the first such failure pauses reading.
+    pub fn hold(&mut self, policy: Policy, pause_after: u32, metrics: &AgentMetrics) -> bool {
+        self.failures = self.failures.saturating_add(1);
+        if policy == Policy::Drop || self.failures < pause_after {
+            return false;
+        }
+        if self.paused_since.is_none() {
+            eprintln!(
+                "[backpressure] pausing reading after {} failed batches in a row; \
+                 holding the last one until the server takes it",
+                self.failures
+            );
+            self.paused_since = Some(Instant::now());
+            metrics.set_paused(true);
+        }
+        true
+    }
+
+    /// Records a sent batch, resuming reading if it was paused.
+    pub fn sent(&mut self, metrics: &AgentMetrics) {
+        self.failures = 0;
+        if let Some(since) = self.paused_since.take() {
+            println!(
+                "[backpressure] server took the held batch; resuming reading after {:?} paused",
+                since.elapsed()
+            );
+            metrics.set_paused(false);
+        }
+    }
+
+    /// The wait before resending a held batch: `retry_base_ms`, doubled for
+    /// each failure past the pause, up to [`MAX_PROBE_WAIT`].
+    pub fn probe_wait(&self, retry_base_ms: u64, pause_after: u32) -> Duration {
+        let past = self.failures.saturating_sub(pause_after).min(16);
+        Duration::from_millis(retry_base_ms.saturating_mul(1 << past)).min(MAX_PROBE_WAIT)
+    }
+}
@@ -0,0 +1,257 @@
+//! One line buffer per source, all feeding the agent's single chain.
+//!
+//! Each source has its own [`SourceConfig`]: lines its `include` / `exclude`
+//! patterns drop never reach a buffer, and a buffer is due once it holds
+//! `batch_size` lines or its oldest line has waited `flush_interval`. The
+//! agent sends one due buffer at a time as the next batch of the chain, so a
+//! batch only ever holds lines of one source, in the order they were read.
+//! When several buffers are due at once they go in config order, so the
+//! same input yields the same chain on every run.
+
+use crate::log_dir::glob_match;
+use crate::source::SourceSpec;
+use tokio::time::{Duration, Instant};
+
+/// A source's batching settings: its `[sources.<name>]` table layered over
+/// the top-level ones, or the top-level ones alone for the one source of an
+/// agent without tables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceConfig {
+    /// The table name; `default` for the source of an agent without tables.
+    pub name: String,
+    pub spec: SourceSpec,
+    pub batch_size: usize,
+    /// Send a batch that is not full once its oldest line is this old.
+    pub flush_interval: Option<Duration>,
+    /// Only lines matching this pattern (`*` and `?` wildcards) are shipped.
+    pub include: Option<String>,
+    /// Lines matching this pattern are not shipped.
+    pub exclude: Option<String>,
+}
+
+impl SourceConfig {
+    /// Whether `line` passes the source's filters.
+    pub fn keeps(&self, line: &str) -> bool {
+        self.include
+            .as_deref()
+            .is_none_or(|pattern| glob_match(pattern, line))
+            && !self
+                .exclude
+                .as_deref()
+                .is_some_and(|pattern| glob_match(pattern, line))
+    }
+}
+
+/// A `[sources.<name>]` table as configured; what it leaves unset comes
+/// from the top-level settings when [`SourceConfig`]s are resolved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceOverrides {
+    pub name: String,
+    pub spec: SourceSpec,
+    pub batch_size: Option<usize>,
+    /// `Some(0)` turns off a top-level flush interval for this source.
+    pub flush_interval_ms: Option<u64>,
+    pub include: Option<String>,
+    pub exclude: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Buffer {
+    lines: Vec<String>,
+    /// When the oldest buffered line was read.
+    since: Option<Instant>,
+    /// Lines the filters dropped since the last batch.
+    filtered: u64,
+}
+
+/// The per-source buffers, indexed like the `sources` every call is given;
+/// a config reload only changes the settings, never the sources.
+#[derive(Debug)]
+pub struct Batcher {
+    buffers: Vec<Buffer>,
+}
+
+impl Batcher {
+    pub fn new(sources: usize) -> Self {
+        Self {
+            buffers: (0..sources).map(|_| Buffer::default()).collect(),
+        }
+    }
+
+    /// Buffers `line`, read from `sources[source]` at `now`, unless the
+    /// source's filters drop it. Returns whether it was kept.
+    pub fn push(
+        &mut self,
+        sources: &[SourceConfig],
+        source: usize,
+        line: String,
+        now: Instant,
+    ) -> bool {
+        if !sources[source].keeps(&line) {
+            self.buffers[source].filtered += 1;
+            return false;
+        }
+        let buffer = &mut self.buffers[source];
+        buffer.since.get_or_insert(now);
+        buffer.lines.push(line);
+        true
+    }
+
+    /// The first source, in config order, whose buffer is full or has
+    /// waited out its flush interval at `now`.
+    pub fn due(&self, sources: &[SourceConfig], now: Instant) -> Option<usize> {
+        self.buffers
+            .iter()
+            .zip(sources)
+            .position(|(buffer, config)| {
+                buffer.lines.len() >= config.batch_size
+                    || buffer
+                        .since
+                        .zip(config.flush_interval)
+                        .is_some_and(|(since, interval)| now >= since + interval)
+            })
+    }
+
+    /// When the next buffer waits out its flush interval, if any can.
+    pub fn next_flush(&self, sources: &[SourceConfig]) -> Option<Instant> {
+        self.buffers
+            .iter()
+            .zip(sources)
+            .filter_map(|(buffer, config)| Some(buffer.since? + config.flush_interval?))
+            .min()
+    }
+
+    /// Empties `source`'s buffer for a batch. Also returns how many of its
+    /// lines the filters dropped since the last batch, which `--count-lines`
+    /// counts as read with this one.
+    pub fn take(&mut self, source: usize) -> (Vec<String>, u64) {
+        let buffer = &mut self.buffers[source];
+        buffer.since = None;
+        (
+            std::mem::take(&mut buffer.lines),
+            std::mem::take(&mut buffer.filtered),
+        )
+    }
+
+    /// Lines buffered for `source`.
+    pub fn depth(&self, source: usize) -> usize {
+        self.buffers[source].lines.len()
+    }
+
+    /// Lines buffered across all sources.
+    pub fn total(&self) -> usize {
+        self.buffers.iter().map(|buffer| buffer.lines.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn source(name: &str, batch_size: usize, flush_ms: Option<u64>) -> SourceConfig {
+        SourceConfig {
+            name: name.into(),
+            spec: SourceSpec::File(PathBuf::from(format!("{name}.log"))),
+            batch_size,
+            flush_interval: flush_ms.map(Duration::from_millis),
+            include: None,
+            exclude: None,
+        }
+    }
+
+    #[test]
+    fn filters_apply_before_a_line_is_buffered() {
+        let mut debug = source("debug", 10, None);
+        debug.include = Some("*app*".into());
+        debug.exclude = Some("*DEBUG*".into());
+        assert!(debug.keeps("app INFO started"));
+        assert!(!debug.keeps("app DEBUG cache hit"));
+        assert!(!debug.keeps("kernel INFO"));
+
+        let sources = [debug];
+        let mut batcher = Batcher::new(1);
+        let now = Instant::now();
+        assert!(!batcher.push(&sources, 0, "app DEBUG x".into(), now));
+        assert!(batcher.push(&sources, 0, "app INFO y".into(), now));
+        assert_eq!(batcher.depth(0), 1);
+        assert_eq!(batcher.take(0), (vec!["app INFO y".to_string()], 1));
+        assert_eq!(batcher.take(0), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn two_sources_with_different_flush_intervals_interleave_onto_one_chain() {
+        // The audit log ships small batches quickly; the debug log waits
+        // for big ones, or 50ms.
+        let sources = [source("audit", 2, Some(10)), source("debug", 100, Some(50))];
+        let mut batcher = Batcher::new(sources.len());
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        // (ms, source, line), as the readers hand them over.
+        let reads = [
+            (0, 1, "d1"),
+            (1, 0, "a1"),
+            (2, 1, "d2"),
+            (3, 0, "a2"),
+            (5, 0, "a3"),
+            (30, 1, "d3"),
+            (40, 0, "a4"),
+            (45, 0, "a5"),
+            (45, 0, "a6"),
+        ];
+
+        // The agent's loop: flush whatever is due, else take the next line
+        // or wait for the next flush, whichever comes first.
+        let mut chain: Vec<(u64, &str, Vec<String>)> = Vec::new();
+        let mut reads = reads.into_iter().peekable();
+        let mut now = start;
+        loop {
+            if let Some(index) = batcher.due(&sources, now) {
+                let seq = chain.len() as u64 + 1;
+                chain.push((seq, sources[index].name.as_str(), batcher.take(index).0));
+                continue;
+            }
+            let next_flush = batcher.next_flush(&sources);
+            match reads.peek() {
+                Some(&(ms, index, line)) if next_flush.is_none_or(|flush| at(ms) <= flush) => {
+                    reads.next();
+                    now = at(ms);
+                    batcher.push(&sources, index, line.into(), now);
+                }
+                _ => match next_flush {
+                    Some(flush) => now = flush,
+                    None => break,
+                },
+            }
+        }
+
+        let expected: Vec<(u64, &str, Vec<String>)> = [
+            (1, "audit", vec!["a1", "a2"]),
+            (2, "audit", vec!["a3"]),
+            (3, "audit", vec!["a4", "a5"]),
+            // 50ms after d1; a6 came later and waits out its own interval.
+            (4, "debug", vec!["d1", "d2", "d3"]),
+            (5, "audit", vec!["a6"]),
+        ]
+        .into_iter()
+        .map(|(seq, name, lines)| (seq, name, lines.into_iter().map(String::from).collect()))
+        .collect();
+        assert_eq!(chain, expected);
+        assert_eq!(batcher.total(), 0);
+    }
+
+    #[test]
+    fn buffers_due_together_go_in_config_order() {
+        let sources = [source("first", 1, None), source("second", 1, None)];
+        let mut batcher = Batcher::new(2);
+        let now = Instant::now();
+        batcher.push(&sources, 1, "b".into(), now);
+        batcher.push(&sources, 0, "a".into(), now);
+        assert_eq!(batcher.due(&sources, now), Some(0));
+        batcher.take(0);
+        assert_eq!(batcher.due(&sources, now), Some(1));
+        batcher.take(1);
+        assert_eq!(batcher.due(&sources, now), None);
+        assert_eq!(batcher.next_flush(&sources), None);
+    }
+}
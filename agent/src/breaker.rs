@@ -0,0 +1,202 @@
+//! `--breaker-threshold N`: a circuit breaker around the agent's calls to
+//! the server, the submits of [`crate::send_batch`] and the checkpoint
+//! fetch.
+//!
+//! Retries cover a blip; through a long outage they would keep every batch
+//! hammering a dead server. After N attempts in a row that failed (a
+//! network error or a rejection, anything that spends a retry) the circuit
+//! opens: for `--breaker-cooldown-ms` (default 30s) no attempt goes out, and
+//! a batch due meanwhile fails at once, so `--backpressure` drops or holds
+//! it without touching the network. Once the cooldown is over the next
+//! attempt is a probe, and the circuit is half-open until its answer. An
+//! answer from the server (accepted, deferred, or a seq clash) closes the
+//! circuit; a failure opens it for another cooldown. A probe that never
+//! answers (cut off by `--batch-timeout-ms`) lets another one go a cooldown
+//! later.
+//!
+//! Off by default (threshold 0). Transitions are logged as `[breaker]` lines
+//! and shown by the `logchain_agent_circuit_state` gauge.
+
+use crate::metrics::AgentMetrics;
+use std::fmt;
+use std::time::Instant;
+use tokio::time::Duration;
+
+pub const DEFAULT_COOLDOWN_MS: u64 = 30_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl State {
+    /// The value of `logchain_agent_circuit_state`.
+    pub fn gauge(self) -> u64 {
+        match self {
+            Self::Closed => 0,
+            Self::Open => 1,
+            Self::HalfOpen => 2,
+        }
+    }
+}
+
+/// An attempt the open circuit kept from going out.
+#[derive(Debug)]
+pub struct CircuitOpen {
+    /// How long until a probe may go.
+    pub retry_in: Duration,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "circuit open; the server is probed again in {:?}",
+            self.retry_in
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// Failed attempts in a row, and while open, when the next probe may go.
+#[derive(Debug, Default)]
+pub struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+    probing: bool,
+}
+
+impl Breaker {
+    pub fn state(&self) -> State {
+        match self.open_until {
+            None => State::Closed,
+            Some(_) if self.probing => State::HalfOpen,
+            Some(_) => State::Open,
+        }
+    }
+
+    /// Whether an attempt may go out now. Past the cooldown it may, as the
+    /// probe, and the attempts after it wait one more cooldown for its
+    /// answer.
+    pub fn admit(&mut self, cooldown: Duration, metrics: &AgentMetrics) -> Result<(), CircuitOpen> {
+        let Some(until) = self.open_until else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < until {
+            return Err(CircuitOpen {
+                retry_in: until - now,
+            });
+        }
+        println!("[breaker] cooldown over; probing the server");
+        self.open_until = Some(now + cooldown);
+        self.probing = true;
+        metrics.set_circuit(self.state());
+        Ok(())
+    }
+
+    /// Records an attempt the server answered, closing the circuit.
+    pub fn succeeded(&mut self, metrics: &AgentMetrics) {
+        self.failures = 0;
+        self.probing = false;
+        if self.open_until.take().is_some() {
+            println!("[breaker] the server answered the probe; closing the circuit");
+            metrics.set_circuit(self.state());
+        }
+    }
+
+    /// Records a failed attempt; the `threshold`th in a row, or a failed
+    /// probe, opens the circuit for `cooldown`. A threshold of 0 never does.
+    pub fn failed(&mut self, threshold: u32, cooldown: Duration, metrics: &AgentMetrics) {
+        if threshold == 0 {
+            return;
+        }
+        self.failures = self.failures.saturating_add(1);
+        if self.probing {
+            eprintln!("[breaker] the probe failed; open for another {cooldown:?}");
+        } else if self.open_until.is_none() && self.failures >= threshold {
+            eprintln!(
+                "[breaker] {} failed attempts in a row; opening the circuit for {cooldown:?}",
+                self.failures
+            );
+            metrics.circuit_opened();
+        } else {
+            return;
+        }
+        self.probing = false;
+        self.open_until = Some(Instant::now() + cooldown);
+        metrics.set_circuit(self.state());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_the_threshold_then_probes_before_closing() {
+        let metrics = AgentMetrics::default();
+        let cooldown = Duration::from_millis(40);
+        let mut breaker = Breaker::default();
+
+        breaker.failed(3, cooldown, &metrics);
+        breaker.failed(3, cooldown, &metrics);
+        assert_eq!(breaker.state(), State::Closed);
+        assert!(breaker.admit(cooldown, &metrics).is_ok());
+        breaker.failed(3, cooldown, &metrics);
+        assert_eq!(breaker.state(), State::Open);
+        let skipped = breaker.admit(cooldown, &metrics).unwrap_err();
+        assert!(skipped.retry_in <= cooldown);
+        assert!(
+            metrics
+                .render()
+                .contains("logchain_agent_circuit_state 1\n")
+        );
+
+        // Past the cooldown one probe goes; the next attempt waits for it.
+        std::thread::sleep(cooldown);
+        assert!(breaker.admit(cooldown, &metrics).is_ok());
+        assert_eq!(breaker.state(), State::HalfOpen);
+        assert!(breaker.admit(cooldown, &metrics).is_err());
+        breaker.failed(3, cooldown, &metrics);
+        assert_eq!(
+            breaker.state(),
+            State::Open,
+            "a failed probe reopens at once"
+        );
+        assert!(breaker.admit(cooldown, &metrics).is_err());
+
+        std::thread::sleep(cooldown);
+        assert!(breaker.admit(cooldown, &metrics).is_ok());
+        breaker.succeeded(&metrics);
+        assert_eq!(breaker.state(), State::Closed);
+        assert!(breaker.admit(cooldown, &metrics).is_ok());
+        let text = metrics.render();
+        assert!(text.contains("logchain_agent_circuit_state 0\n"), "{text}");
+        assert!(
+            text.contains("logchain_agent_circuit_opens_total 1\n"),
+            "{text}"
+        );
+
+        // Successes in between reset the count.
+        breaker.failed(3, cooldown, &metrics);
+        breaker.failed(3, cooldown, &metrics);
+        breaker.succeeded(&metrics);
+        breaker.failed(3, cooldown, &metrics);
+        assert_eq!(breaker.state(), State::Closed);
+    }
+
+    #[test]
+    fn a_zero_threshold_never_opens() {
+        let metrics = AgentMetrics::default();
+        let mut breaker = Breaker::default();
+        for _ in 0..100 {
+            breaker.failed(0, Duration::from_secs(60), &metrics);
+        }
+        assert_eq!(breaker.state(), State::Closed);
+        assert!(breaker.admit(Duration::from_secs(60), &metrics).is_ok());
+    }
+}
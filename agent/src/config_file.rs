@@ -0,0 +1,276 @@
+//! `--config <path>`: agent settings in a flat TOML file, one `key = value`
+//! per line. Values are strings (`"..."`), integers, floats or booleans;
+//! arrays are not supported. Keys are the long flag names with underscores,
+//! e.g. `batch_size = 20` for `--batch-size 20`.
+//!
+//! The one kind of table is `[sources.<name>]`: a further source, with the
+//! [`SOURCE_KEYS`] it sets for itself over the top-level values. Tables come
+//! after the top-level keys, as in TOML.
+
+use anyhow::{Context, Result, anyhow, bail};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Every key the file may set.
+pub const KEYS: &[&str] = &[
+    "log_path",
+    "log_dir",
+    "file_pattern",
+    "file_order",
+    "source",
+    "server_url",
+    "grpc_url",
+    "state_dir",
+    "max_retries",
+    "retry_base_ms",
+    "batch_timeout_ms",
+    "batch_size",
+    "flush_interval_ms",
+    "include",
+    "exclude",
+    "count_lines",
+    "allow_gap",
+    "max_line_bytes",
+    "max_bytes_per_sec",
+    "burst_bytes",
+    "max_batches_per_sec",
+    "burst_batches",
+    "spool",
+    "spool_encrypt",
+    "max_inflight",
+    "gzip_uploads",
+    "gzip_min_saving_pct",
+    "metrics_push_url",
+    "metrics_push_interval_secs",
+    "epoch_max_seq",
+    "epoch_max_age_secs",
+    "journal",
+    "journal_max_bytes",
+    "journal_max_age_secs",
+    "dead_letter_policy",
+    "backpressure",
+    "pause_after_failures",
+    "breaker_threshold",
+    "breaker_cooldown_ms",
+    "wire_format",
+    "batch_header",
+    "batch_version",
+    "skip_compat_check",
+];
+
+/// Every key a `[sources.<name>]` table may set; `source` is required.
+pub const SOURCE_KEYS: &[&str] = &[
+    "source",
+    "batch_size",
+    "flush_interval_ms",
+    "include",
+    "exclude",
+];
+
+#[derive(Debug, Default)]
+pub struct ConfigFile {
+    values: HashMap<String, String>,
+    sources: Vec<SourceTable>,
+}
+
+/// A `[sources.<name>]` table.
+#[derive(Debug)]
+pub struct SourceTable {
+    pub name: String,
+    values: HashMap<String, String>,
+}
+
+impl SourceTable {
+    /// As [`ConfigFile::get`].
+    pub fn get<T: FromStr>(&self, key: &str) -> Result<Option<T>> {
+        get(&self.values, key).with_context(|| format!("in [sources.{}]", self.name))
+    }
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("in config {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut values = HashMap::new();
+        let mut sources: Vec<SourceTable> = Vec::new();
+        for (index, raw) in text.lines().enumerate() {
+            let line_no = index + 1;
+            let line = strip_comment(raw).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                let name = line
+                    .strip_prefix("[sources.")
+                    .and_then(|rest| rest.strip_suffix(']'))
+                    .filter(|name| is_table_name(name))
+                    .ok_or_else(|| {
+                        anyhow!("line {line_no}: tables are not supported, except [sources.<name>]")
+                    })?;
+                if sources.iter().any(|table| table.name == name) {
+                    bail!("line {line_no}: [sources.{name}] is defined twice");
+                }
+                sources.push(SourceTable {
+                    name: name.to_string(),
+                    values: HashMap::new(),
+                });
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("line {line_no}: expected key = value"))?;
+            let key = key.trim();
+            let (keys, values) = match sources.last_mut() {
+                Some(table) => (SOURCE_KEYS, &mut table.values),
+                None => (KEYS, &mut values),
+            };
+            if !keys.contains(&key) {
+                match sources.last() {
+                    Some(table) if KEYS.contains(&key) => {
+                        bail!(
+                            "line {line_no}: {key} cannot be set in [sources.{}]",
+                            table.name
+                        )
+                    }
+                    _ => bail!("line {line_no}: unknown key '{key}'"),
+                }
+            }
+            let value = unquote(value.trim())
+                .ok_or_else(|| anyhow!("line {line_no}: unterminated string for {key}"))?;
+            if values.insert(key.to_string(), value).is_some() {
+                bail!("line {line_no}: {key} is set twice");
+            }
+        }
+        if let Some(table) = sources
+            .iter()
+            .find(|table| !table.values.contains_key("source"))
+        {
+            bail!("[sources.{}] has no source", table.name);
+        }
+        Ok(Self { values, sources })
+    }
+
+    /// The value of `key`, if set; an unparsable value is an error rather
+    /// than silently falling back to the default.
+    pub fn get<T: FromStr>(&self, key: &str) -> Result<Option<T>> {
+        get(&self.values, key)
+    }
+
+    /// The `[sources.<name>]` tables, in file order.
+    pub fn sources(&self) -> &[SourceTable] {
+        &self.sources
+    }
+}
+
+fn get<T: FromStr>(values: &HashMap<String, String>, key: &str) -> Result<Option<T>> {
+    values
+        .get(key)
+        .map(|v| {
+            v.parse()
+                .map_err(|_| anyhow!("invalid value '{v}' for {key}"))
+        })
+        .transpose()
+}
+
+/// Table names end up in log lines and metric labels, so they stay plain.
+fn is_table_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Drops a `#` comment that is not inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn unquote(value: &str) -> Option<String> {
+    match value.strip_prefix('"') {
+        Some(rest) => rest.strip_suffix('"').map(str::to_string),
+        None => Some(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_flat_keys_with_comments() {
+        let file = ConfigFile::parse(
+            "# agent\nserver_url = \"http://logs:3000/#x\" # trailing\nbatch_size = 20\n\ncount_lines = true\nmax_batches_per_sec = 2.5\n",
+        )
+        .unwrap();
+        assert_eq!(
+            file.get::<String>("server_url").unwrap().as_deref(),
+            Some("http://logs:3000/#x")
+        );
+        assert_eq!(file.get::<usize>("batch_size").unwrap(), Some(20));
+        assert_eq!(file.get::<bool>("count_lines").unwrap(), Some(true));
+        assert_eq!(file.get::<f64>("max_batches_per_sec").unwrap(), Some(2.5));
+        assert_eq!(file.get::<u64>("burst_bytes").unwrap(), None);
+        assert!(file.get::<usize>("server_url").is_err());
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_apply() {
+        for (text, expected) in [
+            ("batch_sise = 3", "unknown key 'batch_sise'"),
+            ("[source]", "tables are not supported"),
+            ("batch_size", "expected key = value"),
+            ("source = \"exec:tail", "unterminated string"),
+            ("batch_size = 1\nbatch_size = 2", "set twice"),
+            ("[sources.a b]", "tables are not supported"),
+            (
+                "[sources.a]\nserver_url = \"x\"",
+                "server_url cannot be set in [sources.a]",
+            ),
+            ("[sources.a]\nbatch_size = 2", "[sources.a] has no source"),
+            ("[sources.a]\nsource = \"x\"\n[sources.a]", "defined twice"),
+        ] {
+            let err = ConfigFile::parse(text).unwrap_err().to_string();
+            assert!(err.contains(expected), "{text}: {err}");
+        }
+    }
+
+    #[test]
+    fn source_tables_follow_the_top_level_keys() {
+        let file = ConfigFile::parse(
+            "batch_size = 50\n\n[sources.audit]\nsource = \"/var/log/audit.log\"\nbatch_size = 2\n\n[sources.debug]\nsource = \"exec:journalctl -f\"\nexclude = \"*DEBUG*\"\n",
+        )
+        .unwrap();
+        assert_eq!(file.get::<usize>("batch_size").unwrap(), Some(50));
+        let names: Vec<_> = file
+            .sources()
+            .iter()
+            .map(|table| table.name.as_str())
+            .collect();
+        assert_eq!(names, ["audit", "debug"]);
+        assert_eq!(
+            file.sources()[0].get::<usize>("batch_size").unwrap(),
+            Some(2)
+        );
+        assert_eq!(file.sources()[1].get::<usize>("batch_size").unwrap(), None);
+        assert_eq!(
+            file.sources()[1]
+                .get::<String>("exclude")
+                .unwrap()
+                .as_deref(),
+            Some("*DEBUG*")
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Dead letters: batches the server rejected for good. A rejection whose
+//! `code` (see [`permanent`]) says no resend can fix it ends the send at
+//! once instead of spending the retries, or being held under `--backpressure
+//! pause` forever. The batch is kept in `state_dir/deadletter/`, named like
+//! the acks (see [`crate::acks::file_name`]), with the code and the server's
+//! reason, for an operator to inspect.
+//!
+//! `--dead-letter-policy` says what happens next: `continue` (the default)
+//! moves on, so the next batch takes the dead-lettered one's place in the
+//! chain and its lines live on only in the dead letter; `halt` stops the
+//! agent, for chains where a hole needs a human first.
+
+use crate::acks;
+use anyhow::Result;
+use common::batch::LogBatch;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Continue,
+    Halt,
+}
+
+impl Policy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "continue" => Some(Policy::Continue),
+            "halt" => Some(Policy::Halt),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a rejection with `code` is one resending the same batch cannot
+/// fix: a batch that is malformed, badly signed or of a version the server
+/// refuses, or one that does not fit the stored chain. A seq conflict is
+/// not, since the agent's position may catch up; nor is anything about
+/// tokens, limits, maintenance or the server's own faults. Without a code
+/// (an older server) a rejection is retried as before.
+pub fn permanent(code: Option<&str>) -> bool {
+    matches!(
+        code,
+        Some(
+            "invalid_signature"
+                | "malformed"
+                | "malformed_kind"
+                | "unsupported_version"
+                | "reserved_agent_id"
+                | "prev_hash_mismatch"
+                | "accumulator_mismatch"
+                | "epoch_mismatch"
+                | "gap_refused"
+                | "chain_closed"
+        )
+    )
+}
+
+pub fn deadletter_dir(state_dir: &Path) -> PathBuf {
+    state_dir.join("deadletter")
+}
+
+/// A dead-lettered batch as kept on disk.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub dead_lettered_at_ms: u64,
+    pub code: String,
+    /// The status and message the server answered with.
+    pub reason: String,
+    pub batch: LogBatch,
+}
+
+/// Writes `letter` through a temporary file and returns where it went. A
+/// later batch at the same position (under `continue`) replaces it.
+pub fn persist(state_dir: &Path, letter: &DeadLetter) -> Result<PathBuf> {
+    let dir = deadletter_dir(state_dir);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(acks::file_name(letter.batch.epoch, letter.batch.seq));
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(letter)?)?;
+    fs::rename(&tmp, &path)?;
+    Ok(path)
+}
+
+/// The error a send ends with once its batch is dead-lettered.
+#[derive(Debug)]
+pub struct DeadLettered {
+    pub seq: u64,
+    pub code: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for DeadLettered {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "batch seq {} was rejected for good ({}: {}) and dead-lettered",
+            self.seq, self.code, self.reason
+        )
+    }
+}
+
+impl std::error::Error for DeadLettered {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_rejections_no_resend_can_fix_are_permanent() {
+        for code in [
+            "invalid_signature",
+            "malformed",
+            "prev_hash_mismatch",
+            "chain_closed",
+            "unsupported_version",
+        ] {
+            assert!(permanent(Some(code)), "{code}");
+        }
+        for code in [
+            "seq_conflict",
+            "forbidden",
+            "denied",
+            "rate_limited",
+            "maintenance",
+            "rollback_suspected",
+            "storage_full",
+            "internal",
+            "a_code_from_a_newer_server",
+        ] {
+            assert!(!permanent(Some(code)), "{code}");
+        }
+        assert!(!permanent(None));
+        assert_eq!(Policy::parse("halt"), Some(Policy::Halt));
+        assert_eq!(Policy::parse("skip"), None);
+    }
+}
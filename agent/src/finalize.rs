@@ -0,0 +1,134 @@
+//! `--finalize --reason <text> --confirm`: closes this agent's chain for
+//! good, e.g. when its host is decommissioned.
+//!
+//! The agent syncs with the server's checkpoint and sends one last signed
+//! batch at the next position, a [`BatchKind::Closed`] carrying the reason.
+//! The server refuses anything after it unless `ACCEPT_AFTER_CLOSE` is set,
+//! and `cli verify` reports the chain as closed instead of just ending. The
+//! close is also written to `state_dir/closed.json`; the agent will not run
+//! from that state dir again.
+
+use crate::{
+    AgentConfig, AgentMetrics, Breaker, Checkpoint, fetch_checkpoint, kind_batch, load_epoch,
+    load_key, load_lines_read, persist_accumulator, persist_epoch, persist_prev_hash, persist_seq,
+    send_batch,
+};
+use anyhow::{Context, Result, bail};
+use chrono::Utc;
+use common::batch::BatchKind;
+use common::hex::hex_encode;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What `closed.json` records about the close.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Closed {
+    pub epoch: u64,
+    pub seq: u64,
+    pub hash: String,
+    pub reason: String,
+    pub closed_at_ms: u64,
+}
+
+fn closed_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("closed.json")
+}
+
+/// The close recorded in `state_dir`, if the chain was closed from it.
+pub fn load(state_dir: &Path) -> Result<Option<Closed>> {
+    let path = closed_path(state_dir);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let raw = fs::read(&path).with_context(|| format!("cannot read {}", path.display()))?;
+    Ok(Some(serde_json::from_slice(&raw).with_context(|| {
+        format!("cannot parse {}", path.display())
+    })?))
+}
+
+/// Refuses to go on with a state dir whose chain was closed.
+pub fn check_open(state_dir: &Path) -> Result<()> {
+    if let Some(closed) = load(state_dir)? {
+        bail!(
+            "this agent's chain was closed at epoch {} seq {} ({}); \
+             start a new agent with its own state dir instead",
+            closed.epoch,
+            closed.seq,
+            closed.reason
+        );
+    }
+    Ok(())
+}
+
+pub async fn run(config: &AgentConfig, reason: Option<&str>, confirmed: bool) -> Result<()> {
+    let Some(reason) = reason.filter(|reason| !reason.trim().is_empty()) else {
+        bail!("--finalize needs --reason <text>, which the closing batch records");
+    };
+    if !confirmed {
+        bail!(
+            "--finalize ends this agent's chain on {} for good; \
+             run it again with --confirm",
+            config.server_url
+        );
+    }
+    check_open(&config.state_dir)?;
+    let key = load_key(config)?;
+    let mut breaker = Breaker::default();
+    let metrics = AgentMetrics::default();
+    let checkpoint = fetch_checkpoint(config, &mut breaker, &metrics, &config.agent_id)
+        .await
+        .context("cannot read the server checkpoint")?;
+    let (local_epoch, mut epoch_started_ms) = load_epoch(config)?;
+    let head = checkpoint.unwrap_or(Checkpoint {
+        agent_id: config.agent_id.clone(),
+        last_seq: 0,
+        last_hash: [0u8; 32],
+        count: 0,
+        last_accumulator: None,
+        last_epoch: 0,
+    });
+    if head.last_epoch != local_epoch {
+        epoch_started_ms = Utc::now().timestamp_millis() as u64;
+    }
+    let kind = BatchKind::Closed {
+        reason: reason.to_string(),
+    };
+    let close = kind_batch(
+        config,
+        &key,
+        &head,
+        epoch_started_ms,
+        load_lines_read(config)?,
+        kind,
+    );
+
+    let mut throttle = config.throttle();
+    send_batch(config, &mut throttle, &mut breaker, &metrics, &close)
+        .await
+        .context("the closing batch was not accepted; the chain is still open")?;
+    let hash = close.compute_hash();
+    if close.epoch != head.last_epoch {
+        epoch_started_ms = close.timestamp_ms();
+    }
+    persist_epoch(config, close.epoch, epoch_started_ms)?;
+    persist_seq(config, close.seq + 1)?;
+    persist_prev_hash(config, hash)?;
+    persist_accumulator(config, close.accumulator)?;
+    let closed = Closed {
+        epoch: close.epoch,
+        seq: close.seq,
+        hash: hex_encode(&hash),
+        reason: reason.to_string(),
+        closed_at_ms: Utc::now().timestamp_millis() as u64,
+    };
+    fs::write(
+        closed_path(&config.state_dir),
+        serde_json::to_vec_pretty(&closed)?,
+    )?;
+    println!(
+        "Closed the chain of agent {} at epoch {} seq {}: {reason}",
+        config.agent_id, close.epoch, close.seq
+    );
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! `--grpc-url`: submits batches and reads the startup checkpoint over the
+//! server's gRPC service instead of HTTP. Retries, backoff, the throttle and
+//! `--batch-timeout-ms` apply unchanged; one attempt is one `Submit` call.
+
+use crate::{Attempt, Checkpoint, SubmitAck};
+use anyhow::{Result, anyhow};
+use common::batch::LogBatch;
+use common::grpc::RETRY_AFTER_METADATA;
+use common::grpc::proto::{self, log_chain_client::LogChainClient};
+use prost::Message;
+use std::time::Duration;
+
+/// Bytes one attempt puts on the wire, for the throttle.
+pub fn encoded_len(batch: &LogBatch) -> usize {
+    proto::LogBatch::from(batch).encoded_len()
+}
+
+/// `request_id` goes out as `x-request-id` metadata.
+pub async fn submit(url: &str, batch: &LogBatch, request_id: &str) -> Attempt {
+    let mut client = match LogChainClient::connect(url.to_string()).await {
+        Ok(client) => client,
+        Err(err) => return Attempt::Failed(err.to_string()),
+    };
+    let mut request = tonic::Request::new(proto::LogBatch::from(batch));
+    if let Ok(value) = request_id.parse() {
+        request
+            .metadata_mut()
+            .insert(common::request_id::HEADER, value);
+    }
+    match client.submit(request).await {
+        // The gRPC service issues no receipt.
+        Ok(response) => Attempt::Accepted(SubmitAck {
+            server_time_ms: response.into_inner().server_time_ms,
+            receipt: None,
+        }),
+        // The server answers rejections with a status; transport failures
+        // surface as `Unavailable` or `Unknown`.
+        Err(status) => match status.code() {
+            // Maintenance mode says when to come back.
+            tonic::Code::Unavailable if status.metadata().contains_key(RETRY_AFTER_METADATA) => {
+                Attempt::Deferred {
+                    wait: status
+                        .metadata()
+                        .get(RETRY_AFTER_METADATA)
+                        .and_then(|value| value.to_str().ok()?.parse().ok())
+                        .map(Duration::from_secs),
+                    reason: status.message().to_string(),
+                }
+            }
+            tonic::Code::Unavailable | tonic::Code::Unknown => {
+                Attempt::Failed(status.message().to_string())
+            }
+            code => Attempt::Rejected {
+                reason: format!("{code:?}: {}", status.message()),
+                code: status
+                    .metadata()
+                    .get(common::grpc::ERROR_CODE_METADATA)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string),
+            },
+        },
+    }
+}
+
+pub async fn fetch_checkpoint(url: &str, agent_id: &str) -> Result<Option<Checkpoint>> {
+    let mut client = LogChainClient::connect(url.to_string()).await?;
+    let mut stream = client
+        .checkpoints(proto::CheckpointsRequest {})
+        .await?
+        .into_inner();
+    while let Some(cp) = stream.message().await? {
+        if cp.agent_id != agent_id {
+            continue;
+        }
+        let last_hash = cp
+            .last_hash
+            .try_into()
+            .map_err(|_| anyhow!("checkpoint last_hash is not 32 bytes"))?;
+        return Ok(Some(Checkpoint {
+            agent_id: cp.agent_id,
+            last_seq: cp.last_seq,
+            last_hash,
+            count: cp.count,
+            last_accumulator: cp.last_accumulator.and_then(|acc| acc.try_into().ok()),
+            last_epoch: cp.last_epoch,
+        }));
+    }
+    Ok(None)
+}
@@ -0,0 +1,111 @@
+//! `--max-inflight N`: a cap on submits in flight at once, for a sender that
+//! runs several chains (shards or files) side by side.
+//!
+//! A chain still sends serially: it holds one permit for a batch, retries
+//! included, and builds its next batch only once that one is settled, so seq
+//! order within a chain is unchanged and only different chains overlap. A
+//! chain waiting for a permit is not reading, so a saturated limit pauses the
+//! sources instead of queueing batches in memory.
+//!
+//! The agent tails a single source today, so one submit is in flight at most
+//! whatever the limit.
+
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Clone)]
+pub struct Inflight {
+    permits: Arc<Semaphore>,
+    limit: usize,
+}
+
+impl Inflight {
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(limit)),
+            limit,
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Waits for a free slot; it is released when the permit is dropped.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("inflight semaphore is never closed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time::{Duration, sleep, timeout};
+
+    #[tokio::test]
+    async fn concurrency_never_exceeds_the_limit() {
+        let inflight = Inflight::new(3);
+        let current = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let sent = Arc::new(Mutex::new(Vec::new()));
+
+        let chains: Vec<_> = (0..8)
+            .map(|chain| {
+                let (inflight, current, peak, sent) = (
+                    inflight.clone(),
+                    current.clone(),
+                    peak.clone(),
+                    sent.clone(),
+                );
+                tokio::spawn(async move {
+                    for seq in 1..=5 {
+                        let _permit = inflight.acquire().await;
+                        let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        sleep(Duration::from_millis(2)).await;
+                        sent.lock().unwrap().push((chain, seq));
+                        current.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for chain in chains {
+            chain.await.unwrap();
+        }
+
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 40);
+        for chain in 0..8 {
+            let seqs: Vec<u32> = sent
+                .iter()
+                .filter(|(c, _)| *c == chain)
+                .map(|(_, seq)| *seq)
+                .collect();
+            assert_eq!(seqs, [1, 2, 3, 4, 5], "chain {chain}");
+        }
+    }
+
+    #[tokio::test]
+    async fn saturated_limit_blocks_the_next_sender() {
+        let inflight = Inflight::new(0);
+        assert_eq!(inflight.limit(), 1);
+        let held = inflight.acquire().await;
+        assert!(
+            timeout(Duration::from_millis(20), inflight.acquire())
+                .await
+                .is_err()
+        );
+        drop(held);
+        let _next = timeout(Duration::from_millis(20), inflight.acquire())
+            .await
+            .unwrap();
+    }
+}
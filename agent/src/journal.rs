@@ -0,0 +1,388 @@
+//! `--journal`: a local record of what this agent shipped, so an operator
+//! on the host can ask what went out without the server (`--local-log`).
+//! It is advisory and outside the trust model: nothing verifies it, and the
+//! server's chain and the kept acks (see [`crate::acks`]) are the evidence.
+//!
+//! Entries are appended after each accepted batch to ndjson segments in
+//! `state_dir/journal/`, named by the send time of their first entry (unix
+//! ms, 20 digits). A segment is closed at an eighth of
+//! `--journal-max-bytes`; the oldest are pruned once the journal outgrows
+//! that cap, or once every entry in them is older than
+//! `--journal-max-age-secs`. The writes happen on a thread of their own
+//! behind a bounded queue, so a slow disk never holds up a send: when the
+//! queue is full the entry is dropped and counted.
+
+use anyhow::{Context, Result, anyhow};
+use common::batch::LogBatch;
+use common::hex::hex_encode;
+use common::receipt::Receipt;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+/// Journal size unless `--journal-max-bytes` says otherwise.
+pub const DEFAULT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+/// Journal age unless `--journal-max-age-secs` says otherwise: a week.
+pub const DEFAULT_MAX_AGE_SECS: u64 = 7 * 24 * 3600;
+/// Entries waiting for the writer before new ones are dropped.
+const QUEUE: usize = 1024;
+/// Segments the cap is split into, so pruning frees a fraction at a time.
+const SEGMENTS: u64 = 8;
+
+pub fn journal_dir(state_dir: &Path) -> PathBuf {
+    state_dir.join("journal")
+}
+
+/// One shipped batch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// When the server accepted it, by our clock (unix ms).
+    pub sent_at_ms: u64,
+    pub epoch: u64,
+    pub seq: u64,
+    pub hash: String,
+    /// The batch's own capture time (unix ms).
+    pub timestamp: u64,
+    pub logs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<Receipt>,
+}
+
+impl Entry {
+    pub fn new(batch: &LogBatch, receipt: Option<Receipt>, sent_at_ms: u64) -> Self {
+        Self {
+            sent_at_ms,
+            epoch: batch.epoch,
+            seq: batch.seq,
+            hash: hex_encode(&batch.compute_hash()),
+            timestamp: batch.timestamp,
+            logs: batch.logs.clone(),
+            receipt,
+        }
+    }
+}
+
+/// The send path's handle on the journal writer.
+#[derive(Clone)]
+pub struct Journal {
+    queue: SyncSender<Entry>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Journal {
+    /// Starts the writer thread for the journal under `state_dir`.
+    pub fn start(state_dir: &Path, max_bytes: u64, max_age_secs: u64) -> Result<Self> {
+        let mut writer = Writer::open(&journal_dir(state_dir), max_bytes, max_age_secs)?;
+        let (journal, entries) = Self::with_queue(QUEUE);
+        std::thread::Builder::new()
+            .name("journal".into())
+            .spawn(move || {
+                for entry in entries {
+                    if let Err(err) = writer.append(&entry) {
+                        eprintln!("[journal] could not record seq {}: {err}", entry.seq);
+                    }
+                }
+            })?;
+        Ok(journal)
+    }
+
+    fn with_queue(capacity: usize) -> (Self, Receiver<Entry>) {
+        let (queue, entries) = mpsc::sync_channel(capacity);
+        let journal = Self {
+            queue,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (journal, entries)
+    }
+
+    /// Queues `entry` without waiting; a full queue or a stopped writer
+    /// drops it.
+    pub fn record(&self, entry: Entry) {
+        let seq = entry.seq;
+        match self.queue.try_send(entry) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                // Reported on the first drop and every thousandth after it.
+                if self
+                    .dropped
+                    .fetch_add(1, Ordering::Relaxed)
+                    .is_multiple_of(1000)
+                {
+                    eprintln!("[journal] writer is behind; seq {seq} not recorded");
+                }
+            }
+        }
+    }
+
+    /// Entries dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Appends entries to the segments and prunes them.
+struct Writer {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age_ms: u64,
+    /// `(first sent_at_ms, bytes)` of each segment, oldest first; the last
+    /// is written to.
+    segments: VecDeque<(u64, u64)>,
+}
+
+impl Writer {
+    fn open(dir: &Path, max_bytes: u64, max_age_secs: u64) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+        let mut segments = VecDeque::new();
+        for (start, path) in segment_paths(dir)? {
+            segments.push_back((start, fs::metadata(&path)?.len()));
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_bytes: max_bytes.max(1),
+            max_age_ms: max_age_secs.saturating_mul(1000),
+            segments,
+        })
+    }
+
+    fn path(&self, start: u64) -> PathBuf {
+        self.dir.join(format!("{start:020}.ndjson"))
+    }
+
+    fn append(&mut self, entry: &Entry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let segment_cap = (self.max_bytes / SEGMENTS).max(1);
+        if self
+            .segments
+            .back()
+            .is_none_or(|(_, bytes)| *bytes >= segment_cap)
+        {
+            self.segments.push_back((entry.sent_at_ms, 0));
+        }
+        let (start, bytes) = self
+            .segments
+            .back_mut()
+            .expect("a segment was just ensured");
+        *bytes += line.len() as u64;
+        let start = *start;
+        let path = self.path(start);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?
+            .write_all(&line)?;
+        self.prune(entry.sent_at_ms)
+    }
+
+    /// Drops the oldest segments while the journal is over its cap, and
+    /// those whose entries all predate the age limit; the one being
+    /// written to stays.
+    fn prune(&mut self, now_ms: u64) -> Result<()> {
+        let cutoff = now_ms.saturating_sub(self.max_age_ms);
+        while self.segments.len() > 1 {
+            let total: u64 = self.segments.iter().map(|(_, bytes)| bytes).sum();
+            // A segment ends where the next one starts.
+            let expired = self.segments[1].0 <= cutoff;
+            if total <= self.max_bytes && !expired {
+                break;
+            }
+            let (start, _) = self.segments.pop_front().expect("more than one segment");
+            fs::remove_file(self.path(start))?;
+        }
+        Ok(())
+    }
+}
+
+/// The segments in `dir`, oldest first.
+fn segment_paths(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut segments: Vec<(u64, PathBuf)> = fs::read_dir(dir)
+        .with_context(|| format!("cannot read {}", dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ndjson"))
+        .filter_map(|path| {
+            let start = path.file_stem()?.to_str()?.parse().ok()?;
+            Some((start, path))
+        })
+        .collect();
+    segments.sort();
+    Ok(segments)
+}
+
+/// The entries sent at or after `since_ms`, oldest first, keeping only the
+/// lines containing `grep` when given (and entries with any). A line cut
+/// short by a crash is skipped.
+pub fn query(state_dir: &Path, since_ms: u64, grep: Option<&str>) -> Result<Vec<Entry>> {
+    let dir = journal_dir(state_dir);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut found = Vec::new();
+    for (_, path) in segment_paths(&dir)? {
+        let raw = fs::read(&path).with_context(|| format!("cannot read {}", path.display()))?;
+        for line in raw.split(|&b| b == b'\n') {
+            let Ok(mut entry) = serde_json::from_slice::<Entry>(line) else {
+                continue;
+            };
+            if entry.sent_at_ms < since_ms {
+                continue;
+            }
+            if let Some(needle) = grep {
+                entry.logs.retain(|log| log.contains(needle));
+                if entry.logs.is_empty() {
+                    continue;
+                }
+            }
+            found.push(entry);
+        }
+    }
+    Ok(found)
+}
+
+/// A `--since` span: seconds, or a number with `s`, `m`, `h` or `d`.
+pub fn parse_span(span: &str) -> Result<u64> {
+    let span = span.trim();
+    let (digits, unit) = match span.char_indices().last() {
+        Some((at, unit)) if unit.is_ascii_alphabetic() => (&span[..at], unit),
+        _ => (span, 's'),
+    };
+    let scale = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86_400,
+        _ => return Err(anyhow!("--since unit must be s, m, h or d, not '{unit}'")),
+    };
+    let count: u64 = digits
+        .parse()
+        .map_err(|_| anyhow!("--since must look like 90s, 30m, 1h or 2d, not '{span}'"))?;
+    Ok(count.saturating_mul(scale))
+}
+
+/// `--local-log`: prints what the journal holds since `since_ms`, one line
+/// per shipped line, and a summary.
+pub fn print(state_dir: &Path, since_ms: u64, grep: Option<&str>) -> Result<()> {
+    let entries = query(state_dir, since_ms, grep)?;
+    let mut lines = 0;
+    for entry in &entries {
+        let acked = if entry.receipt.is_some() {
+            "acked"
+        } else {
+            "no receipt"
+        };
+        println!(
+            "# epoch {} seq {} sent {} hash {} ({acked})",
+            entry.epoch,
+            entry.seq,
+            chrono::DateTime::from_timestamp_millis(entry.sent_at_ms as i64)
+                .map(|at| at.to_rfc3339())
+                .unwrap_or_else(|| entry.sent_at_ms.to_string()),
+            entry.hash
+        );
+        for log in &entry.logs {
+            println!("{log}");
+        }
+        lines += entry.logs.len();
+    }
+    println!(
+        "{} batches, {lines} lines (local journal; not evidence)",
+        entries.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn entry(seq: u64, sent_at_ms: u64, log: &str) -> Entry {
+        Entry {
+            sent_at_ms,
+            epoch: 0,
+            seq,
+            hash: "00".repeat(32),
+            timestamp: sent_at_ms,
+            logs: vec![log.to_string()],
+            receipt: None,
+        }
+    }
+
+    fn size(dir: &Path) -> u64 {
+        segment_paths(dir)
+            .unwrap()
+            .iter()
+            .map(|(_, path)| fs::metadata(path).unwrap().len())
+            .sum()
+    }
+
+    #[test]
+    fn the_journal_stays_within_its_size_and_age_caps() {
+        let state_dir =
+            std::env::temp_dir().join(format!("agent-journal-caps-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&state_dir);
+        let dir = journal_dir(&state_dir);
+        let mut writer = Writer::open(&dir, 4096, 3600).unwrap();
+        for seq in 1..=200 {
+            writer
+                .append(&entry(seq, 1_000_000 + seq, &format!("line {seq}")))
+                .unwrap();
+            assert!(size(&dir) <= 4096, "journal grew to {}", size(&dir));
+        }
+        let kept = query(&state_dir, 0, None).unwrap();
+        assert_eq!(kept.last().unwrap().seq, 200);
+        assert!(kept.len() < 200 && kept.len() > 20, "{}", kept.len());
+        // Reopening picks the segments up where they were.
+        let mut writer = Writer::open(&dir, 4096, 3600).unwrap();
+        assert_eq!(writer.segments.len(), segment_paths(&dir).unwrap().len());
+
+        // An hour on, only the segment still open then has entries that
+        // are not all too old.
+        let tail_start = writer.segments.back().unwrap().0;
+        let later = 1_000_200 + 3_600_000;
+        for seq in 201..=210 {
+            let log = if seq == 210 { "later" } else { "late" };
+            writer.append(&entry(seq, later + seq, log)).unwrap();
+        }
+        let kept = query(&state_dir, 0, None).unwrap();
+        assert!(
+            kept[0].seq < 201 && kept[0].sent_at_ms >= tail_start,
+            "{:?}",
+            kept[0]
+        );
+
+        let found = query(&state_dir, later + 205, Some("later")).unwrap();
+        assert_eq!(found.iter().map(|e| e.seq).collect::<Vec<_>>(), [210]);
+        assert_eq!(found[0].logs, ["later"]);
+        let _ = fs::remove_dir_all(&state_dir);
+    }
+
+    #[test]
+    fn recording_never_waits_for_the_writer() {
+        // Nothing drains this queue, as with a writer stuck on a slow disk.
+        let (journal, _stuck) = Journal::with_queue(2);
+        let started = Instant::now();
+        for seq in 1..=100 {
+            journal.record(entry(seq, seq, "x"));
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(journal.dropped(), 98);
+    }
+
+    #[test]
+    fn spans_take_a_unit() {
+        assert_eq!(parse_span("1h").unwrap(), 3600);
+        assert_eq!(parse_span("30m").unwrap(), 1800);
+        assert_eq!(parse_span("2d").unwrap(), 172_800);
+        assert_eq!(parse_span("90").unwrap(), 90);
+        assert!(parse_span("1w").is_err());
+        assert!(parse_span("h").is_err());
+    }
+}
@@ -0,0 +1,368 @@
+//! `--log-dir <dir> --file-pattern <glob>`: follows a directory of rotated
+//! logs such as `app.2024-01-01.log`, `app.2024-01-02.log`. Matching files
+//! are read one at a time in `--file-order` (`mtime`, the default, or
+//! `name`). The agent stays on the current file while it grows and moves to
+//! the next one once the current file is at its end and a later file exists.
+//!
+//! Progress is the source's [`Manifest`]: the files already finished and
+//! the byte offset reached in the current one. It is persisted with the
+//! other source checkpoints (see [`crate::source::SourceStates`]) once a
+//! batch's lines are done with, so a restart resumes after the last batch
+//! instead of shipping finished files again. Lines buffered but not yet in a
+//! batch are read again after a restart.
+
+use crate::reader::LineReader;
+use crate::source::{LogSource, Record, SourceCheckpoint};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, BufReader};
+use tokio::time::{Duration, sleep};
+
+/// How often a directory is looked at again while the current file is idle.
+pub const DIR_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOrder {
+    /// Oldest modification time first; ties go by name.
+    Mtime,
+    /// Byte-wise name order, which is date order for ISO-dated names.
+    Name,
+}
+
+impl FileOrder {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "mtime" => Some(Self::Mtime),
+            "name" => Some(Self::Name),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FileOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Mtime => "mtime",
+            Self::Name => "name",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirSpec {
+    pub dir: PathBuf,
+    /// Glob over file names: `*` matches any run of characters, `?` one.
+    pub pattern: String,
+    pub order: FileOrder,
+}
+
+impl fmt::Display for DirSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "directory {} ({}, by {})",
+            self.dir.display(),
+            self.pattern,
+            self.order
+        )
+    }
+}
+
+/// Whether `name` matches `pattern` (`*` and `?` wildcards, no classes).
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and the name position it currently covers up to.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// A directory source's checkpoint.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Files read to the end and left behind.
+    pub completed: BTreeSet<String>,
+    /// The file being read and the offset of its first line not yet shipped.
+    pub current: Option<String>,
+    pub offset: u64,
+}
+
+impl Manifest {
+    /// `state_dir/log-dir.json`, where agents before the unified state file
+    /// kept the manifest; read once so an upgrade resumes where it was.
+    pub fn load_legacy(state_dir: &Path) -> Result<Option<Self>> {
+        let path = state_dir.join("log-dir.json");
+        match fs::read(&path) {
+            Ok(raw) => serde_json::from_slice(&raw)
+                .map(Some)
+                .with_context(|| format!("{} is not a log-dir manifest", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Matching files not yet completed, in `order`.
+fn pending_files(spec: &DirSpec, completed: &BTreeSet<String>) -> std::io::Result<Vec<String>> {
+    let mut files: Vec<(SystemTime, String)> = Vec::new();
+    for entry in fs::read_dir(&spec.dir)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if !glob_match(&spec.pattern, &name) || completed.contains(&name) {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        if !meta.is_file() {
+            continue;
+        }
+        let mtime = match spec.order {
+            FileOrder::Mtime => meta.modified()?,
+            FileOrder::Name => SystemTime::UNIX_EPOCH,
+        };
+        files.push((mtime, name));
+    }
+    files.sort();
+    Ok(files.into_iter().map(|(_, name)| name).collect())
+}
+
+pub struct DirSource {
+    spec: DirSpec,
+    max_line_bytes: usize,
+    poll_interval: Duration,
+    manifest: Manifest,
+    /// Offset the current file's reader started at.
+    start: u64,
+    reader: Option<LineReader<BufReader<File>>>,
+}
+
+impl DirSource {
+    pub fn new(spec: DirSpec, max_line_bytes: usize, poll_interval: Duration) -> Self {
+        Self {
+            spec,
+            max_line_bytes,
+            poll_interval,
+            manifest: Manifest::default(),
+            start: 0,
+            reader: None,
+        }
+    }
+
+    /// Next line; waits while there is nothing new, so never `None`.
+    pub async fn next_line(&mut self) -> std::io::Result<String> {
+        loop {
+            if self.reader.is_none() && !self.open_next().await? {
+                sleep(self.poll_interval).await;
+                continue;
+            }
+            let reader = self.reader.as_mut().expect("opened above");
+            if let Some(line) = reader.next_line().await? {
+                return Ok(line);
+            }
+
+            // At the end of the current file: move on only once a later one
+            // exists, so a file still being written is not left early.
+            let current = self
+                .manifest
+                .current
+                .clone()
+                .expect("set while a file is open");
+            let later = pending_files(&self.spec, &self.manifest.completed)?
+                .into_iter()
+                .any(|name| name != current);
+            if !later {
+                sleep(self.poll_interval).await;
+                continue;
+            }
+            // Anything written after the last poll is read before leaving.
+            if let Some(line) = reader.next_line().await? {
+                return Ok(line);
+            }
+            let last = reader.take_partial();
+            self.reader = None;
+            self.manifest.completed.insert(current.clone());
+            self.manifest.current = None;
+            self.manifest.offset = 0;
+            println!("[log-dir] finished {current}");
+            if let Some(line) = last {
+                return Ok(line);
+            }
+        }
+    }
+
+    /// Opens the first pending file, at the recorded offset if it is the one
+    /// the manifest names. `false` when there is none yet.
+    async fn open_next(&mut self) -> std::io::Result<bool> {
+        let Some(name) = pending_files(&self.spec, &self.manifest.completed)?
+            .into_iter()
+            .next()
+        else {
+            return Ok(false);
+        };
+        let mut offset = match &self.manifest.current {
+            Some(current) if *current == name => self.manifest.offset,
+            _ => 0,
+        };
+        let mut file = File::open(self.spec.dir.join(&name)).await?;
+        let len = file.metadata().await?.len();
+        if offset > len {
+            eprintln!("[log-dir] {name} is shorter than byte {offset}; reading it from the start");
+            offset = 0;
+        }
+        file.seek(SeekFrom::Start(offset)).await?;
+        println!("[log-dir] reading {name} from byte {offset}");
+        self.manifest.current = Some(name);
+        self.manifest.offset = offset;
+        self.start = offset;
+        self.reader = Some(LineReader::following(
+            BufReader::new(file),
+            self.max_line_bytes,
+        ));
+        Ok(true)
+    }
+}
+
+impl LogSource for DirSource {
+    async fn next_record(&mut self) -> std::io::Result<Option<Record>> {
+        self.next_line().await.map(|line| Some(Record { line }))
+    }
+
+    /// Everything read so far. Completed files no longer in the directory
+    /// are forgotten.
+    fn checkpoint(&self) -> SourceCheckpoint {
+        let mut manifest = self.manifest.clone();
+        if let Some(reader) = &self.reader {
+            manifest.offset = self.start + reader.consumed();
+        }
+        if let Ok(entries) = fs::read_dir(&self.spec.dir) {
+            let present: BTreeSet<String> = entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .collect();
+            manifest.completed.retain(|name| present.contains(name));
+        }
+        SourceCheckpoint::Dir(manifest)
+    }
+
+    /// Takes effect when the next file is opened, so call it before reading.
+    fn restore(&mut self, checkpoint: SourceCheckpoint) {
+        let SourceCheckpoint::Dir(manifest) = checkpoint else {
+            eprintln!("[log-dir] ignoring a checkpoint that is not a directory's");
+            return;
+        };
+        if let Some(current) = &manifest.current {
+            println!("[log-dir] resuming {current} at byte {}", manifest.offset);
+        }
+        self.manifest = manifest;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_whole_names() {
+        assert!(glob_match("app.*.log", "app.2024-01-01.log"));
+        assert!(glob_match("app.????-??-??.log", "app.2024-01-01.log"));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("*.log", "a.b.log"));
+        assert!(!glob_match("app.*.log", "app.2024-01-01.log.gz"));
+        assert!(!glob_match("app.*.log", "other.2024-01-01.log"));
+        assert!(!glob_match("app.?.log", "app.10.log"));
+    }
+
+    #[tokio::test]
+    async fn dated_files_are_read_in_order_and_a_restart_resumes_mid_file() {
+        let root = std::env::temp_dir().join(format!("agent-log-dir-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let dir = root.join("logs");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("app.2024-01-01.log"), "a1\na2\n").unwrap();
+        fs::write(dir.join("app.2024-01-02.log"), "b1\nb2\nb3\n").unwrap();
+        fs::write(dir.join("app.2024-01-03.log"), "c1\nc2").unwrap();
+        fs::write(dir.join("other.log"), "not shipped\n").unwrap();
+        let spec = DirSpec {
+            dir: dir.clone(),
+            pattern: "app.*.log".into(),
+            order: FileOrder::Name,
+        };
+        let open = || DirSource::new(spec.clone(), 1024, Duration::from_millis(10));
+        let manifest = |source: &DirSource| match source.checkpoint() {
+            SourceCheckpoint::Dir(manifest) => manifest,
+            other => panic!("{other:?}"),
+        };
+
+        let mut source = open();
+        let mut lines = Vec::new();
+        for _ in 0..3 {
+            lines.push(source.next_line().await.unwrap());
+        }
+        let committed = source.checkpoint();
+        // Read but never committed: shipped again after the restart.
+        lines.push(source.next_line().await.unwrap());
+        assert_eq!(lines, ["a1", "a2", "b1", "b2"]);
+        let SourceCheckpoint::Dir(kept) = &committed else {
+            unreachable!()
+        };
+        assert_eq!(
+            kept.completed.iter().collect::<Vec<_>>(),
+            ["app.2024-01-01.log"]
+        );
+        assert_eq!(
+            (kept.current.as_deref(), kept.offset),
+            (Some("app.2024-01-02.log"), 3)
+        );
+        drop(source);
+
+        let mut source = open();
+        source.restore(committed);
+        let mut lines = Vec::new();
+        for _ in 0..3 {
+            lines.push(source.next_line().await.unwrap());
+        }
+        assert_eq!(lines, ["b2", "b3", "c1"]);
+        // `c2` has no newline yet and the file is the newest: it waits.
+        let waiting = tokio::time::timeout(Duration::from_millis(50), source.next_line()).await;
+        assert!(waiting.is_err());
+        // A newer file means the writer moved on; `c2` was its last line.
+        fs::write(dir.join("app.2024-01-04.log"), "d1\n").unwrap();
+        assert_eq!(source.next_line().await.unwrap(), "c2");
+        assert_eq!(source.next_line().await.unwrap(), "d1");
+        let manifest = manifest(&source);
+        assert_eq!(manifest.completed.len(), 3);
+        assert_eq!(
+            (manifest.current.as_deref(), manifest.offset),
+            (Some("app.2024-01-04.log"), 3)
+        );
+        let _ = fs::remove_dir_all(&root);
+    }
+}
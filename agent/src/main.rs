@@ -1,55 +1,283 @@
-use common::batch::{generate_keypair, LogBatch};
-use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::time::{sleep, Duration};
+mod acks;
+mod backpressure;
+mod batcher;
+mod breaker;
+mod config_file;
+mod deadletter;
+mod finalize;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod inflight;
+mod journal;
+mod log_dir;
+mod metrics;
+mod platform;
+mod reader;
+mod reanchor;
+mod rotate;
+mod source;
+mod spool;
+mod throttle;
+
+use anyhow::{Result, anyhow};
+use backpressure::Backpressure;
+use batcher::{Batcher, SourceConfig, SourceOverrides};
+use breaker::{Breaker, CircuitOpen};
 use chrono::Utc;
+use common::batch::{
+    BATCH_VERSION_V1, BatchKind, CURRENT_BATCH_VERSION, EpochStart, GapRecord, LogBatch,
+    generate_keypair,
+};
+use common::compat::{self, Capabilities, Produces};
+use common::hex::{hex_decode_fixed, hex_encode};
+use common::producer::{self, Checkpoint};
+use common::receipt::Receipt;
+use common::wire::WireFormat;
+use config_file::ConfigFile;
 use ed25519_dalek::Signature;
-use anyhow::{anyhow, Result};
+use inflight::Inflight;
+use log_dir::{DirSpec, FileOrder};
+use metrics::AgentMetrics;
+use reader::DEFAULT_MAX_LINE_BYTES;
+use serde::Deserialize;
+use source::SourceSpec;
 use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
-use serde::Deserialize;
+use std::sync::Arc;
+use throttle::Throttle;
+use tokio::time::{Duration, Instant, sleep, sleep_until, timeout};
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli_args = AgentArgs::parse();
+    if cli_args.version {
+        println!(
+            "{}",
+            Capabilities::current(env!("CARGO_PKG_VERSION")).describe("agent")
+        );
+        return Ok(());
+    }
     println!("Starting agent...");
+    let boot_time_ms = Utc::now().timestamp_millis() as u64;
 
-    let cli_args = AgentArgs::parse();
-    let config = AgentConfig::load(cli_args)?;
+    let mut config = AgentConfig::load(&cli_args)?;
     println!("Agent ID: {}", config.agent_id);
-    println!("Tailing {}", config.log_path.display());
-    println!("Sending to {}", config.server_url);
+    if cli_args.local_log {
+        let since_ms = match cli_args.since.as_deref() {
+            Some(span) => (Utc::now().timestamp_millis() as u64)
+                .saturating_sub(journal::parse_span(span)?.saturating_mul(1000)),
+            None => 0,
+        };
+        return journal::print(&config.state_dir, since_ms, cli_args.grep.as_deref());
+    }
+    // `--re-anchor` rebuilds the chain in the current format, whatever
+    // `--batch-version` says; `--verify-acks` only reads.
+    let produces = (!cli_args.verify_acks).then_some(Produces {
+        batch_version: if cli_args.re_anchor {
+            CURRENT_BATCH_VERSION
+        } else {
+            config.batch_version
+        },
+        gzip: config.gzip_uploads,
+        wire_format: config.wire_format,
+    });
+    check_compat(&config, produces).await?;
+    if cli_args.verify_acks {
+        let intact = acks::verify(&config.server_url, &config.state_dir).await?;
+        std::process::exit(if intact { 0 } else { 1 });
+    }
+    if cli_args.check_spool {
+        let key = load_key(&config)?;
+        return spool::check(&config.state_dir, &spool::SpoolKey::derive(&key));
+    }
+    // Before `--re-anchor`, which spools what it sends too.
+    if config.spool && config.spool_encrypt {
+        config.spool_key = Some(spool::SpoolKey::derive(&load_key(&config)?));
+        println!("Encrypting the spool under a key derived from the agent key");
+    }
+    if cli_args.finalize {
+        return finalize::run(&config, cli_args.reason.as_deref(), cli_args.confirm).await;
+    }
+    finalize::check_open(&config.state_dir)?;
+    if cli_args.re_anchor {
+        return reanchor::run(&config, cli_args.confirm).await;
+    }
+    if cli_args.rotate_key {
+        return rotate::run(&config).await;
+    }
+    let mut sources = config.sources();
+    for source in &sources {
+        println!("Tailing {} as source {}", source.spec, source.name);
+    }
+    match &config.grpc_url {
+        Some(url) => println!("Sending to {url} over gRPC"),
+        None => println!("Sending to {}", config.server_url),
+    }
     println!(
         "Retries: max {} with base {}ms",
         config.max_retries, config.retry_base_ms
     );
+    if let Some(ms) = config.batch_timeout_ms {
+        println!("Batch timeout: {ms}ms per batch, retries included");
+    }
 
-    let mut key = load_or_generate_key(&config)?;
+    for source in &sources {
+        let flush = match source.flush_interval {
+            Some(interval) => format!(", or after {}ms", interval.as_millis()),
+            None => String::new(),
+        };
+        println!(
+            "Batch size for {}: {} lines{flush}",
+            source.name, source.batch_size
+        );
+        if let Some(pattern) = &source.include {
+            println!("Only shipping lines of {} matching {pattern}", source.name);
+        }
+        if let Some(pattern) = &source.exclude {
+            println!("Not shipping lines of {} matching {pattern}", source.name);
+        }
+    }
+
+    let mut throttle = config.throttle();
+    println!("Throttle: {}", throttle.status());
+    let inflight = Inflight::new(config.max_inflight);
+    println!("Max in-flight submits: {}", inflight.limit());
+    match config.backpressure {
+        backpressure::Policy::Drop => println!("Backpressure: drop the lines of a failed batch"),
+        backpressure::Policy::Pause => println!(
+            "Backpressure: pause reading after {} failed batches in a row",
+            config.pause_after_failures
+        ),
+    }
+    let mut breaker = Breaker::default();
+    if config.breaker_threshold > 0 {
+        println!(
+            "Circuit breaker: open for {}ms after {} failed attempts in a row",
+            config.breaker_cooldown_ms, config.breaker_threshold
+        );
+    }
+    if config.wire_format != WireFormat::Json {
+        println!(
+            "Wire format: {} ({})",
+            config.wire_format,
+            config.wire_format.content_type()
+        );
+    }
+    if config.gzip_uploads {
+        println!(
+            "Gzipping uploads that shrink by at least {}%",
+            config.gzip_min_saving_pct
+        );
+    }
+
+    if config.journal {
+        config.journal_writer = Some(journal::Journal::start(
+            &config.state_dir,
+            config.journal_max_bytes,
+            config.journal_max_age_secs,
+        )?);
+        println!(
+            "Journaling shipped batches to {} (up to {} bytes, {}s)",
+            journal::journal_dir(&config.state_dir).display(),
+            config.journal_max_bytes,
+            config.journal_max_age_secs
+        );
+    }
+
+    let metrics = Arc::new(AgentMetrics::default());
+    let push_url = config
+        .metrics_push_url
+        .as_ref()
+        .map(|base| metrics::group_url(base, &config.agent_id, &platform::hostname()));
+    if let Some(url) = &push_url {
+        println!(
+            "Pushing metrics to {url} every {}s",
+            config.metrics_push_interval_secs
+        );
+        metrics::spawn_pusher(
+            metrics.clone(),
+            url.clone(),
+            Duration::from_secs(config.metrics_push_interval_secs),
+        );
+    }
+
+    // Loaded (or generated, on a first start) with the config.
+    let key = load_key(&config)?;
     let mut seq = load_seq(&config)?; // persistent monotonic counter
     let mut prev_hash = load_prev_hash(&config)?;
+    // Accumulator of the last accepted batch; `None` before the first one.
+    let mut prev_accumulator = load_accumulator(&config)?;
+    // Chain epoch of the next batch and when it started; see [`next_position`].
+    let (mut epoch, mut epoch_started_ms) = load_epoch(&config)?;
+    persist_epoch(&config, epoch, epoch_started_ms)?;
+    // Cumulative count of lines ever read (not shipped); only signed into
+    // batches when --count-lines is set.
+    let mut lines_read = load_lines_read(&config)?;
 
     // Try to align with server checkpoint so we don't send out-of-sync batches.
-    match fetch_checkpoint(&config, &config.agent_id).await {
+    let mut checkpoint = fetch_checkpoint(&config, &mut breaker, &metrics, &config.agent_id).await;
+    // With --allow-gap, seqs local state counts as sent but the server lacks
+    // are declared lost in a signed marker instead of being silently reused.
+    if config.allow_gap
+        && let Ok(server) = &checkpoint
+        && let Some(marker) = gap_marker(&config, &key, (epoch, seq), server.as_ref(), lines_read)
+    {
+        let gap = marker.gap.as_ref().expect("gap_marker sets the gap");
+        println!(
+            "Server is behind local state; declaring seqs {}..={} lost",
+            gap.missing_from, gap.missing_to
+        );
+        match send_batch(&config, &mut throttle, &mut breaker, &metrics, &marker).await {
+            Ok(_) => {
+                checkpoint = Ok(Some(Checkpoint {
+                    agent_id: marker.agent_id.clone(),
+                    last_seq: marker.seq,
+                    last_hash: marker.compute_hash(),
+                    count: 0,
+                    last_accumulator: marker.accumulator,
+                    last_epoch: marker.epoch,
+                }));
+            }
+            Err(err) => {
+                eprintln!("Gap marker not accepted, reusing the server's seqs instead: {err:?}")
+            }
+        }
+    }
+    match checkpoint {
         Ok(Some(cp)) => {
             prev_hash = cp.last_hash;
+            prev_accumulator = cp.last_accumulator;
             seq = cp.last_seq.saturating_add(1);
+            if cp.last_epoch != epoch {
+                epoch = cp.last_epoch;
+                epoch_started_ms = Utc::now().timestamp_millis() as u64;
+            }
+            persist_epoch(&config, epoch, epoch_started_ms)?;
             persist_seq(&config, seq)?;
             persist_prev_hash(&config, prev_hash)?;
+            persist_accumulator(&config, prev_accumulator)?;
             println!(
-                "Synced from server checkpoint: last_seq={}, next_seq={}, prev_hash={}",
+                "Synced from server checkpoint: epoch={}, last_seq={}, next_seq={}, prev_hash={}",
+                epoch,
                 cp.last_seq,
                 seq,
-                to_hex(&prev_hash)
+                hex_encode(&prev_hash)
             );
         }
         Ok(None) => {
             // No batches stored for this agent; reset local state to the beginning.
-            if seq != 1 || prev_hash != [0u8; 32] {
+            if seq != 1 || epoch != 0 || prev_hash != [0u8; 32] || prev_accumulator.is_some() {
                 println!("Server has no batches for this agent; resetting local chain state");
                 seq = 1;
+                epoch = 0;
+                epoch_started_ms = Utc::now().timestamp_millis() as u64;
+                persist_epoch(&config, epoch, epoch_started_ms)?;
                 prev_hash = [0u8; 32];
+                prev_accumulator = None;
                 persist_seq(&config, seq)?;
                 persist_prev_hash(&config, prev_hash)?;
+                persist_accumulator(&config, prev_accumulator)?;
             }
         }
         Err(err) => {
@@ -59,142 +287,1278 @@ async fn main() -> Result<()> {
         }
     }
 
-    // Open log file
-    let file = File::open(&config.log_path).await?;
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
+    // Last batch timestamp (epoch ms); bursts and clock steps backwards are
+    // bumped past it so consecutive batches never share a timestamp.
+    let mut last_timestamp_ms: u64 = 0;
 
-    let mut buffer: Vec<String> = Vec::new();
+    // With --batch-header the run opens with a signed session start, so the
+    // chain itself records where each restart falls.
+    if config.batch_header {
+        let head = Checkpoint {
+            agent_id: config.agent_id.clone(),
+            last_seq: seq.saturating_sub(1),
+            last_hash: prev_hash,
+            count: 0,
+            last_accumulator: prev_accumulator,
+            last_epoch: epoch,
+        };
+        let marker = session_start(
+            &config,
+            &key,
+            &head,
+            epoch_started_ms,
+            lines_read,
+            boot_time_ms,
+        );
+        let Some(BatchKind::SessionStart { session_id, .. }) = &marker.kind else {
+            unreachable!("session_start sets the kind");
+        };
+        println!(
+            "Starting session {session_id} at epoch {} seq {}",
+            marker.epoch, marker.seq
+        );
+        match send_batch(&config, &mut throttle, &mut breaker, &metrics, &marker).await {
+            Ok(_) => {
+                last_timestamp_ms = marker.timestamp_ms();
+                prev_hash = marker.compute_hash();
+                prev_accumulator = marker.accumulator;
+                if marker.epoch != epoch {
+                    epoch = marker.epoch;
+                    epoch_started_ms = marker.timestamp_ms();
+                    persist_epoch(&config, epoch, epoch_started_ms)?;
+                }
+                seq = marker.seq + 1;
+                persist_seq(&config, seq)?;
+                persist_prev_hash(&config, prev_hash)?;
+                persist_accumulator(&config, prev_accumulator)?;
+            }
+            Err(err) => eprintln!("Session start not accepted; this run goes unmarked: {err:?}"),
+        }
+    }
 
-    while let Some(line) = lines.next_line().await? {
-        buffer.push(line);
+    let mut opened = Vec::new();
+    for source in &sources {
+        opened.push(source::open(&source.spec, config.max_line_bytes, &config.state_dir).await?);
+    }
+    let mut readers = source::Readers::new(opened, &config.state_dir);
+    let mut shutdown = std::pin::pin!(shutdown_signal());
+    let config_reload = cli_args.config_reload
+        || env::var("AGENT_CONFIG_RELOAD")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+    let mut reload = ReloadSignal::install(config_reload)?;
 
-        // Once buffer hits batch size (5)
-        if buffer.len() >= 5 {
-            let timestamp = Utc::now().timestamp() as u64;
+    let mut batcher = Batcher::new(sources.len());
+    let mut backpressure = Backpressure::default();
+    let mut skew_warned = false;
+    // Set when the run must end with an error once state is flushed.
+    let mut fatal: Option<anyhow::Error> = None;
 
-            // Build batch (placeholder signature overwritten by .sign())
-            let mut batch = LogBatch {
-                prev_hash,
-                logs: buffer.clone(),
-                timestamp,
-                agent_id: config.agent_id.clone(),
-                seq,
-                // Placeholder signature overwritten by `sign`
-                signature: Signature::from_bytes(&[0u8; 64]),
-                public_key: key.verifying_key(),
-            };
+    loop {
+        // Send the batch that is due, or read on until one is.
+        let Some(index) = batcher.due(&sources, Instant::now()) else {
+            let next_flush = batcher.next_flush(&sources);
+            tokio::select! {
+                read = readers.next() => {
+                    let Some((index, line)) = read? else {
+                        report_unsent(&sources, &batcher);
+                        break;
+                    };
+                    if batcher.push(&sources, index, line, Instant::now()) {
+                        metrics.set_buffered_lines(batcher.total());
+                        metrics.set_source_buffered(&sources[index].name, batcher.depth(index));
+                    }
+                }
+                _ = async {
+                    match next_flush {
+                        Some(at) => sleep_until(at).await,
+                        None => std::future::pending().await,
+                    }
+                } => {}
+                _ = &mut shutdown => {
+                    println!("Shutting down");
+                    report_unsent(&sources, &batcher);
+                    break;
+                }
+                _ = reload.recv() => {
+                    reload_config(&cli_args, &mut config, &mut throttle);
+                    sources = config.sources();
+                }
+            }
+            continue;
+        };
+        let (buffer, filtered) = batcher.take(index);
+        lines_read += filtered + buffer.len() as u64;
+        let timestamp = (Utc::now().timestamp_millis() as u64).max(last_timestamp_ms + 1);
+        last_timestamp_ms = timestamp;
+        let (batch_epoch, batch_seq, epoch_start) =
+            next_position(&config, epoch, epoch_started_ms, seq, timestamp);
+        if let Some(start) = &epoch_start {
+            println!(
+                "Starting chain epoch {batch_epoch} after seq {} of epoch {epoch}",
+                start.previous_last_seq
+            );
+        }
 
-            // Sign batch & compute expected hash
-            batch.sign(&key);
-            let next_hash = batch.compute_hash();
+        // Build batch (placeholder signature overwritten by .sign())
+        let mut batch = LogBatch {
+            prev_hash,
+            logs: buffer,
+            timestamp: batch_timestamp(config.batch_version, timestamp),
+            agent_id: config.agent_id.clone(),
+            seq: batch_seq,
+            // Placeholder signature overwritten by `sign`
+            signature: Signature::from_bytes(&[0u8; 64]),
+            public_key: key.verifying_key(),
+            lines_read: config.count_lines.then_some(lines_read),
+            version: config.batch_version,
+            accumulator: None,
+            gap: None,
+            epoch: batch_epoch,
+            epoch_start,
+            kind: None,
+        };
+        // Extend the accumulator, sign & compute expected hash
+        producer::seal(&mut batch, &key, prev_accumulator.as_ref());
+        let next_hash = batch.compute_hash();
 
-            println!("Produced batch: {:?}", prev_hash);
+        println!("Produced batch: {:?}", prev_hash);
 
-            // Send to server; on success advance chain/seq
-            match send_batch(&config, &batch).await {
-                Ok(_) => {
-                    prev_hash = next_hash;
-                    seq += 1;
-                    persist_seq(&config, seq)?;
-                    persist_prev_hash(&config, prev_hash)?;
+        // Send to server; on success advance chain/seq. Reading waits for
+        // both the permit and the send, and for as long as a held batch
+        // is resent.
+        let Some(sent) = send_or_hold(
+            &config,
+            &mut throttle,
+            &mut breaker,
+            &metrics,
+            &inflight,
+            &mut backpressure,
+            &key,
+            &batch,
+            &mut shutdown,
+        )
+        .await
+        else {
+            println!("Shutting down; the held batch's lines are read again on restart");
+            report_unsent(&sources, &batcher);
+            break;
+        };
+        match sent {
+            Ok(skew_ms) => {
+                if let Some(skew_ms) = skew_ms {
+                    report_clock_skew(skew_ms, &mut skew_warned);
                 }
-                Err(err) => {
-                    eprintln!("Failed to send batch: {err:?}");
-                    // regenerate key if it was invalidated on disk
-                    key = load_or_generate_key(&config)?;
+                prev_hash = next_hash;
+                prev_accumulator = batch.accumulator;
+                if batch.epoch != epoch {
+                    epoch = batch.epoch;
+                    epoch_started_ms = timestamp;
+                    persist_epoch(&config, epoch, epoch_started_ms)?;
                 }
-            };
+                seq = batch.seq + 1;
+                persist_seq(&config, seq)?;
+                persist_prev_hash(&config, prev_hash)?;
+                persist_accumulator(&config, prev_accumulator)?;
+            }
+            Err(err) => {
+                eprintln!("Failed to send batch: {err:?}");
+                if config.count_lines {
+                    eprintln!(
+                        "Dropping {} lines; the next batch's line counter will show the gap",
+                        batch.logs.len()
+                    );
+                }
+                // A failing send may mean the key changed under us; that
+                // ends the run instead of continuing as someone else.
+                if let Err(key_err) = check_key(&config, &key) {
+                    fatal = Some(key_err);
+                } else if config.dead_letter_policy == deadletter::Policy::Halt
+                    && err.is::<deadletter::DeadLettered>()
+                {
+                    fatal = Some(anyhow!("{err} (--dead-letter-policy halt)"));
+                }
+            }
+        };
 
-            buffer.clear();
+        if config.count_lines {
+            persist_lines_read(&config, lines_read)?;
         }
+        readers.commit(index)?;
+        metrics.set_buffered_lines(batcher.total());
+        metrics.set_source_buffered(&sources[index].name, 0);
+        if let Some(err) = &fatal {
+            eprintln!("Stopping: {err}");
+            break;
+        }
+    }
+
+    readers.shutdown().await;
+    if let Some(journal) = &config.journal_writer
+        && journal.dropped() > 0
+    {
+        eprintln!(
+            "[journal] {} shipped batches were not recorded",
+            journal.dropped()
+        );
+    }
+    // A short-lived agent may exit before the next tick; leave its final counts.
+    if let Some(url) = &push_url
+        && let Err(err) = metrics::push(&reqwest::Client::new(), url, &metrics).await
+    {
+        eprintln!("Final metrics push to {url} failed: {err}");
     }
+    match fatal {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
 
+/// Rejects settings that contradict each other: two sources sharing one
+/// checkpoint, or filters that ship no line at all.
+fn check_sources(sources: &[SourceConfig]) -> Result<()> {
+    for (index, source) in sources.iter().enumerate() {
+        if let Some(other) = sources[..index]
+            .iter()
+            .find(|other| other.spec.key() == source.spec.key())
+        {
+            return Err(anyhow!(
+                "sources {} and {} both read {}; each source needs its own",
+                other.name,
+                source.name,
+                source.spec
+            ));
+        }
+        if let Some(exclude) = &source.exclude {
+            if exclude.chars().all(|c| c == '*') {
+                return Err(anyhow!(
+                    "source {}: exclude '{exclude}' drops every line",
+                    source.name
+                ));
+            }
+            if source.include.as_ref() == Some(exclude) {
+                return Err(anyhow!(
+                    "source {}: include and exclude are both '{exclude}', so no line would be shipped",
+                    source.name
+                ));
+            }
+        }
+    }
     Ok(())
 }
 
+/// Lists the lines still buffered per source when the agent stops; they
+/// were never sent, so their sources read them again on restart.
+fn report_unsent(sources: &[SourceConfig], batcher: &Batcher) {
+    if batcher.total() == 0 {
+        return;
+    }
+    let depths: Vec<String> = sources
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| batcher.depth(*index) > 0)
+        .map(|(index, source)| format!("{} {}", source.name, batcher.depth(index)))
+        .collect();
+    println!("Unsent buffered lines: {}", depths.join(", "));
+}
+
+/// Re-reads flags, env and the config file and applies the hot-reloadable
+/// settings. The buffer and chain state are not touched; a bad file leaves
+/// the running config as it is.
+fn reload_config(args: &AgentArgs, config: &mut AgentConfig, throttle: &mut Throttle) {
+    let fresh = match AgentConfig::load(args) {
+        Ok(fresh) => fresh,
+        Err(err) => {
+            eprintln!("Config reload failed, keeping current settings: {err:#}");
+            return;
+        }
+    };
+    let (applied, ignored) = config.reload(fresh);
+    let throttle_settings = [
+        "max_bytes_per_sec",
+        "burst_bytes",
+        "max_batches_per_sec",
+        "burst_batches",
+    ];
+    if applied.iter().any(|name| throttle_settings.contains(name)) {
+        *throttle = config.throttle();
+    }
+    if applied.is_empty() {
+        println!("Config reloaded; nothing changed");
+    } else {
+        println!("Config reloaded; applied {}", applied.join(", "));
+    }
+    if !ignored.is_empty() {
+        eprintln!(
+            "Config reload ignored until restart: {}",
+            ignored.join(", ")
+        );
+    }
+}
+
+/// SIGHUP on unix when `--config-reload` is set; otherwise never fires.
+struct ReloadSignal {
+    #[cfg(unix)]
+    hangup: Option<tokio::signal::unix::Signal>,
+}
+
+impl ReloadSignal {
+    fn install(enabled: bool) -> Result<Self> {
+        #[cfg(unix)]
+        {
+            let hangup = enabled
+                .then(|| tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()))
+                .transpose()?;
+            Ok(Self { hangup })
+        }
+        #[cfg(not(unix))]
+        {
+            if enabled {
+                eprintln!("--config-reload needs SIGHUP and is ignored on this platform");
+            }
+            Ok(Self {})
+        }
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(hangup) = &mut self.hangup {
+            if hangup.recv().await.is_some() {
+                return;
+            }
+            self.hangup = None;
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+/// Resolves on Ctrl-C or, on unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Lines per batch unless `--batch-size` says otherwise.
+const DEFAULT_BATCH_SIZE: usize = 5;
+/// Below this saving a gzipped upload is not worth the server's inflate.
+/// Typical log text saves far more; random-looking text saves under 20%.
+const DEFAULT_GZIP_MIN_SAVING_PCT: u8 = 25;
+
 /* -------------------------
    POST BATCH TO SERVER
 ------------------------- */
-async fn send_batch(config: &AgentConfig, batch: &LogBatch) -> Result<()> {
+/// How far the server's clock may sit from ours before the agent warns.
+const CLOCK_SKEW_WARN_MS: i64 = 30_000;
+/// The longest the agent holds a deferred batch before asking again,
+/// whatever `Retry-After` says.
+const MAX_DEFER_WAIT: Duration = Duration::from_secs(300);
+
+#[derive(Default, Deserialize)]
+struct SubmitAck {
+    #[serde(default)]
+    server_time_ms: Option<u64>,
+    /// The server's signed receipt for the batch; see [`acks`].
+    #[serde(default)]
+    receipt: Option<Receipt>,
+}
+
+/// Server clock minus ours, taking the request's midpoint as the moment the
+/// server stamped its reply.
+fn clock_skew_ms(sent_ms: i64, received_ms: i64, server_time_ms: u64) -> i64 {
+    server_time_ms as i64 - (sent_ms + (received_ms - sent_ms) / 2)
+}
+
+/// Warns once when our clock drifts past `CLOCK_SKEW_WARN_MS`, and again
+/// when it is back in range. Batch timestamps are left as they are.
+fn report_clock_skew(skew_ms: i64, warned: &mut bool) {
+    let exceeded = skew_ms.unsigned_abs() > CLOCK_SKEW_WARN_MS as u64;
+    if exceeded && !*warned {
+        eprintln!(
+            "Local clock is {}ms {} the server's; batch timestamps will look skewed",
+            skew_ms.unsigned_abs(),
+            if skew_ms > 0 { "behind" } else { "ahead of" }
+        );
+    } else if !exceeded && *warned {
+        println!("Local clock is back within {CLOCK_SKEW_WARN_MS}ms of the server's");
+    }
+    *warned = exceeded;
+}
+
+/// Sends a batch with retries; on success returns the estimated clock skew
+/// when the server reported its time. With `--batch-timeout-ms` the whole
+/// attempt, retries and throttle waits included, is cut off at that limit so
+/// a hanging server cannot stall the tail; the batch is then dropped like
+/// one that exhausted its retries, without advancing the chain. While the
+/// circuit is open it fails at once; see [`breaker`].
+async fn send_batch(
+    config: &AgentConfig,
+    throttle: &mut Throttle,
+    breaker: &mut Breaker,
+    metrics: &AgentMetrics,
+    batch: &LogBatch,
+) -> Result<Option<i64>> {
+    let sent = match config.batch_timeout_ms {
+        None => send_with_retries(config, throttle, breaker, metrics, batch).await,
+        Some(limit_ms) => timeout(
+            Duration::from_millis(limit_ms),
+            send_with_retries(config, throttle, breaker, metrics, batch),
+        )
+        .await
+        .map_err(|_| anyhow!("batch timed out after {limit_ms}ms"))
+        .and_then(|sent| sent),
+    };
+    match &sent {
+        Ok(_) => metrics.sent(batch.seq),
+        Err(_) => metrics.failed(),
+    }
+    sent
+}
+
+async fn send_with_retries(
+    config: &AgentConfig,
+    throttle: &mut Throttle,
+    breaker: &mut Breaker,
+    metrics: &AgentMetrics,
+    batch: &LogBatch,
+) -> Result<Option<i64>> {
     let client = reqwest::Client::new();
+    let body = config
+        .wire_format
+        .encode(batch)
+        .map_err(|err| anyhow!("cannot encode batch: {err}"))?;
+    let body = encode_upload(body, config)?;
+    let wire_bytes = match &config.grpc_url {
+        #[cfg(feature = "grpc")]
+        Some(_) => grpc::encoded_len(batch),
+        _ => body.bytes.len(),
+    };
     let mut attempt: u32 = 0;
+    // Attempts that count against `max_retries`; deferrals do not.
+    let mut spent: u32 = 0;
+    let cooldown = Duration::from_millis(config.breaker_cooldown_ms);
 
     loop {
+        breaker.admit(cooldown, metrics)?;
         attempt += 1;
-        let resp = client
-            .post(format!("{}/submit", config.server_url))
-            .json(batch)
-            .send()
-            .await;
+        if attempt > 1 {
+            metrics.retried();
+        }
+        // Every attempt uses the link, so retries are throttled too.
+        throttle.acquire(wire_bytes).await;
+        // One id per attempt, so the server's log line for it can be found.
+        let request_id = common::request_id::generate();
+        let sent_ms = Utc::now().timestamp_millis();
+        let outcome = match &config.grpc_url {
+            #[cfg(feature = "grpc")]
+            Some(url) => grpc::submit(url, batch, &request_id).await,
+            _ => submit_http(&client, config, &body, &request_id).await,
+        };
 
-        match resp {
-            Ok(r) if r.status().is_success() => {
-                println!("Batch sent successfully (attempt {})", attempt);
-                return Ok(());
+        match &outcome {
+            Attempt::Accepted(_) | Attempt::Deferred { .. } | Attempt::Clashed { .. } => {
+                breaker.succeeded(metrics)
+            }
+            Attempt::Rejected { .. } | Attempt::Failed(_) => {
+                breaker.failed(config.breaker_threshold, cooldown, metrics)
+            }
+        }
+        match outcome {
+            Attempt::Accepted(ack) => {
+                let received_ms = Utc::now().timestamp_millis();
+                println!(
+                    "Batch sent successfully (attempt {}, request {})",
+                    attempt, request_id
+                );
+                if let Some(receipt) = &ack.receipt {
+                    keep_ack(config, batch, receipt);
+                }
+                if config.spool
+                    && let Err(err) =
+                        spool::persist(&config.state_dir, batch, config.spool_key.as_ref())
+                {
+                    eprintln!("Could not spool seq {}: {err}", batch.seq);
+                }
+                if let Some(journal) = &config.journal_writer {
+                    journal.record(journal::Entry::new(batch, ack.receipt, received_ms as u64));
+                }
+                return Ok(ack
+                    .server_time_ms
+                    .map(|server_ms| clock_skew_ms(sent_ms, received_ms, server_ms)));
             }
-            Ok(r) => {
+            Attempt::Rejected { reason, code } => {
                 eprintln!(
-                    "Server rejected batch (attempt {}): status {}",
-                    attempt,
-                    r.status()
+                    "Server rejected batch (attempt {}, request {}): status {}",
+                    attempt, request_id, reason
                 );
+                if let Some(code) = code.filter(|code| deadletter::permanent(Some(code))) {
+                    return Err(dead_letter(config, metrics, batch, code, reason).into());
+                }
             }
-            Err(err) => {
-                eprintln!("Network error sending batch (attempt {}): {err}", attempt);
+            Attempt::Failed(err) => {
+                eprintln!(
+                    "Network error sending batch (attempt {}, request {}): {err}",
+                    attempt, request_id
+                );
+            }
+            Attempt::Deferred { wait, reason } => {
+                let wait = defer_wait(config, wait, spent);
+                eprintln!(
+                    "Server deferred batch seq {} (attempt {}, request {}): {reason}; holding it for {:?}",
+                    batch.seq, attempt, request_id, wait
+                );
+                metrics.deferred();
+                sleep(wait).await;
+                continue;
+            }
+            Attempt::Clashed { stored_hash } => {
+                eprintln!(
+                    "[seq-clash] the server already stores a different batch at seq {} (hash {stored_hash}, ours {}); \
+                     another agent is sending as {} with this key — a cloned host?",
+                    batch.seq,
+                    hex_encode(&batch.compute_hash()),
+                    batch.agent_id
+                );
+                return Err(SeqClash {
+                    seq: batch.seq,
+                    request_id,
+                }
+                .into());
             }
         }
 
-        if attempt >= config.max_retries {
+        spent += 1;
+        if spent >= config.max_retries {
             return Err(anyhow::anyhow!(
                 "exhausted retries after {} attempts",
                 attempt
             ));
         }
 
-        let backoff_ms = config.retry_base_ms.saturating_mul(1 << (attempt - 1));
-        sleep(Duration::from_millis(backoff_ms)).await;
+        sleep(backoff(config, spent)).await;
+    }
+}
+
+/// Keeps a batch the server rejected for good in the dead-letter dir, loudly;
+/// a failed write is only reported, as the batch is dropped either way.
+fn dead_letter(
+    config: &AgentConfig,
+    metrics: &AgentMetrics,
+    batch: &LogBatch,
+    code: String,
+    reason: String,
+) -> deadletter::DeadLettered {
+    metrics.dead_lettered();
+    let letter = deadletter::DeadLetter {
+        dead_lettered_at_ms: Utc::now().timestamp_millis() as u64,
+        code,
+        reason,
+        batch: batch.clone(),
+    };
+    match deadletter::persist(&config.state_dir, &letter) {
+        Ok(path) => eprintln!(
+            "[dead-letter] the server rejected batch seq {} for good ({}); kept in {} — not resending",
+            batch.seq,
+            letter.code,
+            path.display()
+        ),
+        Err(err) => eprintln!(
+            "[dead-letter] the server rejected batch seq {} for good ({}); could not keep it: {err}",
+            batch.seq, letter.code
+        ),
+    }
+    deadletter::DeadLettered {
+        seq: batch.seq,
+        code: letter.code,
+        reason: letter.reason,
+    }
+}
+
+/// A different batch is stored at our seq: resending cannot help.
+#[derive(Debug)]
+struct SeqClash {
+    seq: u64,
+    request_id: String,
+}
+
+impl std::fmt::Display for SeqClash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "seq {} is already stored with a different batch (request {})",
+            self.seq, self.request_id
+        )
+    }
+}
+
+impl std::error::Error for SeqClash {}
+
+/// [`send_batch`] under the `--backpressure` policy: a batch that failed is
+/// returned as failed, unless the policy holds it; then it is resent until
+/// the server takes it. `None` when `shutdown` came while it was held. A
+/// seq clash, a dead-lettered batch or a replaced key is never held. A batch the open circuit kept
+/// back is resent when the server may be probed.
+#[allow(clippy::too_many_arguments)]
+async fn send_or_hold(
+    config: &AgentConfig,
+    throttle: &mut Throttle,
+    breaker: &mut Breaker,
+    metrics: &AgentMetrics,
+    inflight: &Inflight,
+    backpressure: &mut Backpressure,
+    key: &ed25519_dalek::SigningKey,
+    batch: &LogBatch,
+    shutdown: &mut (impl std::future::Future<Output = ()> + Unpin),
+) -> Option<Result<Option<i64>>> {
+    loop {
+        let permit = inflight.acquire().await;
+        let sent = send_batch(config, throttle, breaker, metrics, batch).await;
+        drop(permit);
+        let err = match &sent {
+            Ok(_) => {
+                backpressure.sent(metrics);
+                return Some(sent);
+            }
+            Err(err) => err,
+        };
+        if err.is::<SeqClash>()
+            || err.is::<deadletter::DeadLettered>()
+            || !backpressure.hold(config.backpressure, config.pause_after_failures, metrics)
+            || check_key(config, key).is_err()
+        {
+            return Some(sent);
+        }
+        let wait = match err.downcast_ref::<CircuitOpen>() {
+            Some(open) => open.retry_in,
+            None => backpressure.probe_wait(config.retry_base_ms, config.pause_after_failures),
+        };
+        eprintln!(
+            "[backpressure] holding batch seq {} ({err}); resending in {wait:?}",
+            batch.seq
+        );
+        tokio::select! {
+            _ = sleep(wait) => {}
+            _ = &mut *shutdown => return None,
+        }
+    }
+}
+
+/// The wait before retry `spent + 1` of a batch.
+fn backoff(config: &AgentConfig, spent: u32) -> Duration {
+    Duration::from_millis(config.retry_base_ms.saturating_mul(1 << (spent - 1)))
+}
+
+/// How long to hold a batch the server deferred: its `Retry-After`, or the
+/// next backoff when it sent none, within `retry_base_ms` and
+/// `MAX_DEFER_WAIT`.
+fn defer_wait(config: &AgentConfig, retry_after: Option<Duration>, spent: u32) -> Duration {
+    retry_after
+        .unwrap_or_else(|| backoff(config, spent + 1))
+        .clamp(Duration::from_millis(config.retry_base_ms), MAX_DEFER_WAIT)
+}
+
+/// Keeps the receipt for an accepted batch in the acks dir. The batch is
+/// stored either way, so a receipt that does not fit it, or a failed write,
+/// is only reported.
+fn keep_ack(config: &AgentConfig, batch: &LogBatch, receipt: &Receipt) {
+    if let Some(field) = acks::mismatch(receipt, batch) {
+        eprintln!(
+            "Server receipt for seq {} has the wrong {field}; not kept",
+            batch.seq
+        );
+        return;
+    }
+    if let Err(err) = acks::persist(&config.state_dir, receipt) {
+        eprintln!("Could not keep the receipt for seq {}: {err}", batch.seq);
+    }
+}
+
+/// Where the next batch goes, as `(epoch, seq, epoch start)`: seq
+/// `next_seq` of `epoch`, or, once `epoch` holds `--epoch-max-seq` batches or
+/// started `--epoch-max-age-secs` before `now_ms`, seq 1 of the next epoch,
+/// linked to this one's last seq. An epoch always holds at least one batch.
+fn next_position(
+    config: &AgentConfig,
+    epoch: u64,
+    started_ms: u64,
+    next_seq: u64,
+    now_ms: u64,
+) -> (u64, u64, Option<EpochStart>) {
+    let full = config.epoch_max_seq.is_some_and(|max| next_seq > max);
+    let old = config
+        .epoch_max_age_secs
+        .is_some_and(|secs| now_ms.saturating_sub(started_ms) >= secs.saturating_mul(1000));
+    if next_seq > 1 && (full || old) {
+        let start = EpochStart {
+            previous_last_seq: next_seq - 1,
+        };
+        (epoch + 1, 1, Some(start))
+    } else {
+        (epoch, next_seq, None)
+    }
+}
+
+/// The gap marker for `--allow-gap`: local state's next batch is seq
+/// `local_next` of `local_epoch`, the server's last is `server`'s (none
+/// stored at all when `None`). `None` unless the server is behind in the
+/// same epoch; a gap cannot span an epoch start. The marker takes seq
+/// `local_next` and links to the server's last batch, so only the missing
+/// seqs are skipped.
+fn gap_marker(
+    config: &AgentConfig,
+    key: &ed25519_dalek::SigningKey,
+    (local_epoch, local_next): (u64, u64),
+    server: Option<&Checkpoint>,
+    lines_read: u64,
+) -> Option<LogBatch> {
+    let (server_epoch, server_last, prev_hash, prev_accumulator) = match server {
+        Some(cp) => (
+            cp.last_epoch,
+            cp.last_seq,
+            cp.last_hash,
+            cp.last_accumulator,
+        ),
+        None => (0, 0, [0u8; 32], None),
+    };
+    if local_epoch != server_epoch || local_next <= server_last + 1 {
+        return None;
+    }
+    let mut batch = LogBatch {
+        prev_hash,
+        logs: Vec::new(),
+        timestamp: batch_timestamp(config.batch_version, Utc::now().timestamp_millis() as u64),
+        agent_id: config.agent_id.clone(),
+        seq: local_next,
+        signature: Signature::from_bytes(&[0u8; 64]),
+        public_key: key.verifying_key(),
+        lines_read: config.count_lines.then_some(lines_read),
+        version: config.batch_version,
+        accumulator: None,
+        gap: Some(GapRecord {
+            missing_from: server_last + 1,
+            missing_to: local_next - 1,
+            reason: format!(
+                "agent state counted seq {} as sent; server holds up to seq {}",
+                local_next - 1,
+                server_last
+            ),
+        }),
+        epoch: server_epoch,
+        epoch_start: None,
+        kind: None,
+    };
+    producer::seal(&mut batch, key, prev_accumulator.as_ref());
+    Some(batch)
+}
+
+/// The session start for `--batch-header`, carrying a fresh session id and
+/// the run's `boot_time_ms`; see [`kind_batch`].
+fn session_start(
+    config: &AgentConfig,
+    key: &ed25519_dalek::SigningKey,
+    head: &Checkpoint,
+    epoch_started_ms: u64,
+    lines_read: u64,
+    boot_time_ms: u64,
+) -> LogBatch {
+    let kind = BatchKind::SessionStart {
+        session_id: common::request_id::generate(),
+        boot_time_ms,
+    };
+    kind_batch(config, key, head, epoch_started_ms, lines_read, kind)
+}
+
+/// A batch without logs right after `head`, the chain's last batch, that
+/// records `kind`. It takes the position an ordinary batch would, so it can
+/// open a new epoch.
+fn kind_batch(
+    config: &AgentConfig,
+    key: &ed25519_dalek::SigningKey,
+    head: &Checkpoint,
+    epoch_started_ms: u64,
+    lines_read: u64,
+    kind: BatchKind,
+) -> LogBatch {
+    let timestamp = Utc::now().timestamp_millis() as u64;
+    let (epoch, seq, epoch_start) = next_position(
+        config,
+        head.last_epoch,
+        epoch_started_ms,
+        head.last_seq + 1,
+        timestamp,
+    );
+    let mut batch = LogBatch {
+        prev_hash: head.last_hash,
+        logs: Vec::new(),
+        timestamp: batch_timestamp(config.batch_version, timestamp),
+        agent_id: config.agent_id.clone(),
+        seq,
+        signature: Signature::from_bytes(&[0u8; 64]),
+        public_key: key.verifying_key(),
+        lines_read: config.count_lines.then_some(lines_read),
+        version: config.batch_version,
+        accumulator: None,
+        gap: None,
+        epoch,
+        epoch_start,
+        kind: Some(kind),
+    };
+    producer::seal(&mut batch, key, head.last_accumulator.as_ref());
+    batch
+}
+
+/// A batch `timestamp` of `version`: unix seconds for v1, milliseconds
+/// after it.
+fn batch_timestamp(version: u32, timestamp_ms: u64) -> u64 {
+    if version == BATCH_VERSION_V1 {
+        timestamp_ms / 1000
+    } else {
+        timestamp_ms
+    }
+}
+
+/// The `GET /version` handshake (see [`common::compat`]): fails fast when
+/// the server cannot take what this agent `produces`, or signs acks with a
+/// scheme it cannot verify. An unreachable server, or one that predates the
+/// handshake, is let through; gRPC submits are not checked.
+async fn check_compat(config: &AgentConfig, produces: Option<Produces>) -> Result<()> {
+    if config.skip_compat_check {
+        println!("Compatibility check skipped (--skip-compat-check)");
+        return Ok(());
+    }
+    if config.grpc_url.is_some() {
+        return Ok(());
+    }
+    let caps = match compat::fetch(&reqwest::Client::new(), &config.server_url).await {
+        Ok(Some(caps)) => caps,
+        Ok(None) => {
+            println!("Server predates GET /version; compatibility not checked");
+            return Ok(());
+        }
+        Err(err) => {
+            eprintln!("Compatibility not checked: {err}");
+            return Ok(());
+        }
+    };
+    compat::check_reader(&caps, "agent")
+        .and_then(|()| produces.map_or(Ok(()), |produces| compat::check_producer(&caps, produces)))
+        .map_err(|err| anyhow!("incompatible server {}: {err}", caps.server_version))
+}
+
+/// How one submit attempt ended, whatever the transport.
+enum Attempt {
+    /// With the server's clock and receipt when it sent them.
+    Accepted(SubmitAck),
+    /// With the status and message, and the server's machine-readable
+    /// `code` when it sent one; see [`deadletter::permanent`].
+    Rejected {
+        reason: String,
+        code: Option<String>,
+    },
+    Failed(String),
+    /// The server asked for the batch later: a rate limit (429), or
+    /// maintenance mode (503 with `Retry-After`). It does not use a retry.
+    Deferred {
+        wait: Option<Duration>,
+        reason: String,
+    },
+    /// A different batch already holds this seq, with this hex hash: another
+    /// copy of the agent is sending with the same key. Retrying cannot help.
+    Clashed {
+        stored_hash: String,
+    },
+}
+
+/// A `/submit` body in `--wire-format`, gzipped when that was worth it.
+struct Upload {
+    bytes: Vec<u8>,
+    content_type: &'static str,
+    gzipped: bool,
+}
+
+/// Gzips `body`, a batch in `config.wire_format`, with `--gzip-uploads` when the result is at least
+/// `gzip_min_saving_pct` percent smaller. Small or already random-looking
+/// batches barely shrink, and then cost the server an inflate for nothing.
+fn encode_upload(body: Vec<u8>, config: &AgentConfig) -> Result<Upload> {
+    use flate2::{Compression, write::GzEncoder};
+    use std::io::Write;
+
+    let content_type = config.wire_format.content_type();
+    if !config.gzip_uploads {
+        return Ok(Upload {
+            bytes: body,
+            content_type,
+            gzipped: false,
+        });
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&body)?;
+    let gzipped = encoder.finish()?;
+    let keep_pct = 100 - u64::from(config.gzip_min_saving_pct);
+    if gzipped.len() as u64 * 100 <= body.len() as u64 * keep_pct {
+        Ok(Upload {
+            bytes: gzipped,
+            content_type,
+            gzipped: true,
+        })
+    } else {
+        Ok(Upload {
+            bytes: body,
+            content_type,
+            gzipped: false,
+        })
+    }
+}
+
+/// Whether a `/submit` answer asks for the batch later: always for 429, and
+/// for a 503 that says when (maintenance mode). A bare 503 is a fault.
+fn deferred(status: reqwest::StatusCode, headers: &reqwest::header::HeaderMap) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || (status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+            && headers.contains_key(reqwest::header::RETRY_AFTER))
+}
+
+async fn submit_http(
+    client: &reqwest::Client,
+    config: &AgentConfig,
+    body: &Upload,
+    request_id: &str,
+) -> Attempt {
+    let mut request = client
+        .post(format!("{}/submit", config.server_url))
+        .header(reqwest::header::CONTENT_TYPE, body.content_type)
+        .header(common::request_id::HEADER, request_id);
+    if body.gzipped {
+        request = request.header(reqwest::header::CONTENT_ENCODING, "gzip");
+    }
+    let resp = request.body(body.bytes.clone()).send().await;
+    match resp {
+        Ok(r) if r.status().is_success() => {
+            let ack = r.json::<SubmitAck>().await.unwrap_or_default();
+            Attempt::Accepted(ack)
+        }
+        Ok(r) if deferred(r.status(), r.headers()) => {
+            let wait = r
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+                .map(Duration::from_secs);
+            let status = r.status();
+            let message = r
+                .json::<serde_json::Value>()
+                .await
+                .ok()
+                .and_then(|body| body["message"].as_str().map(str::to_string));
+            Attempt::Deferred {
+                wait,
+                reason: message
+                    .map_or(status.to_string(), |message| format!("{status}: {message}")),
+            }
+        }
+        Ok(r) if r.status() == reqwest::StatusCode::CONFLICT => {
+            let status = r.status();
+            let body = r.json::<serde_json::Value>().await.ok();
+            let stored_hash = body
+                .as_ref()
+                .and_then(|body| body["stored_hash"].as_str().map(str::to_string));
+            match stored_hash {
+                Some(stored_hash) => Attempt::Clashed { stored_hash },
+                None => rejected(status, body),
+            }
+        }
+        Ok(r) => {
+            let status = r.status();
+            rejected(status, r.json().await.ok())
+        }
+        Err(err) => Attempt::Failed(err.to_string()),
+    }
+}
+
+/// A rejection with `status`, and the message and code of its JSON `body`.
+fn rejected(status: reqwest::StatusCode, body: Option<serde_json::Value>) -> Attempt {
+    let field = |name: &str| {
+        body.as_ref()
+            .and_then(|body| body[name].as_str().map(str::to_string))
+    };
+    Attempt::Rejected {
+        reason: field("message")
+            .map_or(status.to_string(), |message| format!("{status}: {message}")),
+        code: field("code"),
     }
 }
 
 struct AgentConfig {
-    log_path: PathBuf,
+    /// The source of an agent without `[sources.<name>]` tables; with them,
+    /// the first table's.
+    source: SourceSpec,
+    /// The `[sources.<name>]` tables, in file order; see [`AgentConfig::sources`].
+    source_tables: Vec<SourceOverrides>,
     server_url: String,
+    /// Set by `--grpc-url`: batches and the checkpoint go over gRPC instead.
+    grpc_url: Option<String>,
     state_dir: PathBuf,
     agent_id: String,
     max_retries: u32,
     retry_base_ms: u64,
+    batch_timeout_ms: Option<u64>,
+    /// Lines per batch.
+    batch_size: usize,
+    /// Send a batch that is not full once its oldest line is this old.
+    flush_interval_ms: Option<u64>,
+    /// Line filters; see [`SourceConfig::keeps`].
+    include: Option<String>,
+    exclude: Option<String>,
+    count_lines: bool,
+    /// Declare seqs the server lacks lost at startup; see [`gap_marker`].
+    allow_gap: bool,
+    max_line_bytes: usize,
+    max_bytes_per_sec: Option<u64>,
+    burst_bytes: Option<u64>,
+    max_batches_per_sec: Option<f64>,
+    burst_batches: Option<f64>,
+    /// Keep every accepted batch for `--re-anchor`; see [`spool`].
+    spool: bool,
+    /// Encrypt the spool files at rest.
+    spool_encrypt: bool,
+    /// The spool's key, derived by `main` when `spool_encrypt` is set.
+    spool_key: Option<spool::SpoolKey>,
+    /// Submits in flight at once across chains; see [`inflight`].
+    max_inflight: usize,
+    /// Gzip HTTP uploads that shrink by at least `gzip_min_saving_pct`
+    /// percent; see [`encode_upload`].
+    gzip_uploads: bool,
+    gzip_min_saving_pct: u8,
+    /// Pushgateway to push [`metrics`] to; see [`metrics::group_url`].
+    metrics_push_url: Option<String>,
+    metrics_push_interval_secs: u64,
+    /// Start a new chain epoch once the current one holds this many batches
+    /// or is this old; see [`next_position`].
+    epoch_max_seq: Option<u64>,
+    epoch_max_age_secs: Option<u64>,
+    /// Record shipped batches for `--local-log`, within these caps; see
+    /// [`journal`].
+    journal: bool,
+    journal_max_bytes: u64,
+    journal_max_age_secs: u64,
+    /// The journal's writer, started by `main` when `journal` is set.
+    journal_writer: Option<journal::Journal>,
+    /// What follows a dead-lettered batch; see [`deadletter`].
+    dead_letter_policy: deadletter::Policy,
+    /// What a failed batch does to reading; see [`backpressure`].
+    backpressure: backpressure::Policy,
+    pause_after_failures: u32,
+    /// Failed attempts in a row that open the circuit (0: never), and for
+    /// how long; see [`breaker`].
+    breaker_threshold: u32,
+    breaker_cooldown_ms: u64,
+    /// How `/submit` bodies are encoded; see [`WireFormat`].
+    wire_format: WireFormat,
+    /// Open each run with a session start; see [`session_start`].
+    batch_header: bool,
+    /// Version new batches are produced with; an older one only for a
+    /// server that takes nothing newer (see [`check_compat`]).
+    batch_version: u32,
+    skip_compat_check: bool,
 }
 
+/// Kept after startup: a config reload re-resolves settings with the same flags.
+#[derive(Clone, Default)]
 struct AgentArgs {
+    config_path: Option<PathBuf>,
+    config_reload: bool,
+    batch_size: Option<usize>,
+    flush_interval_ms: Option<u64>,
+    include: Option<String>,
+    exclude: Option<String>,
     log_path: Option<PathBuf>,
+    log_dir: Option<PathBuf>,
+    file_pattern: Option<String>,
+    file_order: Option<String>,
+    source: Option<String>,
     server_url: Option<String>,
+    grpc_url: Option<String>,
     state_dir: Option<PathBuf>,
     max_retries: Option<u32>,
     retry_base_ms: Option<u64>,
+    batch_timeout_ms: Option<u64>,
+    count_lines: bool,
+    allow_gap: bool,
+    max_line_bytes: Option<usize>,
+    max_bytes_per_sec: Option<u64>,
+    burst_bytes: Option<u64>,
+    max_batches_per_sec: Option<f64>,
+    burst_batches: Option<f64>,
+    spool: bool,
+    spool_encrypt: bool,
+    check_spool: bool,
+    max_inflight: Option<usize>,
+    gzip_uploads: bool,
+    gzip_min_saving_pct: Option<u8>,
+    metrics_push_url: Option<String>,
+    metrics_push_interval_secs: Option<u64>,
+    epoch_max_seq: Option<u64>,
+    epoch_max_age_secs: Option<u64>,
+    /// Check the kept acks against the server and exit; see [`acks`].
+    verify_acks: bool,
+    journal: bool,
+    journal_max_bytes: Option<u64>,
+    journal_max_age_secs: Option<u64>,
+    /// Print the journal and exit; see [`journal`].
+    local_log: bool,
+    since: Option<String>,
+    grep: Option<String>,
+    dead_letter_policy: Option<String>,
+    backpressure: Option<String>,
+    pause_after_failures: Option<u32>,
+    breaker_threshold: Option<u32>,
+    breaker_cooldown_ms: Option<u64>,
+    wire_format: Option<String>,
+    /// Start the chain over on a fresh server and exit; see [`reanchor`].
+    re_anchor: bool,
+    /// Close the chain for good and exit; see [`finalize`].
+    finalize: bool,
+    /// Hand the agent over to a new key and exit; see [`rotate`].
+    rotate_key: bool,
+    reason: Option<String>,
+    confirm: bool,
+    batch_header: bool,
+    batch_version: Option<u32>,
+    skip_compat_check: bool,
+    /// Print the build and what it produces, then exit.
+    version: bool,
 }
 
 impl AgentArgs {
     fn parse() -> Self {
+        let mut config_path = None;
+        let mut config_reload = false;
+        let mut batch_size = None;
+        let mut flush_interval_ms = None;
+        let mut include = None;
+        let mut exclude = None;
         let mut log_path = None;
+        let mut log_dir = None;
+        let mut file_pattern = None;
+        let mut file_order = None;
+        let mut source = None;
         let mut server_url = None;
+        let mut grpc_url = None;
         let mut state_dir = None;
         let mut max_retries = None;
         let mut retry_base_ms = None;
+        let mut batch_timeout_ms = None;
+        let mut count_lines = false;
+        let mut allow_gap = false;
+        let mut max_line_bytes = None;
+        let mut max_bytes_per_sec = None;
+        let mut burst_bytes = None;
+        let mut max_batches_per_sec = None;
+        let mut burst_batches = None;
+        let mut spool = false;
+        let mut spool_encrypt = false;
+        let mut check_spool = false;
+        let mut max_inflight = None;
+        let mut gzip_uploads = false;
+        let mut gzip_min_saving_pct = None;
+        let mut metrics_push_url = None;
+        let mut metrics_push_interval_secs = None;
+        let mut epoch_max_seq = None;
+        let mut epoch_max_age_secs = None;
+        let mut verify_acks = false;
+        let mut journal = false;
+        let mut journal_max_bytes = None;
+        let mut journal_max_age_secs = None;
+        let mut local_log = false;
+        let mut since = None;
+        let mut grep = None;
+        let mut dead_letter_policy = None;
+        let mut backpressure = None;
+        let mut pause_after_failures = None;
+        let mut breaker_threshold = None;
+        let mut breaker_cooldown_ms = None;
+        let mut wire_format = None;
+        let mut re_anchor = false;
+        let mut finalize = false;
+        let mut rotate_key = false;
+        let mut reason = None;
+        let mut confirm = false;
+        let mut batch_header = false;
+        let mut batch_version = None;
+        let mut skip_compat_check = false;
+        let mut version = false;
 
         let mut args = env::args().skip(1);
         while let Some(arg) = args.next() {
             match arg.as_str() {
+                "--config" => {
+                    if let Some(v) = args.next() {
+                        config_path = Some(PathBuf::from(v));
+                    }
+                }
+                "--config-reload" => config_reload = true,
+                "--batch-size" => {
+                    if let Some(v) = args.next() {
+                        batch_size = v.parse().ok();
+                    }
+                }
                 "--log-path" => {
                     if let Some(v) = args.next() {
                         log_path = Some(PathBuf::from(v));
                     }
                 }
+                "--flush-interval-ms" => {
+                    if let Some(v) = args.next() {
+                        flush_interval_ms = v.parse().ok();
+                    }
+                }
+                "--include" => {
+                    if let Some(v) = args.next() {
+                        include = Some(v);
+                    }
+                }
+                "--exclude" => {
+                    if let Some(v) = args.next() {
+                        exclude = Some(v);
+                    }
+                }
+                "--log-dir" => {
+                    if let Some(v) = args.next() {
+                        log_dir = Some(PathBuf::from(v));
+                    }
+                }
+                "--file-pattern" => {
+                    if let Some(v) = args.next() {
+                        file_pattern = Some(v);
+                    }
+                }
+                "--file-order" => {
+                    if let Some(v) = args.next() {
+                        file_order = Some(v);
+                    }
+                }
+                "--source" => {
+                    if let Some(v) = args.next() {
+                        source = Some(v);
+                    }
+                }
                 "--server-url" => {
                     if let Some(v) = args.next() {
                         server_url = Some(v);
                     }
                 }
+                "--grpc-url" => {
+                    if let Some(v) = args.next() {
+                        grpc_url = Some(v);
+                    }
+                }
                 "--state-dir" => {
                     if let Some(v) = args.next() {
                         state_dir = Some(PathBuf::from(v));
@@ -210,62 +1574,878 @@ impl AgentArgs {
                         retry_base_ms = v.parse().ok();
                     }
                 }
-                _ => {}
-            }
-        }
-
-        Self {
-            log_path,
-            server_url,
-            state_dir,
-            max_retries,
-            retry_base_ms,
-        }
-    }
-}
-
-impl AgentConfig {
-    fn load(args: AgentArgs) -> Result<Self> {
-        let home = env::var("HOME")
-            .map(PathBuf::from)
-            .unwrap_or_else(|_| PathBuf::from("."));
-        let state_dir = args
-            .state_dir
-            .or_else(|| env::var("AGENT_STATE_DIR").ok().map(PathBuf::from))
-            .unwrap_or_else(|| home.join(".logagent"));
-        fs::create_dir_all(&state_dir)?;
-
-        let log_path = args
-            .log_path
-            .or_else(|| env::var("AGENT_LOG_PATH").ok().map(PathBuf::from))
-            .unwrap_or_else(|| PathBuf::from("/var/log/dpkg.log"));
-
+                "--batch-timeout-ms" => {
+                    if let Some(v) = args.next() {
+                        batch_timeout_ms = v.parse().ok();
+                    }
+                }
+                "--count-lines" => count_lines = true,
+                "--allow-gap" => allow_gap = true,
+                "--max-line-bytes" => {
+                    if let Some(v) = args.next() {
+                        max_line_bytes = v.parse().ok();
+                    }
+                }
+                "--max-bytes-per-sec" => {
+                    if let Some(v) = args.next() {
+                        max_bytes_per_sec = v.parse().ok();
+                    }
+                }
+                "--burst-bytes" => {
+                    if let Some(v) = args.next() {
+                        burst_bytes = v.parse().ok();
+                    }
+                }
+                "--max-batches-per-sec" => {
+                    if let Some(v) = args.next() {
+                        max_batches_per_sec = v.parse().ok();
+                    }
+                }
+                "--burst-batches" => {
+                    if let Some(v) = args.next() {
+                        burst_batches = v.parse().ok();
+                    }
+                }
+                "--spool" => spool = true,
+                "--spool-encrypt" => spool_encrypt = true,
+                "--check-spool" => check_spool = true,
+                "--max-inflight" => {
+                    if let Some(v) = args.next() {
+                        max_inflight = v.parse().ok();
+                    }
+                }
+                "--gzip-uploads" => gzip_uploads = true,
+                "--gzip-min-saving-pct" => {
+                    if let Some(v) = args.next() {
+                        gzip_min_saving_pct = v.parse().ok();
+                    }
+                }
+                "--metrics-push-url" => {
+                    if let Some(v) = args.next() {
+                        metrics_push_url = Some(v);
+                    }
+                }
+                "--metrics-push-interval-secs" => {
+                    if let Some(v) = args.next() {
+                        metrics_push_interval_secs = v.parse().ok();
+                    }
+                }
+                "--epoch-max-seq" => {
+                    if let Some(v) = args.next() {
+                        epoch_max_seq = v.parse().ok();
+                    }
+                }
+                "--epoch-max-age-secs" => {
+                    if let Some(v) = args.next() {
+                        epoch_max_age_secs = v.parse().ok();
+                    }
+                }
+                "--verify-acks" => verify_acks = true,
+                "--journal" => journal = true,
+                "--journal-max-bytes" => {
+                    if let Some(v) = args.next() {
+                        journal_max_bytes = v.parse().ok();
+                    }
+                }
+                "--journal-max-age-secs" => {
+                    if let Some(v) = args.next() {
+                        journal_max_age_secs = v.parse().ok();
+                    }
+                }
+                "--local-log" => local_log = true,
+                "--since" => since = args.next(),
+                "--grep" => grep = args.next(),
+                "--dead-letter-policy" => dead_letter_policy = args.next(),
+                "--backpressure" => {
+                    if let Some(v) = args.next() {
+                        backpressure = Some(v);
+                    }
+                }
+                "--pause-after-failures" => {
+                    if let Some(v) = args.next() {
+                        pause_after_failures = v.parse().ok();
+                    }
+                }
+                "--breaker-threshold" => {
+                    if let Some(v) = args.next() {
+                        breaker_threshold = v.parse().ok();
+                    }
+                }
+                "--breaker-cooldown-ms" => {
+                    if let Some(v) = args.next() {
+                        breaker_cooldown_ms = v.parse().ok();
+                    }
+                }
+                "--wire-format" => {
+                    if let Some(v) = args.next() {
+                        wire_format = Some(v);
+                    }
+                }
+                "--re-anchor" => re_anchor = true,
+                "--finalize" => finalize = true,
+                "--rotate-key" => rotate_key = true,
+                "--reason" => reason = args.next(),
+                "--confirm" => confirm = true,
+                "--batch-header" => batch_header = true,
+                "--batch-version" => {
+                    if let Some(v) = args.next() {
+                        batch_version = v.parse().ok();
+                    }
+                }
+                "--skip-compat-check" => skip_compat_check = true,
+                "--version" | "-V" => version = true,
+                _ => {}
+            }
+        }
+
+        Self {
+            config_path,
+            config_reload,
+            batch_size,
+            flush_interval_ms,
+            include,
+            exclude,
+            log_path,
+            log_dir,
+            file_pattern,
+            file_order,
+            source,
+            server_url,
+            grpc_url,
+            state_dir,
+            max_retries,
+            retry_base_ms,
+            batch_timeout_ms,
+            count_lines,
+            allow_gap,
+            max_line_bytes,
+            max_bytes_per_sec,
+            burst_bytes,
+            max_batches_per_sec,
+            burst_batches,
+            spool,
+            spool_encrypt,
+            check_spool,
+            max_inflight,
+            gzip_uploads,
+            gzip_min_saving_pct,
+            metrics_push_url,
+            metrics_push_interval_secs,
+            epoch_max_seq,
+            epoch_max_age_secs,
+            verify_acks,
+            journal,
+            journal_max_bytes,
+            journal_max_age_secs,
+            local_log,
+            since,
+            grep,
+            dead_letter_policy,
+            backpressure,
+            pause_after_failures,
+            breaker_threshold,
+            breaker_cooldown_ms,
+            wire_format,
+            re_anchor,
+            finalize,
+            rotate_key,
+            reason,
+            confirm,
+            batch_header,
+            batch_version,
+            skip_compat_check,
+            version,
+        }
+    }
+}
+
+impl AgentConfig {
+    /// Flags win over env vars, which win over the `--config` file.
+    fn load(args: &AgentArgs) -> Result<Self> {
+        let config_path = args
+            .config_path
+            .clone()
+            .or_else(|| env::var("AGENT_CONFIG").ok().map(PathBuf::from));
+        let file = match &config_path {
+            Some(path) => ConfigFile::load(path)?,
+            None => ConfigFile::default(),
+        };
+
+        let state_dir = match args
+            .state_dir
+            .clone()
+            .or_else(|| env::var("AGENT_STATE_DIR").ok().map(PathBuf::from))
+            .or(file.get("state_dir")?)
+        {
+            Some(dir) => dir,
+            None => platform::default_state_dir()?,
+        };
+        platform::create_private_dir(&state_dir)?;
+
+        let log_path: Option<PathBuf> = args
+            .log_path
+            .clone()
+            .or_else(|| env::var("AGENT_LOG_PATH").ok().map(PathBuf::from))
+            .or(file.get("log_path")?);
+        let log_dir: Option<PathBuf> = args
+            .log_dir
+            .clone()
+            .or_else(|| env::var("AGENT_LOG_DIR").ok().map(PathBuf::from))
+            .or(file.get("log_dir")?);
+        let file_pattern = args
+            .file_pattern
+            .clone()
+            .or_else(|| env::var("AGENT_FILE_PATTERN").ok())
+            .or(file.get("file_pattern")?)
+            .unwrap_or_else(|| "*".to_string());
+        let file_order = match args
+            .file_order
+            .clone()
+            .or_else(|| env::var("AGENT_FILE_ORDER").ok())
+            .or(file.get("file_order")?)
+        {
+            Some(order) => FileOrder::parse(&order)
+                .ok_or_else(|| anyhow!("--file-order must be mtime or name, not '{order}'"))?,
+            None => FileOrder::Mtime,
+        };
+        let named = args
+            .source
+            .clone()
+            .or_else(|| env::var("AGENT_SOURCE").ok())
+            .or(file.get("source")?)
+            .map(|v: String| SourceSpec::parse(&v))
+            .or(log_dir.map(|dir| {
+                SourceSpec::Dir(DirSpec {
+                    dir,
+                    pattern: file_pattern,
+                    order: file_order,
+                })
+            }))
+            .or(log_path.map(SourceSpec::File));
+
+        let source_tables = file
+            .sources()
+            .iter()
+            .map(|table| {
+                Ok(SourceOverrides {
+                    name: table.name.clone(),
+                    spec: SourceSpec::parse(
+                        &table.get::<String>("source")?.expect("checked on parse"),
+                    ),
+                    batch_size: table.get("batch_size")?,
+                    flush_interval_ms: table.get("flush_interval_ms")?,
+                    include: table.get("include")?,
+                    exclude: table.get("exclude")?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let source = match (named, source_tables.first()) {
+            (Some(named), Some(_)) => {
+                return Err(anyhow!(
+                    "{named} is set as well as [sources.<name>] tables; name every source in a table"
+                ));
+            }
+            (Some(named), None) => named,
+            (None, Some(first)) => first.spec.clone(),
+            (None, None) => platform::default_log_path(env::consts::OS)
+                .map(SourceSpec::File)
+                .ok_or_else(|| {
+                    anyhow!("no default log on this platform; set --log-path or --source")
+                })?,
+        };
+
         let server_url = args
             .server_url
+            .clone()
             .or_else(|| env::var("AGENT_SERVER_URL").ok())
+            .or(file.get("server_url")?)
             .unwrap_or_else(|| "http://127.0.0.1:3000".to_string());
 
+        let grpc_url = args
+            .grpc_url
+            .clone()
+            .or_else(|| env::var("AGENT_GRPC_URL").ok())
+            .or(file.get("grpc_url")?);
+        if grpc_url.is_some() && !cfg!(feature = "grpc") {
+            return Err(anyhow!(
+                "--grpc-url needs an agent built with the grpc feature"
+            ));
+        }
+
         let max_retries = args
             .max_retries
-            .or_else(|| env::var("AGENT_MAX_RETRIES").ok().and_then(|v| v.parse().ok()))
+            .or_else(|| {
+                env::var("AGENT_MAX_RETRIES")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("max_retries")?)
             .unwrap_or(5);
 
         let retry_base_ms = args
             .retry_base_ms
-            .or_else(|| env::var("AGENT_RETRY_BASE_MS").ok().and_then(|v| v.parse().ok()))
+            .or_else(|| {
+                env::var("AGENT_RETRY_BASE_MS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("retry_base_ms")?)
             .unwrap_or(500);
 
-        let key_path = Self::key_path(&state_dir);
-        let agent_id = derive_agent_id(&key_path)?;
+        // Unset or 0: no cap beyond the retry schedule.
+        let batch_timeout_ms = args
+            .batch_timeout_ms
+            .or_else(|| {
+                env::var("AGENT_BATCH_TIMEOUT_MS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("batch_timeout_ms")?)
+            .filter(|ms| *ms > 0);
 
-        Ok(Self {
-            log_path,
+        let batch_size = args
+            .batch_size
+            .or_else(|| {
+                env::var("AGENT_BATCH_SIZE")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("batch_size")?)
+            .unwrap_or(DEFAULT_BATCH_SIZE)
+            .max(1);
+        // Unset or 0: a batch waits until it is full.
+        let flush_interval_ms = args
+            .flush_interval_ms
+            .or_else(|| {
+                env::var("AGENT_FLUSH_INTERVAL_MS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("flush_interval_ms")?)
+            .filter(|ms| *ms > 0);
+        let include = args
+            .include
+            .clone()
+            .or_else(|| env::var("AGENT_INCLUDE").ok())
+            .or(file.get("include")?);
+        let exclude = args
+            .exclude
+            .clone()
+            .or_else(|| env::var("AGENT_EXCLUDE").ok())
+            .or(file.get("exclude")?);
+
+        let count_lines = args.count_lines
+            || env::var("AGENT_COUNT_LINES")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false)
+            || file.get("count_lines")?.unwrap_or(false);
+
+        let allow_gap = args.allow_gap
+            || env::var("AGENT_ALLOW_GAP")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false)
+            || file.get("allow_gap")?.unwrap_or(false);
+
+        let max_line_bytes = args
+            .max_line_bytes
+            .or_else(|| {
+                env::var("AGENT_MAX_LINE_BYTES")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("max_line_bytes")?)
+            .unwrap_or(DEFAULT_MAX_LINE_BYTES);
+
+        let max_bytes_per_sec = args
+            .max_bytes_per_sec
+            .or_else(|| {
+                env::var("AGENT_MAX_BYTES_PER_SEC")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("max_bytes_per_sec")?);
+        let burst_bytes = args
+            .burst_bytes
+            .or_else(|| {
+                env::var("AGENT_BURST_BYTES")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("burst_bytes")?);
+        let max_batches_per_sec = args
+            .max_batches_per_sec
+            .or_else(|| {
+                env::var("AGENT_MAX_BATCHES_PER_SEC")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("max_batches_per_sec")?);
+        let burst_batches = args
+            .burst_batches
+            .or_else(|| {
+                env::var("AGENT_BURST_BATCHES")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("burst_batches")?);
+
+        let spool = args.spool
+            || env::var("AGENT_SPOOL")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false)
+            || file.get("spool")?.unwrap_or(false);
+        let spool_encrypt = args.spool_encrypt
+            || env::var("AGENT_SPOOL_ENCRYPT")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false)
+            || file.get("spool_encrypt")?.unwrap_or(false);
+        let max_inflight = args
+            .max_inflight
+            .or_else(|| {
+                env::var("AGENT_MAX_INFLIGHT")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("max_inflight")?)
+            .unwrap_or(1)
+            .max(1);
+
+        let gzip_uploads = args.gzip_uploads
+            || env::var("AGENT_GZIP_UPLOADS")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false)
+            || file.get("gzip_uploads")?.unwrap_or(false);
+        let gzip_min_saving_pct = args
+            .gzip_min_saving_pct
+            .or_else(|| {
+                env::var("AGENT_GZIP_MIN_SAVING_PCT")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("gzip_min_saving_pct")?)
+            .unwrap_or(DEFAULT_GZIP_MIN_SAVING_PCT)
+            .min(100);
+
+        let metrics_push_url = args
+            .metrics_push_url
+            .clone()
+            .or_else(|| env::var("AGENT_METRICS_PUSH_URL").ok())
+            .or(file.get("metrics_push_url")?);
+        let metrics_push_interval_secs = args
+            .metrics_push_interval_secs
+            .or_else(|| {
+                env::var("AGENT_METRICS_PUSH_INTERVAL_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("metrics_push_interval_secs")?)
+            .unwrap_or(metrics::DEFAULT_PUSH_INTERVAL_SECS)
+            .max(1);
+
+        // Unset or 0: epochs never end.
+        let epoch_max_seq = args
+            .epoch_max_seq
+            .or_else(|| {
+                env::var("AGENT_EPOCH_MAX_SEQ")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("epoch_max_seq")?)
+            .filter(|n| *n > 0);
+        let epoch_max_age_secs = args
+            .epoch_max_age_secs
+            .or_else(|| {
+                env::var("AGENT_EPOCH_MAX_AGE_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("epoch_max_age_secs")?)
+            .filter(|secs| *secs > 0);
+
+        let journal = args.journal
+            || env::var("AGENT_JOURNAL")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false)
+            || file.get("journal")?.unwrap_or(false);
+        let journal_max_bytes = args
+            .journal_max_bytes
+            .or_else(|| {
+                env::var("AGENT_JOURNAL_MAX_BYTES")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("journal_max_bytes")?)
+            .unwrap_or(journal::DEFAULT_MAX_BYTES)
+            .max(1);
+        let journal_max_age_secs = args
+            .journal_max_age_secs
+            .or_else(|| {
+                env::var("AGENT_JOURNAL_MAX_AGE_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("journal_max_age_secs")?)
+            .unwrap_or(journal::DEFAULT_MAX_AGE_SECS)
+            .max(1);
+
+        let dead_letter_policy = match args
+            .dead_letter_policy
+            .clone()
+            .or_else(|| env::var("AGENT_DEAD_LETTER_POLICY").ok())
+            .or(file.get("dead_letter_policy")?)
+        {
+            Some(policy) => deadletter::Policy::parse(&policy).ok_or_else(|| {
+                anyhow!("--dead-letter-policy must be continue or halt, not '{policy}'")
+            })?,
+            None => deadletter::Policy::Continue,
+        };
+        let backpressure = match args
+            .backpressure
+            .clone()
+            .or_else(|| env::var("AGENT_BACKPRESSURE").ok())
+            .or(file.get("backpressure")?)
+        {
+            Some(policy) => backpressure::Policy::parse(&policy)
+                .ok_or_else(|| anyhow!("--backpressure must be drop or pause, not '{policy}'"))?,
+            None => backpressure::Policy::Drop,
+        };
+        let pause_after_failures = args
+            .pause_after_failures
+            .or_else(|| {
+                env::var("AGENT_PAUSE_AFTER_FAILURES")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("pause_after_failures")?)
+            .unwrap_or(backpressure::DEFAULT_PAUSE_AFTER)
+            .max(1);
+        let breaker_threshold = args
+            .breaker_threshold
+            .or_else(|| {
+                env::var("AGENT_BREAKER_THRESHOLD")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("breaker_threshold")?)
+            .unwrap_or(0);
+        let breaker_cooldown_ms = args
+            .breaker_cooldown_ms
+            .or_else(|| {
+                env::var("AGENT_BREAKER_COOLDOWN_MS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("breaker_cooldown_ms")?)
+            .unwrap_or(breaker::DEFAULT_COOLDOWN_MS)
+            .max(1);
+
+        let wire_format = match args
+            .wire_format
+            .clone()
+            .or_else(|| env::var("AGENT_WIRE_FORMAT").ok())
+            .or(file.get("wire_format")?)
+        {
+            Some(format) => WireFormat::parse(&format)
+                .ok_or_else(|| anyhow!("--wire-format must be json or msgpack, not '{format}'"))?,
+            None => WireFormat::Json,
+        };
+
+        let batch_header = args.batch_header
+            || env::var("AGENT_BATCH_HEADER")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false)
+            || file.get("batch_header")?.unwrap_or(false);
+
+        let batch_version = args
+            .batch_version
+            .or_else(|| {
+                env::var("AGENT_BATCH_VERSION")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+            })
+            .or(file.get("batch_version")?)
+            .unwrap_or(CURRENT_BATCH_VERSION);
+        if !(BATCH_VERSION_V1..=CURRENT_BATCH_VERSION).contains(&batch_version) {
+            return Err(anyhow!(
+                "--batch-version must be {BATCH_VERSION_V1} to {CURRENT_BATCH_VERSION}, not {batch_version}"
+            ));
+        }
+        let skip_compat_check = args.skip_compat_check
+            || env::var("AGENT_SKIP_COMPAT_CHECK")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false)
+            || file.get("skip_compat_check")?.unwrap_or(false);
+
+        let agent_id = derive_agent_id(&state_dir)?;
+
+        let config = Self {
+            source,
+            source_tables,
             server_url,
+            grpc_url,
             state_dir,
             agent_id,
             max_retries,
             retry_base_ms,
-        })
+            batch_timeout_ms,
+            batch_size,
+            flush_interval_ms,
+            include,
+            exclude,
+            count_lines,
+            allow_gap,
+            max_line_bytes,
+            max_bytes_per_sec,
+            burst_bytes,
+            max_batches_per_sec,
+            burst_batches,
+            spool,
+            spool_encrypt,
+            spool_key: None,
+            max_inflight,
+            gzip_uploads,
+            gzip_min_saving_pct,
+            metrics_push_url,
+            metrics_push_interval_secs,
+            epoch_max_seq,
+            epoch_max_age_secs,
+            journal,
+            journal_max_bytes,
+            journal_max_age_secs,
+            journal_writer: None,
+            dead_letter_policy,
+            backpressure,
+            pause_after_failures,
+            breaker_threshold,
+            breaker_cooldown_ms,
+            wire_format,
+            batch_header,
+            batch_version,
+            skip_compat_check,
+        };
+        check_sources(&config.sources())?;
+        Ok(config)
+    }
+
+    /// The sources to read, in order, each with its settings resolved: a
+    /// `[sources.<name>]` table's own values over the top-level ones, or
+    /// the top-level source alone as `default`.
+    fn sources(&self) -> Vec<SourceConfig> {
+        let flush_interval = |own: Option<u64>| {
+            own.or(self.flush_interval_ms)
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis)
+        };
+        if self.source_tables.is_empty() {
+            return vec![SourceConfig {
+                name: "default".into(),
+                spec: self.source.clone(),
+                batch_size: self.batch_size,
+                flush_interval: flush_interval(None),
+                include: self.include.clone(),
+                exclude: self.exclude.clone(),
+            }];
+        }
+        self.source_tables
+            .iter()
+            .map(|table| SourceConfig {
+                name: table.name.clone(),
+                spec: table.spec.clone(),
+                batch_size: table.batch_size.unwrap_or(self.batch_size).max(1),
+                flush_interval: flush_interval(table.flush_interval_ms),
+                include: table.include.clone().or_else(|| self.include.clone()),
+                exclude: table.exclude.clone().or_else(|| self.exclude.clone()),
+            })
+            .collect()
+    }
+
+    /// Takes the hot-reloadable settings from `fresh`. Returns the names of
+    /// the settings that changed, split into applied and ignored-until-restart;
+    /// the latter need the chain state, the key or the open source untouched.
+    fn reload(&mut self, fresh: AgentConfig) -> (Vec<&'static str>, Vec<&'static str>) {
+        let mut applied = Vec::new();
+        take(
+            "batch_size",
+            &mut self.batch_size,
+            fresh.batch_size,
+            &mut applied,
+        );
+        take(
+            "flush_interval_ms",
+            &mut self.flush_interval_ms,
+            fresh.flush_interval_ms,
+            &mut applied,
+        );
+        take("include", &mut self.include, fresh.include, &mut applied);
+        take("exclude", &mut self.exclude, fresh.exclude, &mut applied);
+        // A table's settings reload; adding, removing, renaming or
+        // repointing a source does not.
+        let same_sources = self.source_tables.len() == fresh.source_tables.len()
+            && self
+                .source_tables
+                .iter()
+                .zip(&fresh.source_tables)
+                .all(|(running, fresh)| running.name == fresh.name && running.spec == fresh.spec);
+        if same_sources {
+            take(
+                "sources",
+                &mut self.source_tables,
+                fresh.source_tables,
+                &mut applied,
+            );
+        }
+        take(
+            "max_retries",
+            &mut self.max_retries,
+            fresh.max_retries,
+            &mut applied,
+        );
+        take(
+            "retry_base_ms",
+            &mut self.retry_base_ms,
+            fresh.retry_base_ms,
+            &mut applied,
+        );
+        take(
+            "batch_timeout_ms",
+            &mut self.batch_timeout_ms,
+            fresh.batch_timeout_ms,
+            &mut applied,
+        );
+        take(
+            "max_bytes_per_sec",
+            &mut self.max_bytes_per_sec,
+            fresh.max_bytes_per_sec,
+            &mut applied,
+        );
+        take(
+            "burst_bytes",
+            &mut self.burst_bytes,
+            fresh.burst_bytes,
+            &mut applied,
+        );
+        take(
+            "max_batches_per_sec",
+            &mut self.max_batches_per_sec,
+            fresh.max_batches_per_sec,
+            &mut applied,
+        );
+        take(
+            "burst_batches",
+            &mut self.burst_batches,
+            fresh.burst_batches,
+            &mut applied,
+        );
+        take("spool", &mut self.spool, fresh.spool, &mut applied);
+        take(
+            "gzip_uploads",
+            &mut self.gzip_uploads,
+            fresh.gzip_uploads,
+            &mut applied,
+        );
+        take(
+            "gzip_min_saving_pct",
+            &mut self.gzip_min_saving_pct,
+            fresh.gzip_min_saving_pct,
+            &mut applied,
+        );
+        take(
+            "epoch_max_seq",
+            &mut self.epoch_max_seq,
+            fresh.epoch_max_seq,
+            &mut applied,
+        );
+        take(
+            "epoch_max_age_secs",
+            &mut self.epoch_max_age_secs,
+            fresh.epoch_max_age_secs,
+            &mut applied,
+        );
+        take(
+            "dead_letter_policy",
+            &mut self.dead_letter_policy,
+            fresh.dead_letter_policy,
+            &mut applied,
+        );
+        take(
+            "backpressure",
+            &mut self.backpressure,
+            fresh.backpressure,
+            &mut applied,
+        );
+        take(
+            "pause_after_failures",
+            &mut self.pause_after_failures,
+            fresh.pause_after_failures,
+            &mut applied,
+        );
+        take(
+            "breaker_threshold",
+            &mut self.breaker_threshold,
+            fresh.breaker_threshold,
+            &mut applied,
+        );
+        take(
+            "breaker_cooldown_ms",
+            &mut self.breaker_cooldown_ms,
+            fresh.breaker_cooldown_ms,
+            &mut applied,
+        );
+        take(
+            "wire_format",
+            &mut self.wire_format,
+            fresh.wire_format,
+            &mut applied,
+        );
+
+        let ignored = [
+            ("source", self.source != fresh.source),
+            ("sources", !same_sources),
+            ("server_url", self.server_url != fresh.server_url),
+            ("grpc_url", self.grpc_url != fresh.grpc_url),
+            ("state_dir", self.state_dir != fresh.state_dir),
+            ("agent_id", self.agent_id != fresh.agent_id),
+            ("count_lines", self.count_lines != fresh.count_lines),
+            ("allow_gap", self.allow_gap != fresh.allow_gap),
+            ("batch_header", self.batch_header != fresh.batch_header),
+            ("batch_version", self.batch_version != fresh.batch_version),
+            (
+                "max_line_bytes",
+                self.max_line_bytes != fresh.max_line_bytes,
+            ),
+            ("spool_encrypt", self.spool_encrypt != fresh.spool_encrypt),
+            ("max_inflight", self.max_inflight != fresh.max_inflight),
+            ("journal", self.journal != fresh.journal),
+            (
+                "journal_max_bytes",
+                self.journal_max_bytes != fresh.journal_max_bytes,
+            ),
+            (
+                "journal_max_age_secs",
+                self.journal_max_age_secs != fresh.journal_max_age_secs,
+            ),
+            (
+                "metrics_push_url",
+                self.metrics_push_url != fresh.metrics_push_url,
+            ),
+            (
+                "metrics_push_interval_secs",
+                self.metrics_push_interval_secs != fresh.metrics_push_interval_secs,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect();
+        (applied, ignored)
+    }
+
+    fn throttle(&self) -> Throttle {
+        Throttle::new(
+            self.max_bytes_per_sec,
+            self.burst_bytes,
+            self.max_batches_per_sec,
+            self.burst_batches,
+        )
     }
 
     fn key_path(state_dir: &Path) -> PathBuf {
@@ -273,44 +2453,131 @@ impl AgentConfig {
     }
 
     fn seq_path(&self) -> PathBuf {
-        self.state_dir.join("seq.txt")
+        Self::seq_path_in(&self.state_dir)
+    }
+
+    fn seq_path_in(state_dir: &Path) -> PathBuf {
+        state_dir.join("seq.txt")
     }
 
     fn prev_hash_path(&self) -> PathBuf {
         self.state_dir.join("prev_hash.txt")
     }
+
+    fn accumulator_path(&self) -> PathBuf {
+        self.state_dir.join("accumulator.txt")
+    }
+
+    /// `<epoch> <started ms>`.
+    fn epoch_path(&self) -> PathBuf {
+        self.state_dir.join("epoch.txt")
+    }
+
+    fn lines_read_path(&self) -> PathBuf {
+        self.state_dir.join("lines_read.txt")
+    }
+}
+
+/// Sets `current` to `fresh` and records `name` if that changed it.
+fn take<T: PartialEq>(
+    name: &'static str,
+    current: &mut T,
+    fresh: T,
+    applied: &mut Vec<&'static str>,
+) {
+    if *current != fresh {
+        *current = fresh;
+        applied.push(name);
+    }
 }
 
-fn derive_agent_id(key_path: &Path) -> Result<String> {
-    let key = load_or_generate_key_path(key_path)?;
+/// The hex public key, unless a `--rotate-key` pinned the id it had.
+fn derive_agent_id(state_dir: &Path) -> Result<String> {
+    let key = load_or_generate_key(state_dir)?;
+    if let Some(id) = rotate::pinned_agent_id(state_dir)? {
+        return Ok(id);
+    }
     let pk = key.verifying_key();
-    Ok(to_hex(&pk.to_bytes()))
+    Ok(hex_encode(&pk.to_bytes()))
 }
 
-fn load_or_generate_key(config: &AgentConfig) -> Result<ed25519_dalek::SigningKey> {
-    load_or_generate_key_path(&AgentConfig::key_path(&config.state_dir))
+/// The agent key once running: it must still be on disk. A key that went
+/// missing or unreadable is an error, never regenerated.
+fn load_key(config: &AgentConfig) -> Result<ed25519_dalek::SigningKey> {
+    let path = AgentConfig::key_path(&config.state_dir);
+    read_key(&path)?.ok_or_else(|| {
+        anyhow!(
+            "agent key {} disappeared while running; refusing to generate a new identity \
+             (restore the key, or move {} aside to start over as a new agent)",
+            path.display(),
+            config.state_dir.display()
+        )
+    })
 }
 
-fn load_or_generate_key_path(path: &Path) -> Result<ed25519_dalek::SigningKey> {
-    if let Ok(bytes) = fs::read(path) {
-        if bytes.len() == 32 {
-            let mut key_bytes = [0u8; 32];
-            key_bytes.copy_from_slice(&bytes);
-            return Ok(ed25519_dalek::SigningKey::from_bytes(&key_bytes));
-        }
+/// Fails unless the key file still holds `running`. The key is the agent's
+/// identity: signing on under a replaced key, or restarting into a fresh
+/// one, forks its chain.
+fn check_key(config: &AgentConfig, running: &ed25519_dalek::SigningKey) -> Result<()> {
+    let on_disk = load_key(config)?;
+    if on_disk.to_bytes() != running.to_bytes() {
+        return Err(anyhow!(
+            "agent key {} was replaced while running; refusing to continue under a new identity",
+            AgentConfig::key_path(&config.state_dir).display()
+        ));
+    }
+    Ok(())
+}
+
+/// At startup: the key in `state_dir`, generated only on a first start,
+/// i.e. with no key and no chain state yet. A corrupt key, or a missing one
+/// next to existing chain state, is an error rather than a new identity.
+fn load_or_generate_key(state_dir: &Path) -> Result<ed25519_dalek::SigningKey> {
+    let path = AgentConfig::key_path(state_dir);
+    if let Some(key) = read_key(&path)? {
+        return Ok(key);
+    }
+    let seq_path = AgentConfig::seq_path_in(state_dir);
+    if seq_path.exists() {
+        return Err(anyhow!(
+            "agent key {} is missing but {} holds chain state; refusing to generate a new identity \
+             (restore the key, or move {} aside to start over as a new agent)",
+            path.display(),
+            seq_path.display(),
+            state_dir.display()
+        ));
     }
 
     let key = generate_keypair();
-    fs::write(path, key.to_bytes())?;
+    platform::write_private(&path, &key.to_bytes())?;
+    println!("Generated a new agent key at {}", path.display());
     Ok(key)
 }
 
+/// `None` if there is no key file; an error if it cannot be read or is not
+/// a 32-byte key.
+fn read_key(path: &Path) -> Result<Option<ed25519_dalek::SigningKey>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(anyhow!("cannot read agent key {}: {err}", path.display())),
+    };
+    let bytes: [u8; 32] = bytes.try_into().map_err(|bytes: Vec<u8>| {
+        anyhow!(
+            "agent key {} is corrupt ({} bytes, expected 32); refusing to replace it",
+            path.display(),
+            bytes.len()
+        )
+    })?;
+    Ok(Some(ed25519_dalek::SigningKey::from_bytes(&bytes)))
+}
+
 fn load_seq(config: &AgentConfig) -> Result<u64> {
     let path = config.seq_path();
-    if let Ok(contents) = fs::read_to_string(&path) {
-        if let Ok(v) = contents.trim().parse::<u64>() {
-            return Ok(v);
-        }
+    if let Ok(contents) = fs::read_to_string(&path)
+        && let Ok(v) = contents.trim().parse::<u64>()
+    {
+        return Ok(v);
     }
     Ok(1)
 }
@@ -320,59 +2587,1984 @@ fn persist_seq(config: &AgentConfig, seq: u64) -> Result<()> {
     Ok(())
 }
 
+/// The current epoch and when it started; epoch 0 from now without a file.
+fn load_epoch(config: &AgentConfig) -> Result<(u64, u64)> {
+    if let Ok(contents) = fs::read_to_string(config.epoch_path())
+        && let Some((epoch, started_ms)) = contents.trim().split_once(' ')
+        && let (Ok(epoch), Ok(started_ms)) = (epoch.parse(), started_ms.parse())
+    {
+        return Ok((epoch, started_ms));
+    }
+    Ok((0, Utc::now().timestamp_millis() as u64))
+}
+
+fn persist_epoch(config: &AgentConfig, epoch: u64, started_ms: u64) -> Result<()> {
+    fs::write(config.epoch_path(), format!("{epoch} {started_ms}"))?;
+    Ok(())
+}
+
+fn load_lines_read(config: &AgentConfig) -> Result<u64> {
+    if let Ok(contents) = fs::read_to_string(config.lines_read_path())
+        && let Ok(v) = contents.trim().parse::<u64>()
+    {
+        return Ok(v);
+    }
+    Ok(0)
+}
+
+fn persist_lines_read(config: &AgentConfig, count: u64) -> Result<()> {
+    fs::write(config.lines_read_path(), count.to_string())?;
+    Ok(())
+}
+
 fn load_prev_hash(config: &AgentConfig) -> Result<[u8; 32]> {
-    let path = config.prev_hash_path();
-    if let Ok(contents) = fs::read_to_string(&path) {
+    Ok(read_hash_file(&config.prev_hash_path(), "prev_hash")?.unwrap_or([0u8; 32]))
+}
+
+fn load_accumulator(config: &AgentConfig) -> Result<Option<[u8; 32]>> {
+    read_hash_file(&config.accumulator_path(), "accumulator")
+}
+
+fn read_hash_file(path: &Path, what: &str) -> Result<Option<[u8; 32]>> {
+    if let Ok(contents) = fs::read_to_string(path) {
         let hex = contents.trim();
         if hex.len() == 64 {
-            let mut out = [0u8; 32];
-            for i in 0..32 {
-                let byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
-                    .map_err(|e| anyhow!("invalid prev_hash hex: {e}"))?;
-                out[i] = byte;
-            }
-            return Ok(out);
+            let hash = hex_decode_fixed(hex).map_err(|e| anyhow!("invalid {what} hex: {e}"))?;
+            return Ok(Some(hash));
+        }
+    }
+    Ok(None)
+}
+
+fn persist_accumulator(config: &AgentConfig, accumulator: Option<[u8; 32]>) -> Result<()> {
+    match accumulator {
+        Some(acc) => fs::write(config.accumulator_path(), hex_encode(&acc))?,
+        None => {
+            let _ = fs::remove_file(config.accumulator_path());
         }
     }
-    Ok([0u8; 32])
+    Ok(())
 }
 
 fn persist_prev_hash(config: &AgentConfig, hash: [u8; 32]) -> Result<()> {
-    fs::write(config.prev_hash_path(), to_hex(&hash))?;
+    fs::write(config.prev_hash_path(), hex_encode(&hash))?;
     Ok(())
 }
 
-fn to_hex(bytes: &[u8]) -> String {
-    let mut s = String::with_capacity(bytes.len() * 2);
-    for b in bytes {
-        s.push_str(&format!("{:02x}", b));
+/// The server's checkpoint for `agent_id`, through the circuit breaker like
+/// a submit.
+async fn fetch_checkpoint(
+    config: &AgentConfig,
+    breaker: &mut Breaker,
+    metrics: &AgentMetrics,
+    agent_id: &str,
+) -> Result<Option<Checkpoint>> {
+    let cooldown = Duration::from_millis(config.breaker_cooldown_ms);
+    breaker.admit(cooldown, metrics)?;
+    let fetched = match &config.grpc_url {
+        #[cfg(feature = "grpc")]
+        Some(url) => grpc::fetch_checkpoint(url, agent_id).await,
+        _ => producer::Client::new(&config.server_url)
+            .checkpoint(agent_id)
+            .await
+            .map_err(Into::into),
+    };
+    match &fetched {
+        Ok(_) => breaker.succeeded(metrics),
+        Err(_) => breaker.failed(config.breaker_threshold, cooldown, metrics),
     }
-    s
+    fetched
 }
 
-#[derive(Deserialize)]
-struct AgentCheckpoint {
-    agent_id: String,
-    last_seq: u64,
-    last_hash: [u8; 32],
-    #[serde(rename = "count")]
-    _count: u64,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
 
-async fn fetch_checkpoint(config: &AgentConfig, agent_id: &str) -> Result<Option<AgentCheckpoint>> {
-    let client = reqwest::Client::new();
-    let resp = client
-        .get(format!("{}/batches/checkpoints", config.server_url))
-        .send()
-        .await?;
+    /// Minimal HTTP server that answers an empty 200 to everything and records when
+    /// each request body finished arriving, with its lowercased head.
+    async fn mock_server() -> (String, Arc<Mutex<Vec<(Instant, String)>>>) {
+        mock_server_replying(String::new()).await
+    }
 
-    if !resp.status().is_success() {
-        return Err(anyhow!(
-            "checkpoint request failed with status {}",
-            resp.status()
-        ));
+    /// [`mock_server`] answering every request with `body`.
+    async fn mock_server_replying(body: String) -> (String, Arc<Mutex<Vec<(Instant, String)>>>) {
+        mock_server_answering(move || {
+            format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{body}",
+                body.len()
+            )
+        })
+        .await
+    }
+
+    /// [`mock_server`] answering each request with whatever raw response
+    /// `reply` gives when it arrives.
+    async fn mock_server_answering(
+        reply: impl Fn() -> String + Send + Sync + 'static,
+    ) -> (String, Arc<Mutex<Vec<(Instant, String)>>>) {
+        let (url, arrivals, _) = mock_server_recording(reply).await;
+        (url, arrivals)
+    }
+
+    /// [`mock_server_answering`] that also keeps each request body.
+    async fn mock_server_recording(
+        reply: impl Fn() -> String + Send + Sync + 'static,
+    ) -> (
+        String,
+        Arc<Mutex<Vec<(Instant, String)>>>,
+        Arc<Mutex<Vec<Vec<u8>>>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let seen = arrivals.clone();
+        let kept = bodies.clone();
+        let reply = Arc::new(reply);
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let seen = seen.clone();
+                let kept = kept.clone();
+                let reply = reply.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 4096];
+                    loop {
+                        let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+                            match socket.read(&mut chunk).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                            }
+                            continue;
+                        };
+                        let head = String::from_utf8_lossy(&buf[..end]).to_ascii_lowercase();
+                        let body_len: usize = head
+                            .lines()
+                            .find_map(|l| l.strip_prefix("content-length:"))
+                            .and_then(|v| v.trim().parse().ok())
+                            .unwrap_or(0);
+                        while buf.len() < end + 4 + body_len {
+                            match socket.read(&mut chunk).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                            }
+                        }
+                        seen.lock().unwrap().push((Instant::now(), head));
+                        kept.lock()
+                            .unwrap()
+                            .push(buf[end + 4..end + 4 + body_len].to_vec());
+                        buf.drain(..end + 4 + body_len);
+                        let _ = socket.write_all(reply().as_bytes()).await;
+                    }
+                });
+            }
+        });
+
+        (url, arrivals, bodies)
+    }
+
+    fn test_config(server_url: String) -> AgentConfig {
+        AgentConfig {
+            source: SourceSpec::File(PathBuf::from("unused.log")),
+            source_tables: Vec::new(),
+            server_url,
+            grpc_url: None,
+            state_dir: env::temp_dir(),
+            agent_id: "agent-test".into(),
+            max_retries: 1,
+            retry_base_ms: 1,
+            batch_timeout_ms: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            flush_interval_ms: None,
+            include: None,
+            exclude: None,
+            count_lines: false,
+            allow_gap: false,
+            max_line_bytes: DEFAULT_MAX_LINE_BYTES,
+            max_bytes_per_sec: None,
+            burst_bytes: None,
+            max_batches_per_sec: None,
+            burst_batches: None,
+            spool: false,
+            spool_encrypt: false,
+            spool_key: None,
+            max_inflight: 1,
+            gzip_uploads: false,
+            gzip_min_saving_pct: DEFAULT_GZIP_MIN_SAVING_PCT,
+            metrics_push_url: None,
+            metrics_push_interval_secs: metrics::DEFAULT_PUSH_INTERVAL_SECS,
+            epoch_max_seq: None,
+            epoch_max_age_secs: None,
+            journal: false,
+            journal_max_bytes: journal::DEFAULT_MAX_BYTES,
+            journal_max_age_secs: journal::DEFAULT_MAX_AGE_SECS,
+            journal_writer: None,
+            dead_letter_policy: deadletter::Policy::Continue,
+            backpressure: backpressure::Policy::Drop,
+            pause_after_failures: backpressure::DEFAULT_PAUSE_AFTER,
+            breaker_threshold: 0,
+            breaker_cooldown_ms: breaker::DEFAULT_COOLDOWN_MS,
+            wire_format: WireFormat::Json,
+            batch_header: false,
+            batch_version: CURRENT_BATCH_VERSION,
+            skip_compat_check: false,
+        }
+    }
+
+    fn batch(seq: u64) -> LogBatch {
+        signed_batch(&generate_keypair(), seq, [0u8; 32])
+    }
+
+    fn signed_batch(key: &ed25519_dalek::SigningKey, seq: u64, prev_hash: [u8; 32]) -> LogBatch {
+        let mut batch = LogBatch {
+            prev_hash,
+            logs: vec!["x".repeat(100)],
+            timestamp: 0,
+            agent_id: "agent-test".into(),
+            seq,
+            signature: Signature::from_bytes(&[0u8; 64]),
+            public_key: key.verifying_key(),
+            lines_read: None,
+            version: CURRENT_BATCH_VERSION,
+            accumulator: None,
+            gap: None,
+            epoch: 0,
+            epoch_start: None,
+            kind: None,
+        };
+        batch.sign(key);
+        batch
+    }
+
+    /// `n` linked batches from seq 1, each with its own line.
+    fn chain(key: &ed25519_dalek::SigningKey, n: u64) -> Vec<LogBatch> {
+        let mut prev_hash = [0u8; 32];
+        (1..=n)
+            .map(|seq| {
+                let mut batch = signed_batch(key, seq, prev_hash);
+                batch.logs = vec![format!("secret line {seq}")];
+                batch.sign(key);
+                prev_hash = batch.compute_hash();
+                batch
+            })
+            .collect()
+    }
+
+    /// Span between first and last arrival for `n` batches under `throttle`.
+    async fn ship(throttle: &mut Throttle, n: u64) -> Duration {
+        let (url, arrivals) = mock_server().await;
+        let config = test_config(url);
+        for seq in 1..=n {
+            send_batch(
+                &config,
+                throttle,
+                &mut Breaker::default(),
+                &AgentMetrics::default(),
+                &batch(seq),
+            )
+            .await
+            .unwrap();
+        }
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len() as u64, n);
+        arrivals.last().unwrap().0 - arrivals[0].0
+    }
+
+    #[tokio::test]
+    async fn batch_rate_is_capped_after_burst() {
+        // Burst of 2, then 20/s: batches 3..=8 need at least 6 * 50ms.
+        let mut throttle = Throttle::new(None, None, Some(20.0), Some(2.0));
+        let span = ship(&mut throttle, 8).await;
+        assert!(span >= Duration::from_millis(290), "span {span:?}");
+    }
+
+    #[tokio::test]
+    async fn byte_rate_is_capped_after_burst() {
+        let size = serde_json::to_vec(&batch(1)).unwrap().len() as u64;
+        // One batch of burst, then 10 batches' worth of bytes per second.
+        let mut throttle = Throttle::new(Some(size * 10), Some(size), None, None);
+        let span = ship(&mut throttle, 5).await;
+        // 4 more batches at ~100ms each; signature encodings vary a few bytes.
+        assert!(span >= Duration::from_millis(360), "span {span:?}");
+        assert!(
+            throttle
+                .status()
+                .starts_with(&format!("bytes {}B/s", size * 10))
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_timeout_caps_a_hanging_server() {
+        // Accepts connections and reads forever without answering.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n) = socket.read(&mut buf).await {
+                        if n == 0 {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut config = test_config(url);
+        config.max_retries = 10;
+        config.batch_timeout_ms = Some(200);
+        let mut throttle = Throttle::new(None, None, None, None);
+
+        let started = Instant::now();
+        let err = send_batch(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &AgentMetrics::default(),
+            &batch(1),
+        )
+        .await
+        .unwrap_err();
+        let elapsed = started.elapsed();
+        assert!(err.to_string().contains("timed out after 200ms"), "{err}");
+        assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+    }
+
+    #[test]
+    fn clock_skew_uses_request_midpoint() {
+        // Request took 200ms; the server answered 60s past its midpoint.
+        assert_eq!(clock_skew_ms(1_000, 1_200, 61_100), 60_000);
+        assert_eq!(clock_skew_ms(100_000, 100_000, 40_000), -60_000);
+
+        let mut warned = false;
+        report_clock_skew(60_000, &mut warned);
+        assert!(warned);
+        report_clock_skew(-5, &mut warned);
+        assert!(!warned);
+    }
+
+    #[test]
+    fn reload_changes_batch_size_mid_run() {
+        let dir = env::temp_dir().join(format!("agent-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("agent.toml");
+        let args = AgentArgs {
+            config_path: Some(config_path.clone()),
+            state_dir: Some(dir.join("state")),
+            server_url: Some("http://127.0.0.1:3000".into()),
+            ..AgentArgs::default()
+        };
+        fs::write(&config_path, "batch_size = 3\n").unwrap();
+        let mut config = AgentConfig::load(&args).unwrap();
+        let mut throttle = config.throttle();
+        assert_eq!(config.batch_size, 3);
+        let agent_id = config.agent_id.clone();
+
+        // Two lines are buffered: not a batch yet at 3, one at 2.
+        let buffer = ["a", "b"];
+        assert!(buffer.len() < config.batch_size);
+        fs::write(
+            &config_path,
+            "batch_size = 2\nmax_batches_per_sec = 4\nsource = \"exec:journalctl -f\"\n",
+        )
+        .unwrap();
+        reload_config(&args, &mut config, &mut throttle);
+        assert!(buffer.len() >= config.batch_size);
+        assert_eq!(config.max_batches_per_sec, Some(4.0));
+        assert!(throttle.status().contains("4"), "{}", throttle.status());
+        // The source is not reloadable, and the identity is untouched.
+        assert_eq!(
+            config.source,
+            SourceSpec::File(PathBuf::from("/var/log/dpkg.log"))
+        );
+        assert_eq!(config.agent_id, agent_id);
+
+        let (applied, ignored) = config.reload(AgentConfig::load(&args).unwrap());
+        assert!(applied.is_empty(), "{applied:?}");
+        assert_eq!(ignored, ["source"]);
+
+        // A broken file keeps the running settings; a flag beats the file.
+        fs::write(&config_path, "batch_size = two\n").unwrap();
+        reload_config(&args, &mut config, &mut throttle);
+        assert_eq!(config.batch_size, 2);
+        fs::write(&config_path, "batch_size = 9\n").unwrap();
+        let flagged = AgentArgs {
+            batch_size: Some(7),
+            ..args.clone()
+        };
+        assert_eq!(AgentConfig::load(&flagged).unwrap().batch_size, 7);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn accepted_batches_are_spooled_when_enabled() {
+        let (url, _) = mock_server().await;
+        let mut config = test_config(url);
+        config.state_dir = env::temp_dir().join(format!("agent-spool-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&config.state_dir);
+        let mut throttle = Throttle::new(None, None, None, None);
+        let key = generate_keypair();
+        let sent = chain(&key, 2);
+
+        send_batch(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &AgentMetrics::default(),
+            &sent[0],
+        )
+        .await
+        .unwrap();
+        assert!(!spool::spool_dir(&config.state_dir).exists());
+        config.spool = true;
+        send_batch(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &AgentMetrics::default(),
+            &sent[1],
+        )
+        .await
+        .unwrap();
+        let spooled = spool::load(&config.state_dir, &spool::SpoolKey::derive(&key)).unwrap();
+        assert_eq!(spooled.len(), 1);
+        assert_eq!(spooled[0].compute_hash(), sent[1].compute_hash());
+        fs::remove_dir_all(&config.state_dir).unwrap();
+    }
+
+    #[test]
+    fn encrypted_spool_files_hide_their_lines_and_quarantine_when_unreadable() {
+        let dir = env::temp_dir().join(format!("agent-spool-crypt-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let key = generate_keypair();
+        let spool_key = spool::SpoolKey::derive(&key);
+        let chain = chain(&key, 4);
+        for batch in &chain {
+            spool::persist(&dir, batch, Some(&spool_key)).unwrap();
+        }
+        let path = |seq| spool::spool_dir(&dir).join(acks::file_name(0, seq));
+        let raw = fs::read(path(1)).unwrap();
+        assert!(raw.starts_with(b"LCSPOOL"));
+        let line = chain[0].logs[0].as_bytes();
+        assert!(
+            !raw.windows(line.len()).any(|w| w == line),
+            "lines are not stored in the clear"
+        );
+        let hashes = |batches: &[LogBatch]| {
+            batches
+                .iter()
+                .map(LogBatch::compute_hash)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            hashes(&spool::load(&dir, &spool_key).unwrap()),
+            hashes(&chain)
+        );
+        spool::check(&dir, &spool_key).unwrap();
+
+        // A flipped bit, and a file copied over another seq, are set aside.
+        let mut tampered = fs::read(path(2)).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        fs::write(path(2), tampered).unwrap();
+        fs::copy(path(1), path(3)).unwrap();
+        let loaded = spool::load(&dir, &spool_key).unwrap();
+        assert_eq!(loaded.iter().map(|b| b.seq).collect::<Vec<_>>(), [1, 4]);
+        let quarantined = spool::quarantined(&dir).unwrap();
+        let corrupt = |seq| spool::corrupt_dir(&dir).join(acks::file_name(0, seq));
+        assert_eq!(quarantined, [corrupt(2), corrupt(3)]);
+        assert!(!path(2).exists() && !path(3).exists());
+        let err = spool::check(&dir, &spool_key).unwrap_err();
+        assert!(
+            err.to_string().contains("2 spool files are quarantined"),
+            "{err}"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_replaced_agent_key_quarantines_the_encrypted_spool_but_not_plain_files() {
+        let dir = env::temp_dir().join(format!("agent-spool-rekey-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let old = generate_keypair();
+        let chain = chain(&old, 3);
+        spool::persist(&dir, &chain[0], None).unwrap();
+        for batch in &chain[1..] {
+            spool::persist(&dir, batch, Some(&spool::SpoolKey::derive(&old))).unwrap();
+        }
+
+        // The key changed between writing the spool and reading it back.
+        let new = spool::SpoolKey::derive(&generate_keypair());
+        let loaded = spool::load(&dir, &new).unwrap();
+        assert_eq!(loaded.iter().map(|b| b.seq).collect::<Vec<_>>(), [1]);
+        assert_eq!(spool::quarantined(&dir).unwrap().len(), 2);
+        assert!(spool::check(&dir, &new).is_err());
+
+        // Nothing was lost: put back, the files still open under the old key.
+        for path in spool::quarantined(&dir).unwrap() {
+            fs::rename(
+                &path,
+                spool::spool_dir(&dir).join(path.file_name().unwrap()),
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            spool::load(&dir, &spool::SpoolKey::derive(&old))
+                .unwrap()
+                .len(),
+            3
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn source_tables_layer_over_the_top_level_and_contradictions_are_rejected() {
+        let dir = env::temp_dir().join(format!("agent-sources-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("agent.toml");
+        let args = AgentArgs {
+            config_path: Some(config_path.clone()),
+            state_dir: Some(dir.join("state")),
+            ..AgentArgs::default()
+        };
+        let load = |text: &str| {
+            fs::write(&config_path, text).unwrap();
+            AgentConfig::load(&args)
+        };
+
+        let config = load(
+            "batch_size = 50\nflush_interval_ms = 5000\nexclude = \"*DEBUG*\"\n\
+             [sources.audit]\nsource = \"/var/log/audit.log\"\nbatch_size = 2\nflush_interval_ms = 100\ninclude = \"*type=*\"\n\
+             [sources.debug]\nsource = \"exec:journalctl -f\"\nflush_interval_ms = 0\n",
+        )
+        .unwrap();
+        let sources = config.sources();
+        let names: Vec<_> = sources.iter().map(|source| source.name.as_str()).collect();
+        assert_eq!(names, ["audit", "debug"]);
+        assert_eq!(sources[0].batch_size, 2);
+        assert_eq!(sources[0].flush_interval, Some(Duration::from_millis(100)));
+        assert_eq!(sources[0].include.as_deref(), Some("*type=*"));
+        assert_eq!(sources[0].exclude.as_deref(), Some("*DEBUG*"));
+        assert_eq!(sources[1].batch_size, 50);
+        assert_eq!(sources[1].flush_interval, None);
+        assert_eq!(sources[1].spec, SourceSpec::Exec("journalctl -f".into()));
+
+        // Table settings reload; a repointed source does not.
+        let mut running = config;
+        let fresh =
+            load("[sources.audit]\nsource = \"/var/log/audit.log\"\nbatch_size = 3\n").unwrap();
+        let (applied, ignored) = running.reload(fresh);
+        assert!(ignored.contains(&"sources"), "{ignored:?}");
+        assert!(!applied.contains(&"sources"), "{applied:?}");
+
+        for (text, expected) in [
+            (
+                "source = \"/var/log/app.log\"\n[sources.a]\nsource = \"/var/log/b.log\"\n",
+                "as well as [sources.<name>] tables",
+            ),
+            (
+                "[sources.a]\nsource = \"/var/log/x.log\"\n[sources.b]\nsource = \"file:/var/log/x.log\"\n",
+                "sources a and b both read",
+            ),
+            (
+                "include = \"*ERROR*\"\nexclude = \"*ERROR*\"\n",
+                "no line would be shipped",
+            ),
+            (
+                "[sources.a]\nsource = \"/var/log/a.log\"\nexclude = \"*\"\n",
+                "drops every line",
+            ),
+        ] {
+            let err = load(text)
+                .err()
+                .unwrap_or_else(|| panic!("{text} loaded"))
+                .to_string();
+            assert!(err.contains(expected), "{text}: {err}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn grpc_transport_submits_and_reads_the_checkpoint() {
+        use common::grpc::proto;
+        use common::grpc::proto::log_chain_server::{LogChain, LogChainServer};
+        use tonic::codegen::{BoxStream, tokio_stream};
+        use tonic::transport::server::TcpIncoming;
+        use tonic::{Request, Response, Status};
+
+        /// Stores seq 1 with a fixed clock and refuses anything else.
+        struct Server {
+            stored: Arc<Mutex<Vec<LogBatch>>>,
+        }
+
+        #[tonic::async_trait]
+        impl LogChain for Server {
+            async fn submit(
+                &self,
+                request: Request<proto::LogBatch>,
+            ) -> Result<Response<proto::SubmitResponse>, Status> {
+                let batch =
+                    LogBatch::try_from(request.into_inner()).map_err(Status::invalid_argument)?;
+                if !batch.verify() || batch.seq != 1 {
+                    return Err(Status::failed_precondition("refused"));
+                }
+                self.stored.lock().unwrap().push(batch);
+                Ok(Response::new(proto::SubmitResponse {
+                    status: "ok".into(),
+                    message: "batch stored".into(),
+                    server_time_ms: Some(0),
+                    ack: Some("durable".into()),
+                }))
+            }
+
+            type CheckpointsStream = BoxStream<proto::AgentCheckpoint>;
+
+            async fn checkpoints(
+                &self,
+                _: Request<proto::CheckpointsRequest>,
+            ) -> Result<Response<Self::CheckpointsStream>, Status> {
+                let checkpoints: Vec<_> = self
+                    .stored
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|batch| proto::AgentCheckpoint {
+                        agent_id: batch.agent_id.clone(),
+                        last_seq: batch.seq,
+                        last_hash: batch.compute_hash().to_vec(),
+                        count: 1,
+                        last_accumulator: None,
+                        last_epoch: batch.epoch,
+                    })
+                    .map(Ok)
+                    .collect();
+                Ok(Response::new(Box::pin(tokio_stream::iter(checkpoints))))
+            }
+        }
+
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        let service = LogChainServer::new(Server {
+            stored: stored.clone(),
+        });
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming),
+        );
+
+        let mut config = test_config("http://127.0.0.1:9".into());
+        config.grpc_url = Some(url);
+        assert!(
+            fetch_checkpoint(
+                &config,
+                &mut Breaker::default(),
+                &AgentMetrics::default(),
+                "agent-test"
+            )
+            .await
+            .unwrap()
+            .is_none()
+        );
+
+        let mut throttle = config.throttle();
+        let first = batch(1);
+        let skew = send_batch(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &AgentMetrics::default(),
+            &first,
+        )
+        .await
+        .unwrap();
+        // The mock's clock reads 0, far behind ours.
+        assert!(skew.unwrap() < 0);
+        assert_eq!(
+            stored.lock().unwrap()[0].compute_hash(),
+            first.compute_hash()
+        );
+        assert!(
+            send_batch(
+                &config,
+                &mut throttle,
+                &mut Breaker::default(),
+                &AgentMetrics::default(),
+                &batch(2)
+            )
+            .await
+            .is_err()
+        );
+
+        let cp = fetch_checkpoint(
+            &config,
+            &mut Breaker::default(),
+            &AgentMetrics::default(),
+            "agent-test",
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!((cp.last_seq, cp.last_hash), (1, first.compute_hash()));
+    }
+
+    #[test]
+    fn gap_marker_covers_only_what_the_server_lacks() {
+        let config = test_config("http://unused".into());
+        let key = generate_keypair();
+        let checkpoint = Checkpoint {
+            agent_id: "agent-test".into(),
+            last_seq: 3,
+            last_hash: [6u8; 32],
+            count: 3,
+            last_accumulator: Some([8u8; 32]),
+            last_epoch: 0,
+        };
+
+        let marker = gap_marker(&config, &key, (0, 7), Some(&checkpoint), 40).unwrap();
+        let gap = marker.gap.as_ref().unwrap();
+        assert_eq!((gap.missing_from, gap.missing_to, marker.seq), (4, 6, 7));
+        assert_eq!(marker.prev_hash, checkpoint.last_hash);
+        assert_eq!(
+            marker.accumulator,
+            Some(marker.expected_accumulator(Some(&[8u8; 32])))
+        );
+        assert!(marker.verify() && marker.check_gap().is_ok());
+
+        // An empty server: everything before local state's next seq is lost.
+        let from_genesis = gap_marker(&config, &key, (0, 3), None, 0).unwrap();
+        assert_eq!(from_genesis.gap.as_ref().unwrap().missing_from, 1);
+        assert_eq!(from_genesis.prev_hash, [0u8; 32]);
+
+        // In step or ahead of local state: nothing to declare.
+        assert!(gap_marker(&config, &key, (0, 4), Some(&checkpoint), 0).is_none());
+        assert!(gap_marker(&config, &key, (0, 2), Some(&checkpoint), 0).is_none());
+        assert!(gap_marker(&config, &key, (0, 1), None, 0).is_none());
+
+        // Within a later epoch the marker stays in it; across epochs there
+        // is nothing it could link.
+        let later = Checkpoint {
+            last_epoch: 2,
+            ..checkpoint
+        };
+        let marker = gap_marker(&config, &key, (2, 7), Some(&later), 0).unwrap();
+        assert_eq!((marker.epoch, marker.seq), (2, 7));
+        assert!(marker.verify() && marker.check_gap().is_ok() && marker.check_epoch().is_ok());
+        assert!(gap_marker(&config, &key, (3, 7), Some(&later), 0).is_none());
+        assert!(gap_marker(&config, &key, (1, 7), Some(&later), 0).is_none());
+    }
+
+    #[test]
+    fn each_run_opens_with_one_fresh_session_start() {
+        let mut config = test_config("http://unused".into());
+        let key = generate_keypair();
+        let mut head = Checkpoint {
+            agent_id: "agent-test".into(),
+            last_seq: 0,
+            last_hash: [0u8; 32],
+            count: 0,
+            last_accumulator: None,
+            last_epoch: 0,
+        };
+
+        // Two runs, each a marker and then one batch of logs.
+        let mut chain = Vec::new();
+        for boot_time_ms in [1_000, 2_000] {
+            let marker = session_start(&config, &key, &head, 0, 0, boot_time_ms);
+            assert_eq!((marker.epoch, marker.seq), (0, head.last_seq + 1));
+            assert_eq!(marker.prev_hash, head.last_hash);
+            assert_eq!(
+                marker.accumulator,
+                Some(marker.expected_accumulator(head.last_accumulator.as_ref()))
+            );
+            assert!(marker.verify() && marker.check_kind().is_ok() && marker.check_epoch().is_ok());
+            let mut logs = LogBatch {
+                prev_hash: marker.compute_hash(),
+                seq: marker.seq + 1,
+                kind: None,
+                logs: vec!["line".into()],
+                accumulator: None,
+                ..marker.clone()
+            };
+            logs.accumulator = Some(logs.expected_accumulator(marker.accumulator.as_ref()));
+            logs.sign(&key);
+            head.last_seq = logs.seq;
+            head.last_hash = logs.compute_hash();
+            head.last_accumulator = logs.accumulator;
+            chain.extend([marker, logs]);
+        }
+
+        let sessions: Vec<_> = chain
+            .iter()
+            .filter_map(|batch| {
+                let Some(BatchKind::SessionStart {
+                    session_id,
+                    boot_time_ms,
+                }) = &batch.kind
+                else {
+                    return None;
+                };
+                Some((batch.seq, session_id.clone(), *boot_time_ms))
+            })
+            .collect();
+        assert_eq!(sessions.len(), 2, "one marker per run");
+        assert_eq!((sessions[0].0, sessions[0].2), (1, 1_000));
+        assert_eq!((sessions[1].0, sessions[1].2), (3, 2_000));
+        assert_ne!(
+            sessions[0].1, sessions[1].1,
+            "every run gets its own session id"
+        );
+
+        // A full epoch: the marker opens the next one like any batch would.
+        config.epoch_max_seq = Some(4);
+        head.last_seq = 4;
+        let opener = session_start(&config, &key, &head, 0, 0, 3_000);
+        assert_eq!((opener.epoch, opener.seq), (1, 1));
+        assert!(opener.verify() && opener.check_epoch().is_ok());
+    }
+
+    #[test]
+    fn epochs_end_at_the_seq_or_age_bound() {
+        let mut config = test_config("http://unused".into());
+        assert_eq!(
+            next_position(&config, 0, 0, 1_000, u64::MAX),
+            (0, 1_000, None)
+        );
+
+        config.epoch_max_seq = Some(3);
+        assert_eq!(next_position(&config, 0, 0, 3, 0), (0, 3, None));
+        let (epoch, seq, start) = next_position(&config, 0, 0, 4, 0);
+        assert_eq!((epoch, seq, start.unwrap().previous_last_seq), (1, 1, 3));
+
+        config.epoch_max_seq = None;
+        config.epoch_max_age_secs = Some(60);
+        assert_eq!(next_position(&config, 2, 1_000, 5, 60_999), (2, 5, None));
+        let (epoch, seq, start) = next_position(&config, 2, 1_000, 5, 61_000);
+        assert_eq!((epoch, seq, start.unwrap().previous_last_seq), (3, 1, 4));
+        // An epoch past its age with no batch yet keeps its first one.
+        assert_eq!(next_position(&config, 3, 0, 1, 61_000), (3, 1, None));
+    }
+
+    #[tokio::test]
+    async fn uploads_are_gzipped_only_when_it_pays() {
+        let (url, arrivals) = mock_server().await;
+        let mut config = test_config(url);
+        config.gzip_uploads = true;
+        let mut throttle = config.throttle();
+
+        // Random printable ASCII: gzip saves well under the default 25%.
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let noise: String = (0..4000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                char::from(b' ' + (state % 95) as u8)
+            })
+            .collect();
+        let key = generate_keypair();
+        let mut random = batch(1);
+        random.logs = vec![noise];
+        random.sign(&key);
+        let json = serde_json::to_vec(&random).unwrap();
+        assert!(!encode_upload(json.clone(), &config).unwrap().gzipped);
+        send_batch(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &AgentMetrics::default(),
+            &random,
+        )
+        .await
+        .unwrap();
+
+        // Repetitive lines shrink a lot and go out gzipped, unless gzip is off.
+        let repetitive = batch(2);
+        send_batch(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &AgentMetrics::default(),
+            &repetitive,
+        )
+        .await
+        .unwrap();
+        config.gzip_uploads = false;
+        send_batch(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &AgentMetrics::default(),
+            &repetitive,
+        )
+        .await
+        .unwrap();
+
+        let heads: Vec<bool> = arrivals
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, head)| head.contains("content-encoding: gzip"))
+            .collect();
+        assert_eq!(heads, [false, true, false]);
+
+        // A threshold of 100% can never be met.
+        config.gzip_uploads = true;
+        config.gzip_min_saving_pct = 100;
+        assert!(
+            !encode_upload(serde_json::to_vec(&repetitive).unwrap(), &config)
+                .unwrap()
+                .gzipped
+        );
+    }
+
+    #[tokio::test]
+    async fn wire_format_sets_the_body_encoding_and_content_type() {
+        let (url, arrivals) = mock_server().await;
+        let mut config = test_config(url);
+        let mut throttle = config.throttle();
+        send_batch(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &AgentMetrics::default(),
+            &batch(1),
+        )
+        .await
+        .unwrap();
+        config.wire_format = WireFormat::Msgpack;
+        send_batch(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &AgentMetrics::default(),
+            &batch(2),
+        )
+        .await
+        .unwrap();
+
+        let types: Vec<bool> = arrivals
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, head)| head.contains("content-type: application/msgpack"))
+            .collect();
+        assert_eq!(types, [false, true]);
+        let body = encode_upload(WireFormat::Msgpack.encode(&batch(2)).unwrap(), &config).unwrap();
+        assert_eq!(body.content_type, "application/msgpack");
+        assert_eq!(WireFormat::Msgpack.decode(&body.bytes).unwrap().seq, 2);
+    }
+
+    #[tokio::test]
+    async fn receipts_of_accepted_batches_are_kept_as_acks() {
+        let server_key = ed25519_dalek::SigningKey::from_bytes(&[9; 32]);
+        let sent = batch(4);
+        let receipt = Receipt::issue(
+            &server_key,
+            1,
+            40,
+            "agent-test",
+            (0, 4),
+            sent.compute_hash(),
+            1_000,
+        );
+        let body =
+            serde_json::json!({ "status": "ok", "message": "batch stored", "receipt": receipt });
+        let (url, _) = mock_server_replying(body.to_string()).await;
+        let mut config = test_config(url);
+        config.state_dir = env::temp_dir().join(format!("agent-keep-acks-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&config.state_dir);
+        let mut throttle = config.throttle();
+
+        send_batch(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &AgentMetrics::default(),
+            &sent,
+        )
+        .await
+        .unwrap();
+        let kept = acks::load(&config.state_dir).unwrap();
+        assert_eq!(kept, [receipt]);
+
+        // A receipt for another batch is not kept.
+        send_batch(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &AgentMetrics::default(),
+            &batch(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(acks::load(&config.state_dir).unwrap(), kept);
+        let _ = fs::remove_dir_all(&config.state_dir);
+    }
+
+    #[tokio::test]
+    async fn accepted_batches_are_journaled_with_their_receipts() {
+        let server_key = ed25519_dalek::SigningKey::from_bytes(&[9; 32]);
+        let sent = batch(4);
+        let receipt = Receipt::issue(
+            &server_key,
+            1,
+            40,
+            "agent-test",
+            (0, 4),
+            sent.compute_hash(),
+            1_000,
+        );
+        let body =
+            serde_json::json!({ "status": "ok", "message": "batch stored", "receipt": receipt });
+        let (url, _) = mock_server_replying(body.to_string()).await;
+        let mut config = test_config(url);
+        config.state_dir = env::temp_dir().join(format!("agent-journal-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&config.state_dir);
+        config.journal_writer =
+            Some(journal::Journal::start(&config.state_dir, 1 << 20, 3600).unwrap());
+        let mut throttle = config.throttle();
+
+        let before = Utc::now().timestamp_millis() as u64;
+        send_batch(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &AgentMetrics::default(),
+            &sent,
+        )
+        .await
+        .unwrap();
+        let mut journaled = Vec::new();
+        for _ in 0..100 {
+            journaled = journal::query(&config.state_dir, before, None).unwrap();
+            if !journaled.is_empty() {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(journaled.len(), 1);
+        assert_eq!(journaled[0].seq, 4);
+        assert_eq!(journaled[0].hash, hex_encode(&sent.compute_hash()));
+        assert_eq!(journaled[0].receipt, Some(receipt));
+        assert!(
+            journal::query(&config.state_dir, before, Some("nothing like it"))
+                .unwrap()
+                .is_empty()
+        );
+        let _ = fs::remove_dir_all(&config.state_dir);
+    }
+
+    #[test]
+    fn a_key_is_generated_only_on_a_first_start() {
+        let dir = env::temp_dir().join(format!("agent-key-start-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let key_path = AgentConfig::key_path(&dir);
+
+        let key = load_or_generate_key(&dir).unwrap();
+        assert_eq!(
+            load_or_generate_key(&dir).unwrap().to_bytes(),
+            key.to_bytes()
+        );
+
+        // Once the chain has state, a missing key is not replaced.
+        fs::write(dir.join("seq.txt"), "5").unwrap();
+        fs::remove_file(&key_path).unwrap();
+        let err = load_or_generate_key(&dir).unwrap_err();
+        assert!(err.to_string().contains("refusing to generate"), "{err}");
+        assert!(!key_path.exists());
+
+        // Nor is a corrupt one, first start or not.
+        fs::remove_file(dir.join("seq.txt")).unwrap();
+        fs::write(&key_path, b"truncated").unwrap();
+        let err = load_or_generate_key(&dir).unwrap_err();
+        assert!(err.to_string().contains("corrupt"), "{err}");
+        assert_eq!(fs::read(&key_path).unwrap(), b"truncated");
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    let checkpoints: Vec<AgentCheckpoint> = resp.json().await?;
-    Ok(checkpoints.into_iter().find(|cp| cp.agent_id == agent_id))
+    #[test]
+    fn a_key_lost_at_runtime_is_fatal() {
+        let mut config = test_config("http://127.0.0.1:9".into());
+        config.state_dir =
+            env::temp_dir().join(format!("agent-key-runtime-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&config.state_dir);
+        fs::create_dir_all(&config.state_dir).unwrap();
+        let key_path = AgentConfig::key_path(&config.state_dir);
+        let key = load_or_generate_key(&config.state_dir).unwrap();
+        check_key(&config, &key).unwrap();
+
+        fs::remove_file(&key_path).unwrap();
+        let err = check_key(&config, &key).unwrap_err();
+        assert!(
+            err.to_string().contains("disappeared while running"),
+            "{err}"
+        );
+        assert!(load_key(&config).is_err());
+        assert!(!key_path.exists());
+
+        platform::write_private(&key_path, &[7; 32]).unwrap();
+        let err = check_key(&config, &key).unwrap_err();
+        assert!(err.to_string().contains("replaced while running"), "{err}");
+        fs::remove_dir_all(&config.state_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn finalizing_sends_one_close_after_the_server_head_and_retires_the_state_dir() {
+        let head = [4u8; 32];
+        let cp = serde_json::json!([{ "agent_id": "test-agent", "last_seq": 7, "last_hash": head, "count": 7 }]);
+        let (url, arrivals) = mock_server_replying(cp.to_string()).await;
+        let mut config = test_config(url);
+        config.agent_id = "test-agent".into();
+        config.state_dir = env::temp_dir().join(format!("agent-finalize-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&config.state_dir);
+        fs::create_dir_all(&config.state_dir).unwrap();
+        config.spool = true;
+        let key = spool::SpoolKey::derive(&load_or_generate_key(&config.state_dir).unwrap());
+
+        assert!(
+            finalize::run(&config, None, true).await.is_err(),
+            "a close needs a reason"
+        );
+        assert!(
+            finalize::run(&config, Some("decommissioned"), false)
+                .await
+                .is_err()
+        );
+        assert!(arrivals.lock().unwrap().is_empty());
+        finalize::run(&config, Some("decommissioned"), true)
+            .await
+            .unwrap();
+
+        let sent = spool::load(&config.state_dir, &key).unwrap();
+        assert_eq!(sent.len(), 1);
+        let close = &sent[0];
+        assert_eq!((close.seq, close.prev_hash), (8, head));
+        assert_eq!(close.closed_reason(), Some("decommissioned"));
+        assert!(close.logs.is_empty() && close.verify());
+        assert_eq!(load_seq(&config).unwrap(), 9);
+        let closed = finalize::load(&config.state_dir).unwrap().unwrap();
+        assert_eq!(
+            (closed.seq, closed.hash),
+            (8, hex_encode(&close.compute_hash()))
+        );
+
+        // The state dir is retired: neither a second close nor a run goes on.
+        let err = finalize::check_open(&config.state_dir).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("closed at epoch 0 seq 8 (decommissioned)"),
+            "{err}"
+        );
+        assert!(finalize::run(&config, Some("again"), true).await.is_err());
+        assert_eq!(spool::load(&config.state_dir, &key).unwrap().len(), 1);
+        fs::remove_dir_all(&config.state_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotating_the_key_sends_a_v2_request_and_keeps_the_agent_id() {
+        use common::rotation::{NONCE_BYTES, RotateRequest, rotation_envelope};
+
+        let refuse = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let refusing = refuse.clone();
+        let (url, _, bodies) = mock_server_recording(move || {
+            let (status, message) = if refusing.load(std::sync::atomic::Ordering::SeqCst) {
+                ("409 Conflict", "rotation nonce already used; sign a new request")
+            } else {
+                ("200 OK", "Key rotated")
+            };
+            let body = serde_json::json!({ "status": "error", "message": message }).to_string();
+            format!(
+                "HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{body}",
+                body.len()
+            )
+        })
+        .await;
+        let mut config = test_config(url);
+        config.state_dir = env::temp_dir().join(format!("agent-rotate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&config.state_dir);
+        fs::create_dir_all(&config.state_dir).unwrap();
+        config.agent_id = derive_agent_id(&config.state_dir).unwrap();
+        let old = load_key(&config).unwrap();
+        let key_path = AgentConfig::key_path(&config.state_dir);
+
+        // A refusal leaves the key in place and keeps the pending one for a retry.
+        let err = rotate::run(&config).await.unwrap_err();
+        assert!(err.to_string().contains("nonce already used"), "{err}");
+        assert_eq!(load_key(&config).unwrap().to_bytes(), old.to_bytes());
+        assert_eq!(rotate::load_counter(&config.state_dir).unwrap(), 0);
+
+        refuse.store(false, std::sync::atomic::Ordering::SeqCst);
+        rotate::run(&config).await.unwrap();
+        let new = load_key(&config).unwrap();
+        assert_ne!(new.to_bytes(), old.to_bytes());
+        assert!(!config.state_dir.join("agent.key.next").exists());
+        assert_eq!(rotate::load_counter(&config.state_dir).unwrap(), 1);
+        assert_eq!(derive_agent_id(&config.state_dir).unwrap(), config.agent_id);
+        assert_eq!(
+            read_key(&key_path).unwrap().unwrap().to_bytes(),
+            new.to_bytes()
+        );
+
+        // Both requests were v2, signed by the old key, for the key now in use,
+        // each under its own nonce.
+        let sent: Vec<RotateRequest> = bodies
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|body| serde_json::from_slice(body).unwrap())
+            .collect();
+        assert_eq!(sent.len(), 2);
+        assert_ne!(sent[0].nonce, sent[1].nonce);
+        for req in &sent {
+            assert_eq!(req.agent_id, config.agent_id);
+            assert_eq!(req.counter, 1);
+            assert_eq!(
+                req.new_public_key_hex,
+                hex_encode(&new.verifying_key().to_bytes())
+            );
+            let nonce = req.nonce.as_deref().unwrap();
+            assert_eq!(nonce.len(), NONCE_BYTES * 2);
+            let envelope = rotation_envelope(
+                &req.agent_id,
+                &new.verifying_key(),
+                req.counter,
+                req.timestamp.unwrap(),
+                Some(nonce),
+            );
+            let signature: [u8; 64] = common::hex::hex_decode(&req.auth_signature_hex)
+                .unwrap()
+                .try_into()
+                .unwrap();
+            old.verifying_key()
+                .verify_strict(&envelope, &ed25519_dalek::Signature::from_bytes(&signature))
+                .unwrap();
+        }
+        fs::remove_dir_all(&config.state_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_key_rotation_re_encrypts_the_spool_so_it_still_replays() {
+        use common::testutil::build_chain;
+
+        let (rotate_url, _) =
+            mock_server_replying(r#"{"status":"ok","message":"Key rotated"}"#.into()).await;
+        let mut config = test_config(rotate_url);
+        config.state_dir =
+            env::temp_dir().join(format!("agent-spool-rotate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&config.state_dir);
+        fs::create_dir_all(&config.state_dir).unwrap();
+        config.agent_id = derive_agent_id(&config.state_dir).unwrap();
+        config.spool = true;
+        config.spool_encrypt = true;
+        let old = load_key(&config).unwrap();
+        let old_spool_key = spool::SpoolKey::derive(&old);
+
+        // Spooled under the old key, in the state dir and in an older archive.
+        let chain = build_chain(&old, &config.agent_id, 3);
+        let archive = config.state_dir.join("reanchor-1");
+        for batch in &chain {
+            spool::persist(&config.state_dir, batch, Some(&old_spool_key)).unwrap();
+            spool::persist(&archive, batch, Some(&old_spool_key)).unwrap();
+        }
+        fs::write(archive.join("done"), "").unwrap();
+
+        rotate::run(&config).await.unwrap();
+        let new = load_key(&config).unwrap();
+        let new_spool_key = spool::SpoolKey::derive(&new);
+        for dir in [&config.state_dir, &archive] {
+            let sealed = fs::read(spool::spool_dir(dir).join(acks::file_name(0, 1))).unwrap();
+            assert!(sealed.starts_with(b"LCSPOOL"));
+        }
+        let loaded = spool::load(&archive, &new_spool_key).unwrap();
+        assert_eq!(
+            loaded
+                .iter()
+                .map(LogBatch::compute_hash)
+                .collect::<Vec<_>>(),
+            chain.iter().map(LogBatch::compute_hash).collect::<Vec<_>>()
+        );
+
+        // Replayed onto a fresh server under the new key: every batch decrypts
+        // and verifies, and the new spool is sealed under the new key.
+        let (fresh_url, arrivals) = mock_server_replying("[]".into()).await;
+        config.server_url = fresh_url;
+        config.spool_key = Some(spool::SpoolKey::derive(&new));
+        reanchor::run(&config, true).await.unwrap();
+        let submits = arrivals
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, head)| head.starts_with("post /submit"))
+            .count();
+        assert_eq!(submits, 3);
+        let replayed = spool::load(&config.state_dir, &new_spool_key).unwrap();
+        assert_eq!(
+            replayed.iter().map(|b| b.logs.clone()).collect::<Vec<_>>(),
+            chain.iter().map(|b| b.logs.clone()).collect::<Vec<_>>()
+        );
+        assert!(replayed.iter().all(|b| b.public_key == new.verifying_key()));
+        assert!(spool::quarantined(&config.state_dir).unwrap().is_empty());
+        fs::remove_dir_all(&config.state_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn re_anchoring_replays_the_spooled_history_onto_a_fresh_server() {
+        use common::testutil::{append_gap, build_chain, extend_chain, start_epoch};
+
+        let (url, arrivals) = mock_server_replying("[]".into()).await;
+        let mut config = test_config(url);
+        config.state_dir = env::temp_dir().join(format!("agent-re-anchor-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&config.state_dir);
+        fs::create_dir_all(&config.state_dir).unwrap();
+        config.spool = true;
+        let key = load_or_generate_key(&config.state_dir).unwrap();
+
+        // The old server's history: 100 batches across an epoch start, plus a
+        // gap marker, every one spooled and acked.
+        let server_key = ed25519_dalek::SigningKey::from_bytes(&[9; 32]);
+        let mut old = build_chain(&key, &config.agent_id, 40);
+        append_gap(&mut old, &key, 2, "disk full");
+        extend_chain(&mut old, &key, 19);
+        start_epoch(&mut old, &key);
+        extend_chain(&mut old, &key, 40);
+        for (id, batch) in old.iter().enumerate() {
+            spool::persist(&config.state_dir, batch, None).unwrap();
+            let receipt = Receipt::issue(
+                &server_key,
+                1,
+                id as i64 + 1,
+                &config.agent_id,
+                batch.position(),
+                batch.compute_hash(),
+                1_000,
+            );
+            acks::persist(&config.state_dir, &receipt).unwrap();
+        }
+        let lines: Vec<_> = old
+            .iter()
+            .filter(|b| !b.logs.is_empty())
+            .map(|b| (b.timestamp, b.logs.clone()))
+            .collect();
+        assert_eq!(lines.len(), 100);
+
+        assert!(reanchor::run(&config, false).await.is_err());
+        assert!(
+            spool::load(&config.state_dir, &spool::SpoolKey::derive(&key))
+                .unwrap()
+                .len()
+                == old.len()
+        );
+        reanchor::run(&config, true).await.unwrap();
+
+        // What the fresh server was sent is one contiguous chain with the same content.
+        let submits = arrivals
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, head)| head.starts_with("post /submit"))
+            .count();
+        assert_eq!(submits, 100);
+        let new = spool::load(&config.state_dir, &spool::SpoolKey::derive(&key)).unwrap();
+        assert_eq!(
+            new.iter().map(|b| b.seq).collect::<Vec<_>>(),
+            (1..=100).collect::<Vec<_>>()
+        );
+        assert!(
+            new.iter()
+                .all(|b| b.epoch == 0 && b.gap.is_none() && b.verify())
+        );
+        for pair in new.windows(2) {
+            assert_eq!(pair[1].prev_hash, pair[0].compute_hash());
+            assert_eq!(
+                pair[1].accumulator,
+                Some(pair[1].expected_accumulator(pair[0].accumulator.as_ref()))
+            );
+        }
+        let replayed: Vec<_> = new.iter().map(|b| (b.timestamp, b.logs.clone())).collect();
+        assert_eq!(replayed, lines);
+        assert_eq!(load_seq(&config).unwrap(), 101);
+        assert_eq!(load_prev_hash(&config).unwrap(), new[99].compute_hash());
+        let log = fs::read_to_string(config.state_dir.join("reanchors.jsonl")).unwrap();
+        assert_eq!(log.lines().count(), 2);
+        assert!(log.contains("\"event\":\"completed\""));
+
+        // The server now holds the chain: a second run refuses.
+        let cp = serde_json::json!([{ "agent_id": config.agent_id, "last_seq": 100, "last_hash": new[99].compute_hash(), "count": 100 }]);
+        let (resumed_url, _) = mock_server_replying(cp.to_string()).await;
+        config.server_url = resumed_url;
+        assert!(reanchor::run(&config, true).await.is_err());
+
+        // An interrupted run resumes after the server's checkpoint.
+        let archive = fs::read_dir(&config.state_dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| {
+                p.file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .starts_with("reanchor-")
+            })
+            .unwrap();
+        fs::remove_file(archive.join("done")).unwrap();
+        let cp = serde_json::json!([{ "agent_id": config.agent_id, "last_seq": 60, "last_hash": new[59].compute_hash(), "count": 60 }]);
+        let (url, arrivals) = mock_server_replying(cp.to_string()).await;
+        config.server_url = url;
+        reanchor::run(&config, true).await.unwrap();
+        assert_eq!(
+            arrivals
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, head)| head.starts_with("post /submit"))
+                .count(),
+            40
+        );
+        assert!(archive.join("done").exists());
+        let _ = fs::remove_dir_all(&config.state_dir);
+    }
+
+    #[tokio::test]
+    async fn sends_are_counted_and_pushed_to_the_agents_group() {
+        let (url, arrivals) = mock_server().await;
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let metrics = AgentMetrics::default();
+        let mut throttle = Throttle::new(None, None, None, None);
+        send_batch(
+            &test_config(url.clone()),
+            &mut throttle,
+            &mut Breaker::default(),
+            &metrics,
+            &batch(3),
+        )
+        .await
+        .unwrap();
+        let mut unreachable = test_config(closed.clone());
+        unreachable.max_retries = 3;
+        assert!(
+            send_batch(
+                &unreachable,
+                &mut throttle,
+                &mut Breaker::default(),
+                &metrics,
+                &batch(4)
+            )
+            .await
+            .is_err()
+        );
+
+        let text = metrics.render();
+        for line in [
+            "logchain_agent_batches_sent_total 1",
+            "logchain_agent_batches_failed_total 1",
+            "logchain_agent_retries_total 2",
+            "logchain_agent_current_seq 3",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{line} missing from\n{text}"
+            );
+        }
+
+        let client = reqwest::Client::new();
+        let group = metrics::group_url(&url, "agent-test", "host-1");
+        metrics::push(&client, &group, &metrics).await.unwrap();
+        let head = arrivals.lock().unwrap().last().unwrap().1.clone();
+        assert!(
+            head.starts_with("put /metrics/job/logchain_agent/agent_id/agent-test/host/host-1 "),
+            "{head}"
+        );
+        // An unreachable gateway is an error to log, not to stop on.
+        let group = metrics::group_url(&closed, "agent-test", "host-1");
+        assert!(metrics::push(&client, &group, &metrics).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn maintenance_holds_batches_without_spending_retries() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let paused = Arc::new(AtomicBool::new(true));
+        let server_paused = paused.clone();
+        let (url, arrivals) = mock_server_answering(move || {
+            let (status, retry_after, body) = if server_paused.load(Ordering::Relaxed) {
+                (
+                    "503 Service Unavailable",
+                    "retry-after: 1\r\n",
+                    r#"{"status":"error","message":"server is in maintenance mode; retry later"}"#,
+                )
+            } else {
+                ("201 Created", "", "{}")
+            };
+            format!(
+                "HTTP/1.1 {status}\r\n{retry_after}content-type: application/json\r\ncontent-length: {}\r\n\r\n{body}",
+                body.len()
+            )
+        })
+        .await;
+        // One attempt each: a deferral that spent a retry would drop the batch.
+        let config = test_config(url);
+        let metrics = Arc::new(AgentMetrics::default());
+        let started = Instant::now();
+        let sender = {
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                let mut throttle = Throttle::new(None, None, None, None);
+                for seq in 1..=3 {
+                    send_batch(
+                        &config,
+                        &mut throttle,
+                        &mut Breaker::default(),
+                        &metrics,
+                        &batch(seq),
+                    )
+                    .await
+                    .unwrap();
+                }
+            })
+        };
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(
+            arrivals.lock().unwrap().len(),
+            1,
+            "held for Retry-After, not retry_base_ms"
+        );
+        paused.store(false, Ordering::Relaxed);
+        sender.await.unwrap();
+
+        // The held batch went first once the flag cleared, then the rest.
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(arrivals.lock().unwrap().len(), 4);
+        let text = metrics.render();
+        for line in [
+            "logchain_agent_batches_sent_total 3",
+            "logchain_agent_batches_failed_total 0",
+            "logchain_agent_deferrals_total 1",
+            "logchain_agent_current_seq 3",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{line} missing from\n{text}"
+            );
+        }
+
+        // A 503 without Retry-After is a fault, and uses the retry.
+        let (faulty, _) = mock_server_answering(|| {
+            "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n".to_string()
+        })
+        .await;
+        let mut throttle = Throttle::new(None, None, None, None);
+        assert!(
+            send_batch(
+                &test_config(faulty),
+                &mut throttle,
+                &mut Breaker::default(),
+                &metrics,
+                &batch(4)
+            )
+            .await
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_fails_fast_only_on_a_real_mismatch() {
+        let v1_only = serde_json::json!({
+            "server_version": "0.0.9",
+            "batch_versions": [1],
+            "hash_schemes": ["accumulator-v1", "receipt-v1"],
+            "encodings": ["identity"],
+        });
+        let (old_server, _) = mock_server_replying(v1_only.to_string()).await;
+        let mut config = test_config(old_server);
+        let produces = |batch_version| {
+            Some(Produces {
+                batch_version,
+                gzip: false,
+                wire_format: WireFormat::Json,
+            })
+        };
+
+        let err = check_compat(&config, produces(CURRENT_BATCH_VERSION))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "incompatible server 0.0.9: server supports batch v1 only; this agent produces v3 — \
+             pass --batch-version 1 or upgrade the server"
+        );
+        check_compat(&config, produces(BATCH_VERSION_V1))
+            .await
+            .unwrap();
+        // Reading acks needs nothing the old server lacks.
+        check_compat(&config, None).await.unwrap();
+        config.skip_compat_check = true;
+        check_compat(&config, produces(CURRENT_BATCH_VERSION))
+            .await
+            .unwrap();
+
+        // A server from before the handshake, or none at all, is let through.
+        let (legacy, _) = mock_server_answering(|| {
+            "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n".to_string()
+        })
+        .await;
+        check_compat(&test_config(legacy), produces(CURRENT_BATCH_VERSION))
+            .await
+            .unwrap();
+        let down = test_config("http://127.0.0.1:1".into());
+        check_compat(&down, produces(CURRENT_BATCH_VERSION))
+            .await
+            .unwrap();
+
+        // v1 batches count seconds.
+        assert_eq!(
+            batch_timestamp(BATCH_VERSION_V1, 1_700_000_000_999),
+            1_700_000_000
+        );
+        assert_eq!(
+            batch_timestamp(CURRENT_BATCH_VERSION, 1_700_000_000_999),
+            1_700_000_000_999
+        );
+    }
+
+    #[tokio::test]
+    async fn a_seq_clash_fails_at_once_but_a_plain_conflict_is_retried() {
+        let conflict = |body: &'static str| {
+            move || {
+                format!(
+                    "HTTP/1.1 409 Conflict\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{body}",
+                    body.len()
+                )
+            }
+        };
+        let (clashing, clash_arrivals) = mock_server_answering(conflict(
+            r#"{"status":"error","message":"a different batch is already stored at this seq","stored_hash":"ab"}"#,
+        ))
+        .await;
+        let (conflicting, conflict_arrivals) = mock_server_answering(conflict(
+            r#"{"status":"error","message":"seq must increment: expected 3, got 1"}"#,
+        ))
+        .await;
+
+        for (url, arrivals, attempts) in [
+            (clashing, clash_arrivals, 1),
+            (conflicting, conflict_arrivals, 3),
+        ] {
+            let mut config = test_config(url);
+            config.max_retries = 3;
+            let mut throttle = Throttle::new(None, None, None, None);
+            let err = send_batch(
+                &config,
+                &mut throttle,
+                &mut Breaker::default(),
+                &AgentMetrics::default(),
+                &batch(1),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(arrivals.lock().unwrap().len(), attempts, "{err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_prolonged_outage_pauses_reading_on_a_held_batch_under_pause() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Down for the first six attempts, then up.
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let seen = attempts.clone();
+        let (url, arrivals) = mock_server_answering(move || {
+            if seen.fetch_add(1, Ordering::SeqCst) < 6 {
+                "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\n\r\n".to_string()
+            } else {
+                "HTTP/1.1 201 Created\r\ncontent-type: application/json\r\ncontent-length: 2\r\n\r\n{}".to_string()
+            }
+        })
+        .await;
+        let mut config = test_config(url);
+        config.state_dir =
+            env::temp_dir().join(format!("agent-backpressure-{}", std::process::id()));
+        config.backpressure = backpressure::Policy::Pause;
+        config.pause_after_failures = 2;
+        fs::create_dir_all(&config.state_dir).unwrap();
+        let key = load_or_generate_key(&config.state_dir).unwrap();
+        let metrics = AgentMetrics::default();
+        let inflight = Inflight::new(1);
+        let mut throttle = Throttle::new(None, None, None, None);
+        let mut pressure = Backpressure::default();
+        let mut never = std::pin::pin!(std::future::pending::<()>());
+
+        // The first failure is dropped as before; the second is held and
+        // resent through the rest of the outage.
+        let first = send_or_hold(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &metrics,
+            &inflight,
+            &mut pressure,
+            &key,
+            &batch(1),
+            &mut never,
+        )
+        .await;
+        assert!(first.unwrap().is_err());
+        let second = send_or_hold(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &metrics,
+            &inflight,
+            &mut pressure,
+            &key,
+            &batch(1),
+            &mut never,
+        )
+        .await;
+        assert!(second.unwrap().is_ok());
+        assert_eq!(arrivals.lock().unwrap().len(), 7);
+        let text = metrics.render();
+        for line in [
+            "logchain_agent_batches_failed_total 6",
+            "logchain_agent_batches_sent_total 1",
+            "logchain_agent_paused 0",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{line} missing from\n{text}"
+            );
+        }
+
+        // A shutdown while holding leaves the batch unsent, and reading paused.
+        let (down, _) = mock_server_answering(|| {
+            "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\n\r\n".to_string()
+        })
+        .await;
+        config.server_url = down;
+        // The sent batch reset the count: one drop before the next pause.
+        let sent = send_or_hold(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &metrics,
+            &inflight,
+            &mut pressure,
+            &key,
+            &batch(2),
+            &mut never,
+        )
+        .await;
+        assert!(sent.unwrap().is_err());
+        let mut shutdown = std::pin::pin!(sleep(Duration::from_millis(100)));
+        assert!(
+            send_or_hold(
+                &config,
+                &mut throttle,
+                &mut Breaker::default(),
+                &metrics,
+                &inflight,
+                &mut pressure,
+                &key,
+                &batch(2),
+                &mut shutdown
+            )
+            .await
+            .is_none()
+        );
+        assert!(
+            metrics
+                .render()
+                .lines()
+                .any(|l| l == "logchain_agent_paused 1")
+        );
+
+        // Under drop, every failure is returned at once.
+        config.backpressure = backpressure::Policy::Drop;
+        let mut pressure = Backpressure::default();
+        for seq in 3..=5 {
+            let sent = send_or_hold(
+                &config,
+                &mut throttle,
+                &mut Breaker::default(),
+                &metrics,
+                &inflight,
+                &mut pressure,
+                &key,
+                &batch(seq),
+                &mut never,
+            )
+            .await;
+            assert!(sent.unwrap().is_err());
+        }
+        let _ = fs::remove_dir_all(&config.state_dir);
+    }
+
+    #[tokio::test]
+    async fn permanent_rejections_are_dead_lettered_and_never_held() {
+        let reply = |status: &str, body: &str| {
+            format!(
+                "HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{body}",
+                body.len()
+            )
+        };
+        let bad_signature = reply(
+            "400 Bad Request",
+            r#"{"status":"error","message":"invalid signature","code":"invalid_signature"}"#,
+        );
+        let (url, arrivals) = mock_server_answering(move || bad_signature.clone()).await;
+        let mut config = test_config(url);
+        config.max_retries = 3;
+        config.state_dir = env::temp_dir().join(format!("agent-deadletter-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&config.state_dir);
+        // Pause would hold any other failure; this one is not retried at all.
+        config.backpressure = backpressure::Policy::Pause;
+        config.pause_after_failures = 1;
+        fs::create_dir_all(&config.state_dir).unwrap();
+        let key = load_or_generate_key(&config.state_dir).unwrap();
+        let metrics = AgentMetrics::default();
+        let mut throttle = Throttle::new(None, None, None, None);
+        let mut never = std::pin::pin!(std::future::pending::<()>());
+
+        let sent = send_or_hold(
+            &config,
+            &mut throttle,
+            &mut Breaker::default(),
+            &metrics,
+            &Inflight::new(1),
+            &mut Backpressure::default(),
+            &key,
+            &batch(7),
+            &mut never,
+        )
+        .await;
+        let err = sent.unwrap().unwrap_err();
+        let letter = err.downcast_ref::<deadletter::DeadLettered>().unwrap();
+        assert_eq!((letter.seq, letter.code.as_str()), (7, "invalid_signature"));
+        assert_eq!(arrivals.lock().unwrap().len(), 1);
+        let kept =
+            fs::read(deadletter::deadletter_dir(&config.state_dir).join(acks::file_name(0, 7)))
+                .unwrap();
+        let kept: deadletter::DeadLetter = serde_json::from_slice(&kept).unwrap();
+        assert_eq!(kept.batch.compute_hash(), batch(7).compute_hash());
+        assert_eq!(kept.reason, "400 Bad Request: invalid signature");
+        assert!(
+            metrics
+                .render()
+                .lines()
+                .any(|l| l == "logchain_agent_batches_dead_lettered_total 1")
+        );
+
+        // A transient rejection, or one without a code, spends the retries.
+        for body in [
+            r#"{"status":"error","message":"forbidden","code":"forbidden"}"#,
+            r#"{"status":"error","message":"invalid signature"}"#,
+        ] {
+            let forbidden = reply("403 Forbidden", body);
+            let (url, arrivals) = mock_server_answering(move || forbidden.clone()).await;
+            config.server_url = url;
+            let err = send_batch(
+                &config,
+                &mut throttle,
+                &mut Breaker::default(),
+                &metrics,
+                &batch(8),
+            )
+            .await
+            .unwrap_err();
+            assert!(!err.is::<deadletter::DeadLettered>());
+            assert_eq!(arrivals.lock().unwrap().len(), 3);
+        }
+        let _ = fs::remove_dir_all(&config.state_dir);
+    }
+
+    #[tokio::test]
+    async fn a_sustained_outage_opens_the_circuit_until_a_probe_gets_through() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let down = Arc::new(AtomicBool::new(true));
+        let server_down = down.clone();
+        let (url, arrivals) = mock_server_answering(move || {
+            if server_down.load(Ordering::Relaxed) {
+                "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\n\r\n".to_string()
+            } else {
+                "HTTP/1.1 201 Created\r\ncontent-type: application/json\r\ncontent-length: 2\r\n\r\n{}".to_string()
+            }
+        })
+        .await;
+        let mut config = test_config(url);
+        config.max_retries = 5;
+        config.breaker_threshold = 3;
+        config.breaker_cooldown_ms = 200;
+        let metrics = AgentMetrics::default();
+        let mut throttle = Throttle::new(None, None, None, None);
+        let mut breaker = Breaker::default();
+
+        // The third failed attempt opens the circuit, ending the retries early.
+        let err = send_batch(&config, &mut throttle, &mut breaker, &metrics, &batch(1))
+            .await
+            .unwrap_err();
+        assert!(err.is::<CircuitOpen>(), "{err:?}");
+        assert_eq!(arrivals.lock().unwrap().len(), 3);
+        // While open, batches and the checkpoint fail without a request.
+        let err = send_batch(&config, &mut throttle, &mut breaker, &metrics, &batch(1))
+            .await
+            .unwrap_err();
+        assert!(err.is::<CircuitOpen>());
+        assert!(
+            fetch_checkpoint(&config, &mut breaker, &metrics, "agent-test")
+                .await
+                .is_err()
+        );
+        assert_eq!(arrivals.lock().unwrap().len(), 3);
+        assert!(
+            metrics
+                .render()
+                .lines()
+                .any(|l| l == "logchain_agent_circuit_state 1")
+        );
+
+        // A failed probe opens it again at once, without using the retries.
+        sleep(Duration::from_millis(200)).await;
+        let err = send_batch(&config, &mut throttle, &mut breaker, &metrics, &batch(1))
+            .await
+            .unwrap_err();
+        assert!(err.is::<CircuitOpen>());
+        assert_eq!(arrivals.lock().unwrap().len(), 4);
+
+        // Once the server is back, the probe closes it.
+        down.store(false, Ordering::Relaxed);
+        sleep(Duration::from_millis(200)).await;
+        send_batch(&config, &mut throttle, &mut breaker, &metrics, &batch(1))
+            .await
+            .unwrap();
+        send_batch(&config, &mut throttle, &mut breaker, &metrics, &batch(2))
+            .await
+            .unwrap();
+        assert_eq!(arrivals.lock().unwrap().len(), 6);
+        let text = metrics.render();
+        for line in [
+            "logchain_agent_circuit_state 0",
+            "logchain_agent_circuit_opens_total 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{line} missing from\n{text}"
+            );
+        }
+    }
 }
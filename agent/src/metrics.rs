@@ -0,0 +1,283 @@
+//! The agent's counters, in Prometheus text format, and `--metrics-push-url`:
+//! pushing them to a Prometheus Pushgateway for agents nothing can scrape
+//! (ephemeral jobs, agents behind NAT).
+//!
+//! Lines wait in memory until a batch is due; `--spool` keeps only batches
+//! the server already accepted, so it is no backlog. The backlog is
+//! `logchain_agent_buffered_lines`, and
+//! `logchain_agent_source_buffered_lines{source=...}` splits it by source.
+//!
+//! Every push replaces the agent's group, keyed by `agent_id` and `host`
+//! under job `logchain_agent`; a last push on shutdown leaves the final
+//! counts behind. A failed push is logged once per run of failures and never
+//! stops the agent.
+
+use anyhow::{Result, bail};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, interval};
+
+pub const DEFAULT_PUSH_INTERVAL_SECS: u64 = 15;
+
+#[derive(Default)]
+pub struct AgentMetrics {
+    batches_sent: AtomicU64,
+    batches_failed: AtomicU64,
+    retries: AtomicU64,
+    deferrals: AtomicU64,
+    dead_lettered: AtomicU64,
+    buffered_lines: AtomicU64,
+    /// Buffered lines per source, by name, in config order.
+    source_buffered: Mutex<Vec<(String, usize)>>,
+    /// 1 while reading is paused; see [`crate::backpressure`].
+    paused: AtomicU64,
+    /// See [`crate::breaker::State::gauge`].
+    circuit_state: AtomicU64,
+    circuit_opens: AtomicU64,
+    /// Seq of the last batch the server accepted; 0 before the first.
+    last_seq: AtomicU64,
+}
+
+impl AgentMetrics {
+    pub fn sent(&self, seq: u64) {
+        self.batches_sent.fetch_add(1, Ordering::Relaxed);
+        self.last_seq.store(seq, Ordering::Relaxed);
+    }
+
+    /// A batch whose retries ran out or that timed out; dropped, unless
+    /// `--backpressure pause` holds it for another try.
+    pub fn failed(&self) {
+        self.batches_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An attempt after the first for the same batch.
+    pub fn retried(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An attempt the server asked to repeat later; see [`crate::Attempt`].
+    pub fn deferred(&self) {
+        self.deferrals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A batch the server rejected for good; see [`crate::deadletter`].
+    pub fn dead_lettered(&self) {
+        self.dead_lettered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_buffered_lines(&self, lines: usize) {
+        self.buffered_lines.store(lines as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_source_buffered(&self, source: &str, lines: usize) {
+        let mut depths = self.source_buffered.lock().unwrap();
+        match depths.iter_mut().find(|(name, _)| name == source) {
+            Some((_, depth)) => *depth = lines,
+            None => depths.push((source.to_string(), lines)),
+        }
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_circuit(&self, state: crate::breaker::State) {
+        self.circuit_state.store(state.gauge(), Ordering::Relaxed);
+    }
+
+    pub fn circuit_opened(&self) {
+        self.circuit_opens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The text exposition of every counter.
+    pub fn render(&self) -> String {
+        let series = [
+            (
+                "logchain_agent_batches_sent_total",
+                "counter",
+                "Batches the server accepted.",
+                &self.batches_sent,
+            ),
+            (
+                "logchain_agent_batches_failed_total",
+                "counter",
+                "Batch sends that exhausted retries or timed out; each is a dropped batch unless reading is paused.",
+                &self.batches_failed,
+            ),
+            (
+                "logchain_agent_retries_total",
+                "counter",
+                "Submit attempts after the first for a batch.",
+                &self.retries,
+            ),
+            (
+                "logchain_agent_deferrals_total",
+                "counter",
+                "Submit attempts the server asked to repeat later (rate limit or maintenance); they use no retry.",
+                &self.deferrals,
+            ),
+            (
+                "logchain_agent_batches_dead_lettered_total",
+                "counter",
+                "Batches the server rejected for good, kept in the dead-letter dir instead of resent.",
+                &self.dead_lettered,
+            ),
+            (
+                "logchain_agent_buffered_lines",
+                "gauge",
+                "Lines read and waiting for a batch, across sources.",
+                &self.buffered_lines,
+            ),
+            (
+                "logchain_agent_paused",
+                "gauge",
+                "1 while reading is paused until the server takes a held batch.",
+                &self.paused,
+            ),
+            (
+                "logchain_agent_circuit_state",
+                "gauge",
+                "The circuit breaker around the server: 0 closed, 1 open (no attempts go out), 2 half-open (probing).",
+                &self.circuit_state,
+            ),
+            (
+                "logchain_agent_circuit_opens_total",
+                "counter",
+                "Times the circuit breaker opened after failed attempts in a row.",
+                &self.circuit_opens,
+            ),
+            (
+                "logchain_agent_current_seq",
+                "gauge",
+                "Seq of the last batch the server accepted.",
+                &self.last_seq,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, kind, help, value) in series {
+            out.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {}\n",
+                value.load(Ordering::Relaxed)
+            ));
+        }
+        let name = "logchain_agent_source_buffered_lines";
+        out.push_str(&format!(
+            "# HELP {name} Lines read from a source and waiting for its next batch.\n# TYPE {name} gauge\n"
+        ));
+        for (source, lines) in self.source_buffered.lock().unwrap().iter() {
+            out.push_str(&format!("{name}{{source=\"{source}\"}} {lines}\n"));
+        }
+        out
+    }
+}
+
+/// The Pushgateway URL of this agent's group. `agent_id` and `host` become
+/// grouping labels, which Pushgateway attaches to every pushed series.
+pub fn group_url(base: &str, agent_id: &str, host: &str) -> String {
+    format!(
+        "{}/metrics/job/logchain_agent/agent_id/{}/host/{}",
+        base.trim_end_matches('/'),
+        path_segment(agent_id),
+        path_segment(host)
+    )
+}
+
+/// A label value as a path segment. Pushgateway would read a `/` as a
+/// separator; agent ids are hex and host names plain, so anything else is
+/// replaced with `_` rather than base64-encoded.
+fn path_segment(value: &str) -> String {
+    let plain: String = value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if plain.is_empty() {
+        "unknown".into()
+    } else {
+        plain
+    }
+}
+
+/// Replaces the group at `url` with the current counts.
+pub async fn push(client: &reqwest::Client, url: &str, metrics: &AgentMetrics) -> Result<()> {
+    let resp = client
+        .put(url)
+        .header(reqwest::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(metrics.render())
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        bail!("pushgateway answered {}", resp.status());
+    }
+    Ok(())
+}
+
+/// Pushes every `every` until the agent exits.
+pub fn spawn_pusher(metrics: Arc<AgentMetrics>, url: String, every: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let mut ticks = interval(every);
+        let mut failing = false;
+        loop {
+            ticks.tick().await;
+            match push(&client, &url, &metrics).await {
+                Ok(()) if failing => {
+                    println!("Metrics push to {url} succeeds again");
+                    failing = false;
+                }
+                Ok(()) => {}
+                Err(err) if !failing => {
+                    eprintln!("Metrics push to {url} failed, will keep trying: {err}");
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_render_as_prometheus_text_under_the_agents_group() {
+        let metrics = AgentMetrics::default();
+        metrics.retried();
+        metrics.sent(7);
+        metrics.failed();
+        metrics.set_buffered_lines(3);
+        metrics.set_source_buffered("audit", 1);
+        metrics.set_source_buffered("debug", 2);
+        let text = metrics.render();
+        for line in [
+            "# TYPE logchain_agent_batches_sent_total counter",
+            "logchain_agent_batches_sent_total 1",
+            "logchain_agent_batches_failed_total 1",
+            "logchain_agent_retries_total 1",
+            "# TYPE logchain_agent_buffered_lines gauge",
+            "logchain_agent_buffered_lines 3",
+            "logchain_agent_current_seq 7",
+            "logchain_agent_source_buffered_lines{source=\"audit\"} 1",
+            "logchain_agent_source_buffered_lines{source=\"debug\"} 2",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{line} missing from\n{text}"
+            );
+        }
+
+        assert_eq!(
+            group_url("http://gw:9091/", "ab12", "web-1.example"),
+            "http://gw:9091/metrics/job/logchain_agent/agent_id/ab12/host/web-1.example"
+        );
+        assert_eq!(path_segment("a/b c"), "a_b_c");
+        assert_eq!(path_segment(""), "unknown");
+    }
+}
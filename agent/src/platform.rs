@@ -0,0 +1,207 @@
+//! Per-OS defaults for the state dir and log path, and keeping the key private.
+//!
+//! Resolution is written against injected lookups so every platform's rules
+//! are tested on any host.
+
+use anyhow::{Result, anyhow};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directory name under the platform's per-user data location.
+const APP_DIR: &str = "logagent";
+
+/// Where state lives when `--state-dir` and `AGENT_STATE_DIR` are unset.
+pub fn default_state_dir() -> Result<PathBuf> {
+    state_dir_for(
+        std::env::consts::OS,
+        |key| std::env::var(key).ok(),
+        Path::exists,
+    )
+}
+
+/// `~/.logagent` was the only default before per-OS locations; an agent that
+/// already has one keeps it, or it would come back with a new key and id.
+pub fn state_dir_for(
+    os: &str,
+    env: impl Fn(&str) -> Option<String>,
+    exists: impl Fn(&Path) -> bool,
+) -> Result<PathBuf> {
+    let home = match os {
+        "windows" => env("USERPROFILE"),
+        _ => env("HOME"),
+    }
+    .map(PathBuf::from);
+    if let Some(legacy) = home.as_ref().map(|home| home.join(".logagent"))
+        && (os == "linux" || exists(&legacy))
+    {
+        return Ok(legacy);
+    }
+    let base = match os {
+        "windows" => env("LOCALAPPDATA")
+            .or_else(|| env("APPDATA"))
+            .map(PathBuf::from),
+        "macos" => home.map(|home| home.join("Library").join("Application Support")),
+        _ => env("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home.map(|home| home.join(".local").join("state"))),
+    };
+    base.map(|base| base.join(APP_DIR))
+        .ok_or_else(|| anyhow!("no home directory to keep agent state in; set --state-dir"))
+}
+
+/// This machine's host name, for the `host` label of pushed metrics.
+pub fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        // SAFETY: the buffer is valid for its length; gethostname writes at
+        // most that many bytes.
+        let rc = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+        if rc == 0 {
+            let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            if let Ok(name) = std::str::from_utf8(&buf[..end])
+                && !name.is_empty()
+            {
+                return name.to_string();
+            }
+        }
+    }
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .unwrap_or_else(|_| "unknown".into())
+}
+
+/// Only Debian-style Linux has a log every install writes to; elsewhere the
+/// source must be given.
+pub fn default_log_path(os: &str) -> Option<PathBuf> {
+    (os == "linux").then(|| PathBuf::from("/var/log/dpkg.log"))
+}
+
+/// Creates `dir` readable by the owner only (on unix). An existing directory
+/// is left as it is: it may be shared, like `/tmp`.
+pub fn create_private_dir(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        return Ok(());
+    }
+    fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// Writes `bytes` to a file only the owner can read. On Windows the file
+/// inherits the state dir's ACL, which is the user profile's by default.
+pub fn write_private(path: &Path, bytes: &[u8]) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        // `mode` only applies to new files.
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(bytes)?;
+    }
+    #[cfg(not(unix))]
+    {
+        fs::write(path, bytes)?;
+        eprintln!(
+            "{} is protected only by its directory's ACL; keep the state dir private",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn resolve(os: &str, vars: &[(&str, &str)], existing: &[&str]) -> Result<PathBuf> {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        state_dir_for(
+            os,
+            |key| vars.get(key).map(|v| v.to_string()),
+            |path| existing.iter().any(|e| Path::new(e) == path),
+        )
+    }
+
+    #[test]
+    fn state_dir_follows_each_platform() {
+        assert_eq!(
+            resolve("linux", &[("HOME", "/home/u")], &[]).ok(),
+            Some(PathBuf::from("/home/u/.logagent"))
+        );
+        assert_eq!(
+            resolve("macos", &[("HOME", "/Users/u")], &[]).ok(),
+            Some(PathBuf::from("/Users/u/Library/Application Support").join(APP_DIR))
+        );
+        assert_eq!(
+            resolve(
+                "freebsd",
+                &[("HOME", "/home/u"), ("XDG_STATE_HOME", "/s")],
+                &[]
+            )
+            .ok(),
+            Some(PathBuf::from("/s").join(APP_DIR))
+        );
+        assert_eq!(
+            resolve(
+                "windows",
+                &[
+                    ("USERPROFILE", "C:\\Users\\u"),
+                    ("LOCALAPPDATA", "C:\\Local")
+                ],
+                &[]
+            )
+            .ok(),
+            Some(PathBuf::from("C:\\Local").join(APP_DIR))
+        );
+        assert_eq!(
+            resolve("windows", &[("APPDATA", "C:\\Roaming")], &[]).ok(),
+            Some(PathBuf::from("C:\\Roaming").join(APP_DIR))
+        );
+        assert!(resolve("windows", &[], &[]).is_err());
+    }
+
+    #[test]
+    fn existing_legacy_state_dir_is_kept() {
+        assert_eq!(
+            resolve("macos", &[("HOME", "/Users/u")], &["/Users/u/.logagent"]).ok(),
+            Some(PathBuf::from("/Users/u/.logagent"))
+        );
+    }
+
+    #[test]
+    fn only_linux_has_a_default_log() {
+        assert!(default_log_path("linux").is_some());
+        assert_eq!(default_log_path("macos"), None);
+        assert_eq!(default_log_path("windows"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_files_are_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("agent-private-{}", std::process::id()));
+        create_private_dir(&dir).unwrap();
+        let path = dir.join("agent.key");
+        fs::write(&path, b"loose").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&path, b"secret").unwrap();
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(fs::read(&path).unwrap(), b"secret");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
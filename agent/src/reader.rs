@@ -0,0 +1,159 @@
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// Default cap on a single shipped line; longer lines are split.
+pub const DEFAULT_MAX_LINE_BYTES: usize = 64 * 1024;
+
+/// Byte-oriented replacement for `Lines` that never fails on log content.
+///
+/// Invalid UTF-8 is converted lossily (U+FFFD), and a line longer than
+/// `max_len` bytes is emitted in `max_len`-byte pieces, so a stray binary byte
+/// or a gigabyte without a newline neither kills the agent nor exhausts memory.
+/// A split can land inside a multi-byte character; both halves then carry a
+/// replacement character. Trailing `\n` / `\r\n` are stripped like `Lines`.
+pub struct LineReader<R> {
+    inner: R,
+    max_len: usize,
+    /// Bytes taken from `inner` so far, newlines included.
+    consumed: u64,
+    /// Set by [`LineReader::following`]: at EOF an unterminated line is kept
+    /// in `partial` for the writer to finish instead of being returned.
+    follow: bool,
+    partial: Vec<u8>,
+}
+
+impl<R: AsyncBufRead + Unpin> LineReader<R> {
+    pub fn new(inner: R, max_len: usize) -> Self {
+        Self {
+            inner,
+            max_len: max_len.max(1),
+            consumed: 0,
+            follow: false,
+            partial: Vec::new(),
+        }
+    }
+
+    /// For a file that may still be written to: EOF in the middle of a line
+    /// returns `None` and the next call picks the line up where it stopped.
+    pub fn following(inner: R, max_len: usize) -> Self {
+        Self {
+            follow: true,
+            ..Self::new(inner, max_len)
+        }
+    }
+
+    /// Offset of the next complete line, counted from where `inner` started.
+    pub fn consumed(&self) -> u64 {
+        self.consumed - self.partial.len() as u64
+    }
+
+    /// The unterminated line held at EOF, if any, once the file is known to
+    /// be finished.
+    pub fn take_partial(&mut self) -> Option<String> {
+        if self.partial.is_empty() {
+            return None;
+        }
+        let line = std::mem::take(&mut self.partial);
+        Some(finish(line))
+    }
+
+    /// Next line, or `None` at EOF. Only I/O errors are returned.
+    pub async fn next_line(&mut self) -> std::io::Result<Option<String>> {
+        let mut line = std::mem::take(&mut self.partial);
+
+        loop {
+            let available = self.inner.fill_buf().await?;
+            if available.is_empty() {
+                if line.is_empty() {
+                    return Ok(None);
+                }
+                if self.follow {
+                    self.partial = line;
+                    return Ok(None);
+                }
+                return Ok(Some(finish(line)));
+            }
+
+            let take = available.len().min(self.max_len - line.len());
+            if let Some(newline) = available[..take].iter().position(|&b| b == b'\n') {
+                line.extend_from_slice(&available[..newline]);
+                self.inner.consume(newline + 1);
+                self.consumed += newline as u64 + 1;
+                return Ok(Some(finish(line)));
+            }
+
+            line.extend_from_slice(&available[..take]);
+            self.inner.consume(take);
+            self.consumed += take as u64;
+
+            if line.len() == self.max_len {
+                // A newline right at the cut belongs to this piece; without this
+                // an exactly-max_len line would be followed by a phantom empty one.
+                if self.inner.fill_buf().await?.first() == Some(&b'\n') {
+                    self.inner.consume(1);
+                    self.consumed += 1;
+                }
+                return Ok(Some(finish(line)));
+            }
+        }
+    }
+}
+
+fn finish(mut line: Vec<u8>) -> String {
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    match String::from_utf8(line) {
+        Ok(s) => s,
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    async fn read_all(input: &[u8], max_len: usize, buf_cap: usize) -> Vec<String> {
+        let mut reader = LineReader::new(BufReader::with_capacity(buf_cap, input), max_len);
+        let mut out = Vec::new();
+        while let Some(line) = reader.next_line().await.unwrap() {
+            out.push(line);
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_is_replaced_not_fatal() {
+        let input = b"ok\nbad \xff\xfe byte\r\nlast";
+        assert_eq!(
+            read_all(input, 1024, 8).await,
+            vec!["ok", "bad \u{fffd}\u{fffd} byte", "last"]
+        );
+    }
+
+    #[tokio::test]
+    async fn unterminated_long_line_is_split() {
+        let input = vec![b'a'; 10_000];
+        let lines = read_all(&input, 4096, 1000).await;
+        let lens: Vec<usize> = lines.iter().map(|l| l.len()).collect();
+        assert_eq!(lens, vec![4096, 4096, 1808]);
+    }
+
+    #[tokio::test]
+    async fn line_of_exactly_max_len_has_no_phantom_split() {
+        assert_eq!(
+            read_all(b"abcd\nef\n\nxyz", 4, 3).await,
+            vec!["abcd", "ef", "", "xyz"]
+        );
+    }
+
+    #[tokio::test]
+    async fn following_holds_an_unterminated_line_until_it_ends() {
+        let mut reader = LineReader::following(BufReader::new(&b"one\ntw"[..]), 1024);
+        assert_eq!(reader.next_line().await.unwrap().as_deref(), Some("one"));
+        assert_eq!(reader.next_line().await.unwrap(), None);
+        assert_eq!(reader.consumed(), 4);
+        assert_eq!(reader.take_partial().as_deref(), Some("tw"));
+        assert_eq!(reader.take_partial(), None);
+    }
+}
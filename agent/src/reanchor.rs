@@ -0,0 +1,286 @@
+//! `--re-anchor --confirm`: moves this agent's chain onto a server that holds
+//! none of it, e.g. a freshly stood-up environment, instead of resetting to
+//! an empty chain.
+//!
+//! The current `spool/` and `acks/` are first moved into an archive,
+//! `state_dir/reanchor-<unix ms>/`. The spooled history is then rebuilt as a
+//! new chain from epoch 0, seq 1 and sent in order. Each batch keeps its
+//! original lines, capture `timestamp` and `lines_read`, and is re-signed
+//! with the agent key under its new seq and prev_hash. Gap markers carry no
+//! lines and are dropped. Every spooled batch must still verify and match
+//! its kept ack, so nothing the old server did not accept is carried over,
+//! and none may have been quarantined (see [`spool::load`]).
+//! Without a spool the new chain starts empty.
+//!
+//! Rebuilding is deterministic, so an interrupted run resumes: the next
+//! `--re-anchor --confirm` picks up the archive without a `done` marker and
+//! continues after the server's checkpoint, as long as that checkpoint is a
+//! prefix of the rebuilt chain. Each run prints a banner and appends a line
+//! to `state_dir/reanchors.jsonl`.
+
+use crate::{
+    AgentConfig, AgentMetrics, Breaker, acks, fetch_checkpoint, load_key, persist_accumulator,
+    persist_epoch, persist_prev_hash, persist_seq, send_batch, spool,
+};
+use anyhow::{Context, Result, bail};
+use chrono::Utc;
+use common::batch::{CURRENT_BATCH_VERSION, LogBatch};
+use common::hex::hex_encode;
+use common::producer;
+use common::receipt::Receipt;
+use ed25519_dalek::{Signature, SigningKey};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const ARCHIVE_PREFIX: &str = "reanchor-";
+const DONE_MARKER: &str = "done";
+
+pub async fn run(config: &AgentConfig, confirmed: bool) -> Result<()> {
+    if !confirmed {
+        bail!(
+            "--re-anchor starts this agent's chain over on the server at {}; \
+             run it again with --confirm",
+            config.server_url
+        );
+    }
+    let key = load_key(config)?;
+    let mut breaker = Breaker::default();
+    let metrics = AgentMetrics::default();
+    let checkpoint = fetch_checkpoint(config, &mut breaker, &metrics, &config.agent_id)
+        .await
+        .context("cannot read the server checkpoint")?;
+
+    let (archive, resumed) = match pending_archive(&config.state_dir)? {
+        Some(archive) => (archive, true),
+        None => {
+            if let Some(cp) = &checkpoint {
+                bail!(
+                    "the server already holds this agent's chain (epoch {}, seq {}); \
+                     re-anchoring only starts on a server without it",
+                    cp.last_epoch,
+                    cp.last_seq
+                );
+            }
+            (archive_current(&config.state_dir)?, false)
+        }
+    };
+    let history = spool::load(&archive, &spool::SpoolKey::derive(&key))?;
+    let quarantined = spool::quarantined(&archive)?;
+    if !quarantined.is_empty() {
+        bail!(
+            "{} spooled batches in {} cannot be replayed (see the [spool] lines); \
+             restore them or move them aside to go on without them",
+            quarantined.len(),
+            spool::corrupt_dir(&archive).display()
+        );
+    }
+    let kept_acks = if acks::acks_dir(&archive).exists() {
+        acks::load(&archive)?
+    } else {
+        Vec::new()
+    };
+    let chain = rebuild(&history, &kept_acks, &key, &config.agent_id)?;
+
+    let start = match &checkpoint {
+        None => 0,
+        Some(cp) => {
+            let sent = cp.last_seq as usize;
+            let matches = cp.last_epoch == 0
+                && chain
+                    .get(sent.wrapping_sub(1))
+                    .is_some_and(|batch| batch.compute_hash() == cp.last_hash);
+            if !matches {
+                bail!(
+                    "the server's chain (epoch {}, seq {}) is not the one {} rebuilds; \
+                     not resuming",
+                    cp.last_epoch,
+                    cp.last_seq,
+                    archive.display()
+                );
+            }
+            sent
+        }
+    };
+
+    let now_ms = Utc::now().timestamp_millis();
+    println!("==================== RE-ANCHORING ====================");
+    println!(
+        "Agent {} starts a new chain on {}: {} batches rebuilt from {} ({} spooled), {}",
+        config.agent_id,
+        config.server_url,
+        chain.len(),
+        archive.display(),
+        history.len(),
+        if start > 0 {
+            format!("resuming after seq {start}")
+        } else {
+            "from seq 1".to_string()
+        }
+    );
+    println!("======================================================");
+    record(
+        &config.state_dir,
+        serde_json::json!({
+            "event": if resumed { "resumed" } else { "started" },
+            "at_ms": now_ms,
+            "server_url": config.server_url,
+            "archive": archive.display().to_string(),
+            "spooled": history.len(),
+            "batches": chain.len(),
+            "resume_after_seq": start,
+            "old_last": history.last().map(|b| serde_json::json!({
+                "epoch": b.epoch,
+                "seq": b.seq,
+                "hash": hex_encode(&b.compute_hash()),
+            })),
+        }),
+    )?;
+
+    persist_epoch(config, 0, now_ms as u64)?;
+    let mut throttle = config.throttle();
+    for batch in &chain[start..] {
+        send_batch(config, &mut throttle, &mut breaker, &metrics, batch)
+            .await
+            .with_context(|| {
+                format!(
+                    "re-anchoring stopped at seq {}; run --re-anchor --confirm again to resume",
+                    batch.seq
+                )
+            })?;
+        persist_seq(config, batch.seq + 1)?;
+        persist_prev_hash(config, batch.compute_hash())?;
+        persist_accumulator(config, batch.accumulator)?;
+    }
+    if chain.is_empty() {
+        persist_seq(config, 1)?;
+        persist_prev_hash(config, [0u8; 32])?;
+        persist_accumulator(config, None)?;
+    }
+
+    fs::write(
+        archive.join(DONE_MARKER),
+        Utc::now().timestamp_millis().to_string(),
+    )?;
+    record(
+        &config.state_dir,
+        serde_json::json!({
+            "event": "completed",
+            "at_ms": Utc::now().timestamp_millis(),
+            "archive": archive.display().to_string(),
+            "batches": chain.len(),
+        }),
+    )?;
+    println!(
+        "Re-anchored {} batches; the chain continues at seq {}",
+        chain.len(),
+        chain.len() + 1
+    );
+    Ok(())
+}
+
+/// `history` as a new chain from epoch 0, seq 1, signed by `key`. Refuses
+/// history that does not verify, belongs to another agent, or disagrees
+/// with its kept ack.
+pub fn rebuild(
+    history: &[LogBatch],
+    kept_acks: &[Receipt],
+    key: &SigningKey,
+    agent_id: &str,
+) -> Result<Vec<LogBatch>> {
+    let mut chain: Vec<LogBatch> = Vec::with_capacity(history.len());
+    for old in history {
+        let label = format!("spooled epoch {} seq {}", old.epoch, old.seq);
+        if old.agent_id != agent_id {
+            bail!("{label} belongs to agent {}", old.agent_id);
+        }
+        if !old.verify() {
+            bail!("{label} does not verify");
+        }
+        let ack = kept_acks
+            .iter()
+            .find(|ack| ack.position() == old.position());
+        if let Some(field) = ack.and_then(|ack| acks::mismatch(ack, old)) {
+            bail!("{label} does not match its kept ack ({field})");
+        }
+        if old.gap.is_some() && old.logs.is_empty() {
+            continue;
+        }
+
+        let previous = chain.last();
+        let mut batch = LogBatch {
+            prev_hash: previous.map_or([0u8; 32], LogBatch::compute_hash),
+            logs: old.logs.clone(),
+            timestamp: old.timestamp,
+            agent_id: agent_id.to_string(),
+            seq: chain.len() as u64 + 1,
+            signature: Signature::from_bytes(&[0u8; 64]),
+            public_key: key.verifying_key(),
+            lines_read: old.lines_read,
+            version: CURRENT_BATCH_VERSION,
+            accumulator: None,
+            gap: None,
+            epoch: 0,
+            epoch_start: None,
+            kind: old.kind.clone(),
+        };
+        let previous_accumulator = previous.and_then(|prev| prev.accumulator);
+        producer::seal(&mut batch, key, previous_accumulator.as_ref());
+        chain.push(batch);
+    }
+    Ok(chain)
+}
+
+/// The newest archive a run started and did not finish.
+fn pending_archive(state_dir: &Path) -> Result<Option<PathBuf>> {
+    Ok(archives(state_dir)?
+        .into_iter()
+        .rev()
+        .find(|path| !path.join(DONE_MARKER).exists()))
+}
+
+/// Every archive under `state_dir`, oldest first.
+pub fn archives(state_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut archives: Vec<PathBuf> = fs::read_dir(state_dir)
+        .with_context(|| format!("cannot read {}", state_dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    archives.retain(|path| {
+        path.is_dir()
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(ARCHIVE_PREFIX))
+    });
+    archives.sort_by_key(|path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name[ARCHIVE_PREFIX.len()..].parse::<u64>().ok())
+    });
+    Ok(archives)
+}
+
+/// Moves the current spool and acks into a new archive.
+fn archive_current(state_dir: &Path) -> Result<PathBuf> {
+    let archive = state_dir.join(format!("{ARCHIVE_PREFIX}{}", Utc::now().timestamp_millis()));
+    fs::create_dir_all(&archive)?;
+    for (from, name) in [
+        (spool::spool_dir(state_dir), "spool"),
+        (acks::acks_dir(state_dir), "acks"),
+    ] {
+        if from.exists() {
+            fs::rename(&from, archive.join(name))
+                .with_context(|| format!("cannot archive {}", from.display()))?;
+        }
+    }
+    Ok(archive)
+}
+
+fn record(state_dir: &Path, event: serde_json::Value) -> Result<()> {
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(state_dir.join("reanchors.jsonl"))?;
+    writeln!(log, "{event}")?;
+    Ok(())
+}
@@ -0,0 +1,142 @@
+//! `--rotate-key`: hands this agent over to a fresh key, e.g. after the old
+//! one may have leaked. Run it while the agent is stopped; a running agent
+//! notices its key was replaced and stops (see [`crate::check_key`]).
+//!
+//! The new key is generated into `state_dir/agent.key.next` and the current
+//! key signs a v2 rotation request for it (see
+//! [`common::rotation::RotateRequest::signed`]): the next rotation counter,
+//! the time and a fresh nonce, in the signing envelope. Once the server
+//! accepts it, the new key replaces `agent.key` and the counter is kept in
+//! `rotation_counter.txt`. A rejected request leaves everything as it was,
+//! and a second run retries with the same pending key. An encrypted spool,
+//! archives included, is re-encrypted under the new key before it takes
+//! over (see [`spool::reencrypt`]).
+//!
+//! The agent id is first pinned to `agent_id.txt`, since by default it is
+//! derived from the key and must not follow it.
+
+use crate::{AgentConfig, load_key, platform, read_key, reanchor, spool};
+use anyhow::{Context, Result, anyhow};
+use chrono::Utc;
+use common::batch::generate_keypair;
+use common::rotation::RotateRequest;
+use ed25519_dalek::SigningKey;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn next_key_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("agent.key.next")
+}
+
+fn counter_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("rotation_counter.txt")
+}
+
+fn agent_id_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("agent_id.txt")
+}
+
+/// The agent id a rotation pinned in `state_dir`, if any.
+pub fn pinned_agent_id(state_dir: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(agent_id_path(state_dir)) {
+        Ok(id) => Ok(Some(id.trim().to_string())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(anyhow!(
+            "cannot read {}: {err}",
+            agent_id_path(state_dir).display()
+        )),
+    }
+}
+
+/// The last rotation the server accepted from this state dir; 0 before any.
+pub fn load_counter(state_dir: &Path) -> Result<u64> {
+    match fs::read_to_string(counter_path(state_dir)) {
+        Ok(counter) => counter
+            .trim()
+            .parse()
+            .with_context(|| format!("{} is corrupt", counter_path(state_dir).display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(anyhow!(
+            "cannot read {}: {err}",
+            counter_path(state_dir).display()
+        )),
+    }
+}
+
+/// What the server answers `/agents/rotate` with.
+#[derive(Deserialize)]
+struct AgentResponse {
+    message: String,
+}
+
+pub async fn run(config: &AgentConfig) -> Result<()> {
+    let current = load_key(config)?;
+    let next = pending_key(&config.state_dir)?;
+    let counter = load_counter(&config.state_dir)? + 1;
+    if pinned_agent_id(&config.state_dir)?.is_none() {
+        fs::write(agent_id_path(&config.state_dir), &config.agent_id)?;
+    }
+
+    let timestamp = Utc::now().timestamp() as u64;
+    let req = RotateRequest::signed(
+        &current,
+        &config.agent_id,
+        &next.verifying_key(),
+        counter,
+        timestamp,
+    );
+    let resp = reqwest::Client::new()
+        .post(format!("{}/agents/rotate", config.server_url))
+        .header(common::request_id::HEADER, common::request_id::generate())
+        .json(&req)
+        .send()
+        .await
+        .context("cannot reach the server; the key was not rotated")?;
+    let status = resp.status();
+    let message = resp
+        .json::<AgentResponse>()
+        .await
+        .map(|body| body.message)
+        .unwrap_or_default();
+    if !status.is_success() {
+        return Err(anyhow!(
+            "the server refused the rotation ({status}: {message}); the key was not rotated"
+        ));
+    }
+
+    let (from, to) = (
+        spool::SpoolKey::derive(&current),
+        spool::SpoolKey::derive(&next),
+    );
+    let mut reencrypted = 0;
+    for dir in
+        std::iter::once(config.state_dir.clone()).chain(reanchor::archives(&config.state_dir)?)
+    {
+        reencrypted += spool::reencrypt(&dir, &from, &to)?;
+    }
+    if reencrypted > 0 {
+        println!("Re-encrypted {reencrypted} spooled batches under the new key");
+    }
+    fs::write(counter_path(&config.state_dir), counter.to_string())?;
+    fs::rename(
+        next_key_path(&config.state_dir),
+        AgentConfig::key_path(&config.state_dir),
+    )?;
+    println!(
+        "Rotated the key of agent {} (rotation counter {counter}): {message}",
+        config.agent_id
+    );
+    Ok(())
+}
+
+/// The key an earlier, refused run generated, or a new one.
+fn pending_key(state_dir: &Path) -> Result<SigningKey> {
+    let path = next_key_path(state_dir);
+    if let Some(key) = read_key(&path)? {
+        return Ok(key);
+    }
+    let key = generate_keypair();
+    platform::write_private(&path, &key.to_bytes())?;
+    Ok(key)
+}
@@ -0,0 +1,799 @@
+//! Where the agent's lines come from. Every kind of source implements
+//! [`LogSource`]: it yields [`Record`]s, reports how far it has got as a
+//! [`SourceCheckpoint`] and can be put back there. [`open`] builds the
+//! configured source and restores its checkpoint from the agent's state
+//! file, `state_dir/sources.json` ([`SourceStates`]), which holds one
+//! checkpoint per source key. The agent reads its sources through
+//! [`Readers`], one or several at once, and [`Readers::commit`] writes a
+//! source's checkpoint once a batch of its lines is done with, so a restart
+//! resumes after the last batch.
+
+use crate::log_dir::{DIR_POLL_INTERVAL, DirSource, DirSpec, Manifest};
+use crate::reader::LineReader;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::future::{Future, poll_fn};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::Poll;
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, BufReader};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::sync::watch;
+use tokio::time::{Duration, sleep, timeout};
+
+/// First restart delay for an exited `exec:` command; doubles per failed run.
+pub const EXEC_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+const EXEC_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// How long a child gets to exit after SIGTERM before it is killed.
+const EXEC_TERM_GRACE: Duration = Duration::from_secs(5);
+
+/// Where the agent reads lines from: `--source exec:<command>`, a file path
+/// (`file:<path>` or a bare path, the default being `--log-path`), or a
+/// directory of rotated files (`--log-dir`, see [`crate::log_dir`]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceSpec {
+    File(PathBuf),
+    Exec(String),
+    Dir(DirSpec),
+}
+
+impl SourceSpec {
+    pub fn parse(value: &str) -> Self {
+        match value.strip_prefix("exec:") {
+            Some(command) => Self::Exec(command.trim().to_string()),
+            None => Self::File(PathBuf::from(value.strip_prefix("file:").unwrap_or(value))),
+        }
+    }
+
+    /// The source's entry in [`SourceStates`]; a changed path, command or
+    /// pattern starts from a fresh checkpoint.
+    pub fn key(&self) -> String {
+        match self {
+            Self::File(path) => format!("file:{}", path.display()),
+            Self::Exec(command) => format!("exec:{command}"),
+            Self::Dir(spec) => format!("dir:{}/{}", spec.dir.display(), spec.pattern),
+        }
+    }
+}
+
+impl fmt::Display for SourceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "file {}", path.display()),
+            Self::Exec(command) => write!(f, "command `{command}`"),
+            Self::Dir(spec) => spec.fmt(f),
+        }
+    }
+}
+
+/// One line read from a source. Per-line metadata joins the line here as
+/// sources start to carry it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub line: String,
+}
+
+/// How far a source has got, as kept in [`SourceStates`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SourceCheckpoint {
+    /// Byte offset of the first line not yet done with.
+    File {
+        offset: u64,
+    },
+    Dir(Manifest),
+    /// A stream that cannot be rewound, such as a command's stdout.
+    Stream,
+    #[cfg(test)]
+    Mock {
+        next: usize,
+    },
+}
+
+/// A producer of log lines the agent can batch and resume.
+pub trait LogSource {
+    /// Next record; `None` once the source has ended, which only a plain
+    /// file does. Waits while there is nothing new.
+    async fn next_record(&mut self) -> std::io::Result<Option<Record>>;
+
+    /// Where a restart should pick up: just after the last record returned.
+    fn checkpoint(&self) -> SourceCheckpoint;
+
+    /// Resumes from `checkpoint`; call it before the first record is read.
+    /// A checkpoint of another kind of source is ignored.
+    fn restore(&mut self, checkpoint: SourceCheckpoint);
+
+    async fn shutdown(&mut self) {}
+}
+
+/// `state_dir/sources.json`: the last committed checkpoint of each source.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceStates {
+    pub sources: BTreeMap<String, SourceCheckpoint>,
+}
+
+impl SourceStates {
+    pub fn path(state_dir: &Path) -> PathBuf {
+        state_dir.join("sources.json")
+    }
+
+    pub fn load(state_dir: &Path) -> anyhow::Result<Self> {
+        let path = Self::path(state_dir);
+        match fs::read(&path) {
+            Ok(raw) => serde_json::from_slice(&raw)
+                .with_context(|| format!("{} is not a source state file", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Written through a temporary file so a crash never leaves half of it.
+    pub fn persist(&self, state_dir: &Path) -> anyhow::Result<()> {
+        let path = Self::path(state_dir);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Sets `key`'s checkpoint in the file, keeping what other sources of
+    /// the same agent committed.
+    pub fn record(state_dir: &Path, key: &str, checkpoint: SourceCheckpoint) -> anyhow::Result<()> {
+        let mut states = Self::load(state_dir)?;
+        states.sources.insert(key.to_string(), checkpoint);
+        states.persist(state_dir)
+    }
+}
+
+/// Builds the source `spec` names and restores its committed checkpoint.
+/// A directory source without one picks up the `log-dir.json` manifest of
+/// an agent from before `sources.json`.
+pub async fn open(
+    spec: &SourceSpec,
+    max_line_bytes: usize,
+    state_dir: &Path,
+) -> anyhow::Result<Tracked<LineSource>> {
+    let source = match spec {
+        SourceSpec::File(path) => LineSource::File(FileSource::open(path, max_line_bytes).await?),
+        SourceSpec::Exec(command) => LineSource::Exec(ExecSource::new(
+            command.clone(),
+            max_line_bytes,
+            EXEC_BACKOFF_INITIAL,
+        )),
+        SourceSpec::Dir(dir) => LineSource::Dir(DirSource::new(
+            dir.clone(),
+            max_line_bytes,
+            DIR_POLL_INTERVAL,
+        )),
+    };
+    let mut states = SourceStates::load(state_dir)?;
+    let key = spec.key();
+    if !states.sources.contains_key(&key)
+        && matches!(spec, SourceSpec::Dir(_))
+        && let Some(manifest) = Manifest::load_legacy(state_dir)?
+    {
+        states
+            .sources
+            .insert(key.clone(), SourceCheckpoint::Dir(manifest));
+    }
+    Ok(Tracked::new(source, key, states))
+}
+
+/// A source together with its entry in [`SourceStates`].
+pub struct Tracked<S> {
+    source: S,
+    key: String,
+}
+
+impl<S: LogSource> Tracked<S> {
+    pub fn new(mut source: S, key: String, states: SourceStates) -> Self {
+        if let Some(checkpoint) = states.sources.get(&key) {
+            source.restore(checkpoint.clone());
+        }
+        Self { source, key }
+    }
+
+    pub async fn next_line(&mut self) -> std::io::Result<Option<String>> {
+        Ok(self.source.next_record().await?.map(|record| record.line))
+    }
+
+    pub async fn shutdown(&mut self) {
+        self.source.shutdown().await;
+    }
+}
+
+/// A read in progress: it owns its source and hands it back with the line,
+/// or with `None` once [`Readers::shutdown`] stopped it.
+type Read<S> = Pin<Box<dyn Future<Output = (Tracked<S>, Option<std::io::Result<Option<String>>>)>>>;
+
+/// Several sources read at once, each keeping one read in progress between
+/// calls to [`Readers::next`]. A source is only read again once its line was
+/// taken, so a caller that stops asking stops every source.
+pub struct Readers<S> {
+    reads: Vec<Option<Read<S>>>,
+    /// Sources that ended, kept for [`Readers::shutdown`].
+    ended: Vec<Tracked<S>>,
+    keys: Vec<String>,
+    /// Each source's checkpoint after the last line [`Readers::next`] returned.
+    checkpoints: Vec<SourceCheckpoint>,
+    state_dir: PathBuf,
+    /// Where the next poll starts, so a busy source cannot starve the rest.
+    start: usize,
+    stop: watch::Sender<bool>,
+}
+
+impl<S: LogSource + 'static> Readers<S> {
+    /// Sources are indexed in the order given.
+    pub fn new(sources: Vec<Tracked<S>>, state_dir: &Path) -> Self {
+        let (stop, _) = watch::channel(false);
+        let keys = sources.iter().map(|source| source.key.clone()).collect();
+        let checkpoints = sources
+            .iter()
+            .map(|source| source.source.checkpoint())
+            .collect();
+        let reads = sources
+            .into_iter()
+            .map(|source| Some(read(source, stop.subscribe())))
+            .collect();
+        Self {
+            reads,
+            ended: Vec::new(),
+            keys,
+            checkpoints,
+            state_dir: state_dir.to_path_buf(),
+            start: 0,
+            stop,
+        }
+    }
+
+    /// The next line of any source with its index, or `None` once every
+    /// source has ended. Sources are polled round-robin. Dropping the
+    /// future loses nothing: the reads in progress carry on at the next call.
+    pub async fn next(&mut self) -> std::io::Result<Option<(usize, String)>> {
+        poll_fn(|cx| {
+            let count = self.reads.len();
+            let mut live = false;
+            for offset in 0..count {
+                let index = (self.start + offset) % count;
+                let Some(pending) = self.reads[index].as_mut() else {
+                    continue;
+                };
+                let Poll::Ready((source, line)) = pending.as_mut().poll(cx) else {
+                    live = true;
+                    continue;
+                };
+                match line {
+                    Some(Ok(Some(line))) => {
+                        self.checkpoints[index] = source.source.checkpoint();
+                        self.reads[index] = Some(read(source, self.stop.subscribe()));
+                        self.start = (index + 1) % count;
+                        return Poll::Ready(Ok(Some((index, line))));
+                    }
+                    Some(Err(err)) => {
+                        self.reads[index] = None;
+                        self.ended.push(source);
+                        return Poll::Ready(Err(err));
+                    }
+                    Some(Ok(None)) | None => {
+                        self.reads[index] = None;
+                        self.ended.push(source);
+                    }
+                }
+            }
+            if live {
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(None))
+            }
+        })
+        .await
+    }
+
+    /// Records source `index` as done with up to the last line returned;
+    /// called once those lines are in a batch that was sent or dropped.
+    pub fn commit(&self, index: usize) -> anyhow::Result<()> {
+        SourceStates::record(
+            &self.state_dir,
+            &self.keys[index],
+            self.checkpoints[index].clone(),
+        )
+    }
+
+    /// Stops the reads in progress and shuts every source down.
+    pub async fn shutdown(mut self) {
+        let _ = self.stop.send(true);
+        for pending in self.reads.into_iter().flatten() {
+            let (mut source, _) = pending.await;
+            source.shutdown().await;
+        }
+        for source in &mut self.ended {
+            source.shutdown().await;
+        }
+    }
+}
+
+fn read<S: LogSource + 'static>(
+    mut source: Tracked<S>,
+    mut stop: watch::Receiver<bool>,
+) -> Read<S> {
+    Box::pin(async move {
+        let line = tokio::select! {
+            line = source.next_line() => Some(line),
+            _ = stop.wait_for(|stopped| *stopped) => None,
+        };
+        (source, line)
+    })
+}
+
+/// The source kinds [`open`] can build.
+pub enum LineSource {
+    File(FileSource),
+    Exec(ExecSource),
+    Dir(DirSource),
+}
+
+impl LogSource for LineSource {
+    async fn next_record(&mut self) -> std::io::Result<Option<Record>> {
+        match self {
+            Self::File(file) => file.next_record().await,
+            Self::Exec(exec) => exec.next_record().await,
+            Self::Dir(dir) => dir.next_record().await,
+        }
+    }
+
+    fn checkpoint(&self) -> SourceCheckpoint {
+        match self {
+            Self::File(file) => file.checkpoint(),
+            Self::Exec(exec) => exec.checkpoint(),
+            Self::Dir(dir) => dir.checkpoint(),
+        }
+    }
+
+    fn restore(&mut self, checkpoint: SourceCheckpoint) {
+        match self {
+            Self::File(file) => file.restore(checkpoint),
+            Self::Exec(exec) => exec.restore(checkpoint),
+            Self::Dir(dir) => dir.restore(checkpoint),
+        }
+    }
+
+    async fn shutdown(&mut self) {
+        if let Self::Exec(exec) = self {
+            exec.shutdown().await;
+        }
+    }
+}
+
+/// Reads a file once to its end, starting after the last committed line.
+pub struct FileSource {
+    path: PathBuf,
+    max_line_bytes: usize,
+    /// Opened up front so a missing file fails the start; wrapped in the
+    /// reader at the first read, once the offset is known.
+    file: Option<File>,
+    /// Offset the reader starts at.
+    start: u64,
+    reader: Option<LineReader<BufReader<File>>>,
+}
+
+impl FileSource {
+    pub async fn open(path: &Path, max_line_bytes: usize) -> std::io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_line_bytes,
+            file: Some(File::open(path).await?),
+            start: 0,
+            reader: None,
+        })
+    }
+}
+
+impl LogSource for FileSource {
+    async fn next_record(&mut self) -> std::io::Result<Option<Record>> {
+        if let Some(mut file) = self.file.take() {
+            let len = file.metadata().await?.len();
+            if self.start > len {
+                eprintln!(
+                    "[file] {} is shorter than byte {}; reading it from the start",
+                    self.path.display(),
+                    self.start
+                );
+                self.start = 0;
+            }
+            file.seek(SeekFrom::Start(self.start)).await?;
+            self.reader = Some(LineReader::new(BufReader::new(file), self.max_line_bytes));
+        }
+        let reader = self.reader.as_mut().expect("opened above");
+        Ok(reader.next_line().await?.map(|line| Record { line }))
+    }
+
+    fn checkpoint(&self) -> SourceCheckpoint {
+        let consumed = self.reader.as_ref().map_or(0, |reader| reader.consumed());
+        SourceCheckpoint::File {
+            offset: self.start + consumed,
+        }
+    }
+
+    fn restore(&mut self, checkpoint: SourceCheckpoint) {
+        match checkpoint {
+            SourceCheckpoint::File { offset } if self.reader.is_none() => {
+                println!("[file] resuming {} at byte {offset}", self.path.display());
+                self.start = offset;
+            }
+            _ => eprintln!("[file] ignoring a checkpoint that is not a file's"),
+        }
+    }
+}
+
+/// Tails a command's stdout, restarting it with exponential backoff whenever
+/// it exits. The command runs under `sh -c` in its own process group so a
+/// shutdown reaches whatever it spawned; its stderr goes to the agent's stderr.
+pub struct ExecSource {
+    command: String,
+    max_line_bytes: usize,
+    child: Option<Child>,
+    stdout: Option<LineReader<BufReader<ChildStdout>>>,
+    initial_backoff: Duration,
+    backoff: Duration,
+    produced: bool,
+}
+
+impl ExecSource {
+    pub fn new(command: String, max_line_bytes: usize, initial_backoff: Duration) -> Self {
+        Self {
+            command,
+            max_line_bytes,
+            child: None,
+            stdout: None,
+            initial_backoff,
+            backoff: initial_backoff,
+            produced: false,
+        }
+    }
+
+    pub async fn next_line(&mut self) -> std::io::Result<String> {
+        loop {
+            if self.stdout.is_none()
+                && let Err(err) = self.spawn()
+            {
+                eprintln!("[exec] failed to start `{}`: {err}", self.command);
+                self.wait_backoff().await;
+                continue;
+            }
+
+            let stdout = self.stdout.as_mut().expect("spawned above");
+            match stdout.next_line().await {
+                Ok(Some(line)) => {
+                    self.produced = true;
+                    return Ok(line);
+                }
+                Ok(None) => {}
+                Err(err) => eprintln!("[exec] reading `{}` failed: {err}", self.command),
+            }
+
+            self.stdout = None;
+            if let Some(mut child) = self.child.take() {
+                match child.wait().await {
+                    Ok(status) => eprintln!("[exec] `{}` exited with {status}", self.command),
+                    Err(err) => eprintln!("[exec] waiting for `{}` failed: {err}", self.command),
+                }
+            }
+            // A run that produced output resets the backoff; a crash loop does not.
+            if std::mem::take(&mut self.produced) {
+                self.backoff = self.initial_backoff;
+            }
+            self.wait_backoff().await;
+        }
+    }
+
+    fn spawn(&mut self) -> std::io::Result<()> {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        #[cfg(unix)]
+        command.process_group(0);
+
+        let mut child = command.spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        if let Some(stderr) = child.stderr.take() {
+            let name = self.command.clone();
+            let max_line_bytes = self.max_line_bytes;
+            tokio::spawn(async move {
+                let mut lines = LineReader::new(BufReader::new(stderr), max_line_bytes);
+                while let Ok(Some(line)) = lines.next_line().await {
+                    eprintln!("[exec `{name}`] {line}");
+                }
+            });
+        }
+
+        println!("[exec] started `{}` (pid {:?})", self.command, child.id());
+        self.stdout = Some(LineReader::new(BufReader::new(stdout), self.max_line_bytes));
+        self.child = Some(child);
+        Ok(())
+    }
+
+    async fn wait_backoff(&mut self) {
+        eprintln!(
+            "[exec] restarting `{}` in {}ms",
+            self.command,
+            self.backoff.as_millis()
+        );
+        sleep(self.backoff).await;
+        self.backoff = (self.backoff * 2).min(EXEC_BACKOFF_MAX);
+    }
+
+    /// SIGTERMs the command's process group, then kills it if it has not
+    /// exited within the grace period.
+    pub async fn shutdown(&mut self) {
+        self.stdout = None;
+        let Some(mut child) = self.child.take() else {
+            return;
+        };
+
+        #[cfg(unix)]
+        if let Some(pid) = child.id() {
+            // SAFETY: plain syscall; a negative pid addresses the process group.
+            unsafe {
+                libc::kill(-(pid as libc::pid_t), libc::SIGTERM);
+            }
+            if let Ok(status) = timeout(EXEC_TERM_GRACE, child.wait()).await {
+                println!("[exec] `{}` stopped: {:?}", self.command, status);
+                return;
+            }
+        }
+
+        let _ = child.kill().await;
+        println!("[exec] `{}` killed", self.command);
+    }
+}
+
+impl LogSource for ExecSource {
+    async fn next_record(&mut self) -> std::io::Result<Option<Record>> {
+        self.next_line().await.map(|line| Some(Record { line }))
+    }
+
+    /// A restarted command starts over; there is nothing to resume.
+    fn checkpoint(&self) -> SourceCheckpoint {
+        SourceCheckpoint::Stream
+    }
+
+    fn restore(&mut self, _checkpoint: SourceCheckpoint) {}
+
+    async fn shutdown(&mut self) {
+        ExecSource::shutdown(self).await;
+    }
+}
+
+/// Replays fixed lines and then ends, like a file; for pipeline tests.
+#[cfg(test)]
+pub struct MockSource {
+    lines: Vec<String>,
+    next: usize,
+}
+
+#[cfg(test)]
+impl MockSource {
+    pub fn new(lines: &[&str]) -> Self {
+        Self {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+            next: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+impl LogSource for MockSource {
+    async fn next_record(&mut self) -> std::io::Result<Option<Record>> {
+        let line = self.lines.get(self.next).cloned();
+        self.next += usize::from(line.is_some());
+        Ok(line.map(|line| Record { line }))
+    }
+
+    fn checkpoint(&self) -> SourceCheckpoint {
+        SourceCheckpoint::Mock { next: self.next }
+    }
+
+    fn restore(&mut self, checkpoint: SourceCheckpoint) {
+        if let SourceCheckpoint::Mock { next } = checkpoint {
+            self.next = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agent-source-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn take(source: &mut Tracked<impl LogSource>, n: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+        for _ in 0..n {
+            lines.extend(source.next_line().await.unwrap());
+        }
+        lines
+    }
+
+    /// What [`Readers::commit`] does, for a source read on its own.
+    fn commit(source: &Tracked<impl LogSource>, state_dir: &Path) {
+        SourceStates::record(state_dir, &source.key, source.source.checkpoint()).unwrap();
+    }
+
+    fn exec(command: &str) -> ExecSource {
+        ExecSource::new(command.to_string(), 1024, Duration::from_millis(10))
+    }
+
+    #[test]
+    fn parses_source_specs() {
+        assert_eq!(
+            SourceSpec::parse("exec:kubectl logs -f web"),
+            SourceSpec::Exec("kubectl logs -f web".into())
+        );
+        assert_eq!(
+            SourceSpec::parse("file:/var/log/syslog"),
+            SourceSpec::File("/var/log/syslog".into())
+        );
+        assert_eq!(
+            SourceSpec::parse("/var/log/syslog"),
+            SourceSpec::File("/var/log/syslog".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn restarts_command_after_exit() {
+        let mut source = exec("echo oops >&2; echo one; echo two");
+        let mut lines = Vec::new();
+        for _ in 0..4 {
+            lines.push(source.next_line().await.unwrap());
+        }
+        // stderr is not shipped; the second run starts over.
+        assert_eq!(lines, vec!["one", "two", "one", "two"]);
+        source.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_terminates_running_command() {
+        let mut source = exec("echo ready; sleep 30");
+        assert_eq!(source.next_line().await.unwrap(), "ready");
+
+        let started = Instant::now();
+        source.shutdown().await;
+        assert!(started.elapsed() < EXEC_TERM_GRACE);
+        assert!(source.child.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_restart_resumes_after_the_last_committed_line() {
+        let dir = state_dir("mock");
+        let restart = || {
+            let states = SourceStates::load(&dir).unwrap();
+            Tracked::new(
+                MockSource::new(&["a", "b", "c", "d"]),
+                "mock".into(),
+                states,
+            )
+        };
+
+        let mut source = restart();
+        assert_eq!(take(&mut source, 2).await, ["a", "b"]);
+        commit(&source, &dir);
+        // Read but never committed: read again after the restart.
+        assert_eq!(take(&mut source, 1).await, ["c"]);
+
+        let mut source = restart();
+        assert_eq!(take(&mut source, 5).await, ["c", "d"]);
+        commit(&source, &dir);
+        let states = SourceStates::load(&dir).unwrap();
+        assert_eq!(states.sources["mock"], SourceCheckpoint::Mock { next: 4 });
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn readers_take_turns_and_commit_each_source_on_its_own() {
+        let dir = state_dir("readers");
+        let track = |key: &str, lines: &[&str]| {
+            let states = SourceStates::load(&dir).unwrap();
+            Tracked::new(MockSource::new(lines), key.into(), states)
+        };
+        let mut readers = Readers::new(
+            vec![track("a", &["a1", "a2", "a3"]), track("b", &["b1"])],
+            &dir,
+        );
+        let mut lines = Vec::new();
+        while let Some((index, line)) = readers.next().await.unwrap() {
+            lines.push((index, line));
+            if lines.len() == 2 {
+                readers.commit(1).unwrap();
+                readers.commit(0).unwrap();
+            }
+        }
+        let lines: Vec<_> = lines
+            .iter()
+            .map(|(index, line)| (*index, line.as_str()))
+            .collect();
+        assert_eq!(lines, [(0, "a1"), (1, "b1"), (0, "a2"), (0, "a3")]);
+        readers.shutdown().await;
+
+        // Each commit kept the other source's entry.
+        let states = SourceStates::load(&dir).unwrap();
+        assert_eq!(states.sources["a"], SourceCheckpoint::Mock { next: 1 });
+        assert_eq!(states.sources["b"], SourceCheckpoint::Mock { next: 1 });
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn files_resume_by_offset_and_restart_when_truncated() {
+        let dir = state_dir("file");
+        let log = dir.join("app.log");
+        fs::write(&log, "one\ntwo\nthree").unwrap();
+        let spec = SourceSpec::File(log.clone());
+
+        let mut source = open(&spec, 1024, &dir).await.unwrap();
+        assert_eq!(take(&mut source, 1).await, ["one"]);
+        commit(&source, &dir);
+        let states = SourceStates::load(&dir).unwrap();
+        assert_eq!(
+            states.sources[&spec.key()],
+            SourceCheckpoint::File { offset: 4 }
+        );
+
+        let mut source = open(&spec, 1024, &dir).await.unwrap();
+        assert_eq!(take(&mut source, 3).await, ["two", "three"]);
+        commit(&source, &dir);
+
+        fs::write(&log, "new\n").unwrap();
+        let mut source = open(&spec, 1024, &dir).await.unwrap();
+        assert_eq!(take(&mut source, 2).await, ["new"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_directory_picks_up_the_legacy_manifest() {
+        let dir = state_dir("legacy");
+        let logs = dir.join("logs");
+        fs::create_dir_all(&logs).unwrap();
+        fs::write(logs.join("app.1.log"), "old\n").unwrap();
+        fs::write(logs.join("app.2.log"), "x1\nx2\n").unwrap();
+        fs::write(
+            dir.join("log-dir.json"),
+            r#"{"completed":["app.1.log"],"current":"app.2.log","offset":3}"#,
+        )
+        .unwrap();
+        let spec = SourceSpec::Dir(DirSpec {
+            dir: logs,
+            pattern: "app.*.log".into(),
+            order: crate::log_dir::FileOrder::Name,
+        });
+
+        let mut source = open(&spec, 1024, &dir).await.unwrap();
+        assert_eq!(take(&mut source, 1).await, ["x2"]);
+        commit(&source, &dir);
+        let SourceCheckpoint::Dir(manifest) =
+            &SourceStates::load(&dir).unwrap().sources[&spec.key()]
+        else {
+            panic!("not a directory checkpoint");
+        };
+        assert_eq!(
+            (manifest.current.as_deref(), manifest.offset),
+            (Some("app.2.log"), 6)
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,272 @@
+//! `--spool`: a copy of every batch the server accepted, exactly as sent, in
+//! `state_dir/spool/`, named like the acks (see [`acks::file_name`]). Nothing
+//! prunes it; it is the history `--re-anchor` replays onto a fresh server
+//! (see [`crate::reanchor`]). `--check-spool` reads it back (see [`check`]).
+//!
+//! With `--spool-encrypt` each file is sealed with ChaCha20-Poly1305 under a
+//! [`SpoolKey`] derived from the agent key, so the logs are not readable at
+//! rest. An encrypted file is a header, [`MAGIC`], a version byte, the key
+//! id and the 12-byte nonce, followed by the ciphertext; the header and the
+//! file name are authenticated with it. Plain files stay readable, so the
+//! flag can be turned on or off at any time.
+//!
+//! Loading re-verifies every batch's signature. A file that cannot be
+//! decrypted with the current key, does not parse or does not verify is
+//! moved to `spool/corrupt/` and reported, never dropped or fatal.
+//! `--rotate-key` re-encrypts the spool under the new key (see [`reencrypt`]);
+//! a spool encrypted under an agent key replaced any other way no longer
+//! decrypts, so it ends up there too.
+
+use crate::acks;
+use anyhow::{Context, Result, anyhow, bail};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use common::batch::LogBatch;
+use ed25519_dalek::SigningKey;
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Opens every encrypted spool file; plain JSON never starts with it.
+const MAGIC: &[u8; 7] = b"LCSPOOL";
+const VERSION: u8 = 1;
+const KEY_ID_BYTES: usize = 8;
+const NONCE_BYTES: usize = 12;
+const HEADER_BYTES: usize = MAGIC.len() + 1 + KEY_ID_BYTES + NONCE_BYTES;
+/// HKDF `info`, so the spool key is never the key of anything else.
+const HKDF_INFO: &[u8] = b"logchain agent spool v1";
+
+pub fn spool_dir(state_dir: &Path) -> PathBuf {
+    state_dir.join("spool")
+}
+
+pub fn corrupt_dir(state_dir: &Path) -> PathBuf {
+    spool_dir(state_dir).join("corrupt")
+}
+
+/// The spool's encryption key: HKDF-SHA256 of the agent's signing key. Its
+/// id, the first bytes of the SHA-256 of the agent's public key, names it
+/// in each file's header.
+pub struct SpoolKey {
+    id: [u8; KEY_ID_BYTES],
+    cipher: ChaCha20Poly1305,
+}
+
+impl SpoolKey {
+    pub fn derive(signing_key: &SigningKey) -> Self {
+        let mut key = Key::default();
+        Hkdf::<Sha256>::new(None, signing_key.as_bytes())
+            .expand(HKDF_INFO, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 length");
+        let digest = Sha256::digest(signing_key.verifying_key().as_bytes());
+        let mut id = [0u8; KEY_ID_BYTES];
+        id.copy_from_slice(&digest[..KEY_ID_BYTES]);
+        Self {
+            id,
+            cipher: ChaCha20Poly1305::new(&key),
+        }
+    }
+
+    fn seal(&self, name: &str, plain: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = Vec::with_capacity(HEADER_BYTES + plain.len() + 16);
+        sealed.extend_from_slice(MAGIC);
+        sealed.push(VERSION);
+        sealed.extend_from_slice(&self.id);
+        sealed.extend_from_slice(&nonce);
+        let aad = [&sealed[..], name.as_bytes()].concat();
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plain,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| anyhow!("cannot encrypt {name}"))?;
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn open(&self, name: &str, sealed: &[u8]) -> Result<Vec<u8>, String> {
+        if sealed.len() < HEADER_BYTES {
+            return Err("truncated header".into());
+        }
+        let (header, ciphertext) = sealed.split_at(HEADER_BYTES);
+        if header[MAGIC.len()] != VERSION {
+            return Err(format!("unknown version {}", header[MAGIC.len()]));
+        }
+        let id = &header[MAGIC.len() + 1..MAGIC.len() + 1 + KEY_ID_BYTES];
+        if id != self.id {
+            return Err("encrypted under another agent key".into());
+        }
+        let nonce = Nonce::from_slice(&header[HEADER_BYTES - NONCE_BYTES..]);
+        let aad = [header, name.as_bytes()].concat();
+        self.cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| "does not decrypt".to_string())
+    }
+}
+
+fn is_sealed(raw: &[u8]) -> bool {
+    raw.starts_with(MAGIC)
+}
+
+/// Writes `batch` to the spool through a temporary file, so a crash never
+/// leaves half a batch. A resend replaces the earlier, identical copy. With
+/// `key`, the file is encrypted.
+pub fn persist(state_dir: &Path, batch: &LogBatch, key: Option<&SpoolKey>) -> Result<()> {
+    let dir = spool_dir(state_dir);
+    fs::create_dir_all(&dir)?;
+    let name = acks::file_name(batch.epoch, batch.seq);
+    let plain = serde_json::to_vec(batch)?;
+    let contents = match key {
+        Some(key) => key.seal(&name, &plain)?,
+        None => plain,
+    };
+    write_atomically(&dir.join(name), &contents)
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// The spool's batch files, in (epoch, seq) order; none if nothing was
+/// ever spooled.
+fn spooled_paths(state_dir: &Path) -> Result<Vec<PathBuf>> {
+    let dir = spool_dir(state_dir);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .with_context(|| format!("cannot read {}", dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "json"));
+    paths.sort();
+    Ok(paths)
+}
+
+/// Every spooled batch under `state_dir` that decrypts with `key` where
+/// encrypted and verifies, in (epoch, seq) order. The others are moved to
+/// [`corrupt_dir`], each with a `[spool]` line.
+pub fn load(state_dir: &Path, key: &SpoolKey) -> Result<Vec<LogBatch>> {
+    let mut batches = Vec::new();
+    for path in spooled_paths(state_dir)? {
+        match read_batch(&path, key)? {
+            Ok(batch) => batches.push(batch),
+            Err(reason) => quarantine(state_dir, &path, &reason)?,
+        }
+    }
+    Ok(batches)
+}
+
+/// The batch in `path`; `Err` with the reason when it is unusable. The
+/// outer error is the file being unreadable.
+fn read_batch(path: &Path, key: &SpoolKey) -> Result<Result<LogBatch, String>> {
+    let raw = fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let name = file_name(path);
+    let plain = if is_sealed(&raw) {
+        match key.open(name, &raw) {
+            Ok(plain) => plain,
+            Err(reason) => return Ok(Err(reason)),
+        }
+    } else {
+        raw
+    };
+    let batch: LogBatch = match serde_json::from_slice(&plain) {
+        Ok(batch) => batch,
+        Err(err) => return Ok(Err(format!("not a spooled batch: {err}"))),
+    };
+    if acks::file_name(batch.epoch, batch.seq) != name {
+        return Ok(Err(format!(
+            "holds epoch {} seq {}",
+            batch.epoch, batch.seq
+        )));
+    }
+    if !batch.verify() {
+        return Ok(Err("does not verify".into()));
+    }
+    Ok(Ok(batch))
+}
+
+fn file_name(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+}
+
+fn quarantine(state_dir: &Path, path: &Path, reason: &str) -> Result<()> {
+    let dir = corrupt_dir(state_dir);
+    fs::create_dir_all(&dir)?;
+    let to = dir.join(file_name(path));
+    fs::rename(path, &to).with_context(|| format!("cannot quarantine {}", path.display()))?;
+    eprintln!(
+        "[spool] {} {reason}; moved to {}",
+        path.display(),
+        to.display()
+    );
+    Ok(())
+}
+
+/// Files [`load`] moved aside under `state_dir`.
+pub fn quarantined(state_dir: &Path) -> Result<Vec<PathBuf>> {
+    let dir = corrupt_dir(state_dir);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .with_context(|| format!("cannot read {}", dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// `--check-spool`: reads the whole spool back, which quarantines what is
+/// unusable, and reports. Fails while anything sits in quarantine, from this
+/// run or an earlier one.
+pub fn check(state_dir: &Path, key: &SpoolKey) -> Result<()> {
+    let batches = load(state_dir, key)?;
+    println!("Spool: {} batches decrypt and verify", batches.len());
+    let quarantined = quarantined(state_dir)?;
+    if !quarantined.is_empty() {
+        bail!(
+            "{} spool files are quarantined in {}",
+            quarantined.len(),
+            corrupt_dir(state_dir).display()
+        );
+    }
+    Ok(())
+}
+
+/// Re-encrypts every file sealed under `from` with `to`, for a key
+/// rotation. Plain files and files under other keys are left alone, so a
+/// run cut short can simply be repeated. Returns how many it re-encrypted.
+pub fn reencrypt(state_dir: &Path, from: &SpoolKey, to: &SpoolKey) -> Result<usize> {
+    let mut count = 0;
+    for path in spooled_paths(state_dir)? {
+        let raw = fs::read(&path).with_context(|| format!("cannot read {}", path.display()))?;
+        if !is_sealed(&raw) {
+            continue;
+        }
+        let name = file_name(&path);
+        let Ok(plain) = from.open(name, &raw) else {
+            continue;
+        };
+        write_atomically(&path, &to.seal(name, &plain)?)?;
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,130 @@
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Token bucket: refills at `rate` per second up to `capacity`.
+///
+/// A request larger than what is left still goes through but leaves the bucket
+/// in debt, so the wait lands on the next request; over any window of `t`
+/// seconds at most `capacity + rate * t` is let through.
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        let capacity = capacity.max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes `amount` tokens at `now`, returning how long to wait before sending.
+    fn reserve_at(&mut self, amount: f64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+
+        let wait = if self.tokens >= amount {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((amount - self.tokens) / self.rate)
+        };
+        self.tokens -= amount;
+        wait
+    }
+
+    fn level(&self) -> String {
+        format!("{:.0}/{:.0}", self.tokens.max(0.0), self.capacity)
+    }
+}
+
+/// Sender-side rate limits (`--max-bytes-per-sec`, `--max-batches-per-sec`).
+/// Every POST attempt passes through [`Throttle::acquire`], retries included.
+#[derive(Default)]
+pub struct Throttle {
+    bytes: Option<TokenBucket>,
+    batches: Option<TokenBucket>,
+}
+
+impl Throttle {
+    /// Bursts default to one second's worth of the rate.
+    pub fn new(
+        max_bytes_per_sec: Option<u64>,
+        burst_bytes: Option<u64>,
+        max_batches_per_sec: Option<f64>,
+        burst_batches: Option<f64>,
+    ) -> Self {
+        Self {
+            bytes: max_bytes_per_sec
+                .filter(|&r| r > 0)
+                .map(|r| TokenBucket::new(r as f64, burst_bytes.unwrap_or(r) as f64)),
+            batches: max_batches_per_sec
+                .filter(|&r| r > 0.0)
+                .map(|r| TokenBucket::new(r, burst_batches.unwrap_or(r))),
+        }
+    }
+
+    /// Waits until a request of `bytes` may be sent under both limits.
+    pub async fn acquire(&mut self, bytes: usize) {
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+        if let Some(bucket) = self.bytes.as_mut() {
+            wait = wait.max(bucket.reserve_at(bytes as f64, now));
+        }
+        if let Some(bucket) = self.batches.as_mut() {
+            wait = wait.max(bucket.reserve_at(1.0, now));
+        }
+
+        if !wait.is_zero() {
+            println!("Throttled for {}ms ({})", wait.as_millis(), self.status());
+            sleep(wait).await;
+        }
+    }
+
+    /// Current limits and bucket levels, for the agent's status output.
+    pub fn status(&self) -> String {
+        let describe = |name: &str, bucket: &Option<TokenBucket>, unit: &str| match bucket {
+            Some(b) => format!("{name} {}{unit}/s bucket {}", b.rate, b.level()),
+            None => format!("{name} unlimited"),
+        };
+        format!(
+            "{}, {}",
+            describe("bytes", &self.bytes, "B"),
+            describe("batches", &self.batches, "")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_sustained_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100.0, 300.0);
+
+        assert_eq!(bucket.reserve_at(300.0, start), Duration::ZERO);
+        assert_eq!(bucket.reserve_at(50.0, start), Duration::from_millis(500));
+        // After the 500ms debt plus another 500ms, 50 tokens are back.
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.reserve_at(50.0, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn refill_is_capped_at_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 20.0);
+        bucket.reserve_at(20.0, start);
+
+        let much_later = start + Duration::from_secs(3600);
+        assert_eq!(bucket.reserve_at(20.0, much_later), Duration::ZERO);
+        assert!(bucket.reserve_at(1.0, much_later) > Duration::ZERO);
+    }
+}
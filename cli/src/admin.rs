@@ -0,0 +1,812 @@
+use anyhow::{anyhow, bail};
+use clap::Subcommand;
+use reqwest::{Client, Method, RequestBuilder, Response};
+use serde::Deserialize;
+use serde_json::Value;
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
+
+/// `cli admin ...`: operator calls against the server's `/admin` endpoints.
+#[derive(Subcommand)]
+pub enum AdminCommand {
+    /// Write a database snapshot next to SQLITE_BACKUP_PATH now.
+    Snapshot,
+    /// Run SQLite's integrity check and look for broken chain links.
+    IntegrityCheck,
+    /// Check every stored row against the values it recomputes to. Starts a
+    /// background job on the server and prints its status.
+    Fsck {
+        /// Poll until the job finishes and print its report.
+        #[arg(long)]
+        wait: bool,
+        /// Show this job instead of starting one.
+        #[arg(long)]
+        job: Option<String>,
+        /// Stop the `--job` after its current chunk.
+        #[arg(long, requires = "job")]
+        abort: bool,
+        /// Rows per chunk; the server's FSCK_CHUNK_ROWS by default.
+        #[arg(long)]
+        chunk_rows: Option<u64>,
+    },
+    Rejections {
+        #[command(subcommand)]
+        command: RejectionsCommand,
+    },
+    Tokens {
+        #[command(subcommand)]
+        command: TokensCommand,
+    },
+    Agents {
+        #[command(subcommand)]
+        command: AgentsCommand,
+    },
+    /// Remove the content of one stored line for good, e.g. for an erasure
+    /// request; the batch still verifies through the marker left in its
+    /// place. Only v3 batches. Irreversible.
+    Redact {
+        /// Row id of the batch.
+        batch_id: i64,
+        /// The line, counting from 0.
+        line_idx: usize,
+        /// Skip the confirmation prompt.
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum RejectionsCommand {
+    /// List recorded rejections, newest first.
+    List {
+        #[arg(long)]
+        agent_id: Option<String>,
+        #[arg(long)]
+        category: Option<String>,
+        #[arg(long)]
+        limit: Option<u64>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TokensCommand {
+    /// Mint a bearer token for a tenant. The token is shown once.
+    Create {
+        #[arg(long)]
+        tenant: String,
+        /// submit, read, export, admin or register; repeat or comma-separate.
+        /// The server defaults to submit.
+        #[arg(long = "scope", value_delimiter = ',')]
+        scopes: Vec<String>,
+        /// Only valid for this agent; submit and register scopes only.
+        #[arg(long)]
+        agent_id: Option<String>,
+        #[arg(long)]
+        expires_in_secs: Option<u64>,
+    },
+    /// Revoke a minted token by id. Its scopes stay closed to anonymous callers.
+    Revoke { id: i64 },
+}
+
+#[derive(Subcommand)]
+pub enum AgentsCommand {
+    /// Stop an agent from submitting, registering or rotating. Irreversible.
+    Revoke {
+        agent_id: String,
+        /// Skip the confirmation prompt.
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+pub struct AdminClient {
+    client: Client,
+    server_url: String,
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    message: String,
+}
+
+impl AdminClient {
+    pub fn new(server_url: &str, token: String) -> Self {
+        Self {
+            client: Client::new(),
+            server_url: server_url.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, format!("{}{}", self.server_url, path))
+            .bearer_auth(&self.token)
+            .header(common::request_id::HEADER, common::request_id::generate())
+    }
+
+    /// Sends and decodes the JSON body, turning error statuses into
+    /// `admin request failed: <status>: <server message>`.
+    pub async fn send(&self, request: RequestBuilder) -> anyhow::Result<Value> {
+        let resp: Response = request.send().await?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp.json().await?);
+        }
+        let text = resp.text().await.unwrap_or_default();
+        let message = serde_json::from_str::<ErrorBody>(&text)
+            .map(|e| e.message)
+            .unwrap_or(text);
+        Err(anyhow!("admin request failed: {status}: {message}"))
+    }
+}
+
+/// How often `fsck --wait` asks for the job's status.
+const FSCK_POLL: Duration = Duration::from_millis(500);
+
+const FSCK_COLUMNS: [&str; 5] = ["id", "agent_id", "seq", "kind", "detail"];
+
+const REJECTION_COLUMNS: [&str; 6] = [
+    "id",
+    "created_at",
+    "agent_id",
+    "category",
+    "source",
+    "reason",
+];
+
+/// Runs one admin command and returns what to print. `confirm` is asked
+/// before destructive operations unless `--yes` was given.
+pub async fn execute(
+    client: &AdminClient,
+    command: AdminCommand,
+    json: bool,
+    confirm: impl FnOnce(&str) -> bool,
+) -> anyhow::Result<String> {
+    let (value, table) = match command {
+        AdminCommand::Snapshot => {
+            let v = client
+                .send(client.request(Method::POST, "/admin/snapshot"))
+                .await?;
+            let table = render_table(&["path"], &[vec![field(&v, "path")]]);
+            (v, table)
+        }
+        AdminCommand::IntegrityCheck => {
+            let v = client
+                .send(client.request(Method::POST, "/admin/integrity-check"))
+                .await?;
+            (v.clone(), integrity_table(&v))
+        }
+        AdminCommand::Fsck {
+            wait,
+            job,
+            abort,
+            chunk_rows,
+        } => {
+            let mut v = match (&job, abort) {
+                (Some(id), true) => {
+                    client
+                        .send(client.request(Method::POST, &format!("/admin/fsck/{id}/abort")))
+                        .await?
+                }
+                (Some(id), false) => {
+                    client
+                        .send(client.request(Method::GET, &format!("/admin/fsck/{id}")))
+                        .await?
+                }
+                (None, _) => {
+                    let query: Vec<(&str, u64)> =
+                        chunk_rows.map(|n| ("chunk_rows", n)).into_iter().collect();
+                    client
+                        .send(client.request(Method::POST, "/admin/fsck").query(&query))
+                        .await?
+                }
+            };
+            if wait {
+                let path = format!("/admin/fsck/{}", field(&v, "job_id"));
+                while v.get("state").and_then(Value::as_str) == Some("running") {
+                    tokio::time::sleep(FSCK_POLL).await;
+                    v = client.send(client.request(Method::GET, &path)).await?;
+                }
+            }
+            (v.clone(), fsck_table(&v))
+        }
+        AdminCommand::Rejections {
+            command:
+                RejectionsCommand::List {
+                    agent_id,
+                    category,
+                    limit,
+                },
+        } => {
+            let mut query: Vec<(&str, String)> = Vec::new();
+            if let Some(agent_id) = agent_id {
+                query.push(("agent_id", agent_id));
+            }
+            if let Some(category) = category {
+                query.push(("category", category));
+            }
+            if let Some(limit) = limit {
+                query.push(("limit", limit.to_string()));
+            }
+            let v = client
+                .send(
+                    client
+                        .request(Method::GET, "/admin/rejections")
+                        .query(&query),
+                )
+                .await?;
+            let rows: Vec<Vec<String>> = v
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|r| REJECTION_COLUMNS.iter().map(|k| field(r, k)).collect())
+                .collect();
+            let table = render_table(&REJECTION_COLUMNS, &rows);
+            (v, table)
+        }
+        AdminCommand::Tokens {
+            command:
+                TokensCommand::Create {
+                    tenant,
+                    scopes,
+                    agent_id,
+                    expires_in_secs,
+                },
+        } => {
+            let mut body = serde_json::json!({ "tenant": tenant });
+            if !scopes.is_empty() {
+                body["scopes"] = scopes.into();
+            }
+            if let Some(agent_id) = agent_id {
+                body["agent_id"] = agent_id.into();
+            }
+            if let Some(secs) = expires_in_secs {
+                body["expires_in_secs"] = secs.into();
+            }
+            let v = client
+                .send(client.request(Method::POST, "/admin/tokens").json(&body))
+                .await?;
+            let table = render_table(
+                &["id", "tenant", "token"],
+                &[vec![
+                    field(&v, "id"),
+                    field(&v, "tenant"),
+                    field(&v, "token"),
+                ]],
+            );
+            (v, table)
+        }
+        AdminCommand::Tokens {
+            command: TokensCommand::Revoke { id },
+        } => {
+            let v = client
+                .send(client.request(Method::POST, &format!("/admin/tokens/{id}/revoke")))
+                .await?;
+            let table = render_table(
+                &["id", "revoked_at"],
+                &[vec![field(&v, "id"), field(&v, "revoked_at")]],
+            );
+            (v, table)
+        }
+        AdminCommand::Agents {
+            command: AgentsCommand::Revoke { agent_id, yes },
+        } => {
+            if !yes
+                && !confirm(&format!(
+                    "Revoke agent '{agent_id}'? It will not be able to submit again."
+                ))
+            {
+                bail!("aborted; pass --yes to revoke without prompting");
+            }
+            let v = client
+                .send(client.request(Method::POST, &format!("/admin/agents/{agent_id}/revoke")))
+                .await?;
+            let table = render_table(
+                &["agent_id", "revoked_from_seq"],
+                &[vec![field(&v, "agent_id"), field(&v, "revoked_from_seq")]],
+            );
+            (v, table)
+        }
+        AdminCommand::Redact {
+            batch_id,
+            line_idx,
+            yes,
+        } => {
+            if !yes
+                && !confirm(&format!(
+                    "Redact line {line_idx} of batch {batch_id}? Its content cannot be recovered."
+                ))
+            {
+                bail!("aborted; pass --yes to redact without prompting");
+            }
+            let body = serde_json::json!({ "batch_id": batch_id, "line_idx": line_idx });
+            let v = client
+                .send(
+                    client
+                        .request(Method::POST, "/admin/redactions")
+                        .json(&body),
+                )
+                .await?;
+            let table = render_table(
+                &["batch_id", "line_idx", "authorized_by", "redacted_at_ms"],
+                &[vec![
+                    field(&v, "batch_id"),
+                    field(&v, "line_idx"),
+                    field(&v, "authorized_by"),
+                    field(&v, "redacted_at_ms"),
+                ]],
+            );
+            (v, table)
+        }
+    };
+
+    if json {
+        Ok(serde_json::to_string_pretty(&value)?)
+    } else {
+        Ok(table)
+    }
+}
+
+/// Interactive y/N prompt on stderr; anything but `y`/`yes` declines.
+pub fn prompt_confirm(question: &str) -> bool {
+    eprint!("{question} [y/N] ");
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+fn integrity_table(v: &Value) -> String {
+    let ok = v.get("ok").and_then(Value::as_bool).unwrap_or(false);
+    let sqlite: Vec<&str> = v
+        .get("sqlite")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+    let mut out = format!(
+        "integrity: {}\nsqlite: {}\n",
+        if ok { "ok" } else { "FAILED" },
+        sqlite.join("; ")
+    );
+    let links: Vec<Vec<String>> = v
+        .get("broken_links")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(|l| vec![field(l, "agent_id"), field(l, "seq"), field(l, "reason")])
+        .collect();
+    if !links.is_empty() {
+        out.push_str(&render_table(&["agent_id", "seq", "reason"], &links));
+    }
+    out
+}
+
+fn fsck_table(v: &Value) -> String {
+    let report = v.get("report").unwrap_or(&Value::Null);
+    let discrepancies: Vec<Vec<String>> = report
+        .get("discrepancies")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(|d| FSCK_COLUMNS.iter().map(|k| field(d, k)).collect())
+        .collect();
+    let complete = report
+        .get("complete")
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    let result = match (complete, discrepancies.len()) {
+        (true, 0) => "ok".to_string(),
+        (true, n) => format!("{n} discrepancies"),
+        (false, n) => format!("incomplete, {n} discrepancies so far"),
+    };
+    let mut out = format!(
+        "fsck {}: {}, {} rows checked up to id {}\nresult: {result}\n",
+        field(v, "job_id"),
+        field(v, "state"),
+        field(report, "rows_checked"),
+        field(report, "last_id"),
+    );
+    if !discrepancies.is_empty() {
+        out.push_str(&render_table(&FSCK_COLUMNS, &discrepancies));
+    }
+    out
+}
+
+fn field(v: &Value, key: &str) -> String {
+    match v.get(key) {
+        None | Some(Value::Null) => "-".to_string(),
+        Some(Value::String(s)) => s.clone(),
+        Some(other) => other.to_string(),
+    }
+}
+
+/// Left-aligned columns separated by two spaces, header first.
+fn render_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{c:<w$}"))
+            .collect();
+        padded.join("  ").trim_end().to_string() + "\n"
+    };
+
+    let mut out = line(headers.to_vec());
+    for row in rows {
+        out.push_str(&line(row.iter().map(String::as_str).collect()));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_json, header, header_exists, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn run(server: &MockServer, command: AdminCommand, json: bool) -> anyhow::Result<String> {
+        let client = AdminClient::new(&server.uri(), "admin-secret".into());
+        execute(&client, command, json, |_| false).await
+    }
+
+    fn admin_call(verb: &str, route: &str) -> wiremock::MockBuilder {
+        Mock::given(method(verb))
+            .and(path(route))
+            .and(header("authorization", "Bearer admin-secret"))
+            .and(header_exists(common::request_id::HEADER))
+    }
+
+    #[tokio::test]
+    async fn snapshot_posts_and_prints_path() {
+        let server = MockServer::start().await;
+        admin_call("POST", "/admin/snapshot")
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "path": "/backups/db.sqlite.1" })),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let out = run(&server, AdminCommand::Snapshot, false).await.unwrap();
+        assert_eq!(out, "path\n/backups/db.sqlite.1\n");
+    }
+
+    #[tokio::test]
+    async fn snapshot_renders_server_error() {
+        let server = MockServer::start().await;
+        admin_call("POST", "/admin/snapshot")
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "status": "error",
+                "message": "no snapshot location; set SQLITE_BACKUP_PATH"
+            })))
+            .mount(&server)
+            .await;
+
+        let err = run(&server, AdminCommand::Snapshot, false)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "admin request failed: 400 Bad Request: no snapshot location; set SQLITE_BACKUP_PATH"
+        );
+    }
+
+    #[tokio::test]
+    async fn integrity_check_reports_broken_links() {
+        let server = MockServer::start().await;
+        let report = serde_json::json!({
+            "ok": false,
+            "sqlite": ["ok"],
+            "broken_links": [{ "agent_id": "a1", "seq": 4, "reason": "prev_hash mismatch" }]
+        });
+        admin_call("POST", "/admin/integrity-check")
+            .respond_with(ResponseTemplate::new(200).set_body_json(report.clone()))
+            .mount(&server)
+            .await;
+
+        let table = run(&server, AdminCommand::IntegrityCheck, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            table,
+            "integrity: FAILED\nsqlite: ok\nagent_id  seq  reason\na1        4    prev_hash mismatch\n"
+        );
+        let json = run(&server, AdminCommand::IntegrityCheck, true)
+            .await
+            .unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), report);
+    }
+
+    #[tokio::test]
+    async fn rejections_list_passes_filters() {
+        let server = MockServer::start().await;
+        admin_call("GET", "/admin/rejections")
+            .and(query_param("agent_id", "a1"))
+            .and(query_param("category", "bad_signature"))
+            .and(query_param("limit", "5"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+                    "id": 9, "agent_id": "a1", "category": "bad_signature",
+                    "reason": "signature mismatch", "source": null, "created_at": 1700000000
+                }])),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let command = AdminCommand::Rejections {
+            command: RejectionsCommand::List {
+                agent_id: Some("a1".into()),
+                category: Some("bad_signature".into()),
+                limit: Some(5),
+            },
+        };
+        let out = run(&server, command, false).await.unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("id  created_at  agent_id  category"));
+        assert!(lines[1].contains("bad_signature") && lines[1].contains("  -  "));
+    }
+
+    #[tokio::test]
+    async fn rejections_list_renders_auth_error() {
+        let server = MockServer::start().await;
+        admin_call("GET", "/admin/rejections")
+            .respond_with(ResponseTemplate::new(401).set_body_string("not json"))
+            .mount(&server)
+            .await;
+
+        let command = AdminCommand::Rejections {
+            command: RejectionsCommand::List {
+                agent_id: None,
+                category: None,
+                limit: None,
+            },
+        };
+        let err = run(&server, command, false).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "admin request failed: 401 Unauthorized: not json"
+        );
+    }
+
+    #[tokio::test]
+    async fn tokens_create_sends_tenant() {
+        let server = MockServer::start().await;
+        admin_call("POST", "/admin/tokens")
+            .and(body_json(serde_json::json!({ "tenant": "site-a" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": 1, "tenant": "site-a", "token": "abc123"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let command = AdminCommand::Tokens {
+            command: TokensCommand::Create {
+                tenant: "site-a".into(),
+                scopes: vec![],
+                agent_id: None,
+                expires_in_secs: None,
+            },
+        };
+        let out = run(&server, command, false).await.unwrap();
+        assert_eq!(out, "id  tenant  token\n1   site-a  abc123\n");
+    }
+
+    #[tokio::test]
+    async fn tokens_create_sends_scopes_and_revoke_posts_id() {
+        let server = MockServer::start().await;
+        admin_call("POST", "/admin/tokens")
+            .and(body_json(serde_json::json!({
+                "tenant": "site-a",
+                "scopes": ["submit", "register"],
+                "agent_id": "a1",
+                "expires_in_secs": 3600,
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": 2, "tenant": "site-a", "token": "def456"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        admin_call("POST", "/admin/tokens/2/revoke")
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": 2, "revoked_at": 1700000000
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let command = TokensCommand::Create {
+            tenant: "site-a".into(),
+            scopes: vec!["submit".into(), "register".into()],
+            agent_id: Some("a1".into()),
+            expires_in_secs: Some(3600),
+        };
+        run(&server, AdminCommand::Tokens { command }, false)
+            .await
+            .unwrap();
+
+        let command = AdminCommand::Tokens {
+            command: TokensCommand::Revoke { id: 2 },
+        };
+        let out = run(&server, command, false).await.unwrap();
+        assert_eq!(out, "id  revoked_at\n2   1700000000\n");
+    }
+
+    #[tokio::test]
+    async fn agents_revoke_requires_confirmation() {
+        let server = MockServer::start().await;
+        admin_call("POST", "/admin/agents/a1/revoke")
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "agent_id": "a1", "revoked_from_seq": 12
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        // Declined prompt: nothing is sent.
+        let declined = AdminCommand::Agents {
+            command: AgentsCommand::Revoke {
+                agent_id: "a1".into(),
+                yes: false,
+            },
+        };
+        let err = run(&server, declined, false).await.unwrap_err();
+        assert!(err.to_string().starts_with("aborted"));
+
+        let confirmed = AdminCommand::Agents {
+            command: AgentsCommand::Revoke {
+                agent_id: "a1".into(),
+                yes: true,
+            },
+        };
+        let out = run(&server, confirmed, true).await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&out).unwrap()["revoked_from_seq"],
+            12
+        );
+    }
+
+    #[tokio::test]
+    async fn redact_requires_confirmation_and_posts_the_line() {
+        let server = MockServer::start().await;
+        admin_call("POST", "/admin/redactions")
+            .and(body_json(
+                serde_json::json!({ "batch_id": 7, "line_idx": 2 }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "batch_id": 7, "line_idx": 2, "authorized_by": "bootstrap", "redacted_at_ms": 1700
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let redact = |yes| AdminCommand::Redact {
+            batch_id: 7,
+            line_idx: 2,
+            yes,
+        };
+        let err = run(&server, redact(false), false).await.unwrap_err();
+        assert!(err.to_string().starts_with("aborted"));
+
+        let out = run(&server, redact(true), false).await.unwrap();
+        assert_eq!(
+            out,
+            "batch_id  line_idx  authorized_by  redacted_at_ms\n7         2         bootstrap      1700\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn agents_revoke_renders_not_found() {
+        let server = MockServer::start().await;
+        admin_call("POST", "/admin/agents/ghost/revoke")
+            .respond_with(ResponseTemplate::new(404).set_body_json(serde_json::json!({
+                "status": "error",
+                "message": "agent not registered or already revoked"
+            })))
+            .mount(&server)
+            .await;
+
+        let command = AdminCommand::Agents {
+            command: AgentsCommand::Revoke {
+                agent_id: "ghost".into(),
+                yes: true,
+            },
+        };
+        let err = run(&server, command, false).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "admin request failed: 404 Not Found: agent not registered or already revoked"
+        );
+    }
+
+    #[tokio::test]
+    async fn fsck_wait_polls_until_the_job_finishes() {
+        let server = MockServer::start().await;
+        let status = |state: &str, discrepancies: Value| {
+            serde_json::json!({
+                "job_id": "f00d", "state": state, "started_at_ms": 1, "finished_at_ms": null,
+                "error": null,
+                "report": {
+                    "rows_checked": 7, "last_id": 9, "complete": state == "done",
+                    "discrepancies": discrepancies
+                }
+            })
+        };
+        admin_call("POST", "/admin/fsck")
+            .and(query_param("chunk_rows", "50"))
+            .respond_with(
+                ResponseTemplate::new(202).set_body_json(status("running", serde_json::json!([]))),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        admin_call("GET", "/admin/fsck/f00d")
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(status("running", serde_json::json!([]))),
+            )
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        admin_call("GET", "/admin/fsck/f00d")
+            .respond_with(ResponseTemplate::new(200).set_body_json(status(
+                "done",
+                serde_json::json!([{
+                    "id": 3, "agent_id": "a1", "seq": 3, "kind": "hash_mismatch",
+                    "detail": "stored hash differs"
+                }]),
+            )))
+            .mount(&server)
+            .await;
+
+        let fsck = |wait| AdminCommand::Fsck {
+            wait,
+            job: None,
+            abort: false,
+            chunk_rows: Some(50),
+        };
+        let table = run(&server, fsck(true), false).await.unwrap();
+        assert_eq!(
+            table,
+            "fsck f00d: done, 7 rows checked up to id 9\nresult: 1 discrepancies\n\
+             id  agent_id  seq  kind           detail\n\
+             3   a1        3    hash_mismatch  stored hash differs\n"
+        );
+
+        let server = MockServer::start().await;
+        admin_call("POST", "/admin/fsck/f00d/abort")
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(status("running", serde_json::json!([]))),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let command = AdminCommand::Fsck {
+            wait: false,
+            job: Some("f00d".into()),
+            abort: true,
+            chunk_rows: None,
+        };
+        let table = run(&server, command, false).await.unwrap();
+        assert!(
+            table.contains("result: incomplete, 0 discrepancies so far"),
+            "{table}"
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Archived exports on disk: a `json` or `ndjson` export, with or without
+//! the signed header `/batches/export?header=true` leads with (see
+//! [`common::export_header`]). `verify --from-file` walks one offline; it
+//! needs the header, so it knows the rules the rows are hashed by.
+
+use crate::progress::Progress;
+use crate::{RemoteBatch, verify_chain};
+use anyhow::{Context, anyhow, bail};
+use common::export_header::{ExportHeader, HEADER_KEY};
+use common::hex::{hex_decode_fixed, hex_encode};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+pub struct Archive<T> {
+    pub header: Option<ExportHeader>,
+    pub rows: Vec<T>,
+}
+
+#[derive(Deserialize)]
+struct HeaderLine {
+    export_header: ExportHeader,
+}
+
+#[derive(Deserialize)]
+#[serde(bound = "T: DeserializeOwned")]
+struct HeadedJson<T> {
+    export_header: ExportHeader,
+    batches: Vec<T>,
+}
+
+/// Rows of a JSON array export, of a headed JSON object, or of an ndjson
+/// one whose first line may be the header.
+pub fn load<T: DeserializeOwned>(path: &Path) -> anyhow::Result<Archive<T>> {
+    let raw = fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let trimmed = raw.trim_ascii_start();
+    if trimmed.starts_with(b"[") {
+        let rows = serde_json::from_slice(&raw)
+            .with_context(|| format!("{} is not a json export", path.display()))?;
+        return Ok(Archive { header: None, rows });
+    }
+    // A headed JSON object is one value; ndjson is several, or one row.
+    if trimmed.starts_with(b"{")
+        && let Ok(headed) = serde_json::from_slice::<HeadedJson<T>>(&raw)
+    {
+        return Ok(Archive {
+            header: Some(headed.export_header),
+            rows: headed.batches,
+        });
+    }
+    let mut lines = raw
+        .split(|&b| b == b'\n')
+        .enumerate()
+        .filter(|(_, line)| !line.trim_ascii().is_empty())
+        .peekable();
+    let mut header = None;
+    if let Some((_, first)) = lines.peek()
+        && let Ok(line) = serde_json::from_slice::<HeaderLine>(first)
+    {
+        header = Some(line.export_header);
+        lines.next();
+    }
+    let rows = lines
+        .map(|(n, line)| {
+            serde_json::from_slice(line)
+                .with_context(|| format!("{} line {} is not an export row", path.display(), n + 1))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Archive { header, rows })
+}
+
+/// Checks the archive's header (and, given `server_key`, that the server key
+/// in it is that one), then every row against the hashing rules it declares,
+/// then each agent's chain as `verify` does, without a server. Returns
+/// whether every chain is valid.
+pub fn verify(path: &Path, server_key: Option<&str>, progress: &Progress) -> anyhow::Result<bool> {
+    let archive: Archive<RemoteBatch> = load(path)?;
+    let header = archive.header.ok_or_else(|| {
+        anyhow!(
+            "{} has no {HEADER_KEY}; export it with header=true to verify it offline",
+            path.display()
+        )
+    })?;
+    header
+        .validate()
+        .map_err(|err| anyhow!("{}: {err}", path.display()))?;
+    let key_hex = hex_encode(header.server_public_key.as_bytes());
+    if let Some(expected) = server_key {
+        let expected: [u8; 32] =
+            hex_decode_fixed(expected).map_err(|err| anyhow!("invalid --server-key: {err}"))?;
+        if expected != *header.server_public_key.as_bytes() {
+            bail!(
+                "{} was signed by server key {key_hex}, not the one given",
+                path.display()
+            );
+        }
+    }
+    println!(
+        "Export header: format v{}, batch versions {:?}, hash schemes {}",
+        header.format_version,
+        header.batch_versions,
+        header.hash_schemes.join(", ")
+    );
+    println!(
+        "  signed at {} ms by server key {} ({key_hex}){}",
+        header.exported_at_ms,
+        header.key_id,
+        if server_key.is_some() {
+            ", as given"
+        } else {
+            "; pass --server-key to pin it"
+        }
+    );
+
+    for row in &archive.rows {
+        header.check_row(&row.batch, &row.redacted).map_err(|err| {
+            anyhow!(
+                "row {} ({} seq {}): {err}",
+                row.id,
+                row.batch.agent_id,
+                row.batch.seq
+            )
+        })?;
+    }
+    println!(
+        "Read {} batches from {}",
+        archive.rows.len(),
+        path.display()
+    );
+    Ok(verify_chain(&archive.rows, None, &HashMap::new(), progress))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::batch::{BATCH_VERSION_V1, BATCH_VERSION_V2, BATCH_VERSION_V3, LogBatch};
+    use common::compat::{ACCUMULATOR_V1, HASH_SCHEMES, RECEIPT_V1};
+    use common::testutil::build_chain;
+    use ed25519_dalek::SigningKey;
+
+    fn write(
+        name: &str,
+        header: &ExportHeader,
+        chain: &[LogBatch],
+        ndjson: bool,
+    ) -> std::path::PathBuf {
+        let rows: Vec<RemoteBatch> = chain
+            .iter()
+            .enumerate()
+            .map(|(n, batch)| RemoteBatch {
+                id: n as i64 + 1,
+                batch: batch.clone(),
+                hash: batch.compute_hash(),
+                redacted: Vec::new(),
+            })
+            .collect();
+        let body = if ndjson {
+            let mut body = format!("{}\n", serde_json::json!({ HEADER_KEY: header }));
+            for row in &rows {
+                body.push_str(&format!("{}\n", serde_json::to_string(row).unwrap()));
+            }
+            body
+        } else {
+            serde_json::to_string_pretty(
+                &serde_json::json!({ HEADER_KEY: header, "batches": rows }),
+            )
+            .unwrap()
+        };
+        let path = std::env::temp_dir().join(format!("cli-archive-{name}-{}", std::process::id()));
+        fs::write(&path, body).unwrap();
+        path
+    }
+
+    #[test]
+    fn exports_are_verified_by_the_rules_their_header_declares() {
+        let server = SigningKey::from_bytes(&[9; 32]);
+        let agent = SigningKey::from_bytes(&[4; 32]);
+        let progress = Progress::for_command(true, true);
+        let header = |versions: Vec<u32>, schemes: &[&str]| {
+            let schemes = schemes.iter().map(|s| s.to_string()).collect();
+            ExportHeader::issue(&server, 1, versions, schemes, "0.1.0", 1_700_000_000_000)
+        };
+        // An older server's scheme: batches up to v2, hashed whole.
+        let old = header(
+            vec![BATCH_VERSION_V1, BATCH_VERSION_V2],
+            &[ACCUMULATOR_V1, RECEIPT_V1],
+        );
+        let mut v2_chain = build_chain(&agent, "agent-old", 3);
+        let mut prev = [0u8; 32];
+        for batch in &mut v2_chain {
+            batch.version = BATCH_VERSION_V2;
+            batch.prev_hash = prev;
+            batch.accumulator = None;
+            batch.sign(&agent);
+            prev = batch.compute_hash();
+        }
+        let file = write("v2", &old, &v2_chain, true);
+        assert!(verify(&file, None, &progress).unwrap());
+        let loaded: Archive<RemoteBatch> = load(&file).unwrap();
+        assert_eq!(loaded.header, Some(old.clone()));
+        assert_eq!(loaded.rows.len(), 3);
+        let _ = fs::remove_file(&file);
+
+        // v3 rows hash their lines as leaves, which the old scheme lacks.
+        let v3_chain = build_chain(&agent, "agent-new", 3);
+        assert_eq!(v3_chain[0].version, BATCH_VERSION_V3);
+        let file = write("v3-old", &old, &v3_chain, true);
+        let err = verify(&file, None, &progress).unwrap_err().to_string();
+        assert!(err.contains("row 1 (agent-new seq 1)"), "{err}");
+        let _ = fs::remove_file(&file);
+
+        // The current scheme takes them, here as a headed JSON object.
+        let current = header(
+            vec![BATCH_VERSION_V1, BATCH_VERSION_V2, BATCH_VERSION_V3],
+            HASH_SCHEMES,
+        );
+        let file = write("v3", &current, &v3_chain, false);
+        let server_hex = hex_encode(server.verifying_key().as_bytes());
+        assert!(verify(&file, Some(&server_hex), &progress).unwrap());
+        let other = hex_encode(SigningKey::from_bytes(&[8; 32]).verifying_key().as_bytes());
+        assert!(verify(&file, Some(&other), &progress).is_err());
+        let _ = fs::remove_file(&file);
+
+        // A header edited after signing, or none at all, is refused.
+        let mut forged = current.clone();
+        forged.hash_schemes.retain(|s| s != ACCUMULATOR_V1);
+        let file = write("forged", &forged, &v3_chain, true);
+        assert!(
+            verify(&file, None, &progress)
+                .unwrap_err()
+                .to_string()
+                .contains("signature")
+        );
+        fs::write(
+            &file,
+            serde_json::to_string(&Vec::<RemoteBatch>::new()).unwrap(),
+        )
+        .unwrap();
+        assert!(
+            verify(&file, None, &progress)
+                .unwrap_err()
+                .to_string()
+                .contains(HEADER_KEY)
+        );
+        let _ = fs::remove_file(&file);
+    }
+}
@@ -0,0 +1,337 @@
+//! `fork-check`: compares the receipts agents kept with what the server
+//! stores now. After a restore from an older snapshot, batches the server
+//! acknowledged are gone, and a resynced agent may have refilled their seqs
+//! with different batches. Each receipt is checked against the server key
+//! history (`/server-keys`) and the batch stored at its agent and seq.
+//!
+//! `--record` uploads the forks found to `POST /admin/forks`, which checks
+//! them again and keeps them as acknowledged data loss.
+
+use crate::admin::AdminClient;
+use crate::{fetch_batch_at, http_client};
+use anyhow::{Context, bail};
+use common::hex::{hex_decode_fixed, hex_encode};
+use common::receipt::{Receipt, ReceiptStanding};
+use ed25519_dalek::VerifyingKey;
+use reqwest::{Client, Method};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+/// A `/server-keys` entry.
+#[derive(Deserialize)]
+struct ServerKey {
+    id: i64,
+    public_key: String,
+    created_at_ms: i64,
+    retired_at_ms: Option<i64>,
+}
+
+/// One receipt file holds a receipt or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ReceiptFile {
+    One(Receipt),
+    Many(Vec<Receipt>),
+}
+
+#[derive(Serialize)]
+pub struct Check {
+    agent_id: String,
+    seq: u64,
+    /// `intact`, `hash_differs`, `missing` or `unverifiable`.
+    status: &'static str,
+    detail: Option<String>,
+    #[serde(skip)]
+    receipt: Receipt,
+}
+
+/// Every receipt in the `.json` files of `dir`, files in name order.
+pub fn load_receipts(dir: &Path) -> anyhow::Result<Vec<Receipt>> {
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .with_context(|| format!("cannot read {}", dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "json"));
+    paths.sort();
+
+    let mut receipts = Vec::new();
+    for path in paths {
+        let raw = fs::read(&path)?;
+        match serde_json::from_slice(&raw)
+            .with_context(|| format!("{} holds no receipts", path.display()))?
+        {
+            ReceiptFile::One(receipt) => receipts.push(receipt),
+            ReceiptFile::Many(many) => receipts.extend(many),
+        }
+    }
+    Ok(receipts)
+}
+
+/// Why `receipt` cannot be trusted, if it cannot: the server must have
+/// signed it with the key that was active when it was issued.
+fn unverifiable(receipt: &Receipt, keys: &[ServerKey]) -> Option<String> {
+    let Some(key) = keys.iter().find(|key| key.id == receipt.key_id) else {
+        return Some(format!("unknown server key {}", receipt.key_id));
+    };
+    let at = receipt.issued_at_ms as i64;
+    if at < key.created_at_ms || key.retired_at_ms.is_some_and(|retired| at > retired) {
+        return Some(format!("server key {} was not active at {at}", key.id));
+    }
+    let verifying = hex_decode_fixed(key.public_key.trim())
+        .ok()
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok());
+    match verifying {
+        Some(verifying) if receipt.verify(&verifying) => None,
+        _ => Some(format!(
+            "signature does not verify with server key {}",
+            key.id
+        )),
+    }
+}
+
+pub async fn check(
+    client: &Client,
+    server_url: &str,
+    receipts: Vec<Receipt>,
+) -> anyhow::Result<Vec<Check>> {
+    let keys: Vec<ServerKey> = client
+        .get(format!("{}/server-keys", server_url))
+        .send()
+        .await?
+        .error_for_status()
+        .context("the server keeps no receipt keys")?
+        .json()
+        .await?;
+
+    let mut checks = Vec::with_capacity(receipts.len());
+    for receipt in receipts {
+        let (status, detail) = match unverifiable(&receipt, &keys) {
+            Some(reason) => ("unverifiable", Some(reason)),
+            None => {
+                let stored =
+                    fetch_batch_at(client, server_url, &receipt.agent_id, receipt.position())
+                        .await?
+                        .map(|row| row.hash);
+                let standing = ReceiptStanding::of(&receipt, stored.as_ref());
+                let detail = stored
+                    .filter(|_| standing == ReceiptStanding::HashDiffers)
+                    .map(|hash| format!("server stores {}", hex_encode(&hash)));
+                (standing.as_str(), detail)
+            }
+        };
+        checks.push(Check {
+            agent_id: receipt.agent_id.clone(),
+            seq: receipt.seq,
+            status,
+            detail,
+            receipt,
+        });
+    }
+    Ok(checks)
+}
+
+/// Checks the receipts in `dir` and prints the result; with `record`, also
+/// uploads the forks found. Returns whether every receipt is intact.
+pub async fn run(
+    server_url: &str,
+    dir: &Path,
+    json: bool,
+    record: Option<(&AdminClient, Option<String>)>,
+) -> anyhow::Result<bool> {
+    let receipts = load_receipts(dir)?;
+    if receipts.is_empty() {
+        bail!("no receipts in {}", dir.display());
+    }
+    let checks = check(&http_client(), server_url, receipts).await?;
+
+    let forks: Vec<&Receipt> = checks
+        .iter()
+        .filter(|c| c.status == "hash_differs" || c.status == "missing")
+        .map(|c| &c.receipt)
+        .collect();
+    let recorded = match record {
+        Some((admin, note)) if !forks.is_empty() => {
+            let body = serde_json::json!({ "receipts": forks, "note": note });
+            let verdicts = admin
+                .send(admin.request(Method::POST, "/admin/forks").json(&body))
+                .await?;
+            let ids = verdicts
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|v| v.get("fork_id").is_some_and(Value::is_i64))
+                .count();
+            Some(ids)
+        }
+        _ => None,
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&checks)?);
+    } else {
+        let count = |status: &str| checks.iter().filter(|c| c.status == status).count();
+        println!(
+            "Checked {} receipts from {}: {} intact, {} forked, {} unverifiable",
+            checks.len(),
+            dir.display(),
+            count("intact"),
+            forks.len(),
+            count("unverifiable")
+        );
+        for c in checks.iter().filter(|c| c.status != "intact") {
+            match &c.detail {
+                Some(detail) => {
+                    println!("  ✗ {} seq {}: {} ({detail})", c.agent_id, c.seq, c.status)
+                }
+                None => println!("  ✗ {} seq {}: {}", c.agent_id, c.seq, c.status),
+            }
+        }
+        if let Some(ids) = recorded {
+            println!("Recorded {ids} of {} forks on the server", forks.len());
+        }
+    }
+    Ok(checks.iter().all(|c| c.status == "intact"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RemoteBatch;
+    use common::testutil::build_chain;
+    use ed25519_dalek::SigningKey;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn receipts_for_lost_batches_are_reported_and_recorded() {
+        let agent = SigningKey::from_bytes(&[3; 32]);
+        let server_key = SigningKey::from_bytes(&[4; 32]);
+        // The agent was acknowledged seqs 1-3 before the restore lost 2-3,
+        // then resent a different seq 2 on top of seq 1.
+        let acked = build_chain(&agent, "agent-f", 3);
+        let resent = build_chain(&agent, "agent-f", 2).pop().map(|mut batch| {
+            batch.logs.push("after restore".into());
+            batch.sign(&agent);
+            batch
+        });
+        let stored = [Some(acked[0].clone()), resent, None];
+        let receipts: Vec<Receipt> = acked
+            .iter()
+            .zip(1..)
+            .map(|(batch, id)| {
+                Receipt::issue(
+                    &server_key,
+                    1,
+                    id,
+                    "agent-f",
+                    batch.position(),
+                    batch.compute_hash(),
+                    1_000,
+                )
+            })
+            .collect();
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/server-keys"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+                    "id": 1,
+                    "public_key": hex_encode(server_key.verifying_key().as_bytes()),
+                    "created_at_ms": 500,
+                    "retired_at_ms": null,
+                }])),
+            )
+            .mount(&server)
+            .await;
+        for (seq, row) in (1..).zip(&stored) {
+            let rows: Vec<RemoteBatch> = row
+                .iter()
+                .map(|batch| RemoteBatch {
+                    id: seq,
+                    hash: batch.compute_hash(),
+                    batch: batch.clone(),
+                    redacted: Vec::new(),
+                })
+                .collect();
+            Mock::given(method("GET"))
+                .and(path("/batches"))
+                .and(query_param("since_seq", seq.to_string()))
+                .respond_with(ResponseTemplate::new(200).set_body_json(rows))
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("POST"))
+            .and(path("/admin/forks"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                { "agent_id": "agent-f", "seq": 2, "status": "hash_differs", "fork_id": 1 },
+                { "agent_id": "agent-f", "seq": 3, "status": "missing", "fork_id": 2 },
+            ])))
+            .mount(&server)
+            .await;
+
+        let mut forged = receipts[0].clone();
+        forged.issued_at_ms = 100;
+        let mut uploaded = receipts.clone();
+        uploaded.push(forged);
+        let checks = check(&http_client(), &server.uri(), uploaded)
+            .await
+            .unwrap();
+        let statuses: Vec<&str> = checks.iter().map(|c| c.status).collect();
+        assert_eq!(
+            statuses,
+            ["intact", "hash_differs", "missing", "unverifiable"]
+        );
+        assert!(checks[3].detail.as_deref().unwrap().contains("not active"));
+
+        // Receipts are read from a list file and a single one, in name order.
+        let dir = std::env::temp_dir().join(format!("cli-forks-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("a.json"),
+            serde_json::to_vec(&receipts[..2]).unwrap(),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("b.json"),
+            serde_json::to_vec(&receipts[2]).unwrap(),
+        )
+        .unwrap();
+        fs::write(dir.join("notes.txt"), "not a receipt").unwrap();
+        assert_eq!(load_receipts(&dir).unwrap(), receipts);
+
+        let admin = AdminClient::new(&server.uri(), "admin-secret".into());
+        let intact = run(
+            &server.uri(),
+            &dir,
+            false,
+            Some((&admin, Some("restore".into()))),
+        )
+        .await
+        .unwrap();
+        assert!(!intact);
+        let uploads: Vec<Value> = server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|r| r.url.path() == "/admin/forks")
+            .map(|r| serde_json::from_slice(&r.body).unwrap())
+            .collect();
+        assert_eq!(uploads.len(), 1);
+        let sent: Vec<Receipt> = serde_json::from_value(uploads[0]["receipts"].clone()).unwrap();
+        assert_eq!(sent, receipts[1..]);
+        assert_eq!(uploads[0]["note"], "restore");
+
+        fs::write(dir.join("b.json"), "{}").unwrap();
+        assert!(
+            load_receipts(&dir)
+                .unwrap_err()
+                .to_string()
+                .contains("b.json")
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
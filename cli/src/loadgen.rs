@@ -0,0 +1,537 @@
+//! `loadgen`: a fleet of simulated agents ([`ChainSimulator`], real keys)
+//! submitting correctly chained, signed batches to `/submit` at a set rate,
+//! to find a server's ceiling before a rollout and to compare runs of it.
+//!
+//! Each agent keeps one batch in flight, as the real agent does. A batch the
+//! server defers (429, or 503 with `Retry-After`) is resent after the wait it
+//! asked for, and one lost to a 5xx or the network at the next chance, so
+//! back-pressure slows the fleet instead of breaking its chains. Any other
+//! 4xx stops that agent: its chain cannot go on.
+//!
+//! `--find-max` runs steps of increasing rate until one is not sustained:
+//! more than `--max-error-pct` of its submits failed, deferrals included, or
+//! fewer than [`SUSTAINED_SHARE`] of the batches asked for were stored.
+
+use crate::http_client;
+use anyhow::bail;
+use common::batch::LogBatch;
+use common::testkit::ChainSimulator;
+use reqwest::{Client, StatusCode};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::{Instant, interval, sleep, timeout_at};
+
+/// Share of a step's target rate that must be stored for it to count as
+/// sustained.
+pub const SUSTAINED_SHARE: f64 = 0.9;
+
+/// Wait before resending a 429 that names none.
+const DEFAULT_DEFER: Duration = Duration::from_secs(1);
+
+pub struct LoadOptions {
+    pub agents: usize,
+    /// Batches per second across the fleet; the first step's with a ramp.
+    pub rate: f64,
+    pub batch_lines: usize,
+    pub line_bytes: usize,
+    /// Of the run, or of each step.
+    pub duration: Duration,
+    pub seed: u64,
+    pub ramp: Option<Ramp>,
+}
+
+/// `--find-max`.
+pub struct Ramp {
+    /// Rate multiplier from one step to the next.
+    pub factor: f64,
+    pub max_error_pct: f64,
+    pub max_steps: u32,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Latency {
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StepReport {
+    pub target_rate: f64,
+    pub duration_secs: f64,
+    pub submits: u64,
+    pub stored: u64,
+    /// Stored batches per second.
+    pub achieved_rate: f64,
+    pub lines_per_sec: f64,
+    pub latency: Latency,
+    /// Submits by response status, and `network` for those with no answer.
+    pub responses: BTreeMap<String, u64>,
+    /// Percent of submits that stored nothing, deferrals included.
+    pub error_pct: f64,
+    /// Agents stopped by a 4xx so far.
+    pub stopped_agents: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoadReport {
+    pub server_url: String,
+    pub seed: u64,
+    pub agents: usize,
+    pub batch_lines: usize,
+    pub line_bytes: usize,
+    pub steps: Vec<StepReport>,
+    /// With `--find-max`: the highest target rate a step sustained.
+    pub max_sustained_rate: Option<f64>,
+    /// With `--find-max`: why the ramp ended.
+    pub stopped_because: Option<String>,
+}
+
+/// How one submit ended, by what the agent does next.
+enum Outcome {
+    Stored,
+    /// 429, or 503 with `Retry-After`: resent after the wait.
+    Deferred(Duration),
+    /// Any other 5xx, or no answer: resent at the next permit.
+    Failed,
+    /// Any other 4xx: the agent stops.
+    Refused,
+}
+
+struct Fleet {
+    sim: Mutex<ChainSimulator>,
+    /// Per agent, the batch the server has not stored yet; sent before any
+    /// new one, across steps too.
+    pending: Vec<Mutex<Option<LogBatch>>>,
+    stopped: Vec<AtomicBool>,
+}
+
+#[derive(Default)]
+struct StepStats {
+    latencies_us: Vec<u64>,
+    responses: BTreeMap<String, u64>,
+    stored: u64,
+    lines: u64,
+}
+
+async fn submit(client: &Client, server_url: &str, batch: &LogBatch) -> (String, Outcome) {
+    let resp = match client
+        .post(format!("{server_url}/submit"))
+        .json(batch)
+        .send()
+        .await
+    {
+        Ok(resp) => resp,
+        Err(_) => return ("network".into(), Outcome::Failed),
+    };
+    let status = resp.status();
+    let retry_after = resp
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+        .map(Duration::from_secs);
+    let outcome = match (status, retry_after) {
+        (status, _) if status.is_success() => Outcome::Stored,
+        (StatusCode::TOO_MANY_REQUESTS, wait) => Outcome::Deferred(wait.unwrap_or(DEFAULT_DEFER)),
+        (StatusCode::SERVICE_UNAVAILABLE, Some(wait)) => Outcome::Deferred(wait),
+        (status, _) if status.is_server_error() => Outcome::Failed,
+        _ => Outcome::Refused,
+    };
+    (status.as_u16().to_string(), outcome)
+}
+
+/// One agent's sends until `deadline`, one per permit.
+async fn drive_agent(
+    agent: usize,
+    fleet: Arc<Fleet>,
+    client: Client,
+    server_url: Arc<str>,
+    permits: Arc<Semaphore>,
+    stats: Arc<Mutex<StepStats>>,
+    deadline: Instant,
+) {
+    // Permits left over when the agents fell behind do not outlive the step.
+    while !fleet.stopped[agent].load(Ordering::Relaxed) && Instant::now() < deadline {
+        match timeout_at(deadline, permits.acquire()).await {
+            Ok(Ok(permit)) => permit.forget(),
+            _ => return,
+        }
+        let pending = fleet.pending[agent].lock().unwrap().take();
+        let batch = pending.unwrap_or_else(|| fleet.sim.lock().unwrap().next_batch(agent));
+        let started = Instant::now();
+        let (label, outcome) = submit(&client, &server_url, &batch).await;
+        {
+            let mut stats = stats.lock().unwrap();
+            stats
+                .latencies_us
+                .push(started.elapsed().as_micros() as u64);
+            *stats.responses.entry(label).or_default() += 1;
+            if matches!(outcome, Outcome::Stored) {
+                stats.stored += 1;
+                stats.lines += batch.logs.len() as u64;
+            }
+        }
+        match outcome {
+            Outcome::Stored => {}
+            Outcome::Deferred(wait) => {
+                *fleet.pending[agent].lock().unwrap() = Some(batch);
+                sleep(wait.min(deadline.saturating_duration_since(Instant::now()))).await;
+            }
+            Outcome::Failed => *fleet.pending[agent].lock().unwrap() = Some(batch),
+            Outcome::Refused => {
+                *fleet.pending[agent].lock().unwrap() = Some(batch);
+                fleet.stopped[agent].store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn percentile_ms(sorted_us: &[u64], pct: f64) -> f64 {
+    if sorted_us.is_empty() {
+        return 0.0;
+    }
+    let rank = ((pct / 100.0) * sorted_us.len() as f64).ceil() as usize;
+    sorted_us[rank.clamp(1, sorted_us.len()) - 1] as f64 / 1000.0
+}
+
+async fn run_step(
+    client: &Client,
+    server_url: &str,
+    fleet: &Arc<Fleet>,
+    rate: f64,
+    duration: Duration,
+) -> StepReport {
+    let permits = Arc::new(Semaphore::new(0));
+    let stats = Arc::new(Mutex::new(StepStats::default()));
+    let started = Instant::now();
+    let deadline = started + duration;
+
+    let pacer = {
+        let permits = permits.clone();
+        tokio::spawn(async move {
+            let mut tick = interval(Duration::from_secs_f64(1.0 / rate));
+            while tick.tick().await < deadline {
+                permits.add_permits(1);
+            }
+        })
+    };
+    let server_url: Arc<str> = server_url.into();
+    let agents: Vec<_> = (0..fleet.pending.len())
+        .map(|agent| {
+            tokio::spawn(drive_agent(
+                agent,
+                fleet.clone(),
+                client.clone(),
+                server_url.clone(),
+                permits.clone(),
+                stats.clone(),
+                deadline,
+            ))
+        })
+        .collect();
+    for agent in agents {
+        let _ = agent.await;
+    }
+    pacer.abort();
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let mut stats = std::mem::take(&mut *stats.lock().unwrap());
+    stats.latencies_us.sort_unstable();
+    let submits = stats.latencies_us.len() as u64;
+    StepReport {
+        target_rate: rate,
+        duration_secs: elapsed,
+        submits,
+        stored: stats.stored,
+        achieved_rate: stats.stored as f64 / elapsed,
+        lines_per_sec: stats.lines as f64 / elapsed,
+        latency: Latency {
+            p50_ms: percentile_ms(&stats.latencies_us, 50.0),
+            p90_ms: percentile_ms(&stats.latencies_us, 90.0),
+            p99_ms: percentile_ms(&stats.latencies_us, 99.0),
+            max_ms: percentile_ms(&stats.latencies_us, 100.0),
+        },
+        responses: stats.responses,
+        error_pct: match submits {
+            0 => 0.0,
+            _ => 100.0 * (submits - stats.stored) as f64 / submits as f64,
+        },
+        stopped_agents: fleet
+            .stopped
+            .iter()
+            .filter(|s| s.load(Ordering::Relaxed))
+            .count(),
+    }
+}
+
+/// Why `step` was not sustained, if it was not.
+fn unsustained(step: &StepReport, max_error_pct: f64) -> Option<String> {
+    if step.error_pct > max_error_pct {
+        return Some(format!(
+            "step at {:.1}/s had {:.1}% failed submits",
+            step.target_rate, step.error_pct
+        ));
+    }
+    if step.achieved_rate < step.target_rate * SUSTAINED_SHARE {
+        return Some(format!(
+            "step at {:.1}/s stored only {:.1}/s",
+            step.target_rate, step.achieved_rate
+        ));
+    }
+    None
+}
+
+pub async fn generate(
+    client: &Client,
+    server_url: &str,
+    options: &LoadOptions,
+) -> anyhow::Result<LoadReport> {
+    if options.agents == 0 || options.batch_lines == 0 {
+        bail!("--agents and --batch-lines must be at least 1");
+    }
+    if !(options.rate > 0.0 && options.rate.is_finite()) {
+        bail!("--rate must be a positive number of batches per second");
+    }
+    if let Some(ramp) = &options.ramp
+        && !(ramp.factor > 1.0 && ramp.factor.is_finite())
+    {
+        bail!("--ramp-factor must be greater than 1");
+    }
+    let start_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let sim = ChainSimulator::new(options.seed, options.agents)
+        .agent_prefix(&format!("loadgen-{}", options.seed))
+        .lines_per_batch(options.batch_lines..=options.batch_lines)
+        .line_len(options.line_bytes..=options.line_bytes)
+        .clock(start_ms, 1);
+    let fleet = Arc::new(Fleet {
+        sim: Mutex::new(sim),
+        pending: (0..options.agents).map(|_| Mutex::new(None)).collect(),
+        stopped: (0..options.agents)
+            .map(|_| AtomicBool::new(false))
+            .collect(),
+    });
+
+    let mut report = LoadReport {
+        server_url: server_url.to_string(),
+        seed: options.seed,
+        agents: options.agents,
+        batch_lines: options.batch_lines,
+        line_bytes: options.line_bytes,
+        steps: Vec::new(),
+        max_sustained_rate: None,
+        stopped_because: None,
+    };
+    let Some(ramp) = &options.ramp else {
+        report
+            .steps
+            .push(run_step(client, server_url, &fleet, options.rate, options.duration).await);
+        return Ok(report);
+    };
+
+    let mut rate = options.rate;
+    for _ in 0..ramp.max_steps {
+        let step = run_step(client, server_url, &fleet, rate, options.duration).await;
+        let verdict = unsustained(&step, ramp.max_error_pct);
+        let all_stopped = step.stopped_agents == options.agents;
+        report.steps.push(step);
+        if let Some(reason) = verdict {
+            report.stopped_because = Some(reason);
+            return Ok(report);
+        }
+        report.max_sustained_rate = Some(rate);
+        if all_stopped {
+            report.stopped_because = Some("every agent was refused".into());
+            return Ok(report);
+        }
+        rate *= ramp.factor;
+    }
+    report.stopped_because = Some(format!("sustained all {} steps", ramp.max_steps));
+    Ok(report)
+}
+
+fn print_step(step: &StepReport) {
+    let responses: Vec<String> = step
+        .responses
+        .iter()
+        .map(|(k, n)| format!("{k}×{n}"))
+        .collect();
+    println!(
+        "  {:.1}/s target: {:.1}/s stored ({:.0} lines/s), latency p50 {:.1}ms p90 {:.1}ms p99 {:.1}ms max {:.1}ms, {:.1}% failed [{}]",
+        step.target_rate,
+        step.achieved_rate,
+        step.lines_per_sec,
+        step.latency.p50_ms,
+        step.latency.p90_ms,
+        step.latency.p99_ms,
+        step.latency.max_ms,
+        step.error_pct,
+        responses.join(" ")
+    );
+    if step.stopped_agents > 0 {
+        println!("    {} agent(s) stopped by a 4xx", step.stopped_agents);
+    }
+}
+
+/// Runs the load and prints the report, as JSON with `json`; `output` also
+/// gets the JSON.
+pub async fn run(
+    server_url: &str,
+    options: &LoadOptions,
+    json: bool,
+    output: Option<&PathBuf>,
+) -> anyhow::Result<()> {
+    if !json {
+        println!(
+            "loadgen: {} agents, {} lines of {} bytes per batch, seed {}, against {}",
+            options.agents, options.batch_lines, options.line_bytes, options.seed, server_url
+        );
+    }
+    let report = generate(&http_client(), server_url, options).await?;
+    let rendered = serde_json::to_string_pretty(&report)?;
+    if let Some(path) = output {
+        std::fs::write(path, format!("{rendered}\n"))?;
+    }
+    if json {
+        println!("{rendered}");
+        return Ok(());
+    }
+    report.steps.iter().for_each(print_step);
+    if options.ramp.is_some() {
+        match report.max_sustained_rate {
+            Some(rate) => println!("max sustained rate: {rate:.1} batches/s"),
+            None => println!("max sustained rate: none; even the first step failed"),
+        }
+        if let Some(reason) = &report.stopped_because {
+            println!("stopped: {reason}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn options(agents: usize, rate: f64, ramp: Option<Ramp>) -> LoadOptions {
+        LoadOptions {
+            agents,
+            rate,
+            batch_lines: 3,
+            line_bytes: 120,
+            duration: Duration::from_millis(400),
+            seed: 7,
+            ramp,
+        }
+    }
+
+    #[tokio::test]
+    async fn deferred_batches_are_resent_so_every_chain_stays_linked() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/submit"))
+            .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "0"))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/submit"))
+            .respond_with(ResponseTemplate::new(201))
+            .mount(&server)
+            .await;
+
+        let report = generate(&http_client(), &server.uri(), &options(3, 100.0, None))
+            .await
+            .unwrap();
+        let step = &report.steps[0];
+        assert_eq!(step.responses.get("429"), Some(&2));
+        assert_eq!(step.responses.get("201"), Some(&step.stored));
+        assert!(step.stored > 10, "{step:?}");
+        assert_eq!(step.submits, step.stored + 2);
+
+        // Stored per agent: seq 1 on, each linked to the one before.
+        let mut sent: Vec<(LogBatch, bool)> = Vec::new();
+        let mut deferrals = 2;
+        for request in server.received_requests().await.unwrap() {
+            let batch: LogBatch = serde_json::from_slice(&request.body).unwrap();
+            assert!(batch.verify() && batch.agent_id.starts_with("loadgen-7-"));
+            let stored = deferrals == 0;
+            deferrals -= usize::from(!stored);
+            sent.push((batch, stored));
+        }
+        for agent in 0..3 {
+            let id = format!("loadgen-7-{agent:03}");
+            let chain: Vec<&LogBatch> = sent
+                .iter()
+                .filter(|(b, s)| *s && b.agent_id == id)
+                .map(|(b, _)| b)
+                .collect();
+            assert_eq!(chain[0].seq, 1, "{id}");
+            for pair in chain.windows(2) {
+                assert_eq!(
+                    (pair[1].seq, pair[1].prev_hash),
+                    (pair[0].seq + 1, pair[0].compute_hash())
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn find_max_stops_at_the_first_step_the_server_cannot_take() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/submit"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+        let ramp = Ramp {
+            factor: 2.0,
+            max_error_pct: 1.0,
+            max_steps: 5,
+        };
+        let report = generate(&http_client(), &server.uri(), &options(2, 50.0, Some(ramp)))
+            .await
+            .unwrap();
+        assert_eq!(report.steps.len(), 1);
+        assert_eq!(report.max_sustained_rate, None);
+        assert_eq!(report.steps[0].error_pct, 100.0);
+        assert!(report.stopped_because.unwrap().contains("100.0% failed"));
+        // A 5xx is retried, never a reason to stop the agent.
+        assert_eq!(report.steps[0].stopped_agents, 0);
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/submit"))
+            .respond_with(ResponseTemplate::new(201))
+            .mount(&server)
+            .await;
+        let ramp = Ramp {
+            factor: 2.0,
+            max_error_pct: 1.0,
+            max_steps: 2,
+        };
+        let report = generate(&http_client(), &server.uri(), &options(4, 20.0, Some(ramp)))
+            .await
+            .unwrap();
+        let rates: Vec<f64> = report.steps.iter().map(|s| s.target_rate).collect();
+        assert_eq!(rates, [20.0, 40.0]);
+        assert_eq!(report.max_sustained_rate, Some(40.0));
+        assert_eq!(
+            report.stopped_because.as_deref(),
+            Some("sustained all 2 steps")
+        );
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["steps"][1]["responses"]["201"], report.steps[1].stored);
+    }
+}
@@ -1,65 +1,1289 @@
-use common::batch::LogBatch;
+use anyhow::anyhow;
+use clap::{Parser, Subcommand};
+use common::address::LineAddress;
+use common::batch::{GapRecord, LogBatch, extend_accumulator, find_line_count_gaps};
+use common::compat::{self, Capabilities};
+use common::export::{ExportFormat, ParquetCompression, render_lines};
+use common::hex::{hex_decode_fixed, hex_encode};
+use common::serde_util::is_zero;
+use indicatif::ProgressBar;
+use progress::{Progress, Unit};
 use reqwest::Client;
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
 use std::env;
+use std::future::Future;
+use std::path::PathBuf;
 
-#[derive(Default)]
+mod admin;
+mod archive;
+mod fork_check;
+mod loadgen;
+mod progress;
+mod resume;
+mod rotate;
+mod summary_check;
+mod tsa;
+#[cfg(feature = "tui")]
+mod tui;
+
+#[derive(Parser)]
+#[command(about = "Fetch and verify tamper-evident log batches")]
 struct CliArgs {
+    /// Server base URL (falls back to CLI_SERVER_URL).
+    #[arg(long, global = true)]
     server_url: Option<String>,
+
+    /// No progress bars; they are also off when stdout is not a terminal.
+    #[arg(long, global = true)]
+    quiet: bool,
+
+    /// Talk to a server even when `GET /version` says this CLI cannot
+    /// verify what it stores.
+    #[arg(long, global = true)]
+    skip_compat_check: bool,
+
+    /// Print the build and what it verifies, then exit.
+    #[arg(long, short = 'V')]
+    version: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-impl CliArgs {
-    fn parse() -> Self {
-        let mut server_url = None;
-        let mut args = env::args().skip(1);
-        while let Some(arg) = args.next() {
-            if arg == "--server-url" {
-                if let Some(v) = args.next() {
-                    server_url = Some(v);
-                }
-            }
-        }
-        Self { server_url }
-    }
+#[derive(Subcommand)]
+enum Command {
+    /// Verify every agent chain stored on the server (default).
+    Verify {
+        /// Verify an export archived with `header=true` instead, offline,
+        /// by the hashing rules its signed header declares.
+        #[arg(long)]
+        from_file: Option<PathBuf>,
+        /// Hex public key the archive's header must be signed with, e.g.
+        /// from `GET /server-keys`.
+        #[arg(long, requires = "from_file")]
+        server_key: Option<String>,
+    },
+    /// Fetch a single batch by row id, or one line by its address
+    /// (`agent_id/seq/line_idx`, as `search` prints them).
+    Get {
+        id: String,
+        /// Fetch the originally submitted bytes and check them against the stored hash.
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Print the stored lines that contain a substring (ASCII letters in
+    /// either case), each after its address.
+    Search {
+        substring: String,
+        #[arg(long)]
+        agent_id: Option<String>,
+        /// Batches searched, newest first.
+        #[arg(long, default_value_t = 100)]
+        limit: u64,
+    },
+    /// Export stored batches; syslog, cef and parquet emit one record per log line.
+    Export {
+        /// json, ndjson, syslog (RFC 5424), cef or parquet.
+        #[arg(long, default_value = "json")]
+        format: ExportFormat,
+        /// Parquet codec: snappy or zstd.
+        #[arg(long, default_value = "snappy")]
+        compression: ParquetCompression,
+        /// Write here instead of stdout; required for parquet.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Only batches with a row id greater than this.
+        #[arg(long)]
+        since_id: Option<i64>,
+        #[arg(long)]
+        limit: Option<u64>,
+        /// Continue an interrupted ndjson, syslog or cef export into
+        /// --output, checked against `<output>.manifest.json`.
+        #[arg(long)]
+        resume: bool,
+    },
+    /// Compare two servers that should hold identical chains and report, per
+    /// agent, the first seq where they diverge. Exits 1 if any agent differs.
+    Diff {
+        #[arg(long)]
+        server_a: String,
+        #[arg(long)]
+        server_b: String,
+        /// Print a JSON report instead of text.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check that a later batch extends a trusted accumulator, using only the
+    /// batch hashes in between. Exits 1 if it does not.
+    Anchor {
+        #[arg(long)]
+        agent_id: String,
+        /// Chain epoch of the trusted batch and the target; accumulators
+        /// restart at each epoch, so both must be in the same one.
+        #[arg(long, default_value_t = 0)]
+        epoch: u64,
+        /// Seq of the batch whose accumulator is trusted.
+        #[arg(long)]
+        seq: u64,
+        /// That batch's accumulator, hex.
+        #[arg(long)]
+        accumulator: String,
+        /// Batch to check; defaults to the agent's latest.
+        #[arg(long)]
+        to_seq: Option<u64>,
+    },
+    /// Check receipts agents kept against what the server stores now, e.g.
+    /// after a restore from a snapshot. Exits 1 unless every receipt matches.
+    ForkCheck {
+        /// Directory of `.json` files, each a receipt or a list of them.
+        #[arg(long)]
+        receipts_dir: PathBuf,
+        /// Record the forks found with the server (`POST /admin/forks`);
+        /// needs the admin token.
+        #[arg(long)]
+        record: bool,
+        /// Kept with the recorded forks, e.g. which restore lost them.
+        #[arg(long, requires = "record")]
+        note: Option<String>,
+        /// Falls back to CLI_ADMIN_TOKEN.
+        #[arg(long)]
+        admin_token: Option<String>,
+        /// Print a JSON report instead of text.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check an archived export (json or ndjson) against the server's daily
+    /// summaries. Exits 1 if a row was altered or a day differs.
+    SummaryCheck {
+        #[arg(long)]
+        archive: PathBuf,
+        /// Print a JSON report instead of text.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Simulate a fleet of agents submitting chained batches at a set rate,
+    /// and report throughput, latency and responses. Stores what it sends:
+    /// point it at a test server.
+    Loadgen {
+        #[arg(long, default_value_t = 10)]
+        agents: usize,
+        /// Batches per second across the fleet; the first step's with --find-max.
+        #[arg(long, default_value_t = 50.0)]
+        rate: f64,
+        #[arg(long, default_value_t = 20)]
+        batch_lines: usize,
+        /// Target length of each line.
+        #[arg(long, default_value_t = 120)]
+        line_bytes: usize,
+        /// Of the run, or of each --find-max step.
+        #[arg(long, default_value_t = 30)]
+        duration_secs: u64,
+        /// Seeds the agents' keys and names; new each run by default, since
+        /// the server keeps every agent's chain.
+        #[arg(long)]
+        seed: Option<u64>,
+        /// Raise the rate step by step until the server cannot sustain it.
+        #[arg(long)]
+        find_max: bool,
+        /// Rate multiplier from one --find-max step to the next.
+        #[arg(long, default_value_t = 1.5)]
+        ramp_factor: f64,
+        /// A step fails once more than this percent of its submits do.
+        #[arg(long, default_value_t = 1.0)]
+        max_error_pct: f64,
+        #[arg(long, default_value_t = 20)]
+        max_steps: u32,
+        /// Also write the JSON report here, to compare runs.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Print the JSON report instead of text.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Hand an agent over to a new key, signed by its current one; see
+    /// [`rotate`]. Stop the agent first if it runs with the current key.
+    Rotate {
+        agent_id: String,
+        /// File holding the agent's current key, 32 raw bytes.
+        #[arg(long)]
+        current_key: PathBuf,
+        /// File holding the new key; generated when absent.
+        #[arg(long)]
+        new_key: PathBuf,
+        /// One more than the agent's last accepted rotation; 1 for its first.
+        #[arg(long)]
+        counter: u64,
+    },
+    /// RFC 3161 trusted timestamps of batches.
+    Tsa {
+        #[command(subcommand)]
+        command: tsa::TsaCommand,
+    },
+    /// Interactive triage view: agents, a live tail, batch inspector and
+    /// verification status. Keys are listed at the bottom of the screen.
+    #[cfg(feature = "tui")]
+    Tui {
+        /// Batches the tail keeps, newest first from `/batches/latest`.
+        #[arg(long, default_value_t = tui::DEFAULT_TAIL)]
+        tail: usize,
+        /// How often every pane is refreshed.
+        #[arg(long, default_value_t = tui::DEFAULT_REFRESH_MS)]
+        refresh_ms: u64,
+    },
+    /// Server administration; needs the admin bearer token.
+    Admin {
+        /// Falls back to CLI_ADMIN_TOKEN.
+        #[arg(long)]
+        admin_token: Option<String>,
+        /// Print the server's JSON response instead of a table.
+        #[arg(long)]
+        json: bool,
+        #[command(subcommand)]
+        command: admin::AdminCommand,
+    },
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct RemoteBatch {
     id: i64,
     batch: LogBatch,
     hash: [u8; 32],
+    /// Lines the server replaced by their redaction marker.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    redacted: Vec<usize>,
+}
+
+/// One entry of `/agents/:id/keys`: the positions, (epoch, seq),
+/// `public_key` may sign. Epochs are absent from servers that predate them.
+#[derive(Deserialize)]
+struct KeyWindow {
+    public_key: String,
+    #[serde(default)]
+    valid_from_epoch: u64,
+    valid_from_seq: u64,
+    #[serde(default)]
+    valid_until_epoch: u64,
+    /// Exclusive; `None` for the current key.
+    valid_until_seq: Option<u64>,
+}
+
+/// The `/batches/meta` fields `anchor` needs.
+#[derive(Debug, Deserialize)]
+struct RemoteMeta {
+    seq: u64,
+    hash: [u8; 32],
+    accumulator: Option<[u8; 32]>,
+    #[serde(default)]
+    gap: Option<GapRecord>,
+}
+
+/// Rows fetched per `/batches/meta` request by `anchor`.
+const META_PAGE: u64 = 1000;
+
+#[derive(Deserialize)]
+struct RemoteCheckpoint {
+    agent_id: String,
+    #[serde(default)]
+    last_epoch: u64,
+    last_seq: u64,
+    last_hash: [u8; 32],
+    /// Batches stored for the agent; sizes the progress bars.
+    #[serde(default)]
+    count: u64,
+}
+
+#[derive(Serialize)]
+struct AgentDiff {
+    agent_id: String,
+    /// `identical`, `missing_on_a`, `missing_on_b` or `diverged`.
+    status: &'static str,
+    /// Chain epoch of `first_divergent_seq`.
+    #[serde(skip_serializing_if = "is_zero")]
+    first_divergent_epoch: u64,
+    /// First seq whose hash differs or that only one server holds.
+    first_divergent_seq: Option<u64>,
+    hash_a: Option<String>,
+    hash_b: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = CliArgs::parse();
+    if args.version {
+        println!(
+            "{}",
+            Capabilities::current(env!("CARGO_PKG_VERSION")).describe("cli")
+        );
+        return Ok(());
+    }
     let server_url = args
         .server_url
         .or_else(|| env::var("CLI_SERVER_URL").ok())
         .unwrap_or_else(|| "http://127.0.0.1:3000".to_string());
 
+    let command = args.command.unwrap_or(Command::Verify {
+        from_file: None,
+        server_key: None,
+    });
+    // `diff` compares two servers' heads and verifies nothing itself; an
+    // archive carries its own header.
+    let offline = matches!(
+        command,
+        Command::Verify {
+            from_file: Some(_),
+            ..
+        }
+    );
+    if !args.skip_compat_check && !offline && !matches!(command, Command::Diff { .. }) {
+        check_compat(&server_url).await?;
+    }
+
+    match command {
+        Command::Verify {
+            from_file: Some(path),
+            server_key,
+        } => {
+            let progress = Progress::for_command(args.quiet, false);
+            if !archive::verify(&path, server_key.as_deref(), &progress)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Verify {
+            from_file: None, ..
+        } => run_verify(&server_url, &Progress::for_command(args.quiet, false)).await,
+        Command::Get { id, raw } => match id.parse::<i64>() {
+            Ok(id) => run_get(&server_url, id, raw).await,
+            Err(_) if raw => Err(anyhow!("--raw needs a row id, not a line address")),
+            Err(_) => {
+                let address = id.parse().map_err(|e: String| anyhow!(e))?;
+                run_get_line(&server_url, &address).await
+            }
+        },
+        Command::Search {
+            substring,
+            agent_id,
+            limit,
+        } => run_search(&server_url, &substring, agent_id.as_deref(), limit).await,
+        Command::Export {
+            format,
+            compression,
+            output,
+            since_id,
+            limit,
+            resume,
+        } => {
+            // JSON and anything on stdout is for a program, not a person.
+            let progress =
+                Progress::for_command(args.quiet, output.is_none() || format == ExportFormat::Json);
+            if let Some(output) = &output
+                && resume::supports(format)
+            {
+                let total = if progress.is_hidden() {
+                    None
+                } else {
+                    export_total(&server_url, since_id, limit).await
+                };
+                let options = resume::ExportOptions {
+                    format,
+                    since_id,
+                    limit,
+                };
+                resume::run(
+                    &server_url,
+                    output,
+                    options,
+                    resume,
+                    resume::EXPORT_PAGE,
+                    &progress.bar(Unit::Batches, "export", total),
+                )
+                .await
+            } else if resume {
+                Err(anyhow!(
+                    "--resume needs --output and --format ndjson, syslog or cef"
+                ))
+            } else if format == ExportFormat::Parquet {
+                let output = output.ok_or_else(|| anyhow!("--format parquet needs --output"))?;
+                download_parquet(
+                    &server_url,
+                    compression,
+                    &output,
+                    since_id,
+                    limit,
+                    &progress,
+                )
+                .await
+            } else {
+                run_export(&server_url, format, output, since_id, limit, &progress).await
+            }
+        }
+        Command::Diff {
+            server_a,
+            server_b,
+            json,
+        } => run_diff(&server_a, &server_b, json).await,
+        Command::Anchor {
+            agent_id,
+            epoch,
+            seq,
+            accumulator,
+            to_seq,
+        } => run_anchor(&server_url, &agent_id, epoch, seq, &accumulator, to_seq).await,
+        Command::ForkCheck {
+            receipts_dir,
+            record,
+            note,
+            admin_token,
+            json,
+        } => {
+            let admin = if record {
+                Some(admin::AdminClient::new(
+                    &server_url,
+                    admin_token_or_env(admin_token)?,
+                ))
+            } else {
+                None
+            };
+            let record = admin.as_ref().map(|client| (client, note));
+            if !fork_check::run(&server_url, &receipts_dir, json, record).await? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::SummaryCheck { archive, json } => {
+            if !summary_check::run(&server_url, &archive, json).await? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Loadgen {
+            agents,
+            rate,
+            batch_lines,
+            line_bytes,
+            duration_secs,
+            seed,
+            find_max,
+            ramp_factor,
+            max_error_pct,
+            max_steps,
+            output,
+            json,
+        } => {
+            let seed = seed.unwrap_or_else(|| {
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_nanos() as u64)
+                    .unwrap_or(0)
+            });
+            let options = loadgen::LoadOptions {
+                agents,
+                rate,
+                batch_lines,
+                line_bytes,
+                duration: std::time::Duration::from_secs(duration_secs),
+                seed,
+                ramp: find_max.then_some(loadgen::Ramp {
+                    factor: ramp_factor,
+                    max_error_pct,
+                    max_steps,
+                }),
+            };
+            loadgen::run(&server_url, &options, json, output.as_ref()).await
+        }
+        Command::Rotate {
+            agent_id,
+            current_key,
+            new_key,
+            counter,
+        } => {
+            let message = rotate::run(
+                &http_client(),
+                &server_url,
+                &agent_id,
+                &current_key,
+                &new_key,
+                counter,
+            )
+            .await?;
+            println!("{message}");
+            Ok(())
+        }
+        Command::Tsa { command } => {
+            if !tsa::run(&server_url, &command).await? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        #[cfg(feature = "tui")]
+        Command::Tui { tail, refresh_ms } => {
+            tui::run(
+                &server_url,
+                tail,
+                std::time::Duration::from_millis(refresh_ms.max(100)),
+            )
+            .await
+        }
+        Command::Admin {
+            admin_token,
+            json,
+            command,
+        } => {
+            let client = admin::AdminClient::new(&server_url, admin_token_or_env(admin_token)?);
+            let out = admin::execute(&client, command, json, admin::prompt_confirm).await?;
+            print!("{out}");
+            if json {
+                println!();
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The `GET /version` handshake (see [`common::compat`]): refuses a server
+/// that stores batches or signs with schemes this CLI cannot verify. A server
+/// that predates it passes; an unreachable one is left to the command to
+/// report.
+async fn check_compat(server_url: &str) -> anyhow::Result<()> {
+    match compat::fetch(&Client::new(), server_url).await {
+        Ok(Some(caps)) => compat::check_reader(&caps, "CLI").map_err(|err| {
+            anyhow!(
+                "incompatible server {}: {err}, or pass --skip-compat-check",
+                caps.server_version
+            )
+        }),
+        Ok(None) | Err(_) => Ok(()),
+    }
+}
+
+fn admin_token_or_env(admin_token: Option<String>) -> anyhow::Result<String> {
+    admin_token
+        .or_else(|| env::var("CLI_ADMIN_TOKEN").ok())
+        .ok_or_else(|| anyhow!("admin token required (--admin-token or CLI_ADMIN_TOKEN)"))
+}
+
+/// Client for the read and export endpoints; sends `CLI_BEARER_TOKEN` when
+/// set, which the server requires once it has minted `read`/`export` tokens.
+/// Its requests share one `X-Request-Id`, so a command's calls can be found
+/// together in the server's logs.
+fn http_client() -> Client {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Ok(value) = common::request_id::generate().parse() {
+        headers.insert(common::request_id::HEADER, value);
+    }
+    if let Ok(token) = env::var("CLI_BEARER_TOKEN")
+        && let Ok(value) = format!("Bearer {token}").parse()
+    {
+        headers.insert(reqwest::header::AUTHORIZATION, value);
+    }
+    Client::builder()
+        .default_headers(headers)
+        .build()
+        .unwrap_or_default()
+}
+
+async fn run_verify(server_url: &str, progress: &Progress) -> anyhow::Result<()> {
     println!("Fetching batches from server {}...", server_url);
 
-    let batches: Vec<RemoteBatch> = Client::new()
+    let client = http_client();
+    let resp = client.get(format!("{}/batches", server_url)).send().await?;
+    let body = download(resp, &progress.bar(Unit::Bytes, "fetch", None)).await?;
+    let batches: Vec<RemoteBatch> = serde_json::from_slice(&body)?;
+
+    println!("Received {} batches", batches.len());
+    // Per-agent totals for the bars; the fetched batches stand in without them.
+    let counts: HashMap<String, u64> = if progress.is_hidden() {
+        HashMap::new()
+    } else {
+        fetch_checkpoints(&client, server_url)
+            .await
+            .map(|checkpoints| {
+                checkpoints
+                    .into_iter()
+                    .map(|(agent, cp)| (agent, cp.count))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    let agents: BTreeSet<&String> = batches.iter().map(|b| &b.batch.agent_id).collect();
+    let mut key_history = HashMap::new();
+    for agent in agents {
+        if let Some(windows) = fetch_key_history(&client, server_url, agent).await? {
+            key_history.insert(agent.clone(), windows);
+        }
+    }
+    verify_chain(&batches, Some(&key_history), &counts, progress);
+
+    Ok(())
+}
+
+/// Reads `resp`'s body, counting it on `bar`, which becomes a bar when the
+/// length is known.
+async fn download(mut resp: reqwest::Response, bar: &ProgressBar) -> anyhow::Result<Vec<u8>> {
+    if !resp.status().is_success() {
+        return Err(anyhow!("request failed: status {}", resp.status()));
+    }
+    if let Some(len) = resp.content_length() {
+        bar.set_length(len);
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        body.extend_from_slice(&chunk);
+        bar.inc(chunk.len() as u64);
+    }
+    bar.finish_and_clear();
+    Ok(body)
+}
+
+/// Batches an export will write, if the server's checkpoint counts say:
+/// only for an export from the start, where they add up to the store.
+async fn export_total(server_url: &str, since_id: Option<i64>, limit: Option<u64>) -> Option<u64> {
+    if since_id.is_some() {
+        return None;
+    }
+    let checkpoints = fetch_checkpoints(&http_client(), server_url).await.ok()?;
+    let stored: u64 = checkpoints.values().map(|cp| cp.count).sum();
+    Some(limit.map_or(stored, |limit| limit.min(stored)))
+}
+
+/// `None` when the server has no history for the agent (or predates the endpoint).
+async fn fetch_key_history(
+    client: &Client,
+    server_url: &str,
+    agent_id: &str,
+) -> anyhow::Result<Option<Vec<KeyWindow>>> {
+    let resp = client
+        .get(format!("{}/agents/{}/keys", server_url, agent_id))
+        .send()
+        .await?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(resp.error_for_status()?.json().await?))
+}
+
+/// Whether `public_key_hex` was the agent's authorized key at `position`,
+/// an (epoch, seq).
+fn key_authorized(windows: &[KeyWindow], public_key_hex: &str, position: (u64, u64)) -> bool {
+    windows.iter().any(|w| {
+        w.public_key.eq_ignore_ascii_case(public_key_hex)
+            && (w.valid_from_epoch, w.valid_from_seq) <= position
+            && w.valid_until_seq
+                .is_none_or(|until| position < (w.valid_until_epoch, until))
+    })
+}
+
+/// `seq N`, or `epoch E seq N` past the first epoch.
+fn position_label((epoch, seq): (u64, u64)) -> String {
+    match epoch {
+        0 => format!("seq {seq}"),
+        epoch => format!("epoch {epoch} seq {seq}"),
+    }
+}
+
+async fn run_get(server_url: &str, id: i64, raw: bool) -> anyhow::Result<()> {
+    let client = http_client();
+    let resp = client
+        .get(format!("{}/batches/{}", server_url, id))
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        return Err(anyhow!(
+            "batch {} not available: status {}",
+            id,
+            resp.status()
+        ));
+    }
+    let stored: RemoteBatch = resp.json().await?;
+    // v1 batches carry seconds, v2 milliseconds; always show epoch millis.
+    eprintln!(
+        "batch v{} timestamp {} ms since epoch",
+        stored.batch.version,
+        stored.batch.timestamp_ms()
+    );
+
+    if !raw {
+        println!("{}", serde_json::to_string_pretty(&stored)?);
+        return Ok(());
+    }
+
+    let bytes = fetch_raw(&client, server_url, id).await?;
+    println!("{}", String::from_utf8_lossy(&bytes));
+    match check_raw(&bytes, &stored) {
+        Ok(()) => println!("  ✓ raw body matches stored hash and signature"),
+        Err(problem) => println!("  ✗ raw body {problem} for id {id}"),
+    }
+
+    Ok(())
+}
+
+/// The request body batch `id` arrived as, byte for byte.
+async fn fetch_raw(client: &Client, server_url: &str, id: i64) -> anyhow::Result<Vec<u8>> {
+    let resp = client
+        .get(format!("{}/batches/{}/raw", server_url, id))
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        return Err(anyhow!(
+            "raw body for batch {} not available: status {}",
+            id,
+            resp.status()
+        ));
+    }
+    Ok(resp.bytes().await?.to_vec())
+}
+
+/// Whether a raw body parses to a batch with the stored row's hash and a
+/// valid signature; the problem if not.
+fn check_raw(bytes: &[u8], stored: &RemoteBatch) -> Result<(), String> {
+    let parsed: LogBatch =
+        serde_json::from_slice(bytes).map_err(|err| format!("does not parse ({err})"))?;
+    let computed = parsed.compute_hash();
+    if computed != stored.hash {
+        return Err(format!(
+            "hash mismatch (computed {}, stored {})",
+            hex_encode(&computed),
+            hex_encode(&stored.hash)
+        ));
+    }
+    if !parsed.verify() {
+        return Err("signature INVALID".into());
+    }
+    Ok(())
+}
+
+/// The `{"error", "message"}` body `/logs` answers with when a line cannot
+/// be served.
+#[derive(Deserialize)]
+struct LineError {
+    error: String,
+    message: String,
+}
+
+async fn run_get_line(server_url: &str, address: &LineAddress) -> anyhow::Result<()> {
+    let resp = http_client()
+        .get(format!(
+            "{}/logs/{}/{}/{}",
+            server_url,
+            address.agent_id,
+            address.position(),
+            address.line_idx
+        ))
+        .send()
+        .await?;
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(match resp.json::<LineError>().await {
+            Ok(err) if err.error == "batch_archived" => {
+                anyhow!("{address} is archived: {}", err.message)
+            }
+            Ok(err) => anyhow!("{address} not available: {}", err.message),
+            Err(_) => anyhow!("{address} not available: status {status}"),
+        });
+    }
+    let line: serde_json::Value = resp.json().await?;
+    println!("{}", serde_json::to_string_pretty(&line)?);
+    Ok(())
+}
+
+/// A `/batches` row found by `log_substring`, with the addresses of the
+/// matching lines.
+#[derive(Deserialize)]
+struct SearchHit {
+    batch: LogBatch,
+    #[serde(default)]
+    matches: Vec<String>,
+}
+
+async fn run_search(
+    server_url: &str,
+    substring: &str,
+    agent_id: Option<&str>,
+    limit: u64,
+) -> anyhow::Result<()> {
+    let mut query = vec![
+        ("log_substring", substring.to_string()),
+        ("limit", limit.to_string()),
+    ];
+    if let Some(agent_id) = agent_id {
+        query.push(("agent_id", agent_id.to_string()));
+    }
+    let resp = http_client()
+        .get(format!("{}/batches", server_url))
+        .query(&query)
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        return Err(anyhow!("search failed: status {}", resp.status()));
+    }
+    let hits: Vec<SearchHit> = resp.json().await?;
+    for line in search_lines(&hits) {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// `address<TAB>line` for every matching line of `hits`.
+fn search_lines(hits: &[SearchHit]) -> Vec<String> {
+    hits.iter()
+        .flat_map(|hit| {
+            hit.matches.iter().filter_map(|address| {
+                let parsed: LineAddress = address.parse().ok()?;
+                let line = hit.batch.logs.get(parsed.line_idx)?;
+                Some(format!("{address}\t{line}"))
+            })
+        })
+        .collect()
+}
+
+fn export_query(since_id: Option<i64>, limit: Option<u64>) -> Vec<(&'static str, String)> {
+    let mut query = Vec::new();
+    if let Some(id) = since_id {
+        query.push(("since_id", id.to_string()));
+    }
+    if let Some(limit) = limit {
+        query.push(("limit", limit.to_string()));
+    }
+    query
+}
+
+async fn run_export(
+    server_url: &str,
+    format: ExportFormat,
+    output: Option<PathBuf>,
+    since_id: Option<i64>,
+    limit: Option<u64>,
+    progress: &Progress,
+) -> anyhow::Result<()> {
+    let query = export_query(since_id, limit);
+
+    let resp = http_client()
+        .get(format!("{}/batches/export", server_url))
+        .query(&query)
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        return Err(anyhow!("export failed: status {}", resp.status()));
+    }
+    let body = download(resp, &progress.bar(Unit::Bytes, "export", None)).await?;
+    let batches: Vec<RemoteBatch> = serde_json::from_slice(&body)?;
+    let out = render_rows(format, &batches)?;
+
+    match output {
+        Some(path) => {
+            std::fs::write(&path, out)?;
+            eprintln!("Exported {} batches to {}", batches.len(), path.display());
+        }
+        None => print!("{}", out),
+    }
+
+    Ok(())
+}
+
+/// Renders export rows locally with the same formatter the server uses for
+/// `?format=`.
+fn render_rows(format: ExportFormat, batches: &[RemoteBatch]) -> anyhow::Result<String> {
+    let mut out = String::new();
+    match format {
+        ExportFormat::Json => {
+            out.push_str(&serde_json::to_string_pretty(batches)?);
+            out.push('\n');
+        }
+        ExportFormat::Ndjson => {
+            for entry in batches {
+                out.push_str(&serde_json::to_string(entry)?);
+                out.push('\n');
+            }
+        }
+        ExportFormat::Syslog | ExportFormat::Cef => {
+            for entry in batches {
+                out.push_str(&render_lines(format, &entry.batch, &entry.hash).unwrap_or_default());
+            }
+        }
+        ExportFormat::Parquet => unreachable!("parquet is downloaded, not rendered"),
+    }
+    Ok(out)
+}
+
+/// Parquet is encoded by the server (built with its `parquet` feature) and
+/// streamed to `output` chunk by chunk, so the CLI never holds the whole file.
+async fn download_parquet(
+    server_url: &str,
+    compression: ParquetCompression,
+    output: &std::path::Path,
+    since_id: Option<i64>,
+    limit: Option<u64>,
+    progress: &Progress,
+) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let mut query = export_query(since_id, limit);
+    query.push(("format", "parquet".into()));
+    query.push(("compression", compression.as_str().into()));
+
+    let mut resp = http_client()
+        .get(format!("{}/batches/export", server_url))
+        .query(&query)
+        .send()
+        .await?;
+    if resp.status() == reqwest::StatusCode::NOT_IMPLEMENTED {
+        return Err(anyhow!("server was built without parquet export"));
+    }
+    if !resp.status().is_success() {
+        return Err(anyhow!("export failed: status {}", resp.status()));
+    }
+
+    let mut file = std::io::BufWriter::new(std::fs::File::create(output)?);
+    let bar = progress.bar(Unit::Bytes, "export", resp.content_length());
+    let mut written = 0usize;
+    while let Some(chunk) = resp.chunk().await? {
+        file.write_all(&chunk)?;
+        written += chunk.len();
+        bar.inc(chunk.len() as u64);
+    }
+    file.flush()?;
+    bar.finish_and_clear();
+    eprintln!(
+        "Exported {written} bytes of parquet to {}",
+        output.display()
+    );
+    Ok(())
+}
+
+async fn run_diff(server_a: &str, server_b: &str, json: bool) -> anyhow::Result<()> {
+    let client = http_client();
+    let checkpoints_a = fetch_checkpoints(&client, server_a).await?;
+    let checkpoints_b = fetch_checkpoints(&client, server_b).await?;
+
+    let agents: BTreeSet<&String> = checkpoints_a.keys().chain(checkpoints_b.keys()).collect();
+    let mut report = Vec::new();
+
+    for agent in agents {
+        let (cp_a, cp_b) = (checkpoints_a.get(agent), checkpoints_b.get(agent));
+        let diff = match (cp_a, cp_b) {
+            (Some(a), Some(b))
+                if (a.last_epoch, a.last_seq, a.last_hash)
+                    == (b.last_epoch, b.last_seq, b.last_hash) =>
+            {
+                AgentDiff {
+                    agent_id: agent.clone(),
+                    status: "identical",
+                    first_divergent_epoch: 0,
+                    first_divergent_seq: None,
+                    hash_a: None,
+                    hash_b: None,
+                }
+            }
+            (Some(a), Some(b)) => {
+                // Searched within the earlier of the two last epochs: an
+                // epoch start links by hash to the epoch before, so a
+                // divergence in an earlier epoch shows at this one's seq 1.
+                let (epoch, common) = match a.last_epoch.cmp(&b.last_epoch) {
+                    Ordering::Less => (a.last_epoch, a.last_seq),
+                    Ordering::Greater => (b.last_epoch, b.last_seq),
+                    Ordering::Equal => (a.last_epoch, a.last_seq.min(b.last_seq)),
+                };
+                let fetch = |seq| hash_pair(&client, server_a, server_b, agent, (epoch, seq));
+                let seq = first_divergence(common, fetch).await?.unwrap_or(common + 1);
+                let (hash_a, hash_b) =
+                    hash_pair(&client, server_a, server_b, agent, (epoch, seq)).await?;
+                AgentDiff {
+                    agent_id: agent.clone(),
+                    status: "diverged",
+                    first_divergent_epoch: epoch,
+                    first_divergent_seq: Some(seq),
+                    hash_a: hash_a.map(|h| hex_encode(&h)),
+                    hash_b: hash_b.map(|h| hex_encode(&h)),
+                }
+            }
+            (Some(_), None) | (None, Some(_)) => AgentDiff {
+                agent_id: agent.clone(),
+                status: if cp_a.is_some() {
+                    "missing_on_b"
+                } else {
+                    "missing_on_a"
+                },
+                first_divergent_epoch: 0,
+                first_divergent_seq: Some(1),
+                hash_a: None,
+                hash_b: None,
+            },
+            (None, None) => unreachable!("agent came from one of the checkpoint maps"),
+        };
+        report.push(diff);
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("Comparing {} (A) with {} (B)", server_a, server_b);
+        for diff in &report {
+            match diff.status {
+                "identical" => println!("  ✓ {}: identical", diff.agent_id),
+                "diverged" => println!(
+                    "  ✗ {}: first divergence at {} (A {}, B {})",
+                    diff.agent_id,
+                    position_label((
+                        diff.first_divergent_epoch,
+                        diff.first_divergent_seq.unwrap_or_default()
+                    )),
+                    diff.hash_a.as_deref().unwrap_or("missing"),
+                    diff.hash_b.as_deref().unwrap_or("missing"),
+                ),
+                other => println!("  ✗ {}: {}", diff.agent_id, other),
+            }
+        }
+    }
+
+    if report.iter().any(|d| d.status != "identical") {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+async fn run_anchor(
+    server_url: &str,
+    agent_id: &str,
+    epoch: u64,
+    trusted_seq: u64,
+    trusted_hex: &str,
+    to_seq: Option<u64>,
+) -> anyhow::Result<()> {
+    let trusted = hex_decode_fixed::<32>(trusted_hex.trim())
+        .map_err(|err| anyhow!("--accumulator must be 64 hex characters: {err}"))?;
+    let client = http_client();
+
+    // Hashes from the trusted seq up to (not including) the target.
+    let mut metas: Vec<RemoteMeta> = Vec::new();
+    loop {
+        let next = metas.last().map_or(trusted_seq, |m| m.seq + 1);
+        if to_seq.is_some_and(|to| next > to) {
+            break;
+        }
+        let page: Vec<RemoteMeta> = client
+            .get(format!("{}/batches/meta", server_url))
+            .query(&[
+                ("agent_id", agent_id.to_string()),
+                ("epoch", epoch.to_string()),
+                ("since_seq", next.to_string()),
+                ("limit", META_PAGE.to_string()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let full = page.len() as u64 == META_PAGE;
+        metas.extend(page);
+        if !full {
+            break;
+        }
+    }
+    if let Some(to) = to_seq {
+        metas.retain(|m| m.seq <= to);
+    }
+
+    let verdict = check_anchor(trusted_seq, trusted, &metas);
+    let target = match verdict {
+        Ok(target) => target,
+        Err(reason) => {
+            println!("✗ {reason}");
+            std::process::exit(1);
+        }
+    };
+
+    // The target's accumulator only counts if the agent signed it.
+    let batch = fetch_batch_at(&client, server_url, agent_id, (epoch, target.seq))
+        .await?
+        .ok_or_else(|| anyhow!("batch seq {} disappeared while checking", target.seq))?;
+    let windows = fetch_key_history(&client, server_url, agent_id).await?;
+    let signed = batch.batch.verify_redacted(&batch.redacted)
+        && batch.batch.compute_hash_redacted(&batch.redacted) == Ok(target.hash)
+        && batch.batch.accumulator == target.accumulator
+        && windows.as_deref().is_none_or(|w| {
+            key_authorized(
+                w,
+                &hex_encode(batch.batch.public_key.as_bytes()),
+                (epoch, target.seq),
+            )
+        });
+    if !signed {
+        println!(
+            "✗ batch seq {} does not carry a valid agent signature over its accumulator",
+            target.seq
+        );
+        std::process::exit(1);
+    }
+
+    println!(
+        "✓ seq {} extends the trusted accumulator at seq {} over {} batch hashes",
+        target.seq,
+        trusted_seq,
+        metas.len() - 1
+    );
+    println!(
+        "  accumulator {}",
+        hex_encode(&target.accumulator.unwrap_or_default())
+    );
+    Ok(())
+}
+
+/// Folds the hashes of `metas` (contiguous seqs starting at `trusted_seq`,
+/// bar the seqs a gap marker declares lost) into `trusted` and checks the
+/// result against the last row's accumulator. Returns the last row on success.
+fn check_anchor(
+    trusted_seq: u64,
+    trusted: [u8; 32],
+    metas: &[RemoteMeta],
+) -> Result<&RemoteMeta, String> {
+    let Some((target, between)) = metas.split_last() else {
+        return Err(format!("server has no batch at seq {trusted_seq}"));
+    };
+    if target.seq == trusted_seq {
+        return Err("nothing to check: target is the trusted batch itself".into());
+    }
+    let mut expected = trusted_seq;
+    for (i, meta) in metas.iter().enumerate() {
+        // The trusted batch itself must be there; a marker may only follow it.
+        let starts_at = match &meta.gap {
+            Some(gap) if i > 0 && gap.missing_to + 1 == meta.seq => gap.missing_from,
+            _ => meta.seq,
+        };
+        if starts_at != expected {
+            return Err(format!("seq gap: expected {expected}, found {}", meta.seq));
+        }
+        expected = meta.seq + 1;
+    }
+    if between[0].accumulator != Some(trusted) {
+        return Err(format!(
+            "server's accumulator at seq {trusted_seq} differs from the trusted value"
+        ));
+    }
+
+    let expected = extend_accumulator(trusted, between.iter().map(|m| &m.hash));
+    match target.accumulator {
+        Some(acc) if acc == expected => Ok(target),
+        Some(_) => Err(format!(
+            "seq {} does not extend the trusted accumulator: history between seq {} and {} was altered",
+            target.seq, trusted_seq, target.seq
+        )),
+        None => Err(format!("seq {} carries no accumulator", target.seq)),
+    }
+}
+
+async fn fetch_checkpoints(
+    client: &Client,
+    server_url: &str,
+) -> anyhow::Result<HashMap<String, RemoteCheckpoint>> {
+    let checkpoints: Vec<RemoteCheckpoint> = client
+        .get(format!("{}/batches/checkpoints", server_url))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(checkpoints
+        .into_iter()
+        .map(|cp| (cp.agent_id.clone(), cp))
+        .collect())
+}
+
+/// Stored hash of one agent's batch at `position`, or `None` if the server
+/// lacks it.
+async fn fetch_hash_at(
+    client: &Client,
+    server_url: &str,
+    agent_id: &str,
+    position: (u64, u64),
+) -> anyhow::Result<Option<[u8; 32]>> {
+    Ok(fetch_batch_at(client, server_url, agent_id, position)
+        .await?
+        .map(|b| b.hash))
+}
+
+async fn fetch_batch_at(
+    client: &Client,
+    server_url: &str,
+    agent_id: &str,
+    (epoch, seq): (u64, u64),
+) -> anyhow::Result<Option<RemoteBatch>> {
+    let batches: Vec<RemoteBatch> = client
         .get(format!("{}/batches", server_url))
+        .query(&[
+            ("agent_id", agent_id.to_string()),
+            ("epoch", epoch.to_string()),
+            ("since_seq", seq.to_string()),
+            ("limit", "1".to_string()),
+        ])
         .send()
         .await?
+        .error_for_status()?
         .json()
         .await?;
+    Ok(batches
+        .into_iter()
+        .find(|b| b.batch.position() == (epoch, seq)))
+}
 
-    println!("Received {} batches", batches.len());
-    verify_chain(&batches);
+async fn hash_pair(
+    client: &Client,
+    server_a: &str,
+    server_b: &str,
+    agent_id: &str,
+    position: (u64, u64),
+) -> anyhow::Result<(Option<[u8; 32]>, Option<[u8; 32]>)> {
+    Ok((
+        fetch_hash_at(client, server_a, agent_id, position).await?,
+        fetch_hash_at(client, server_b, agent_id, position).await?,
+    ))
+}
 
-    Ok(())
+/// Binary-searches seqs `1..=upper` for the first one whose hashes differ.
+///
+/// Each hash commits to every earlier batch through `prev_hash`, so once two
+/// chains differ at some seq they differ at every later one; that makes the
+/// search valid and keeps transfer at O(log n) single-batch fetches.
+async fn first_divergence<F, Fut>(upper: u64, mut hashes_at: F) -> anyhow::Result<Option<u64>>
+where
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = anyhow::Result<(Option<[u8; 32]>, Option<[u8; 32]>)>>,
+{
+    let (mut lo, mut hi) = (1u64, upper);
+    let mut found = None;
+    while lo <= hi {
+        let mid = lo + (hi - lo) / 2;
+        let (a, b) = hashes_at(mid).await?;
+        if a.is_some() && a == b {
+            lo = mid + 1;
+        } else {
+            found = Some(mid);
+            hi = mid - 1;
+        }
+    }
+    Ok(found)
 }
 
-fn verify_chain(chain: &[RemoteBatch]) {
+/// `counts` sizes each agent's progress bar; agents missing from it use
+/// their fetched batches. Without a `key_history` (an archive verified
+/// offline) signers are not checked against one. Returns whether every
+/// chain is valid.
+fn verify_chain(
+    chain: &[RemoteBatch],
+    key_history: Option<&HashMap<String, Vec<KeyWindow>>>,
+    counts: &HashMap<String, u64>,
+    progress: &Progress,
+) -> bool {
     println!("Verifying chain integrity per agent...\n");
 
     if chain.is_empty() {
         println!("No batches found.");
-        return;
+        return true;
+    }
+    if key_history.is_none() {
+        println!("ℹ verified offline: signers are not checked against a key history\n");
     }
 
     let mut per_agent: HashMap<String, Vec<&RemoteBatch>> = HashMap::new();
@@ -70,52 +1294,792 @@ fn verify_chain(chain: &[RemoteBatch]) {
             .push(batch);
     }
 
+    let overall = progress.bar(Unit::Batches, "all agents", Some(chain.len() as u64));
     for (agent, batches) in per_agent.iter_mut() {
-        batches.sort_by_key(|b| b.batch.seq);
-        println!("Agent {}: {} batches", agent, batches.len());
+        batches.sort_by_key(|b| b.batch.position());
+        let total = counts.get(agent).copied().unwrap_or(batches.len() as u64);
+        let label: String = agent.chars().take(12).collect();
+        let agent_bar = progress.bar(Unit::Batches, &label, Some(total));
+        let windows = key_history.and_then(|history| history.get(agent.as_str()));
+        let checked = check_agent_chain(agent, batches, windows.map(Vec::as_slice), &agent_bar);
+        agent_bar.finish_and_clear();
+        overall.inc(batches.len() as u64);
 
-        let mut expected_prev = [0u8; 32];
-        let mut expected_seq = 1u64;
-        for entry in batches.iter() {
-            let id = entry.id;
-            let batch = &entry.batch;
+        let mut report = vec![format!("Agent {}: {} batches", agent, batches.len())];
+        if key_history.is_some() && windows.is_none() {
+            report.push("  ⚠ no key history on server; signer authorization not checked".into());
+        }
+        if let Err(problem) = checked {
+            report.push(format!("  ✗ {problem}"));
+            overall.finish_and_clear();
+            progress.suspend(|| report.iter().for_each(|line| println!("{line}")));
+            return false;
+        }
 
-            if !batch.verify() {
-                println!("  ✗ signature INVALID at id {}", id);
-                return;
-            }
+        report.push("  ✓ chain valid".into());
 
-            if batch.seq != expected_seq {
-                println!(
-                    "  ✗ sequence gap for agent {} at id {} (expected {}, found {})",
-                    agent, id, expected_seq, batch.seq
-                );
-                return;
+        for entry in batches.iter() {
+            if let Some(start) = &entry.batch.epoch_start {
+                report.push(format!(
+                    "  ℹ epoch {} starts after seq {} of epoch {}",
+                    entry.batch.epoch,
+                    start.previous_last_seq,
+                    entry.batch.epoch - 1
+                ));
             }
-
-            if batch.prev_hash != expected_prev {
-                println!(
-                    "  ✗ hash chain broken for agent {} at id {} (expected {:02x?}, found {:02x?})",
-                    agent, id, expected_prev, batch.prev_hash
-                );
-                return;
+            if let Some(gap) = &entry.batch.gap {
+                report.push(format!(
+                    "  ⚠ seqs {}..={} declared lost by the agent at seq {}: {}",
+                    gap.missing_from, gap.missing_to, entry.batch.seq, gap.reason
+                ));
             }
+            if !entry.redacted.is_empty() {
+                report.push(format!(
+                    "  ℹ {} line(s) of {} redacted by the server: {:?}",
+                    entry.redacted.len(),
+                    position_label(entry.batch.position()),
+                    entry.redacted
+                ));
+            }
+        }
+
+        for gap in find_line_count_gaps(batches.iter().map(|b| &b.batch)) {
+            report.push(format!(
+                "  ⚠ lines read but not shipped before seq {} (line counter expected {}, found {})",
+                gap.seq, gap.expected, gap.found
+            ));
+        }
+        report.push(close_status(batches));
+        progress.suspend(|| report.iter().for_each(|line| println!("{line}")));
+    }
+    overall.finish_and_clear();
+
+    println!("\nAll chains valid. No tampering detected.");
+    true
+}
+
+/// Whether the agent closed its chain (see `agent --finalize`), so a chain
+/// that was closed reads differently from one that just stops. Batches after
+/// a close only reach a server with `ACCEPT_AFTER_CLOSE` set.
+fn close_status(batches: &[&RemoteBatch]) -> String {
+    let Some(last) = batches.last() else {
+        return "  ℹ chain empty".into();
+    };
+    let closed = batches.iter().enumerate().find_map(|(index, entry)| {
+        Some((index, entry.batch.position(), entry.batch.closed_reason()?))
+    });
+    match closed {
+        None => format!(
+            "  ℹ chain open: last batch is {}, no close",
+            position_label(last.batch.position())
+        ),
+        Some((index, position, reason)) if index + 1 == batches.len() => format!(
+            "  ✓ chain closed by the agent at {}: {reason}",
+            position_label(position)
+        ),
+        Some((index, position, reason)) => format!(
+            "  ⚠ chain closed at {} ({reason}), but {} batch(es) follow the close",
+            position_label(position),
+            batches.len() - index - 1
+        ),
+    }
+}
+
+/// Checks one agent's batches, sorted by (epoch, seq), and describes the
+/// first problem.
+fn check_agent_chain(
+    agent: &str,
+    batches: &[&RemoteBatch],
+    windows: Option<&[KeyWindow]>,
+    verified: &ProgressBar,
+) -> Result<(), String> {
+    // A page that starts mid-chain (a partial fetch, or pruned history) is
+    // not a tamper; say so instead of reporting its first link as broken.
+    if let Some(first) = batches.first() {
+        let batch = &first.batch;
+        if batch.linked_position() != (0, 0) {
+            let first_seen = match batch.epoch {
+                0 => format!("seq={}", batch.seq),
+                epoch => format!("epoch={epoch} seq={}", batch.seq),
+            };
+            return Err(format!(
+                "chain does not start at genesis (first seen {first_seen})"
+            ));
+        }
+        if batch.prev_hash != [0u8; 32] {
+            return Err(format!(
+                "chain does not start at genesis (first seen seq={}, prev_hash not zero)",
+                batch.seq
+            ));
+        }
+    }
+
+    let mut expected_prev = [0u8; 32];
+    let mut prev_accumulator: Option<[u8; 32]> = None;
+    let mut last_position = (0u64, 0u64);
+    for entry in batches {
+        let id = entry.id;
+        let batch = &entry.batch;
+
+        // Only the lines the server lists as redacted may stand in as
+        // markers; every other line counts as it reads.
+        if !batch.verify_redacted(&entry.redacted) {
+            return Err(format!("signature INVALID at id {}", id));
+        }
+
+        // A valid signature only proves the embedded key signed it; the key
+        // must also have been the agent's key for this seq.
+        if let Some(windows) = windows
+            && !key_authorized(
+                windows,
+                &hex_encode(batch.public_key.as_bytes()),
+                batch.position(),
+            )
+        {
+            return Err(format!(
+                "batch at id {} ({}) signed by a key outside its validity window",
+                id,
+                position_label(batch.position())
+            ));
+        }
+
+        if let Err(reason) = batch.check_gap() {
+            return Err(format!("malformed gap marker at id {}: {}", id, reason));
+        }
+
+        if let Err(reason) = batch.check_epoch() {
+            return Err(format!("malformed epoch start at id {}: {}", id, reason));
+        }
+
+        if let Err(reason) = batch.check_kind() {
+            return Err(format!("malformed batch kind at id {}: {}", id, reason));
+        }
+
+        // An epoch start links to the previous epoch's last seq.
+        if batch.epoch_start.is_some() && batch.linked_position() != last_position {
+            return Err(format!(
+                "epoch start for agent {} at id {} follows {}, but the last batch is {}",
+                agent,
+                id,
+                position_label(batch.linked_position()),
+                position_label(last_position)
+            ));
+        }
 
-            let computed_hash = batch.compute_hash();
-            if computed_hash != entry.hash {
-                println!(
-                    "  ✗ hash mismatch at id {} for agent {} (computed {:02x?}, stored {:02x?})",
-                    id, agent, computed_hash, entry.hash
-                );
-                return;
+        // A gap marker stands in for the seqs it declares lost.
+        if batch.linked_position() != last_position {
+            return Err(format!(
+                "sequence gap for agent {} at id {} (expected {}, found {})",
+                agent,
+                id,
+                last_position.1 + 1,
+                batch.gap.as_ref().map_or(batch.seq, |gap| gap.missing_from)
+            ));
+        }
+        last_position = batch.position();
+
+        if batch.prev_hash != expected_prev {
+            return Err(format!(
+                "hash chain broken for agent {} at id {} (expected {}, found {})",
+                agent,
+                id,
+                hex_encode(&expected_prev),
+                hex_encode(&batch.prev_hash)
+            ));
+        }
+
+        match (batch.accumulator, prev_accumulator) {
+            (Some(acc), previous) if acc != batch.expected_accumulator(previous.as_ref()) => {
+                return Err(format!(
+                    "accumulator at id {} (seq {}) does not extend the previous batch",
+                    id, batch.seq
+                ));
+            }
+            (None, Some(_)) => {
+                return Err(format!(
+                    "accumulator dropped at id {} (seq {}) after earlier batches carried one",
+                    id, batch.seq
+                ));
             }
+            _ => {}
+        }
+        prev_accumulator = batch.accumulator;
 
-            expected_prev = computed_hash;
-            expected_seq += 1;
+        let computed_hash = batch
+            .compute_hash_redacted(&entry.redacted)
+            .map_err(|reason| format!("bad redaction at id {}: {}", id, reason))?;
+        if computed_hash != entry.hash {
+            return Err(format!(
+                "hash mismatch at id {} for agent {} (computed {}, stored {})",
+                id,
+                agent,
+                hex_encode(&computed_hash),
+                hex_encode(&entry.hash)
+            ));
         }
 
-        println!("  ✓ chain valid");
+        expected_prev = computed_hash;
+        verified.inc(1);
     }
 
-    println!("\nAll chains valid. No tampering detected.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::batch::generate_keypair;
+    use common::testutil::{
+        append_closed, append_gap, build_chain, chain_hashes, drop_seq, extend_chain,
+        flip_log_line, mutate_hash, reorder, resign_with, start_epoch,
+    };
+    use ed25519_dalek::SigningKey;
+
+    async fn search(a: &[u8], b: &[u8]) -> Option<u64> {
+        let upper = a.len().min(b.len()) as u64;
+        first_divergence(upper, |seq| {
+            let pick = |chain: &[u8]| chain.get(seq as usize - 1).map(|&h| [h; 32]);
+            let pair = (pick(a), pick(b));
+            async move { Ok(pair) }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn search_prints_each_matching_line_after_its_address() {
+        let key = generate_keypair();
+        let chain = build_chain(&key, "web-1", 1);
+        let hit = |matches: &[&str]| SearchHit {
+            batch: chain[0].clone(),
+            matches: matches.iter().map(|m| m.to_string()).collect(),
+        };
+        let address = "web-1/1/0".to_string();
+        let lines = search_lines(&[hit(&[&address, "not an address"]), hit(&[])]);
+        assert_eq!(lines, vec![format!("{address}\t{}", chain[0].logs[0])]);
+    }
+
+    #[test]
+    fn key_windows_bound_each_signer() {
+        let windows = vec![
+            KeyWindow {
+                public_key: "aa".into(),
+                valid_from_epoch: 0,
+                valid_from_seq: 1,
+                valid_until_epoch: 0,
+                valid_until_seq: Some(3),
+            },
+            KeyWindow {
+                public_key: "bb".into(),
+                valid_from_epoch: 0,
+                valid_from_seq: 3,
+                valid_until_epoch: 1,
+                valid_until_seq: Some(2),
+            },
+            KeyWindow {
+                public_key: "cc".into(),
+                valid_from_epoch: 1,
+                valid_from_seq: 2,
+                valid_until_epoch: 0,
+                valid_until_seq: None,
+            },
+        ];
+        // Rotation mid-chain: old key before seq 3, new key from 3 on.
+        assert!(key_authorized(&windows, "aa", (0, 2)));
+        assert!(key_authorized(&windows, "BB", (0, 3)));
+        assert!(!key_authorized(&windows, "aa", (0, 3)));
+        assert!(!key_authorized(&windows, "bb", (0, 2)));
+        // Windows run across epoch starts, where seqs restart.
+        assert!(key_authorized(&windows, "bb", (1, 1)));
+        assert!(!key_authorized(&windows, "bb", (1, 2)));
+        assert!(key_authorized(&windows, "cc", (1, 2)));
+        assert!(!key_authorized(&windows, "cc", (0, 4)));
+        // A foreign key is never authorized.
+        assert!(!key_authorized(&windows, "dd", (1, 4)));
+    }
+
+    #[tokio::test]
+    async fn finds_first_divergent_seq() {
+        // Once hashes differ they keep differing, as chained hashes do.
+        assert_eq!(search(&[1, 2, 3, 4, 5], &[1, 2, 9, 9, 9]).await, Some(3));
+        assert_eq!(search(&[1, 2, 3], &[9, 9, 9]).await, Some(1));
+        assert_eq!(search(&[1, 2, 3], &[1, 2, 3]).await, None);
+        assert_eq!(search(&[1, 2, 3, 4], &[1, 2]).await, None);
+        assert_eq!(search(&[], &[]).await, None);
+    }
+
+    #[test]
+    fn anchor_folds_hashes_between_trusted_and_target() {
+        use common::batch::next_accumulator;
+
+        let trusted = [9u8; 32];
+        let hashes = [[1u8; 32], [2u8; 32], [3u8; 32]];
+        let acc2 = next_accumulator(&trusted, &hashes[0]);
+        let acc3 = next_accumulator(&acc2, &hashes[1]);
+        let metas = |target_acc| {
+            vec![
+                RemoteMeta {
+                    seq: 5,
+                    hash: hashes[0],
+                    accumulator: Some(trusted),
+                    gap: None,
+                },
+                RemoteMeta {
+                    seq: 6,
+                    hash: hashes[1],
+                    accumulator: Some(acc2),
+                    gap: None,
+                },
+                RemoteMeta {
+                    seq: 7,
+                    hash: hashes[2],
+                    accumulator: Some(target_acc),
+                    gap: None,
+                },
+            ]
+        };
+
+        let good = metas(acc3);
+        assert_eq!(check_anchor(5, trusted, &good).unwrap().seq, 7);
+
+        // A rewritten middle batch changes the hash the fold sees.
+        let mut rewritten = metas(acc3);
+        rewritten[1].hash = [0xee; 32];
+        assert!(
+            check_anchor(5, trusted, &rewritten)
+                .unwrap_err()
+                .contains("altered")
+        );
+
+        assert!(
+            check_anchor(5, [0u8; 32], &good)
+                .unwrap_err()
+                .contains("trusted value")
+        );
+        assert!(
+            check_anchor(4, trusted, &good)
+                .unwrap_err()
+                .contains("seq gap")
+        );
+    }
+
+    #[tokio::test]
+    async fn parquet_export_streams_server_file_to_output() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let body: Vec<u8> = b"PAR1".iter().copied().cycle().take(100_000).collect();
+        Mock::given(method("GET"))
+            .and(path("/batches/export"))
+            .and(query_param("format", "parquet"))
+            .and(query_param("compression", "zstd"))
+            .and(query_param("since_id", "7"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(body.clone()))
+            .mount(&server)
+            .await;
+
+        let output =
+            env::temp_dir().join(format!("logchain-export-{}.parquet", std::process::id()));
+        download_parquet(
+            &server.uri(),
+            ParquetCompression::Zstd,
+            &output,
+            Some(7),
+            None,
+            &Progress::hidden(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), body);
+        let _ = std::fs::remove_file(&output);
+
+        let disabled = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(501))
+            .mount(&disabled)
+            .await;
+        let err = download_parquet(
+            &disabled.uri(),
+            ParquetCompression::Snappy,
+            &output,
+            None,
+            None,
+            &Progress::hidden(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("without parquet"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn raw_bodies_are_checked_against_the_stored_hash() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let key = generate_keypair();
+        let chain = build_chain(&key, "agent-raw", 1);
+        let stored = rows(chain.clone(), chain_hashes(&chain)).remove(0);
+        // Not how serde would re-emit it, so only the archived bytes hash right.
+        let sent = serde_json::to_vec_pretty(&chain[0]).unwrap();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/batches/1/raw"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(sent.clone()))
+            .mount(&server)
+            .await;
+
+        let raw = fetch_raw(&http_client(), &server.uri(), 1).await.unwrap();
+        assert_eq!(raw, sent);
+        let parsed: LogBatch = serde_json::from_slice(&raw).unwrap();
+        assert_eq!(parsed.compute_hash(), stored.hash);
+        assert_eq!(check_raw(&raw, &stored), Ok(()));
+
+        let tampered = String::from_utf8(sent).unwrap().replace("line 1", "lime 1");
+        assert!(
+            check_raw(tampered.as_bytes(), &stored)
+                .unwrap_err()
+                .starts_with("hash mismatch")
+        );
+        let missing = fetch_raw(&http_client(), &server.uri(), 2)
+            .await
+            .unwrap_err();
+        assert!(missing.to_string().contains("status 404"), "{missing}");
+    }
+
+    #[tokio::test]
+    async fn handshake_refuses_only_servers_newer_than_the_cli() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        async fn advertising(caps: Option<Capabilities>) -> MockServer {
+            let server = MockServer::start().await;
+            let resp = match caps {
+                Some(caps) => ResponseTemplate::new(200).set_body_json(caps),
+                None => ResponseTemplate::new(404),
+            };
+            Mock::given(method("GET"))
+                .and(path("/version"))
+                .respond_with(resp)
+                .mount(&server)
+                .await;
+            server
+        }
+
+        let current = Capabilities::current("0.1.0");
+        let old = Capabilities {
+            batch_versions: vec![1],
+            hash_schemes: vec!["accumulator-v1".into(), "receipt-v1".into()],
+            ..current.clone()
+        };
+        let newer = Capabilities {
+            batch_versions: vec![1, 2, 3, 4],
+            ..current.clone()
+        };
+        for caps in [Some(current), Some(old), None] {
+            check_compat(&advertising(caps).await.uri()).await.unwrap();
+        }
+        let err = check_compat(&advertising(Some(newer)).await.uri())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "incompatible server 0.1.0: server stores batch versions up to v4; this CLI verifies \
+             up to v3 — upgrade the CLI, or pass --skip-compat-check"
+        );
+    }
+
+    /// `/batches` rows for `chain`, with the stored hashes it would have.
+    fn rows(chain: Vec<LogBatch>, hashes: Vec<[u8; 32]>) -> Vec<RemoteBatch> {
+        chain
+            .into_iter()
+            .zip(hashes)
+            .zip(1..)
+            .map(|((batch, hash), id)| RemoteBatch {
+                id,
+                batch,
+                hash,
+                redacted: Vec::new(),
+            })
+            .collect()
+    }
+
+    fn check(rows: &[RemoteBatch], key: &SigningKey) -> Result<(), String> {
+        let windows = [KeyWindow {
+            public_key: hex_encode(key.verifying_key().as_bytes()),
+            valid_from_epoch: 0,
+            valid_from_seq: 1,
+            valid_until_epoch: 0,
+            valid_until_seq: None,
+        }];
+        let mut batches: Vec<&RemoteBatch> = rows.iter().collect();
+        batches.sort_by_key(|b| b.batch.position());
+        check_agent_chain("agent-t", &batches, Some(&windows), &ProgressBar::hidden())
+    }
+
+    #[test]
+    fn verifier_reports_every_tamper() {
+        let key = generate_keypair();
+        let chain = build_chain(&key, "agent-t", 4);
+        let hashes = chain_hashes(&chain);
+        assert_eq!(check(&rows(chain.clone(), hashes.clone()), &key), Ok(()));
+
+        let expect = |rows: Vec<RemoteBatch>, needle: &str| {
+            let err = check(&rows, &key).unwrap_err();
+            assert!(err.contains(needle), "expected {needle:?}, got {err:?}");
+        };
+
+        let mut flipped = chain.clone();
+        flip_log_line(&mut flipped, 2);
+        expect(rows(flipped, hashes.clone()), "signature INVALID at id 2");
+
+        let mut reordered = chain.clone();
+        reorder(&mut reordered, 2, 3, &key);
+        let rehashed = chain_hashes(&reordered);
+        expect(
+            rows(reordered, rehashed),
+            "hash chain broken for agent agent-t at id 2",
+        );
+
+        let mut dropped = chain.clone();
+        let mut dropped_hashes = hashes.clone();
+        drop_seq(&mut dropped, 3);
+        dropped_hashes.remove(2);
+        expect(
+            rows(dropped, dropped_hashes),
+            "sequence gap for agent agent-t at id 3",
+        );
+
+        let mut resigned = chain.clone();
+        resign_with(&mut resigned, 4, &generate_keypair());
+        expect(
+            rows(resigned, hashes.clone()),
+            "(seq 4) signed by a key outside",
+        );
+
+        let mut stored = hashes.clone();
+        mutate_hash(&mut stored[1]);
+        expect(rows(chain.clone(), stored), "hash mismatch at id 2");
+    }
+
+    #[test]
+    fn verifier_tells_a_partial_chain_from_a_broken_one() {
+        let key = generate_keypair();
+        let chain = build_chain(&key, "agent-t", 5);
+        let hashes = chain_hashes(&chain);
+        let partial = rows(chain[2..].to_vec(), hashes[2..].to_vec());
+        assert_eq!(
+            check(&partial, &key),
+            Err("chain does not start at genesis (first seen seq=3)".into())
+        );
+
+        // Seq 1 linking to anything but zero is not a genesis either.
+        let mut relinked = chain.clone();
+        relinked[0].prev_hash = [9u8; 32];
+        relinked[0].sign(&key);
+        let rehashed = chain_hashes(&relinked);
+        let err = check(&rows(relinked, rehashed), &key).unwrap_err();
+        assert!(
+            err.contains("first seen seq=1, prev_hash not zero"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn verifier_accepts_gap_markers_but_not_forged_ones() {
+        let key = generate_keypair();
+        let mut chain = build_chain(&key, "agent-t", 2);
+        append_gap(&mut chain, &key, 2, "state lost");
+        extend_chain(&mut chain, &key, 2);
+        // Seqs 3 and 4 are declared lost by the marker at seq 5.
+        let hashes = chain_hashes(&chain);
+        assert_eq!(check(&rows(chain.clone(), hashes.clone()), &key), Ok(()));
+
+        // Widening the declared range after the fact breaks the signature.
+        let mut widened = chain.clone();
+        widened[2].gap.as_mut().unwrap().missing_from = 2;
+        let err = check(&rows(widened, hashes.clone()), &key).unwrap_err();
+        assert!(err.contains("signature INVALID at id 3"), "{err}");
+
+        // A key holder cannot re-sign a marker over seqs the server holds.
+        let mut overlapping = chain.clone();
+        overlapping[2].gap.as_mut().unwrap().missing_from = 2;
+        overlapping[2].sign(&key);
+        let rehashed = chain_hashes(&overlapping);
+        let err = check(&rows(overlapping, rehashed), &key).unwrap_err();
+        assert!(
+            err.contains("sequence gap for agent agent-t at id 3 (expected 3, found 2)"),
+            "{err}"
+        );
+
+        // Nor hide logs in one.
+        let mut smuggled = chain.clone();
+        smuggled[2].logs.push("hidden".into());
+        smuggled[2].sign(&key);
+        let rehashed = chain_hashes(&smuggled);
+        let err = check(&rows(smuggled, rehashed), &key).unwrap_err();
+        assert!(err.contains("malformed gap marker at id 3"), "{err}");
+    }
+
+    #[test]
+    fn verifier_tells_a_closed_chain_from_a_truncated_one() {
+        let key = generate_keypair();
+        let mut chain = build_chain(&key, "agent-t", 3);
+        let truncated = rows(chain[..2].to_vec(), chain_hashes(&chain[..2]));
+        append_closed(&mut chain, &key, "host decommissioned");
+        let hashes = chain_hashes(&chain);
+        let closed = rows(chain.clone(), hashes);
+        assert_eq!(check(&closed, &key), Ok(()));
+        let status = |rows: &[RemoteBatch]| close_status(&rows.iter().collect::<Vec<_>>());
+        assert_eq!(
+            status(&closed),
+            "  ✓ chain closed by the agent at seq 4: host decommissioned"
+        );
+        assert_eq!(
+            status(&truncated),
+            "  ℹ chain open: last batch is seq 2, no close"
+        );
+
+        // A server with ACCEPT_AFTER_CLOSE can store more; the verifier says so.
+        extend_chain(&mut chain, &key, 2);
+        let hashes = chain_hashes(&chain);
+        let reopened = rows(chain.clone(), hashes);
+        assert_eq!(check(&reopened, &key), Ok(()));
+        assert_eq!(
+            status(&reopened),
+            "  ⚠ chain closed at seq 4 (host decommissioned), but 2 batch(es) follow the close"
+        );
+
+        // The reason cannot be blanked after the fact, even re-signed.
+        let mut blanked = chain;
+        blanked[3].kind = Some(common::batch::BatchKind::Closed {
+            reason: String::new(),
+        });
+        blanked[3].sign(&key);
+        let rehashed = chain_hashes(&blanked);
+        let err = check(&rows(blanked, rehashed), &key).unwrap_err();
+        assert_eq!(
+            err,
+            "malformed batch kind at id 4: chain close needs a reason"
+        );
+    }
+
+    #[test]
+    fn verifier_accepts_epoch_starts_but_not_forged_links() {
+        let key = generate_keypair();
+        let mut chain = build_chain(&key, "agent-t", 3);
+        start_epoch(&mut chain, &key);
+        extend_chain(&mut chain, &key, 2);
+        // Epoch 1 restarts at seq 1 and its accumulator with it.
+        let hashes = chain_hashes(&chain);
+        assert_eq!(check(&rows(chain.clone(), hashes.clone()), &key), Ok(()));
+
+        // Dropping the last batch of epoch 0 leaves the start linking past it.
+        let (mut dropped, mut dropped_hashes) = (chain.clone(), hashes.clone());
+        dropped.remove(2);
+        dropped_hashes.remove(2);
+        let err = check(&rows(dropped, dropped_hashes), &key).unwrap_err();
+        assert_eq!(
+            err,
+            "epoch start for agent agent-t at id 3 follows seq 3, but the last batch is seq 2"
+        );
+
+        // A key holder cannot re-sign the start to cover for it either.
+        let mut relinked = chain.clone();
+        relinked[3].epoch_start.as_mut().unwrap().previous_last_seq = 2;
+        relinked[3].sign(&key);
+        let rehashed = chain_hashes(&relinked);
+        let err = check(&rows(relinked, rehashed), &key).unwrap_err();
+        assert!(
+            err.contains("follows seq 2, but the last batch is seq 3"),
+            "{err}"
+        );
+
+        // Nor move to a new epoch without a start.
+        let mut unmarked = chain.clone();
+        unmarked[3].epoch_start = None;
+        unmarked[3].sign(&key);
+        let rehashed = chain_hashes(&unmarked);
+        let err = check(&rows(unmarked, rehashed), &key).unwrap_err();
+        assert!(err.contains("malformed epoch start at id 4"), "{err}");
+    }
+
+    #[test]
+    fn anchor_crosses_gap_markers() {
+        use common::batch::next_accumulator;
+
+        let trusted = [9u8; 32];
+        let hashes = [[1u8; 32], [2u8; 32], [3u8; 32]];
+        let acc2 = next_accumulator(&trusted, &hashes[0]);
+        let target = next_accumulator(&acc2, &hashes[1]);
+        let gap = |from| GapRecord {
+            missing_from: from,
+            missing_to: 7,
+            reason: "lost".into(),
+        };
+        let metas = |from| {
+            vec![
+                RemoteMeta {
+                    seq: 5,
+                    hash: hashes[0],
+                    accumulator: Some(trusted),
+                    gap: None,
+                },
+                RemoteMeta {
+                    seq: 8,
+                    hash: hashes[1],
+                    accumulator: Some(acc2),
+                    gap: Some(gap(from)),
+                },
+                RemoteMeta {
+                    seq: 9,
+                    hash: hashes[2],
+                    accumulator: Some(target),
+                    gap: None,
+                },
+            ]
+        };
+
+        assert_eq!(check_anchor(5, trusted, &metas(6)).unwrap().seq, 9);
+        // The marker must pick up right after the row before it.
+        assert!(
+            check_anchor(5, trusted, &metas(7))
+                .unwrap_err()
+                .contains("seq gap")
+        );
+    }
+
+    #[test]
+    fn verifier_catches_every_simulated_fault() {
+        use common::testkit::{ChainSimulator, Fault};
+
+        for (fault, needle) in [
+            (Fault::BitFlip, "signature INVALID"),
+            (Fault::SeqGap, "sequence gap"),
+            (Fault::WrongPrevHash, "hash chain broken"),
+            (
+                Fault::ForeignSignature,
+                "signed by a key outside its validity window",
+            ),
+        ] {
+            let mut sim = ChainSimulator::new(23, 3);
+            let mut batches = sim.run(5);
+            let injected = sim.inject(&mut batches, fault);
+            for agent in sim.agents() {
+                let chain: Vec<LogBatch> = batches
+                    .iter()
+                    .filter(|b| b.agent_id == agent.agent_id)
+                    .cloned()
+                    .collect();
+                let hashes = chain_hashes(&chain);
+                let result = check(&rows(chain, hashes), &agent.key);
+                if agent.agent_id == injected.agent_id {
+                    let err = result.unwrap_err();
+                    assert!(err.contains(needle), "{injected}: {err}");
+                } else {
+                    assert_eq!(result, Ok(()), "{injected}");
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,183 @@
+//! Progress bars for the long-running commands, drawn on stderr. They only
+//! show when stdout is a terminal and the output is meant for a person:
+//! `--quiet`, JSON reports and exports to stdout turn them off, so cron logs
+//! and pipes never see control sequences.
+//!
+//! A bar needs a known total, taken from `/batches/checkpoints` counts or
+//! `Content-Length`; without one the command gets a spinner. Templates are
+//! picked by terminal width, so a narrow terminal gets a shorter line
+//! instead of a wrapped one.
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::IsTerminal;
+use std::time::Duration;
+
+/// Used when the terminal width cannot be read.
+const DEFAULT_WIDTH: u16 = 80;
+
+/// What a bar counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Batches,
+    Bytes,
+}
+
+pub struct Progress {
+    /// `None` when progress is suppressed.
+    multi: Option<MultiProgress>,
+    width: u16,
+}
+
+/// Whether a command should draw progress: only for a terminal on stdout,
+/// without `--quiet`, and when stdout does not carry machine-readable output.
+pub fn should_show(stdout_is_terminal: bool, quiet: bool, machine_output: bool) -> bool {
+    stdout_is_terminal && !quiet && !machine_output
+}
+
+impl Progress {
+    /// Progress for a command whose stdout is `machine_output` or not; checks
+    /// the real terminal.
+    pub fn for_command(quiet: bool, machine_output: bool) -> Self {
+        if !should_show(std::io::stdout().is_terminal(), quiet, machine_output) {
+            return Self::hidden();
+        }
+        let width = console::Term::stderr()
+            .size_checked()
+            .map_or(DEFAULT_WIDTH, |(_, cols)| cols);
+        Self::drawn_to(ProgressDrawTarget::stderr(), width)
+    }
+
+    /// Progress that draws nothing.
+    pub fn hidden() -> Self {
+        Self {
+            multi: None,
+            width: DEFAULT_WIDTH,
+        }
+    }
+
+    fn drawn_to(target: ProgressDrawTarget, width: u16) -> Self {
+        Self {
+            multi: Some(MultiProgress::with_draw_target(target)),
+            width,
+        }
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.multi.is_none()
+    }
+
+    /// A bar of `total` units, or a spinner if the total is unknown.
+    pub fn bar(&self, unit: Unit, label: &str, total: Option<u64>) -> ProgressBar {
+        let Some(multi) = &self.multi else {
+            return ProgressBar::hidden();
+        };
+        let bar = match total {
+            Some(total) => ProgressBar::new(total),
+            None => ProgressBar::new_spinner(),
+        };
+        let style = ProgressStyle::with_template(template(unit, total.is_some(), self.width))
+            .expect("progress templates are valid")
+            .progress_chars("=> ");
+        let bar = multi.add(bar.with_style(style).with_prefix(label.to_string()));
+        if total.is_none() {
+            bar.enable_steady_tick(Duration::from_millis(120));
+        }
+        bar
+    }
+
+    /// Runs `f` with the bars cleared, for printing between their updates.
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        match &self.multi {
+            Some(multi) => multi.suspend(f),
+            None => f(),
+        }
+    }
+}
+
+/// The template for a `width`-column terminal: bar, counts and throughput
+/// when there is room, counts alone when there is not.
+pub fn template(unit: Unit, known_total: bool, width: u16) -> &'static str {
+    match (unit, known_total, width) {
+        (Unit::Batches, true, 80..) => {
+            "{prefix:>12} [{wide_bar}] {human_pos}/{human_len} batches ({per_sec}, eta {eta}) {msg}"
+        }
+        (Unit::Batches, true, 50..) => "{prefix:>12} [{bar:20}] {human_pos}/{human_len} {msg}",
+        (Unit::Batches, true, _) => "{human_pos}/{human_len}",
+        (Unit::Batches, false, 50..) => {
+            "{spinner} {prefix:>12} {human_pos} batches ({per_sec}) {msg}"
+        }
+        (Unit::Batches, false, _) => "{spinner} {human_pos}",
+        (Unit::Bytes, true, 80..) => {
+            "{prefix:>12} [{wide_bar}] {bytes}/{total_bytes} ({bytes_per_sec}, eta {eta}) {msg}"
+        }
+        (Unit::Bytes, true, 50..) => "{prefix:>12} [{bar:20}] {bytes}/{total_bytes} {msg}",
+        (Unit::Bytes, true, _) => "{bytes}/{total_bytes}",
+        (Unit::Bytes, false, 50..) => "{spinner} {prefix:>12} {bytes} ({bytes_per_sec}) {msg}",
+        (Unit::Bytes, false, _) => "{spinner} {bytes}",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_only_for_people_at_a_terminal() {
+        assert!(should_show(true, false, false));
+        assert!(!should_show(false, false, false));
+        assert!(!should_show(true, true, false));
+        assert!(!should_show(true, false, true));
+
+        // Tests run with stdout captured: no terminal, so nothing is drawn.
+        let progress = Progress::for_command(false, false);
+        assert!(progress.is_hidden());
+        let bar = progress.bar(Unit::Batches, "verify", Some(10));
+        assert!(bar.is_hidden());
+        bar.inc(10);
+        assert_eq!(progress.suspend(|| 7), 7);
+    }
+
+    #[test]
+    fn templates_fit_the_terminal_width() {
+        for unit in [Unit::Batches, Unit::Bytes] {
+            for known in [true, false] {
+                let widths = [200, 80, 79, 50, 49, 20];
+                let lengths: Vec<usize> = widths
+                    .iter()
+                    .map(|&w| template(unit, known, w).len())
+                    .collect();
+                // Never longer as the terminal narrows.
+                assert!(lengths.windows(2).all(|w| w[0] >= w[1]), "{unit:?} {known}");
+                // Narrow terminals drop the label, the bar and throughput.
+                let narrow = template(unit, known, 20);
+                assert!(!narrow.contains("prefix") && !narrow.contains("bar"));
+                assert!(!narrow.contains("per_sec"));
+                // Every template parses.
+                for &w in &widths {
+                    ProgressStyle::with_template(template(unit, known, w)).unwrap();
+                }
+            }
+            assert!(template(unit, true, 120).contains("wide_bar"));
+            assert!(template(unit, false, 120).contains("spinner"));
+        }
+
+        // Drawn into a fixed-width buffer, a line never exceeds the width.
+        for width in [30u16, 60, 100] {
+            let term = indicatif::InMemoryTerm::new(4, width);
+            let progress =
+                Progress::drawn_to(ProgressDrawTarget::term_like(Box::new(term.clone())), width);
+            let bar = progress.bar(Unit::Bytes, "export", Some(10_000_000));
+            bar.set_position(4_000_000);
+            bar.set_message("12 batches");
+            bar.tick();
+            let contents = term.contents();
+            assert!(!contents.trim().is_empty(), "{width}: nothing drawn");
+            for line in contents.lines() {
+                assert!(
+                    console::measure_text_width(line) <= width as usize,
+                    "{width}: {line:?}"
+                );
+            }
+        }
+    }
+}
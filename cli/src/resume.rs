@@ -0,0 +1,412 @@
+//! `export --output` in a line format: pages through `/batches/export` by
+//! `since_id` and, after each page is on disk, rewrites
+//! `<output>.manifest.json` with the last row id written and the SHA-256 of
+//! the file so far. `--resume` checks the file against that hash and carries
+//! on after the recorded id, so the result is byte-identical to an export
+//! that was never interrupted.
+//!
+//! Only ndjson, syslog and cef qualify: a page appended to them never
+//! rewrites earlier bytes. The JSON array and parquet are written in one go.
+
+use crate::{RemoteBatch, export_query, http_client, render_rows};
+use anyhow::{Context, anyhow, bail};
+use common::export::ExportFormat;
+use common::hex::hex_encode;
+use indicatif::{HumanBytes, ProgressBar};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Batches requested per call to `/batches/export`.
+pub const EXPORT_PAGE: u64 = 1000;
+
+const MANIFEST_VERSION: u32 = 1;
+
+/// What an export covers; a resume must repeat the options it started with.
+#[derive(Debug, Clone, Copy)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    pub since_id: Option<i64>,
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    /// The options the export was started with; a resume must repeat them.
+    format: String,
+    since_id: Option<i64>,
+    limit: Option<u64>,
+    /// Row id of the last batch fully written; the next page starts after it.
+    last_id: Option<i64>,
+    batches: u64,
+    /// Length of the output the hash covers. Anything past it is a page the
+    /// interrupted run did not finish.
+    bytes: u64,
+    /// Hex SHA-256 of the first `bytes` bytes of the output.
+    sha256: String,
+}
+
+pub fn supports(format: ExportFormat) -> bool {
+    matches!(
+        format,
+        ExportFormat::Ndjson | ExportFormat::Syslog | ExportFormat::Cef
+    )
+}
+
+pub fn manifest_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".manifest.json");
+    PathBuf::from(name)
+}
+
+/// Replaces the manifest atomically, so a kill leaves the old or the new one.
+fn save(path: &Path, manifest: &Manifest) -> anyhow::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(manifest)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Opens `output` where the manifest left off: cuts an unfinished page,
+/// refuses a file that is shorter than recorded or hashes differently.
+fn reopen(
+    output: &Path,
+    manifest_path: &Path,
+    options: ExportOptions,
+) -> anyhow::Result<(File, Sha256, Manifest)> {
+    let ExportOptions {
+        format,
+        since_id,
+        limit,
+    } = options;
+    let raw = fs::read(manifest_path)
+        .with_context(|| format!("no manifest at {}", manifest_path.display()))?;
+    let manifest: Manifest = serde_json::from_slice(&raw)
+        .with_context(|| format!("{} is not an export manifest", manifest_path.display()))?;
+    if manifest.version != MANIFEST_VERSION {
+        bail!("unsupported manifest version {}", manifest.version);
+    }
+    if manifest.format != format.as_str()
+        || manifest.since_id != since_id
+        || manifest.limit != limit
+    {
+        bail!(
+            "{} was started with --format {} --since-id {:?} --limit {:?}; resume with the same options",
+            output.display(),
+            manifest.format,
+            manifest.since_id,
+            manifest.limit
+        );
+    }
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(output)
+        .with_context(|| format!("cannot open {}", output.display()))?;
+    let len = file.metadata()?.len();
+    if len < manifest.bytes {
+        bail!(
+            "{} is truncated: {len} bytes, manifest records {}; export again without --resume",
+            output.display(),
+            manifest.bytes
+        );
+    }
+    if len > manifest.bytes {
+        eprintln!(
+            "Dropping {} bytes of an unfinished page from {}",
+            len - manifest.bytes,
+            output.display()
+        );
+        file.set_len(manifest.bytes)?;
+    }
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    if hex_encode(&hasher.clone().finalize()) != manifest.sha256 {
+        bail!(
+            "{} does not match its manifest (SHA-256 differs); export again without --resume",
+            output.display()
+        );
+    }
+    file.seek(SeekFrom::End(0))?;
+    Ok((file, hasher, manifest))
+}
+
+pub async fn run(
+    server_url: &str,
+    output: &Path,
+    options: ExportOptions,
+    resume: bool,
+    page: u64,
+    bar: &ProgressBar,
+) -> anyhow::Result<()> {
+    let ExportOptions {
+        format,
+        since_id,
+        limit,
+    } = options;
+    let manifest_path = manifest_path(output);
+    let (mut file, mut hasher, mut manifest) = if resume {
+        reopen(output, &manifest_path, options)?
+    } else {
+        let file = File::create(output)?;
+        let hasher = Sha256::new();
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            format: format.as_str().into(),
+            since_id,
+            limit,
+            last_id: since_id,
+            batches: 0,
+            bytes: 0,
+            sha256: hex_encode(&hasher.clone().finalize()),
+        };
+        save(&manifest_path, &manifest)?;
+        (file, hasher, manifest)
+    };
+    let resumed_at = manifest.batches;
+    bar.set_position(resumed_at);
+    bar.set_message(format!("{} written", HumanBytes(manifest.bytes)));
+
+    let client = http_client();
+    loop {
+        let want = match limit {
+            Some(limit) => limit.saturating_sub(manifest.batches).min(page),
+            None => page,
+        };
+        if want == 0 {
+            break;
+        }
+        let resp = client
+            .get(format!("{}/batches/export", server_url))
+            .query(&export_query(manifest.last_id, Some(want)))
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            return Err(anyhow!(
+                "export failed after {} batches: status {}; rerun with --resume",
+                manifest.batches,
+                resp.status()
+            ));
+        }
+        let batches: Vec<RemoteBatch> = resp.json().await?;
+        let Some(last) = batches.last() else {
+            break;
+        };
+
+        // Data first, manifest second: a kill in between leaves bytes the
+        // manifest does not cover, which the next resume cuts off.
+        let out = render_rows(format, &batches)?;
+        file.write_all(out.as_bytes())?;
+        file.sync_data()?;
+        hasher.update(out.as_bytes());
+        manifest.last_id = Some(last.id);
+        manifest.batches += batches.len() as u64;
+        manifest.bytes += out.len() as u64;
+        manifest.sha256 = hex_encode(&hasher.clone().finalize());
+        save(&manifest_path, &manifest)?;
+        bar.inc(batches.len() as u64);
+        bar.set_message(format!("{} written", HumanBytes(manifest.bytes)));
+
+        if (batches.len() as u64) < want {
+            break;
+        }
+    }
+
+    bar.finish_and_clear();
+    eprintln!(
+        "Exported {} batches to {} ({} this run)",
+        manifest.batches,
+        output.display(),
+        manifest.batches - resumed_at
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::testutil::build_chain;
+    use ed25519_dalek::SigningKey;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+    /// Serves `rows` by `since_id` and `limit` like `/batches/export`, and
+    /// fails every call after the first `healthy` ones.
+    struct Pages {
+        rows: Vec<RemoteBatch>,
+        healthy: usize,
+        calls: AtomicUsize,
+    }
+
+    impl Respond for Pages {
+        fn respond(&self, request: &Request) -> ResponseTemplate {
+            if self.calls.fetch_add(1, Ordering::SeqCst) >= self.healthy {
+                return ResponseTemplate::new(500);
+            }
+            let param = |name: &str| {
+                request
+                    .url
+                    .query_pairs()
+                    .find(|(k, _)| k == name)
+                    .map(|(_, v)| v.parse::<i64>().unwrap())
+            };
+            let since = param("since_id").unwrap_or(0);
+            let limit = param("limit").unwrap_or(i64::MAX) as usize;
+            let page: Vec<&RemoteBatch> = self
+                .rows
+                .iter()
+                .filter(|row| row.id > since)
+                .take(limit)
+                .collect();
+            ResponseTemplate::new(200).set_body_json(page)
+        }
+    }
+
+    async fn serve(healthy: usize) -> MockServer {
+        let chain = build_chain(&SigningKey::from_bytes(&[7; 32]), "agent-r", 7);
+        let rows = chain
+            .into_iter()
+            .zip(1..)
+            .map(|(batch, id)| RemoteBatch {
+                id,
+                hash: batch.compute_hash(),
+                batch,
+                redacted: Vec::new(),
+            })
+            .collect();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/batches/export"))
+            .respond_with(Pages {
+                rows,
+                healthy,
+                calls: AtomicUsize::new(0),
+            })
+            .mount(&server)
+            .await;
+        server
+    }
+
+    #[tokio::test]
+    async fn interrupted_export_resumes_to_identical_bytes() {
+        let dir = std::env::temp_dir().join(format!("cli-resume-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let whole = dir.join("whole.ndjson");
+        let parts = dir.join("parts.ndjson");
+        let options = ExportOptions {
+            format: ExportFormat::Ndjson,
+            since_id: None,
+            limit: None,
+        };
+
+        let healthy = serve(usize::MAX).await;
+        run(
+            &healthy.uri(),
+            &whole,
+            options,
+            false,
+            2,
+            &ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+        let expected = fs::read(&whole).unwrap();
+        assert_eq!(expected.iter().filter(|&&b| b == b'\n').count(), 7);
+
+        // Killed after two pages, mid-way through writing a third.
+        let dying = serve(2).await;
+        let err = run(
+            &dying.uri(),
+            &parts,
+            options,
+            false,
+            2,
+            &ProgressBar::hidden(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("after 4 batches"), "{err}");
+        OpenOptions::new()
+            .append(true)
+            .open(&parts)
+            .unwrap()
+            .write_all(b"{\"id\":5,\"bat")
+            .unwrap();
+
+        run(
+            &healthy.uri(),
+            &parts,
+            options,
+            true,
+            2,
+            &ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&parts).unwrap(), expected);
+        // Resuming a finished export leaves it as it is.
+        run(
+            &healthy.uri(),
+            &parts,
+            options,
+            true,
+            2,
+            &ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&parts).unwrap(), expected);
+
+        let cef = ExportOptions {
+            format: ExportFormat::Cef,
+            ..options
+        };
+        let err = run(&healthy.uri(), &parts, cef, true, 2, &ProgressBar::hidden())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("same options"), "{err}");
+
+        let mut edited = expected.clone();
+        edited[3] ^= 1;
+        fs::write(&parts, &edited).unwrap();
+        let err = run(
+            &healthy.uri(),
+            &parts,
+            options,
+            true,
+            2,
+            &ProgressBar::hidden(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("SHA-256 differs"), "{err}");
+
+        fs::write(&parts, &expected[..10]).unwrap();
+        let err = run(
+            &healthy.uri(),
+            &parts,
+            options,
+            true,
+            2,
+            &ProgressBar::hidden(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{err}");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
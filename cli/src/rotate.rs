@@ -0,0 +1,175 @@
+//! `rotate`: hands an agent over to a new key from outside the agent, e.g.
+//! with keys kept offline. The current key signs a v2 request (see
+//! [`common::rotation::RotateRequest::signed`]) carrying the counter, the
+//! time and a fresh nonce. Keys are files of 32 raw bytes, as the agent
+//! keeps them; a missing `--new-key` file is generated first.
+
+use anyhow::{Context, anyhow};
+use common::batch::generate_keypair;
+use common::rotation::RotateRequest;
+use ed25519_dalek::SigningKey;
+use reqwest::Client;
+use serde::Deserialize;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Deserialize)]
+struct AgentResponse {
+    message: String,
+}
+
+fn read_key(path: &Path) -> anyhow::Result<SigningKey> {
+    let bytes = fs::read(path).with_context(|| format!("cannot read key {}", path.display()))?;
+    let bytes: [u8; 32] = bytes.try_into().map_err(|bytes: Vec<u8>| {
+        anyhow!(
+            "key {} is {} bytes, expected 32",
+            path.display(),
+            bytes.len()
+        )
+    })?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// The key in `path`, or a new one written there readable by the owner only.
+fn read_or_generate_key(path: &Path) -> anyhow::Result<SigningKey> {
+    if path.exists() {
+        return read_key(path);
+    }
+    let key = generate_keypair();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(&key.to_bytes()))
+        .with_context(|| format!("cannot write key {}", path.display()))?;
+    println!("Generated a new key at {}", path.display());
+    Ok(key)
+}
+
+/// Sends the rotation; returns the server's message once it took it.
+pub async fn run(
+    client: &Client,
+    server_url: &str,
+    agent_id: &str,
+    current_key: &Path,
+    new_key: &Path,
+    counter: u64,
+) -> anyhow::Result<String> {
+    let current = read_key(current_key)?;
+    let next = read_or_generate_key(new_key)?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let req = RotateRequest::signed(
+        &current,
+        agent_id,
+        &next.verifying_key(),
+        counter,
+        timestamp,
+    );
+    let resp = client
+        .post(format!("{server_url}/agents/rotate"))
+        .json(&req)
+        .send()
+        .await?;
+    let status = resp.status();
+    let message = resp
+        .json::<AgentResponse>()
+        .await
+        .map(|body| body.message)
+        .unwrap_or_default();
+    if !status.is_success() {
+        return Err(anyhow!("rotation refused ({status}): {message}"));
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::hex::{hex_decode_fixed, hex_encode};
+    use common::rotation::rotation_envelope;
+    use ed25519_dalek::Signature;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn rotations_are_sent_as_v2_requests_signed_by_the_current_key() {
+        let dir = std::env::temp_dir().join(format!("cli-rotate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let current = generate_keypair();
+        let (current_path, new_path) = (dir.join("current.key"), dir.join("new.key"));
+        fs::write(&current_path, current.to_bytes()).unwrap();
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/agents/rotate"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "status": "ok", "message": "Key rotated"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let message = run(
+            &Client::new(),
+            &server.uri(),
+            "agent-1",
+            &current_path,
+            &new_path,
+            3,
+        )
+        .await
+        .unwrap();
+        assert_eq!(message, "Key rotated");
+
+        let next = read_key(&new_path).unwrap();
+        let sent: RotateRequest = server.received_requests().await.unwrap()[0]
+            .body_json()
+            .unwrap();
+        assert_eq!((sent.agent_id.as_str(), sent.counter), ("agent-1", 3));
+        assert_eq!(
+            sent.new_public_key_hex,
+            hex_encode(next.verifying_key().as_bytes())
+        );
+        let envelope = rotation_envelope(
+            "agent-1",
+            &next.verifying_key(),
+            3,
+            sent.timestamp.unwrap(),
+            Some(sent.nonce.as_deref().unwrap()),
+        );
+        let signature =
+            Signature::from_bytes(&hex_decode_fixed::<64>(&sent.auth_signature_hex).unwrap());
+        current
+            .verifying_key()
+            .verify_strict(&envelope, &signature)
+            .unwrap();
+
+        // A refusal surfaces the server's reason; the new key stays for a retry.
+        server.reset().await;
+        Mock::given(method("POST"))
+            .and(path("/agents/rotate"))
+            .respond_with(ResponseTemplate::new(409).set_body_json(serde_json::json!({
+                "status": "error", "message": "rotation nonce already used; sign a new request"
+            })))
+            .mount(&server)
+            .await;
+        let err = run(
+            &Client::new(),
+            &server.uri(),
+            "agent-1",
+            &current_path,
+            &new_path,
+            3,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("409 Conflict"), "{err}");
+        assert!(err.to_string().contains("nonce already used"), "{err}");
+        assert_eq!(read_key(&new_path).unwrap().to_bytes(), next.to_bytes());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
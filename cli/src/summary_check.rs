@@ -0,0 +1,258 @@
+//! `summary-check`: checks an archive, a `json` or `ndjson` export, against
+//! the server's daily summaries (`/summaries`). Once old batches are gone
+//! from the server, the summaries are what is left to prove the archive
+//! holds exactly the batches that were stored: each row must still hash to
+//! its stored hash, and each agent's day must match its summary's counts,
+//! seq range, head hash and Merkle root.
+
+use crate::{archive, http_client};
+use anyhow::bail;
+use common::batch::LogBatch;
+use common::summary::{DailySummary, SummaryEntry, day_of};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// An export row; `id` and anything else are ignored.
+#[derive(Deserialize)]
+struct ArchivedBatch {
+    batch: LogBatch,
+    hash: [u8; 32],
+    received_at: u64,
+    #[serde(default)]
+    redacted: Vec<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct DayCheck {
+    agent_id: String,
+    day: String,
+    /// `match`, `differs` or `unsummarized` (the server has no summary
+    /// for the day yet).
+    status: &'static str,
+    /// Fields that differ from the summary.
+    differs: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    /// Rows whose content no longer hashes to their stored hash.
+    altered: Vec<String>,
+    days: Vec<DayCheck>,
+}
+
+impl Report {
+    fn ok(&self) -> bool {
+        self.altered.is_empty() && self.days.iter().all(|day| day.status != "differs")
+    }
+}
+
+/// The rows of an export; a header, if any, is not needed here.
+fn load_archive(path: &Path) -> anyhow::Result<Vec<ArchivedBatch>> {
+    Ok(archive::load(path)?.rows)
+}
+
+async fn check(
+    client: &Client,
+    server_url: &str,
+    archive: Vec<ArchivedBatch>,
+) -> anyhow::Result<Report> {
+    let mut altered = Vec::new();
+    let mut days: BTreeMap<(String, String), Vec<SummaryEntry>> = BTreeMap::new();
+    for row in archive {
+        let hash = row
+            .batch
+            .compute_hash_redacted(&row.redacted)
+            .unwrap_or_else(|_| row.batch.compute_hash());
+        if hash != row.hash {
+            altered.push(format!("{} seq {}", row.batch.agent_id, row.batch.seq));
+        }
+        days.entry((row.batch.agent_id.clone(), day_of(row.received_at as i64)))
+            .or_default()
+            .push(SummaryEntry {
+                epoch: row.batch.epoch,
+                seq: row.batch.seq,
+                hash,
+                lines: row.batch.logs.len() as u64,
+            });
+    }
+    let (Some(first), Some(last)) = (
+        days.keys().map(|k| &k.1).min(),
+        days.keys().map(|k| &k.1).max(),
+    ) else {
+        bail!("the archive holds no batches");
+    };
+
+    let summaries: Vec<DailySummary> = client
+        .get(format!("{}/summaries", server_url))
+        .query(&[("since_day", first), ("until_day", last)])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let summaries: BTreeMap<(&str, &str), &DailySummary> = summaries
+        .iter()
+        .map(|s| ((s.agent_id.as_str(), s.day.as_str()), s))
+        .collect();
+
+    let days = days
+        .iter()
+        .map(|((agent_id, day), entries)| {
+            let mut check = DayCheck {
+                agent_id: agent_id.clone(),
+                day: day.clone(),
+                status: "unsummarized",
+                differs: Vec::new(),
+            };
+            let Some(expected) = summaries.get(&(agent_id.as_str(), day.as_str())) else {
+                return check;
+            };
+            let found = DailySummary::of(agent_id, day, entries).expect("a day has entries");
+            for (field, same) in [
+                ("batches", found.batches == expected.batches),
+                ("lines", found.lines == expected.lines),
+                ("min_seq", found.min_seq == expected.min_seq),
+                ("max_seq", found.max_seq == expected.max_seq),
+                ("head_hash", found.head_hash == expected.head_hash),
+                ("merkle_root", found.merkle_root == expected.merkle_root),
+            ] {
+                if !same {
+                    check.differs.push(field);
+                }
+            }
+            check.status = if check.differs.is_empty() {
+                "match"
+            } else {
+                "differs"
+            };
+            check
+        })
+        .collect();
+    Ok(Report { altered, days })
+}
+
+/// Checks the archive at `path` and prints the result. Returns whether it
+/// holds no altered row and no day that differs from its summary.
+pub async fn run(server_url: &str, path: &Path, json: bool) -> anyhow::Result<bool> {
+    let archive = load_archive(path)?;
+    let report = check(&http_client(), server_url, archive).await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(report.ok());
+    }
+    let count = |status: &str| report.days.iter().filter(|d| d.status == status).count();
+    println!(
+        "Checked {} agent-days from {}: {} match, {} differ, {} not summarized yet",
+        report.days.len(),
+        path.display(),
+        count("match"),
+        count("differs"),
+        count("unsummarized")
+    );
+    for row in &report.altered {
+        println!("  ✗ {row}: content does not hash to its stored hash");
+    }
+    for day in report.days.iter().filter(|d| d.status == "differs") {
+        println!(
+            "  ✗ {} {}: {} differ from the summary",
+            day.agent_id,
+            day.day,
+            day.differs.join(", ")
+        );
+    }
+    Ok(report.ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::summary::DAY_MS;
+    use common::testutil::build_chain;
+    use ed25519_dalek::SigningKey;
+    use std::fs;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn archived_days_are_checked_against_their_summaries() {
+        let key = SigningKey::from_bytes(&[5; 32]);
+        let chain = build_chain(&key, "agent-s", 5);
+        // Seqs 1-2 arrived on day one, 3-4 on day two, 5 on day three.
+        let received = [10, 20, DAY_MS + 1, DAY_MS + 2, 2 * DAY_MS];
+        let entries: Vec<SummaryEntry> = chain
+            .iter()
+            .map(|batch| SummaryEntry {
+                epoch: batch.epoch,
+                seq: batch.seq,
+                hash: batch.compute_hash(),
+                lines: batch.logs.len() as u64,
+            })
+            .collect();
+        let day_one = DailySummary::of("agent-s", "1970-01-01", &entries[..2]).unwrap();
+        // The server summarized day two with seq 4 only, as if the archive
+        // had gained a batch since.
+        let day_two = DailySummary::of("agent-s", "1970-01-02", &entries[3..4]).unwrap();
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/summaries"))
+            .respond_with(ResponseTemplate::new(200).set_body_json([&day_one, &day_two]))
+            .mount(&server)
+            .await;
+
+        let mut archive = String::new();
+        for (batch, at) in chain.iter().zip(received) {
+            let row = serde_json::json!({
+                "id": batch.seq,
+                "batch": batch,
+                "hash": batch.compute_hash(),
+                "received_at": at,
+            });
+            archive.push_str(&format!("{row}\n"));
+        }
+        let file =
+            std::env::temp_dir().join(format!("cli-summaries-{}.ndjson", std::process::id()));
+        fs::write(&file, &archive).unwrap();
+        let rows = load_archive(&file).unwrap();
+        let report = check(&http_client(), &server.uri(), rows).await.unwrap();
+        let statuses: Vec<(&str, &str)> = report
+            .days
+            .iter()
+            .map(|d| (d.day.as_str(), d.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("1970-01-01", "match"),
+                ("1970-01-02", "differs"),
+                ("1970-01-03", "unsummarized"),
+            ]
+        );
+        assert_eq!(
+            report.days[1].differs,
+            ["batches", "lines", "min_seq", "merkle_root"]
+        );
+        assert!(report.altered.is_empty());
+        assert!(!report.ok());
+
+        // An edited row is caught even where its day is not summarized.
+        let mut rows = load_archive(&file).unwrap();
+        rows.truncate(2);
+        rows[1].batch.logs.push("inserted".into());
+        let report = check(&http_client(), &server.uri(), rows).await.unwrap();
+        assert_eq!(report.altered, ["agent-s seq 2"]);
+        assert_eq!(
+            report.days[0].differs,
+            ["lines", "head_hash", "merkle_root"]
+        );
+        let asked = server.received_requests().await.unwrap();
+        assert_eq!(
+            asked[0].url.query(),
+            Some("since_day=1970-01-01&until_day=1970-01-03")
+        );
+        let _ = fs::remove_file(&file);
+    }
+}
@@ -0,0 +1,375 @@
+//! `tsa verify`: checks a batch's RFC 3161 timestamp (`GET
+//! /batches/:id/tsa`) without taking the server's word for any of it. The
+//! batch content must hash to the stamped hash, the audit path must lead
+//! from that hash to the stamped Merkle root, the token's signature must
+//! chain to a certificate of the CA bundle through a timeStamping
+//! certificate, and the token must imprint that root.
+
+use crate::http_client;
+use anyhow::{Context, anyhow};
+use clap::Subcommand;
+use common::batch::LogBatch;
+use common::hex::hex_decode;
+use common::tsa::{TsaStamp, TstInfo};
+use openssl::cms::{CMSOptions, CmsContentInfo};
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509, X509PurposeId};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+#[derive(Subcommand)]
+pub enum TsaCommand {
+    /// Check a batch's trusted timestamp. Exits 1 unless it holds.
+    Verify {
+        /// Row id of the batch.
+        id: i64,
+        /// PEM certificates the TSA's must chain to.
+        #[arg(long)]
+        ca_bundle: std::path::PathBuf,
+        /// Print a JSON report instead of text.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// The `/batches/:id` fields the check needs.
+#[derive(Deserialize)]
+struct StoredBatch {
+    batch: LogBatch,
+    #[serde(default)]
+    redacted: Vec<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    batch_id: i64,
+    /// The TSA's time, from the verified token.
+    gen_time: Option<String>,
+    tsa_url: String,
+    /// Batches the token covers.
+    leaf_count: usize,
+    /// Every check that failed; empty when the timestamp holds.
+    problems: Vec<String>,
+}
+
+/// Verifies `token`'s signature against `ca_bundle` (PEM) and reads the
+/// `TSTInfo` it signs.
+fn verify_token(token: &[u8], ca_bundle: &[u8]) -> Result<TstInfo, String> {
+    let certs = X509::stack_from_pem(ca_bundle).map_err(|err| format!("bad CA bundle: {err}"))?;
+    if certs.is_empty() {
+        return Err("the CA bundle holds no certificate".into());
+    }
+    let store = (|| {
+        let mut store = X509StoreBuilder::new()?;
+        for cert in certs {
+            store.add_cert(cert)?;
+        }
+        store.set_purpose(X509PurposeId::TIMESTAMP_SIGN)?;
+        Ok::<_, openssl::error::ErrorStack>(store.build())
+    })()
+    .map_err(|err| format!("bad CA bundle: {err}"))?;
+    let mut cms =
+        CmsContentInfo::from_der(token).map_err(|err| format!("token is not CMS: {err}"))?;
+    cms.verify(None, Some(&store), None, None, CMSOptions::BINARY)
+        .map_err(|err| format!("token signature does not verify against the CA bundle: {err}"))?;
+    TstInfo::from_token(token)
+}
+
+async fn check(
+    client: &Client,
+    server_url: &str,
+    id: i64,
+    ca_bundle: &[u8],
+) -> anyhow::Result<Report> {
+    let resp = client
+        .get(format!("{server_url}/batches/{id}/tsa"))
+        .send()
+        .await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Err(anyhow!(
+            "batch {id} has no timestamp: unknown, not stamped yet, or TSA_URL is unset"
+        ));
+    }
+    let stamp: TsaStamp = resp.error_for_status()?.json().await?;
+    let stored: StoredBatch = client
+        .get(format!("{server_url}/batches/{id}"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let mut report = Report {
+        batch_id: id,
+        gen_time: None,
+        tsa_url: stamp.tsa_url.clone(),
+        leaf_count: stamp.leaf_count,
+        problems: Vec::new(),
+    };
+    let hash = stored
+        .batch
+        .compute_hash_redacted(&stored.redacted)
+        .unwrap_or_else(|_| stored.batch.compute_hash());
+    let imprint = match stamp.check_inclusion(&hash) {
+        Ok(imprint) => Some(imprint),
+        Err(err) => {
+            report.problems.push(format!("batch content: {err}"));
+            None
+        }
+    };
+    let token = hex_decode(&stamp.token).map_err(|err| anyhow!("token is not hex: {err}"))?;
+    match verify_token(&token, ca_bundle) {
+        Ok(info) => {
+            if imprint.is_some_and(|imprint| imprint != info.imprint) {
+                report
+                    .problems
+                    .push("the token imprints another root".into());
+            }
+            report.gen_time = Some(info.gen_time);
+        }
+        Err(err) => report.problems.push(err),
+    }
+    Ok(report)
+}
+
+/// Checks batch `id`'s timestamp and prints the result. Returns whether it
+/// holds.
+pub async fn run(server_url: &str, command: &TsaCommand) -> anyhow::Result<bool> {
+    let TsaCommand::Verify {
+        id,
+        ca_bundle,
+        json,
+    } = command;
+    let bundle = read_bundle(ca_bundle)?;
+    let report = check(&http_client(), server_url, *id, &bundle).await?;
+
+    if *json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(report.problems.is_empty());
+    }
+    if report.problems.is_empty() {
+        println!(
+            "  ✓ batch {} existed at {} per {} (token covers {} batches)",
+            report.batch_id,
+            report.gen_time.as_deref().unwrap_or("?"),
+            report.tsa_url,
+            report.leaf_count
+        );
+    }
+    for problem in &report.problems {
+        println!("  ✗ batch {}: {problem}", report.batch_id);
+    }
+    Ok(report.problems.is_empty())
+}
+
+fn read_bundle(path: &Path) -> anyhow::Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("cannot read {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::summary::{merkle_path, merkle_root};
+    use common::testutil::build_chain;
+    use ed25519_dalek::SigningKey;
+    use foreign_types::ForeignType;
+    use openssl::asn1::{Asn1Object, Asn1Time};
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::X509Builder;
+    use openssl::x509::extension::{BasicConstraints, ExtendedKeyUsage, KeyUsage};
+    use std::os::raw::{c_int, c_uint};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    // Not bound by openssl-sys; a mock TSA needs them to sign a TSTInfo.
+    unsafe extern "C" {
+        fn CMS_set1_eContentType(
+            cms: *mut openssl_sys::CMS_ContentInfo,
+            oid: *const openssl_sys::ASN1_OBJECT,
+        ) -> c_int;
+        fn CMS_final(
+            cms: *mut openssl_sys::CMS_ContentInfo,
+            data: *mut openssl_sys::BIO,
+            dcont: *mut openssl_sys::BIO,
+            flags: c_uint,
+        ) -> c_int;
+    }
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn cert(name: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>) -> X509 {
+        let mut subject = openssl::x509::X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(name.len() as u32)
+            .unwrap()
+            .to_asn1_integer()
+            .unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let (issuer_name, signer) = match issuer {
+            Some((ca, ca_key)) => {
+                builder
+                    .append_extension(
+                        ExtendedKeyUsage::new()
+                            .critical()
+                            .time_stamping()
+                            .build()
+                            .unwrap(),
+                    )
+                    .unwrap();
+                builder
+                    .append_extension(
+                        KeyUsage::new()
+                            .critical()
+                            .digital_signature()
+                            .build()
+                            .unwrap(),
+                    )
+                    .unwrap();
+                (ca.subject_name().to_owned().unwrap(), ca_key)
+            }
+            None => {
+                builder
+                    .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                    .unwrap();
+                builder
+                    .append_extension(KeyUsage::new().critical().key_cert_sign().build().unwrap())
+                    .unwrap();
+                (subject, key)
+            }
+        };
+        builder.set_issuer_name(&issuer_name).unwrap();
+        builder.sign(signer, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    /// A token over `tst_info` signed as a TSA signs, by `tsa`.
+    fn sign_token(tst_info: &[u8], tsa: &X509, tsa_key: &PKey<Private>) -> Vec<u8> {
+        let flags = CMSOptions::BINARY | CMSOptions::PARTIAL;
+        let cms = CmsContentInfo::sign(Some(tsa), Some(tsa_key), None, None, flags).unwrap();
+        let oid = Asn1Object::from_str("1.2.840.113549.1.9.16.1.4").unwrap();
+        unsafe {
+            assert_eq!(CMS_set1_eContentType(cms.as_ptr(), oid.as_ptr()), 1);
+            let data =
+                openssl_sys::BIO_new_mem_buf(tst_info.as_ptr().cast(), tst_info.len() as c_int);
+            let done = CMS_final(cms.as_ptr(), data, std::ptr::null_mut(), flags.bits());
+            openssl_sys::BIO_free_all(data);
+            assert_eq!(done, 1);
+        }
+        cms.to_der().unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_timestamp_holds_only_with_its_content_path_and_ca() {
+        let key_ed = SigningKey::from_bytes(&[21; 32]);
+        let chain = build_chain(&key_ed, "agent-t", 3);
+        let hashes: Vec<[u8; 32]> = chain.iter().map(LogBatch::compute_hash).collect();
+        let root = merkle_root(&hashes);
+
+        let (ca_key, tsa_key) = (key(), key());
+        let ca = cert("Test TSA Root", &ca_key, None);
+        let tsa = cert("Test TSA", &tsa_key, Some((&ca, &ca_key)));
+        let tst_info =
+            common::tsa::tst_info_der(&common::tsa::imprint(&root), 7, "20261017120000Z");
+        let token = sign_token(&tst_info, &tsa, &tsa_key);
+        let bundle = ca.to_pem().unwrap();
+
+        let stamp = TsaStamp {
+            batch_id: 2,
+            hash: hashes[1],
+            token_id: 1,
+            leaf_index: 1,
+            leaf_count: 3,
+            path: merkle_path(&hashes, 1),
+            root,
+            gen_time: "2026-10-17T12:00:00Z".into(),
+            tsa_url: "http://tsa.test/".into(),
+            token: common::hex::hex_encode(&token),
+        };
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/batches/2/tsa"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&stamp))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/batches/2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "batch": &chain[1], "hash": hashes[1] })),
+            )
+            // The check against the right CA, then the one against another.
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+
+        let report = check(&http_client(), &server.uri(), 2, &bundle)
+            .await
+            .unwrap();
+        assert_eq!(report.problems, Vec::<String>::new());
+        assert_eq!(report.gen_time.as_deref(), Some("2026-10-17T12:00:00Z"));
+
+        // Another CA does not vouch for the TSA.
+        let other = cert("Other Root", &key(), None).to_pem().unwrap();
+        let report = check(&http_client(), &server.uri(), 2, &other)
+            .await
+            .unwrap();
+        assert!(
+            report.problems[0].contains("does not verify"),
+            "{:?}",
+            report.problems
+        );
+
+        // Content edited after stamping no longer reaches the root.
+        let mut edited = chain[1].clone();
+        edited.logs.push("inserted".into());
+        Mock::given(method("GET"))
+            .and(path("/batches/2"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "batch": edited })),
+            )
+            .mount(&server)
+            .await;
+        let report = check(&http_client(), &server.uri(), 2, &bundle)
+            .await
+            .unwrap();
+        assert_eq!(report.problems.len(), 1);
+        assert!(
+            report.problems[0].starts_with("batch content"),
+            "{:?}",
+            report.problems
+        );
+
+        // A self-signed token, or one imprinting something else, fails.
+        let rogue_key = key();
+        let rogue = sign_token(&tst_info, &cert("Rogue", &rogue_key, None), &rogue_key);
+        assert!(verify_token(&rogue, &bundle).is_err());
+        let other_root = common::tsa::tst_info_der(&[0; 32], 7, "20261017120000Z");
+        let info = verify_token(&sign_token(&other_root, &tsa, &tsa_key), &bundle).unwrap();
+        assert_ne!(info.imprint, common::tsa::imprint(&root));
+
+        let missing = check(&http_client(), &server.uri(), 9, &bundle)
+            .await
+            .unwrap_err();
+        assert!(missing.to_string().contains("no timestamp"), "{missing}");
+    }
+}
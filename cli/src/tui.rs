@@ -0,0 +1,1134 @@
+//! `tui`: an interactive triage view of one server, refreshed every
+//! `--refresh-ms`.
+//!
+//! - Agents, from `/agents/status`: the silent ones `/agents/stale` lists in
+//!   red, those whose clock drifts past the server's alert in yellow.
+//! - A live tail of the last `--tail` batches to arrive, from
+//!   `/batches/latest`, one row per log line, with incremental search.
+//! - An inspector with the chain fields of the selected line's batch and
+//!   what about it verifies.
+//! - A verification panel: how many tail batches check out, and `/readyz`.
+//!
+//! The server has no push stream and keeps no verification status of its
+//! own, so the tail polls and every batch in it is checked here: its
+//! signature, its stored hash, and its link to the batch the agent sent
+//! before it, when that one is in the tail too. A pane whose endpoint an
+//! older server lacks (404) says so; the others work without it.
+//!
+//! Keys: `tab` switches pane, `j`/`k` or the arrows move, `g`/`G` jump to
+//! either end, `/` searches the tail as you type, `n`/`N` go to the next or
+//! previous match, `enter` inspects the selected line's batch (in the tail)
+//! or shows only the selected agent's lines (in the agents list), `esc`
+//! backs out, and `q` quits.
+
+use crate::{http_client, position_label};
+use common::batch::LogBatch;
+use common::hex::hex_encode;
+use ratatui::Frame;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_TAIL: usize = 200;
+pub const DEFAULT_REFRESH_MS: u64 = 2000;
+/// Silence after which an agent counts as stale when the server cannot say
+/// (no `/agents/stale`); the server's own default.
+const FALLBACK_STALE_SECS: u64 = 300;
+
+/// The `/agents/status` fields shown.
+#[derive(Debug, Clone, Deserialize)]
+struct AgentStatus {
+    agent_id: String,
+    last_seq: u64,
+    last_received_at_ms: u64,
+    clock_drift_ms: i64,
+    drift_exceeded: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct StaleAgent {
+    agent_id: String,
+}
+
+/// A `/batches/latest?logs=true` row.
+#[derive(Debug, Clone, Deserialize)]
+struct TailBatch {
+    id: i64,
+    batch: LogBatch,
+    hash: [u8; 32],
+    received_at: u64,
+    #[serde(default)]
+    redacted: Vec<usize>,
+}
+
+/// `/readyz`, which answers 503 with the same body when not ready.
+#[derive(Debug, Clone, Deserialize)]
+struct Readiness {
+    ready: bool,
+    #[serde(default)]
+    storage_faults: Vec<serde_json::Value>,
+    #[serde(default)]
+    rollback_suspected: Vec<serde_json::Value>,
+    #[serde(default)]
+    maintenance: bool,
+}
+
+/// What one endpoint gave on the last refresh.
+#[derive(Debug, Clone)]
+enum Feed<T> {
+    Loading,
+    Loaded(T),
+    /// 404: the server predates the endpoint.
+    Missing,
+    Failed(String),
+}
+
+impl<T> Feed<T> {
+    fn loaded(&self) -> Option<&T> {
+        match self {
+            Feed::Loaded(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The line a pane shows instead of its content.
+    fn placeholder(&self, endpoint: &str) -> Option<String> {
+        match self {
+            Feed::Loaded(_) => None,
+            Feed::Loading => Some("loading…".into()),
+            Feed::Missing => Some(format!("{endpoint} is not available on this server")),
+            Feed::Failed(err) => Some(format!("{endpoint}: {err}")),
+        }
+    }
+}
+
+/// One refresh of every pane.
+#[derive(Debug, Clone)]
+struct Snapshot {
+    agents: Feed<Vec<AgentStatus>>,
+    stale: Feed<Vec<StaleAgent>>,
+    tail: Feed<Vec<TailBatch>>,
+    readiness: Feed<Readiness>,
+    /// Server-independent clock for the "silent for" column.
+    now_ms: u64,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self {
+            agents: Feed::Loading,
+            stale: Feed::Loading,
+            tail: Feed::Loading,
+            readiness: Feed::Loading,
+            now_ms: 0,
+        }
+    }
+}
+
+async fn get<T: DeserializeOwned>(client: &Client, url: String) -> Feed<T> {
+    let resp = match client
+        .get(url)
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+    {
+        Ok(resp) => resp,
+        Err(err) => return Feed::Failed(err.to_string()),
+    };
+    match resp.status() {
+        StatusCode::NOT_FOUND => Feed::Missing,
+        status if !status.is_success() && status != StatusCode::SERVICE_UNAVAILABLE => {
+            Feed::Failed(status.to_string())
+        }
+        _ => resp
+            .json()
+            .await
+            .map_or_else(|err| Feed::Failed(err.to_string()), Feed::Loaded),
+    }
+}
+
+async fn fetch(client: &Client, server_url: &str, tail: usize) -> Snapshot {
+    let (agents, stale, tail, readiness) = tokio::join!(
+        get(client, format!("{server_url}/agents/status")),
+        get(client, format!("{server_url}/agents/stale")),
+        get(
+            client,
+            format!("{server_url}/batches/latest?limit={tail}&logs=true")
+        ),
+        get(client, format!("{server_url}/readyz")),
+    );
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    Snapshot {
+        agents,
+        stale,
+        tail,
+        readiness,
+        now_ms,
+    }
+}
+
+/// What checks out about one tail batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Verdict {
+    signed: bool,
+    hash_matches: bool,
+    /// Whether `prev_hash` is the hash of the agent's batch before it;
+    /// `None` when that batch is older than the tail.
+    linked: Option<bool>,
+}
+
+impl Verdict {
+    fn ok(&self) -> bool {
+        self.signed && self.hash_matches && self.linked != Some(false)
+    }
+}
+
+/// Checks every tail batch. An agent's batches arrive in chain order, so
+/// its rows in the tail are the end of its chain, in id order.
+fn verdicts(tail: &[TailBatch]) -> HashMap<i64, Verdict> {
+    let mut by_agent: HashMap<&str, Vec<&TailBatch>> = HashMap::new();
+    for entry in tail {
+        by_agent
+            .entry(&entry.batch.agent_id)
+            .or_default()
+            .push(entry);
+    }
+    let mut verdicts = HashMap::new();
+    for rows in by_agent.values_mut() {
+        rows.sort_by_key(|entry| entry.id);
+        for (index, entry) in rows.iter().enumerate() {
+            let batch = &entry.batch;
+            let linked = match index.checked_sub(1) {
+                Some(before) => Some(batch.prev_hash == rows[before].hash),
+                None if batch.position() == (0, 1) => Some(batch.prev_hash == [0u8; 32]),
+                None => None,
+            };
+            verdicts.insert(
+                entry.id,
+                Verdict {
+                    signed: batch.verify_redacted(&entry.redacted),
+                    hash_matches: batch.compute_hash_redacted(&entry.redacted) == Ok(entry.hash),
+                    linked,
+                },
+            );
+        }
+    }
+    verdicts
+}
+
+/// A row of the tail: one log line, or a batch without lines (a gap
+/// marker, session start or chain close).
+#[derive(Debug, Clone, PartialEq)]
+struct TailLine {
+    batch_id: i64,
+    /// Line index in its batch; `None` for a batch without lines.
+    line_idx: Option<usize>,
+    agent_id: String,
+    position: (u64, u64),
+    text: String,
+}
+
+impl TailLine {
+    fn matches(&self, query: &str) -> bool {
+        !query.is_empty()
+            && (self.text.to_lowercase().contains(&query.to_lowercase())
+                || self.agent_id.to_lowercase().contains(&query.to_lowercase()))
+    }
+}
+
+/// A key, as the app handles it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Esc,
+    Tab,
+    Up,
+    Down,
+    Backspace,
+    Quit,
+    /// The terminal was resized; only redraws.
+    Redraw,
+}
+
+impl Key {
+    fn from_event(key: KeyEvent) -> Option<Self> {
+        if key.kind != KeyEventKind::Press {
+            return None;
+        }
+        Some(match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => Key::Quit,
+            KeyCode::Char(c) => Key::Char(c),
+            KeyCode::Enter => Key::Enter,
+            KeyCode::Esc => Key::Esc,
+            KeyCode::Tab | KeyCode::BackTab => Key::Tab,
+            KeyCode::Up => Key::Up,
+            KeyCode::Down => Key::Down,
+            KeyCode::Backspace => Key::Backspace,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    Agents,
+    Tail,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Browse,
+    /// Typing a search; every key moves to the first match.
+    Search,
+    /// The inspector is open on this batch.
+    Inspect(i64),
+}
+
+struct App {
+    snapshot: Snapshot,
+    verdicts: HashMap<i64, Verdict>,
+    /// Oldest first, as a tail reads.
+    lines: Vec<TailLine>,
+    focus: Focus,
+    mode: Mode,
+    agent_selected: usize,
+    line_selected: usize,
+    /// Keep the newest line selected as new ones arrive.
+    follow: bool,
+    /// Only this agent's lines, chosen in the agents list.
+    agent_filter: Option<String>,
+    search: String,
+}
+
+impl App {
+    fn new() -> Self {
+        Self {
+            snapshot: Snapshot::default(),
+            verdicts: HashMap::new(),
+            lines: Vec::new(),
+            focus: Focus::Tail,
+            mode: Mode::Browse,
+            agent_selected: 0,
+            line_selected: 0,
+            follow: true,
+            agent_filter: None,
+            search: String::new(),
+        }
+    }
+
+    fn apply(&mut self, snapshot: Snapshot) {
+        self.verdicts = snapshot
+            .tail
+            .loaded()
+            .map(|tail| verdicts(tail))
+            .unwrap_or_default();
+        self.snapshot = snapshot;
+        self.rebuild_lines();
+    }
+
+    /// Refills the tail rows, keeping the selected line selected unless the
+    /// view follows the newest.
+    fn rebuild_lines(&mut self) {
+        let selected = self
+            .lines
+            .get(self.line_selected)
+            .map(|line| (line.batch_id, line.line_idx));
+        let mut batches: Vec<&TailBatch> = self.snapshot.tail.loaded().map_or(Vec::new(), |tail| {
+            tail.iter()
+                .filter(|entry| {
+                    self.agent_filter
+                        .as_ref()
+                        .is_none_or(|agent| &entry.batch.agent_id == agent)
+                })
+                .collect()
+        });
+        batches.sort_by_key(|entry| (entry.received_at, entry.id));
+        self.lines = batches
+            .into_iter()
+            .flat_map(|entry| {
+                let batch = &entry.batch;
+                let line = |line_idx, text| TailLine {
+                    batch_id: entry.id,
+                    line_idx,
+                    agent_id: batch.agent_id.clone(),
+                    position: batch.position(),
+                    text,
+                };
+                if batch.logs.is_empty() {
+                    vec![line(None, empty_batch_label(batch))]
+                } else {
+                    batch
+                        .logs
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, text)| line(Some(idx), text.clone()))
+                        .collect()
+                }
+            })
+            .collect();
+        let last = self.lines.len().saturating_sub(1);
+        self.line_selected = match selected {
+            Some(selected) if !self.follow => self
+                .lines
+                .iter()
+                .position(|line| (line.batch_id, line.line_idx) == selected)
+                .unwrap_or(last),
+            _ => last,
+        };
+        self.agent_selected = self
+            .agent_selected
+            .min(self.agents().len().saturating_sub(1));
+    }
+
+    fn agents(&self) -> &[AgentStatus] {
+        self.snapshot.agents.loaded().map_or(&[], Vec::as_slice)
+    }
+
+    fn is_stale(&self, agent: &AgentStatus) -> bool {
+        match self.snapshot.stale.loaded() {
+            Some(stale) => stale.iter().any(|stale| stale.agent_id == agent.agent_id),
+            None => {
+                self.snapshot
+                    .now_ms
+                    .saturating_sub(agent.last_received_at_ms)
+                    > FALLBACK_STALE_SECS * 1000
+            }
+        }
+    }
+
+    fn selected_line(&self) -> Option<&TailLine> {
+        self.lines.get(self.line_selected)
+    }
+
+    fn batch(&self, id: i64) -> Option<&TailBatch> {
+        self.snapshot
+            .tail
+            .loaded()?
+            .iter()
+            .find(|entry| entry.id == id)
+    }
+
+    fn move_by(&mut self, step: isize) {
+        match self.focus {
+            Focus::Agents => {
+                let last = self.agents().len().saturating_sub(1);
+                self.agent_selected = self.agent_selected.saturating_add_signed(step).min(last);
+            }
+            Focus::Tail => {
+                let last = self.lines.len().saturating_sub(1);
+                self.line_selected = self.line_selected.saturating_add_signed(step).min(last);
+                self.follow = self.line_selected == last;
+            }
+        }
+    }
+
+    fn jump(&mut self, to_end: bool) {
+        self.move_by(if to_end { isize::MAX } else { isize::MIN });
+    }
+
+    /// Selects the nearest matching line from `from` onwards (backwards
+    /// when `forward` is false), wrapping around.
+    fn find(&mut self, from: usize, forward: bool) {
+        let count = self.lines.len();
+        let found = (0..count)
+            .map(|offset| match forward {
+                true => (from + offset) % count,
+                false => (from + count - offset % count) % count,
+            })
+            .find(|&index| self.lines[index].matches(&self.search));
+        if let Some(index) = found {
+            self.focus = Focus::Tail;
+            self.line_selected = index;
+            self.follow = index + 1 == count;
+        }
+    }
+
+    /// Handles one key; true to quit.
+    fn handle(&mut self, key: Key) -> bool {
+        match (self.mode, key) {
+            (_, Key::Quit) => return true,
+            (_, Key::Redraw) => {}
+            (Mode::Search, Key::Char(c)) => {
+                self.search.push(c);
+                self.find(self.line_selected, true);
+            }
+            (Mode::Search, Key::Backspace) => {
+                self.search.pop();
+                self.find(self.line_selected, true);
+            }
+            (Mode::Search, Key::Enter) => self.mode = Mode::Browse,
+            (Mode::Search, Key::Esc) => {
+                self.search.clear();
+                self.mode = Mode::Browse;
+            }
+            (Mode::Search, _) => {}
+            (Mode::Inspect(_), Key::Esc | Key::Enter | Key::Char('q')) => self.mode = Mode::Browse,
+            (Mode::Inspect(_), _) => {}
+            (Mode::Browse, Key::Char('q')) => return true,
+            (Mode::Browse, Key::Tab) => {
+                self.focus = match self.focus {
+                    Focus::Agents => Focus::Tail,
+                    Focus::Tail => Focus::Agents,
+                }
+            }
+            (Mode::Browse, Key::Char('j') | Key::Down) => self.move_by(1),
+            (Mode::Browse, Key::Char('k') | Key::Up) => self.move_by(-1),
+            (Mode::Browse, Key::Char('g')) => self.jump(false),
+            (Mode::Browse, Key::Char('G')) => self.jump(true),
+            (Mode::Browse, Key::Char('/')) => {
+                self.search.clear();
+                self.mode = Mode::Search;
+            }
+            (Mode::Browse, Key::Char('n')) => self.find(self.line_selected + 1, true),
+            (Mode::Browse, Key::Char('N')) => self.find(
+                self.line_selected
+                    .checked_sub(1)
+                    .unwrap_or(self.lines.len().saturating_sub(1)),
+                false,
+            ),
+            (Mode::Browse, Key::Enter) => match self.focus {
+                Focus::Tail => {
+                    if let Some(line) = self.selected_line() {
+                        self.mode = Mode::Inspect(line.batch_id);
+                    }
+                }
+                Focus::Agents => {
+                    let chosen = self
+                        .agents()
+                        .get(self.agent_selected)
+                        .map(|agent| agent.agent_id.clone());
+                    self.agent_filter = if self.agent_filter == chosen {
+                        None
+                    } else {
+                        chosen
+                    };
+                    self.follow = true;
+                    self.rebuild_lines();
+                }
+            },
+            (Mode::Browse, Key::Esc) => {
+                if !self.search.is_empty() {
+                    self.search.clear();
+                } else if self.agent_filter.take().is_some() {
+                    self.rebuild_lines();
+                }
+            }
+            (Mode::Browse, _) => {}
+        }
+        false
+    }
+}
+
+fn empty_batch_label(batch: &LogBatch) -> String {
+    if let Some(gap) = &batch.gap {
+        return format!(
+            "[gap marker: seqs {}-{} lost: {}]",
+            gap.missing_from, gap.missing_to, gap.reason
+        );
+    }
+    if let Some(reason) = batch.closed_reason() {
+        return format!("[chain closed: {reason}]");
+    }
+    if batch.kind.is_some() {
+        return "[session start]".into();
+    }
+    "[no lines]".into()
+}
+
+fn pane(title: String, focused: bool) -> Block<'static> {
+    let style = if focused {
+        Style::default().fg(Color::Cyan)
+    } else {
+        Style::default()
+    };
+    Block::default()
+        .borders(Borders::ALL)
+        .border_style(style)
+        .title(title)
+}
+
+fn render(frame: &mut Frame, app: &App) {
+    let [main, status] =
+        Layout::vertical([Constraint::Min(3), Constraint::Length(1)]).areas(frame.area());
+    let [agents, right] =
+        Layout::horizontal([Constraint::Percentage(30), Constraint::Percentage(70)]).areas(main);
+    let [tail, checks] = Layout::vertical([Constraint::Min(3), Constraint::Length(6)]).areas(right);
+    render_agents(frame, app, agents);
+    render_tail(frame, app, tail);
+    render_checks(frame, app, checks);
+    render_status(frame, app, status);
+    if let Mode::Inspect(id) = app.mode {
+        render_inspector(frame, app, id);
+    }
+}
+
+fn render_agents(frame: &mut Frame, app: &App, area: Rect) {
+    let stale_source = if app.snapshot.stale.loaded().is_some() {
+        String::new()
+    } else {
+        format!(", stale > {FALLBACK_STALE_SECS}s")
+    };
+    let block = pane(
+        format!(" Agents{stale_source} "),
+        app.focus == Focus::Agents,
+    );
+    if let Some(text) = app.snapshot.agents.placeholder("/agents/status") {
+        frame.render_widget(
+            Paragraph::new(text).wrap(Wrap { trim: true }).block(block),
+            area,
+        );
+        return;
+    }
+    let items: Vec<ListItem> = app
+        .agents()
+        .iter()
+        .map(|agent| {
+            let silent_secs = app
+                .snapshot
+                .now_ms
+                .saturating_sub(agent.last_received_at_ms)
+                / 1000;
+            let style = if app.is_stale(agent) {
+                Style::default().fg(Color::Red)
+            } else if agent.drift_exceeded {
+                Style::default().fg(Color::Yellow)
+            } else {
+                Style::default()
+            };
+            let filtered = if app.agent_filter.as_deref() == Some(&agent.agent_id) {
+                "▸ "
+            } else {
+                ""
+            };
+            ListItem::new(Line::from(vec![
+                Span::styled(
+                    format!("{filtered}{}", agent.agent_id),
+                    style.add_modifier(Modifier::BOLD),
+                ),
+                Span::styled(
+                    format!(
+                        "  seq {}  {silent_secs}s ago  drift {:+}ms",
+                        agent.last_seq, agent.clock_drift_ms
+                    ),
+                    style,
+                ),
+            ]))
+        })
+        .collect();
+    let mut state = ListState::default().with_selected(Some(app.agent_selected));
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
+fn render_tail(frame: &mut Frame, app: &App, area: Rect) {
+    let scope = app
+        .agent_filter
+        .as_ref()
+        .map_or(String::new(), |agent| format!(" of {agent}"));
+    let follow = if app.follow { ", following" } else { "" };
+    let block = pane(
+        format!(" Tail{scope} ({} lines{follow}) ", app.lines.len()),
+        app.focus == Focus::Tail,
+    );
+    if let Some(text) = app.snapshot.tail.placeholder("/batches/latest") {
+        frame.render_widget(
+            Paragraph::new(text).wrap(Wrap { trim: true }).block(block),
+            area,
+        );
+        return;
+    }
+    let items: Vec<ListItem> = app
+        .lines
+        .iter()
+        .map(|line| {
+            let failed = app
+                .verdicts
+                .get(&line.batch_id)
+                .is_some_and(|verdict| !verdict.ok());
+            let mark = if failed {
+                Span::styled("✗ ", Style::default().fg(Color::Red))
+            } else {
+                Span::raw("  ")
+            };
+            let text_style = if line.matches(&app.search) {
+                Style::default().fg(Color::Black).bg(Color::Yellow)
+            } else {
+                Style::default()
+            };
+            let index = line.line_idx.map_or(String::new(), |idx| format!("#{idx}"));
+            ListItem::new(Line::from(vec![
+                mark,
+                Span::styled(
+                    format!(
+                        "{} {}{index} ",
+                        line.agent_id,
+                        position_label(line.position)
+                    ),
+                    Style::default().fg(Color::DarkGray),
+                ),
+                Span::styled(line.text.clone(), text_style),
+            ]))
+        })
+        .collect();
+    let mut state = ListState::default().with_selected(Some(app.line_selected));
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
+fn render_checks(frame: &mut Frame, app: &App, area: Rect) {
+    let mut lines = Vec::new();
+    let failed = app
+        .verdicts
+        .values()
+        .filter(|verdict| !verdict.ok())
+        .count();
+    if app.snapshot.tail.loaded().is_some() {
+        let count = |test: fn(&Verdict) -> bool| {
+            app.verdicts
+                .values()
+                .filter(|verdict| test(verdict))
+                .count()
+        };
+        let style = if failed > 0 {
+            Style::default().fg(Color::Red)
+        } else {
+            Style::default().fg(Color::Green)
+        };
+        lines.push(Line::styled(
+            format!(
+                "tail: {} of {} batches verify",
+                app.verdicts.len() - failed,
+                app.verdicts.len()
+            ),
+            style,
+        ));
+        lines.push(Line::raw(format!(
+            "bad signature {}, hash mismatch {}, broken link {}; links checked {}",
+            count(|verdict| !verdict.signed),
+            count(|verdict| !verdict.hash_matches),
+            count(|verdict| verdict.linked == Some(false)),
+            count(|verdict| verdict.linked.is_some()),
+        )));
+    }
+    match (
+        &app.snapshot.readiness,
+        app.snapshot.readiness.placeholder("/readyz"),
+    ) {
+        (Feed::Loaded(readiness), _) => {
+            let (text, color) = if readiness.ready {
+                ("ready", Color::Green)
+            } else {
+                ("NOT READY", Color::Red)
+            };
+            let mut spans = vec![
+                Span::raw("server: "),
+                Span::styled(text, Style::default().fg(color)),
+            ];
+            if !readiness.storage_faults.is_empty() {
+                spans.push(Span::raw(format!(
+                    ", {} storage fault(s)",
+                    readiness.storage_faults.len()
+                )));
+            }
+            if !readiness.rollback_suspected.is_empty() {
+                spans.push(Span::raw(format!(
+                    ", rollback suspected for {} agent(s)",
+                    readiness.rollback_suspected.len()
+                )));
+            }
+            if readiness.maintenance {
+                spans.push(Span::styled(
+                    ", maintenance mode",
+                    Style::default().fg(Color::Yellow),
+                ));
+            }
+            lines.push(Line::from(spans));
+        }
+        (_, placeholder) => lines.push(Line::raw(placeholder.unwrap_or_default())),
+    }
+    let block = pane(" Verification ".into(), false);
+    frame.render_widget(
+        Paragraph::new(lines).wrap(Wrap { trim: true }).block(block),
+        area,
+    );
+}
+
+fn render_status(frame: &mut Frame, app: &App, area: Rect) {
+    let text = match app.mode {
+        Mode::Search => format!("/{}▏", app.search),
+        Mode::Inspect(_) => "esc close".into(),
+        Mode::Browse if !app.search.is_empty() => {
+            let matches = app
+                .lines
+                .iter()
+                .filter(|line| line.matches(&app.search))
+                .count();
+            format!(
+                "/{}: {matches} matching lines · n/N next/previous · esc clear",
+                app.search
+            )
+        }
+        Mode::Browse => {
+            "tab pane · j/k move · g/G ends · / search · enter inspect/filter · esc back · q quit"
+                .into()
+        }
+    };
+    frame.render_widget(
+        Paragraph::new(text).style(Style::default().fg(Color::DarkGray)),
+        area,
+    );
+}
+
+fn render_inspector(frame: &mut Frame, app: &App, id: i64) {
+    let area = frame.area();
+    let area = Rect {
+        x: area.width / 8,
+        y: area.height / 8,
+        width: area.width * 3 / 4,
+        height: area.height * 3 / 4,
+    };
+    let mut lines = Vec::new();
+    match app.batch(id) {
+        None => lines.push(Line::raw("the batch has left the tail")),
+        Some(entry) => {
+            let batch = &entry.batch;
+            let field = |name: &str, value: String| {
+                Line::from(vec![
+                    Span::styled(format!("{name:<14}"), Style::default().fg(Color::DarkGray)),
+                    Span::raw(value),
+                ])
+            };
+            lines.push(field("row id", entry.id.to_string()));
+            lines.push(field("agent", batch.agent_id.clone()));
+            lines.push(field("position", position_label(batch.position())));
+            lines.push(field("version", batch.version.to_string()));
+            lines.push(field("timestamp ms", batch.timestamp_ms().to_string()));
+            lines.push(field("received ms", entry.received_at.to_string()));
+            lines.push(field("hash", hex_encode(&entry.hash)));
+            lines.push(field("prev hash", hex_encode(&batch.prev_hash)));
+            lines.push(field(
+                "accumulator",
+                batch
+                    .accumulator
+                    .map_or("none".into(), |acc| hex_encode(&acc)),
+            ));
+            lines.push(field("public key", hex_encode(batch.public_key.as_bytes())));
+            lines.push(field("lines", batch.logs.len().to_string()));
+            if let Some(read) = batch.lines_read {
+                lines.push(field("lines read", read.to_string()));
+            }
+            if !entry.redacted.is_empty() {
+                lines.push(field("redacted", format!("{:?}", entry.redacted)));
+            }
+            if batch.logs.is_empty() {
+                lines.push(field("kind", empty_batch_label(batch)));
+            }
+            lines.push(Line::raw(""));
+            if let Some(verdict) = app.verdicts.get(&id) {
+                let check = |ok: bool, text: &str| {
+                    let (mark, color) = if ok {
+                        ("✓", Color::Green)
+                    } else {
+                        ("✗", Color::Red)
+                    };
+                    Line::from(vec![
+                        Span::styled(format!("{mark} "), Style::default().fg(color)),
+                        Span::raw(text.to_string()),
+                    ])
+                };
+                lines.push(check(verdict.signed, "signature"));
+                lines.push(check(
+                    verdict.hash_matches,
+                    "stored hash matches the content",
+                ));
+                lines.push(match verdict.linked {
+                    Some(linked) => check(linked, "links to the agent's batch before it"),
+                    None => {
+                        Line::raw("· link not checked: the batch before it is older than the tail")
+                    }
+                });
+            }
+        }
+    }
+    frame.render_widget(Clear, area);
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(format!(" Batch {id} "));
+    frame.render_widget(
+        Paragraph::new(lines)
+            .wrap(Wrap { trim: false })
+            .block(block),
+        area,
+    );
+}
+
+pub async fn run(server_url: &str, tail: usize, refresh: Duration) -> anyhow::Result<()> {
+    let client = http_client();
+    let mut app = App::new();
+    app.apply(fetch(&client, server_url, tail).await);
+
+    // crossterm reads block, so keys come from a thread of their own.
+    let (keys_tx, mut keys) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            let key = match event {
+                Event::Key(key) => Key::from_event(key),
+                Event::Resize(..) => Some(Key::Redraw),
+                _ => None,
+            };
+            if let Some(key) = key
+                && keys_tx.send(key).is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let mut terminal = ratatui::init();
+    let mut ticker = tokio::time::interval(refresh);
+    ticker.tick().await;
+    let result = loop {
+        if let Err(err) = terminal.draw(|frame| render(frame, &app)) {
+            break Err(err.into());
+        }
+        tokio::select! {
+            _ = ticker.tick() => app.apply(fetch(&client, server_url, tail).await),
+            key = keys.recv() => match key {
+                Some(key) if !app.handle(key) => {}
+                _ => break Ok(()),
+            },
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::testutil::build_chain;
+    use ed25519_dalek::SigningKey;
+    use ratatui::Terminal;
+    use ratatui::backend::TestBackend;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Two agents' chains, arriving alternately: ids 1-6 are a1 b1 a2 b2 a3 b3.
+    fn interleaved() -> Vec<TailBatch> {
+        let a = build_chain(&SigningKey::from_bytes(&[1; 32]), "agent-a", 3);
+        let b = build_chain(&SigningKey::from_bytes(&[2; 32]), "agent-b", 3);
+        a.into_iter()
+            .zip(b)
+            .flat_map(|(a, b)| [a, b])
+            .enumerate()
+            .map(|(index, batch)| TailBatch {
+                id: index as i64 + 1,
+                hash: batch.compute_hash(),
+                received_at: 1_000 + index as u64,
+                batch,
+                redacted: Vec::new(),
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect()
+    }
+
+    fn snapshot(tail: Vec<TailBatch>) -> Snapshot {
+        let agent = |agent_id: &str, last_received_at_ms, drift_exceeded| AgentStatus {
+            agent_id: agent_id.into(),
+            last_seq: 3,
+            last_received_at_ms,
+            clock_drift_ms: 12,
+            drift_exceeded,
+        };
+        Snapshot {
+            agents: Feed::Loaded(vec![
+                agent("agent-a", 1_004, false),
+                agent("agent-b", 1_005, true),
+            ]),
+            stale: Feed::Loaded(vec![StaleAgent {
+                agent_id: "agent-a".into(),
+            }]),
+            tail: Feed::Loaded(tail),
+            readiness: Feed::Loaded(Readiness {
+                ready: true,
+                storage_faults: Vec::new(),
+                rollback_suspected: Vec::new(),
+                maintenance: false,
+            }),
+            now_ms: 10_000,
+        }
+    }
+
+    fn screen(app: &App) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(120, 30)).unwrap();
+        terminal.draw(|frame| render(frame, app)).unwrap();
+        let buffer = terminal.backend().buffer();
+        buffer
+            .content()
+            .chunks(buffer.area.width as usize)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn press(app: &mut App, keys: &str) {
+        for c in keys.chars() {
+            assert!(!app.handle(Key::Char(c)));
+        }
+    }
+
+    #[test]
+    fn keys_move_search_inspect_and_filter() {
+        let mut app = App::new();
+        app.apply(snapshot(interleaved()));
+        let texts: Vec<&str> = app.lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            [
+                "agent-a line 1",
+                "agent-b line 1",
+                "agent-a line 2",
+                "agent-b line 2",
+                "agent-a line 3",
+                "agent-b line 3"
+            ]
+        );
+        // The newest line is selected and stays so as the tail grows.
+        assert_eq!((app.line_selected, app.follow), (5, true));
+
+        press(&mut app, "kk");
+        assert_eq!((app.line_selected, app.follow), (3, false));
+        app.apply(snapshot(interleaved()));
+        assert_eq!(app.selected_line().unwrap().text, "agent-b line 2");
+        press(&mut app, "g");
+        assert_eq!(app.line_selected, 0);
+
+        // Each key of a search moves to the first match from the selection.
+        press(&mut app, "/");
+        assert_eq!(app.mode, Mode::Search);
+        press(&mut app, "line 2");
+        assert_eq!(app.selected_line().unwrap().text, "agent-a line 2");
+        app.handle(Key::Backspace);
+        press(&mut app, "3");
+        assert_eq!(app.selected_line().unwrap().text, "agent-a line 3");
+        app.handle(Key::Enter);
+        press(&mut app, "n");
+        assert_eq!(app.selected_line().unwrap().text, "agent-b line 3");
+        press(&mut app, "n");
+        assert_eq!(
+            app.selected_line().unwrap().text,
+            "agent-a line 3",
+            "wraps around"
+        );
+        press(&mut app, "N");
+        assert_eq!(app.selected_line().unwrap().text, "agent-b line 3");
+        assert!(screen(&app).contains("/line 3: 2 matching lines"));
+
+        app.handle(Key::Enter);
+        assert_eq!(app.mode, Mode::Inspect(6));
+        let inspector = screen(&app);
+        assert!(inspector.contains("Batch 6"), "{inspector}");
+        assert!(inspector.contains("✓ signature"));
+        assert!(inspector.contains("✓ links to the agent's batch before it"));
+        app.handle(Key::Esc);
+        assert_eq!(app.mode, Mode::Browse);
+
+        // Enter on an agent shows only its lines; again, or esc, shows all.
+        app.handle(Key::Esc);
+        app.handle(Key::Tab);
+        press(&mut app, "j");
+        app.handle(Key::Enter);
+        assert_eq!(app.agent_filter.as_deref(), Some("agent-b"));
+        assert!(app.lines.iter().all(|line| line.agent_id == "agent-b"));
+        assert!(screen(&app).contains("Tail of agent-b (3 lines, following)"));
+        app.handle(Key::Esc);
+        assert_eq!(app.lines.len(), 6);
+        assert!(app.handle(Key::Char('q')));
+    }
+
+    #[test]
+    fn tampered_and_unlinked_batches_fail_their_checks() {
+        let mut tail = interleaved();
+        // agent-b's last batch: content changed after signing.
+        tail[0].batch.logs[0] = "agent-b line three".into();
+        // agent-a's last batch: stored hash of another batch.
+        tail[1].hash = tail[3].hash;
+        let verdicts = verdicts(&tail);
+        assert!(!verdicts[&6].signed && !verdicts[&6].hash_matches);
+        assert!(verdicts[&5].signed && !verdicts[&5].hash_matches);
+        assert_eq!(verdicts[&1].linked, Some(true), "seq 1 links to zeros");
+        assert_eq!(verdicts.values().filter(|verdict| verdict.ok()).count(), 4);
+
+        // Without its first batches, an agent's oldest row is not checked.
+        let verdicts = super::verdicts(&tail[..4]);
+        assert_eq!(verdicts[&3].linked, None);
+        assert_eq!(verdicts[&5].linked, Some(true));
+
+        let mut app = App::new();
+        app.apply(snapshot(tail));
+        assert!(screen(&app).contains("tail: 4 of 6 batches verify"));
+    }
+
+    #[tokio::test]
+    async fn panes_degrade_on_a_server_without_their_endpoints() {
+        let server = MockServer::start().await;
+        let status = serde_json::json!([{
+            "agent_id": "agent-old",
+            "last_seq": 9,
+            "last_received_at_ms": 0,
+            "clock_drift_ms": 0,
+            "drift_samples": 1,
+            "drift_exceeded": false,
+        }]);
+        Mock::given(method("GET"))
+            .and(path("/agents/status"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(status))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/readyz"))
+            .respond_with(ResponseTemplate::new(503).set_body_json(serde_json::json!({
+                "ready": false,
+                "storage_faults": ["disk full"],
+                "rollback_suspected": [],
+                "maintenance": false,
+            })))
+            .mount(&server)
+            .await;
+
+        let mut app = App::new();
+        app.apply(fetch(&Client::new(), &server.uri(), 10).await);
+        assert!(matches!(app.snapshot.tail, Feed::Missing));
+        assert!(matches!(app.snapshot.stale, Feed::Missing));
+        // Without /agents/stale, silence past the server default is stale.
+        assert!(app.is_stale(&app.agents()[0]));
+        let screen = screen(&app);
+        assert!(
+            screen.contains("/batches/latest is not available on this server"),
+            "{screen}"
+        );
+        assert!(screen.contains("Agents, stale > 300s"));
+        assert!(screen.contains("agent-old"));
+        assert!(screen.contains("server: NOT READY, 1 storage fault(s)"));
+        // Keys still work on an empty tail.
+        press(&mut app, "jkgGn/x");
+        app.handle(Key::Enter);
+        assert_eq!(app.mode, Mode::Browse);
+    }
+}
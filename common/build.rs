@@ -0,0 +1,47 @@
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=proto/logchain.proto");
+    println!("cargo:rustc-env=LOGCHAIN_GIT_COMMIT={}", git_commit());
+    #[cfg(feature = "grpc")]
+    {
+        // SAFETY: build scripts are single-threaded.
+        unsafe {
+            std::env::set_var(
+                "PROTOC",
+                protoc_bin_vendored::protoc_bin_path().expect("vendored protoc"),
+            );
+        }
+        tonic_build::compile_protos("proto/logchain.proto").expect("compiling logchain.proto");
+    }
+}
+
+/// The commit being built, for `GET /version` and `--version`:
+/// `LOGCHAIN_GIT_COMMIT` when set (builds from a tarball), otherwise
+/// `git rev-parse`, otherwise `unknown`.
+fn git_commit() -> String {
+    println!("cargo:rerun-if-env-changed=LOGCHAIN_GIT_COMMIT");
+    if let Ok(commit) = std::env::var("LOGCHAIN_GIT_COMMIT") {
+        return commit;
+    }
+    // Rebuild when HEAD moves: a checkout rewrites HEAD, a commit the ref.
+    let git_dir = Path::new("../.git");
+    if let Ok(head) = std::fs::read_to_string(git_dir.join("HEAD")) {
+        println!("cargo:rerun-if-changed=../.git/HEAD");
+        if let Some(reference) = head.strip_prefix("ref: ")
+            && git_dir.join(reference.trim()).exists()
+        {
+            println!("cargo:rerun-if-changed=../.git/{}", reference.trim());
+        }
+    }
+    Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .filter(|commit| !commit.is_empty())
+        .unwrap_or_else(|| "unknown".into())
+}
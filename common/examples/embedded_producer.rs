@@ -0,0 +1,53 @@
+//! Signs and ships lines from inside a service, without the agent binary.
+//!
+//! ```sh
+//! cargo run -p common --features producer --example embedded_producer -- http://127.0.0.1:3000
+//! ```
+//!
+//! The key and chain state live next to each other, like an agent's state
+//! dir; a second run continues the same chain.
+
+use common::batch::generate_keypair;
+use common::producer::{Client, FileStateStore, Producer, ProducerError};
+use ed25519_dalek::SigningKey;
+use std::fs;
+use std::path::Path;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let server_url = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "http://127.0.0.1:3000".into());
+    let state_dir = Path::new("embedded-producer");
+    fs::create_dir_all(state_dir)?;
+    let key = load_or_generate_key(&state_dir.join("agent.key"))?;
+
+    let mut producer = Producer::new(key, "embedded-producer", Client::new(server_url))
+        .with_state_store(FileStateStore::new(state_dir.join("state.json")))
+        .with_batch_size(10);
+    for n in 0..25 {
+        if let Some(sent) = producer.log(format!("order {n} processed")).await? {
+            println!("sent seq {} ({} lines)", sent.seq, sent.lines);
+        }
+    }
+    match producer.flush().await {
+        Ok(Some(sent)) => println!("sent seq {} ({} lines)", sent.seq, sent.lines),
+        Ok(None) => {}
+        Err(ProducerError::Rejected { status, message }) => {
+            eprintln!("the server refused the last batch ({status}): {message}");
+        }
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+fn load_or_generate_key(path: &Path) -> std::io::Result<SigningKey> {
+    if let Ok(bytes) = fs::read(path)
+        && let Ok(secret) = <[u8; 32]>::try_from(bytes.as_slice())
+    {
+        return Ok(SigningKey::from_bytes(&secret));
+    }
+    let key = generate_keypair();
+    fs::write(path, key.to_bytes())?;
+    Ok(key)
+}
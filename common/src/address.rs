@@ -0,0 +1,119 @@
+//! Line addresses: `agent_id/seq/line_idx` names one stored log line, e.g.
+//! `web-1/1042/3` for line 3 (counting from 0) of seq 1042. Past epoch 0
+//! the seq part carries its epoch as `epoch:seq`, since seqs count from 1
+//! again in every epoch: `web-1/2:17/0`. An address stays valid for as long
+//! as the batch is stored, because the chain never rewrites a position.
+//!
+//! Agent ids may themselves contain `/` or `:` (ingest sources are
+//! `ingest:<name>`), so an address is split from the right.
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LineAddress {
+    pub agent_id: String,
+    pub epoch: u64,
+    pub seq: u64,
+    /// Index into the batch's `logs`, from 0.
+    pub line_idx: usize,
+}
+
+impl LineAddress {
+    pub fn new(agent_id: &str, (epoch, seq): (u64, u64), line_idx: usize) -> Self {
+        Self {
+            agent_id: agent_id.to_string(),
+            epoch,
+            seq,
+            line_idx,
+        }
+    }
+
+    /// The seq part: `seq` in epoch 0, `epoch:seq` after it.
+    pub fn position(&self) -> String {
+        match self.epoch {
+            0 => self.seq.to_string(),
+            epoch => format!("{epoch}:{}", self.seq),
+        }
+    }
+
+    /// Parses the seq part of an address (or of a `/logs` path).
+    pub fn parse_position(part: &str) -> Result<(u64, u64), String> {
+        let (epoch, seq) = match part.split_once(':') {
+            Some((epoch, seq)) => (
+                epoch
+                    .parse()
+                    .map_err(|_| format!("invalid epoch '{epoch}' in '{part}'"))?,
+                seq,
+            ),
+            None => (0, part),
+        };
+        let seq = seq
+            .parse()
+            .map_err(|_| format!("invalid seq '{seq}' in '{part}'"))?;
+        Ok((epoch, seq))
+    }
+}
+
+impl fmt::Display for LineAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.agent_id, self.position(), self.line_idx)
+    }
+}
+
+impl FromStr for LineAddress {
+    type Err = String;
+
+    fn from_str(address: &str) -> Result<Self, String> {
+        let mut parts = address.rsplitn(3, '/');
+        let (Some(line_idx), Some(position), Some(agent_id)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!("'{address}' is not agent_id/seq/line_idx"));
+        };
+        if agent_id.is_empty() {
+            return Err(format!("'{address}' has no agent id"));
+        }
+        let (epoch, seq) = Self::parse_position(position)?;
+        let line_idx = line_idx
+            .parse()
+            .map_err(|_| format!("invalid line index '{line_idx}' in '{address}'"))?;
+        Ok(Self {
+            agent_id: agent_id.to_string(),
+            epoch,
+            seq,
+            line_idx,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_round_trip_and_bad_ones_are_refused() {
+        let first = LineAddress::new("web-1", (0, 1042), 3);
+        assert_eq!(first.to_string(), "web-1/1042/3");
+        assert_eq!("web-1/1042/3".parse(), Ok(first));
+
+        let later = LineAddress::new("ingest:nginx", (2, 17), 0);
+        assert_eq!(later.to_string(), "ingest:nginx/2:17/0");
+        assert_eq!("ingest:nginx/2:17/0".parse(), Ok(later));
+        let nested: LineAddress = "team/web/5/1".parse().unwrap();
+        assert_eq!(
+            (nested.agent_id.as_str(), nested.seq, nested.line_idx),
+            ("team/web", 5, 1)
+        );
+
+        for (bad, error) in [
+            ("web-1/1042", "'web-1/1042' is not agent_id/seq/line_idx"),
+            ("/1042/3", "'/1042/3' has no agent id"),
+            ("web-1/x/3", "invalid seq 'x' in 'x'"),
+            ("web-1/e:4/3", "invalid epoch 'e' in 'e:4'"),
+            ("web-1/4/-1", "invalid line index '-1' in 'web-1/4/-1'"),
+        ] {
+            assert_eq!(bad.parse::<LineAddress>().unwrap_err(), error, "{bad}");
+        }
+    }
+}
@@ -1,20 +1,39 @@
+use ed25519_dalek::Signer;
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
 use rand::Rng;
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
-use ed25519_dalek::Signer;
+use std::collections::BTreeMap;
+
+use crate::serde_util::is_zero;
+use crate::summary::merkle_root;
 
 /// A tamper-evident batch of logs sent from an agent to the server.
 ///
 /// Each batch includes:
 /// - `prev_hash`: the hash of the previous batch in the chain
 /// - `logs`: the log lines
-/// - `timestamp`: unix time when batch was created
+/// - `timestamp`: creation time; unix seconds in v1 batches, unix milliseconds
+///   from v2 on (use [`LogBatch::timestamp_ms`] to compare across versions)
 /// - `signature`: digital signature of the batch content
 /// - `public_key`: the agent's public key (used to verify signature)
 /// - `agent_id`: stable identifier for the producing agent
 /// - `seq`: monotonically increasing sequence number per agent
+/// - `lines_read`: optional cumulative count of lines the agent has ever read,
+///   including this batch's lines; jumps reveal lines read but never shipped
+/// - `version`: batch format version; absent in serialized v1 batches
+/// - `accumulator`: optional running hash over every earlier batch hash of the
+///   chain, see [`next_accumulator`]
+/// - `gap`: set only on a gap marker, a batch without logs declaring the seqs
+///   right before it lost for good, see [`GapRecord`]
+/// - `epoch`: which epoch of the agent's chain the batch belongs to; absent
+///   (0) until the agent starts its first new epoch. Seqs count from 1 again
+///   in every epoch, so a batch's place in the chain is `(epoch, seq)`
+/// - `epoch_start`: set only on seq 1 of epochs after the first, linking the
+///   epoch to the last batch of the one before, see [`EpochStart`]
+/// - `kind`: set only on batches that record an event of the agent's own
+///   instead of carrying logs, see [`BatchKind`]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LogBatch {
     pub prev_hash: [u8; 32],
@@ -24,11 +43,198 @@ pub struct LogBatch {
     pub seq: u64,
     pub signature: Signature,
     pub public_key: VerifyingKey,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lines_read: Option<u64>,
+    #[serde(default = "default_version", skip_serializing_if = "is_v1")]
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accumulator: Option<[u8; 32]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gap: Option<GapRecord>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub epoch: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epoch_start: Option<EpochStart>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<BatchKind>,
+}
+
+/// What a gap marker declares: seqs `missing_from..=missing_to` were produced
+/// (or counted as produced) by the agent but will never reach the server.
+///
+/// The marker takes seq `missing_to + 1` and its `prev_hash` is the hash of
+/// seq `missing_from - 1` (all zeros when the range starts at 1), so the hash
+/// chain has no hole and the jump in seqs is signed rather than silent. It
+/// proves only that the agent's key vouched for the gap, not why it happened:
+/// `reason` is the agent's own account.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct GapRecord {
+    pub missing_from: u64,
+    pub missing_to: u64,
+    pub reason: String,
+}
+
+/// What the first batch of a new epoch records about the epoch it closes.
+///
+/// The batch is seq 1 of epoch `n` (`n > 0`); its `prev_hash` is the hash of
+/// seq `previous_last_seq` of epoch `n - 1`, the last batch of that epoch.
+/// Both sit under the agent's signature, so closing an epoch early or leaving
+/// batches out of it breaks the link rather than passing as a fresh start.
+/// The accumulator restarts at the epoch's first batch, which keeps checking
+/// one epoch independent of the length of the ones before it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct EpochStart {
+    pub previous_last_seq: u64,
+}
+
+/// What a batch without logs records about the agent that signed it.
+///
+/// The batch is otherwise ordinary: it takes the next seq and links to the
+/// batch before it, so the event sits in the chain under the agent's
+/// signature and cannot be dropped or moved without breaking the chain.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BatchKind {
+    /// The agent started a run: the first batch it sends once synced with
+    /// the server. `session_id` is fresh for every run, and `boot_time_ms`
+    /// is when the run started, in unix milliseconds.
+    SessionStart {
+        session_id: String,
+        boot_time_ms: u64,
+    },
+    /// The agent's chain ends here: `agent --finalize` sent it when the
+    /// agent was decommissioned. Nothing may follow it, so a verifier can
+    /// tell a chain that was closed from one that was cut short.
+    Closed { reason: String },
+}
+
+/// Original format: `timestamp` in unix seconds.
+pub const BATCH_VERSION_V1: u32 = 1;
+/// `timestamp` in unix milliseconds.
+pub const BATCH_VERSION_V2: u32 = 2;
+/// `logs` hashed as a Merkle root over one leaf per line (see
+/// [`LogBatch::line_leaf`]), so a single line can be redacted and the batch
+/// still checked against its signature.
+pub const BATCH_VERSION_V3: u32 = 3;
+/// Version new batches are produced with.
+pub const CURRENT_BATCH_VERSION: u32 = BATCH_VERSION_V3;
+
+const LINE_LEAF_DOMAIN: &[u8] = b"logchain-line-v1";
+
+/// Accumulator that precedes the first batch of a chain, and the one assumed
+/// before a batch whose predecessor carries none.
+pub const ACCUMULATOR_GENESIS: [u8; 32] = [0u8; 32];
+const ACCUMULATOR_DOMAIN: &[u8] = b"logchain-accumulator-v1";
+
+/// One accumulator step: `SHA-256("logchain-accumulator-v1" || prev_accumulator || prev_hash)`.
+///
+/// A batch's accumulator is `next_accumulator(acc_prev, batch.prev_hash)`, where
+/// `acc_prev` is the previous batch's accumulator, or [`ACCUMULATOR_GENESIS`] at
+/// seq 1 and when the previous batch has none. Batch `n` therefore commits to the
+/// hashes of batches `1..n` (or of every batch since the chain started carrying
+/// accumulators), and `seq` doubles as the depth.
+///
+/// The chain itself already commits to its history through `prev_hash`; what the
+/// accumulator adds is that checking a later batch against a trusted earlier
+/// accumulator needs only the 32-byte hashes in between, not the batches
+/// themselves (see [`extend_accumulator`]). The cost stays linear in the gap.
+pub fn next_accumulator(prev_accumulator: &[u8; 32], prev_hash: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(ACCUMULATOR_DOMAIN);
+    hasher.update(prev_accumulator);
+    hasher.update(prev_hash);
+    hasher.finalize().into()
+}
+
+/// Folds batch hashes `h_k .. h_{n-1}` into the trusted accumulator of batch `k`,
+/// giving the accumulator batch `n` must carry.
+pub fn extend_accumulator<'a>(
+    trusted: [u8; 32],
+    hashes: impl IntoIterator<Item = &'a [u8; 32]>,
+) -> [u8; 32] {
+    hashes
+        .into_iter()
+        .fold(trusted, |acc, hash| next_accumulator(&acc, hash))
+}
+
+fn default_version() -> u32 {
+    BATCH_VERSION_V1
+}
+
+fn is_v1(version: &u32) -> bool {
+    *version == BATCH_VERSION_V1
 }
 
 impl LogBatch {
     /// Computes the SHA-256 hash of this batch (excluding the signature).
+    ///
+    /// Optional fields are hashed only when present, so batches produced
+    /// before a field existed keep their original hash.
     pub fn compute_hash(&self) -> [u8; 32] {
+        self.hash_over_leaves(&BTreeMap::new())
+    }
+
+    /// The hash this batch was signed with, `redacted` naming the lines that
+    /// were replaced by a redaction marker (see [`crate::redaction`]): each
+    /// stands in for its line with the leaf the marker carries. Only v3
+    /// batches hash their lines one by one; lines of any other batch, and
+    /// a line not named here whatever it holds, count as they are.
+    pub fn compute_hash_redacted(&self, redacted: &[usize]) -> Result<[u8; 32], String> {
+        if redacted.is_empty() {
+            return Ok(self.compute_hash());
+        }
+        if self.version < BATCH_VERSION_V3 {
+            return Err(format!(
+                "a v{} batch hashes its lines together; none can be redacted",
+                self.version
+            ));
+        }
+        let mut leaves = BTreeMap::new();
+        for &line_idx in redacted {
+            let line = self.logs.get(line_idx).ok_or_else(|| {
+                format!(
+                    "line {line_idx} is past the batch's {} lines",
+                    self.logs.len()
+                )
+            })?;
+            let leaf = crate::redaction::parse_marker(line)
+                .ok_or_else(|| format!("line {line_idx} is not a redaction marker"))?;
+            leaves.insert(line_idx, leaf);
+        }
+        Ok(self.hash_over_leaves(&leaves))
+    }
+
+    /// [`LogBatch::verify`] for a batch with redacted lines, see
+    /// [`LogBatch::compute_hash_redacted`].
+    pub fn verify_redacted(&self, redacted: &[usize]) -> bool {
+        self.compute_hash_redacted(redacted).is_ok_and(|hash| {
+            self.public_key
+                .verify_strict(&hash, &self.signature)
+                .is_ok()
+        })
+    }
+
+    /// The leaf a v3 batch's line `line_idx` is hashed as:
+    /// `SHA-256("logchain-line-v1" || prev_hash || agent_id || epoch || seq
+    /// || line_idx || line)`, lengths before the variable parts. The batch's
+    /// place in the chain salts the line, so equal lines in two places give
+    /// unrelated leaves and no table of precomputed hashes applies; a short
+    /// line can still be confirmed by whoever guesses it.
+    pub fn line_leaf(&self, line_idx: usize, line: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(LINE_LEAF_DOMAIN);
+        hasher.update(self.prev_hash);
+        hasher.update((self.agent_id.len() as u64).to_le_bytes());
+        hasher.update(self.agent_id.as_bytes());
+        hasher.update(self.epoch.to_le_bytes());
+        hasher.update(self.seq.to_le_bytes());
+        hasher.update((line_idx as u64).to_le_bytes());
+        hasher.update((line.len() as u64).to_le_bytes());
+        hasher.update(line.as_bytes());
+        hasher.finalize().into()
+    }
+
+    fn hash_over_leaves(&self, redacted: &BTreeMap<usize, [u8; 32]>) -> [u8; 32] {
         let mut hasher = Sha256::new();
 
         hasher.update(self.prev_hash);
@@ -36,14 +242,204 @@ impl LogBatch {
         hasher.update(self.seq.to_le_bytes());
         hasher.update(self.agent_id.as_bytes());
 
-        for log in &self.logs {
-            hasher.update(log.as_bytes());
+        if self.version >= BATCH_VERSION_V3 {
+            let leaves: Vec<[u8; 32]> = self
+                .logs
+                .iter()
+                .enumerate()
+                .map(|(idx, line)| match redacted.get(&idx) {
+                    Some(leaf) => *leaf,
+                    None => self.line_leaf(idx, line),
+                })
+                .collect();
+            hasher.update(b"lines");
+            hasher.update((leaves.len() as u64).to_le_bytes());
+            hasher.update(merkle_root(&leaves));
+        } else {
+            for log in &self.logs {
+                hasher.update(log.as_bytes());
+            }
+        }
+
+        if let Some(lines_read) = self.lines_read {
+            hasher.update(b"lines_read");
+            hasher.update(lines_read.to_le_bytes());
+        }
+
+        if self.version != BATCH_VERSION_V1 {
+            hasher.update(b"version");
+            hasher.update(self.version.to_le_bytes());
+        }
+
+        if let Some(accumulator) = self.accumulator {
+            hasher.update(b"accumulator");
+            hasher.update(accumulator);
+        }
+
+        if let Some(gap) = &self.gap {
+            hasher.update(b"gap");
+            hasher.update(gap.missing_from.to_le_bytes());
+            hasher.update(gap.missing_to.to_le_bytes());
+            hasher.update((gap.reason.len() as u64).to_le_bytes());
+            hasher.update(gap.reason.as_bytes());
+        }
+
+        if self.epoch != 0 {
+            hasher.update(b"epoch");
+            hasher.update(self.epoch.to_le_bytes());
+        }
+
+        if let Some(start) = &self.epoch_start {
+            hasher.update(b"epoch_start");
+            hasher.update(start.previous_last_seq.to_le_bytes());
+        }
+
+        match &self.kind {
+            Some(BatchKind::SessionStart {
+                session_id,
+                boot_time_ms,
+            }) => {
+                hasher.update(b"session_start");
+                hasher.update((session_id.len() as u64).to_le_bytes());
+                hasher.update(session_id.as_bytes());
+                hasher.update(boot_time_ms.to_le_bytes());
+            }
+            Some(BatchKind::Closed { reason }) => {
+                hasher.update(b"closed");
+                hasher.update((reason.len() as u64).to_le_bytes());
+                hasher.update(reason.as_bytes());
+            }
+            None => {}
         }
 
         let result = hasher.finalize();
         result.into()
     }
 
+    /// The accumulator this batch must carry given its predecessor's
+    /// (`None` at seq 1 or when the predecessor carries none). An epoch's
+    /// first batch starts over from the genesis whatever came before.
+    pub fn expected_accumulator(&self, previous: Option<&[u8; 32]>) -> [u8; 32] {
+        let previous = match self.epoch_start {
+            Some(_) => None,
+            None => previous,
+        };
+        next_accumulator(previous.unwrap_or(&ACCUMULATOR_GENESIS), &self.prev_hash)
+    }
+
+    /// Seq of the batch `prev_hash` links to, 0 when it links to the genesis:
+    /// `seq - 1`, for a gap marker the seq before the declared range, and for
+    /// an epoch's first batch the last seq of the epoch before (see
+    /// [`LogBatch::linked_position`]).
+    pub fn linked_seq(&self) -> u64 {
+        match (&self.epoch_start, &self.gap) {
+            (Some(start), _) => start.previous_last_seq,
+            (None, Some(gap)) => gap.missing_from.saturating_sub(1),
+            (None, None) => self.seq.saturating_sub(1),
+        }
+    }
+
+    /// The batch's place in its agent's chain, `(epoch, seq)`; positions
+    /// order the chain.
+    pub fn position(&self) -> (u64, u64) {
+        (self.epoch, self.seq)
+    }
+
+    /// Position of the batch `prev_hash` links to; `(0, 0)` is the genesis.
+    pub fn linked_position(&self) -> (u64, u64) {
+        match self.epoch_start {
+            Some(_) => (self.epoch.saturating_sub(1), self.linked_seq()),
+            None => (self.epoch, self.linked_seq()),
+        }
+    }
+
+    /// Checks the epoch fields: an epoch after the first opens with an
+    /// [`EpochStart`] at seq 1 that follows a non-empty epoch, and nothing
+    /// else carries one. Always `Ok` for batches of the first epoch.
+    pub fn check_epoch(&self) -> Result<(), String> {
+        match &self.epoch_start {
+            Some(_) if self.epoch == 0 => Err("epoch 0 has no epoch start".into()),
+            Some(_) if self.seq != 1 => {
+                Err(format!("epoch start must be seq 1, got seq {}", self.seq))
+            }
+            Some(start) if start.previous_last_seq == 0 => {
+                Err(format!("epoch {} follows an empty epoch", self.epoch))
+            }
+            Some(_) => Ok(()),
+            None if self.epoch > 0 && self.linked_seq() == 0 => Err(format!(
+                "seq {} of epoch {} must carry the epoch start",
+                self.seq, self.epoch
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the shape of a gap marker: no logs, and a non-empty range that
+    /// ends right before the marker's own seq. Always `Ok` for other batches.
+    pub fn check_gap(&self) -> Result<(), String> {
+        let Some(gap) = &self.gap else {
+            return Ok(());
+        };
+        if !self.logs.is_empty() {
+            return Err("gap marker must carry no logs".into());
+        }
+        if gap.missing_from == 0 || gap.missing_from > gap.missing_to {
+            return Err(format!(
+                "gap range {}..={} is empty",
+                gap.missing_from, gap.missing_to
+            ));
+        }
+        if gap.missing_to.checked_add(1) != Some(self.seq) {
+            return Err(format!(
+                "gap ends at seq {} but the marker is seq {}",
+                gap.missing_to, self.seq
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the shape of a batch with a [`BatchKind`]: no logs, no gap and
+    /// a session id or close reason. Always `Ok` for other batches.
+    pub fn check_kind(&self) -> Result<(), String> {
+        let (what, missing) = match &self.kind {
+            None => return Ok(()),
+            Some(BatchKind::SessionStart { session_id, .. }) => (
+                "session start",
+                session_id.is_empty().then_some("a session id"),
+            ),
+            Some(BatchKind::Closed { reason }) => {
+                ("chain close", reason.is_empty().then_some("a reason"))
+            }
+        };
+        if !self.logs.is_empty() {
+            return Err(format!("{what} must carry no logs"));
+        }
+        if self.gap.is_some() {
+            return Err(format!("{what} cannot be a gap marker"));
+        }
+        if let Some(missing) = missing {
+            return Err(format!("{what} needs {missing}"));
+        }
+        Ok(())
+    }
+
+    /// The reason this batch closed its agent's chain, if it did.
+    pub fn closed_reason(&self) -> Option<&str> {
+        match &self.kind {
+            Some(BatchKind::Closed { reason }) => Some(reason),
+            _ => None,
+        }
+    }
+
+    /// Creation time in unix milliseconds regardless of batch version.
+    pub fn timestamp_ms(&self) -> u64 {
+        if self.version >= BATCH_VERSION_V2 {
+            self.timestamp
+        } else {
+            self.timestamp.saturating_mul(1000)
+        }
+    }
+
     /// Signs the batch content and stores signature + public key.
     pub fn sign(&mut self, signer: &SigningKey) {
         let hash = self.compute_hash();
@@ -58,6 +454,47 @@ impl LogBatch {
     }
 }
 
+/// A jump in the cumulative `lines_read` counter between consecutive batches.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LineCountGap {
+    pub seq: u64,
+    pub expected: u64,
+    pub found: u64,
+}
+
+/// Finds batches whose `lines_read` does not equal the previous batch's count
+/// plus this batch's line count. Expects one agent's batches in seq order;
+/// batches without a counter break the comparison rather than being flagged.
+pub fn find_line_count_gaps<'a>(
+    batches: impl IntoIterator<Item = &'a LogBatch>,
+) -> Vec<LineCountGap> {
+    let mut gaps = Vec::new();
+    let mut previous: Option<u64> = None;
+
+    for batch in batches {
+        if let (Some(prev), Some(found)) = (previous, batch.lines_read) {
+            let expected = prev.saturating_add(batch.logs.len() as u64);
+            if found != expected {
+                gaps.push(LineCountGap {
+                    seq: batch.seq,
+                    expected,
+                    found,
+                });
+            }
+        }
+        previous = batch.lines_read;
+    }
+
+    gaps
+}
+
+/// Utility: create a new signing key (agent identity).
+pub fn generate_keypair() -> SigningKey {
+    let mut bytes = [0u8; 32];
+    OsRng.fill(&mut bytes);
+    SigningKey::from_bytes(&bytes)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -72,6 +509,13 @@ mod tests {
             seq: 1,
             signature: Signature::from_bytes(&[0u8; 64]),
             public_key: generate_keypair().verifying_key(),
+            lines_read: None,
+            version: BATCH_VERSION_V1,
+            accumulator: None,
+            gap: None,
+            epoch: 0,
+            epoch_start: None,
+            kind: None,
         };
 
         let signer = generate_keypair();
@@ -89,6 +533,13 @@ mod tests {
             seq: 1,
             signature: Signature::from_bytes(&[0u8; 64]),
             public_key: generate_keypair().verifying_key(),
+            lines_read: None,
+            version: BATCH_VERSION_V1,
+            accumulator: None,
+            gap: None,
+            epoch: 0,
+            epoch_start: None,
+            kind: None,
         };
 
         let signer = generate_keypair();
@@ -99,11 +550,503 @@ mod tests {
         batch.logs.push("evil".into());
         assert!(!batch.verify(), "tampering should fail verification");
     }
-}
 
-/// Utility: create a new signing key (agent identity).
-pub fn generate_keypair() -> SigningKey {
-    let mut bytes = [0u8; 32];
-    OsRng.fill(&mut bytes);
-    SigningKey::from_bytes(&bytes)
+    fn counted(seq: u64, lines: usize, lines_read: Option<u64>) -> LogBatch {
+        LogBatch {
+            prev_hash: [0u8; 32],
+            logs: vec!["x".into(); lines],
+            timestamp: seq,
+            agent_id: "agent-c".into(),
+            seq,
+            signature: Signature::from_bytes(&[0u8; 64]),
+            public_key: generate_keypair().verifying_key(),
+            lines_read,
+            version: BATCH_VERSION_V1,
+            accumulator: None,
+            gap: None,
+            epoch: 0,
+            epoch_start: None,
+            kind: None,
+        }
+    }
+
+    #[test]
+    fn line_count_jump_is_detected() {
+        let batches = vec![
+            counted(1, 5, Some(5)),
+            counted(2, 5, Some(10)),
+            // 5 lines were read after seq 2 but never shipped.
+            counted(3, 5, Some(20)),
+            counted(4, 5, Some(25)),
+        ];
+        assert_eq!(
+            find_line_count_gaps(&batches),
+            vec![LineCountGap {
+                seq: 3,
+                expected: 15,
+                found: 20
+            }]
+        );
+    }
+
+    #[test]
+    fn missing_counter_is_not_a_gap() {
+        let batches = vec![
+            counted(1, 5, Some(5)),
+            counted(2, 5, None),
+            counted(3, 5, Some(40)),
+        ];
+        assert!(find_line_count_gaps(&batches).is_empty());
+    }
+
+    #[test]
+    fn lines_read_is_signed() {
+        let signer = generate_keypair();
+        let mut batch = counted(1, 2, Some(2));
+        batch.sign(&signer);
+        assert!(batch.verify());
+
+        batch.lines_read = Some(3);
+        assert!(!batch.verify(), "counter must be covered by the signature");
+
+        batch.lines_read = None;
+        assert!(!batch.verify());
+    }
+
+    #[test]
+    fn version_is_signed_and_v1_hash_is_unchanged() {
+        let signer = generate_keypair();
+        let mut batch = counted(1, 1, None);
+        let v1_hash = batch.compute_hash();
+
+        batch.version = BATCH_VERSION_V2;
+        assert_ne!(batch.compute_hash(), v1_hash);
+        batch.sign(&signer);
+        assert!(batch.verify());
+
+        batch.version = BATCH_VERSION_V1;
+        assert!(
+            !batch.verify(),
+            "downgrading the version must break the signature"
+        );
+    }
+
+    #[test]
+    fn v3_lines_are_leaves_only_a_marker_can_stand_in_for() {
+        let signer = generate_keypair();
+        let mut batch = counted(1, 3, None);
+        batch.logs = vec!["alice@example.com logged in".into(), "b".into(), "c".into()];
+        batch.version = BATCH_VERSION_V3;
+        batch.sign(&signer);
+        let signed = batch.compute_hash();
+
+        // Every line is still covered, and so is its place.
+        let mut moved = batch.clone();
+        moved.logs.swap(1, 2);
+        assert!(!moved.verify());
+
+        let leaf = batch.line_leaf(0, &batch.logs[0]);
+        let mut redacted = batch.clone();
+        redacted.logs[0] = crate::redaction::marker(&leaf);
+        assert!(!redacted.verify());
+        assert_eq!(redacted.compute_hash_redacted(&[0]), Ok(signed));
+        assert!(redacted.verify_redacted(&[0]));
+
+        // A marker cannot carry another line, nor cover a line it replaced.
+        redacted.logs[1] = "B".into();
+        assert!(!redacted.verify_redacted(&[0]));
+        redacted.logs[1] = crate::redaction::marker(&batch.line_leaf(1, "B"));
+        assert!(!redacted.verify_redacted(&[0, 1]));
+        assert_eq!(
+            redacted.compute_hash_redacted(&[2]),
+            Err("line 2 is not a redaction marker".into())
+        );
+        assert_eq!(
+            redacted.compute_hash_redacted(&[3]),
+            Err("line 3 is past the batch's 3 lines".into())
+        );
+
+        // Earlier versions hash their lines together.
+        let mut v2 = counted(1, 1, None);
+        v2.version = BATCH_VERSION_V2;
+        assert!(v2.compute_hash_redacted(&[0]).is_err());
+        assert_eq!(v2.compute_hash_redacted(&[]), Ok(v2.compute_hash()));
+    }
+
+    #[test]
+    fn timestamp_ms_normalizes_units() {
+        let mut batch = counted(1, 1, None);
+        batch.timestamp = 1_700_000_000;
+        assert_eq!(batch.timestamp_ms(), 1_700_000_000_000);
+
+        batch.version = BATCH_VERSION_V2;
+        batch.timestamp = 1_700_000_000_123;
+        assert_eq!(batch.timestamp_ms(), 1_700_000_000_123);
+    }
+
+    #[test]
+    fn v1_json_without_version_deserializes_as_v1() {
+        let mut batch = counted(1, 1, None);
+        batch.sign(&generate_keypair());
+        let json = serde_json::to_string(&batch).unwrap();
+        assert!(!json.contains("version"));
+        let back: LogBatch = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.version, BATCH_VERSION_V1);
+        assert!(back.verify());
+    }
+
+    fn from_hex(s: &str) -> [u8; 32] {
+        crate::hex::hex_decode_fixed(s).unwrap()
+    }
+
+    #[test]
+    fn accumulator_test_vectors() {
+        // acc(seq 1) over a zero prev_hash, then two batches with hashes 0x11.. and 0x22..
+        let acc1 = next_accumulator(&ACCUMULATOR_GENESIS, &[0u8; 32]);
+        assert_eq!(
+            acc1,
+            from_hex("0b408d414c0ddc4c558a072b3730b6b34475691a060df6defec9e900eaba2ee2")
+        );
+        let acc2 = next_accumulator(&acc1, &[0x11; 32]);
+        assert_eq!(
+            acc2,
+            from_hex("347f563f9774d2f5b336834b126a1d29222a96b6ce4fd3115d7bd86e33509b96")
+        );
+        let acc3 = extend_accumulator(acc1, [&[0x11; 32], &[0x22; 32]]);
+        assert_eq!(
+            acc3,
+            from_hex("69ab706b999f3e8e8835cef135a088351a8333bc788562bd22e8efdec25bc8f6")
+        );
+    }
+
+    #[test]
+    fn accumulator_anchors_chain_from_hashes_only() {
+        let signer = generate_keypair();
+        let mut chain: Vec<LogBatch> = Vec::new();
+        for seq in 1..=5 {
+            let mut batch = counted(seq, 1, None);
+            if let Some(prev) = chain.last() {
+                batch.prev_hash = prev.compute_hash();
+            }
+            let previous = chain.last().and_then(|b| b.accumulator);
+            batch.accumulator = Some(batch.expected_accumulator(previous.as_ref()));
+            batch.sign(&signer);
+            chain.push(batch);
+        }
+
+        // Trusting seq 2's accumulator, hashes of seqs 2..=4 anchor seq 5.
+        let trusted = chain[1].accumulator.unwrap();
+        let hashes: Vec<[u8; 32]> = chain[1..4].iter().map(|b| b.compute_hash()).collect();
+        assert_eq!(
+            extend_accumulator(trusted, &hashes),
+            chain[4].accumulator.unwrap()
+        );
+
+        // Any altered intermediate hash breaks the anchor.
+        let mut forged = hashes.clone();
+        forged[1][0] ^= 1;
+        assert_ne!(
+            extend_accumulator(trusted, &forged),
+            chain[4].accumulator.unwrap()
+        );
+    }
+
+    #[test]
+    fn accumulator_is_signed_and_absent_keeps_hash() {
+        let signer = generate_keypair();
+        let mut batch = counted(1, 1, None);
+        let without = batch.compute_hash();
+
+        batch.accumulator = Some(batch.expected_accumulator(None));
+        assert_ne!(batch.compute_hash(), without);
+        batch.sign(&signer);
+        assert!(batch.verify());
+
+        batch.accumulator = Some([7u8; 32]);
+        assert!(
+            !batch.verify(),
+            "accumulator must be covered by the signature"
+        );
+        batch.accumulator = None;
+        assert!(
+            !batch.verify(),
+            "stripping the accumulator must break the signature"
+        );
+    }
+
+    #[test]
+    fn gap_record_is_signed_and_checked() {
+        let signer = generate_keypair();
+        let mut marker = counted(8, 0, None);
+        marker.prev_hash = [4u8; 32];
+        marker.gap = Some(GapRecord {
+            missing_from: 5,
+            missing_to: 7,
+            reason: "agent state lost".into(),
+        });
+        marker.sign(&signer);
+        assert!(marker.verify());
+        assert_eq!(marker.check_gap(), Ok(()));
+        assert_eq!(marker.linked_seq(), 4);
+
+        let json = serde_json::to_string(&marker).unwrap();
+        let back: LogBatch = serde_json::from_str(&json).unwrap();
+        assert!(back.verify());
+        assert_eq!(back.gap, marker.gap);
+
+        let mut widened = marker.clone();
+        widened.gap.as_mut().unwrap().missing_from = 4;
+        assert!(
+            !widened.verify(),
+            "the range must be covered by the signature"
+        );
+        let mut reworded = marker.clone();
+        reworded.gap.as_mut().unwrap().reason = "routine".into();
+        assert!(
+            !reworded.verify(),
+            "the reason must be covered by the signature"
+        );
+        let mut stripped = marker.clone();
+        stripped.gap = None;
+        assert!(
+            !stripped.verify(),
+            "dropping the gap must break the signature"
+        );
+
+        let mut detached = marker.clone();
+        detached.seq = 9;
+        assert_eq!(
+            detached.check_gap().unwrap_err(),
+            "gap ends at seq 7 but the marker is seq 9"
+        );
+        let mut with_logs = marker.clone();
+        with_logs.logs.push("smuggled".into());
+        assert_eq!(
+            with_logs.check_gap().unwrap_err(),
+            "gap marker must carry no logs"
+        );
+        let mut empty = marker;
+        empty.gap.as_mut().unwrap().missing_from = 8;
+        assert_eq!(empty.check_gap().unwrap_err(), "gap range 8..=7 is empty");
+
+        let plain = counted(3, 1, None);
+        assert_eq!(plain.check_gap(), Ok(()));
+        assert_eq!(plain.linked_seq(), 2);
+    }
+
+    #[test]
+    fn epoch_start_is_signed_and_links_to_the_previous_epoch() {
+        let signer = generate_keypair();
+        let mut last = counted(40, 1, None);
+        last.accumulator = Some([9u8; 32]);
+        last.sign(&signer);
+
+        let mut opener = counted(1, 1, None);
+        opener.prev_hash = last.compute_hash();
+        opener.epoch = 1;
+        opener.epoch_start = Some(EpochStart {
+            previous_last_seq: 40,
+        });
+        assert_eq!(opener.check_epoch(), Ok(()));
+        assert_eq!(opener.position(), (1, 1));
+        assert_eq!(opener.linked_position(), last.position());
+        // The accumulator starts over; the link is carried by prev_hash.
+        assert_eq!(
+            opener.expected_accumulator(last.accumulator.as_ref()),
+            opener.expected_accumulator(None)
+        );
+        opener.sign(&signer);
+        assert!(opener.verify());
+
+        let json = serde_json::to_string(&opener).unwrap();
+        let back: LogBatch = serde_json::from_str(&json).unwrap();
+        assert!(back.verify());
+        assert_eq!(back.epoch_start, opener.epoch_start);
+        assert!(!serde_json::to_string(&last).unwrap().contains("epoch"));
+
+        let mut shortened = opener.clone();
+        shortened.epoch_start.as_mut().unwrap().previous_last_seq = 39;
+        assert!(
+            !shortened.verify(),
+            "the closed epoch's length must be signed"
+        );
+        let mut renumbered = opener.clone();
+        renumbered.epoch = 2;
+        assert!(!renumbered.verify(), "the epoch must be signed");
+        let mut stripped = opener.clone();
+        stripped.epoch_start = None;
+        assert!(!stripped.verify());
+        assert_eq!(
+            stripped.check_epoch().unwrap_err(),
+            "seq 1 of epoch 1 must carry the epoch start"
+        );
+
+        let second = counted(2, 1, None);
+        let second = LogBatch { epoch: 1, ..second };
+        assert_eq!(second.check_epoch(), Ok(()));
+        assert_eq!(second.linked_position(), (1, 1));
+
+        let mut late = opener.clone();
+        late.seq = 2;
+        assert_eq!(
+            late.check_epoch().unwrap_err(),
+            "epoch start must be seq 1, got seq 2"
+        );
+        let mut first = opener.clone();
+        first.epoch = 0;
+        assert_eq!(
+            first.check_epoch().unwrap_err(),
+            "epoch 0 has no epoch start"
+        );
+        let mut after_empty = opener;
+        after_empty.epoch_start.as_mut().unwrap().previous_last_seq = 0;
+        assert_eq!(
+            after_empty.check_epoch().unwrap_err(),
+            "epoch 1 follows an empty epoch"
+        );
+
+        let mut marker = counted(3, 0, None);
+        marker.epoch = 1;
+        marker.gap = Some(GapRecord {
+            missing_from: 1,
+            missing_to: 2,
+            reason: "lost".into(),
+        });
+        assert!(
+            marker.check_epoch().is_err(),
+            "a gap cannot hide an epoch's start"
+        );
+    }
+
+    #[test]
+    fn session_start_is_signed_and_checked() {
+        let signer = generate_keypair();
+        let mut marker = counted(6, 0, None);
+        marker.prev_hash = [5u8; 32];
+        let unmarked = marker.compute_hash();
+        marker.kind = Some(BatchKind::SessionStart {
+            session_id: "4bf92f35-77b3-4da6-a3ce-929d0e0e4736".into(),
+            boot_time_ms: 1_700_000_000_000,
+        });
+        assert_ne!(marker.compute_hash(), unmarked);
+        assert_eq!(marker.check_kind(), Ok(()));
+        assert_eq!(
+            marker.linked_seq(),
+            5,
+            "a session start is an ordinary link"
+        );
+        marker.sign(&signer);
+        assert!(marker.verify());
+
+        let json = serde_json::to_string(&marker).unwrap();
+        assert!(
+            json.contains(r#""kind":{"type":"session_start","#),
+            "{json}"
+        );
+        let back: LogBatch = serde_json::from_str(&json).unwrap();
+        assert!(back.verify());
+        assert_eq!(back.kind, marker.kind);
+        assert!(
+            !serde_json::to_string(&counted(1, 1, None))
+                .unwrap()
+                .contains("kind")
+        );
+
+        let mut rebooted = marker.clone();
+        if let Some(BatchKind::SessionStart { boot_time_ms, .. }) = &mut rebooted.kind {
+            *boot_time_ms += 1;
+        }
+        assert!(
+            !rebooted.verify(),
+            "the boot time must be covered by the signature"
+        );
+        let mut renamed = marker.clone();
+        if let Some(BatchKind::SessionStart { session_id, .. }) = &mut renamed.kind {
+            session_id.push('0');
+        }
+        assert!(
+            !renamed.verify(),
+            "the session id must be covered by the signature"
+        );
+        let mut stripped = marker.clone();
+        stripped.kind = None;
+        assert!(
+            !stripped.verify(),
+            "dropping the kind must break the signature"
+        );
+
+        let mut with_logs = marker.clone();
+        with_logs.logs.push("smuggled".into());
+        assert_eq!(
+            with_logs.check_kind().unwrap_err(),
+            "session start must carry no logs"
+        );
+        let mut anonymous = marker;
+        anonymous.kind = Some(BatchKind::SessionStart {
+            session_id: String::new(),
+            boot_time_ms: 0,
+        });
+        assert_eq!(
+            anonymous.check_kind().unwrap_err(),
+            "session start needs a session id"
+        );
+        assert_eq!(counted(3, 1, None).check_kind(), Ok(()));
+    }
+
+    #[test]
+    fn chain_close_is_signed_and_checked() {
+        let signer = generate_keypair();
+        let mut close = counted(9, 0, None);
+        close.prev_hash = [8u8; 32];
+        close.logs.clear();
+        let open = close.compute_hash();
+        close.kind = Some(BatchKind::Closed {
+            reason: "host decommissioned".into(),
+        });
+        assert_ne!(close.compute_hash(), open);
+        assert_eq!(close.check_kind(), Ok(()));
+        assert_eq!(close.closed_reason(), Some("host decommissioned"));
+        close.sign(&signer);
+        assert!(close.verify());
+
+        let json = serde_json::to_string(&close).unwrap();
+        assert!(
+            json.contains(r#""kind":{"type":"closed","reason":"host decommissioned"}"#),
+            "{json}"
+        );
+        let back: LogBatch = serde_json::from_str(&json).unwrap();
+        assert!(back.verify());
+        assert_eq!(back.kind, close.kind);
+
+        let mut reworded = close.clone();
+        reworded.kind = Some(BatchKind::Closed {
+            reason: "host decommissioned!".into(),
+        });
+        assert!(
+            !reworded.verify(),
+            "the reason must be covered by the signature"
+        );
+        let mut reopened = close.clone();
+        reopened.kind = None;
+        assert!(
+            !reopened.verify(),
+            "dropping the close must break the signature"
+        );
+
+        let mut with_logs = close.clone();
+        with_logs.logs.push("one more".into());
+        assert_eq!(
+            with_logs.check_kind().unwrap_err(),
+            "chain close must carry no logs"
+        );
+        let mut unexplained = close;
+        unexplained.kind = Some(BatchKind::Closed {
+            reason: String::new(),
+        });
+        assert_eq!(
+            unexplained.check_kind().unwrap_err(),
+            "chain close needs a reason"
+        );
+        assert_eq!(counted(3, 1, None).closed_reason(), None);
+    }
 }
@@ -0,0 +1,401 @@
+//! Compatibility handshake: `GET /version` advertises what the server
+//! accepts and emits, and a client compares that with what it produces and
+//! verifies before sending anything, so a mismatch fails fast with the fix
+//! instead of surfacing as a cryptic rejection per batch.
+//!
+//! A producer (the agent) needs the server to accept its batch version, the
+//! hash schemes it signs with and its upload encoding. A reader (the agent's
+//! acks, the CLI) needs to know every batch version the server may store and
+//! every scheme the server signs with. Servers from before the handshake
+//! answer `/version` with 404; clients then go on unchecked.
+
+use crate::batch::{BATCH_VERSION_V1, BATCH_VERSION_V3, CURRENT_BATCH_VERSION};
+use crate::wire::{CONTENT_TYPES, JSON_CONTENT_TYPE, WireFormat};
+use serde::{Deserialize, Serialize};
+
+/// Domain-separated hashes and signed messages, by name and version.
+pub const ACCUMULATOR_V1: &str = "accumulator-v1";
+/// Per-line leaves of v3 batches.
+pub const LINE_LEAF_V1: &str = "line-leaf-v1";
+pub const RECEIPT_V1: &str = "receipt-v1";
+/// Receipts past epoch 0.
+pub const RECEIPT_V2: &str = "receipt-v2";
+pub const REDACTION_V1: &str = "redaction-v1";
+/// Signed export headers; see [`crate::export_header`].
+pub const EXPORT_HEADER_V1: &str = "export-header-v1";
+/// Imprints of RFC 3161 timestamps; see [`crate::tsa`].
+pub const TSA_V1: &str = "tsa-v1";
+/// Every scheme this build knows.
+pub const HASH_SCHEMES: &[&str] = &[
+    ACCUMULATOR_V1,
+    LINE_LEAF_V1,
+    RECEIPT_V1,
+    RECEIPT_V2,
+    REDACTION_V1,
+    TSA_V1,
+    EXPORT_HEADER_V1,
+];
+/// `Content-Encoding`s `/submit` takes.
+pub const ENCODINGS: &[&str] = &["identity", "gzip"];
+/// The commit this tree was built from, set by the build script; `unknown`
+/// outside a git checkout unless `LOGCHAIN_GIT_COMMIT` was given.
+pub const GIT_COMMIT: &str = env!("LOGCHAIN_GIT_COMMIT");
+
+/// The `GET /version` body.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// The server's package version, for humans; nothing is decided on it.
+    pub server_version: String,
+    /// The server's build, for humans; absent from servers that predate it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    pub batch_versions: Vec<u32>,
+    pub hash_schemes: Vec<String>,
+    pub encodings: Vec<String>,
+    /// `/submit` body formats; servers that predate it read JSON only.
+    #[serde(default = "json_only")]
+    pub content_types: Vec<String>,
+}
+
+fn json_only() -> Vec<String> {
+    vec![JSON_CONTENT_TYPE.to_string()]
+}
+
+impl Capabilities {
+    /// What a server built from this tree supports.
+    pub fn current(server_version: &str) -> Self {
+        Self {
+            server_version: server_version.to_string(),
+            git_commit: Some(GIT_COMMIT.to_string()),
+            batch_versions: (BATCH_VERSION_V1..=CURRENT_BATCH_VERSION).collect(),
+            hash_schemes: HASH_SCHEMES.iter().map(|s| s.to_string()).collect(),
+            encodings: ENCODINGS.iter().map(|s| s.to_string()).collect(),
+            content_types: CONTENT_TYPES.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// What `--version` prints for `program`, one field per line.
+    pub fn describe(&self, program: &str) -> String {
+        let versions = match (self.batch_versions.first(), self.batch_versions.last()) {
+            (Some(min), Some(max)) if min != max => format!("v{min}-v{max}"),
+            (Some(only), _) => format!("v{only}"),
+            _ => "none".into(),
+        };
+        format!(
+            "{program} {}\ncommit: {}\nbatch versions: {versions}\nhash schemes: {}\nencodings: {}\ncontent types: {}",
+            self.server_version,
+            self.git_commit.as_deref().unwrap_or("unknown"),
+            self.hash_schemes.join(", "),
+            self.encodings.join(", "),
+            self.content_types.join(", ")
+        )
+    }
+
+    fn has_scheme(&self, scheme: &str) -> bool {
+        self.hash_schemes.iter().any(|s| s == scheme)
+    }
+}
+
+/// What a producer sends: its batch version, whether it gzips uploads, and
+/// the body format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Produces {
+    pub batch_version: u32,
+    pub gzip: bool,
+    pub wire_format: WireFormat,
+}
+
+impl Produces {
+    /// The schemes a batch of this version is signed with.
+    pub fn hash_schemes(&self) -> Vec<&'static str> {
+        let mut schemes = vec![ACCUMULATOR_V1];
+        if self.batch_version >= BATCH_VERSION_V3 {
+            schemes.push(LINE_LEAF_V1);
+        }
+        schemes
+    }
+}
+
+fn version_list(versions: &[u32]) -> String {
+    let listed: Vec<String> = versions.iter().map(|v| format!("v{v}")).collect();
+    match listed.as_slice() {
+        [only] => format!("{only} only"),
+        _ => listed.join(", "),
+    }
+}
+
+/// Checks that the server accepts what the agent produces; the error says
+/// what to pass or upgrade.
+pub fn check_producer(caps: &Capabilities, produces: Produces) -> Result<(), String> {
+    let version = produces.batch_version;
+    if !caps.batch_versions.contains(&version) {
+        let supported = version_list(&caps.batch_versions);
+        return Err(
+            match caps
+                .batch_versions
+                .iter()
+                .filter(|v| **v <= CURRENT_BATCH_VERSION)
+                .max()
+            {
+                Some(fallback) => format!(
+                    "server supports batch {supported}; this agent produces v{version} — \
+                     pass --batch-version {fallback} or upgrade the server"
+                ),
+                None => format!(
+                    "server supports batch {supported}; this agent produces up to \
+                     v{CURRENT_BATCH_VERSION} — upgrade the agent"
+                ),
+            },
+        );
+    }
+    if let Some(scheme) = produces
+        .hash_schemes()
+        .into_iter()
+        .find(|s| !caps.has_scheme(s))
+    {
+        return Err(format!(
+            "server does not know the {scheme} hash scheme batch v{version} is signed with — \
+             pass an older --batch-version or upgrade the server"
+        ));
+    }
+    if produces.gzip && !caps.encodings.iter().any(|e| e == "gzip") {
+        return Err(
+            "server does not accept gzip uploads — drop --gzip-uploads or upgrade the server"
+                .to_string(),
+        );
+    }
+    let format = produces.wire_format;
+    if !caps
+        .content_types
+        .iter()
+        .any(|t| t == format.content_type())
+    {
+        return Err(format!(
+            "server does not accept {} submits — drop --wire-format {format} or upgrade the server",
+            format.content_type()
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `client` (`agent`, `CLI`) can verify whatever the server
+/// stores and signs.
+pub fn check_reader(caps: &Capabilities, client: &str) -> Result<(), String> {
+    if let Some(newest) = caps
+        .batch_versions
+        .iter()
+        .filter(|v| **v > CURRENT_BATCH_VERSION)
+        .max()
+    {
+        return Err(format!(
+            "server stores batch versions up to v{newest}; this {client} verifies up to \
+             v{CURRENT_BATCH_VERSION} — upgrade the {client}"
+        ));
+    }
+    if let Some(scheme) = caps
+        .hash_schemes
+        .iter()
+        .find(|s| !HASH_SCHEMES.contains(&s.as_str()))
+    {
+        return Err(format!(
+            "server uses the {scheme} hash scheme, which this {client} does not know — \
+             upgrade the {client}"
+        ));
+    }
+    Ok(())
+}
+
+/// `GET {server_url}/version`; `None` from a server that predates it.
+#[cfg(feature = "client")]
+pub async fn fetch(
+    client: &reqwest::Client,
+    server_url: &str,
+) -> Result<Option<Capabilities>, String> {
+    let resp = client
+        .get(format!("{server_url}/version"))
+        .send()
+        .await
+        .map_err(|err| format!("GET /version failed: {err}"))?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !resp.status().is_success() {
+        return Err(format!("GET /version failed with status {}", resp.status()));
+    }
+    resp.json()
+        .await
+        .map(Some)
+        .map_err(|err| format!("GET /version answered something else: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::BATCH_VERSION_V2;
+
+    fn server(batch_versions: &[u32], hash_schemes: &[&str], encodings: &[&str]) -> Capabilities {
+        Capabilities {
+            server_version: "test".into(),
+            git_commit: None,
+            batch_versions: batch_versions.to_vec(),
+            hash_schemes: hash_schemes.iter().map(|s| s.to_string()).collect(),
+            encodings: encodings.iter().map(|s| s.to_string()).collect(),
+            content_types: json_only(),
+        }
+    }
+
+    #[test]
+    fn producers_are_checked_against_old_and_new_servers() {
+        let current = Capabilities::current("0.1.0");
+        let v1_only = server(&[1], &[ACCUMULATOR_V1, RECEIPT_V1], &["identity"]);
+        let v1_v2 = server(
+            &[1, 2],
+            &[ACCUMULATOR_V1, RECEIPT_V1, RECEIPT_V2],
+            &["identity", "gzip"],
+        );
+        let future = server(
+            &[4],
+            &[ACCUMULATOR_V1, "line-leaf-v2"],
+            &["identity", "gzip"],
+        );
+        let newest = Produces {
+            batch_version: CURRENT_BATCH_VERSION,
+            gzip: true,
+            wire_format: WireFormat::Json,
+        };
+        let v2 = Produces {
+            batch_version: BATCH_VERSION_V2,
+            gzip: false,
+            wire_format: WireFormat::Json,
+        };
+        let v1 = Produces {
+            batch_version: BATCH_VERSION_V1,
+            gzip: false,
+            wire_format: WireFormat::Json,
+        };
+
+        for (caps, produces, expected) in [
+            (&current, newest, Ok(())),
+            (&current, v1, Ok(())),
+            (
+                &v1_only,
+                v2,
+                Err(
+                    "server supports batch v1 only; this agent produces v2 — pass --batch-version 1 or upgrade the server",
+                ),
+            ),
+            (&v1_only, v1, Ok(())),
+            (
+                &v1_only,
+                Produces {
+                    batch_version: BATCH_VERSION_V1,
+                    gzip: true,
+                    wire_format: WireFormat::Json,
+                },
+                Err(
+                    "server does not accept gzip uploads — drop --gzip-uploads or upgrade the server",
+                ),
+            ),
+            (
+                &v1_v2,
+                newest,
+                Err(
+                    "server supports batch v1, v2; this agent produces v3 — pass --batch-version 2 or upgrade the server",
+                ),
+            ),
+            (&v1_v2, Produces { gzip: true, ..v2 }, Ok(())),
+            (
+                &current,
+                Produces {
+                    wire_format: WireFormat::Msgpack,
+                    ..newest
+                },
+                Ok(()),
+            ),
+            (
+                &v1_v2,
+                Produces {
+                    wire_format: WireFormat::Msgpack,
+                    ..v2
+                },
+                Err(
+                    "server does not accept application/msgpack submits — drop --wire-format msgpack or upgrade the server",
+                ),
+            ),
+            (
+                &future,
+                newest,
+                Err(
+                    "server supports batch v4 only; this agent produces up to v3 — upgrade the agent",
+                ),
+            ),
+        ] {
+            assert_eq!(
+                check_producer(caps, produces),
+                expected.map_err(String::from),
+                "{caps:?} {produces:?}"
+            );
+        }
+
+        // A server that takes v3 batches but lacks the leaf scheme they sign.
+        let no_leaves = server(&[1, 2, 3], &[ACCUMULATOR_V1], &["identity"]);
+        assert!(
+            check_producer(
+                &no_leaves,
+                Produces {
+                    batch_version: 3,
+                    gzip: false,
+                    wire_format: WireFormat::Json
+                }
+            )
+            .unwrap_err()
+            .starts_with("server does not know the line-leaf-v1 hash scheme")
+        );
+    }
+
+    #[test]
+    fn readers_refuse_servers_newer_than_they_verify() {
+        assert_eq!(check_reader(&Capabilities::current("0.1.0"), "CLI"), Ok(()));
+        assert_eq!(
+            check_reader(&server(&[1], &[RECEIPT_V1], &["identity"]), "CLI"),
+            Ok(())
+        );
+        assert_eq!(
+            check_reader(&server(&[1, 2, 3, 4], HASH_SCHEMES, &["identity"]), "CLI"),
+            Err("server stores batch versions up to v4; this CLI verifies up to v3 — upgrade the CLI".into())
+        );
+        assert_eq!(
+            check_reader(&server(&[1, 2, 3], &[RECEIPT_V1, "receipt-v3"], &["identity"]), "agent"),
+            Err("server uses the receipt-v3 hash scheme, which this agent does not know — upgrade the agent".into())
+        );
+    }
+
+    #[test]
+    fn version_text_names_the_build_and_what_it_supports() {
+        let caps = Capabilities {
+            git_commit: Some("0123456789ab".into()),
+            ..server(
+                &[1, 2, 3],
+                &[ACCUMULATOR_V1, RECEIPT_V1],
+                &["identity", "gzip"],
+            )
+        };
+        assert_eq!(
+            caps.describe("server"),
+            "server test\ncommit: 0123456789ab\nbatch versions: v1-v3\n\
+             hash schemes: accumulator-v1, receipt-v1\nencodings: identity, gzip\ncontent types: application/json"
+        );
+        // Servers from before `git_commit` still parse.
+        let old: Capabilities = serde_json::from_str(
+            r#"{"server_version":"0.1.0","batch_versions":[1],"hash_schemes":[],"encodings":[]}"#,
+        )
+        .unwrap();
+        assert!(old.git_commit.is_none());
+        assert_eq!(old.content_types, [JSON_CONTENT_TYPE]);
+        assert!(
+            old.describe("server")
+                .contains("commit: unknown\nbatch versions: v1\n")
+        );
+        assert!(!GIT_COMMIT.is_empty());
+    }
+}
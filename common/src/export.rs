@@ -0,0 +1,335 @@
+use crate::batch::LogBatch;
+use crate::hex::hex_encode;
+use chrono::{DateTime, SecondsFormat};
+use serde::Deserialize;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Output formats for `/batches/export` and `cli export`.
+///
+/// `Json` and `Ndjson` carry whole stored batches; `Syslog` and `Cef` emit one
+/// record per log line with the batch provenance (agent id, seq, line index,
+/// batch hash) attached so it survives import into a SIEM. `Parquet` is one
+/// row per line as well, for analytics engines; writing it needs the
+/// `parquet` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Ndjson,
+    Syslog,
+    Cef,
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            "syslog" => Ok(Self::Syslog),
+            "cef" => Ok(Self::Cef),
+            "parquet" => Ok(Self::Parquet),
+            other => Err(format!(
+                "unknown export format '{other}' (expected json, ndjson, syslog, cef or parquet)"
+            )),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Ndjson => "ndjson",
+            Self::Syslog => "syslog",
+            Self::Cef => "cef",
+            Self::Parquet => "parquet",
+        }
+    }
+}
+
+/// Column compression codec for `ExportFormat::Parquet`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParquetCompression {
+    #[default]
+    Snappy,
+    Zstd,
+}
+
+impl FromStr for ParquetCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "snappy" => Ok(Self::Snappy),
+            "zstd" => Ok(Self::Zstd),
+            other => Err(format!(
+                "unknown parquet compression '{other}' (expected snappy or zstd)"
+            )),
+        }
+    }
+}
+
+impl ParquetCompression {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Snappy => "snappy",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+/// RFC 5424 private enterprise number reserved for documentation; scopes the
+/// structured-data element so it cannot clash with IANA-registered ids.
+const SD_ID: &str = "logchain@32473";
+/// facility user (1) * 8 + severity informational (6).
+const SYSLOG_PRI: u8 = 14;
+const APP_NAME: &str = "logchain";
+
+/// Renders every line of `batch` in a per-line format, one record per output
+/// line, each terminated by `\n`. Returns `None` for the whole-batch formats and
+/// for `Parquet`, which is binary.
+pub fn render_lines(format: ExportFormat, batch: &LogBatch, hash: &[u8; 32]) -> Option<String> {
+    let record: fn(&LogBatch, &str, usize, &str) -> String = match format {
+        ExportFormat::Syslog => syslog_record,
+        ExportFormat::Cef => cef_record,
+        ExportFormat::Json | ExportFormat::Ndjson | ExportFormat::Parquet => return None,
+    };
+
+    let hash_hex = hex_encode(hash);
+    let mut out = String::new();
+    for (index, line) in batch.logs.iter().enumerate() {
+        out.push_str(&record(batch, &hash_hex, index, line));
+        out.push('\n');
+    }
+    Some(out)
+}
+
+/// One RFC 5424 frame, e.g.
+/// `<14>1 2023-11-14T22:13:21.000Z - logchain - line [logchain@32473 agent_id="a" seq="1" line="0" hash="…"] text`.
+fn syslog_record(batch: &LogBatch, hash_hex: &str, index: usize, line: &str) -> String {
+    let timestamp = DateTime::from_timestamp_millis(batch.timestamp_ms() as i64)
+        .map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true))
+        .unwrap_or_else(|| "-".to_string());
+
+    format!(
+        "<{SYSLOG_PRI}>1 {timestamp} - {APP_NAME} - line [{SD_ID} agent_id=\"{}\" seq=\"{}\" line=\"{}\" hash=\"{}\"] {}",
+        escape_sd_param(&batch.agent_id),
+        batch.seq,
+        index,
+        hash_hex,
+        escape_syslog_msg(line),
+    )
+}
+
+/// One CEF:0 record; provenance rides in labelled custom extension fields.
+fn cef_record(batch: &LogBatch, hash_hex: &str, index: usize, line: &str) -> String {
+    format!(
+        "CEF:0|{}|{}|{}|log_line|{}|3|rt={} cs1Label=agent_id cs1={} cn1Label=seq cn1={} cn2Label=line_index cn2={} cs2Label=batch_hash cs2={} msg={}",
+        escape_cef_header(APP_NAME),
+        escape_cef_header(APP_NAME),
+        escape_cef_header(env!("CARGO_PKG_VERSION")),
+        escape_cef_header("log line"),
+        batch.timestamp_ms(),
+        escape_cef_extension(&batch.agent_id),
+        batch.seq,
+        index,
+        hash_hex,
+        escape_cef_extension(line),
+    )
+}
+
+/// RFC 5424 §6.3.3: `"`, `\` and `]` must be backslash-escaped in PARAM-VALUE.
+fn escape_sd_param(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// MSG is free-form, but records are newline-delimited, so control characters
+/// are written as `#ooo` octal, matching rsyslog's control-character escaping.
+fn escape_syslog_msg(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_ascii_control() {
+            let _ = write!(out, "#{:03o}", c as u32);
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// CEF header fields escape `\` and `|`; line breaks are not allowed and are
+/// replaced by a space.
+fn escape_cef_header(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '|' => out.push_str("\\|"),
+            '\r' | '\n' => out.push(' '),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// CEF extension values escape `\` and `=`, and encode line breaks as `\n`/`\r`.
+/// Pipes need no escaping here.
+fn escape_cef_extension(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '=' => out.push_str("\\="),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::{BATCH_VERSION_V2, generate_keypair};
+    use ed25519_dalek::Signature;
+
+    fn batch(agent_id: &str, logs: &[&str]) -> LogBatch {
+        let key = generate_keypair();
+        let mut batch = LogBatch {
+            prev_hash: [0u8; 32],
+            logs: logs.iter().map(|l| l.to_string()).collect(),
+            timestamp: 1_700_000_001_500,
+            agent_id: agent_id.to_string(),
+            seq: 7,
+            signature: Signature::from_bytes(&[0u8; 64]),
+            public_key: key.verifying_key(),
+            lines_read: None,
+            version: BATCH_VERSION_V2,
+            accumulator: None,
+            gap: None,
+            epoch: 0,
+            epoch_start: None,
+            kind: None,
+        };
+        batch.sign(&key);
+        batch
+    }
+
+    #[test]
+    fn sd_param_escaping() {
+        let cases = [
+            ("plain", "plain"),
+            ("say \"hi\"", "say \\\"hi\\\""),
+            ("a\\b", "a\\\\b"),
+            ("end]", "end\\]"),
+            ("[x]=\"y\"", "[x\\]=\\\"y\\\""),
+            ("", ""),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(escape_sd_param(input), expected, "input {input:?}");
+        }
+    }
+
+    #[test]
+    fn syslog_msg_escaping() {
+        let cases = [
+            ("ok line", "ok line"),
+            ("two\nlines", "two#012lines"),
+            ("cr\r\n", "cr#015#012"),
+            ("tab\there", "tab#011here"),
+            ("quote \" ] kept", "quote \" ] kept"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(escape_syslog_msg(input), expected, "input {input:?}");
+        }
+    }
+
+    #[test]
+    fn cef_header_escaping() {
+        let cases = [
+            ("vendor", "vendor"),
+            ("a|b", "a\\|b"),
+            ("back\\slash", "back\\\\slash"),
+            ("x=y", "x=y"),
+            ("multi\nline", "multi line"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(escape_cef_header(input), expected, "input {input:?}");
+        }
+    }
+
+    #[test]
+    fn cef_extension_escaping() {
+        let cases = [
+            ("value", "value"),
+            ("k=v", "k\\=v"),
+            ("back\\slash", "back\\\\slash"),
+            ("pipe|ok", "pipe|ok"),
+            ("two\nlines\r", "two\\nlines\\r"),
+            ("\\=", "\\\\\\="),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(escape_cef_extension(input), expected, "input {input:?}");
+        }
+    }
+
+    #[test]
+    fn syslog_records_carry_provenance() {
+        let b = batch("web\"01]", &["first", "second"]);
+        let hash = b.compute_hash();
+        let out = render_lines(ExportFormat::Syslog, &b, &hash).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[1],
+            format!(
+                "<14>1 2023-11-14T22:13:21.500Z - logchain - line [logchain@32473 agent_id=\"web\\\"01\\]\" seq=\"7\" line=\"1\" hash=\"{}\"] second",
+                hex_encode(&hash)
+            )
+        );
+    }
+
+    #[test]
+    fn cef_records_carry_provenance() {
+        let b = batch("agent=1", &["a|b=c"]);
+        let hash = b.compute_hash();
+        let out = render_lines(ExportFormat::Cef, &b, &hash).unwrap();
+
+        assert_eq!(
+            out,
+            format!(
+                "CEF:0|logchain|logchain|{}|log_line|log line|3|rt=1700000001500 cs1Label=agent_id cs1=agent\\=1 cn1Label=seq cn1=7 cn2Label=line_index cn2=0 cs2Label=batch_hash cs2={} msg=a|b\\=c\n",
+                env!("CARGO_PKG_VERSION"),
+                hex_encode(&hash)
+            )
+        );
+    }
+
+    #[test]
+    fn whole_batch_formats_are_not_line_rendered() {
+        let b = batch("a", &["x"]);
+        assert!(render_lines(ExportFormat::Json, &b, &[0u8; 32]).is_none());
+        assert_eq!("CEF".parse::<ExportFormat>(), Ok(ExportFormat::Cef));
+        assert!("xml".parse::<ExportFormat>().is_err());
+        assert!(render_lines(ExportFormat::Parquet, &b, &[0u8; 32]).is_none());
+        assert_eq!(
+            "ZSTD".parse::<ParquetCompression>(),
+            Ok(ParquetCompression::Zstd)
+        );
+    }
+}
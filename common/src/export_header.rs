@@ -0,0 +1,234 @@
+//! Signed export headers. `/batches/export?header=true` puts one ahead of
+//! the rows (the first ndjson line, or beside `batches` in a JSON object),
+//! so an archive carries what a verifier years later needs without asking
+//! the server: the export format, the batch versions and hash schemes its
+//! rows are hashed with, and the server key that signed the header.
+//!
+//! The key is embedded, so the header proves only that whoever held that
+//! key vouched for the metadata; compare it with the server's published
+//! keys (`GET /server-keys`) to trust it. The rows need no such trust:
+//! each is signed by its agent.
+
+use crate::batch::{BATCH_VERSION_V3, CURRENT_BATCH_VERSION, LogBatch};
+use crate::compat::{ACCUMULATOR_V1, HASH_SCHEMES, LINE_LEAF_V1, REDACTION_V1};
+use crate::hex::hex_encode;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+/// The header layout and where it goes in the export.
+pub const EXPORT_FORMAT_V1: u32 = 1;
+/// The key the header is found under: the first ndjson line is
+/// `{"export_header": {...}}` and a JSON export `{"export_header": {...},
+/// "batches": [...]}`.
+pub const HEADER_KEY: &str = "export_header";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ExportHeader {
+    pub format_version: u32,
+    /// Batch versions the server may have stored, as `/version` lists them.
+    pub batch_versions: Vec<u32>,
+    /// The schemes rows are hashed and signed with; see [`crate::compat`].
+    pub hash_schemes: Vec<String>,
+    /// The exporting server's package version, for humans.
+    pub server_version: String,
+    pub exported_at_ms: u64,
+    /// The signing key's id in the server key history.
+    pub key_id: i64,
+    pub server_public_key: VerifyingKey,
+    pub signature: Signature,
+}
+
+impl ExportHeader {
+    /// A header declaring `batch_versions` and `hash_schemes`, signed with
+    /// server key `key_id`.
+    pub fn issue(
+        key: &SigningKey,
+        key_id: i64,
+        batch_versions: Vec<u32>,
+        hash_schemes: Vec<String>,
+        server_version: &str,
+        exported_at_ms: u64,
+    ) -> Self {
+        let mut header = Self {
+            format_version: EXPORT_FORMAT_V1,
+            batch_versions,
+            hash_schemes,
+            server_version: server_version.to_string(),
+            exported_at_ms,
+            key_id,
+            server_public_key: key.verifying_key(),
+            signature: Signature::from_bytes(&[0u8; 64]),
+        };
+        header.signature = key.sign(&header.message());
+        header
+    }
+
+    /// What the server key signs:
+    /// `export-header:v1:<format_version>:<batch_versions>:<hash_schemes>:<server_version>:<exported_at_ms>:<key_id>:<public_key_hex>`,
+    /// lists comma-separated.
+    pub fn message(&self) -> Vec<u8> {
+        let versions: Vec<String> = self.batch_versions.iter().map(u32::to_string).collect();
+        format!(
+            "export-header:v1:{}:{}:{}:{}:{}:{}:{}",
+            self.format_version,
+            versions.join(","),
+            self.hash_schemes.join(","),
+            self.server_version,
+            self.exported_at_ms,
+            self.key_id,
+            hex_encode(self.server_public_key.as_bytes())
+        )
+        .into_bytes()
+    }
+
+    /// Checks the signature against the embedded key, and that this build
+    /// knows the format, every batch version and every scheme declared.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.format_version != EXPORT_FORMAT_V1 {
+            return Err(format!(
+                "export format v{} is newer than this verifier's v{EXPORT_FORMAT_V1} — upgrade it",
+                self.format_version
+            ));
+        }
+        if self
+            .server_public_key
+            .verify(&self.message(), &self.signature)
+            .is_err()
+        {
+            return Err("the export header's signature does not verify".into());
+        }
+        if let Some(newest) = self
+            .batch_versions
+            .iter()
+            .filter(|v| **v > CURRENT_BATCH_VERSION)
+            .max()
+        {
+            return Err(format!(
+                "the export declares batch versions up to v{newest}; this verifier knows up to \
+                 v{CURRENT_BATCH_VERSION} — upgrade it"
+            ));
+        }
+        if let Some(scheme) = self
+            .hash_schemes
+            .iter()
+            .find(|s| !HASH_SCHEMES.contains(&s.as_str()))
+        {
+            return Err(format!(
+                "the export declares the {scheme} hash scheme, which this verifier does not know — \
+                 upgrade it"
+            ));
+        }
+        Ok(())
+    }
+
+    fn declares(&self, scheme: &str) -> bool {
+        self.hash_schemes.iter().any(|s| s == scheme)
+    }
+
+    /// Checks that a row's batch, with lines `redacted`, is hashed by rules
+    /// the header declares: its batch version, per-line leaves for v3,
+    /// redaction markers and accumulators each need their scheme.
+    pub fn check_row(&self, batch: &LogBatch, redacted: &[usize]) -> Result<(), String> {
+        if !self.batch_versions.contains(&batch.version) {
+            return Err(format!(
+                "batch v{} is not among the export's declared versions {:?}",
+                batch.version, self.batch_versions
+            ));
+        }
+        let needs = [
+            (batch.version >= BATCH_VERSION_V3, LINE_LEAF_V1),
+            (!redacted.is_empty(), REDACTION_V1),
+            (batch.accumulator.is_some(), ACCUMULATOR_V1),
+        ];
+        match needs
+            .into_iter()
+            .find(|(needed, scheme)| *needed && !self.declares(scheme))
+        {
+            Some((_, scheme)) => Err(format!(
+                "hashing it takes the {scheme} scheme, which the export does not declare"
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::{BATCH_VERSION_V1, BATCH_VERSION_V2, generate_keypair};
+    use crate::compat::RECEIPT_V1;
+
+    fn header(key: &SigningKey, versions: Vec<u32>, schemes: &[&str]) -> ExportHeader {
+        let schemes = schemes.iter().map(|s| s.to_string()).collect();
+        ExportHeader::issue(key, 3, versions, schemes, "0.1.0", 1_700_000_000_000)
+    }
+
+    fn batch(key: &SigningKey, version: u32) -> LogBatch {
+        let mut batch = LogBatch {
+            prev_hash: [0u8; 32],
+            logs: vec!["line".into()],
+            timestamp: 1_700_000_000_000,
+            agent_id: "a".into(),
+            seq: 1,
+            signature: Signature::from_bytes(&[0u8; 64]),
+            public_key: key.verifying_key(),
+            lines_read: None,
+            version,
+            accumulator: Some([0u8; 32]),
+            gap: None,
+            epoch: 0,
+            epoch_start: None,
+            kind: None,
+        };
+        batch.sign(key);
+        batch
+    }
+
+    #[test]
+    fn headers_are_signed_and_select_the_rules_rows_are_checked_by() {
+        let key = generate_keypair();
+        // An older server: v1 and v2 batches, whole-batch hashing only.
+        let old = header(
+            &key,
+            vec![BATCH_VERSION_V1, BATCH_VERSION_V2],
+            &[ACCUMULATOR_V1, RECEIPT_V1],
+        );
+        assert_eq!(old.validate(), Ok(()));
+        assert_eq!(old.check_row(&batch(&key, BATCH_VERSION_V2), &[]), Ok(()));
+        let v3 = batch(&key, BATCH_VERSION_V3);
+        assert!(old.check_row(&v3, &[]).unwrap_err().contains("not among"));
+
+        // The current one adds line leaves and redactions.
+        let current = header(
+            &key,
+            vec![BATCH_VERSION_V1, BATCH_VERSION_V2, BATCH_VERSION_V3],
+            HASH_SCHEMES,
+        );
+        assert_eq!(current.validate(), Ok(()));
+        assert_eq!(current.check_row(&v3, &[0]), Ok(()));
+        let without_leaves = header(
+            &key,
+            vec![BATCH_VERSION_V1, BATCH_VERSION_V2, BATCH_VERSION_V3],
+            &[ACCUMULATOR_V1],
+        );
+        assert!(
+            without_leaves
+                .check_row(&v3, &[])
+                .unwrap_err()
+                .contains(LINE_LEAF_V1)
+        );
+
+        let mut edited = current.clone();
+        edited.batch_versions.pop();
+        assert!(edited.validate().unwrap_err().contains("signature"));
+        let future = header(&key, vec![CURRENT_BATCH_VERSION + 1], &[ACCUMULATOR_V1]);
+        assert!(future.validate().unwrap_err().contains("upgrade"));
+        let unknown = header(&key, vec![BATCH_VERSION_V1], &["accumulator-v9"]);
+        assert!(unknown.validate().unwrap_err().contains("accumulator-v9"));
+        assert!(
+            String::from_utf8(current.message())
+                .unwrap()
+                .starts_with("export-header:v1:1:1,2,3:accumulator-v1,line-leaf-v1,")
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! The optional gRPC transport: types generated from `proto/logchain.proto`
+//! and conversions to the JSON wire types. A batch converted either way
+//! hashes and verifies exactly as it did.
+
+use crate::batch::{BATCH_VERSION_V1, BatchKind, EpochStart, GapRecord, LogBatch};
+use ed25519_dalek::{Signature, VerifyingKey};
+
+pub mod proto {
+    tonic::include_proto!("logchain.v1");
+}
+
+/// Metadata on an `Unavailable` status carrying the seconds to wait before
+/// retrying, like HTTP's `Retry-After`.
+pub const RETRY_AFTER_METADATA: &str = "retry-after";
+
+/// Metadata on a rejected `Submit` carrying the `code` `/submit` answers
+/// with in its body.
+pub const ERROR_CODE_METADATA: &str = "error-code";
+
+impl From<&LogBatch> for proto::LogBatch {
+    fn from(batch: &LogBatch) -> Self {
+        Self {
+            prev_hash: batch.prev_hash.to_vec(),
+            logs: batch.logs.clone(),
+            timestamp: batch.timestamp,
+            agent_id: batch.agent_id.clone(),
+            seq: batch.seq,
+            signature: batch.signature.to_bytes().to_vec(),
+            public_key: batch.public_key.to_bytes().to_vec(),
+            lines_read: batch.lines_read,
+            version: batch.version,
+            accumulator: batch.accumulator.map(|acc| acc.to_vec()),
+            gap: batch.gap.as_ref().map(|gap| proto::GapRecord {
+                missing_from: gap.missing_from,
+                missing_to: gap.missing_to,
+                reason: gap.reason.clone(),
+            }),
+            epoch: batch.epoch,
+            epoch_start: batch.epoch_start.as_ref().map(|start| proto::EpochStart {
+                previous_last_seq: start.previous_last_seq,
+            }),
+            session_start: match &batch.kind {
+                Some(BatchKind::SessionStart {
+                    session_id,
+                    boot_time_ms,
+                }) => Some(proto::SessionStart {
+                    session_id: session_id.clone(),
+                    boot_time_ms: *boot_time_ms,
+                }),
+                _ => None,
+            },
+            closed: batch.closed_reason().map(|reason| proto::ChainClosed {
+                reason: reason.into(),
+            }),
+        }
+    }
+}
+
+fn fixed<const N: usize>(field: &str, bytes: Vec<u8>) -> Result<[u8; N], String> {
+    let len = bytes.len();
+    bytes
+        .try_into()
+        .map_err(|_| format!("{field} must be {N} bytes, got {len}"))
+}
+
+impl TryFrom<proto::LogBatch> for LogBatch {
+    type Error = String;
+
+    fn try_from(batch: proto::LogBatch) -> Result<Self, String> {
+        let public_key = VerifyingKey::from_bytes(&fixed("public_key", batch.public_key)?)
+            .map_err(|e| format!("invalid public_key: {e}"))?;
+        Ok(Self {
+            prev_hash: fixed("prev_hash", batch.prev_hash)?,
+            logs: batch.logs,
+            timestamp: batch.timestamp,
+            agent_id: batch.agent_id,
+            seq: batch.seq,
+            signature: Signature::from_bytes(&fixed("signature", batch.signature)?),
+            public_key,
+            lines_read: batch.lines_read,
+            version: if batch.version == 0 {
+                BATCH_VERSION_V1
+            } else {
+                batch.version
+            },
+            accumulator: batch
+                .accumulator
+                .map(|acc| fixed("accumulator", acc))
+                .transpose()?,
+            gap: batch.gap.map(|gap| GapRecord {
+                missing_from: gap.missing_from,
+                missing_to: gap.missing_to,
+                reason: gap.reason,
+            }),
+            epoch: batch.epoch,
+            epoch_start: batch.epoch_start.map(|start| EpochStart {
+                previous_last_seq: start.previous_last_seq,
+            }),
+            kind: match (batch.session_start, batch.closed) {
+                (Some(_), Some(_)) => {
+                    return Err("a batch cannot be both a session start and a chain close".into());
+                }
+                (Some(start), None) => Some(BatchKind::SessionStart {
+                    session_id: start.session_id,
+                    boot_time_ms: start.boot_time_ms,
+                }),
+                (None, Some(closed)) => Some(BatchKind::Closed {
+                    reason: closed.reason,
+                }),
+                (None, None) => None,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::{CURRENT_BATCH_VERSION, generate_keypair};
+
+    #[test]
+    fn batches_survive_the_round_trip_and_bad_lengths_are_refused() {
+        let key = generate_keypair();
+        let mut batch = LogBatch {
+            prev_hash: [3u8; 32],
+            logs: vec!["a".into(), "b".into()],
+            timestamp: 1_700_000_000_000,
+            agent_id: "agent-g".into(),
+            seq: 4,
+            signature: Signature::from_bytes(&[0u8; 64]),
+            public_key: key.verifying_key(),
+            lines_read: Some(9),
+            version: CURRENT_BATCH_VERSION,
+            accumulator: Some([5u8; 32]),
+            gap: None,
+            epoch: 0,
+            epoch_start: None,
+            kind: None,
+        };
+        batch.sign(&key);
+
+        let back = LogBatch::try_from(proto::LogBatch::from(&batch)).unwrap();
+        assert!(back.verify());
+        assert_eq!(back.compute_hash(), batch.compute_hash());
+
+        let mut marker = LogBatch {
+            logs: Vec::new(),
+            gap: Some(GapRecord {
+                missing_from: 2,
+                missing_to: 3,
+                reason: "lost".into(),
+            }),
+            ..batch.clone()
+        };
+        marker.sign(&key);
+        let back = LogBatch::try_from(proto::LogBatch::from(&marker)).unwrap();
+        assert!(back.verify());
+        assert_eq!(back.gap, marker.gap);
+
+        let mut opener = LogBatch {
+            seq: 1,
+            epoch: 2,
+            epoch_start: Some(EpochStart {
+                previous_last_seq: 40,
+            }),
+            ..batch.clone()
+        };
+        opener.sign(&key);
+        let back = LogBatch::try_from(proto::LogBatch::from(&opener)).unwrap();
+        assert!(back.verify());
+        assert_eq!((back.epoch, back.epoch_start), (2, opener.epoch_start));
+
+        let mut session = LogBatch {
+            logs: Vec::new(),
+            kind: Some(BatchKind::SessionStart {
+                session_id: "4bf92f35-77b3-4da6-a3ce-929d0e0e4736".into(),
+                boot_time_ms: 1_700_000_000_000,
+            }),
+            ..batch.clone()
+        };
+        session.sign(&key);
+        let back = LogBatch::try_from(proto::LogBatch::from(&session)).unwrap();
+        assert!(back.verify());
+        assert_eq!(back.kind, session.kind);
+
+        let mut close = LogBatch {
+            logs: Vec::new(),
+            kind: Some(BatchKind::Closed {
+                reason: "decommissioned".into(),
+            }),
+            ..batch.clone()
+        };
+        close.sign(&key);
+        let back = LogBatch::try_from(proto::LogBatch::from(&close)).unwrap();
+        assert!(back.verify());
+        assert_eq!(back.kind, close.kind);
+        let mut both = proto::LogBatch::from(&session);
+        both.closed = proto::LogBatch::from(&close).closed;
+        assert_eq!(
+            LogBatch::try_from(both).unwrap_err(),
+            "a batch cannot be both a session start and a chain close"
+        );
+
+        let mut short = proto::LogBatch::from(&batch);
+        short.prev_hash.pop();
+        assert_eq!(
+            LogBatch::try_from(short).unwrap_err(),
+            "prev_hash must be 32 bytes, got 31"
+        );
+        let unversioned = proto::LogBatch {
+            version: 0,
+            ..proto::LogBatch::from(&batch)
+        };
+        assert_eq!(LogBatch::try_from(unversioned).unwrap().version, 1);
+    }
+}
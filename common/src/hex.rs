@@ -0,0 +1,186 @@
+//! Lowercase hex for hashes, keys and signatures, as the whole workspace
+//! writes and reads them. Decoding takes either case.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HexError {
+    /// Hex comes in pairs of digits.
+    OddLength(usize),
+    /// Even, but not the `expected` number of digits.
+    WrongLength { expected: usize, found: usize },
+    /// The character at byte offset `index` is not a hex digit.
+    InvalidChar { index: usize, found: char },
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HexError::OddLength(len) => write!(f, "odd number of hex digits ({len})"),
+            HexError::WrongLength { expected, found } => {
+                write!(f, "expected {expected} hex digits, found {found}")
+            }
+            HexError::InvalidChar { index, found } => {
+                write!(f, "invalid hex digit {found:?} at offset {index}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for HexError {}
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        out.push(DIGITS[(b >> 4) as usize] as char);
+        out.push(DIGITS[(b & 0xf) as usize] as char);
+    }
+    out
+}
+
+/// The value of one hex digit, either case.
+pub fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(10 + c - b'a'),
+        b'A'..=b'F' => Some(10 + c - b'A'),
+        _ => None,
+    }
+}
+
+/// Bytes from an even number of hex digits, with no surrounding space.
+pub fn hex_decode(hex: &str) -> Result<Vec<u8>, HexError> {
+    if let Some((index, found)) = hex
+        .char_indices()
+        .find(|(_, c)| !c.is_ascii() || hex_digit(*c as u8).is_none())
+    {
+        return Err(HexError::InvalidChar { index, found });
+    }
+    if !hex.len().is_multiple_of(2) {
+        return Err(HexError::OddLength(hex.len()));
+    }
+    let digit = |c: u8| hex_digit(c).expect("checked above");
+    Ok(hex
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| (digit(pair[0]) << 4) | digit(pair[1]))
+        .collect())
+}
+
+/// Exactly `N` bytes from `2 * N` hex digits, with no surrounding space.
+pub fn hex_decode_fixed<const N: usize>(hex: &str) -> Result<[u8; N], HexError> {
+    let bytes = hex_decode(hex)?;
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| HexError::WrongLength {
+            expected: N * 2,
+            found: bytes.len() * 2,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_in_lowercase_and_reads_either_case() {
+        let bytes = [0x00, 0x01, 0x7f, 0x80, 0xab, 0xff];
+        assert_eq!(hex_encode(&bytes), "00017f80abff");
+        assert_eq!(hex_decode_fixed::<6>("00017f80abff"), Ok(bytes));
+        assert_eq!(hex_decode_fixed::<6>("00017F80ABFF"), Ok(bytes));
+        assert_eq!(hex_encode(&[]), "");
+        assert_eq!(hex_decode_fixed::<0>(""), Ok([]));
+        assert_eq!(hex_decode("00017F80abff"), Ok(bytes.to_vec()));
+
+        let hash = [0x5a; 32];
+        assert_eq!(hex_decode_fixed::<32>(&hex_encode(&hash)), Ok(hash));
+    }
+
+    #[test]
+    fn malformed_hex_is_refused_with_the_reason() {
+        for (input, error) in [
+            ("abc", HexError::OddLength(3)),
+            ("a", HexError::OddLength(1)),
+            (
+                "abcd",
+                HexError::WrongLength {
+                    expected: 6,
+                    found: 4,
+                },
+            ),
+            (
+                "abcdef00",
+                HexError::WrongLength {
+                    expected: 6,
+                    found: 8,
+                },
+            ),
+            (
+                "abcdeg",
+                HexError::InvalidChar {
+                    index: 5,
+                    found: 'g',
+                },
+            ),
+            (
+                " abcde",
+                HexError::InvalidChar {
+                    index: 0,
+                    found: ' ',
+                },
+            ),
+            (
+                "abcdef\n",
+                HexError::InvalidChar {
+                    index: 6,
+                    found: '\n',
+                },
+            ),
+            (
+                "0x0102",
+                HexError::InvalidChar {
+                    index: 1,
+                    found: 'x',
+                },
+            ),
+            (
+                "ab+cde",
+                HexError::InvalidChar {
+                    index: 2,
+                    found: '+',
+                },
+            ),
+            // Multi-byte characters are reported, never sliced through.
+            (
+                "abé0",
+                HexError::InvalidChar {
+                    index: 2,
+                    found: 'é',
+                },
+            ),
+        ] {
+            assert_eq!(hex_decode_fixed::<3>(input), Err(error), "{input:?}");
+        }
+        assert_eq!(
+            HexError::WrongLength {
+                expected: 64,
+                found: 4
+            }
+            .to_string(),
+            "expected 64 hex digits, found 4"
+        );
+        assert_eq!(
+            HexError::InvalidChar {
+                index: 5,
+                found: 'g'
+            }
+            .to_string(),
+            "invalid hex digit 'g' at offset 5"
+        );
+        assert_eq!(
+            HexError::OddLength(3).to_string(),
+            "odd number of hex digits (3)"
+        );
+    }
+}
@@ -1 +1,25 @@
+pub mod address;
 pub mod batch;
+pub mod compat;
+pub mod export;
+pub mod export_header;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hex;
+#[cfg(feature = "parquet")]
+pub mod parquet_export;
+#[cfg(feature = "producer")]
+pub mod producer;
+pub mod receipt;
+pub mod redaction;
+pub mod request_id;
+pub mod rotation;
+pub mod serde_util;
+pub mod signing;
+pub mod summary;
+#[cfg(feature = "testkit")]
+pub mod testkit;
+#[cfg(feature = "testutil")]
+pub mod testutil;
+pub mod tsa;
+pub mod wire;
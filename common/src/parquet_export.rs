@@ -0,0 +1,272 @@
+use crate::batch::LogBatch;
+use crate::export::ParquetCompression;
+use arrow_array::RecordBatch;
+use arrow_array::builder::{
+    FixedSizeBinaryBuilder, Int64Builder, StringBuilder, TimestampMillisecondBuilder,
+    UInt32Builder, UInt64Builder,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use std::io::Write;
+use std::sync::Arc;
+
+/// Lines buffered before they are written out as one row group; bounds the
+/// exporter's memory independently of the export size.
+pub const PARQUET_ROW_GROUP_LINES: usize = 8192;
+
+/// Column layout of a Parquet export: one row per log line.
+pub fn parquet_schema() -> SchemaRef {
+    let utc_ms = DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into()));
+    Arc::new(Schema::new(vec![
+        Field::new("batch_id", DataType::Int64, false),
+        Field::new("agent_id", DataType::Utf8, false),
+        Field::new("seq", DataType::UInt64, false),
+        Field::new("line_idx", DataType::UInt32, false),
+        Field::new("timestamp", utc_ms.clone(), false),
+        Field::new("received_at", utc_ms, false),
+        Field::new("line", DataType::Utf8, false),
+        Field::new("batch_hash", DataType::FixedSizeBinary(32), false),
+    ]))
+}
+
+/// Writes stored batches to `out` as Parquet, one row per log line.
+///
+/// Rows are buffered up to [`PARQUET_ROW_GROUP_LINES`] and then written as a
+/// row group, so a caller streaming the output can drain `out` after every
+/// [`push`](Self::push) that returns `true`.
+pub struct ParquetExporter<W: Write + Send> {
+    writer: ArrowWriter<W>,
+    schema: SchemaRef,
+    rows: LineRows,
+}
+
+impl<W: Write + Send> ParquetExporter<W> {
+    pub fn new(out: W, compression: ParquetCompression) -> Result<Self, String> {
+        let compression = match compression {
+            ParquetCompression::Snappy => Compression::SNAPPY,
+            ParquetCompression::Zstd => Compression::ZSTD(ZstdLevel::default()),
+        };
+        let props = WriterProperties::builder()
+            .set_compression(compression)
+            .set_max_row_group_size(PARQUET_ROW_GROUP_LINES)
+            .build();
+        let schema = parquet_schema();
+        let writer =
+            ArrowWriter::try_new(out, schema.clone(), Some(props)).map_err(|e| e.to_string())?;
+        Ok(Self {
+            writer,
+            schema,
+            rows: LineRows::default(),
+        })
+    }
+
+    /// Buffers one row per line of `batch`; returns `true` when that filled a
+    /// row group and it was written to the output.
+    pub fn push(
+        &mut self,
+        batch_id: i64,
+        batch: &LogBatch,
+        hash: &[u8; 32],
+        received_at_ms: u64,
+    ) -> Result<bool, String> {
+        for (index, line) in batch.logs.iter().enumerate() {
+            self.rows.batch_id.append_value(batch_id);
+            self.rows.agent_id.append_value(&batch.agent_id);
+            self.rows.seq.append_value(batch.seq);
+            self.rows.line_idx.append_value(index as u32);
+            self.rows
+                .timestamp
+                .append_value(batch.timestamp_ms() as i64);
+            self.rows.received_at.append_value(received_at_ms as i64);
+            self.rows.line.append_value(line);
+            self.rows
+                .batch_hash
+                .append_value(hash)
+                .map_err(|e| e.to_string())?;
+            self.rows.len += 1;
+        }
+        if self.rows.len < PARQUET_ROW_GROUP_LINES {
+            return Ok(false);
+        }
+        self.flush_row_group()?;
+        Ok(true)
+    }
+
+    /// The output written so far; a streaming caller may take its bytes.
+    pub fn output_mut(&mut self) -> &mut W {
+        self.writer.inner_mut()
+    }
+
+    /// Writes the buffered rows and the file footer and returns the output.
+    pub fn finish(mut self) -> Result<W, String> {
+        self.flush_row_group()?;
+        self.writer.into_inner().map_err(|e| e.to_string())
+    }
+
+    fn flush_row_group(&mut self) -> Result<(), String> {
+        if self.rows.len == 0 {
+            return Ok(());
+        }
+        let record_batch = self.rows.finish(self.schema.clone())?;
+        self.writer
+            .write(&record_batch)
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Column builders for the row group being assembled.
+struct LineRows {
+    batch_id: Int64Builder,
+    agent_id: StringBuilder,
+    seq: UInt64Builder,
+    line_idx: UInt32Builder,
+    timestamp: TimestampMillisecondBuilder,
+    received_at: TimestampMillisecondBuilder,
+    line: StringBuilder,
+    batch_hash: FixedSizeBinaryBuilder,
+    len: usize,
+}
+
+impl Default for LineRows {
+    fn default() -> Self {
+        Self {
+            batch_id: Int64Builder::new(),
+            agent_id: StringBuilder::new(),
+            seq: UInt64Builder::new(),
+            line_idx: UInt32Builder::new(),
+            timestamp: TimestampMillisecondBuilder::new().with_timezone("UTC"),
+            received_at: TimestampMillisecondBuilder::new().with_timezone("UTC"),
+            line: StringBuilder::new(),
+            batch_hash: FixedSizeBinaryBuilder::new(32),
+            len: 0,
+        }
+    }
+}
+
+impl LineRows {
+    /// Drains the builders into a record batch.
+    fn finish(&mut self, schema: SchemaRef) -> Result<RecordBatch, String> {
+        self.len = 0;
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(self.batch_id.finish()),
+                Arc::new(self.agent_id.finish()),
+                Arc::new(self.seq.finish()),
+                Arc::new(self.line_idx.finish()),
+                Arc::new(self.timestamp.finish()),
+                Arc::new(self.received_at.finish()),
+                Arc::new(self.line.finish()),
+                Arc::new(self.batch_hash.finish()),
+            ],
+        )
+        .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::{CURRENT_BATCH_VERSION, generate_keypair};
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Int64Type, TimestampMillisecondType, UInt32Type, UInt64Type};
+    use ed25519_dalek::Signature;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    fn batch(seq: u64, lines: usize) -> LogBatch {
+        let key = generate_keypair();
+        let mut batch = LogBatch {
+            prev_hash: [0u8; 32],
+            logs: (0..lines).map(|i| format!("seq {seq} line {i}")).collect(),
+            timestamp: 1_700_000_000_000 + seq,
+            agent_id: "agent-a".into(),
+            seq,
+            signature: Signature::from_bytes(&[0u8; 64]),
+            public_key: key.verifying_key(),
+            lines_read: None,
+            version: CURRENT_BATCH_VERSION,
+            accumulator: None,
+            gap: None,
+            epoch: 0,
+            epoch_start: None,
+            kind: None,
+        };
+        batch.sign(&key);
+        batch
+    }
+
+    #[test]
+    fn round_trips_through_the_arrow_reader() {
+        // The third batch crosses the row-group size; the last two are
+        // written by `finish`.
+        let batches: Vec<LogBatch> = (1..=5).map(|seq| batch(seq, 4_000)).collect();
+        let hashes: Vec<[u8; 32]> = batches.iter().map(LogBatch::compute_hash).collect();
+        for compression in [ParquetCompression::Snappy, ParquetCompression::Zstd] {
+            let mut exporter = ParquetExporter::new(Vec::new(), compression).unwrap();
+            let mut flushed = 0;
+            for (id, batch) in batches.iter().enumerate() {
+                let received = 1_800_000_000_000 + id as u64;
+                if exporter
+                    .push(id as i64 + 10, batch, &hashes[id], received)
+                    .unwrap()
+                {
+                    flushed += 1;
+                    // Streaming callers drain after each row group.
+                    assert!(!exporter.output_mut().is_empty());
+                }
+            }
+            assert_eq!(flushed, 1);
+            let bytes = exporter.finish().unwrap();
+
+            let reader = ParquetRecordBatchReaderBuilder::try_new(bytes::Bytes::from(bytes))
+                .unwrap()
+                .build()
+                .unwrap();
+            let mut rows = 0;
+            for record_batch in reader {
+                let record_batch = record_batch.unwrap();
+                assert_eq!(record_batch.schema(), parquet_schema());
+                let columns = record_batch.columns();
+                for row in 0..record_batch.num_rows() {
+                    let (i, line_idx) = (rows / 4_000, rows % 4_000);
+                    let source = &batches[i];
+                    assert_eq!(
+                        columns[0].as_primitive::<Int64Type>().value(row),
+                        i as i64 + 10
+                    );
+                    assert_eq!(columns[1].as_string::<i32>().value(row), "agent-a");
+                    assert_eq!(
+                        columns[2].as_primitive::<UInt64Type>().value(row),
+                        source.seq
+                    );
+                    assert_eq!(
+                        columns[3].as_primitive::<UInt32Type>().value(row),
+                        line_idx as u32
+                    );
+                    assert_eq!(
+                        columns[4]
+                            .as_primitive::<TimestampMillisecondType>()
+                            .value(row),
+                        source.timestamp_ms() as i64
+                    );
+                    assert_eq!(
+                        columns[5]
+                            .as_primitive::<TimestampMillisecondType>()
+                            .value(row),
+                        1_800_000_000_000 + i as i64
+                    );
+                    assert_eq!(
+                        columns[6].as_string::<i32>().value(row),
+                        source.logs[line_idx]
+                    );
+                    assert_eq!(columns[7].as_fixed_size_binary().value(row), hashes[i]);
+                    rows += 1;
+                }
+            }
+            assert_eq!(rows, 20_000);
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//! Batch production as a library, for services that sign and ship their own
+//! lines instead of having the agent tail a file.
+//!
+//! A [`Producer`] buffers lines, seals them into batches chained onto the
+//! agent's last one and submits them over HTTP. It syncs with the server's
+//! checkpoint before its first batch and again after any conflict, so the
+//! server stays the source of truth for where the chain ends. The chain
+//! state is kept in a [`StateStore`]; [`FileStateStore`] keeps it in a JSON
+//! file. Every failure is a [`ProducerError`], and lines that failed to go
+//! out stay buffered for the next [`Producer::flush`].
+//!
+//! The agent shares [`Checkpoint`], [`Client::checkpoint`] and [`seal`] with
+//! it; retries, throttling, gzip and gRPC stay the agent's own.
+//!
+//! ```no_run
+//! # async fn ship() -> Result<(), common::producer::ProducerError> {
+//! use common::producer::{Client, FileStateStore, Producer};
+//!
+//! let key = common::batch::generate_keypair();
+//! let mut producer = Producer::new(key, "billing-1", Client::new("http://127.0.0.1:3000"))
+//!     .with_state_store(FileStateStore::new("billing-1.state.json"))
+//!     .with_batch_size(100);
+//! producer.log("invoice 42 paid").await?;
+//! producer.flush().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::batch::{CURRENT_BATCH_VERSION, LogBatch};
+use crate::receipt::Receipt;
+use ed25519_dalek::{Signature, SigningKey};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+/// What can go wrong producing a batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProducerError {
+    /// The server could not be reached, or answered something unreadable.
+    Transport(String),
+    /// The server refused the batch: `status` is the HTTP status and
+    /// `message` the reason it gave. A 409 makes the next flush resync.
+    Rejected { status: u16, message: String },
+    /// The server did not answer the checkpoint request with one.
+    Checkpoint(String),
+    /// The state store failed to load or save the chain state.
+    State(String),
+}
+
+impl fmt::Display for ProducerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProducerError::Transport(err) => write!(f, "cannot reach the server: {err}"),
+            ProducerError::Rejected { status, message } => {
+                write!(
+                    f,
+                    "server rejected the batch with status {status}: {message}"
+                )
+            }
+            ProducerError::Checkpoint(err) => write!(f, "checkpoint request failed: {err}"),
+            ProducerError::State(err) => write!(f, "chain state: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ProducerError {}
+
+/// An entry of `GET /batches/checkpoints`: where an agent's chain ends on
+/// the server.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Checkpoint {
+    pub agent_id: String,
+    pub last_seq: u64,
+    pub last_hash: [u8; 32],
+    pub count: u64,
+    /// Absent from servers that predate accumulators.
+    #[serde(default)]
+    pub last_accumulator: Option<[u8; 32]>,
+    /// Absent from servers that predate epochs.
+    #[serde(default)]
+    pub last_epoch: u64,
+}
+
+/// Where the producer's chain goes on: the position and links of the next
+/// batch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainState {
+    pub epoch: u64,
+    pub next_seq: u64,
+    pub prev_hash: [u8; 32],
+    pub accumulator: Option<[u8; 32]>,
+    /// Timestamp of the last batch, in unix milliseconds; the next one is
+    /// stamped later even if the clock is not.
+    pub last_timestamp_ms: u64,
+}
+
+impl Default for ChainState {
+    fn default() -> Self {
+        Self {
+            epoch: 0,
+            next_seq: 1,
+            prev_hash: [0u8; 32],
+            accumulator: None,
+            last_timestamp_ms: 0,
+        }
+    }
+}
+
+impl ChainState {
+    /// The state that continues the server's chain at `checkpoint`, or
+    /// starts it when the server holds none.
+    pub fn after(checkpoint: Option<&Checkpoint>, last_timestamp_ms: u64) -> Self {
+        match checkpoint {
+            Some(cp) => Self {
+                epoch: cp.last_epoch,
+                next_seq: cp.last_seq + 1,
+                prev_hash: cp.last_hash,
+                accumulator: cp.last_accumulator,
+                last_timestamp_ms,
+            },
+            None => Self {
+                last_timestamp_ms,
+                ..Self::default()
+            },
+        }
+    }
+}
+
+/// Where a producer keeps its [`ChainState`] between runs.
+pub trait StateStore: Send {
+    /// The saved state, or `None` before the first save.
+    fn load(&self) -> Result<Option<ChainState>, ProducerError>;
+    fn save(&mut self, state: &ChainState) -> Result<(), ProducerError>;
+}
+
+/// Keeps nothing between runs; the producer syncs from the server's
+/// checkpoint when it starts. The default store.
+#[derive(Debug, Default)]
+pub struct MemoryStateStore {
+    state: Option<ChainState>,
+}
+
+impl StateStore for MemoryStateStore {
+    fn load(&self) -> Result<Option<ChainState>, ProducerError> {
+        Ok(self.state.clone())
+    }
+
+    fn save(&mut self, state: &ChainState) -> Result<(), ProducerError> {
+        self.state = Some(state.clone());
+        Ok(())
+    }
+}
+
+/// Keeps the state as JSON in one file, replaced through a temporary file
+/// so a crash never leaves half of it.
+#[derive(Debug, Clone)]
+pub struct FileStateStore {
+    path: PathBuf,
+}
+
+impl FileStateStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl StateStore for FileStateStore {
+    fn load(&self) -> Result<Option<ChainState>, ProducerError> {
+        let raw = match fs::read(&self.path) {
+            Ok(raw) => raw,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(ProducerError::State(format!(
+                    "cannot read {}: {err}",
+                    self.path.display()
+                )));
+            }
+        };
+        serde_json::from_slice(&raw).map(Some).map_err(|err| {
+            ProducerError::State(format!("cannot parse {}: {err}", self.path.display()))
+        })
+    }
+
+    fn save(&mut self, state: &ChainState) -> Result<(), ProducerError> {
+        let tmp = self.path.with_extension("tmp");
+        let json =
+            serde_json::to_vec(state).map_err(|err| ProducerError::State(err.to_string()))?;
+        fs::write(&tmp, json)
+            .and_then(|()| fs::rename(&tmp, &self.path))
+            .map_err(|err| {
+                ProducerError::State(format!("cannot write {}: {err}", self.path.display()))
+            })
+    }
+}
+
+/// Sets `batch`'s accumulator to extend `previous_accumulator`, the last
+/// batch's, and signs it with `key`. Everything else must already be set.
+pub fn seal(batch: &mut LogBatch, key: &SigningKey, previous_accumulator: Option<&[u8; 32]>) {
+    batch.accumulator = Some(batch.expected_accumulator(previous_accumulator));
+    batch.sign(key);
+}
+
+#[derive(Deserialize)]
+struct SubmitAnswer {
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    receipt: Option<Receipt>,
+}
+
+/// The HTTP calls a producer makes.
+#[derive(Debug, Clone)]
+pub struct Client {
+    server_url: String,
+    http: reqwest::Client,
+}
+
+impl Client {
+    pub fn new(server_url: impl Into<String>) -> Self {
+        Self::with_http(server_url, reqwest::Client::new())
+    }
+
+    /// A client that sends through `http`, e.g. one with timeouts or TLS
+    /// settings of its own.
+    pub fn with_http(server_url: impl Into<String>, http: reqwest::Client) -> Self {
+        Self {
+            server_url: server_url.into().trim_end_matches('/').to_string(),
+            http,
+        }
+    }
+
+    /// Where `agent_id`'s chain ends on the server, if it holds any of it.
+    pub async fn checkpoint(&self, agent_id: &str) -> Result<Option<Checkpoint>, ProducerError> {
+        let resp = self
+            .http
+            .get(format!("{}/batches/checkpoints", self.server_url))
+            .query(&[("agent_id", agent_id)])
+            .send()
+            .await
+            .map_err(|err| ProducerError::Transport(err.to_string()))?;
+        if !resp.status().is_success() {
+            return Err(ProducerError::Checkpoint(format!(
+                "status {}",
+                resp.status()
+            )));
+        }
+        // Servers without the filter return every agent.
+        let checkpoints: Vec<Checkpoint> = resp
+            .json()
+            .await
+            .map_err(|err| ProducerError::Checkpoint(err.to_string()))?;
+        Ok(checkpoints.into_iter().find(|cp| cp.agent_id == agent_id))
+    }
+
+    /// Submits `batch`; an accepted one comes back with the server's
+    /// receipt, if it issued one.
+    pub async fn submit(&self, batch: &LogBatch) -> Result<Option<Receipt>, ProducerError> {
+        let resp = self
+            .http
+            .post(format!("{}/submit", self.server_url))
+            .json(batch)
+            .send()
+            .await
+            .map_err(|err| ProducerError::Transport(err.to_string()))?;
+        let status = resp.status();
+        let text = resp
+            .text()
+            .await
+            .map_err(|err| ProducerError::Transport(err.to_string()))?;
+        let answer = serde_json::from_str::<SubmitAnswer>(&text).ok();
+        if !status.is_success() {
+            return Err(ProducerError::Rejected {
+                status: status.as_u16(),
+                message: answer.map_or(text, |answer| answer.message),
+            });
+        }
+        Ok(answer.and_then(|answer| answer.receipt))
+    }
+}
+
+/// A batch the server accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sent {
+    pub epoch: u64,
+    pub seq: u64,
+    pub hash: [u8; 32],
+    pub lines: usize,
+    pub receipt: Option<Receipt>,
+}
+
+/// Signs lines into `agent_id`'s chain and ships them; see the
+/// [module docs](self).
+pub struct Producer {
+    key: SigningKey,
+    agent_id: String,
+    client: Client,
+    store: Box<dyn StateStore>,
+    batch_size: usize,
+    buffer: Vec<String>,
+    /// Set once the state matches the server's checkpoint.
+    state: Option<ChainState>,
+}
+
+impl Producer {
+    /// A producer with batches of 100 lines whose state lives in memory.
+    pub fn new(key: SigningKey, agent_id: impl Into<String>, client: Client) -> Self {
+        Self {
+            key,
+            agent_id: agent_id.into(),
+            client,
+            store: Box::new(MemoryStateStore::default()),
+            batch_size: 100,
+            buffer: Vec::new(),
+            state: None,
+        }
+    }
+
+    pub fn with_state_store(mut self, store: impl StateStore + 'static) -> Self {
+        self.store = Box::new(store);
+        self
+    }
+
+    /// Lines per batch; [`Producer::log`] sends once this many are buffered.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Buffers `line`, and sends the buffer once it holds a full batch.
+    pub async fn log(&mut self, line: impl Into<String>) -> Result<Option<Sent>, ProducerError> {
+        self.buffer.push(line.into());
+        if self.buffer.len() < self.batch_size {
+            return Ok(None);
+        }
+        self.flush().await
+    }
+
+    /// Sends whatever is buffered as one batch. On an error the lines stay
+    /// buffered for the next call.
+    pub async fn flush(&mut self) -> Result<Option<Sent>, ProducerError> {
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        let state = match self.state.take() {
+            Some(state) => state,
+            None => self.sync().await?,
+        };
+
+        let now_ms = chrono::Utc::now().timestamp_millis() as u64;
+        let timestamp = now_ms.max(state.last_timestamp_ms + 1);
+        let mut batch = LogBatch {
+            prev_hash: state.prev_hash,
+            logs: self.buffer.clone(),
+            timestamp,
+            agent_id: self.agent_id.clone(),
+            seq: state.next_seq,
+            signature: Signature::from_bytes(&[0u8; 64]),
+            public_key: self.key.verifying_key(),
+            lines_read: None,
+            version: CURRENT_BATCH_VERSION,
+            accumulator: None,
+            gap: None,
+            epoch: state.epoch,
+            epoch_start: None,
+            kind: None,
+        };
+        seal(&mut batch, &self.key, state.accumulator.as_ref());
+
+        let receipt = match self.client.submit(&batch).await {
+            Ok(receipt) => receipt,
+            Err(err) => {
+                // After a conflict the server's chain is not where ours
+                // ends; the next flush asks it again.
+                if !matches!(err, ProducerError::Rejected { status: 409, .. }) {
+                    self.state = Some(state);
+                }
+                return Err(err);
+            }
+        };
+        let hash = batch.compute_hash();
+        let next = ChainState {
+            epoch: batch.epoch,
+            next_seq: batch.seq + 1,
+            prev_hash: hash,
+            accumulator: batch.accumulator,
+            last_timestamp_ms: timestamp,
+        };
+        self.store.save(&next)?;
+        self.state = Some(next);
+        Ok(Some(Sent {
+            epoch: batch.epoch,
+            seq: batch.seq,
+            hash,
+            lines: std::mem::take(&mut self.buffer).len(),
+            receipt,
+        }))
+    }
+
+    /// Lines waiting for the next batch.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Adopts the server's checkpoint, saving it when it differs from the
+    /// stored state.
+    async fn sync(&mut self) -> Result<ChainState, ProducerError> {
+        let stored = self.store.load()?;
+        let checkpoint = self.client.checkpoint(&self.agent_id).await?;
+        let last_timestamp_ms = stored.as_ref().map_or(0, |state| state.last_timestamp_ms);
+        let state = ChainState::after(checkpoint.as_ref(), last_timestamp_ms);
+        if stored.as_ref() != Some(&state) {
+            self.store.save(&state)?;
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::generate_keypair;
+
+    #[test]
+    fn file_state_store_round_trips_and_starts_empty() {
+        let path = std::env::temp_dir().join(format!("producer-state-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut store = FileStateStore::new(&path);
+        assert_eq!(store.load(), Ok(None));
+        let state = ChainState {
+            epoch: 2,
+            next_seq: 8,
+            prev_hash: [3u8; 32],
+            accumulator: Some([4u8; 32]),
+            last_timestamp_ms: 1_700_000_000_000,
+        };
+        store.save(&state).unwrap();
+        assert_eq!(FileStateStore::new(&path).load(), Ok(Some(state)));
+
+        fs::write(&path, "{").unwrap();
+        assert!(matches!(store.load(), Err(ProducerError::State(_))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn state_after_a_checkpoint_continues_it() {
+        let cp = Checkpoint {
+            agent_id: "a".into(),
+            last_seq: 7,
+            last_hash: [5u8; 32],
+            count: 7,
+            last_accumulator: Some([6u8; 32]),
+            last_epoch: 1,
+        };
+        let state = ChainState::after(Some(&cp), 99);
+        assert_eq!(
+            (state.epoch, state.next_seq, state.prev_hash),
+            (1, 8, [5u8; 32])
+        );
+        assert_eq!(
+            (state.accumulator, state.last_timestamp_ms),
+            (Some([6u8; 32]), 99)
+        );
+        assert_eq!(ChainState::after(None, 0), ChainState::default());
+
+        let key = generate_keypair();
+        let mut batch = LogBatch {
+            prev_hash: [5u8; 32],
+            logs: vec!["line".into()],
+            timestamp: 1_700_000_000_000,
+            agent_id: "a".into(),
+            seq: 8,
+            signature: Signature::from_bytes(&[0u8; 64]),
+            public_key: key.verifying_key(),
+            lines_read: None,
+            version: CURRENT_BATCH_VERSION,
+            accumulator: None,
+            gap: None,
+            epoch: 1,
+            epoch_start: None,
+            kind: None,
+        };
+        seal(&mut batch, &key, Some(&[6u8; 32]));
+        assert_eq!(
+            batch.accumulator,
+            Some(batch.expected_accumulator(Some(&[6u8; 32])))
+        );
+        assert!(batch.verify());
+    }
+}
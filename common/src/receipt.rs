@@ -0,0 +1,168 @@
+use crate::serde_util::is_zero;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+/// The server's signed acknowledgment that it stored a batch.
+///
+/// Issued once, in the transaction that stores the batch, and served
+/// unchanged afterwards: `issued_at_ms` is the time of storage, never of a
+/// later request. `key_id` names the server key that signed it in the
+/// server's key history (`GET /server-keys`); that key must have been the
+/// active one at `issued_at_ms`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Receipt {
+    /// Row id of the batch on the server.
+    pub batch_id: i64,
+    pub agent_id: String,
+    pub seq: u64,
+    /// Epoch of the batch; absent (0) for batches of an agent's first epoch.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub epoch: u64,
+    pub hash: [u8; 32],
+    pub issued_at_ms: u64,
+    pub key_id: i64,
+    pub signature: Signature,
+}
+
+/// What the server key signs for the batch at `(epoch, seq)`:
+///
+/// - epoch 0: `receipt:v1:<batch_id>:<agent_id>:<seq>:<hash_hex>:<issued_at_ms>`
+/// - later epochs: `receipt:v2:<batch_id>:<agent_id>:<epoch>:<seq>:<hash_hex>:<issued_at_ms>`
+pub fn receipt_message(
+    batch_id: i64,
+    agent_id: &str,
+    (epoch, seq): (u64, u64),
+    hash: &[u8; 32],
+    issued_at_ms: u64,
+) -> Vec<u8> {
+    let hash_hex = crate::hex::hex_encode(hash);
+    match epoch {
+        0 => format!("receipt:v1:{batch_id}:{agent_id}:{seq}:{hash_hex}:{issued_at_ms}"),
+        _ => format!("receipt:v2:{batch_id}:{agent_id}:{epoch}:{seq}:{hash_hex}:{issued_at_ms}"),
+    }
+    .into_bytes()
+}
+
+impl Receipt {
+    pub fn issue(
+        key: &SigningKey,
+        key_id: i64,
+        batch_id: i64,
+        agent_id: &str,
+        (epoch, seq): (u64, u64),
+        hash: [u8; 32],
+        issued_at_ms: u64,
+    ) -> Self {
+        let signature = key.sign(&receipt_message(
+            batch_id,
+            agent_id,
+            (epoch, seq),
+            &hash,
+            issued_at_ms,
+        ));
+        Self {
+            batch_id,
+            agent_id: agent_id.to_string(),
+            seq,
+            epoch,
+            hash,
+            issued_at_ms,
+            key_id,
+            signature,
+        }
+    }
+
+    pub fn message(&self) -> Vec<u8> {
+        receipt_message(
+            self.batch_id,
+            &self.agent_id,
+            self.position(),
+            &self.hash,
+            self.issued_at_ms,
+        )
+    }
+
+    /// Position of the acknowledged batch in its agent's chain, `(epoch, seq)`.
+    pub fn position(&self) -> (u64, u64) {
+        (self.epoch, self.seq)
+    }
+
+    pub fn verify(&self, key: &VerifyingKey) -> bool {
+        key.verify(&self.message(), &self.signature).is_ok()
+    }
+}
+
+/// How a receipt compares with the batch the server now stores at the
+/// receipt's agent and position. Row ids are not compared: a server restored from
+/// a snapshot numbers the batches sent after the restore afresh.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptStanding {
+    /// The acknowledged batch is still stored.
+    Intact,
+    /// Another batch is stored at that position: the chain forked after the
+    /// acknowledged batch was lost.
+    HashDiffers,
+    /// Nothing is stored at that position.
+    Missing,
+}
+
+impl ReceiptStanding {
+    /// `stored_hash` is the hash stored at the receipt's agent and position.
+    pub fn of(receipt: &Receipt, stored_hash: Option<&[u8; 32]>) -> Self {
+        match stored_hash {
+            Some(hash) if *hash == receipt.hash => ReceiptStanding::Intact,
+            Some(_) => ReceiptStanding::HashDiffers,
+            None => ReceiptStanding::Missing,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReceiptStanding::Intact => "intact",
+            ReceiptStanding::HashDiffers => "hash_differs",
+            ReceiptStanding::Missing => "missing",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receipts_sign_every_field() {
+        assert_eq!(
+            receipt_message(3, "a", (0, 2), &[0xab; 32], 1_700_000_000_000),
+            format!("receipt:v1:3:a:2:{}:1700000000000", "ab".repeat(32)).into_bytes()
+        );
+        assert_eq!(
+            receipt_message(3, "a", (1, 2), &[0xab; 32], 1_700_000_000_000),
+            format!("receipt:v2:3:a:1:2:{}:1700000000000", "ab".repeat(32)).into_bytes()
+        );
+
+        let key = SigningKey::from_bytes(&[5; 32]);
+        let receipt = Receipt::issue(&key, 1, 3, "a", (0, 2), [0xab; 32], 1_700_000_000_000);
+        let json = serde_json::to_string(&receipt).unwrap();
+        assert!(!json.contains("epoch"));
+        assert_eq!(serde_json::from_str::<Receipt>(&json).unwrap(), receipt);
+        assert!(receipt.verify(&key.verifying_key()));
+        assert!(!receipt.verify(&SigningKey::from_bytes(&[6; 32]).verifying_key()));
+
+        let backdated = Receipt {
+            issued_at_ms: receipt.issued_at_ms - 1,
+            ..receipt.clone()
+        };
+        assert!(!backdated.verify(&key.verifying_key()));
+        let next_epoch = Receipt {
+            epoch: 1,
+            ..receipt.clone()
+        };
+        assert!(!next_epoch.verify(&key.verifying_key()));
+        let moved = Receipt {
+            batch_id: 4,
+            ..receipt
+        };
+        assert!(!moved.verify(&key.verifying_key()));
+    }
+}
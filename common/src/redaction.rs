@@ -0,0 +1,138 @@
+//! Redactions: the content of a stored line removed for good (an erasure
+//! request), with the chain still checkable. Only v3 batches can be redacted;
+//! they hash each line as a leaf (see [`crate::batch::LogBatch::line_leaf`]),
+//! so the removed line is replaced by a marker carrying its leaf, and the
+//! batch hashes as it was signed from the marker plus the remaining lines.
+//!
+//! A marker only counts where the server's redaction list names the line;
+//! anywhere else it is an ordinary line, hashed as such. Redacting a line can
+//! therefore hide it but never change it or any other line: a marker whose
+//! leaf is not the original one breaks the batch's signature.
+//!
+//! Each redaction is recorded with the admin who authorized it and signed by
+//! the server key, like a receipt (see [`crate::receipt`]).
+
+use crate::hex::{hex_decode_fixed, hex_encode};
+use crate::serde_util::is_zero;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+const MARKER_PREFIX: &str = "[redacted:";
+
+/// The line a redacted line is stored and served as: `[redacted:<leaf hex>]`.
+pub fn marker(leaf: &[u8; 32]) -> String {
+    format!("{MARKER_PREFIX}{}]", hex_encode(leaf))
+}
+
+/// The leaf `line` carries, if it has the shape of a [`marker`].
+pub fn parse_marker(line: &str) -> Option<[u8; 32]> {
+    hex_decode_fixed(line.strip_prefix(MARKER_PREFIX)?.strip_suffix(']')?).ok()
+}
+
+/// The server's signed record that a line's content was removed.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Redaction {
+    /// Row id of the batch on the server.
+    pub batch_id: i64,
+    pub agent_id: String,
+    /// Epoch of the batch; absent (0) for batches of an agent's first epoch.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub epoch: u64,
+    pub seq: u64,
+    pub line_idx: usize,
+    /// The removed line's leaf, which its marker carries.
+    pub commitment: [u8; 32],
+    /// The admin credential that asked for it: `bootstrap` for
+    /// `ADMIN_BEARER_TOKEN`, `token:<id>` for a minted token.
+    pub authorized_by: String,
+    pub redacted_at_ms: u64,
+    pub key_id: i64,
+    pub signature: Signature,
+}
+
+impl Redaction {
+    /// What the server key signs:
+    /// `redaction:v1:<batch_id>:<agent_id>:<epoch>:<seq>:<line_idx>:<commitment_hex>:<authorized_by>:<redacted_at_ms>`.
+    pub fn message(&self) -> Vec<u8> {
+        format!(
+            "redaction:v1:{}:{}:{}:{}:{}:{}:{}:{}",
+            self.batch_id,
+            self.agent_id,
+            self.epoch,
+            self.seq,
+            self.line_idx,
+            hex_encode(&self.commitment),
+            self.authorized_by,
+            self.redacted_at_ms
+        )
+        .into_bytes()
+    }
+
+    /// Signs the record with server key `key_id`.
+    pub fn sign(&mut self, key: &SigningKey, key_id: i64) {
+        self.key_id = key_id;
+        self.signature = key.sign(&self.message());
+    }
+
+    /// Position of the batch in its agent's chain, `(epoch, seq)`.
+    pub fn position(&self) -> (u64, u64) {
+        (self.epoch, self.seq)
+    }
+
+    pub fn verify(&self, key: &VerifyingKey) -> bool {
+        key.verify(&self.message(), &self.signature).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::generate_keypair;
+
+    #[test]
+    fn markers_round_trip_and_look_alikes_are_not_markers() {
+        let leaf = [0xa5; 32];
+        assert_eq!(marker(&leaf), format!("[redacted:{}]", "a5".repeat(32)));
+        assert_eq!(parse_marker(&marker(&leaf)), Some(leaf));
+        for line in [
+            "[redacted]",
+            "[redacted:a5]",
+            &format!("[redacted:{}]", "zz".repeat(32)),
+            &format!("[redacted:{}] trailing", "a5".repeat(32)),
+        ] {
+            assert_eq!(parse_marker(line), None, "{line}");
+        }
+    }
+
+    #[test]
+    fn redactions_sign_who_removed_what() {
+        let key = generate_keypair();
+        let mut redaction = Redaction {
+            batch_id: 7,
+            agent_id: "web-1".into(),
+            epoch: 0,
+            seq: 3,
+            line_idx: 2,
+            commitment: [9; 32],
+            authorized_by: "token:4".into(),
+            redacted_at_ms: 1_700,
+            key_id: 0,
+            signature: Signature::from_bytes(&[0u8; 64]),
+        };
+        redaction.sign(&key, 1);
+        assert_eq!(redaction.key_id, 1);
+        assert!(redaction.verify(&key.verifying_key()));
+        assert_eq!(
+            redaction.message(),
+            format!(
+                "redaction:v1:7:web-1:0:3:2:{}:token:4:1700",
+                "09".repeat(32)
+            )
+            .into_bytes()
+        );
+
+        let mut moved = redaction.clone();
+        moved.authorized_by = "bootstrap".into();
+        assert!(!moved.verify(&key.verifying_key()));
+    }
+}
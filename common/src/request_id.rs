@@ -0,0 +1,59 @@
+//! Request ids: a random UUID per request, sent as `X-Request-Id` so the
+//! sender's log line and the server's can be matched. The server adopts a
+//! well-formed incoming id and generates one otherwise.
+
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+pub const HEADER: &str = "x-request-id";
+
+/// Longest id the server adopts; longer ones are replaced.
+pub const MAX_LEN: usize = 128;
+
+/// A new random (version 4) UUID, lowercase and hyphenated.
+pub fn generate() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = crate::hex::hex_encode(&bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Whether `id` is safe to adopt: 1 to [`MAX_LEN`] ASCII letters, digits,
+/// `-`, `_` or `.`. UUIDs and the hex ids proxies generate qualify; nothing
+/// that could split a log line or a header does.
+pub fn is_well_formed(id: &str) -> bool {
+    (1..=MAX_LEN).contains(&id.len())
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_ids_are_v4_uuids_and_well_formed() {
+        let id = generate();
+        assert_eq!(id.len(), 36);
+        assert_eq!(id.as_bytes()[14], b'4');
+        assert!(matches!(id.as_bytes()[19], b'8' | b'9' | b'a' | b'b'));
+        assert!(is_well_formed(&id));
+        assert_ne!(id, generate());
+
+        assert!(is_well_formed("4bf92f3577b34da6a3ce929d0e0e4736"));
+        assert!(!is_well_formed(""));
+        assert!(!is_well_formed("a b"));
+        assert!(!is_well_formed("id\nforged=1"));
+        assert!(!is_well_formed(&"a".repeat(MAX_LEN + 1)));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::hex::hex_encode;
+use crate::signing::{self, ROTATION};
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use rand::Rng;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+
+/// What the current key signs to hand an agent over to a new key, in the
+/// string forms that came before [`rotation_envelope`]:
+///
+/// - v1, without `stamp`: `rotate:<agent_id>:<new_public_key_hex>:<counter>`
+/// - v2, with `stamp`: `rotate:v2:<agent_id>:<new_public_key_hex>:<counter>:<timestamp>:<nonce>`
+///
+/// Both are deprecated; the server takes each only behind its own flag.
+///
+/// `counter` is one more than the agent's last accepted rotation, so an
+/// accepted request can never be replayed. The v2 `timestamp` (unix seconds)
+/// also bounds how long a signed request stays usable: one that was captured
+/// and never delivered expires with the server's window instead of waiting
+/// for the next rotation. The `nonce` (see [`new_nonce`]) is refused a
+/// second time within that window.
+pub fn rotation_message(
+    agent_id: &str,
+    new_public_key_hex: &str,
+    counter: u64,
+    stamp: Option<(u64, &str)>,
+) -> Vec<u8> {
+    match stamp {
+        Some((ts, nonce)) => {
+            format!("rotate:v2:{agent_id}:{new_public_key_hex}:{counter}:{ts}:{nonce}")
+        }
+        None => format!("rotate:{agent_id}:{new_public_key_hex}:{counter}"),
+    }
+    .into_bytes()
+}
+
+/// A rotation request as [`signing`] envelopes sign it, type [`ROTATION`].
+/// The fields mean what they do in [`rotation_message`]; the key is its 32
+/// bytes, the nonce its hex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RotationPayload {
+    pub agent_id: String,
+    pub new_public_key: VerifyingKey,
+    pub counter: u64,
+    pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+/// What the current key signs to hand an agent over to `new_public_key`,
+/// in the signing envelope.
+pub fn rotation_envelope(
+    agent_id: &str,
+    new_public_key: &VerifyingKey,
+    counter: u64,
+    timestamp: u64,
+    nonce: Option<&str>,
+) -> Vec<u8> {
+    let payload = RotationPayload {
+        agent_id: agent_id.to_string(),
+        new_public_key: *new_public_key,
+        counter,
+        timestamp,
+        nonce: nonce.map(str::to_string),
+    };
+    signing::envelope(ROTATION, &payload)
+        .expect("a rotation payload holds no floats or repeated keys")
+}
+
+/// Bytes in a rotation nonce.
+pub const NONCE_BYTES: usize = 16;
+
+/// A fresh random rotation nonce, in hex.
+pub fn new_nonce() -> String {
+    let mut nonce = [0u8; NONCE_BYTES];
+    OsRng.fill(&mut nonce);
+    hex_encode(&nonce)
+}
+
+/// The body of `POST /agents/rotate`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RotateRequest {
+    pub agent_id: String,
+    pub new_public_key_hex: String,
+    /// Must be exactly one more than the agent's last accepted rotation (the
+    /// first rotation uses 1); signed into the message so captured requests
+    /// cannot be replayed.
+    pub counter: u64,
+    /// Unix seconds, signed into the envelope or the v2 message; absent in
+    /// deprecated v1 requests.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// Signed in with `timestamp`; required with it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    pub auth_signature_hex: String,
+}
+
+impl RotateRequest {
+    /// A v2 request, signed in the envelope by `current`, to hand
+    /// `agent_id` over to `new_public_key`, dated `timestamp` and carrying
+    /// a fresh nonce.
+    pub fn signed(
+        current: &SigningKey,
+        agent_id: &str,
+        new_public_key: &VerifyingKey,
+        counter: u64,
+        timestamp: u64,
+    ) -> Self {
+        let nonce = new_nonce();
+        let envelope =
+            rotation_envelope(agent_id, new_public_key, counter, timestamp, Some(&nonce));
+        Self {
+            agent_id: agent_id.to_string(),
+            new_public_key_hex: hex_encode(new_public_key.as_bytes()),
+            counter,
+            timestamp: Some(timestamp),
+            nonce: Some(nonce),
+            auth_signature_hex: hex_encode(&current.sign(&envelope).to_bytes()),
+        }
+    }
+}
+
+/// What a key signs to prove its holder asked for `agent_id` to be
+/// registered with it: `register:<agent_id>:<public_key_hex>`.
+pub fn registration_message(agent_id: &str, public_key_hex: &str) -> Vec<u8> {
+    format!("register:{agent_id}:{public_key_hex}").into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_formats_are_stable() {
+        assert_eq!(rotation_message("a", "ff", 2, None), b"rotate:a:ff:2");
+        assert_eq!(
+            rotation_message("a", "ff", 2, Some((1_700_000_000, "0a"))),
+            b"rotate:v2:a:ff:2:1700000000:0a"
+        );
+    }
+
+    #[test]
+    fn signed_requests_carry_a_fresh_nonce_in_the_envelope() {
+        use ed25519_dalek::{Signature, Verifier};
+
+        let (current, new) = (
+            SigningKey::from_bytes(&[1; 32]),
+            SigningKey::from_bytes(&[2; 32]),
+        );
+        let req = RotateRequest::signed(&current, "a", &new.verifying_key(), 3, 1_700_000_000);
+        let again = RotateRequest::signed(&current, "a", &new.verifying_key(), 3, 1_700_000_000);
+        let nonce = req.nonce.clone().unwrap();
+        assert_eq!(nonce.len(), NONCE_BYTES * 2);
+        assert_ne!(again.nonce, req.nonce);
+
+        let signature: [u8; 64] = crate::hex::hex_decode(&req.auth_signature_hex)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let envelope = rotation_envelope("a", &new.verifying_key(), 3, 1_700_000_000, Some(&nonce));
+        assert!(
+            current
+                .verifying_key()
+                .verify(&envelope, &Signature::from_bytes(&signature))
+                .is_ok()
+        );
+        let without = rotation_envelope("a", &new.verifying_key(), 3, 1_700_000_000, None);
+        assert!(
+            current
+                .verifying_key()
+                .verify(&without, &Signature::from_bytes(&signature))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,7 @@
+//! Predicates for `#[serde(skip_serializing_if = "...")]`, shared so every
+//! wire type in the workspace omits its defaults the same way.
+
+/// Whether a counter or epoch is at its default and can be left out.
+pub fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
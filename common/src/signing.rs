@@ -0,0 +1,213 @@
+//! The one signing envelope for signed artifacts, so no two of them can be
+//! confused for each other however their payloads look. What a key signs is
+//! the canonical CBOR encoding of the array
+//!
+//! ```text
+//! ["logchain-sig", 1, <type id>, <payload>]
+//! ```
+//!
+//! a domain-separation tag, the envelope version, the artifact's type id
+//! (see [`ROTATION`]) and its payload. Canonical means RFC 8949's core
+//! deterministic encoding: shortest integer and length forms, definite
+//! lengths only, and map entries sorted by the bytes of their encoded keys.
+//! Payloads hold no floats, and no map repeats a key.
+//!
+//! Key rotation signs its request this way (see
+//! [`crate::rotation::rotation_envelope`]); batches keep signing their hash
+//! until a batch version adopts it. `common/testdata/signing_vectors.json`
+//! holds hex fixtures for other implementations to check against.
+
+use ciborium::value::Value;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::Serialize;
+
+/// First element of every envelope.
+pub const DOMAIN: &str = "logchain-sig";
+pub const ENVELOPE_V1: u64 = 1;
+
+/// Type id of a key rotation request; see [`crate::rotation::RotationPayload`].
+pub const ROTATION: &str = "rotation";
+
+/// The canonical CBOR encoding of `payload`.
+pub fn canonical_cbor<T: Serialize>(payload: &T) -> Result<Vec<u8>, String> {
+    let value =
+        Value::serialized(payload).map_err(|err| format!("cannot encode payload: {err}"))?;
+    let mut out = Vec::new();
+    ciborium::into_writer(&canonical(value)?, &mut out)
+        .map_err(|err| format!("cannot encode payload: {err}"))?;
+    Ok(out)
+}
+
+/// `value` with every map sorted; floats and repeated keys are refused.
+fn canonical(value: Value) -> Result<Value, String> {
+    Ok(match value {
+        Value::Float(_) => return Err("payloads may not hold floats".into()),
+        Value::Array(items) => {
+            Value::Array(items.into_iter().map(canonical).collect::<Result<_, _>>()?)
+        }
+        Value::Tag(tag, inner) => Value::Tag(tag, Box::new(canonical(*inner)?)),
+        Value::Map(entries) => {
+            let mut keyed = entries
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = canonical(key)?;
+                    let mut encoded = Vec::new();
+                    ciborium::into_writer(&key, &mut encoded).map_err(|err| err.to_string())?;
+                    Ok((encoded, key, canonical(value)?))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            keyed.sort_by(|a, b| a.0.cmp(&b.0));
+            if keyed.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+                return Err("a payload map repeats a key".into());
+            }
+            Value::Map(
+                keyed
+                    .into_iter()
+                    .map(|(_, key, value)| (key, value))
+                    .collect(),
+            )
+        }
+        other => other,
+    })
+}
+
+/// The bytes signed for a `type_id` artifact with `payload`.
+pub fn envelope<T: Serialize>(type_id: &str, payload: &T) -> Result<Vec<u8>, String> {
+    let payload =
+        Value::serialized(payload).map_err(|err| format!("cannot encode payload: {err}"))?;
+    canonical_cbor(&Value::Array(vec![
+        Value::Text(DOMAIN.into()),
+        Value::Integer(ENVELOPE_V1.into()),
+        Value::Text(type_id.into()),
+        payload,
+    ]))
+}
+
+pub fn sign_envelope<T: Serialize>(
+    key: &SigningKey,
+    type_id: &str,
+    payload: &T,
+) -> Result<Signature, String> {
+    Ok(key.sign(&envelope(type_id, payload)?))
+}
+
+/// Checks that `key` signed `payload` as a `type_id` artifact.
+pub fn verify_envelope<T: Serialize>(
+    key: &VerifyingKey,
+    type_id: &str,
+    payload: &T,
+    signature: &Signature,
+) -> Result<(), String> {
+    key.verify(&envelope(type_id, payload)?, signature)
+        .map_err(|_| format!("the {type_id} signature does not verify"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex::{hex_decode, hex_encode};
+    use crate::rotation::RotationPayload;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Deserialize)]
+    struct Vector {
+        name: String,
+        type_id: String,
+        signer_seed: String,
+        /// The payload as JSON, or for `rotation` the fields of a
+        /// [`RotationPayload`] with the new key in hex.
+        payload: serde_json::Value,
+        payload_cbor: String,
+        message: String,
+        signature: String,
+    }
+
+    fn rotation_payload(fields: &serde_json::Value) -> RotationPayload {
+        let key: [u8; 32] = hex_decode(fields["new_public_key"].as_str().unwrap())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        RotationPayload {
+            agent_id: fields["agent_id"].as_str().unwrap().into(),
+            new_public_key: VerifyingKey::from_bytes(&key).unwrap(),
+            counter: fields["counter"].as_u64().unwrap(),
+            timestamp: fields["timestamp"].as_u64().unwrap(),
+            nonce: fields
+                .get("nonce")
+                .map(|nonce| nonce.as_str().unwrap().into()),
+        }
+    }
+
+    #[test]
+    fn the_checked_in_vectors_still_hold() {
+        let vectors: Vec<Vector> =
+            serde_json::from_str(include_str!("../testdata/signing_vectors.json")).unwrap();
+        assert!(vectors.len() >= 3);
+        for v in &vectors {
+            let seed: [u8; 32] = hex_decode(&v.signer_seed).unwrap().try_into().unwrap();
+            let key = SigningKey::from_bytes(&seed);
+            let (cbor, message, signature) = if v.type_id == ROTATION {
+                let payload = rotation_payload(&v.payload);
+                (
+                    canonical_cbor(&payload),
+                    envelope(&v.type_id, &payload),
+                    sign_envelope(&key, &v.type_id, &payload),
+                )
+            } else {
+                let payload = &v.payload;
+                (
+                    canonical_cbor(payload),
+                    envelope(&v.type_id, payload),
+                    sign_envelope(&key, &v.type_id, payload),
+                )
+            };
+            assert_eq!(hex_encode(&cbor.unwrap()), v.payload_cbor, "{}", v.name);
+            assert_eq!(hex_encode(&message.unwrap()), v.message, "{}", v.name);
+            let signature = signature.unwrap();
+            assert_eq!(hex_encode(&signature.to_bytes()), v.signature, "{}", v.name);
+            let message = hex_decode(&v.message).unwrap();
+            assert!(
+                key.verifying_key().verify(&message, &signature).is_ok(),
+                "{}",
+                v.name
+            );
+        }
+    }
+
+    #[test]
+    fn envelopes_are_canonical_and_bound_to_their_type() {
+        // Field order does not matter; key encoding does: shorter keys first.
+        let mut map = BTreeMap::new();
+        map.insert("zeta", 1);
+        map.insert("a", 2);
+        map.insert("bb", 3);
+        assert_eq!(
+            hex_encode(&canonical_cbor(&map).unwrap()),
+            "a361610262626203647a65746101"
+        );
+        #[derive(Serialize)]
+        struct Reordered {
+            bb: u8,
+            zeta: u8,
+            a: u8,
+        }
+        let reordered = Reordered {
+            bb: 3,
+            zeta: 1,
+            a: 2,
+        };
+        assert_eq!(
+            canonical_cbor(&reordered).unwrap(),
+            canonical_cbor(&map).unwrap()
+        );
+
+        let key = SigningKey::from_bytes(&[5; 32]);
+        let signature = sign_envelope(&key, "test", &map).unwrap();
+        assert!(verify_envelope(&key.verifying_key(), "test", &map, &signature).is_ok());
+        assert!(verify_envelope(&key.verifying_key(), ROTATION, &map, &signature).is_err());
+        map.insert("a", 9);
+        assert!(verify_envelope(&key.verifying_key(), "test", &map, &signature).is_err());
+        assert!(canonical_cbor(&1.5f64).unwrap_err().contains("floats"));
+    }
+}
@@ -0,0 +1,256 @@
+//! Per-agent daily summaries: what stays of old batches once their content
+//! is gone. A day is a UTC calendar day of server arrival time, so a day
+//! that has ended never gains another batch. The Merkle root commits to the
+//! day's batch hashes in chain order, the way RFC 6962 builds a tree, so an
+//! archive of the day can be checked against the summary alone.
+
+use chrono::{DateTime, NaiveDate};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const DAY_MS: i64 = 24 * 3600 * 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DailySummary {
+    pub agent_id: String,
+    /// `YYYY-MM-DD`, UTC.
+    pub day: String,
+    pub batches: u64,
+    /// Log lines over the day's batches.
+    pub lines: u64,
+    /// Seqs of the day's first and last batch. Across an epoch start (see
+    /// [`crate::batch::EpochStart`]) `max_seq` can be below `min_seq`.
+    pub min_seq: u64,
+    pub max_seq: u64,
+    /// Hash of the day's last batch, `max_seq`.
+    pub head_hash: [u8; 32],
+    /// [`merkle_root`] over the day's batch hashes in (epoch, seq) order.
+    pub merkle_root: [u8; 32],
+}
+
+/// One batch as far as its summary is concerned.
+#[derive(Debug, Clone, Copy)]
+pub struct SummaryEntry {
+    pub epoch: u64,
+    pub seq: u64,
+    pub hash: [u8; 32],
+    pub lines: u64,
+}
+
+/// Start of the UTC day holding `ms`.
+pub fn day_start_ms(ms: i64) -> i64 {
+    ms - ms.rem_euclid(DAY_MS)
+}
+
+/// The `YYYY-MM-DD` of the UTC day holding `ms`.
+pub fn day_of(ms: i64) -> String {
+    DateTime::from_timestamp_millis(day_start_ms(ms))
+        .map(|at| at.format("%Y-%m-%d").to_string())
+        .unwrap_or_default()
+}
+
+/// Start of the UTC day `day` (`YYYY-MM-DD`) in unix ms.
+pub fn day_start_of(day: &str) -> Option<i64> {
+    let date = NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp_millis())
+}
+
+/// The Merkle tree hash of RFC 6962 over `hashes`: leaves are
+/// `SHA-256(0x00 || hash)`, inner nodes `SHA-256(0x01 || left || right)`,
+/// and a list splits before its largest power of two. Empty is `SHA-256("")`.
+pub fn merkle_root(hashes: &[[u8; 32]]) -> [u8; 32] {
+    match hashes {
+        [] => Sha256::digest([]).into(),
+        [leaf] => {
+            let mut hasher = Sha256::new();
+            hasher.update([0u8]);
+            hasher.update(leaf);
+            hasher.finalize().into()
+        }
+        _ => {
+            let split = split_point(hashes.len());
+            merkle_node(merkle_root(&hashes[..split]), merkle_root(&hashes[split..]))
+        }
+    }
+}
+
+/// The largest power of two below `len`, for `len > 1`.
+fn split_point(len: usize) -> usize {
+    1 << (usize::BITS - 1 - (len - 1).leading_zeros())
+}
+
+fn merkle_node(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([1u8]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// The RFC 6962 audit path of `hashes[index]`: the sibling subtree roots
+/// from the leaf up. Empty for a one-leaf tree; `index` must be in range.
+pub fn merkle_path(hashes: &[[u8; 32]], index: usize) -> Vec<[u8; 32]> {
+    if hashes.len() <= 1 {
+        return Vec::new();
+    }
+    let split = split_point(hashes.len());
+    let (mut path, sibling) = if index < split {
+        (
+            merkle_path(&hashes[..split], index),
+            merkle_root(&hashes[split..]),
+        )
+    } else {
+        (
+            merkle_path(&hashes[split..], index - split),
+            merkle_root(&hashes[..split]),
+        )
+    };
+    path.push(sibling);
+    path
+}
+
+/// The root a [`merkle_path`] leads to from `hash` at `index` of a tree of
+/// `size` leaves; `None` when the path does not fit that position.
+pub fn merkle_root_from_path(
+    hash: &[u8; 32],
+    index: usize,
+    size: usize,
+    path: &[[u8; 32]],
+) -> Option<[u8; 32]> {
+    if index >= size {
+        return None;
+    }
+    if size == 1 {
+        return path
+            .is_empty()
+            .then(|| merkle_root(std::slice::from_ref(hash)));
+    }
+    let split = split_point(size);
+    let (sibling, rest) = path.split_last()?;
+    Some(if index < split {
+        merkle_node(merkle_root_from_path(hash, index, split, rest)?, *sibling)
+    } else {
+        merkle_node(
+            *sibling,
+            merkle_root_from_path(hash, index - split, size - split, rest)?,
+        )
+    })
+}
+
+impl DailySummary {
+    /// Summarizes `entries`, one agent's batches of one day, in any order.
+    /// `None` when there are none.
+    pub fn of(agent_id: &str, day: &str, entries: &[SummaryEntry]) -> Option<Self> {
+        let mut entries = entries.to_vec();
+        entries.sort_by_key(|entry| (entry.epoch, entry.seq));
+        let (first, last) = (entries.first()?, entries.last()?);
+        let hashes: Vec<[u8; 32]> = entries.iter().map(|entry| entry.hash).collect();
+        Some(Self {
+            agent_id: agent_id.to_string(),
+            day: day.to_string(),
+            batches: entries.len() as u64,
+            lines: entries.iter().map(|entry| entry.lines).sum(),
+            min_seq: first.seq,
+            max_seq: last.seq,
+            head_hash: last.hash,
+            merkle_root: merkle_root(&hashes),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(hash: &[u8; 32]) -> [u8; 32] {
+        merkle_root(std::slice::from_ref(hash))
+    }
+
+    fn node(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update([1u8]);
+        hasher.update(left);
+        hasher.update(right);
+        hasher.finalize().into()
+    }
+
+    #[test]
+    fn merkle_root_splits_before_the_largest_power_of_two() {
+        let h: Vec<[u8; 32]> = (0..5u8).map(|i| [i; 32]).collect();
+        let four = node(
+            node(leaf(&h[0]), leaf(&h[1])),
+            node(leaf(&h[2]), leaf(&h[3])),
+        );
+        assert_eq!(merkle_root(&h[..4]), four);
+        assert_eq!(merkle_root(&h), node(four, leaf(&h[4])));
+        assert_eq!(
+            merkle_root(&h[..3]),
+            node(node(leaf(&h[0]), leaf(&h[1])), leaf(&h[2]))
+        );
+        // Any change in order or content changes the root.
+        let mut swapped = h.clone();
+        swapped.swap(1, 2);
+        assert_ne!(merkle_root(&swapped), merkle_root(&h));
+    }
+
+    #[test]
+    fn merkle_paths_lead_every_leaf_to_the_root() {
+        for size in 1..=9usize {
+            let h: Vec<[u8; 32]> = (0..size as u8).map(|i| [i; 32]).collect();
+            let root = merkle_root(&h);
+            for index in 0..size {
+                let path = merkle_path(&h, index);
+                assert_eq!(
+                    merkle_root_from_path(&h[index], index, size, &path),
+                    Some(root)
+                );
+                // The leaf, its position and the path are all bound.
+                assert_ne!(
+                    merkle_root_from_path(&[99; 32], index, size, &path),
+                    Some(root)
+                );
+                if size > 1 {
+                    assert_ne!(
+                        merkle_root_from_path(&h[index], (index + 1) % size, size, &path),
+                        Some(root)
+                    );
+                }
+            }
+            assert_eq!(merkle_root_from_path(&h[0], size, size, &[]), None);
+        }
+        let h = [[1; 32], [2; 32], [3; 32]];
+        assert_eq!(merkle_path(&h, 2), vec![merkle_root(&h[..2])]);
+        assert_eq!(merkle_root_from_path(&h[2], 2, 3, &[]), None);
+    }
+
+    #[test]
+    fn summaries_cover_one_utc_day_in_seq_order() {
+        assert_eq!(day_of(0), "1970-01-01");
+        assert_eq!(day_of(DAY_MS - 1), "1970-01-01");
+        assert_eq!(day_of(DAY_MS), "1970-01-02");
+        assert_eq!(day_start_ms(DAY_MS + 5), DAY_MS);
+        assert_eq!(day_start_of("1970-01-02"), Some(DAY_MS));
+        assert_eq!(day_start_of("yesterday"), None);
+
+        let entries = [
+            SummaryEntry {
+                epoch: 0,
+                seq: 8,
+                hash: [8; 32],
+                lines: 2,
+            },
+            SummaryEntry {
+                epoch: 0,
+                seq: 7,
+                hash: [7; 32],
+                lines: 3,
+            },
+        ];
+        let summary = DailySummary::of("a", "2026-10-17", &entries).unwrap();
+        assert_eq!((summary.batches, summary.lines), (2, 5));
+        assert_eq!((summary.min_seq, summary.max_seq), (7, 8));
+        assert_eq!(summary.head_hash, [8; 32]);
+        assert_eq!(summary.merkle_root, merkle_root(&[[7; 32], [8; 32]]));
+        assert_eq!(DailySummary::of("a", "2026-10-17", &[]), None);
+    }
+}
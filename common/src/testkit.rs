@@ -0,0 +1,439 @@
+//! Deterministic test data: a [`ChainSimulator`] seeded with a `u64` plays a
+//! fleet of agents, each with its own key, shipping realistic log lines in
+//! correctly chained, signed batches, and injects labelled faults on demand.
+//! Behind the `testkit` feature; tests use it, and so does `cli loadgen` to
+//! put a fleet's worth of load on a server. Never part of the agent or server.
+//!
+//! Everything (keys, lines, fault targets) comes from one seeded RNG, so the
+//! same seed and the same calls give byte-identical batches. A failing test
+//! can therefore print its seed and be replayed exactly.
+//!
+//! Batches are sealed and faults planted with the [`crate::testutil`]
+//! helpers, so a simulated chain links, and breaks, exactly like one from
+//! [`crate::testutil::build_chain`].
+
+use crate::batch::{CURRENT_BATCH_VERSION, LogBatch};
+use crate::testutil::{self, CHAIN_EPOCH_MS};
+use ed25519_dalek::{Signature, SigningKey};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+const LEVELS: [&str; 5] = ["DEBUG", "INFO", "INFO", "WARN", "ERROR"];
+const SERVICES: [&str; 6] = ["sshd", "nginx", "kernel", "cron", "postgres", "app"];
+const USERS: [&str; 5] = ["alice", "bob", "deploy", "root", "svc-backup"];
+const WORDS: [&str; 16] = [
+    "request",
+    "completed",
+    "session",
+    "opened",
+    "closed",
+    "for",
+    "user",
+    "from",
+    "timeout",
+    "retrying",
+    "connection",
+    "reset",
+    "by",
+    "peer",
+    "cache",
+    "miss",
+];
+
+/// One simulated agent: its id, its key and the last batch it shipped.
+pub struct SimAgent {
+    pub agent_id: String,
+    pub key: SigningKey,
+    last: Option<LogBatch>,
+    lines_read: u64,
+}
+
+/// A fault [`ChainSimulator::inject`] can plant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// One log line altered, the signature kept; see
+    /// [`testutil::flip_line`].
+    BitFlip,
+    /// A batch removed, so its successor's seq skips one.
+    SeqGap,
+    /// A batch's `prev_hash` replaced and re-signed by the agent's own key;
+    /// see [`testutil::relink`].
+    WrongPrevHash,
+    /// A batch re-signed by a key the agent never had; see
+    /// [`testutil::resign_with`].
+    ForeignSignature,
+}
+
+/// What [`ChainSimulator::inject`] did, to assert on and to print when a
+/// test fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InjectedFault {
+    pub fault: Fault,
+    pub agent_id: String,
+    /// The batch tampered with (for [`Fault::SeqGap`], the one removed).
+    pub seq: u64,
+    pub label: String,
+}
+
+impl fmt::Display for InjectedFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.label)
+    }
+}
+
+/// Seeded generator of agents, chains and faults.
+pub struct ChainSimulator {
+    seed: u64,
+    rng: StdRng,
+    agents: Vec<SimAgent>,
+    line_len: RangeInclusive<usize>,
+    json_percent: u8,
+    lines_per_batch: RangeInclusive<usize>,
+    tick: u64,
+    start_ms: u64,
+    tick_ms: u64,
+}
+
+impl ChainSimulator {
+    /// `agents` agents named `sim-agent-000`, `sim-agent-001`, ..., with
+    /// keys drawn from `seed`. Lines aim for 40 to 160 bytes, 30% of them JSON,
+    /// 1 to 8 per batch, until changed. Batch timestamps start at
+    /// [`CHAIN_EPOCH_MS`] and step one second per batch shipped by any agent.
+    pub fn new(seed: u64, agents: usize) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let agents = (0..agents)
+            .map(|i| {
+                let mut secret = [0u8; 32];
+                rng.fill_bytes(&mut secret);
+                SimAgent {
+                    agent_id: format!("sim-agent-{i:03}"),
+                    key: SigningKey::from_bytes(&secret),
+                    last: None,
+                    lines_read: 0,
+                }
+            })
+            .collect();
+        ChainSimulator {
+            seed,
+            rng,
+            agents,
+            line_len: 40..=160,
+            json_percent: 30,
+            lines_per_batch: 1..=8,
+            tick: 0,
+            start_ms: CHAIN_EPOCH_MS,
+            tick_ms: 1_000,
+        }
+    }
+
+    /// Names the agents `<prefix>-000`, `<prefix>-001`, ... instead, e.g. so
+    /// fleets from different seeds can share one server.
+    pub fn agent_prefix(mut self, prefix: &str) -> Self {
+        for (i, sim) in self.agents.iter_mut().enumerate() {
+            sim.agent_id = format!("{prefix}-{i:03}");
+        }
+        self
+    }
+
+    /// Timestamps from `start_ms` on, `tick_ms` apart from one batch to the next.
+    pub fn clock(mut self, start_ms: u64, tick_ms: u64) -> Self {
+        self.start_ms = start_ms;
+        self.tick_ms = tick_ms;
+        self
+    }
+
+    /// Target length of each line in bytes. A line never gets shorter than
+    /// its fixed fields (timestamp, level, service, pid, user): up to 65
+    /// bytes as text and 115 as JSON.
+    pub fn line_len(mut self, len: RangeInclusive<usize>) -> Self {
+        assert!(!len.is_empty(), "empty line length range");
+        self.line_len = len;
+        self
+    }
+
+    /// Share of lines written as JSON objects rather than syslog-style text.
+    pub fn json_percent(mut self, percent: u8) -> Self {
+        self.json_percent = percent.min(100);
+        self
+    }
+
+    pub fn lines_per_batch(mut self, lines: RangeInclusive<usize>) -> Self {
+        assert!(
+            !lines.is_empty() && *lines.start() > 0,
+            "batches need at least one line"
+        );
+        self.lines_per_batch = lines;
+        self
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn agents(&self) -> &[SimAgent] {
+        &self.agents
+    }
+
+    /// The next batch of agent number `agent`, linked to its previous one.
+    pub fn next_batch(&mut self, agent: usize) -> LogBatch {
+        self.tick += 1;
+        let timestamp = self.start_ms + self.tick * self.tick_ms;
+        let count = self.rng.gen_range(self.lines_per_batch.clone());
+        let logs: Vec<String> = (0..count)
+            .map(|i| self.line(timestamp + i as u64))
+            .collect();
+
+        let sim = &mut self.agents[agent];
+        sim.lines_read += logs.len() as u64;
+        let previous = sim.last.as_ref();
+        let mut batch = LogBatch {
+            prev_hash: [0u8; 32],
+            logs,
+            timestamp,
+            agent_id: sim.agent_id.clone(),
+            seq: previous.map_or(1, |b| b.seq + 1),
+            signature: Signature::from_bytes(&[0u8; 64]),
+            public_key: sim.key.verifying_key(),
+            lines_read: Some(sim.lines_read),
+            version: CURRENT_BATCH_VERSION,
+            accumulator: None,
+            gap: None,
+            epoch: 0,
+            epoch_start: None,
+            kind: None,
+        };
+        testutil::seal(&mut batch, previous, &sim.key);
+        sim.last = Some(batch.clone());
+        batch
+    }
+
+    /// `batches_per_agent` batches from every agent, interleaved as they
+    /// would reach a server: each round, every agent ships one batch in a
+    /// shuffled order. Each agent's batches stay in seq order.
+    pub fn run(&mut self, batches_per_agent: u64) -> Vec<LogBatch> {
+        let mut order: Vec<usize> = (0..self.agents.len()).collect();
+        let mut out = Vec::with_capacity(order.len() * batches_per_agent as usize);
+        for _ in 0..batches_per_agent {
+            for i in (1..order.len()).rev() {
+                order.swap(i, self.rng.gen_range(0..=i));
+            }
+            for &agent in &order {
+                out.push(self.next_batch(agent));
+            }
+        }
+        out
+    }
+
+    /// Plants `fault` in a batch of `batches` chosen from the seed. The
+    /// target is never an agent's first or last batch, so a verifier walking
+    /// the chain always has a batch on each side to notice it with; panics if
+    /// no agent has three batches.
+    pub fn inject(&mut self, batches: &mut Vec<LogBatch>, fault: Fault) -> InjectedFault {
+        let candidates: Vec<usize> = (0..batches.len())
+            .filter(|&i| {
+                let batch = &batches[i];
+                batch.seq > 1
+                    && batches[i + 1..]
+                        .iter()
+                        .any(|later| later.agent_id == batch.agent_id)
+            })
+            .collect();
+        assert!(
+            !candidates.is_empty(),
+            "inject needs an agent with at least three batches"
+        );
+        let index = candidates[self.rng.gen_range(0..candidates.len())];
+        let (agent_id, seq) = (batches[index].agent_id.clone(), batches[index].seq);
+        let at = format!("{agent_id} seq {seq}");
+
+        let label = match fault {
+            Fault::BitFlip => {
+                let batch = &mut batches[index];
+                let line = self.rng.gen_range(0..batch.logs.len());
+                testutil::flip_line(batch, line);
+                format!("line {line} of {at} altered (signature kept)")
+            }
+            Fault::SeqGap => {
+                batches.remove(index);
+                format!("{at} removed, leaving a seq gap")
+            }
+            Fault::WrongPrevHash => {
+                let key = self.key_of(&agent_id);
+                let mut prev_hash = [0u8; 32];
+                self.rng.fill_bytes(&mut prev_hash);
+                testutil::relink(&mut batches[index], prev_hash, &key);
+                format!("wrong prev_hash at {at} (re-signed by the agent's key)")
+            }
+            Fault::ForeignSignature => {
+                let mut secret = [0u8; 32];
+                self.rng.fill_bytes(&mut secret);
+                batches[index].sign(&SigningKey::from_bytes(&secret));
+                format!("{at} re-signed by a foreign key")
+            }
+        };
+        InjectedFault {
+            fault,
+            agent_id,
+            seq,
+            label,
+        }
+    }
+
+    fn key_of(&self, agent_id: &str) -> SigningKey {
+        self.agents
+            .iter()
+            .find(|sim| sim.agent_id == agent_id)
+            .map(|sim| sim.key.clone())
+            .expect("batches come from this simulator")
+    }
+
+    fn line(&mut self, at_ms: u64) -> String {
+        let target = self.rng.gen_range(self.line_len.clone());
+        let level = LEVELS[self.rng.gen_range(0..LEVELS.len())];
+        let service = SERVICES[self.rng.gen_range(0..SERVICES.len())];
+        let pid = self.rng.gen_range(100..65_536u32);
+        let user = USERS[self.rng.gen_range(0..USERS.len())];
+        let stamp = chrono::DateTime::from_timestamp_millis(at_ms as i64)
+            .expect("simulated timestamps are in range")
+            .format("%Y-%m-%dT%H:%M:%S%.3fZ");
+        let json = self.rng.gen_range(0..100u8) < self.json_percent;
+
+        let skeleton = if json {
+            format!(
+                r#"{{"ts":"{stamp}","level":"{level}","service":"{service}","pid":{pid},"user":"{user}","msg":""}}"#
+            )
+        } else {
+            format!("{stamp} {level} {service}[{pid}]: user={user} ")
+        };
+        let message = self.message(target.saturating_sub(skeleton.len()));
+        if json {
+            let (head, tail) = skeleton.split_at(skeleton.len() - 2);
+            format!("{head}{message}{tail}")
+        } else {
+            skeleton + &message
+        }
+    }
+
+    /// Words up to exactly `len` bytes.
+    fn message(&mut self, len: usize) -> String {
+        let mut message = String::with_capacity(len + 12);
+        while message.len() < len {
+            if !message.is_empty() {
+                message.push(' ');
+            }
+            message.push_str(WORDS[self.rng.gen_range(0..WORDS.len())]);
+        }
+        message.truncate(len);
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linked(chain: &[&LogBatch]) -> bool {
+        chain
+            .windows(2)
+            .all(|w| w[1].prev_hash == w[0].compute_hash() && w[1].seq == w[0].seq + 1)
+    }
+
+    fn chain_of<'a>(batches: &'a [LogBatch], agent_id: &str) -> Vec<&'a LogBatch> {
+        batches.iter().filter(|b| b.agent_id == agent_id).collect()
+    }
+
+    #[test]
+    fn equal_seeds_give_equal_batches_and_faults() {
+        // Batches have no `PartialEq`; their JSON is compared instead.
+        let simulate = |seed| {
+            let mut sim = ChainSimulator::new(seed, 3);
+            let mut batches = sim.run(4);
+            let fault = sim.inject(&mut batches, Fault::BitFlip);
+            (serde_json::to_string(&batches).unwrap(), fault)
+        };
+        let (batches, fault) = simulate(7);
+        assert_eq!(simulate(7), (batches.clone(), fault));
+        assert_ne!(simulate(8).0, batches);
+    }
+
+    #[test]
+    fn simulated_chains_are_valid_and_shaped_as_asked() {
+        let mut sim = ChainSimulator::new(42, 3)
+            .line_len(120..=150)
+            .json_percent(50)
+            .lines_per_batch(2..=3);
+        let batches = sim.run(5);
+        assert_eq!(batches.len(), 15);
+        assert!(batches.iter().all(LogBatch::verify));
+        for agent in sim.agents() {
+            let chain = chain_of(&batches, &agent.agent_id);
+            assert_eq!(chain.len(), 5);
+            assert_eq!((chain[0].seq, chain[0].prev_hash), (1, [0u8; 32]));
+            assert!(linked(&chain));
+            assert!(
+                chain
+                    .iter()
+                    .all(|b| b.public_key == agent.key.verifying_key())
+            );
+        }
+        let lines: Vec<&String> = batches.iter().flat_map(|b| &b.logs).collect();
+        assert!(lines.iter().all(|line| (120..=150).contains(&line.len())));
+        let json = lines.iter().filter(|line| line.starts_with('{')).count();
+        assert!(json > 0 && json < lines.len());
+        assert!(lines.iter().all(|line| {
+            !line.starts_with('{') || serde_json::from_str::<serde_json::Value>(line).is_ok()
+        }));
+    }
+
+    #[test]
+    fn each_injected_fault_breaks_its_chain() {
+        for fault in [
+            Fault::BitFlip,
+            Fault::SeqGap,
+            Fault::WrongPrevHash,
+            Fault::ForeignSignature,
+        ] {
+            let mut sim = ChainSimulator::new(3, 2);
+            let mut batches = sim.run(4);
+            let injected = sim.inject(&mut batches, fault);
+            assert_eq!(injected.fault, fault);
+            assert!(injected.label.contains(&injected.agent_id), "{injected}");
+
+            let chain = chain_of(&batches, &injected.agent_id);
+            let key = sim.key_of(&injected.agent_id).verifying_key();
+            let signed_by_agent = chain.iter().all(|b| b.verify() && b.public_key == key);
+            match fault {
+                Fault::BitFlip | Fault::ForeignSignature => assert!(!signed_by_agent, "{injected}"),
+                Fault::SeqGap | Fault::WrongPrevHash => {
+                    assert!(signed_by_agent && !linked(&chain), "{injected}")
+                }
+            }
+            // The other agent's chain is untouched.
+            let other = &sim.agents()[0].agent_id;
+            let other = if *other == injected.agent_id {
+                &sim.agents()[1].agent_id
+            } else {
+                other
+            };
+            assert!(linked(&chain_of(&batches, other)));
+        }
+    }
+
+    #[test]
+    fn agents_can_be_renamed_and_clocked() {
+        let mut sim = ChainSimulator::new(9, 2)
+            .agent_prefix("loadgen-9")
+            .clock(5_000, 2);
+        let batches = sim.run(2);
+        assert_eq!(sim.agents()[1].agent_id, "loadgen-9-001");
+        assert!(
+            batches
+                .iter()
+                .all(|b| b.agent_id.starts_with("loadgen-9-") && b.verify())
+        );
+        let stamps: Vec<u64> = batches.iter().map(|b| b.timestamp).collect();
+        assert_eq!(stamps, [5_002, 5_004, 5_006, 5_008]);
+    }
+}
@@ -0,0 +1,257 @@
+//! Valid chains and the tampers a verifier must catch, for tests here and in
+//! downstream crates. Behind the `testutil` feature; not for production use.
+//!
+//! Tampers address batches by seq and leave everything else as an attacker
+//! would: a tamper that does not mention a key cannot re-sign.
+
+use crate::batch::{BatchKind, CURRENT_BATCH_VERSION, EpochStart, GapRecord, LogBatch};
+use ed25519_dalek::{Signature, SigningKey};
+
+/// Base of the fixed timestamps `build_chain` uses, in unix milliseconds.
+pub const CHAIN_EPOCH_MS: u64 = 1_700_000_000_000;
+
+/// Seqs `1..=len` of `agent_id`, signed by `key`, linked by `prev_hash` and
+/// carrying accumulators, as the agent produces them. Batch `n` holds the
+/// single line `"<agent_id> line <n>"`.
+pub fn build_chain(key: &SigningKey, agent_id: &str, len: u64) -> Vec<LogBatch> {
+    let mut chain: Vec<LogBatch> = Vec::new();
+    for seq in 1..=len {
+        push(&mut chain, key, agent_id, seq, None, None);
+    }
+    chain
+}
+
+/// Appends `count` batches to a non-empty chain, continuing its seqs (in the
+/// last batch's epoch) the way [`build_chain`] would.
+pub fn extend_chain(chain: &mut Vec<LogBatch>, key: &SigningKey, count: u64) {
+    let last = chain.last().expect("extend_chain needs a batch to follow");
+    let (agent_id, next) = (last.agent_id.clone(), last.seq + 1);
+    for seq in next..next + count {
+        push(chain, key, &agent_id, seq, None, None);
+    }
+}
+
+/// Closes the last batch's epoch and appends the first batch of the next
+/// one, seq 1 linked to the last batch, as the agent does at an epoch
+/// boundary. Not a tamper: a verifier must accept it.
+pub fn start_epoch(chain: &mut Vec<LogBatch>, key: &SigningKey) {
+    let last = chain.last().expect("start_epoch needs a batch to follow");
+    let agent_id = last.agent_id.clone();
+    let start = EpochStart {
+        previous_last_seq: last.seq,
+    };
+    push(chain, key, &agent_id, 1, None, Some(start));
+}
+
+/// Appends a gap marker declaring the `missing` seqs after the last batch
+/// lost, as `agent --allow-gap` sends it. Not a tamper: the marker is signed
+/// and links to the last batch, so a verifier must accept it.
+pub fn append_gap(chain: &mut Vec<LogBatch>, key: &SigningKey, missing: u64, reason: &str) {
+    let last = chain.last().expect("append_gap needs a batch to follow");
+    let agent_id = last.agent_id.clone();
+    let gap = GapRecord {
+        missing_from: last.seq + 1,
+        missing_to: last.seq + missing,
+        reason: reason.into(),
+    };
+    let seq = gap.missing_to + 1;
+    push(chain, key, &agent_id, seq, Some(gap), None);
+}
+
+/// Appends a session start for `session_id` after the last batch, as
+/// `agent --batch-header` sends it when a run begins; the boot time is the
+/// marker's timestamp. Not a tamper: a verifier must accept it.
+pub fn append_session_start(chain: &mut Vec<LogBatch>, key: &SigningKey, session_id: &str) {
+    let last = chain
+        .last()
+        .expect("append_session_start needs a batch to follow");
+    let (agent_id, seq) = (last.agent_id.clone(), last.seq + 1);
+    push(chain, key, &agent_id, seq, None, None);
+    let marker = chain.last_mut().expect("just pushed");
+    marker.logs.clear();
+    marker.kind = Some(BatchKind::SessionStart {
+        session_id: session_id.into(),
+        boot_time_ms: marker.timestamp,
+    });
+    marker.sign(key);
+}
+
+/// Appends the batch that closes the chain for `reason`, as
+/// `agent --finalize` sends it.
+pub fn append_closed(chain: &mut Vec<LogBatch>, key: &SigningKey, reason: &str) {
+    let last = chain.last().expect("append_closed needs a batch to follow");
+    let (agent_id, seq) = (last.agent_id.clone(), last.seq + 1);
+    push(chain, key, &agent_id, seq, None, None);
+    let close = chain.last_mut().expect("just pushed");
+    close.logs.clear();
+    close.kind = Some(BatchKind::Closed {
+        reason: reason.into(),
+    });
+    close.sign(key);
+}
+
+fn push(
+    chain: &mut Vec<LogBatch>,
+    key: &SigningKey,
+    agent_id: &str,
+    seq: u64,
+    gap: Option<GapRecord>,
+    epoch_start: Option<EpochStart>,
+) {
+    let previous = chain.last();
+    let epoch = previous.map_or(0, |b| b.epoch) + u64::from(epoch_start.is_some());
+    // `CHAIN_EPOCH_MS + seq` through the first epoch, one ms per seq after.
+    let timestamp = match previous {
+        None => CHAIN_EPOCH_MS + seq,
+        Some(b) if epoch_start.is_some() => b.timestamp + 1,
+        Some(b) => b.timestamp + (seq - b.seq),
+    };
+    let mut batch = LogBatch {
+        prev_hash: [0u8; 32],
+        logs: match gap {
+            Some(_) => Vec::new(),
+            None => vec![format!("{agent_id} line {seq}")],
+        },
+        timestamp,
+        agent_id: agent_id.into(),
+        seq,
+        signature: Signature::from_bytes(&[0u8; 64]),
+        public_key: key.verifying_key(),
+        lines_read: None,
+        version: CURRENT_BATCH_VERSION,
+        accumulator: None,
+        gap,
+        epoch,
+        epoch_start,
+        kind: None,
+    };
+    seal(&mut batch, previous, key);
+    chain.push(batch);
+}
+
+/// Links `batch` to `previous`, the batch before it in its chain (`None`
+/// for the first), fills in its accumulator and signs it with `key`, as the
+/// agent does before sending it.
+pub fn seal(batch: &mut LogBatch, previous: Option<&LogBatch>, key: &SigningKey) {
+    batch.prev_hash = previous.map_or([0u8; 32], LogBatch::compute_hash);
+    let previous_accumulator = previous.and_then(|b| b.accumulator);
+    batch.accumulator = Some(batch.expected_accumulator(previous_accumulator.as_ref()));
+    batch.sign(key);
+}
+
+/// Each batch's hash, as the server stores it next to the batch.
+pub fn chain_hashes(chain: &[LogBatch]) -> Vec<[u8; 32]> {
+    chain.iter().map(LogBatch::compute_hash).collect()
+}
+
+fn at(chain: &mut [LogBatch], seq: u64) -> &mut LogBatch {
+    chain
+        .iter_mut()
+        .find(|b| b.seq == seq)
+        .unwrap_or_else(|| panic!("no batch with seq {seq}"))
+}
+
+/// Replaces the last character of the batch's first line; the signature is
+/// kept.
+pub fn flip_log_line(chain: &mut [LogBatch], seq: u64) {
+    flip_line(at(chain, seq), 0);
+}
+
+/// [`flip_log_line`] for line `line` of one batch.
+pub fn flip_line(batch: &mut LogBatch, line: usize) {
+    let line = &mut batch.logs[line];
+    let last = line.pop();
+    line.push(if last == Some('X') { 'Y' } else { 'X' });
+}
+
+/// Points the batch at `prev_hash` and re-signs it with `key`: a key holder
+/// rewriting one link. The signature verifies; only the link gives it away.
+pub fn relink(batch: &mut LogBatch, prev_hash: [u8; 32], key: &SigningKey) {
+    batch.prev_hash = prev_hash;
+    batch.sign(key);
+}
+
+/// Swaps two batches and their seqs, so the seqs still read in order, and
+/// re-signs both with `key`: a key holder reordering history. Only the links
+/// between batches give it away.
+pub fn reorder(chain: &mut [LogBatch], a: u64, b: u64, key: &SigningKey) {
+    let i = chain.iter().position(|x| x.seq == a).expect("seq a");
+    let j = chain.iter().position(|x| x.seq == b).expect("seq b");
+    chain.swap(i, j);
+    chain[i].seq = a;
+    chain[j].seq = b;
+    chain[i].sign(key);
+    chain[j].sign(key);
+}
+
+/// Removes the batch, leaving a gap in the seqs.
+pub fn drop_seq(chain: &mut Vec<LogBatch>, seq: u64) {
+    chain.retain(|b| b.seq != seq);
+}
+
+/// Re-signs the batch with `key`, replacing its public key: the signature
+/// verifies, but not under a key the agent was ever given.
+pub fn resign_with(chain: &mut [LogBatch], seq: u64, key: &SigningKey) {
+    at(chain, seq).sign(key);
+}
+
+/// Flips one bit of a stored hash, e.g. an entry of [`chain_hashes`].
+pub fn mutate_hash(hash: &mut [u8; 32]) {
+    hash[0] ^= 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::generate_keypair;
+
+    fn linked(chain: &[LogBatch]) -> bool {
+        chain.windows(2).all(|w| {
+            w[1].prev_hash == w[0].compute_hash() && w[1].linked_position() == w[0].position()
+        })
+    }
+
+    #[test]
+    fn built_chains_are_valid_and_each_tamper_breaks_something() {
+        let key = generate_keypair();
+        let chain = build_chain(&key, "agent-t", 4);
+        assert!(chain.iter().all(LogBatch::verify) && linked(&chain));
+        assert_eq!(chain[3].logs, ["agent-t line 4"]);
+
+        let mut flipped = chain.clone();
+        flip_log_line(&mut flipped, 2);
+        assert!(!flipped[1].verify());
+
+        let mut reordered = chain.clone();
+        reorder(&mut reordered, 2, 3, &key);
+        assert!(reordered.iter().all(LogBatch::verify) && !linked(&reordered));
+
+        let mut dropped = chain.clone();
+        drop_seq(&mut dropped, 3);
+        assert_eq!(dropped.len(), 3);
+        assert!(!linked(&dropped));
+
+        let mut resigned = chain.clone();
+        resign_with(&mut resigned, 2, &generate_keypair());
+        assert!(resigned[1].verify() && resigned[1].public_key != key.verifying_key());
+
+        let mut hashes = chain_hashes(&chain);
+        mutate_hash(&mut hashes[0]);
+        assert_ne!(hashes[0], chain[0].compute_hash());
+
+        let mut gapped = chain.clone();
+        append_gap(&mut gapped, &key, 3, "lost");
+        extend_chain(&mut gapped, &key, 1);
+        let seqs: Vec<u64> = gapped.iter().map(|b| b.seq).collect();
+        assert_eq!(seqs, [1, 2, 3, 4, 8, 9]);
+        assert!(gapped.iter().all(|b| b.verify() && b.check_gap().is_ok()) && linked(&gapped));
+
+        let mut epochs = chain.clone();
+        start_epoch(&mut epochs, &key);
+        extend_chain(&mut epochs, &key, 1);
+        let positions: Vec<(u64, u64)> = epochs.iter().map(LogBatch::position).collect();
+        assert_eq!(positions, [(0, 1), (0, 2), (0, 3), (0, 4), (1, 1), (1, 2)]);
+        assert!(epochs.iter().all(|b| b.verify() && b.check_epoch().is_ok()) && linked(&epochs));
+        assert!(epochs.windows(2).all(|w| w[0].timestamp < w[1].timestamp));
+    }
+}
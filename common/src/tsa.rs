@@ -0,0 +1,411 @@
+//! RFC 3161 trusted timestamps of batches. The server gathers newly stored
+//! batch hashes into an RFC 6962 Merkle tree (see [`crate::summary`]) and
+//! has a Time Stamping Authority sign the tree's [`imprint`], so one TSA
+//! request covers many batches. [`TsaStamp`] is what `GET /batches/:id/tsa`
+//! serves: the token, and the audit path that ties the batch to it.
+//!
+//! Only the DER this needs is handled here: building the `TimeStampReq`,
+//! taking the token out of a `TimeStampResp` and reading the `TSTInfo`
+//! inside. Checking the token's signature is left to the reader (the CLI),
+//! which has the CA bundle to check it against.
+
+use crate::summary::merkle_root_from_path;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Domain of the imprint, so a stamped root cannot pass for another hash.
+const TSA_DOMAIN: &[u8] = b"logchain-tsa-v1";
+
+/// DER of the sha256 `AlgorithmIdentifier`, parameters NULL.
+const SHA256_ALGORITHM: &[u8] = &[
+    0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00,
+];
+/// Content bytes of the sha256 OID, 2.16.840.1.101.3.4.2.1.
+const SHA256_OID: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+/// id-signedData, 1.2.840.113549.1.7.2.
+const SIGNED_DATA_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02];
+/// id-ct-TSTInfo, 1.2.840.113549.1.9.16.1.4.
+const TST_INFO_OID: &[u8] = &[
+    0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x01, 0x04,
+];
+
+const INTEGER: u8 = 0x02;
+const BOOLEAN: u8 = 0x01;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const GENERALIZED_TIME: u8 = 0x18;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+/// `[0] EXPLICIT`, constructed.
+const EXPLICIT_0: u8 = 0xa0;
+
+/// What the TSA signs for a tree of batch hashes with root `root`.
+pub fn imprint(root: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(TSA_DOMAIN);
+    hasher.update(root);
+    hasher.finalize().into()
+}
+
+/// A DER `TimeStampReq` for a sha256 `imprint`, asking for the TSA's
+/// certificate in the token so it verifies on its own.
+pub fn request(imprint: &[u8; 32], nonce: u64) -> Vec<u8> {
+    let message_imprint = tlv(
+        SEQUENCE,
+        &[SHA256_ALGORITHM, &tlv(OCTET_STRING, imprint)].concat(),
+    );
+    let body = [
+        tlv(INTEGER, &[1]),
+        message_imprint,
+        integer(nonce),
+        tlv(BOOLEAN, &[0xff]),
+    ]
+    .concat();
+    tlv(SEQUENCE, &body)
+}
+
+/// The token of a `TimeStampResp`, if the TSA granted the request.
+pub fn token_from_response(der: &[u8]) -> Result<Vec<u8>, String> {
+    let mut resp = Reader::new(Reader::new(der).expect(SEQUENCE)?);
+    let mut status_info = Reader::new(resp.expect(SEQUENCE)?);
+    let status = u64_of(status_info.expect(INTEGER)?)?;
+    // 0 is granted, 1 granted with modifications.
+    if status > 1 {
+        return Err(format!("TSA refused the request (PKIStatus {status})"));
+    }
+    if resp.is_empty() {
+        return Err("TSA granted the request but sent no token".into());
+    }
+    let token = resp.rest;
+    resp.expect(SEQUENCE)?;
+    Ok(token[..token.len() - resp.rest.len()].to_vec())
+}
+
+/// The fields of a `TSTInfo` worth keeping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TstInfo {
+    /// The sha256 hashed message the TSA signed.
+    pub imprint: [u8; 32],
+    /// Hex of the TSA's serial number for this token.
+    pub serial: String,
+    /// RFC 3339, UTC.
+    pub gen_time: String,
+    pub nonce: Option<u64>,
+}
+
+impl TstInfo {
+    /// Reads the `TSTInfo` a token (a CMS `SignedData`) encapsulates,
+    /// without looking at its signature.
+    pub fn from_token(token: &[u8]) -> Result<Self, String> {
+        Self::parse(&encapsulated(token)?)
+    }
+
+    /// Reads a DER `TSTInfo`.
+    pub fn parse(der: &[u8]) -> Result<Self, String> {
+        let mut info = Reader::new(Reader::new(der).expect(SEQUENCE)?);
+        info.expect(INTEGER)?;
+        info.expect(OID)?;
+        let mut message_imprint = Reader::new(info.expect(SEQUENCE)?);
+        let mut algorithm = Reader::new(message_imprint.expect(SEQUENCE)?);
+        if algorithm.expect(OID)? != SHA256_OID {
+            return Err("token imprint is not sha256".into());
+        }
+        let imprint = <[u8; 32]>::try_from(message_imprint.expect(OCTET_STRING)?)
+            .map_err(|_| "token imprint is not 32 bytes".to_string())?;
+        let serial = crate::hex::hex_encode(info.expect(INTEGER)?);
+        let gen_time = generalized_time(info.expect(GENERALIZED_TIME)?)?;
+        // accuracy and ordering may come before the nonce.
+        let mut nonce = None;
+        while let Some(tag) = info.peek() {
+            match tag {
+                SEQUENCE | BOOLEAN => {
+                    info.read()?;
+                }
+                INTEGER => {
+                    nonce = Some(u64_of(info.expect(INTEGER)?)?);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        Ok(Self {
+            imprint,
+            serial,
+            gen_time,
+            nonce,
+        })
+    }
+}
+
+/// The `eContent` of a CMS `SignedData` whose content type is `TSTInfo`.
+fn encapsulated(token: &[u8]) -> Result<Vec<u8>, String> {
+    let mut content_info = Reader::new(Reader::new(token).expect(SEQUENCE)?);
+    if content_info.expect(OID)? != SIGNED_DATA_OID {
+        return Err("token is not a CMS SignedData".into());
+    }
+    let mut explicit = Reader::new(content_info.expect(EXPLICIT_0)?);
+    let mut signed_data = Reader::new(explicit.expect(SEQUENCE)?);
+    signed_data.expect(INTEGER)?;
+    signed_data.expect(SET)?;
+    let mut encap = Reader::new(signed_data.expect(SEQUENCE)?);
+    if encap.expect(OID)? != TST_INFO_OID {
+        return Err("token does not encapsulate a TSTInfo".into());
+    }
+    let mut explicit = Reader::new(encap.expect(EXPLICIT_0)?);
+    Ok(explicit.expect(OCTET_STRING)?.to_vec())
+}
+
+/// `GET /batches/:id/tsa`: a batch's timestamp.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TsaStamp {
+    pub batch_id: i64,
+    /// The batch hash, as stored and stamped.
+    pub hash: [u8; 32],
+    /// Row id of the token in `tsa_tokens`, shared by every batch of the
+    /// same TSA request.
+    pub token_id: i64,
+    pub leaf_index: usize,
+    pub leaf_count: usize,
+    /// [`crate::summary::merkle_path`] of the batch hash.
+    pub path: Vec<[u8; 32]>,
+    pub root: [u8; 32],
+    /// The TSA's time, RFC 3339.
+    pub gen_time: String,
+    pub tsa_url: String,
+    /// Hex of the DER token.
+    pub token: String,
+}
+
+impl TsaStamp {
+    /// Checks that the path ties `hash` to `root`, and returns the imprint
+    /// the token must carry.
+    pub fn check_inclusion(&self, hash: &[u8; 32]) -> Result<[u8; 32], String> {
+        if hash != &self.hash {
+            return Err("the stamp is for a different batch hash".into());
+        }
+        match merkle_root_from_path(hash, self.leaf_index, self.leaf_count, &self.path) {
+            Some(root) if root == self.root => Ok(imprint(&root)),
+            _ => Err("the audit path does not lead from the batch hash to the stamped root".into()),
+        }
+    }
+}
+
+/// `YYYYMMDDHHMMSS[.fff]Z` as RFC 3339.
+fn generalized_time(bytes: &[u8]) -> Result<String, String> {
+    let text = std::str::from_utf8(bytes).map_err(|_| "genTime is not ASCII".to_string())?;
+    let time = text
+        .strip_suffix('Z')
+        .and_then(|t| chrono::NaiveDateTime::parse_from_str(t, "%Y%m%d%H%M%S%.f").ok())
+        .ok_or_else(|| format!("genTime '{text}' is not a UTC GeneralizedTime"))?;
+    Ok(time
+        .and_utc()
+        .to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true))
+}
+
+fn u64_of(bytes: &[u8]) -> Result<u64, String> {
+    if bytes.first().is_some_and(|b| b & 0x80 != 0) {
+        return Err("negative INTEGER".into());
+    }
+    let bytes = match bytes {
+        [0, rest @ ..] => rest,
+        _ => bytes,
+    };
+    if bytes.len() > 8 {
+        return Err("INTEGER out of range".into());
+    }
+    Ok(bytes.iter().fold(0, |n, b| n << 8 | *b as u64))
+}
+
+fn integer(n: u64) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let first = bytes.iter().position(|b| *b != 0).unwrap_or(7);
+    let mut content = bytes[first..].to_vec();
+    if content[0] & 0x80 != 0 {
+        content.insert(0, 0);
+    }
+    tlv(INTEGER, &content)
+}
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    match content.len() {
+        len @ 0..=0x7f => out.push(len as u8),
+        len => {
+            let bytes = (len as u64).to_be_bytes();
+            let first = bytes.iter().position(|b| *b != 0).unwrap_or(7);
+            out.push(0x80 | (8 - first) as u8);
+            out.extend_from_slice(&bytes[first..]);
+        }
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// Single-byte tags and definite lengths: DER as TSAs send it.
+struct Reader<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(rest: &'a [u8]) -> Self {
+        Self { rest }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.rest.is_empty()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.rest.first().copied()
+    }
+
+    fn read(&mut self) -> Result<(u8, &'a [u8]), String> {
+        let truncated = || "truncated DER".to_string();
+        let (&tag, rest) = self.rest.split_first().ok_or_else(truncated)?;
+        let (&first, rest) = rest.split_first().ok_or_else(truncated)?;
+        let (len, rest) = if first < 0x80 {
+            (first as usize, rest)
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 || rest.len() < count {
+                return Err("unsupported DER length".into());
+            }
+            let len = rest[..count]
+                .iter()
+                .fold(0usize, |n, b| n << 8 | *b as usize);
+            (len, &rest[count..])
+        };
+        if rest.len() < len {
+            return Err(truncated());
+        }
+        self.rest = &rest[len..];
+        Ok((tag, &rest[..len]))
+    }
+
+    fn expect(&mut self, tag: u8) -> Result<&'a [u8], String> {
+        match self.read()? {
+            (found, content) if found == tag => Ok(content),
+            (found, _) => Err(format!("expected DER tag {tag:#04x}, found {found:#04x}")),
+        }
+    }
+}
+
+/// The imprint and nonce of a [`request`], as a mock TSA reads them.
+#[cfg(any(test, feature = "testutil"))]
+pub fn request_fields(der: &[u8]) -> Result<([u8; 32], u64), String> {
+    let mut req = Reader::new(Reader::new(der).expect(SEQUENCE)?);
+    req.expect(INTEGER)?;
+    let mut message_imprint = Reader::new(req.expect(SEQUENCE)?);
+    message_imprint.expect(SEQUENCE)?;
+    let imprint = <[u8; 32]>::try_from(message_imprint.expect(OCTET_STRING)?)
+        .map_err(|_| "imprint is not 32 bytes".to_string())?;
+    Ok((imprint, u64_of(req.expect(INTEGER)?)?))
+}
+
+/// A DER `TSTInfo` as a TSA would sign it, for mock TSAs.
+#[cfg(any(test, feature = "testutil"))]
+pub fn tst_info_der(imprint: &[u8; 32], nonce: u64, gen_time: &str) -> Vec<u8> {
+    // 1.2.3.4, a test policy.
+    let policy = tlv(OID, &[0x2a, 0x03, 0x04]);
+    let message_imprint = tlv(
+        SEQUENCE,
+        &[SHA256_ALGORITHM, &tlv(OCTET_STRING, imprint)].concat(),
+    );
+    let body = [
+        tlv(INTEGER, &[1]),
+        policy,
+        message_imprint,
+        integer(nonce ^ 0x5a5a),
+        tlv(GENERALIZED_TIME, gen_time.as_bytes()),
+        tlv(BOOLEAN, &[0]),
+        integer(nonce),
+    ]
+    .concat();
+    tlv(SEQUENCE, &body)
+}
+
+/// A granted `TimeStampResp` around a token that encapsulates `tst_info`
+/// but carries no signer: enough for the server, which does not verify.
+#[cfg(any(test, feature = "testutil"))]
+pub fn unsigned_response(tst_info: &[u8]) -> Vec<u8> {
+    let encap = tlv(
+        SEQUENCE,
+        &[
+            tlv(OID, TST_INFO_OID),
+            tlv(EXPLICIT_0, &tlv(OCTET_STRING, tst_info)),
+        ]
+        .concat(),
+    );
+    let signed_data = tlv(
+        SEQUENCE,
+        &[
+            tlv(INTEGER, &[3]),
+            tlv(SET, SHA256_ALGORITHM),
+            encap,
+            tlv(SET, &[]),
+        ]
+        .concat(),
+    );
+    let token = tlv(
+        SEQUENCE,
+        &[tlv(OID, SIGNED_DATA_OID), tlv(EXPLICIT_0, &signed_data)].concat(),
+    );
+    let status = tlv(SEQUENCE, &tlv(INTEGER, &[0]));
+    tlv(SEQUENCE, &[status, token].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::summary::{merkle_path, merkle_root};
+
+    #[test]
+    fn requests_and_tokens_round_trip_through_der() {
+        let imprint = imprint(&[7; 32]);
+        let req = request(&imprint, 0x80);
+        assert_eq!(&req[..2], &[0x30, req.len() as u8 - 2]);
+        // The nonce's high bit needs a leading zero to stay positive.
+        assert!(req.windows(4).any(|w| w == [INTEGER, 2, 0, 0x80]));
+
+        assert_eq!(request_fields(&req).unwrap(), (imprint, 0x80));
+
+        let tst_info = tst_info_der(&imprint, 0x80, "20261017093000.25Z");
+        let response = unsigned_response(&tst_info);
+        let token = token_from_response(&response).unwrap();
+        let info = TstInfo::from_token(&token).unwrap();
+        assert_eq!(info.imprint, imprint);
+        assert_eq!(info.nonce, Some(0x80));
+        assert_eq!(info.gen_time, "2026-10-17T09:30:00.250Z");
+        assert_eq!(TstInfo::parse(&tst_info).unwrap(), info);
+
+        let rejection = tlv(SEQUENCE, &tlv(SEQUENCE, &tlv(INTEGER, &[2])));
+        assert!(
+            token_from_response(&rejection)
+                .unwrap_err()
+                .contains("PKIStatus 2")
+        );
+        assert!(TstInfo::from_token(&tst_info).is_err());
+        assert!(token_from_response(&response[..response.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn a_stamp_ties_its_batch_hash_to_the_imprint() {
+        let hashes = [[1; 32], [2; 32], [3; 32]];
+        let mut stamp = TsaStamp {
+            batch_id: 2,
+            hash: hashes[1],
+            token_id: 1,
+            leaf_index: 1,
+            leaf_count: 3,
+            path: merkle_path(&hashes, 1),
+            root: merkle_root(&hashes),
+            gen_time: String::new(),
+            tsa_url: String::new(),
+            token: String::new(),
+        };
+        assert_eq!(stamp.check_inclusion(&hashes[1]), Ok(imprint(&stamp.root)));
+        assert!(stamp.check_inclusion(&hashes[0]).is_err());
+        stamp.leaf_index = 0;
+        assert!(stamp.check_inclusion(&hashes[1]).is_err());
+    }
+}
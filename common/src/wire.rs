@@ -0,0 +1,203 @@
+//! How a batch travels in a `/submit` body: JSON by default, or MessagePack
+//! (`Content-Type: application/msgpack`), which is smaller and quicker to
+//! parse for busy agents. Both are the serde encoding of [`LogBatch`] with
+//! its field names, so the server reads either into the same batch; the
+//! signature covers the batch, not the body.
+
+use crate::batch::LogBatch;
+use std::fmt;
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+/// The `Content-Type`s `/submit` reads, advertised by `GET /version`.
+pub const CONTENT_TYPES: &[&str] = &[JSON_CONTENT_TYPE, MSGPACK_CONTENT_TYPE];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+impl WireFormat {
+    /// `json` or `msgpack`, as `--wire-format` takes it.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "json" => Some(Self::Json),
+            "msgpack" => Some(Self::Msgpack),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => JSON_CONTENT_TYPE,
+            Self::Msgpack => MSGPACK_CONTENT_TYPE,
+        }
+    }
+
+    /// The format a request's `Content-Type` names. Anything but
+    /// MessagePack, or none, reads as JSON, as every body did before.
+    pub fn of_content_type(content_type: Option<&str>) -> Self {
+        let essence = content_type
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase());
+        match essence.as_deref() {
+            Some(MSGPACK_CONTENT_TYPE | "application/x-msgpack" | "application/vnd.msgpack") => {
+                Self::Msgpack
+            }
+            _ => Self::Json,
+        }
+    }
+
+    pub fn encode(self, batch: &LogBatch) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Json => serde_json::to_vec(batch).map_err(|err| err.to_string()),
+            // Named, so fields a batch leaves out cannot shift the rest.
+            Self::Msgpack => rmp_serde::to_vec_named(batch).map_err(|err| err.to_string()),
+        }
+    }
+
+    pub fn decode(self, body: &[u8]) -> Result<LogBatch, String> {
+        match self {
+            Self::Json => {
+                serde_json::from_slice(body).map_err(|err| format!("invalid batch JSON: {err}"))
+            }
+            Self::Msgpack => rmp_serde::from_slice(body)
+                .map_err(|err| format!("invalid batch MessagePack: {err}")),
+        }
+    }
+}
+
+impl fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Json => "json",
+            Self::Msgpack => "msgpack",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::{BatchKind, CURRENT_BATCH_VERSION, GapRecord, generate_keypair};
+    use ed25519_dalek::Signature;
+
+    /// A busy agent's batch: 100 access-log lines.
+    fn representative() -> LogBatch {
+        let key = generate_keypair();
+        let mut batch = LogBatch {
+            prev_hash: [7u8; 32],
+            logs: (0..100)
+                .map(|n| {
+                    format!(
+                        "2024-01-01T00:00:{:02}Z INFO request handled path=/api/v1/items/{n} status=200 ms={}",
+                        n % 60,
+                        n % 17
+                    )
+                })
+                .collect(),
+            timestamp: 1_700_000_000_000,
+            agent_id: "agent-bench".into(),
+            seq: 42,
+            signature: Signature::from_bytes(&[0u8; 64]),
+            public_key: key.verifying_key(),
+            lines_read: Some(4_200),
+            version: CURRENT_BATCH_VERSION,
+            accumulator: Some([9u8; 32]),
+            gap: None,
+            epoch: 0,
+            epoch_start: None,
+            kind: None,
+        };
+        batch.sign(&key);
+        batch
+    }
+
+    #[test]
+    fn both_formats_round_trip_a_batch_that_still_verifies() {
+        let batch = representative();
+        let mut marker = batch.clone();
+        marker.logs.clear();
+        marker.gap = Some(GapRecord {
+            missing_from: 40,
+            missing_to: 41,
+            reason: "lost".into(),
+        });
+        marker.kind = Some(BatchKind::Closed {
+            reason: "done".into(),
+        });
+        for format in [WireFormat::Json, WireFormat::Msgpack] {
+            for sent in [&batch, &marker] {
+                let back = format.decode(&format.encode(sent).unwrap()).unwrap();
+                assert_eq!(back.compute_hash(), sent.compute_hash(), "{format}");
+                assert_eq!((back.gap, back.kind), (sent.gap.clone(), sent.kind.clone()));
+            }
+            assert!(
+                format
+                    .decode(&format.encode(&batch).unwrap())
+                    .unwrap()
+                    .verify()
+            );
+        }
+        let msgpack = WireFormat::Msgpack.encode(&batch).unwrap();
+        assert!(msgpack.len() < WireFormat::Json.encode(&batch).unwrap().len());
+        assert!(
+            WireFormat::Json
+                .decode(&msgpack)
+                .unwrap_err()
+                .starts_with("invalid batch JSON")
+        );
+        assert!(
+            WireFormat::Msgpack
+                .decode(b"{}")
+                .unwrap_err()
+                .starts_with("invalid batch MessagePack")
+        );
+    }
+
+    #[test]
+    fn content_types_pick_the_format() {
+        assert_eq!(
+            WireFormat::of_content_type(Some("application/msgpack")),
+            WireFormat::Msgpack
+        );
+        assert_eq!(
+            WireFormat::of_content_type(Some("Application/X-MsgPack; q=1")),
+            WireFormat::Msgpack
+        );
+        assert_eq!(
+            WireFormat::of_content_type(Some("application/json; charset=utf-8")),
+            WireFormat::Json
+        );
+        assert_eq!(
+            WireFormat::of_content_type(Some("text/plain")),
+            WireFormat::Json
+        );
+        assert_eq!(WireFormat::of_content_type(None), WireFormat::Json);
+        assert_eq!(WireFormat::parse("msgpack"), Some(WireFormat::Msgpack));
+        assert_eq!(WireFormat::parse("cbor"), None);
+    }
+
+    /// Body size and parse time of [`representative`] in each format.
+    /// `cargo test -p common --release -- --ignored --nocapture wire_format_tradeoff`
+    #[test]
+    #[ignore]
+    fn wire_format_tradeoff() {
+        let batch = representative();
+        for format in [WireFormat::Json, WireFormat::Msgpack] {
+            let body = format.encode(&batch).unwrap();
+            let rounds = 2_000;
+            let start = std::time::Instant::now();
+            for _ in 0..rounds {
+                std::hint::black_box(format.decode(std::hint::black_box(&body)).unwrap());
+            }
+            let per_parse = start.elapsed() / rounds;
+            println!(
+                "{format:>7}: {:>6} bytes, {per_parse:?} per parse",
+                body.len()
+            );
+        }
+    }
+}
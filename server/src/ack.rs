@@ -0,0 +1,149 @@
+//! `SUBMIT_ACK_MODE`: what a 201 from `/submit` (or the gRPC `Submit`)
+//! promises about the stored batch.
+//!
+//! - `durable` (default): the batch commits with `synchronous=FULL`. The
+//!   WAL is fsynced before the answer, so an acknowledged batch survives a
+//!   power loss.
+//! - `fast`: the batch commits with `synchronous=NORMAL`. It is written to
+//!   the WAL, which survives the server process crashing, but the fsync is
+//!   left to a WAL checkpoint every `SUBMIT_ACK_SYNC_INTERVAL_MS` (default
+//!   1000) or to the next durable write. An OS crash or power loss can lose
+//!   the batches acknowledged since. Their agents have already moved on, so
+//!   the chain then has a hole that only a restart (adopting the server
+//!   checkpoint, or `--allow-gap`) gets past.
+//!
+//! Only the submit's own commit is lowered: every pooled connection is put
+//! back to `FULL` as it returns to the pool, so registration, rotation,
+//! ingestion and admin writes stay durable. The mode is echoed as `ack` on
+//! each 201.
+
+use sqlx::SqliteConnection;
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+use std::env;
+use std::time::Duration;
+
+pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_millis(1000);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AckMode {
+    #[default]
+    Durable,
+    Fast,
+}
+
+impl AckMode {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "durable" => Ok(Self::Durable),
+            "fast" => Ok(Self::Fast),
+            _ => Err(format!(
+                "SUBMIT_ACK_MODE must be 'durable' or 'fast', got '{value}'"
+            )),
+        }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        env::var("SUBMIT_ACK_MODE").map_or(Ok(Self::Durable), |value| Self::parse(&value))
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Durable => "durable",
+            Self::Fast => "fast",
+        }
+    }
+
+    /// Pool options for this mode: in fast mode each connection is set back
+    /// to `synchronous=FULL` on release, undoing [`AckMode::lower`].
+    pub fn pool_options(self) -> SqlitePoolOptions {
+        let options = SqlitePoolOptions::new();
+        if self == Self::Durable {
+            return options;
+        }
+        options.after_release(|conn, _| {
+            Box::pin(async move {
+                sqlx::query("PRAGMA synchronous=FULL").execute(conn).await?;
+                Ok(true)
+            })
+        })
+    }
+
+    /// Sets the sync level `conn`'s next transaction commits at. SQLite
+    /// refuses the change inside a transaction, so call it before `begin`.
+    pub async fn lower(self, conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+        if self == Self::Fast {
+            sqlx::query("PRAGMA synchronous=NORMAL")
+                .execute(conn)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// `SUBMIT_ACK_SYNC_INTERVAL_MS`: how often fast mode checkpoints the WAL.
+pub fn sync_interval_from_env() -> Result<Duration, String> {
+    match env::var("SUBMIT_ACK_SYNC_INTERVAL_MS") {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(ms) if ms > 0 => Ok(Duration::from_millis(ms)),
+            _ => Err(format!(
+                "SUBMIT_ACK_SYNC_INTERVAL_MS must be a positive number of milliseconds, got '{value}'"
+            )),
+        },
+        Err(_) => Ok(DEFAULT_SYNC_INTERVAL),
+    }
+}
+
+/// Checkpoints the WAL every `interval`. A checkpoint fsyncs the WAL before
+/// copying it back, which bounds how long a fast-acked batch stays unsynced.
+pub fn spawn_syncer(pool: SqlitePool, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(err) = sqlx::query("PRAGMA wal_checkpoint(PASSIVE)")
+                .execute(&pool)
+                .await
+            {
+                eprintln!("[ack] WAL checkpoint failed: {err}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::Connection;
+
+    async fn synchronous(conn: &mut SqliteConnection) -> i64 {
+        sqlx::query_scalar("PRAGMA synchronous")
+            .fetch_one(conn)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn fast_mode_lowers_one_transaction_and_the_pool_restores_full() {
+        assert_eq!(AckMode::parse(" Fast").unwrap(), AckMode::Fast);
+        assert_eq!(AckMode::parse("durable").unwrap(), AckMode::Durable);
+        assert!(AckMode::parse("async").is_err());
+
+        let pool = AckMode::Fast
+            .pool_options()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let mut conn = pool.acquire().await.unwrap();
+        AckMode::Fast.lower(&mut conn).await.unwrap();
+        let mut tx = conn.begin().await.unwrap();
+        assert_eq!(synchronous(tx.as_mut()).await, 1);
+        tx.commit().await.unwrap();
+        drop(conn);
+
+        let mut conn = pool.acquire().await.unwrap();
+        assert_eq!(synchronous(&mut conn).await, 2);
+        AckMode::Durable.lower(&mut conn).await.unwrap();
+        assert_eq!(synchronous(&mut conn).await, 2);
+    }
+}
@@ -0,0 +1,780 @@
+//! Operator endpoints under `/admin`, all behind the `admin` scope:
+//! `ADMIN_BEARER_TOKEN` or a minted token carrying it.
+//!
+//! The admin API is disabled (403) when no admin credential exists; it never
+//! falls back to the submit token.
+
+use crate::auth::{AuthContext, Scope, Scopes, token_hash};
+use crate::decompress::Decompressor;
+use crate::forks::{Fork, ForkReport, ReceiptCheck};
+use crate::fsck::JobStatus;
+use crate::key_conflicts::{KeyConflict, key_conflicts};
+use crate::rate_limit::{self, Deny, RateLimitStatus};
+use crate::receipts::ServerKey;
+use crate::storage::StorageFault;
+use crate::tiering;
+use crate::{
+    AppState, BATCH_READ_COLUMNS, close_key_window, key_valid_at, next_position, now_unix,
+    now_unix_ms, parse_stored_logs, row_to_key_window, row_to_query_batch, snapshot_database,
+};
+use axum::{
+    Extension, Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use common::hex::hex_encode;
+use common::redaction::Redaction;
+use common::serde_util::is_zero;
+use futures_util::TryStreamExt;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqlitePool};
+use std::collections::HashMap;
+
+type AdminResult<T> = Result<Json<T>, (StatusCode, Json<AdminError>)>;
+
+#[derive(Debug, Serialize)]
+pub struct AdminError {
+    status: String,
+    message: String,
+}
+
+fn admin_error(code: StatusCode, message: impl Into<String>) -> (StatusCode, Json<AdminError>) {
+    (
+        code,
+        Json(AdminError {
+            status: "error".into(),
+            message: message.into(),
+        }),
+    )
+}
+
+fn internal(err: sqlx::Error) -> (StatusCode, Json<AdminError>) {
+    admin_error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+}
+
+fn authorize(auth: &AuthContext) -> Result<(), (StatusCode, Json<AdminError>)> {
+    auth.require(Scope::Admin)
+        .map_err(|err| admin_error(err.status(), err.message()))
+}
+
+/* ---- POST /admin/snapshot ---- */
+
+#[derive(Debug, Serialize)]
+pub struct SnapshotResponse {
+    path: String,
+    /// With tiering on, the blob store the snapshot's tiered rows point to;
+    /// back it up with the snapshot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blob_store: Option<String>,
+}
+
+/// Writes `<SQLITE_BACKUP_PATH>.<unix_ms>` now, next to the periodic snapshot.
+pub async fn handler_snapshot(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+) -> AdminResult<SnapshotResponse> {
+    authorize(&auth)?;
+    let Some(base) = &state.snapshot_path else {
+        return Err(admin_error(
+            StatusCode::BAD_REQUEST,
+            "no snapshot location; set SQLITE_BACKUP_PATH",
+        ));
+    };
+    let path = format!("{}.{}", base, now_unix_ms());
+    if let Err(err) = snapshot_database(&state.pool, &path).await {
+        let status = match StorageFault::of(&err) {
+            Some(fault) => {
+                state.storage.fault(&state.metrics, fault, "snapshot");
+                fault.status()
+            }
+            None => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        return Err(admin_error(status, err.to_string()));
+    }
+    state.storage.recovered("snapshot");
+    let blob_store = state
+        .blob_store
+        .as_ref()
+        .map(|store| store.dir().display().to_string());
+    Ok(Json(SnapshotResponse { path, blob_store }))
+}
+
+/* ---- POST /admin/integrity-check ---- */
+
+#[derive(Debug, Serialize)]
+pub struct BrokenLink {
+    agent_id: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    epoch: u64,
+    seq: u64,
+    reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IntegrityReport {
+    ok: bool,
+    /// Output of `PRAGMA integrity_check` (`["ok"]` when the file is sound).
+    sqlite: Vec<String>,
+    /// Stored rows whose `prev_hash` or seq does not follow the previous row
+    /// (for a gap marker, the row before the seqs it declares lost; for an
+    /// epoch's first row, the last row of the epoch before, which must be
+    /// that epoch's last).
+    broken_links: Vec<BrokenLink>,
+    /// Rows whose `logs` column is unreadable or not in the canonical encoding
+    /// the server writes, i.e. rows the server did not write as they are.
+    logs_issues: Vec<BrokenLink>,
+    /// Rows edited in place: the stored hash does not match the contents, the
+    /// signature does not verify, or the signer was not the agent's key for
+    /// that seq.
+    content_issues: Vec<BrokenLink>,
+}
+
+pub async fn handler_integrity_check(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+) -> AdminResult<IntegrityReport> {
+    authorize(&auth)?;
+
+    let sqlite: Vec<String> = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_all(&state.pool)
+        .await
+        .map_err(internal)?;
+
+    let rows = sqlx::query(
+        r#"
+        SELECT b.agent_id, b.epoch, b.seq,
+            CASE WHEN p.id IS NULL THEN 'missing previous seq' ELSE 'prev_hash mismatch' END AS reason
+        FROM batches b
+        LEFT JOIN batches p ON p.agent_id = b.agent_id
+            AND p.epoch = b.epoch - (b.epoch_prev_seq IS NOT NULL)
+            AND p.seq = COALESCE(b.epoch_prev_seq, COALESCE(b.gap_from, b.seq) - 1)
+        WHERE (b.epoch_prev_seq IS NOT NULL OR COALESCE(b.gap_from, b.seq) > 1)
+            AND (p.id IS NULL OR p.hash != b.prev_hash)
+        UNION ALL
+        SELECT b.agent_id, b.epoch, b.seq, 'epoch starts before the last seq of the previous epoch'
+        FROM batches b
+        WHERE b.epoch_prev_seq IS NOT NULL AND EXISTS (
+            SELECT 1 FROM batches p
+            WHERE p.agent_id = b.agent_id AND p.epoch = b.epoch - 1 AND p.seq > b.epoch_prev_seq
+        )
+        ORDER BY 1, 2, 3
+        "#,
+    )
+    .fetch_all(&state.pool)
+    .await
+    .map_err(internal)?;
+
+    let broken_links: Vec<BrokenLink> = rows
+        .into_iter()
+        .map(|row| BrokenLink {
+            agent_id: row.get("agent_id"),
+            epoch: row.get::<i64, _>("epoch") as u64,
+            seq: row.get::<i64, _>("seq") as u64,
+            reason: row.get("reason"),
+        })
+        .collect();
+
+    let (logs_issues, content_issues) = stored_row_issues(&state.pool, &state.decompressor)
+        .await
+        .map_err(internal)?;
+
+    Ok(Json(IntegrityReport {
+        ok: sqlite == ["ok"]
+            && broken_links.is_empty()
+            && logs_issues.is_empty()
+            && content_issues.is_empty(),
+        sqlite,
+        broken_links,
+        logs_issues,
+        content_issues,
+    }))
+}
+
+/// Decodes every stored row and checks it against its own hash, signature and
+/// the agent's key history, streaming rows so memory stays flat. Returns the
+/// `logs_issues` and `content_issues` of the report.
+async fn stored_row_issues(
+    pool: &SqlitePool,
+    decompressor: &Decompressor,
+) -> Result<(Vec<BrokenLink>, Vec<BrokenLink>), sqlx::Error> {
+    // Loaded up front: the scan holds the connection of a single-connection pool.
+    let mut key_history: HashMap<String, Vec<_>> = HashMap::new();
+    for row in sqlx::query(
+        "SELECT agent_id, public_key, valid_from_epoch, valid_from_seq, valid_until_epoch, valid_until_seq FROM agent_keys ORDER BY valid_from_epoch, valid_from_seq, id",
+    )
+    .fetch_all(pool)
+    .await?
+    {
+        key_history
+            .entry(row.get("agent_id"))
+            .or_default()
+            .push(row_to_key_window(&row));
+    }
+
+    let select = format!("SELECT {BATCH_READ_COLUMNS} FROM batches ORDER BY agent_id, epoch, seq");
+    let mut rows = sqlx::query(&select).fetch(pool);
+
+    let mut logs_issues = Vec::new();
+    let mut content_issues = Vec::new();
+    while let Some(row) = rows.try_next().await? {
+        let agent_id: String = row.get("agent_id");
+        let epoch = row.get::<i64, _>("epoch") as u64;
+        let seq = row.get::<i64, _>("seq") as u64;
+        let stored = match tiering::compressed_logs(&row) {
+            Ok(Some(blob)) => decompressor.json(&blob),
+            Ok(None) => Ok(row.get::<String, _>("logs")),
+            Err(err) => Err(err.to_string()),
+        };
+        match stored.and_then(|json| parse_stored_logs(&json)) {
+            Ok((_, true)) => {}
+            Ok((_, false)) => logs_issues.push(BrokenLink {
+                agent_id: agent_id.clone(),
+                epoch,
+                seq,
+                reason: "logs not in canonical encoding".into(),
+            }),
+            Err(err) => {
+                // Without the lines there is no content to check.
+                logs_issues.push(BrokenLink {
+                    agent_id,
+                    epoch,
+                    seq,
+                    reason: format!("logs unreadable: {err}"),
+                });
+                continue;
+            }
+        }
+
+        let reason = match row_to_query_batch(decompressor, row) {
+            Err(_) => "row does not decode into a batch",
+            Ok(stored)
+                if stored.batch.compute_hash_redacted(&stored.redacted) != Ok(stored.hash) =>
+            {
+                "stored hash does not match contents"
+            }
+            Ok(stored) if !stored.batch.verify_redacted(&stored.redacted) => "signature invalid",
+            Ok(stored) => match key_history.get(&agent_id) {
+                // Agents from before key history existed are not checked here.
+                Some(windows)
+                    if key_valid_at(windows, (epoch, seq)).map(|w| w.public_key.as_slice())
+                        != Some(stored.batch.public_key.as_bytes().as_slice()) =>
+                {
+                    "signed by a key outside its validity window"
+                }
+                _ => continue,
+            },
+        };
+        content_issues.push(BrokenLink {
+            agent_id,
+            epoch,
+            seq,
+            reason: reason.into(),
+        });
+    }
+    Ok((logs_issues, content_issues))
+}
+
+/* ---- /admin/fsck ---- */
+
+#[derive(Debug, Default, Deserialize)]
+pub struct FsckParams {
+    /// Overrides `FSCK_CHUNK_ROWS` for this job.
+    chunk_rows: Option<u64>,
+}
+
+/// Starts a whole-store check in the background (202 with the job); 409
+/// naming the running job while another one is going.
+pub async fn handler_start_fsck(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Query(params): Query<FsckParams>,
+) -> Result<(StatusCode, Json<JobStatus>), (StatusCode, Json<AdminError>)> {
+    authorize(&auth)?;
+    match state.fsck.start(
+        state.pool.clone(),
+        state.decompressor.clone(),
+        params.chunk_rows,
+    ) {
+        Ok(status) => Ok((StatusCode::ACCEPTED, Json(status))),
+        Err(running) => Err(admin_error(
+            StatusCode::CONFLICT,
+            format!("fsck job {running} is still running"),
+        )),
+    }
+}
+
+/// The last jobs the server kept, newest first.
+pub async fn handler_fsck_jobs(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+) -> AdminResult<Vec<JobStatus>> {
+    authorize(&auth)?;
+    Ok(Json(state.fsck.list()))
+}
+
+pub async fn handler_fsck_status(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Path(id): Path<String>,
+) -> AdminResult<JobStatus> {
+    authorize(&auth)?;
+    state
+        .fsck
+        .status(&id)
+        .map(Json)
+        .ok_or_else(|| admin_error(StatusCode::NOT_FOUND, format!("no fsck job {id}")))
+}
+
+/// Stops the job after its current chunk; poll for the final state.
+pub async fn handler_abort_fsck(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Path(id): Path<String>,
+) -> AdminResult<JobStatus> {
+    authorize(&auth)?;
+    state
+        .fsck
+        .abort(&id)
+        .map(Json)
+        .ok_or_else(|| admin_error(StatusCode::NOT_FOUND, format!("no fsck job {id}")))
+}
+
+/* ---- POST /admin/server-keys/rotate ---- */
+
+/// Retires the receipt signing key and returns its successor. Receipts
+/// already issued keep verifying with the retired key, which stays listed
+/// by `GET /server-keys`.
+pub async fn handler_rotate_server_key(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+) -> AdminResult<ServerKey> {
+    authorize(&auth)?;
+    let key = state.receipts.rotate(&state.pool).await.map_err(internal)?;
+    println!("[receipts] rotated to server key {}", key.id);
+    Ok(Json(key))
+}
+
+/* ---- /admin/maintenance ---- */
+
+#[derive(Debug, Deserialize)]
+pub struct MaintenanceRequest {
+    enabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MaintenanceStatus {
+    enabled: bool,
+    retry_after_secs: u64,
+}
+
+fn maintenance_status(state: &AppState) -> MaintenanceStatus {
+    MaintenanceStatus {
+        enabled: state.maintenance.is_on(),
+        retry_after_secs: state.maintenance.retry_after_secs(),
+    }
+}
+
+pub async fn handler_maintenance(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+) -> AdminResult<MaintenanceStatus> {
+    authorize(&auth)?;
+    Ok(Json(maintenance_status(&state)))
+}
+
+/// Turns maintenance mode on or off; see [`crate::maintenance`].
+pub async fn handler_set_maintenance(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Json(req): Json<MaintenanceRequest>,
+) -> AdminResult<MaintenanceStatus> {
+    authorize(&auth)?;
+    if state.maintenance.set(req.enabled) != req.enabled {
+        println!(
+            "[maintenance] {}",
+            if req.enabled {
+                "on: refusing submits, registration and rotation"
+            } else {
+                "off: accepting submits again"
+            }
+        );
+    }
+    Ok(Json(maintenance_status(&state)))
+}
+
+/* ---- /admin/ratelimit ---- */
+
+#[derive(Debug, Deserialize)]
+pub struct DenyRequest {
+    entry: String,
+    reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UndenyRequest {
+    entry: String,
+}
+
+/// Each limiter with its live buckets, the persistence state and the
+/// denylist; see [`crate::rate_limit`].
+pub async fn handler_rate_limits(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+) -> AdminResult<RateLimitStatus> {
+    authorize(&auth)?;
+    Ok(Json(rate_limit::status(&state).await))
+}
+
+/// Refuses `agent:<id>`, an IP or a CIDR from now on, whatever its budget.
+/// An entry already listed gets the new reason.
+pub async fn handler_deny(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Json(req): Json<DenyRequest>,
+) -> AdminResult<RateLimitStatus> {
+    authorize(&auth)?;
+    let deny = Deny::parse(&req.entry).map_err(|err| admin_error(StatusCode::BAD_REQUEST, err))?;
+    let entry = rate_limit::deny(&state, deny, req.reason)
+        .await
+        .map_err(internal)?;
+    println!("[rate_limit] denylisted {}", entry.entry);
+    Ok(Json(rate_limit::status(&state).await))
+}
+
+pub async fn handler_undeny(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Json(req): Json<UndenyRequest>,
+) -> AdminResult<RateLimitStatus> {
+    authorize(&auth)?;
+    let deny = Deny::parse(&req.entry).map_err(|err| admin_error(StatusCode::BAD_REQUEST, err))?;
+    if !rate_limit::undeny(&state, &deny).await.map_err(internal)? {
+        return Err(admin_error(
+            StatusCode::NOT_FOUND,
+            format!("{deny} is not on the denylist"),
+        ));
+    }
+    println!("[rate_limit] took {deny} off the denylist");
+    Ok(Json(rate_limit::status(&state).await))
+}
+
+/* ---- /admin/forks ---- */
+
+/// Checks uploaded receipts against the store and records the forks among
+/// them; see [`crate::forks`]. Unverifiable receipts are reported, never
+/// recorded.
+pub async fn handler_record_forks(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Json(report): Json<ForkReport>,
+) -> AdminResult<Vec<ReceiptCheck>> {
+    authorize(&auth)?;
+    let checks = crate::forks::record(&state.pool, &report)
+        .await
+        .map_err(internal)?;
+    let forks = checks
+        .iter()
+        .filter(|check| check.fork_id.is_some())
+        .count();
+    if forks > 0 {
+        println!(
+            "[forks] {forks} of {} uploaded receipts no longer match the store",
+            checks.len()
+        );
+    }
+    Ok(Json(checks))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ForkParams {
+    agent_id: Option<String>,
+}
+
+pub async fn handler_forks(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Query(params): Query<ForkParams>,
+) -> AdminResult<Vec<Fork>> {
+    authorize(&auth)?;
+    crate::forks::list(&state.pool, params.agent_id.as_deref())
+        .await
+        .map(Json)
+        .map_err(internal)
+}
+
+/* ---- POST /admin/redactions ---- */
+
+#[derive(Debug, Deserialize)]
+pub struct RedactRequest {
+    batch_id: i64,
+    line_idx: usize,
+}
+
+/// Removes the content of one line for good; see [`crate::redactions`].
+pub async fn handler_redact(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Json(request): Json<RedactRequest>,
+) -> AdminResult<Redaction> {
+    authorize(&auth)?;
+    crate::redactions::redact(&state, request.batch_id, request.line_idx, auth.identity())
+        .await
+        .map(Json)
+        .map_err(|err| admin_error(err.status(), err.to_string()))
+}
+
+/* ---- GET /admin/rejections ---- */
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RejectionParams {
+    agent_id: Option<String>,
+    category: Option<String>,
+    limit: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Rejection {
+    id: i64,
+    agent_id: Option<String>,
+    category: String,
+    reason: String,
+    source: Option<String>,
+    created_at: i64,
+    /// Recorded with `STORE_CLIENT_INFO` on.
+    user_agent: Option<String>,
+    tls_fingerprint: Option<String>,
+    /// The refused request's `X-Request-Id`.
+    request_id: Option<String>,
+}
+
+/// Newest first; defaults to 100 rows.
+pub async fn handler_rejections(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Query(params): Query<RejectionParams>,
+) -> AdminResult<Vec<Rejection>> {
+    authorize(&auth)?;
+
+    let mut builder = sqlx::QueryBuilder::new(
+        "SELECT id, agent_id, category, reason, source, created_at, user_agent, tls_fingerprint, request_id FROM rejections WHERE 1 = 1",
+    );
+    if let Some(agent) = &params.agent_id {
+        builder.push(" AND agent_id = ");
+        builder.push_bind(agent);
+    }
+    if let Some(category) = &params.category {
+        builder.push(" AND category = ");
+        builder.push_bind(category);
+    }
+    builder.push(" ORDER BY id DESC LIMIT ");
+    builder.push_bind(params.limit.unwrap_or(100) as i64);
+
+    let rows = builder
+        .build()
+        .fetch_all(&state.pool)
+        .await
+        .map_err(internal)?;
+
+    Ok(Json(
+        rows.into_iter()
+            .map(|row| Rejection {
+                id: row.get("id"),
+                agent_id: row.get("agent_id"),
+                category: row.get("category"),
+                reason: row.get("reason"),
+                source: row.get("source"),
+                created_at: row.get("created_at"),
+                user_agent: row.get("user_agent"),
+                tls_fingerprint: row.get("tls_fingerprint"),
+                request_id: row.get("request_id"),
+            })
+            .collect(),
+    ))
+}
+
+/* ---- GET /admin/key-conflicts ---- */
+
+/// Keys currently shared by several agents; see [`crate::key_conflicts`].
+pub async fn handler_key_conflicts(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+) -> AdminResult<Vec<KeyConflict>> {
+    authorize(&auth)?;
+    key_conflicts(&state.pool).await.map(Json).map_err(internal)
+}
+
+/* ---- POST /admin/agents/:agent_id/revoke ---- */
+
+#[derive(Debug, Serialize)]
+pub struct RevokeResponse {
+    agent_id: String,
+    /// First position, `(epoch, seq)`, the agent can no longer submit.
+    #[serde(skip_serializing_if = "is_zero")]
+    revoked_from_epoch: u64,
+    revoked_from_seq: u64,
+}
+
+/// Closes the agent's current key window and blocks further submits,
+/// registration and rotation for it. Stored batches stay verifiable.
+pub async fn handler_revoke_agent(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Path(agent_id): Path<String>,
+) -> AdminResult<RevokeResponse> {
+    authorize(&auth)?;
+
+    let mut tx = state.pool.begin().await.map_err(internal)?;
+    let updated =
+        sqlx::query("UPDATE agents SET revoked_at = ?1 WHERE agent_id = ?2 AND revoked_at IS NULL")
+            .bind(now_unix())
+            .bind(&agent_id)
+            .execute(tx.as_mut())
+            .await
+            .map_err(internal)?;
+    if updated.rows_affected() == 0 {
+        return Err(admin_error(
+            StatusCode::NOT_FOUND,
+            "agent not registered or already revoked",
+        ));
+    }
+
+    let (epoch, seq) = next_position(tx.as_mut(), &agent_id)
+        .await
+        .map_err(internal)?;
+    close_key_window(tx.as_mut(), &agent_id, (epoch, seq))
+        .await
+        .map_err(internal)?;
+    tx.commit().await.map_err(internal)?;
+
+    Ok(Json(RevokeResponse {
+        agent_id,
+        revoked_from_epoch: epoch,
+        revoked_from_seq: seq,
+    }))
+}
+
+/* ---- POST /admin/tokens ---- */
+
+#[derive(Deserialize)]
+pub struct CreateTokenRequest {
+    tenant: String,
+    /// Defaults to `["submit"]`, what minted tokens were before scopes.
+    #[serde(default)]
+    scopes: Option<Vec<Scope>>,
+    /// Restricts the token to submitting and registering for this agent.
+    #[serde(default)]
+    agent_id: Option<String>,
+    #[serde(default)]
+    expires_in_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreateTokenResponse {
+    id: i64,
+    tenant: String,
+    scopes: Scopes,
+    agent_id: Option<String>,
+    expires_at: Option<i64>,
+    /// Shown once; only its SHA-256 is stored.
+    token: String,
+}
+
+/// Mints a bearer token labelled with `tenant`. Once any token carries a
+/// scope, the endpoints behind that scope require a token granting it.
+pub async fn handler_create_token(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Json(req): Json<CreateTokenRequest>,
+) -> AdminResult<CreateTokenResponse> {
+    authorize(&auth)?;
+    if req.tenant.trim().is_empty() {
+        return Err(admin_error(
+            StatusCode::BAD_REQUEST,
+            "tenant must not be empty",
+        ));
+    }
+    let scopes = Scopes::of(req.scopes.as_deref().unwrap_or(&[Scope::Submit]));
+    if scopes.is_empty() {
+        return Err(admin_error(
+            StatusCode::BAD_REQUEST,
+            "scopes must not be empty",
+        ));
+    }
+    // Reads and exports span agents, so a bound token could not keep to its agent there.
+    if req.agent_id.is_some()
+        && [Scope::Read, Scope::Export, Scope::Admin]
+            .into_iter()
+            .any(|scope| scopes.contains(scope))
+    {
+        return Err(admin_error(
+            StatusCode::BAD_REQUEST,
+            "agent-bound tokens may only carry submit and register",
+        ));
+    }
+    let expires_at = req
+        .expires_in_secs
+        .map(|secs| now_unix().saturating_add(secs.min(i64::MAX as u64) as i64));
+
+    let mut raw = [0u8; 32];
+    OsRng.fill_bytes(&mut raw);
+    let token = hex_encode(&raw);
+
+    let id = sqlx::query(
+        "INSERT INTO api_tokens (tenant, token_sha256, created_at, scopes, agent_id, expires_at) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )
+    .bind(&req.tenant)
+    .bind(token_hash(&token))
+    .bind(now_unix())
+    .bind(scopes.to_string())
+    .bind(&req.agent_id)
+    .bind(expires_at)
+    .execute(&state.pool)
+    .await
+    .map_err(internal)?
+    .last_insert_rowid();
+
+    Ok(Json(CreateTokenResponse {
+        id,
+        tenant: req.tenant,
+        scopes,
+        agent_id: req.agent_id,
+        expires_at,
+        token,
+    }))
+}
+
+/* ---- POST /admin/tokens/:id/revoke ---- */
+
+#[derive(Debug, Serialize)]
+pub struct RevokeTokenResponse {
+    id: i64,
+    revoked_at: i64,
+}
+
+/// Revoked tokens stop authenticating at once. Their scopes stay enforced, so
+/// revoking the last token of a scope does not reopen its endpoints.
+pub async fn handler_revoke_token(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Path(id): Path<i64>,
+) -> AdminResult<RevokeTokenResponse> {
+    authorize(&auth)?;
+    let revoked_at = now_unix();
+    let updated =
+        sqlx::query("UPDATE api_tokens SET revoked_at = ?1 WHERE id = ?2 AND revoked_at IS NULL")
+            .bind(revoked_at)
+            .bind(id)
+            .execute(&state.pool)
+            .await
+            .map_err(internal)?;
+    if updated.rows_affected() == 0 {
+        return Err(admin_error(
+            StatusCode::NOT_FOUND,
+            "token not found or already revoked",
+        ));
+    }
+    Ok(Json(RevokeTokenResponse { id, revoked_at }))
+}
@@ -0,0 +1,217 @@
+//! Opt-in anomaly scoring on submit: per-agent exponentially weighted
+//! statistics of batch size and arrival interval, kept in memory. A batch is
+//! scored by how many deviations it sits from its agent's norm; nothing is
+//! rejected for it. One map lookup and a few float operations per submit.
+
+use crate::AppState;
+use axum::{Json, extract::State, http::StatusCode};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Weight of the newest batch in the moving statistics.
+const ALPHA: f64 = 0.1;
+/// Batches an agent needs before it is scored at all.
+pub const WARMUP_BATCHES: u64 = 10;
+/// Floor for the deviation (log scale) so a perfectly steady agent does not
+/// make every small change look extreme: about a 28% change per point.
+const MIN_DEVIATION: f64 = 0.25;
+
+/// Exponentially weighted mean and variance.
+#[derive(Debug, Clone, Copy, Default)]
+struct Ewm {
+    mean: f64,
+    var: f64,
+}
+
+impl Ewm {
+    fn update(&mut self, x: f64, first: bool) {
+        if first {
+            self.mean = x;
+            return;
+        }
+        let diff = x - self.mean;
+        let incr = ALPHA * diff;
+        self.mean += incr;
+        self.var = (1.0 - ALPHA) * (self.var + diff * incr);
+    }
+
+    fn deviation(&self) -> f64 {
+        self.var.sqrt().max(MIN_DEVIATION)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct AgentStats {
+    batches: u64,
+    /// Both on a log scale: sizes and gaps vary by orders of magnitude.
+    size: Ewm,
+    interval: Ewm,
+    last_received_ms: Option<i64>,
+    last_score: Option<f64>,
+}
+
+impl AgentStats {
+    fn score(&self, size_bytes: u64, received_ms: i64) -> Option<f64> {
+        if self.batches < WARMUP_BATCHES {
+            return None;
+        }
+        // Larger than usual; smaller batches are not suspicious.
+        let size = (ln(size_bytes as f64) - self.size.mean) / self.size.deviation();
+        // Sooner than usual; a gap is the stale-agent report's business.
+        let rate = self.last_received_ms.map_or(0.0, |last| {
+            (self.interval.mean - ln((received_ms - last) as f64)) / self.interval.deviation()
+        });
+        Some(size.max(rate).max(0.0))
+    }
+
+    fn record(&mut self, size_bytes: u64, received_ms: i64, score: Option<f64>) {
+        self.size.update(ln(size_bytes as f64), self.batches == 0);
+        if let Some(last) = self.last_received_ms {
+            self.interval
+                .update(ln((received_ms - last) as f64), self.batches == 1);
+        }
+        self.batches += 1;
+        self.last_received_ms = Some(received_ms);
+        self.last_score = score;
+    }
+}
+
+fn ln(x: f64) -> f64 {
+    x.max(0.0).ln_1p()
+}
+
+pub struct AnomalyTracker {
+    threshold: f64,
+    agents: Mutex<HashMap<String, AgentStats>>,
+}
+
+impl AnomalyTracker {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            agents: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Score of a batch about to be stored; `None` while the agent warms up.
+    pub fn score(&self, agent_id: &str, size_bytes: u64, received_ms: i64) -> Option<f64> {
+        let agents = self.agents.lock().unwrap();
+        agents
+            .get(agent_id)
+            .and_then(|stats| stats.score(size_bytes, received_ms))
+    }
+
+    /// Folds a stored batch into its agent's statistics.
+    pub fn record(&self, agent_id: &str, size_bytes: u64, received_ms: i64, score: Option<f64>) {
+        let mut agents = self.agents.lock().unwrap();
+        agents
+            .entry(agent_id.to_string())
+            .or_default()
+            .record(size_bytes, received_ms, score);
+    }
+
+    pub fn exceeds(&self, score: f64) -> bool {
+        score > self.threshold
+    }
+
+    fn snapshot(&self) -> Vec<AgentAnomalyStats> {
+        let agents = self.agents.lock().unwrap();
+        let mut out: Vec<AgentAnomalyStats> = agents
+            .iter()
+            .map(|(agent_id, stats)| AgentAnomalyStats {
+                agent_id: agent_id.clone(),
+                batches: stats.batches,
+                typical_size_bytes: stats.size.mean.exp_m1().round() as u64,
+                size_log_deviation: stats.size.var.sqrt(),
+                typical_interval_ms: (stats.batches > 1)
+                    .then(|| stats.interval.mean.exp_m1().round() as u64),
+                interval_log_deviation: stats.interval.var.sqrt(),
+                last_score: stats.last_score,
+                warmed_up: stats.batches >= WARMUP_BATCHES,
+            })
+            .collect();
+        out.sort_by(|a, b| a.agent_id.cmp(&b.agent_id));
+        out
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AgentAnomalyStats {
+    agent_id: String,
+    batches: u64,
+    /// Geometric-mean style typical values, with deviations on the log scale
+    /// the score is computed in.
+    typical_size_bytes: u64,
+    size_log_deviation: f64,
+    typical_interval_ms: Option<u64>,
+    interval_log_deviation: f64,
+    last_score: Option<f64>,
+    warmed_up: bool,
+}
+
+/// `GET /agents/anomaly` – the in-memory statistics, for tuning the threshold.
+pub async fn handler_anomaly_stats(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<AgentAnomalyStats>>, StatusCode> {
+    let tracker = state.anomaly.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(tracker.snapshot()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `n` steady batches: 1000 bytes every 10s.
+    fn steady(tracker: &AnomalyTracker, n: i64) -> i64 {
+        let mut now = 0;
+        for i in 0..n {
+            now = i * 10_000 + (i % 3) * 200;
+            let size = 1_000 + (i as u64 % 4) * 50;
+            let score = tracker.score("a", size, now);
+            tracker.record("a", size, now, score);
+        }
+        now
+    }
+
+    #[test]
+    fn warms_up_before_scoring() {
+        let tracker = AnomalyTracker::new(4.0);
+        assert_eq!(tracker.score("a", 1_000, 0), None);
+        steady(&tracker, WARMUP_BATCHES as i64 - 1);
+        assert_eq!(tracker.score("a", 1_000_000, 100_000), None);
+        tracker.record("a", 1_000, 100_000, None);
+        assert!(tracker.score("a", 1_000_000, 110_000).is_some());
+    }
+
+    #[test]
+    fn flags_size_and_rate_spikes_but_not_normal_batches() {
+        let tracker = AnomalyTracker::new(4.0);
+        let now = steady(&tracker, 50);
+
+        let normal = tracker.score("a", 1_100, now + 10_000).unwrap();
+        assert!(!tracker.exceeds(normal), "normal batch scored {normal}");
+        // Smaller or later than usual is fine.
+        assert!(!tracker.exceeds(tracker.score("a", 10, now + 600_000).unwrap()));
+
+        let huge = tracker.score("a", 100_000, now + 10_000).unwrap();
+        assert!(tracker.exceeds(huge), "100x batch scored {huge}");
+        let burst = tracker.score("a", 1_000, now + 50).unwrap();
+        assert!(tracker.exceeds(burst), "burst scored {burst}");
+
+        // Another agent has its own norm.
+        assert_eq!(tracker.score("b", 100_000, now), None);
+    }
+
+    #[test]
+    fn exposes_typical_values() {
+        let tracker = AnomalyTracker::new(4.0);
+        steady(&tracker, 50);
+        let stats = serde_json::to_value(tracker.snapshot()).unwrap();
+        let size = stats[0]["typical_size_bytes"].as_u64().unwrap();
+        let interval = stats[0]["typical_interval_ms"].as_u64().unwrap();
+        assert!((1_000..1_150).contains(&size), "{size}");
+        assert!((9_000..11_000).contains(&interval), "{interval}");
+        assert_eq!(stats[0]["warmed_up"], true);
+    }
+}
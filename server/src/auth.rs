@@ -0,0 +1,372 @@
+//! Bearer tokens and their scopes. A middleware resolves the token once per
+//! request into an [`AuthContext`] extension; every endpoint requires one
+//! [`Scope`], either in its handler or through [`scoped`] in the router.
+//!
+//! `SUBMIT_BEARER_TOKEN` and `ADMIN_BEARER_TOKEN` are bootstrap tokens with
+//! fixed scopes. Tokens minted via `/admin/tokens` live in `api_tokens`, kept
+//! only as SHA-256 hashes, with their scopes, an optional agent binding, an
+//! optional expiry and a revocation timestamp.
+//!
+//! A scope no credential carries stays open, as every endpoint was before
+//! tokens existed; once one does, the scope needs a token. The admin scope is
+//! the exception: without a credential for it the admin API is disabled.
+
+use crate::{AppState, now_unix, valid_auth};
+use axum::{
+    Extension, Json,
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Row, SqlitePool};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// `POST /submit`.
+    Submit,
+    /// Batch, agent and metrics reads.
+    Read,
+    /// `GET /batches/export`.
+    Export,
+    /// Everything under `/admin`, including minting tokens.
+    Admin,
+    /// `POST /agents/register`, `/agents/register/bulk` and `/agents/rotate`.
+    Register,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 5] = [
+        Scope::Submit,
+        Scope::Read,
+        Scope::Export,
+        Scope::Admin,
+        Scope::Register,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Submit => "submit",
+            Scope::Read => "read",
+            Scope::Export => "export",
+            Scope::Admin => "admin",
+            Scope::Register => "register",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| format!("unknown scope '{s}'"))
+    }
+}
+
+/// A set of scopes, stored as a comma-separated list.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Scopes(u8);
+
+impl Scopes {
+    pub fn of(scopes: &[Scope]) -> Self {
+        Self(scopes.iter().fold(0, |bits, scope| bits | scope.bit()))
+    }
+
+    pub fn contains(self, scope: Scope) -> bool {
+        self.0 & scope.bit() != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    fn iter(self) -> impl Iterator<Item = Scope> {
+        Scope::ALL
+            .into_iter()
+            .filter(move |scope| self.contains(*scope))
+    }
+
+    pub fn parse(list: &str) -> Result<Self, String> {
+        list.split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| s.trim().parse::<Scope>())
+            .collect::<Result<Vec<_>, _>>()
+            .map(|scopes| Self::of(&scopes))
+    }
+}
+
+impl fmt::Display for Scopes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.iter().map(Scope::as_str).collect();
+        f.write_str(&names.join(","))
+    }
+}
+
+impl Serialize for Scopes {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+/// What `SUBMIT_BEARER_TOKEN` grants.
+const SUBMIT_BOOTSTRAP_SCOPES: [Scope; 1] = [Scope::Submit];
+/// What `ADMIN_BEARER_TOKEN` grants: everything but submitting batches.
+const ADMIN_BOOTSTRAP_SCOPES: [Scope; 4] =
+    [Scope::Admin, Scope::Read, Scope::Export, Scope::Register];
+
+/// The caller, as far as its bearer token tells.
+#[derive(Debug, Clone, Default)]
+pub struct AuthContext {
+    /// Scopes the presented token grants; empty without a valid token.
+    granted: Scopes,
+    /// Scopes some credential carries, i.e. the ones that are not open.
+    enforced: Scopes,
+    /// Set when the token may only act for this agent.
+    agent_id: Option<String>,
+    /// Which credential it is, for the records of admin actions:
+    /// `bootstrap` for `ADMIN_BEARER_TOKEN`, `token:<id>` for a minted one.
+    identity: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// Admin scope required but no credential carries it.
+    Disabled,
+    /// No valid token presented.
+    Unauthenticated,
+    /// Valid token without the scope, or bound to another agent.
+    Forbidden,
+}
+
+impl AuthError {
+    pub fn status(self) -> StatusCode {
+        match self {
+            AuthError::Disabled | AuthError::Forbidden => StatusCode::FORBIDDEN,
+            AuthError::Unauthenticated => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            AuthError::Disabled => "admin API disabled; set ADMIN_BEARER_TOKEN to enable",
+            AuthError::Unauthenticated => "missing or invalid bearer token",
+            AuthError::Forbidden => "token lacks the required scope",
+        }
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({ "status": "error", "message": self.message() });
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+impl AuthContext {
+    pub fn require(&self, scope: Scope) -> Result<(), AuthError> {
+        if self.granted.contains(scope) {
+            return Ok(());
+        }
+        if !self.enforced.contains(scope) {
+            return match scope {
+                Scope::Admin => Err(AuthError::Disabled),
+                _ => Ok(()),
+            };
+        }
+        if self.granted.is_empty() {
+            Err(AuthError::Unauthenticated)
+        } else {
+            Err(AuthError::Forbidden)
+        }
+    }
+
+    /// The credential presented, `anonymous` without one.
+    pub fn identity(&self) -> &str {
+        self.identity.as_deref().unwrap_or("anonymous")
+    }
+
+    /// [`require`](Self::require), and the token must not be bound to
+    /// another agent.
+    pub fn require_agent(&self, scope: Scope, agent_id: &str) -> Result<(), AuthError> {
+        self.require(scope)?;
+        match &self.agent_id {
+            Some(bound) if bound != agent_id => Err(AuthError::Forbidden),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Resolves the bearer token in `headers`. Expired and revoked tokens resolve
+/// like no token at all.
+pub async fn resolve(state: &AppState, headers: &HeaderMap) -> AuthContext {
+    let mut ctx = AuthContext {
+        enforced: enforced_scopes(state).await,
+        ..AuthContext::default()
+    };
+    if let Some(expected) = &state.auth_token
+        && valid_auth(headers, expected)
+    {
+        ctx.granted = ctx.granted.union(Scopes::of(&SUBMIT_BOOTSTRAP_SCOPES));
+    }
+    if let Some(expected) = &state.admin_token
+        && valid_auth(headers, expected)
+    {
+        ctx.granted = ctx.granted.union(Scopes::of(&ADMIN_BOOTSTRAP_SCOPES));
+        ctx.identity = Some("bootstrap".into());
+    }
+    if ctx.granted.is_empty()
+        && let Some(token) = bearer_token(headers)
+        && let Some(minted) = lookup_token(&state.pool, token, now_unix()).await
+    {
+        ctx.granted = minted.granted;
+        ctx.agent_id = minted.agent_id;
+        ctx.identity = minted.identity;
+    }
+    ctx
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+pub fn token_hash(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// Lookup is by SHA-256, so timing reveals nothing about the stored tokens.
+async fn lookup_token(pool: &SqlitePool, token: &str, now: i64) -> Option<AuthContext> {
+    let row = sqlx::query(
+        "SELECT id, agent_id, scopes FROM api_tokens \
+         WHERE token_sha256 = ?1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > ?2)",
+    )
+    .bind(token_hash(token))
+    .bind(now)
+    .fetch_optional(pool)
+    .await
+    .ok()??;
+    Some(AuthContext {
+        granted: Scopes::parse(row.get("scopes")).ok()?,
+        enforced: Scopes::default(),
+        agent_id: row.get("agent_id"),
+        identity: Some(format!("token:{}", row.get::<i64, _>("id"))),
+    })
+}
+
+/// Scopes carried by a bootstrap token or any minted one. Revoked and expired
+/// tokens count too: revoking the last read token must not open reads. Fails
+/// closed.
+async fn enforced_scopes(state: &AppState) -> Scopes {
+    let mut enforced = Scopes::default();
+    if state.auth_token.is_some() {
+        enforced = enforced.union(Scopes::of(&[Scope::Submit]));
+    }
+    if state.admin_token.is_some() {
+        enforced = enforced.union(Scopes::of(&[Scope::Admin]));
+    }
+    match sqlx::query_scalar::<_, String>("SELECT DISTINCT scopes FROM api_tokens")
+        .fetch_all(&state.pool)
+        .await
+    {
+        Ok(lists) => lists.iter().fold(enforced, |acc, list| {
+            acc.union(Scopes::parse(list).unwrap_or(Scopes::of(&Scope::ALL)))
+        }),
+        Err(_) => Scopes::of(&Scope::ALL),
+    }
+}
+
+/// Resolves the token once and attaches the [`AuthContext`] for handlers and
+/// [`scoped`] routes.
+pub async fn middleware(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let ctx = resolve(&state, request.headers()).await;
+    request.extensions_mut().insert(ctx);
+    next.run(request).await
+}
+
+/// Puts `route` behind `scope`, for handlers that do not check it themselves.
+pub fn scoped(scope: Scope, route: MethodRouter<AppState>) -> MethodRouter<AppState> {
+    route.route_layer(middleware::from_fn(
+        move |Extension(ctx): Extension<AuthContext>, request: Request, next: Next| async move {
+            match ctx.require(scope) {
+                Ok(()) => next.run(request).await,
+                Err(err) => err.into_response(),
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx(granted: &[Scope], enforced: &[Scope], agent_id: Option<&str>) -> AuthContext {
+        AuthContext {
+            granted: Scopes::of(granted),
+            enforced: Scopes::of(enforced),
+            agent_id: agent_id.map(Into::into),
+            identity: None,
+        }
+    }
+
+    #[test]
+    fn open_scopes_need_no_token_but_admin_is_disabled() {
+        let anonymous = ctx(&[], &[], None);
+        for scope in [Scope::Submit, Scope::Read, Scope::Export, Scope::Register] {
+            assert_eq!(anonymous.require(scope), Ok(()), "{scope:?}");
+        }
+        assert_eq!(anonymous.require(Scope::Admin), Err(AuthError::Disabled));
+
+        let closed = ctx(&[], &[Scope::Read], None);
+        assert_eq!(closed.require(Scope::Read), Err(AuthError::Unauthenticated));
+        assert_eq!(closed.require(Scope::Export), Ok(()));
+        let submitter = ctx(&[Scope::Submit], &[Scope::Read], None);
+        assert_eq!(submitter.require(Scope::Read), Err(AuthError::Forbidden));
+    }
+
+    #[test]
+    fn agent_binding_limits_the_agent() {
+        let bound = ctx(&[Scope::Submit], &[Scope::Submit], Some("a"));
+        assert_eq!(bound.require_agent(Scope::Submit, "a"), Ok(()));
+        assert_eq!(
+            bound.require_agent(Scope::Submit, "b"),
+            Err(AuthError::Forbidden)
+        );
+        let unbound = ctx(&[Scope::Submit], &[Scope::Submit], None);
+        assert_eq!(unbound.require_agent(Scope::Submit, "b"), Ok(()));
+    }
+
+    #[test]
+    fn scope_lists_round_trip() {
+        let scopes = Scopes::parse("read, export,admin").unwrap();
+        assert_eq!(scopes.to_string(), "read,export,admin");
+        assert_eq!(Scopes::parse(&scopes.to_string()), Ok(scopes));
+        assert_eq!(
+            serde_json::to_value(scopes).unwrap(),
+            serde_json::json!(["read", "export", "admin"])
+        );
+        assert!(Scopes::parse("read,write").is_err());
+    }
+}
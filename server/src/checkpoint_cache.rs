@@ -0,0 +1,314 @@
+//! Agent checkpoints behind `/batches/checkpoints` (and the gRPC
+//! `Checkpoints`). Grouping the whole `batches` table per read gets slower
+//! as the table grows, and agents poll it at startup while dashboards poll
+//! it continuously, so each agent's head is kept in `checkpoints_cache`,
+//! one row per agent upserted in the same transaction as every accepted
+//! submit. Reads are index lookups whatever the size of `batches`.
+//!
+//! Writes that bypass the submit path (a restore, a manual insert) make the
+//! table drift; [`reconcile`] rebuilds it from `batches` at startup and
+//! reports what it repaired.
+//!
+//! On top of the table, the full list is also served from memory. A stored
+//! batch always extends its agent's head, so each submit updates that
+//! agent's entry in place; anything else is picked up once the copy is
+//! older than `CHECKPOINT_CACHE_MAX_AGE_MS`, and `0` turns it off.
+//!
+//! Heads as of a past moment ([`as_of`]) come from `batches` instead. Its
+//! rows are never updated or deleted, so the rows that had arrived by then
+//! are exactly the ones the server held.
+
+use crate::{AgentCheckpoint, CheckpointAsOf, RECEIVED_AT_MS_EXPR, load_checkpoints};
+use common::batch::LogBatch;
+use sqlx::{QueryBuilder, Row, SqliteConnection, SqlitePool};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_AGE_MS: u64 = 5000;
+
+pub struct CheckpointCache {
+    max_age: Duration,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Bumped by every stored batch, so a reload that raced one is not kept.
+    generation: u64,
+    loaded: Option<(Instant, BTreeMap<String, AgentCheckpoint>)>,
+}
+
+impl CheckpointCache {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Every agent's checkpoint, ordered by agent_id; from memory while the
+    /// cache is fresh, reloaded otherwise.
+    pub async fn get(&self, pool: &SqlitePool) -> Result<Vec<AgentCheckpoint>, sqlx::Error> {
+        if self.max_age.is_zero() {
+            return load_checkpoints(pool).await;
+        }
+        let generation = {
+            let state = self.state.lock().unwrap();
+            if let Some((at, checkpoints)) = &state.loaded
+                && at.elapsed() <= self.max_age
+            {
+                return Ok(checkpoints.values().cloned().collect());
+            }
+            state.generation
+        };
+
+        let fresh = load_checkpoints(pool).await?;
+        let mut state = self.state.lock().unwrap();
+        // A batch stored meanwhile may or may not be in `fresh`; serve it
+        // once, but do not keep it.
+        if state.generation == generation {
+            let by_agent = fresh
+                .iter()
+                .map(|cp| (cp.agent_id.clone(), cp.clone()))
+                .collect();
+            state.loaded = Some((Instant::now(), by_agent));
+        }
+        Ok(fresh)
+    }
+
+    /// `batch`, hashing to `hash`, was stored. Its agent's entry moves to it
+    /// if the entry is its predecessor; an entry that already shows it is
+    /// left alone, and anything else drops the cache.
+    pub fn stored(&self, batch: &LogBatch, hash: [u8; 32]) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        let Some((_, checkpoints)) = &mut state.loaded else {
+            return;
+        };
+        let consistent = match checkpoints.get_mut(&batch.agent_id) {
+            Some(cp) if cp.last_hash == hash => true,
+            Some(cp) if cp.last_hash == batch.prev_hash => {
+                cp.last_epoch = batch.epoch;
+                cp.last_seq = batch.seq;
+                cp.last_hash = hash;
+                cp.last_accumulator = batch.accumulator;
+                cp.count += 1;
+                true
+            }
+            Some(_) => false,
+            None if batch.position() == (0, 1) => {
+                checkpoints.insert(
+                    batch.agent_id.clone(),
+                    AgentCheckpoint {
+                        agent_id: batch.agent_id.clone(),
+                        last_epoch: 0,
+                        last_seq: 1,
+                        last_hash: hash,
+                        count: 1,
+                        last_accumulator: batch.accumulator,
+                    },
+                );
+                true
+            }
+            None => false,
+        };
+        if !consistent {
+            state.loaded = None;
+        }
+    }
+}
+
+/// Moves `batch`'s agent to it in `checkpoints_cache`; run inside the
+/// transaction that stores it.
+pub async fn record(
+    conn: &mut SqliteConnection,
+    batch: &LogBatch,
+    hash: [u8; 32],
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO checkpoints_cache (agent_id, last_epoch, last_seq, last_hash, last_accumulator, count) \
+         VALUES (?1, ?2, ?3, ?4, ?5, 1) \
+         ON CONFLICT(agent_id) DO UPDATE SET last_epoch = excluded.last_epoch, \
+             last_seq = excluded.last_seq, last_hash = excluded.last_hash, \
+             last_accumulator = excluded.last_accumulator, count = checkpoints_cache.count + 1",
+    )
+    .bind(&batch.agent_id)
+    .bind(batch.epoch as i64)
+    .bind(batch.seq as i64)
+    .bind(hash.to_vec())
+    .bind(batch.accumulator.map(|acc| acc.to_vec()))
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// `checkpoints_cache` in agent_id order: only `agent_id` if given, only
+/// agents after `after`, at most `limit`.
+pub async fn read(
+    pool: &SqlitePool,
+    agent_id: Option<&str>,
+    after: Option<&str>,
+    limit: Option<u64>,
+) -> Result<Vec<AgentCheckpoint>, sqlx::Error> {
+    let mut builder = QueryBuilder::new(
+        "SELECT agent_id, last_epoch, last_seq, last_hash, last_accumulator, count \
+         FROM checkpoints_cache WHERE 1 = 1",
+    );
+    if let Some(agent_id) = agent_id {
+        builder.push(" AND agent_id = ");
+        builder.push_bind(agent_id);
+    }
+    if let Some(after) = after {
+        builder.push(" AND agent_id > ");
+        builder.push_bind(after);
+    }
+    builder.push(" ORDER BY agent_id");
+    if let Some(limit) = limit {
+        builder.push(" LIMIT ");
+        builder.push_bind(limit as i64);
+    }
+    let rows = builder.build().fetch_all(pool).await?;
+    rows.iter().map(row_to_checkpoint).collect()
+}
+
+/// Every agent's checkpoint computed from `batches` itself, ordered by
+/// agent_id; what `checkpoints_cache` must agree with.
+pub async fn scan(conn: &mut SqliteConnection) -> Result<Vec<AgentCheckpoint>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT
+            agent_id,
+            MAX(epoch) AS last_epoch,
+            (SELECT seq FROM batches b2 WHERE b2.agent_id = b.agent_id ORDER BY epoch DESC, seq DESC LIMIT 1) AS last_seq,
+            COUNT(*) AS count,
+            (SELECT hash FROM batches b2 WHERE b2.agent_id = b.agent_id ORDER BY epoch DESC, seq DESC LIMIT 1) AS last_hash,
+            (SELECT accumulator FROM batches b2 WHERE b2.agent_id = b.agent_id ORDER BY epoch DESC, seq DESC LIMIT 1) AS last_accumulator
+        FROM batches b
+        GROUP BY agent_id
+        ORDER BY agent_id
+        "#,
+    )
+    .fetch_all(conn)
+    .await?;
+    rows.iter().map(row_to_checkpoint).collect()
+}
+
+/// Each agent's checkpoint over the batches that had arrived by `as_of_ms`
+/// (inclusive), with its head row; filtered and paged like [`read`]. An
+/// agent with nothing stored by then is left out.
+pub async fn as_of(
+    pool: &SqlitePool,
+    as_of_ms: u64,
+    agent_id: Option<&str>,
+    after: Option<&str>,
+    limit: Option<u64>,
+) -> Result<Vec<CheckpointAsOf>, sqlx::Error> {
+    let mut builder = QueryBuilder::new(format!(
+        "SELECT agent_id, epoch AS last_epoch, seq AS last_seq, hash AS last_hash, \
+             accumulator AS last_accumulator, count, id, received_at_ms \
+         FROM (SELECT agent_id, epoch, seq, hash, accumulator, id, \
+                 {RECEIVED_AT_MS_EXPR} AS received_at_ms, \
+                 COUNT(*) OVER (PARTITION BY agent_id) AS count, \
+                 ROW_NUMBER() OVER (PARTITION BY agent_id ORDER BY epoch DESC, seq DESC) AS rank \
+             FROM batches WHERE {RECEIVED_AT_MS_EXPR} <= "
+    ));
+    builder.push_bind(as_of_ms as i64);
+    if let Some(agent_id) = agent_id {
+        builder.push(" AND agent_id = ");
+        builder.push_bind(agent_id);
+    }
+    if let Some(after) = after {
+        builder.push(" AND agent_id > ");
+        builder.push_bind(after);
+    }
+    builder.push(") WHERE rank = 1 ORDER BY agent_id");
+    if let Some(limit) = limit {
+        builder.push(" LIMIT ");
+        builder.push_bind(limit as i64);
+    }
+    let rows = builder.build().fetch_all(pool).await?;
+    rows.iter()
+        .map(|row| {
+            Ok(CheckpointAsOf {
+                checkpoint: row_to_checkpoint(row)?,
+                batch_id: row.try_get("id")?,
+                received_at_ms: row.try_get::<i64, _>("received_at_ms")? as u64,
+            })
+        })
+        .collect()
+}
+
+/// Brings `checkpoints_cache` in line with `batches`: missing or drifted
+/// entries are rewritten and entries without batches removed, in one
+/// transaction. Returns the agents repaired.
+pub async fn reconcile(pool: &SqlitePool) -> Result<Vec<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let expected: BTreeMap<String, AgentCheckpoint> = scan(&mut tx)
+        .await?
+        .into_iter()
+        .map(|cp| (cp.agent_id.clone(), cp))
+        .collect();
+    let cached = sqlx::query(
+        "SELECT agent_id, last_epoch, last_seq, last_hash, last_accumulator, count \
+         FROM checkpoints_cache",
+    )
+    .fetch_all(tx.as_mut())
+    .await?;
+    let mut cached: BTreeMap<String, Option<AgentCheckpoint>> = cached
+        .iter()
+        .map(|row| {
+            // An undecodable row counts as drifted and is rewritten.
+            let agent_id: String = row.get("agent_id");
+            (agent_id, row_to_checkpoint(row).ok())
+        })
+        .collect();
+
+    let mut repaired = Vec::new();
+    for (agent_id, cp) in &expected {
+        if cached.remove(agent_id).flatten().as_ref() == Some(cp) {
+            continue;
+        }
+        sqlx::query(
+            "INSERT OR REPLACE INTO checkpoints_cache \
+             (agent_id, last_epoch, last_seq, last_hash, last_accumulator, count) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )
+        .bind(agent_id)
+        .bind(cp.last_epoch as i64)
+        .bind(cp.last_seq as i64)
+        .bind(cp.last_hash.to_vec())
+        .bind(cp.last_accumulator.map(|acc| acc.to_vec()))
+        .bind(cp.count as i64)
+        .execute(tx.as_mut())
+        .await?;
+        repaired.push(agent_id.clone());
+    }
+    for agent_id in cached.into_keys() {
+        sqlx::query("DELETE FROM checkpoints_cache WHERE agent_id = ?1")
+            .bind(&agent_id)
+            .execute(tx.as_mut())
+            .await?;
+        repaired.push(agent_id);
+    }
+    tx.commit().await?;
+    repaired.sort();
+    Ok(repaired)
+}
+
+fn row_to_checkpoint(row: &sqlx::sqlite::SqliteRow) -> Result<AgentCheckpoint, sqlx::Error> {
+    let last_hash: Vec<u8> = row.try_get("last_hash")?;
+    let last_hash: [u8; 32] = last_hash
+        .try_into()
+        .map_err(|_| sqlx::Error::Decode("last_hash is not 32 bytes".into()))?;
+    Ok(AgentCheckpoint {
+        agent_id: row.try_get("agent_id")?,
+        last_epoch: row.try_get::<i64, _>("last_epoch")? as u64,
+        last_seq: row.try_get::<i64, _>("last_seq")? as u64,
+        last_hash,
+        count: row.try_get::<i64, _>("count")? as u64,
+        last_accumulator: row
+            .try_get::<Option<Vec<u8>>, _>("last_accumulator")?
+            .and_then(|v| v.try_into().ok()),
+    })
+}
@@ -0,0 +1,13 @@
+//! `GET /dashboard`: a read-only status page for the fleet. The page is
+//! static HTML embedded in the binary; its script fetches the JSON endpoints
+//! from the same origin, so it needs no CORS and holds no data itself. The
+//! route is open; a token typed into the page is kept in the tab's session
+//! storage and sent as a bearer token on those fetches.
+
+use axum::response::Html;
+
+const PAGE: &str = include_str!("../assets/dashboard.html");
+
+pub async fn handler_dashboard() -> Html<&'static str> {
+    Html(PAGE)
+}
@@ -0,0 +1,118 @@
+//! Reading stored blobs back: `logs_compressed`, tiered blobs and archived
+//! raw bodies, gzip or zstd (see [`crate::dicts`]).
+//!
+//! Every read is capped by `MAX_DECOMPRESSED_BYTES`. A blob only the server
+//! wrote stays far below it; one from a tampered import or a direct database
+//! write could otherwise take the process's memory with it, so a blob that
+//! would inflate past the cap is refused after reading one byte too many,
+//! not after reading all of it.
+
+use crate::{dicts, position_label};
+use flate2::read::GzDecoder;
+use sqlx::Row;
+use std::env;
+use std::io::Read;
+use std::sync::Arc;
+
+/// Default for `MAX_DECOMPRESSED_BYTES`: well past any batch the submit
+/// limits let in, far short of what a crafted blob could inflate to.
+pub const DEFAULT_MAX_BYTES: usize = 64 << 20;
+
+/// How stored blobs are inflated, kept on [`crate::AppState`].
+#[derive(Debug, Clone)]
+pub struct Decompressor {
+    /// The most one blob may inflate to.
+    max_bytes: usize,
+    /// The dictionaries zstd blobs name; empty until [`dicts::load`].
+    decoders: Arc<dicts::Decoders>,
+}
+
+impl Default for Decompressor {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_BYTES)
+    }
+}
+
+impl Decompressor {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            decoders: Arc::default(),
+        }
+    }
+
+    pub fn decoders(&self) -> &Arc<dicts::Decoders> {
+        &self.decoders
+    }
+
+    /// `MAX_DECOMPRESSED_BYTES`, or the default when unset or not a positive
+    /// number.
+    pub fn from_env() -> Self {
+        env::var("MAX_DECOMPRESSED_BYTES")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|&max| max > 0)
+            .map_or_else(Self::default, Self::new)
+    }
+
+    pub fn bytes(&self, blob: &[u8]) -> Result<Vec<u8>, String> {
+        self.inflate(blob, Vec::new())
+    }
+
+    pub fn json(&self, blob: &[u8]) -> Result<String, String> {
+        let mut out = String::new();
+        self.json_into(blob, &mut out)?;
+        Ok(out)
+    }
+
+    /// [`Self::json`] into `out`, replacing what it held; reusing one buffer
+    /// across rows saves growing a fresh one per row.
+    pub fn json_into(&self, blob: &[u8], out: &mut String) -> Result<(), String> {
+        let buf = self.inflate(blob, std::mem::take(out).into_bytes())?;
+        *out = String::from_utf8(buf).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// [`Self::json_into`] for the blob of a stored row. The read paths
+    /// answer a failure with a bare 500, so the error, naming the row, is
+    /// logged here.
+    pub fn row_logs(
+        &self,
+        row: &sqlx::sqlite::SqliteRow,
+        blob: &[u8],
+        out: &mut String,
+    ) -> Result<(), String> {
+        self.json_into(blob, out).map_err(|err| {
+            let position = (
+                row.try_get::<i64, _>("epoch").unwrap_or_default() as u64,
+                row.try_get::<i64, _>("seq").unwrap_or_default() as u64,
+            );
+            let err = format!(
+                "logs of batch {} ({} {}) unreadable: {err}",
+                row.try_get::<i64, _>("id").unwrap_or_default(),
+                row.try_get::<String, _>("agent_id").unwrap_or_default(),
+                position_label(position)
+            );
+            eprintln!("{err}");
+            err
+        })
+    }
+
+    fn inflate(&self, blob: &[u8], mut out: Vec<u8>) -> Result<Vec<u8>, String> {
+        let limit = self.max_bytes;
+        if dicts::is_zstd(blob) {
+            return self.decoders.decompress(blob, out, limit);
+        }
+        out.clear();
+        GzDecoder::new(blob)
+            .take(limit as u64 + 1)
+            .read_to_end(&mut out)
+            .map_err(|e| e.to_string())?;
+        if out.len() > limit {
+            return Err(format!(
+                "gzip blob inflates past MAX_DECOMPRESSED_BYTES ({limit} bytes)"
+            ));
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,288 @@
+//! Shared zstd dictionaries for the stored logs of small batches, when
+//! `COMPRESSION_DICT_INTERVAL_SECS` is set.
+//!
+//! A batch of a few short lines barely shrinks under gzip: every blob pays
+//! for its own header and learns nothing from the batches before it. Every
+//! `COMPRESSION_DICT_INTERVAL_SECS` a task takes the logs JSON of the last
+//! `COMPRESSION_DICT_SAMPLES` batches (default 2000) and trains a zstd
+//! dictionary of at most `COMPRESSION_DICT_BYTES` (default 16 KiB) on them.
+//! The dictionary goes into `compression_dicts`, and the logs of batches
+//! stored from then on are a zstd frame compressed with it.
+//!
+//! The frame header names the dictionary by its zstd id, so each blob says
+//! what it needs. Dictionaries are never updated or deleted (triggers refuse
+//! both), and every one is loaded at startup, with or without the task: a
+//! new dictionary only changes what later batches are written with, never
+//! whether an older blob can be read. Gzip blobs are told apart by their
+//! magic bytes and read as before.
+
+use crate::metrics::Metrics;
+use crate::now_unix_ms;
+use sqlx::{Row, SqlitePool};
+use std::collections::HashMap;
+use std::env;
+use std::io::Read;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+pub const DEFAULT_SAMPLE_BATCHES: usize = 2000;
+pub const DEFAULT_DICT_BYTES: usize = 16 << 10;
+/// Fewer samples than this make a dictionary no better than none, and zstd
+/// often refuses to train on them at all.
+pub const MIN_SAMPLES: usize = 100;
+
+/// The first bytes of every zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone)]
+pub struct DictConfig {
+    pub interval: Duration,
+    pub sample_batches: usize,
+    pub dict_bytes: usize,
+}
+
+impl DictConfig {
+    /// `None` without `COMPRESSION_DICT_INTERVAL_SECS`.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let positive = |name: &str| match env::var(name) {
+            Ok(value) => value
+                .parse::<u64>()
+                .ok()
+                .filter(|&n| n > 0)
+                .map(Some)
+                .ok_or_else(|| format!("{name} must be a positive number, got '{value}'")),
+            Err(_) => Ok(None),
+        };
+        let Some(interval_secs) = positive("COMPRESSION_DICT_INTERVAL_SECS")? else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            interval: Duration::from_secs(interval_secs),
+            sample_batches: positive("COMPRESSION_DICT_SAMPLES")?
+                .map_or(DEFAULT_SAMPLE_BATCHES, |n| n as usize),
+            dict_bytes: positive("COMPRESSION_DICT_BYTES")?
+                .map_or(DEFAULT_DICT_BYTES, |n| n as usize),
+        }))
+    }
+}
+
+/// A dictionary prepared for compressing at one level.
+pub struct Dict {
+    /// The zstd dictionary id, which every frame compressed with it carries.
+    pub dict_id: u32,
+    /// Its row in `compression_dicts`; later dictionaries have higher ones.
+    pub version: i64,
+    encoder: EncoderDictionary<'static>,
+}
+
+impl Dict {
+    fn new(version: i64, raw: &[u8], level: i32) -> Result<Self, String> {
+        Ok(Self {
+            dict_id: dict_id(raw)?,
+            version,
+            encoder: EncoderDictionary::copy(raw, level),
+        })
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        zstd::bulk::Compressor::with_prepared_dictionary(&self.encoder)
+            .and_then(|mut compressor| compressor.compress(data))
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl std::fmt::Debug for Dict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dict")
+            .field("dict_id", &self.dict_id)
+            .field("version", &self.version)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The dictionary new batches are compressed with; `None` until the first
+/// one is trained, or always without the task.
+#[derive(Debug, Default)]
+pub struct CurrentDict(RwLock<Option<Arc<Dict>>>);
+
+impl CurrentDict {
+    pub fn get(&self) -> Option<Arc<Dict>> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, dict: Arc<Dict>) {
+        *self.0.write().unwrap() = Some(dict);
+    }
+}
+
+/// Every dictionary loaded or trained, by zstd id, for reading blobs back.
+/// One set per database, kept on the state (see [`crate::decompress`]);
+/// [`load`] and [`train`] add to it.
+#[derive(Default)]
+pub struct Decoders(RwLock<HashMap<u32, Arc<DecoderDictionary<'static>>>>);
+
+impl Decoders {
+    fn register(&self, dict_id: u32, raw: &[u8]) {
+        self.0
+            .write()
+            .unwrap()
+            .entry(dict_id)
+            .or_insert_with(|| Arc::new(DecoderDictionary::copy(raw)));
+    }
+
+    /// Decompresses a zstd blob into `out`, refusing past `limit` bytes like
+    /// the gzip path does.
+    pub fn decompress(
+        &self,
+        blob: &[u8],
+        mut out: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<u8>, String> {
+        let id = zstd::zstd_safe::get_dict_id_from_frame(blob)
+            .ok_or("zstd blob names no dictionary")?
+            .get();
+        let dict = self.0.read().unwrap().get(&id).cloned().ok_or_else(|| {
+            format!("zstd blob needs dictionary {id}, which is not in compression_dicts")
+        })?;
+        out.clear();
+        zstd::stream::read::Decoder::with_prepared_dictionary(blob, &dict)
+            .map_err(|err| err.to_string())?
+            .take(limit as u64 + 1)
+            .read_to_end(&mut out)
+            .map_err(|err| err.to_string())?;
+        if out.len() > limit {
+            return Err(format!(
+                "zstd blob inflates past MAX_DECOMPRESSED_BYTES ({limit} bytes)"
+            ));
+        }
+        Ok(out)
+    }
+}
+
+impl std::fmt::Debug for Decoders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut ids: Vec<u32> = self.0.read().unwrap().keys().copied().collect();
+        ids.sort_unstable();
+        f.debug_tuple("Decoders").field(&ids).finish()
+    }
+}
+
+fn dict_id(raw: &[u8]) -> Result<u32, String> {
+    zstd::zstd_safe::get_dict_id_from_dict(raw)
+        .map(|id| id.get())
+        .ok_or_else(|| "not a zstd dictionary".to_string())
+}
+
+/// Whether a stored blob is a zstd frame rather than gzip.
+pub fn is_zstd(blob: &[u8]) -> bool {
+    blob.starts_with(&ZSTD_MAGIC)
+}
+
+/// Makes every stored dictionary readable through `decoders` and returns
+/// the newest, prepared at `level`.
+pub async fn load(
+    pool: &SqlitePool,
+    decoders: &Decoders,
+    level: i32,
+) -> Result<Option<Arc<Dict>>, String> {
+    let rows = sqlx::query("SELECT id, dict FROM compression_dicts ORDER BY id")
+        .fetch_all(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut newest = None;
+    for row in &rows {
+        let raw: Vec<u8> = row.get("dict");
+        let id = dict_id(&raw)?;
+        decoders.register(id, &raw);
+        newest = Some((row.get::<i64, _>("id"), raw));
+    }
+    newest
+        .map(|(version, raw)| Dict::new(version, &raw, level).map(Arc::new))
+        .transpose()
+}
+
+/// Trains a dictionary on the most recent batches and stores it. `None` when
+/// there are too few batches yet, or when it comes out the same as one
+/// already stored.
+pub async fn train(
+    pool: &SqlitePool,
+    decoders: &Decoders,
+    config: &DictConfig,
+    level: i32,
+) -> Result<Option<Arc<Dict>>, String> {
+    let samples: Vec<String> = sqlx::query_scalar(
+        "SELECT logs FROM batches WHERE logs IS NOT NULL AND logs != '' ORDER BY id DESC LIMIT ?1",
+    )
+    .bind(config.sample_batches as i64)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| err.to_string())?;
+    if samples.len() < MIN_SAMPLES {
+        return Ok(None);
+    }
+    let sample_count = samples.len();
+    let sample_bytes: usize = samples.iter().map(String::len).sum();
+    let dict_bytes = config.dict_bytes;
+    let raw = tokio::task::spawn_blocking(move || zstd::dict::from_samples(&samples, dict_bytes))
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| format!("training failed: {err}"))?;
+    let id = dict_id(&raw)?;
+    // Checked first, as a refused insert would still use up a version.
+    let stored: Option<i64> =
+        sqlx::query_scalar("SELECT id FROM compression_dicts WHERE dict_id = ?1")
+            .bind(i64::from(id))
+            .fetch_optional(pool)
+            .await
+            .map_err(|err| err.to_string())?;
+    if stored.is_some() {
+        return Ok(None);
+    }
+
+    let version: i64 = sqlx::query_scalar(
+        "INSERT INTO compression_dicts (dict_id, dict, sample_batches, sample_bytes, created_at_ms) \
+         VALUES (?1, ?2, ?3, ?4, ?5) RETURNING id",
+    )
+    .bind(i64::from(id))
+    .bind(&raw)
+    .bind(sample_count as i64)
+    .bind(sample_bytes as i64)
+    .bind(now_unix_ms())
+    .fetch_one(pool)
+    .await
+    .map_err(|err| err.to_string())?;
+    decoders.register(id, &raw);
+    Dict::new(version, &raw, level).map(|dict| Some(Arc::new(dict)))
+}
+
+/// The periodic training task; each new dictionary takes over for the
+/// batches stored after it.
+pub async fn run(
+    pool: SqlitePool,
+    decoders: Arc<Decoders>,
+    current: Arc<CurrentDict>,
+    metrics: Arc<Metrics>,
+    config: DictConfig,
+    level: i32,
+) {
+    let mut ticker = tokio::time::interval(config.interval);
+    loop {
+        ticker.tick().await;
+        match train(&pool, &decoders, &config, level).await {
+            Ok(Some(dict)) => {
+                metrics.inc("logchain_compression_dicts_trained_total");
+                println!(
+                    "[dict] compressing new batches with dictionary v{} (zstd id {})",
+                    dict.version, dict.dict_id
+                );
+                current.set(dict);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                metrics.inc("logchain_compression_dict_failures_total");
+                eprintln!("[dict] {err}");
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Per-agent clock drift: how far each agent's batch timestamps sit from the
+//! server's arrival times. Measurement only; no batch is ever rejected here.
+
+use crate::{AppState, RECEIVED_AT_MS_EXPR, TIMESTAMP_MS_EXPR, labeled};
+use axum::{Json, extract::State, http::StatusCode};
+use serde::Serialize;
+use sqlx::{Row, SqlitePool};
+use std::collections::BTreeMap;
+
+/// Recent batches per agent that feed the drift estimate.
+pub const DRIFT_SAMPLE_BATCHES: i64 = 20;
+
+#[derive(Debug, Serialize)]
+pub struct AgentStatus {
+    agent_id: String,
+    last_seq: u64,
+    last_received_at_ms: u64,
+    /// Median of `received_at_ms - timestamp_ms` over the recent batches.
+    /// Positive when the agent's clock is behind the server's (transit and
+    /// retry delays add to it too); negative when it runs ahead.
+    clock_drift_ms: i64,
+    drift_samples: usize,
+    /// `|clock_drift_ms|` is above `CLOCK_DRIFT_ALERT_MS`.
+    drift_exceeded: bool,
+}
+
+/// `GET /agents/status`
+pub async fn handler_agent_status(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<AgentStatus>>, StatusCode> {
+    agent_statuses(&state.pool, state.clock_drift_alert_ms)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn agent_statuses(
+    pool: &SqlitePool,
+    alert_ms: u64,
+) -> Result<Vec<AgentStatus>, sqlx::Error> {
+    let rows = sqlx::query(&format!(
+        r#"
+        SELECT agent_id, epoch, seq, timestamp_ms, received_at_ms FROM (
+            SELECT agent_id, epoch, seq,
+                {TIMESTAMP_MS_EXPR} AS timestamp_ms,
+                {RECEIVED_AT_MS_EXPR} AS received_at_ms,
+                ROW_NUMBER() OVER (PARTITION BY agent_id ORDER BY epoch DESC, seq DESC) AS rn
+            FROM batches
+        )
+        WHERE rn <= ?1
+        ORDER BY agent_id, epoch DESC, seq DESC
+        "#
+    ))
+    .bind(DRIFT_SAMPLE_BATCHES)
+    .fetch_all(pool)
+    .await?;
+
+    // (last_seq, last_received_at_ms, drift samples), newest row first.
+    let mut per_agent: BTreeMap<String, (u64, u64, Vec<i64>)> = BTreeMap::new();
+    for row in rows {
+        let seq = row.get::<i64, _>("seq") as u64;
+        let timestamp_ms: i64 = row.get("timestamp_ms");
+        let received_at_ms: i64 = row.get("received_at_ms");
+        let entry = per_agent
+            .entry(row.get("agent_id"))
+            .or_insert_with(|| (seq, received_at_ms as u64, Vec::new()));
+        entry.2.push(received_at_ms.saturating_sub(timestamp_ms));
+    }
+
+    Ok(per_agent
+        .into_iter()
+        .map(|(agent_id, (last_seq, last_received_at_ms, mut samples))| {
+            let clock_drift_ms = median(&mut samples).unwrap_or(0);
+            AgentStatus {
+                agent_id,
+                last_seq,
+                last_received_at_ms,
+                clock_drift_ms,
+                drift_samples: samples.len(),
+                drift_exceeded: clock_drift_ms.unsigned_abs() > alert_ms,
+            }
+        })
+        .collect())
+}
+
+/// Median, averaging the two middle values for an even count.
+fn median(samples: &mut [i64]) -> Option<i64> {
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort_unstable();
+    let mid = samples.len() / 2;
+    Some(if samples.len() % 2 == 1 {
+        samples[mid]
+    } else {
+        // Halve first so extreme values cannot overflow.
+        samples[mid - 1] / 2 + samples[mid] / 2 + (samples[mid - 1] % 2 + samples[mid] % 2) / 2
+    })
+}
+
+/// `/metrics` lines: the number of agents over the threshold, and the drift
+/// of each of them. Only exceeding agents get a series, to bound cardinality.
+pub async fn render_metrics(state: &AppState) -> String {
+    let statuses = agent_statuses(&state.pool, state.clock_drift_alert_ms)
+        .await
+        .unwrap_or_default();
+    let exceeded: Vec<&AgentStatus> = statuses.iter().filter(|s| s.drift_exceeded).collect();
+
+    let mut out = format!("logchain_agents_clock_drift_exceeded {}\n", exceeded.len());
+    for status in exceeded {
+        out.push_str(&format!(
+            "{} {}\n",
+            labeled(
+                "logchain_agent_clock_drift_ms",
+                "agent_id",
+                &status.agent_id
+            ),
+            status.clock_drift_ms
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn median_of_drift_samples() {
+        assert_eq!(median(&mut []), None);
+        assert_eq!(median(&mut [5]), Some(5));
+        // One batch delayed by a retry does not move the estimate.
+        assert_eq!(median(&mut [1_000, 1_020, 95_000, 990, 1_010]), Some(1_010));
+        assert_eq!(median(&mut [-4, 10]), Some(3));
+        assert_eq!(median(&mut [-3_000, -2_000, -1_000, -5_000]), Some(-2_500));
+        assert_eq!(median(&mut [i64::MAX, i64::MAX]), Some(i64::MAX));
+    }
+}
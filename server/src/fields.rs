@@ -0,0 +1,165 @@
+//! `/batches` filters on structured lines: `level=<value>` and
+//! `field.<key>=<value>`. A line is structured when it is a JSON object;
+//! plain-text lines never match, so the filters only narrow stores whose
+//! agents log JSON. Every filter must hold on one and the same line, and a
+//! batch is listed when any of its lines does.
+//!
+//! Values are compared as text: strings as they are, numbers as SQLite
+//! prints them, booleans as `true`/`false`. `level` ignores ASCII case,
+//! since agents disagree on `ERROR` and `error`. Keys name top-level
+//! members, dots included, and may not contain `"` or `\`.
+//!
+//! The filters run in SQL over the stored `logs` JSON (SQLite's JSON1), so
+//! `limit` and `offset` page over matching batches; [`Filters::matches`] is
+//! the same test in Rust for the `matches` addresses.
+
+use serde_json::Value;
+use sqlx::{QueryBuilder, Sqlite};
+
+/// The query parameter prefix of a custom-field filter.
+const FIELD_PREFIX: &str = "field.";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Filter {
+    key: String,
+    value: String,
+    ignore_case: bool,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Filters(Vec<Filter>);
+
+impl Filters {
+    /// `level` and every `field.<key>` pair of the query string.
+    pub fn parse(level: Option<&str>, query: &[(String, String)]) -> Result<Self, String> {
+        let mut filters = Vec::new();
+        if let Some(level) = level {
+            filters.push(Filter {
+                key: "level".into(),
+                value: level.into(),
+                ignore_case: true,
+            });
+        }
+        for (name, value) in query {
+            let Some(key) = name.strip_prefix(FIELD_PREFIX) else {
+                continue;
+            };
+            if key.is_empty() || key.contains(['"', '\\']) {
+                return Err(format!(
+                    "bad field filter {name:?}: the key must be non-empty without '\"' or '\\'"
+                ));
+            }
+            filters.push(Filter {
+                key: key.into(),
+                value: value.clone(),
+                ignore_case: false,
+            });
+        }
+        Ok(Filters(filters))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Pushes a condition on the `logs` column: some line satisfies every
+    /// filter. Rows whose `logs` is not JSON never match.
+    pub fn push_sql<'a>(&'a self, builder: &mut QueryBuilder<'a, Sqlite>) {
+        builder.push(
+            "CASE WHEN json_valid(logs) THEN EXISTS (SELECT 1 FROM json_each(logs) AS line WHERE CASE \
+             WHEN NOT json_valid(line.value) THEN 0 \
+             WHEN json_type(line.value) <> 'object' THEN 0 ELSE ",
+        );
+        for (i, filter) in self.0.iter().enumerate() {
+            if i > 0 {
+                builder.push(" AND ");
+            }
+            let path = format!("$.\"{}\"", filter.key);
+            let (open, close) = if filter.ignore_case {
+                ("upper(", ")")
+            } else {
+                ("(", ")")
+            };
+            builder.push(format!("{open}CASE json_type(line.value, "));
+            builder.push_bind(path.clone());
+            builder.push(
+                ") WHEN 'true' THEN 'true' WHEN 'false' THEN 'false' \
+                 WHEN 'null' THEN NULL WHEN 'object' THEN NULL WHEN 'array' THEN NULL \
+                 ELSE CAST(json_extract(line.value, ",
+            );
+            builder.push_bind(path);
+            builder.push(format!(") AS TEXT) END{close} = {open}"));
+            builder.push_bind(&filter.value);
+            builder.push(close);
+        }
+        builder.push(" END) ELSE 0 END");
+    }
+
+    /// Whether `line` satisfies every filter, as [`Filters::push_sql`] decides;
+    /// any line does when there are none.
+    pub fn matches(&self, line: &str) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let Ok(Value::Object(object)) = serde_json::from_str::<Value>(line) else {
+            return false;
+        };
+        self.0.iter().all(|filter| {
+            let text = match object.get(&filter.key) {
+                Some(Value::String(s)) => s.clone(),
+                Some(Value::Bool(b)) => b.to_string(),
+                Some(Value::Number(n)) => n.to_string(),
+                _ => return false,
+            };
+            if filter.ignore_case {
+                text.eq_ignore_ascii_case(&filter.value)
+            } else {
+                text == filter.value
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn lines_match_on_level_and_custom_fields_as_text() {
+        let filters = Filters::parse(
+            Some("ERROR"),
+            &query(&[("field.status", "500"), ("agent_id", "a")]),
+        )
+        .unwrap();
+        assert!(filters.matches(r#"{"level":"error","status":500}"#));
+        assert!(filters.matches(r#"{"status":"500","level":"Error","msg":"x"}"#));
+        assert!(!filters.matches(r#"{"level":"warn","status":500}"#));
+        assert!(!filters.matches(r#"{"level":"error"}"#));
+        assert!(!filters.matches(r#"{"level":"error","status":[500]}"#));
+        assert!(!filters.matches("level=error status=500"));
+        assert!(!filters.matches(r#"["error", 500]"#));
+
+        let dotted = Filters::parse(None, &query(&[("field.http.ok", "true")])).unwrap();
+        assert!(dotted.matches(r#"{"http.ok":true}"#));
+        assert!(!dotted.matches(r#"{"http":{"ok":true}}"#));
+        // Field values keep their case.
+        let service = Filters::parse(None, &query(&[("field.service", "API")])).unwrap();
+        assert!(!service.matches(r#"{"service":"api"}"#));
+
+        let none = Filters::parse(None, &query(&[("log_substring", "x")])).unwrap();
+        assert!(none.is_empty() && none.matches("plain text"));
+        for bad in ["field.", "field.a\"b", "field.a\\b"] {
+            assert!(
+                Filters::parse(None, &query(&[(bad, "x")])).is_err(),
+                "{bad}"
+            );
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! Chain forks left by restoring the server from an older snapshot. Agents
+//! that kept running hold receipts for batches the restored store lost;
+//! once they resync, those positions are missing or filled with different
+//! batches. `POST /admin/forks` checks uploaded receipts against the server
+//! key history and the stored chain, and records each one that no longer
+//! matches in the append-only `forks` table, so the loss is acknowledged in
+//! the audit trail instead of silently synced over.
+
+use crate::now_unix_ms;
+use crate::receipts::{ServerKey, server_keys};
+use common::receipt::{Receipt, ReceiptStanding};
+use common::serde_util::is_zero;
+use ed25519_dalek::Signature;
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqlitePool};
+
+#[derive(Debug, Deserialize)]
+pub struct ForkReport {
+    pub receipts: Vec<Receipt>,
+    /// Stored with every fork this report records, e.g. which restore lost
+    /// the batches.
+    pub note: Option<String>,
+}
+
+/// The verdict on one uploaded receipt.
+#[derive(Debug, Serialize)]
+pub struct ReceiptCheck {
+    pub agent_id: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub epoch: u64,
+    pub seq: u64,
+    /// `intact`, `hash_differs`, `missing` or `unverifiable`.
+    pub status: &'static str,
+    /// Why an unverifiable receipt was not accepted.
+    pub detail: Option<String>,
+    /// Row of `forks` holding this fork, when it is one; recorded by this
+    /// report or an earlier one.
+    pub fork_id: Option<i64>,
+}
+
+/// A recorded fork: the receipt exactly as uploaded, and what the server
+/// stored at its position when it was recorded.
+#[derive(Debug, Serialize)]
+pub struct Fork {
+    pub id: i64,
+    /// `hash_differs` or `missing`.
+    pub kind: String,
+    pub stored_hash: Option<[u8; 32]>,
+    pub receipt: Receipt,
+    pub note: Option<String>,
+    pub recorded_at_ms: i64,
+}
+
+/// Why `receipt` cannot be trusted, if it cannot: the server must have
+/// signed it with the key that was active when it was issued.
+fn unverifiable(receipt: &Receipt, keys: &[ServerKey]) -> Option<String> {
+    let Some(key) = keys.iter().find(|key| key.id == receipt.key_id) else {
+        return Some(format!("unknown server key {}", receipt.key_id));
+    };
+    if !key.active_at(receipt.issued_at_ms as i64) {
+        return Some(format!(
+            "server key {} was not active at {}",
+            key.id, receipt.issued_at_ms
+        ));
+    }
+    match key.verifying_key() {
+        Some(verifying) if receipt.verify(&verifying) => None,
+        _ => Some(format!(
+            "signature does not verify with server key {}",
+            key.id
+        )),
+    }
+}
+
+/// Checks every receipt of `report` and records the forks among them.
+/// Re-uploading a recorded receipt is harmless: its fork is kept as first
+/// recorded and its id returned again.
+pub async fn record(
+    pool: &SqlitePool,
+    report: &ForkReport,
+) -> Result<Vec<ReceiptCheck>, sqlx::Error> {
+    let keys = server_keys(pool).await?;
+    let mut checks = Vec::with_capacity(report.receipts.len());
+    let mut tx = pool.begin().await?;
+    for receipt in &report.receipts {
+        let mut check = ReceiptCheck {
+            agent_id: receipt.agent_id.clone(),
+            epoch: receipt.epoch,
+            seq: receipt.seq,
+            status: "unverifiable",
+            detail: unverifiable(receipt, &keys),
+            fork_id: None,
+        };
+        if check.detail.is_some() {
+            checks.push(check);
+            continue;
+        }
+
+        let stored: Option<Vec<u8>> = sqlx::query_scalar(
+            "SELECT hash FROM batches WHERE agent_id = ?1 AND epoch = ?2 AND seq = ?3",
+        )
+        .bind(&receipt.agent_id)
+        .bind(receipt.epoch as i64)
+        .bind(receipt.seq as i64)
+        .fetch_optional(tx.as_mut())
+        .await?;
+        let stored = stored.and_then(|hash| <[u8; 32]>::try_from(hash).ok());
+        let standing = ReceiptStanding::of(receipt, stored.as_ref());
+        check.status = standing.as_str();
+        if standing == ReceiptStanding::Intact {
+            checks.push(check);
+            continue;
+        }
+
+        // The `forks_recorded_once` trigger skips a receipt already recorded.
+        sqlx::query(
+            "INSERT INTO forks (agent_id, seq, kind, receipt_hash, stored_hash, receipt_batch_id, key_id, signature, issued_at_ms, note, recorded_at_ms, epoch) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        )
+        .bind(&receipt.agent_id)
+        .bind(receipt.seq as i64)
+        .bind(standing.as_str())
+        .bind(receipt.hash.to_vec())
+        .bind(stored.map(|hash| hash.to_vec()))
+        .bind(receipt.batch_id)
+        .bind(receipt.key_id)
+        .bind(receipt.signature.to_bytes().to_vec())
+        .bind(receipt.issued_at_ms as i64)
+        .bind(&report.note)
+        .bind(now_unix_ms())
+        .bind(receipt.epoch as i64)
+        .execute(tx.as_mut())
+        .await?;
+        check.fork_id = sqlx::query_scalar(
+            "SELECT id FROM forks WHERE agent_id = ?1 AND seq = ?2 AND receipt_hash = ?3",
+        )
+        .bind(&receipt.agent_id)
+        .bind(receipt.seq as i64)
+        .bind(receipt.hash.to_vec())
+        .fetch_optional(tx.as_mut())
+        .await?;
+        checks.push(check);
+    }
+    tx.commit().await?;
+    Ok(checks)
+}
+
+/// Recorded forks, oldest first; only `agent_id`'s when given.
+pub async fn list(pool: &SqlitePool, agent_id: Option<&str>) -> Result<Vec<Fork>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT id, agent_id, epoch, seq, kind, receipt_hash, stored_hash, receipt_batch_id, key_id, signature, issued_at_ms, note, recorded_at_ms \
+         FROM forks WHERE ?1 IS NULL OR agent_id = ?1 ORDER BY id",
+    )
+    .bind(agent_id)
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter()
+        .map(|row| {
+            let id: i64 = row.get("id");
+            let undecodable = || sqlx::Error::Decode(format!("fork {id}").into());
+            let hash: Vec<u8> = row.get("receipt_hash");
+            let stored: Option<Vec<u8>> = row.get("stored_hash");
+            let signature: Vec<u8> = row.get("signature");
+            Ok(Fork {
+                id,
+                kind: row.get("kind"),
+                stored_hash: stored
+                    .map(|hash| hash.try_into().map_err(|_| undecodable()))
+                    .transpose()?,
+                receipt: Receipt {
+                    batch_id: row.get("receipt_batch_id"),
+                    agent_id: row.get("agent_id"),
+                    seq: row.get::<i64, _>("seq") as u64,
+                    epoch: row.get::<i64, _>("epoch") as u64,
+                    hash: hash.try_into().map_err(|_| undecodable())?,
+                    issued_at_ms: row.get::<i64, _>("issued_at_ms") as u64,
+                    key_id: row.get("key_id"),
+                    signature: Signature::from_slice(&signature).map_err(|_| undecodable())?,
+                },
+                note: row.get("note"),
+                recorded_at_ms: row.get("recorded_at_ms"),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,508 @@
+//! Whole-store consistency check. Where the integrity check asks whether the
+//! chains link up, this asks whether every stored row is what the server
+//! would have written: it walks `batches` in id order, a chunk at a time, and
+//! compares each row's columns with what they recompute to, reporting every
+//! discrepancy by row id. Each row must also have a receipt that the server
+//! key active at its issue time signed.
+//!
+//! Runs once as `server --fsck`, or as a background job behind
+//! `POST /admin/fsck` that `GET /admin/fsck/:id` polls and
+//! `POST /admin/fsck/:id/abort` stops between chunks.
+
+use crate::decompress::Decompressor;
+use crate::receipts::{self, ServerKey};
+use crate::tiering;
+use crate::{now_unix_ms, parse_stored_logs, position_label, row_to_query_batch};
+use common::receipt::Receipt;
+use ed25519_dalek::{Signature, VerifyingKey};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::Serialize;
+use sqlx::{Row, SqlitePool, sqlite::SqliteRow};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Rows read per query; each chunk is one short read.
+pub const DEFAULT_CHUNK_ROWS: u64 = 500;
+/// Pause between chunks so a scan of a large store leaves room for submits.
+const CHUNK_PAUSE: Duration = Duration::from_millis(10);
+/// Jobs kept for `GET /admin/fsck/:id`, oldest dropped first.
+const KEPT_JOBS: usize = 16;
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Discrepancy {
+    /// Row id; `None` for findings about the table as a whole.
+    pub id: Option<i64>,
+    pub agent_id: Option<String>,
+    pub seq: Option<u64>,
+    pub kind: &'static str,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FsckReport {
+    pub rows_checked: u64,
+    /// Highest row id checked so far.
+    pub last_id: i64,
+    /// Set once every row has been checked; an aborted scan leaves it false.
+    pub complete: bool,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl FsckReport {
+    pub fn ok(&self) -> bool {
+        self.complete && self.discrepancies.is_empty()
+    }
+}
+
+/// What the walk carries from one row to the next.
+#[derive(Default)]
+struct Cursor {
+    last_id: i64,
+    /// Highest (epoch, seq) seen per agent; ids follow insertion order, so
+    /// each row's position must be above it.
+    last_position: HashMap<String, (u64, u64)>,
+    /// The whole server key history, to check receipts against.
+    server_keys: Vec<ServerKey>,
+    decompressor: Decompressor,
+}
+
+impl Cursor {
+    fn check(&mut self, row: SqliteRow, found: &mut Vec<Discrepancy>) {
+        let id: i64 = row.get("id");
+        let agent_id: String = row.get("agent_id");
+        let seq = row.get::<i64, _>("seq") as u64;
+        let epoch = row.get::<i64, _>("epoch") as u64;
+        let mut report = |kind, detail: String| {
+            found.push(Discrepancy {
+                id: Some(id),
+                agent_id: Some(agent_id.clone()),
+                seq: Some(seq),
+                kind,
+                detail,
+            })
+        };
+
+        // AUTOINCREMENT never reuses ids and rolled-back inserts give theirs
+        // back, so a hole means rows were deleted despite the triggers.
+        if id > self.last_id + 1 {
+            report(
+                "id_gap",
+                format!(
+                    "ids {}..={} missing before this row",
+                    self.last_id + 1,
+                    id - 1
+                ),
+            );
+        }
+        self.last_id = id;
+
+        match self.last_position.get(&agent_id) {
+            Some(&previous) if (epoch, seq) <= previous => report(
+                "seq_order",
+                format!(
+                    "{} stored after {} of the same agent",
+                    position_label((epoch, seq)),
+                    position_label(previous)
+                ),
+            ),
+            _ => {}
+        }
+        let last = self
+            .last_position
+            .entry(agent_id.clone())
+            .or_insert((epoch, seq));
+        *last = (*last).max((epoch, seq));
+
+        // Reads take the lines from the compressed copy when there is one,
+        // in the row or tiered, so a broken copy there is what keeps the row
+        // from decoding below.
+        let plain: String = row.get("logs");
+        let mut lines_ok = match parse_stored_logs(&plain) {
+            Ok((_, true)) => true,
+            Ok((_, false)) => {
+                report(
+                    "logs_not_canonical",
+                    "logs not in canonical encoding".into(),
+                );
+                true
+            }
+            Err(err) => {
+                report(
+                    "logs_unreadable",
+                    format!("logs is not a list of lines: {err}"),
+                );
+                false
+            }
+        };
+        // A tiered copy must resolve to the blob its stub recorded.
+        let tiered = match row.get::<Option<String>, _>("blob_location") {
+            Some(location) => {
+                match tiering::fetch(&location, &row.get::<Vec<u8>, _>("blob_sha256")) {
+                    Ok(blob) => Some(blob),
+                    Err(err) => {
+                        report(err.kind(), format!("{location}: {err}"));
+                        lines_ok = false;
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        if let Some(blob) = row.get::<Option<Vec<u8>>, _>("logs_compressed").or(tiered) {
+            lines_ok = false;
+            match self.decompressor.json(&blob) {
+                Ok(json) if json == plain => lines_ok = parse_stored_logs(&json).is_ok(),
+                Ok(json) => report(
+                    "compressed_mismatch",
+                    format!(
+                        "logs_compressed holds {} bytes that differ from the {} bytes of logs",
+                        json.len(),
+                        plain.len()
+                    ),
+                ),
+                Err(err) => report("compressed_unreadable", format!("decompress: {err}")),
+            }
+        }
+
+        let key: Vec<u8> = row.get("public_key");
+        let key_ok = match <[u8; 32]>::try_from(key.as_slice()) {
+            Ok(bytes) => match VerifyingKey::from_bytes(&bytes) {
+                Ok(_) => true,
+                Err(err) => {
+                    report("bad_public_key", format!("not an ed25519 key: {err}"));
+                    false
+                }
+            },
+            Err(_) => {
+                report(
+                    "bad_public_key",
+                    format!("{} bytes, expected 32", key.len()),
+                );
+                false
+            }
+        };
+
+        let receipt = self.receipt_issue(&row, id, &agent_id, (epoch, seq));
+
+        match row_to_query_batch(&self.decompressor, row) {
+            Ok(stored) => {
+                let computed = stored.batch.compute_hash_redacted(&stored.redacted);
+                if computed != Ok(stored.hash) {
+                    report(
+                        "hash_mismatch",
+                        "stored hash differs from the hash of the stored contents".into(),
+                    );
+                }
+            }
+            // Already reported above as the reason the row does not decode.
+            Err(_) if !key_ok || !lines_ok => {}
+            Err(_) => report(
+                "undecodable_row",
+                "row does not decode into a batch (hash, prev_hash or signature length)".into(),
+            ),
+        }
+
+        if let Some((kind, detail)) = receipt {
+            report(kind, detail);
+        }
+    }
+
+    /// What is wrong with the row's receipt, if anything: it must exist,
+    /// acknowledge the stored hash and verify with the server key that was
+    /// active when it was issued.
+    fn receipt_issue(
+        &self,
+        row: &SqliteRow,
+        id: i64,
+        agent_id: &str,
+        (epoch, seq): (u64, u64),
+    ) -> Option<(&'static str, String)> {
+        let Some(key_id) = row.get::<Option<i64>, _>("receipt_key_id") else {
+            return Some((
+                "missing_receipt",
+                "no receipt was issued for this row".into(),
+            ));
+        };
+        let hash: Vec<u8> = row.get("receipt_hash");
+        if hash != row.get::<Vec<u8>, _>("hash") {
+            return Some((
+                "receipt_mismatch",
+                "receipt acknowledges a different hash than the row holds".into(),
+            ));
+        }
+        let issued_at_ms: i64 = row.get("receipt_issued_at_ms");
+        let invalid = |detail: String| Some(("receipt_invalid", detail));
+        let Some(key) = self.server_keys.iter().find(|key| key.id == key_id) else {
+            return invalid(format!("signed by unknown server key {key_id}"));
+        };
+        if !key.active_at(issued_at_ms) {
+            return invalid(format!(
+                "server key {key_id} was not active at issued_at_ms {issued_at_ms}"
+            ));
+        }
+        let signature: Vec<u8> = row.get("receipt_signature");
+        let (Ok(hash), Ok(signature), Some(public_key)) = (
+            <[u8; 32]>::try_from(hash),
+            Signature::from_slice(&signature),
+            key.verifying_key(),
+        ) else {
+            return invalid("receipt hash, signature or server key is malformed".into());
+        };
+        let receipt = Receipt {
+            batch_id: id,
+            agent_id: agent_id.to_string(),
+            epoch,
+            seq,
+            hash,
+            issued_at_ms: issued_at_ms as u64,
+            key_id,
+            signature,
+        };
+        if !receipt.verify(&public_key) {
+            return invalid(format!(
+                "signature does not verify with server key {key_id}"
+            ));
+        }
+        None
+    }
+}
+
+/// Findings about the table rather than a row: the seq index the chain relies
+/// on, and rows deleted from the end, which leave no hole for [`Cursor`].
+async fn table_issues(pool: &SqlitePool, last_id: i64) -> Result<Vec<Discrepancy>, sqlx::Error> {
+    let mut found = Vec::new();
+    let table = |kind, detail: String| Discrepancy {
+        id: None,
+        agent_id: None,
+        seq: None,
+        kind,
+        detail,
+    };
+
+    let unique: Option<i64> = sqlx::query_scalar(
+        "SELECT il.\"unique\" FROM pragma_index_list('batches') il WHERE il.name = 'idx_agent_epoch_seq'",
+    )
+    .fetch_optional(pool)
+    .await?;
+    if unique != Some(1) {
+        found.push(table(
+            "missing_index",
+            "unique index idx_agent_epoch_seq on (agent_id, epoch, seq) is missing".into(),
+        ));
+    }
+
+    let issued: Option<i64> =
+        sqlx::query_scalar("SELECT seq FROM sqlite_sequence WHERE name = 'batches'")
+            .fetch_optional(pool)
+            .await?;
+    if let Some(issued) = issued.filter(|&issued| issued > last_id) {
+        found.push(table(
+            "id_gap",
+            format!(
+                "ids {}..={issued} were issued but no row holds them",
+                last_id + 1
+            ),
+        ));
+    }
+    Ok(found)
+}
+
+/// Walks every row in id order, `chunk_rows` at a time, adding findings to
+/// `report` as each chunk finishes so a poller sees them early. Stops between
+/// chunks once `abort` is set, leaving `complete` false.
+pub async fn scan(
+    pool: &SqlitePool,
+    decompressor: &Decompressor,
+    chunk_rows: u64,
+    abort: &AtomicBool,
+    report: &Mutex<FsckReport>,
+) -> Result<(), sqlx::Error> {
+    let mut cursor = Cursor {
+        server_keys: receipts::server_keys(pool).await?,
+        decompressor: decompressor.clone(),
+        ..Cursor::default()
+    };
+    loop {
+        if abort.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let rows = sqlx::query(
+            "SELECT b.*, r.hash AS receipt_hash, r.key_id AS receipt_key_id, \
+             r.signature AS receipt_signature, r.issued_at_ms AS receipt_issued_at_ms, \
+             l.location AS blob_location, l.blob_sha256 \
+             FROM batches b LEFT JOIN receipts r ON r.batch_id = b.id \
+             LEFT JOIN blob_locations l ON l.batch_id = b.id \
+             WHERE b.id > ?1 ORDER BY b.id LIMIT ?2",
+        )
+        .bind(cursor.last_id)
+        .bind(chunk_rows.max(1) as i64)
+        .fetch_all(pool)
+        .await?;
+        let count = rows.len() as u64;
+        let mut found = Vec::new();
+        for row in rows {
+            cursor.check(row, &mut found);
+        }
+        {
+            let mut report = report.lock().unwrap();
+            report.rows_checked += count;
+            report.last_id = cursor.last_id;
+            report.discrepancies.append(&mut found);
+        }
+        if count < chunk_rows.max(1) {
+            break;
+        }
+        tokio::time::sleep(CHUNK_PAUSE).await;
+    }
+
+    let mut found = table_issues(pool, cursor.last_id).await?;
+    let mut report = report.lock().unwrap();
+    report.discrepancies.append(&mut found);
+    report.complete = true;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Done,
+    Aborted,
+    Failed,
+}
+
+/// What `/admin/fsck` returns for a job.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub job_id: String,
+    pub state: JobState,
+    pub started_at_ms: i64,
+    pub finished_at_ms: Option<i64>,
+    /// Set when the scan stopped on a database error.
+    pub error: Option<String>,
+    pub report: FsckReport,
+}
+
+struct Job {
+    id: String,
+    started_at_ms: i64,
+    abort: AtomicBool,
+    report: Mutex<FsckReport>,
+    /// `Running` until the task records how it ended.
+    outcome: Mutex<(JobState, Option<i64>, Option<String>)>,
+}
+
+impl Job {
+    fn status(&self) -> JobStatus {
+        let (state, finished_at_ms, error) = self.outcome.lock().unwrap().clone();
+        JobStatus {
+            job_id: self.id.clone(),
+            state,
+            started_at_ms: self.started_at_ms,
+            finished_at_ms,
+            error,
+            report: self.report.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Background fsck jobs, one running at a time.
+pub struct FsckJobs {
+    chunk_rows: u64,
+    jobs: Mutex<Vec<Arc<Job>>>,
+}
+
+impl FsckJobs {
+    pub fn new(chunk_rows: u64) -> Self {
+        Self {
+            chunk_rows: chunk_rows.max(1),
+            jobs: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Starts a scan in the background and returns its status, or the
+    /// running job's id when one is already going.
+    pub fn start(
+        &self,
+        pool: SqlitePool,
+        decompressor: Decompressor,
+        chunk_rows: Option<u64>,
+    ) -> Result<JobStatus, String> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(running) = jobs
+            .iter()
+            .find(|job| job.outcome.lock().unwrap().0 == JobState::Running)
+        {
+            return Err(running.id.clone());
+        }
+        let job = Arc::new(Job {
+            id: format!("{:016x}", OsRng.next_u64()),
+            started_at_ms: now_unix_ms(),
+            abort: AtomicBool::new(false),
+            report: Mutex::new(FsckReport::default()),
+            outcome: Mutex::new((JobState::Running, None, None)),
+        });
+        if jobs.len() >= KEPT_JOBS {
+            jobs.remove(0);
+        }
+        jobs.push(job.clone());
+        drop(jobs);
+
+        let chunk_rows = chunk_rows.unwrap_or(self.chunk_rows).max(1);
+        let status = job.status();
+        tokio::spawn(async move {
+            let result = scan(&pool, &decompressor, chunk_rows, &job.abort, &job.report).await;
+            let complete = job.report.lock().unwrap().complete;
+            let outcome = match result {
+                Err(err) => (JobState::Failed, Some(err.to_string())),
+                Ok(()) if complete => (JobState::Done, None),
+                Ok(()) => (JobState::Aborted, None),
+            };
+            let report = job.report.lock().unwrap();
+            println!(
+                "[fsck] job {} {:?}: {} rows checked, {} discrepancies",
+                job.id,
+                outcome.0,
+                report.rows_checked,
+                report.discrepancies.len()
+            );
+            *job.outcome.lock().unwrap() = (outcome.0, Some(now_unix_ms()), outcome.1);
+        });
+        Ok(status)
+    }
+
+    /// The kept jobs, newest first.
+    pub fn list(&self) -> Vec<JobStatus> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .map(|job| job.status())
+            .collect()
+    }
+
+    pub fn status(&self, id: &str) -> Option<JobStatus> {
+        self.find(id).map(|job| job.status())
+    }
+
+    /// Asks a running job to stop after its current chunk; a finished job is
+    /// left as it is. Returns the status as of the request.
+    pub fn abort(&self, id: &str) -> Option<JobStatus> {
+        let job = self.find(id)?;
+        job.abort.store(true, Ordering::Relaxed);
+        Some(job.status())
+    }
+
+    fn find(&self, id: &str) -> Option<Arc<Job>> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|job| job.id == id)
+            .cloned()
+    }
+}
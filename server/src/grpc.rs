@@ -0,0 +1,229 @@
+//! `GRPC_ADDR`: the LogChain gRPC service (`common/proto/logchain.proto`) on
+//! its own port, next to the HTTP API. `Submit` goes through the same rate
+//! limits, token scopes, validation and storage as `POST /submit`.
+//! `Checkpoints` streams what `GET /batches/checkpoints` returns, under the
+//! read group's rate limit. Tokens are read from the `authorization`
+//! metadata, as `Bearer <token>`. In maintenance mode `Submit` answers
+//! `Unavailable` with the wait in `retry-after` metadata, and a rejection
+//! carries `/submit`'s `code` in `error-code` metadata.
+//!
+//! gRPC submits have no JSON body, so `STORE_RAW_BODY` archives nothing for
+//! them and `/batches/:id/raw` answers 404.
+
+// `tonic::Status` is large, but it is what the generated service returns.
+#![allow(clippy::result_large_err)]
+
+use crate::auth::{self, AuthContext, Scope};
+use crate::maintenance;
+use crate::rate_limit::{self, Caller, LimitGroup};
+use crate::{AppState, Provenance, SubmitResponse, admit_submitter, submit_error, submit_parsed};
+use axum::Json;
+use axum::http::StatusCode;
+use common::batch::LogBatch;
+use common::grpc::proto::{
+    self,
+    log_chain_server::{LogChain, LogChainServer},
+};
+use futures_util::Stream;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use tonic::{Code, Request, Response, Status};
+
+pub struct GrpcService {
+    state: AppState,
+}
+
+/// The HTTP status `/submit` would answer with, as a gRPC code.
+fn grpc_code(status: StatusCode) -> Code {
+    match status {
+        StatusCode::BAD_REQUEST => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::CONFLICT => Code::FailedPrecondition,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::INSUFFICIENT_STORAGE => Code::ResourceExhausted,
+        StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        _ => Code::Internal,
+    }
+}
+
+fn into_grpc(
+    (status, Json(body)): (StatusCode, Json<SubmitResponse>),
+) -> Result<Response<proto::SubmitResponse>, Status> {
+    if !status.is_success() {
+        let mut status = Status::new(grpc_code(status), body.message);
+        if let Some(code) = body.code.and_then(|code| code.parse().ok()) {
+            status
+                .metadata_mut()
+                .insert(common::grpc::ERROR_CODE_METADATA, code);
+        }
+        return Err(status);
+    }
+    Ok(Response::new(proto::SubmitResponse {
+        status: body.status,
+        message: body.message,
+        server_time_ms: body.server_time_ms,
+        ack: body.ack.map(str::to_string),
+    }))
+}
+
+/// The caller as the rate limiters see it; peerless in-process calls share
+/// one bucket.
+fn caller<'a, T>(request: &'a Request<T>, agent_id: Option<&'a str>) -> Caller<'a> {
+    Caller {
+        ip: request
+            .remote_addr()
+            .map_or(SocketAddr::from(([0, 0, 0, 0], 0)).ip(), |addr| addr.ip()),
+        token: request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer ")),
+        agent_id,
+    }
+}
+
+impl GrpcService {
+    pub fn new(state: AppState) -> Self {
+        Self { state }
+    }
+
+    async fn admit<T>(
+        &self,
+        group: LimitGroup,
+        request: &Request<T>,
+        agent_id: Option<&str>,
+    ) -> bool {
+        let caller = caller(request, agent_id);
+        self.state
+            .rate_limits
+            .admit(&self.state.metrics, group, &caller)
+            .await
+    }
+
+    fn denies<T>(&self, request: &Request<T>, agent_id: Option<&str>) -> bool {
+        let caller = caller(request, agent_id);
+        self.state.rate_limits.denies(&self.state.metrics, &caller)
+    }
+
+    async fn auth<T>(&self, request: &Request<T>) -> AuthContext {
+        auth::resolve(&self.state, &request.metadata().clone().into_headers()).await
+    }
+}
+
+#[tonic::async_trait]
+impl LogChain for GrpcService {
+    async fn submit(
+        &self,
+        request: Request<proto::LogBatch>,
+    ) -> Result<Response<proto::SubmitResponse>, Status> {
+        let state = &self.state;
+        // Only in-process calls lack a peer; they share one rate-limit bucket.
+        let addr = request
+            .remote_addr()
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+        let agent_id = request.get_ref().agent_id.clone();
+        if self.denies(&request, Some(&agent_id)) {
+            return into_grpc(submit_error(
+                state,
+                StatusCode::FORBIDDEN,
+                "denied",
+                rate_limit::DENIED_MESSAGE,
+            ));
+        }
+        if !self
+            .admit(LimitGroup::Submit, &request, Some(&agent_id))
+            .await
+        {
+            return into_grpc(submit_error(
+                state,
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                "rate limit exceeded",
+            ));
+        }
+        if state.maintenance.is_on() {
+            let (_, Json(body)) = submit_error(
+                state,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "maintenance",
+                maintenance::MESSAGE,
+            );
+            let mut status = Status::unavailable(body.message);
+            status.metadata_mut().insert(
+                common::grpc::RETRY_AFTER_METADATA,
+                state.maintenance.retry_after_secs().into(),
+            );
+            return Err(status);
+        }
+        let auth = self.auth(&request).await;
+        let from = Provenance::of_request(state, addr, &request.metadata().clone().into_headers());
+        if let Err(response) = admit_submitter(state, addr, &from, &auth).await {
+            return into_grpc(response);
+        }
+
+        let batch = match LogBatch::try_from(request.into_inner()) {
+            Ok(batch) => batch,
+            Err(err) => {
+                return into_grpc(submit_error(
+                    state,
+                    StatusCode::BAD_REQUEST,
+                    "malformed",
+                    format!("invalid batch: {err}"),
+                ));
+            }
+        };
+        into_grpc(submit_parsed(state, addr, &from, &auth, batch, None).await)
+    }
+
+    type CheckpointsStream =
+        Pin<Box<dyn Stream<Item = Result<proto::AgentCheckpoint, Status>> + Send>>;
+
+    async fn checkpoints(
+        &self,
+        request: Request<proto::CheckpointsRequest>,
+    ) -> Result<Response<Self::CheckpointsStream>, Status> {
+        if self.denies(&request, None) {
+            return Err(Status::permission_denied(rate_limit::DENIED_MESSAGE));
+        }
+        if !self.admit(LimitGroup::Read, &request, None).await {
+            return Err(Status::resource_exhausted("rate limit exceeded"));
+        }
+        if let Err(err) = self.auth(&request).await.require(Scope::Read) {
+            return Err(Status::new(grpc_code(err.status()), err.message()));
+        }
+        let checkpoints = self
+            .state
+            .checkpoints
+            .get(&self.state.pool)
+            .await
+            .map_err(|_| Status::internal("failed to load checkpoints"))?;
+        let messages = checkpoints.into_iter().map(|cp| {
+            Ok(proto::AgentCheckpoint {
+                agent_id: cp.agent_id,
+                last_seq: cp.last_seq,
+                last_hash: cp.last_hash.to_vec(),
+                count: cp.count,
+                last_accumulator: cp.last_accumulator.map(|acc| acc.to_vec()),
+                last_epoch: cp.last_epoch,
+            })
+        });
+        Ok(Response::new(Box::pin(futures_util::stream::iter(
+            messages,
+        ))))
+    }
+}
+
+pub fn service(state: AppState) -> LogChainServer<GrpcService> {
+    LogChainServer::new(GrpcService::new(state))
+}
+
+pub async fn serve(state: AppState, addr: SocketAddr) {
+    println!("gRPC listening on {addr}");
+    if let Err(err) = tonic::transport::Server::builder()
+        .add_service(service(state))
+        .serve(addr)
+        .await
+    {
+        eprintln!("gRPC server stopped: {err}");
+    }
+}
@@ -0,0 +1,102 @@
+//! Ingestion rate over time: batches and log bytes per fixed-width bucket of
+//! arrival time. Grouped in SQL over `idx_batches_received_ms`, so a day of
+//! hourly buckets reads only that day's index entries.
+
+use crate::{AppState, RECEIVED_AT_MS_EXPR, now_unix_ms};
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqlitePool};
+
+const DEFAULT_WINDOW_MS: i64 = 24 * 3600 * 1000;
+const DEFAULT_BUCKET_SECS: u64 = 3600;
+/// Keeps a response (and its zero-filled buckets) small.
+const MAX_BUCKETS: i64 = 1440;
+
+#[derive(Debug, Deserialize)]
+pub struct HistogramParams {
+    /// Start of the first bucket; defaults to 24 hours ago.
+    since_ms: Option<i64>,
+    /// Bucket width; defaults to an hour.
+    bucket_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct Bucket {
+    start_ms: i64,
+    batches: u64,
+    /// Sum of the batches' `logs` JSON sizes; rows stored before sizes were
+    /// kept add 0.
+    log_bytes: u64,
+}
+
+/// `GET /batches/histogram?since_ms=&bucket_secs=`
+pub async fn handler_histogram(
+    State(state): State<AppState>,
+    Query(params): Query<HistogramParams>,
+) -> Result<Json<Vec<Bucket>>, (StatusCode, String)> {
+    let now_ms = now_unix_ms();
+    let since_ms = params.since_ms.unwrap_or(now_ms - DEFAULT_WINDOW_MS);
+    let bucket_ms = params.bucket_secs.unwrap_or(DEFAULT_BUCKET_SECS) as i64 * 1000;
+    if bucket_ms <= 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "bucket_secs must be positive".into(),
+        ));
+    }
+    if now_ms.saturating_sub(since_ms) / bucket_ms >= MAX_BUCKETS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("more than {MAX_BUCKETS} buckets; raise bucket_secs or since_ms"),
+        ));
+    }
+    histogram(&state.pool, since_ms, bucket_ms, now_ms)
+        .await
+        .map(Json)
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, String::new()))
+}
+
+/// One bucket per `bucket_ms` from `since_ms` up to the one holding `now_ms`,
+/// oldest first, including empty ones.
+pub async fn histogram(
+    pool: &SqlitePool,
+    since_ms: i64,
+    bucket_ms: i64,
+    now_ms: i64,
+) -> Result<Vec<Bucket>, sqlx::Error> {
+    let rows = sqlx::query(&format!(
+        r#"
+        SELECT ({RECEIVED_AT_MS_EXPR} - ?1) / ?2 AS bucket,
+               COUNT(*) AS batches,
+               COALESCE(SUM(logs_size), 0) AS log_bytes
+        FROM batches
+        WHERE {RECEIVED_AT_MS_EXPR} >= ?1
+        GROUP BY bucket
+        "#
+    ))
+    .bind(since_ms)
+    .bind(bucket_ms)
+    .fetch_all(pool)
+    .await?;
+
+    let count = (now_ms.saturating_sub(since_ms) / bucket_ms + 1).max(0) as usize;
+    let mut buckets: Vec<Bucket> = (0..count as i64)
+        .map(|i| Bucket {
+            start_ms: since_ms + i * bucket_ms,
+            batches: 0,
+            log_bytes: 0,
+        })
+        .collect();
+    for row in rows {
+        let index: i64 = row.get("bucket");
+        // A batch stamped after `now_ms` lands past the last bucket.
+        if let Some(bucket) = buckets.get_mut(index as usize) {
+            bucket.batches = row.get::<i64, _>("batches") as u64;
+            bucket.log_bytes = row.get::<i64, _>("log_bytes") as u64;
+        }
+    }
+    Ok(buckets)
+}
@@ -0,0 +1,222 @@
+//! `Idempotency-Key` on `POST /agents/register` and `POST /agents/rotate`.
+//!
+//! A client that retries after a lost response cannot tell whether the first
+//! request ran. Run again, a rotation fails its counter check (409) and a
+//! registration may meet its own key. With the header, the first response
+//! for a key is kept in `idempotency_keys` for `IDEMPOTENCY_TTL_SECS`
+//! (default 24h), and a repeat within that time gets it back verbatim, with
+//! `Idempotency-Replayed: true`, without running again.
+//!
+//! - The key is checked after authorization, so a replay is only served to
+//!   a caller allowed to make the request.
+//! - A key is bound to the request it came with: the same key on another
+//!   endpoint is a different key, and on a different body it is refused
+//!   (422).
+//! - A repeat while the first is still running is refused (409) rather than
+//!   run twice.
+//! - A 5xx is not kept, so retrying it runs the request again.
+//!
+//! `0` turns keys off; the header is then ignored.
+
+use crate::{AgentResponse, AppState, now_unix_ms};
+use axum::{
+    Json,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::Row;
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Mutex;
+
+pub const HEADER: &str = "idempotency-key";
+pub const REPLAYED_HEADER: &str = "idempotency-replayed";
+pub const DEFAULT_TTL_SECS: u64 = 24 * 3600;
+/// Longest key accepted; a UUID is 36.
+const MAX_KEY_LEN: usize = 255;
+
+pub struct Idempotency {
+    ttl_ms: u64,
+    /// Keys whose first request is running, by endpoint.
+    running: Mutex<HashSet<(&'static str, String)>>,
+}
+
+impl Idempotency {
+    pub fn new(ttl_secs: u64) -> Self {
+        Self {
+            ttl_ms: ttl_secs.saturating_mul(1000),
+            running: Mutex::default(),
+        }
+    }
+}
+
+/// Releases a running key however its request ends.
+struct Running<'a> {
+    idempotency: &'a Idempotency,
+    entry: (&'static str, String),
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.idempotency.running.lock().unwrap().remove(&self.entry);
+    }
+}
+
+/// A kept response.
+struct Stored {
+    request_sha256: Vec<u8>,
+    status: u16,
+    body: Vec<u8>,
+}
+
+/// Runs `handler` for `request` on `endpoint`, unless its `Idempotency-Key`
+/// was seen; then the kept response. Without the header, just runs it.
+pub async fn once<T: Serialize>(
+    state: &AppState,
+    endpoint: &'static str,
+    headers: &HeaderMap,
+    request: &impl Serialize,
+    handler: impl Future<Output = (StatusCode, Json<T>)>,
+) -> Response {
+    let idempotency = &state.idempotency;
+    let Some(key) = headers.get(HEADER).filter(|_| idempotency.ttl_ms > 0) else {
+        return handler.await.into_response();
+    };
+    let Some(key) = key
+        .to_str()
+        .ok()
+        .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LEN)
+    else {
+        return refuse(
+            StatusCode::BAD_REQUEST,
+            format!("Idempotency-Key must be 1 to {MAX_KEY_LEN} visible ASCII characters"),
+        );
+    };
+    let request_sha256 = Sha256::digest(serde_json::to_vec(request).unwrap_or_default()).to_vec();
+
+    let stored = match lookup(state, endpoint, key).await {
+        Ok(stored) => stored,
+        Err(err) => return failed(err),
+    };
+    if let Some(stored) = stored {
+        return replay(state, stored, &request_sha256);
+    }
+    let entry = (endpoint, key.to_string());
+    if !idempotency.running.lock().unwrap().insert(entry.clone()) {
+        return refuse(
+            StatusCode::CONFLICT,
+            "a request with this Idempotency-Key is still running; retry once it is done".into(),
+        );
+    }
+    let _running = Running { idempotency, entry };
+    // It may have finished between the lookup and the claim.
+    match lookup(state, endpoint, key).await {
+        Ok(Some(stored)) => return replay(state, stored, &request_sha256),
+        Ok(None) => {}
+        Err(err) => return failed(err),
+    }
+
+    let (status, Json(body)) = handler.await;
+    let body = serde_json::to_vec(&body).unwrap_or_default();
+    if !status.is_server_error()
+        && let Err(err) = store(state, endpoint, key, &request_sha256, status, &body).await
+    {
+        // The request ran; its answer stands, it just cannot be replayed.
+        eprintln!("[idempotency] could not keep the {endpoint} response for key {key}: {err}");
+    }
+    respond(status, body, false)
+}
+
+async fn lookup(
+    state: &AppState,
+    endpoint: &str,
+    key: &str,
+) -> Result<Option<Stored>, sqlx::Error> {
+    let fresh_after = now_unix_ms().saturating_sub(state.idempotency.ttl_ms as i64);
+    let row = sqlx::query(
+        "SELECT request_sha256, status, body FROM idempotency_keys \
+         WHERE endpoint = ?1 AND key = ?2 AND created_at_ms > ?3",
+    )
+    .bind(endpoint)
+    .bind(key)
+    .bind(fresh_after)
+    .fetch_optional(&state.pool)
+    .await?;
+    Ok(row.map(|row| Stored {
+        request_sha256: row.get("request_sha256"),
+        status: row.get::<i64, _>("status") as u16,
+        body: row.get("body"),
+    }))
+}
+
+/// Keeps a response, dropping the expired ones on the way.
+async fn store(
+    state: &AppState,
+    endpoint: &str,
+    key: &str,
+    request_sha256: &[u8],
+    status: StatusCode,
+    body: &[u8],
+) -> Result<(), sqlx::Error> {
+    let now = now_unix_ms();
+    sqlx::query("DELETE FROM idempotency_keys WHERE created_at_ms <= ?1")
+        .bind(now.saturating_sub(state.idempotency.ttl_ms as i64))
+        .execute(&state.pool)
+        .await?;
+    sqlx::query(
+        "INSERT OR REPLACE INTO idempotency_keys (endpoint, key, request_sha256, status, body, created_at_ms) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )
+    .bind(endpoint)
+    .bind(key)
+    .bind(request_sha256)
+    .bind(i64::from(status.as_u16()))
+    .bind(body)
+    .bind(now)
+    .execute(&state.pool)
+    .await?;
+    Ok(())
+}
+
+fn replay(state: &AppState, stored: Stored, request_sha256: &[u8]) -> Response {
+    if stored.request_sha256 != request_sha256 {
+        return refuse(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Idempotency-Key was already used with a different request".into(),
+        );
+    }
+    state.metrics.inc("logchain_idempotent_replays_total");
+    let status = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    respond(status, stored.body, true)
+}
+
+fn respond(status: StatusCode, body: Vec<u8>, replayed: bool) -> Response {
+    let mut response = (status, [(header::CONTENT_TYPE, "application/json")], body).into_response();
+    if replayed {
+        response
+            .headers_mut()
+            .insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+    }
+    response
+}
+
+fn refuse(status: StatusCode, message: String) -> Response {
+    (
+        status,
+        Json(AgentResponse {
+            status: "error".into(),
+            message,
+        }),
+    )
+        .into_response()
+}
+
+fn failed(err: sqlx::Error) -> Response {
+    eprintln!("[idempotency] lookup failed: {err}");
+    refuse(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "failed to check the Idempotency-Key".into(),
+    )
+}
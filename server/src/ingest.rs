@@ -0,0 +1,402 @@
+//! Server-side ingestion for producers that cannot sign their own batches.
+//!
+//! Threat model: lines posted to `/ingest/:source_name` are batched, chained,
+//! and signed with a key the *server* holds, under the synthetic agent id
+//! `ingest:<source_name>`. The chain proves the lines were not altered after
+//! the server accepted them. It proves nothing about what the producer sent:
+//! whoever controls the server (or its database, where the key lives) can
+//! forge these chains. Consumers must treat the server as the trust anchor.
+//! Lines buffered in memory are lost if the server stops before a flush.
+//!
+//! A source buffers at most `INGEST_MAX_BUFFERED_LINES` lines, so storage that
+//! keeps refusing flushes cannot grow the buffers without bound. A post that
+//! would go past the cap is refused with 503 and nothing from it is kept.
+//! Lines posted while a flush was in flight can still push a failed flush's
+//! lines past it; the oldest are then dropped and counted in
+//! `logchain_ingest_lines_dropped_total`.
+
+use crate::{AppState, now_unix, now_unix_ms, record_rejection, store_submitted_batch, valid_auth};
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+};
+use common::batch::{CURRENT_BATCH_VERSION, LogBatch, generate_keypair};
+use ed25519_dalek::{Signature, SigningKey};
+use serde::Serialize;
+use sqlx::{Row, SqlitePool};
+use std::collections::HashMap;
+
+/// Agent ids with this prefix belong to server-signed ingestion chains and are
+/// refused on `/submit` and `/agents/register`.
+pub const AGENT_PREFIX: &str = "ingest:";
+
+pub struct IngestConfig {
+    /// Bearer token required on `/ingest`; ingestion is disabled when unset.
+    pub token: Option<String>,
+    /// Flush a source once this many lines are buffered.
+    pub batch_lines: usize,
+    pub max_body_bytes: usize,
+    /// The most lines one source may hold unflushed.
+    pub max_buffered_lines: usize,
+}
+
+/// Default for `INGEST_MAX_BUFFERED_LINES`.
+pub const DEFAULT_MAX_BUFFERED_LINES: usize = 100_000;
+
+pub struct IngestState {
+    pub config: IngestConfig,
+    buffers: std::sync::Mutex<HashMap<String, Vec<String>>>,
+    // Serializes flushes so two never race for the same chain head.
+    flush_lock: tokio::sync::Mutex<()>,
+}
+
+impl IngestState {
+    pub fn new(config: IngestConfig) -> Self {
+        Self {
+            config,
+            buffers: std::sync::Mutex::new(HashMap::new()),
+            flush_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// The source's buffer length after adding `lines`, or `None`, adding
+    /// nothing, if they would take it past `max_buffered_lines`.
+    fn push(&self, source: &str, lines: Vec<String>) -> Option<usize> {
+        let mut guard = self.buffers.lock().unwrap();
+        let buf = guard.entry(source.to_string()).or_default();
+        if buf.len() + lines.len() > self.config.max_buffered_lines {
+            return None;
+        }
+        buf.extend(lines);
+        Some(buf.len())
+    }
+
+    fn take(&self, source: &str) -> Vec<String> {
+        let mut guard = self.buffers.lock().unwrap();
+        guard.remove(source).unwrap_or_default()
+    }
+
+    /// Puts the lines of a failed flush back ahead of any posted since,
+    /// dropping the oldest past `max_buffered_lines`; returns how many.
+    fn restore(&self, source: &str, mut lines: Vec<String>) -> usize {
+        let mut guard = self.buffers.lock().unwrap();
+        let buf = guard.entry(source.to_string()).or_default();
+        lines.append(buf);
+        let dropped = lines.len().saturating_sub(self.config.max_buffered_lines);
+        lines.drain(..dropped);
+        *buf = lines;
+        dropped
+    }
+
+    fn sources(&self) -> Vec<String> {
+        let guard = self.buffers.lock().unwrap();
+        guard.keys().cloned().collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct IngestResponse {
+    status: String,
+    message: String,
+    accepted_lines: usize,
+}
+
+fn ingest_error(
+    code: StatusCode,
+    message: impl Into<String>,
+) -> (StatusCode, Json<IngestResponse>) {
+    (
+        code,
+        Json(IngestResponse {
+            status: "error".into(),
+            message: message.into(),
+            accepted_lines: 0,
+        }),
+    )
+}
+
+pub async fn handler_ingest(
+    State(state): State<AppState>,
+    Path(source_name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, Json<IngestResponse>) {
+    let Some(expected) = &state.ingest.config.token else {
+        return ingest_error(
+            StatusCode::FORBIDDEN,
+            "ingestion disabled; set INGEST_BEARER_TOKEN to enable",
+        );
+    };
+    if !valid_auth(&headers, expected) {
+        return ingest_error(StatusCode::UNAUTHORIZED, "missing or invalid auth");
+    }
+
+    if !valid_source_name(&source_name) {
+        return ingest_error(
+            StatusCode::BAD_REQUEST,
+            "source name must be 1-64 chars of [A-Za-z0-9._-]",
+        );
+    }
+
+    if body.len() > state.ingest.config.max_body_bytes {
+        return ingest_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("body exceeds {} bytes", state.ingest.config.max_body_bytes),
+        );
+    }
+
+    let lines = match parse_ingest_body(&body) {
+        Ok(lines) => lines,
+        Err(msg) => return ingest_error(StatusCode::BAD_REQUEST, msg),
+    };
+    let accepted = lines.len();
+    let max_buffered = state.ingest.config.max_buffered_lines;
+    if accepted > max_buffered {
+        return ingest_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("body holds more than INGEST_MAX_BUFFERED_LINES ({max_buffered}) lines"),
+        );
+    }
+
+    let Some(buffered) = state.ingest.push(&source_name, lines) else {
+        return ingest_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "{max_buffered} lines of {source_name} are waiting for a flush the server cannot store; retry later"
+            ),
+        );
+    };
+    if buffered >= state.ingest.config.batch_lines
+        && let Err(msg) = flush_source(&state, &source_name).await
+    {
+        // Lines stay buffered; the periodic flush retries them.
+        record_rejection(
+            &state,
+            Some(&agent_id_for(&source_name)),
+            "ingest_flush",
+            &msg,
+            &"ingest".into(),
+        )
+        .await;
+    }
+
+    (
+        StatusCode::ACCEPTED,
+        Json(IngestResponse {
+            status: "ok".into(),
+            message: format!(
+                "lines queued for server-signed chain {}",
+                agent_id_for(&source_name)
+            ),
+            accepted_lines: accepted,
+        }),
+    )
+}
+
+pub fn agent_id_for(source_name: &str) -> String {
+    format!("{}{}", AGENT_PREFIX, source_name)
+}
+
+fn valid_source_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'_' || b == b'-')
+}
+
+/// Accepts either a JSON array of strings or NDJSON. NDJSON lines holding a
+/// JSON string are unwrapped; any other JSON value is kept as its compact text.
+fn parse_ingest_body(body: &[u8]) -> Result<Vec<String>, String> {
+    let text = std::str::from_utf8(body).map_err(|_| "body must be UTF-8".to_string())?;
+    let trimmed = text.trim_start();
+
+    if trimmed.starts_with('[') {
+        return serde_json::from_str::<Vec<String>>(trimmed)
+            .map_err(|e| format!("expected a JSON array of strings: {e}"));
+    }
+
+    let mut lines = Vec::new();
+    for (idx, raw) in text.lines().enumerate() {
+        if raw.trim().is_empty() {
+            continue;
+        }
+        let value: serde_json::Value = serde_json::from_str(raw)
+            .map_err(|e| format!("invalid JSON on line {}: {e}", idx + 1))?;
+        match value {
+            serde_json::Value::String(s) => lines.push(s),
+            other => lines.push(other.to_string()),
+        }
+    }
+    Ok(lines)
+}
+
+/// Flushes every source with buffered lines; used by the periodic timer.
+pub async fn flush_all(state: &AppState) {
+    for source in state.ingest.sources() {
+        if let Err(msg) = flush_source(state, &source).await {
+            record_rejection(
+                state,
+                Some(&agent_id_for(&source)),
+                "ingest_flush",
+                &msg,
+                &"ingest".into(),
+            )
+            .await;
+        }
+    }
+}
+
+/// Seals the buffered lines for `source` into one server-signed batch.
+pub async fn flush_source(state: &AppState, source: &str) -> Result<(), String> {
+    let _guard = state.ingest.flush_lock.lock().await;
+
+    let lines = state.ingest.take(source);
+    if lines.is_empty() {
+        return Ok(());
+    }
+
+    match seal_batch(state, source, lines.clone()).await {
+        Ok(()) => Ok(()),
+        Err(msg) => {
+            let dropped = state.ingest.restore(source, lines);
+            if dropped > 0 {
+                state
+                    .metrics
+                    .add("logchain_ingest_lines_dropped_total", dropped as u64);
+                eprintln!(
+                    "[ingest] dropped the {dropped} oldest unflushed lines of {source}: buffer full"
+                );
+            }
+            Err(msg)
+        }
+    }
+}
+
+async fn seal_batch(state: &AppState, source: &str, lines: Vec<String>) -> Result<(), String> {
+    let agent_id = agent_id_for(source);
+    let key = source_key(&state.pool, source).await?;
+
+    // Server-held keys are registered like any agent so chain checks are identical.
+    sqlx::query(
+        "INSERT OR IGNORE INTO agents (agent_id, public_key, created_at) VALUES (?1, ?2, ?3)",
+    )
+    .bind(&agent_id)
+    .bind(key.verifying_key().to_bytes().to_vec())
+    .bind(now_unix())
+    .execute(&state.pool)
+    .await
+    .map_err(|e| format!("failed to register ingest key: {e}"))?;
+    sqlx::query(
+        "INSERT INTO agent_keys (agent_id, public_key, valid_from_seq, valid_until_seq, created_at) \
+         SELECT ?1, ?2, 1, NULL, ?3 WHERE NOT EXISTS (SELECT 1 FROM agent_keys WHERE agent_id = ?1)",
+    )
+    .bind(&agent_id)
+    .bind(key.verifying_key().to_bytes().to_vec())
+    .bind(now_unix())
+    .execute(&state.pool)
+    .await
+    .map_err(|e| format!("failed to record ingest key history: {e}"))?;
+
+    let head =
+        sqlx::query("SELECT epoch, seq, hash, accumulator FROM batches WHERE agent_id = ?1 ORDER BY epoch DESC, seq DESC LIMIT 1")
+            .bind(&agent_id)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(|e| format!("failed to read chain head: {e}"))?;
+
+    // Server-side chains never start an epoch; they continue the head's.
+    let (epoch, seq, prev_hash, prev_accumulator) = match head {
+        Some(row) => {
+            let last_seq: i64 = row.get("seq");
+            let last_hash: Vec<u8> = row.get("hash");
+            let last_hash: [u8; 32] = last_hash
+                .try_into()
+                .map_err(|_| "bad stored hash".to_string())?;
+            let last_accumulator = row
+                .get::<Option<Vec<u8>>, _>("accumulator")
+                .and_then(|v| v.try_into().ok());
+            let epoch: i64 = row.get("epoch");
+            (
+                epoch as u64,
+                last_seq as u64 + 1,
+                last_hash,
+                last_accumulator,
+            )
+        }
+        None => (0, 1, [0u8; 32], None),
+    };
+
+    let mut batch = LogBatch {
+        prev_hash,
+        logs: lines,
+        timestamp: now_unix_ms() as u64,
+        agent_id,
+        seq,
+        signature: Signature::from_bytes(&[0u8; 64]),
+        public_key: key.verifying_key(),
+        lines_read: None,
+        version: CURRENT_BATCH_VERSION,
+        accumulator: None,
+        gap: None,
+        epoch,
+        epoch_start: None,
+        kind: None,
+    };
+    batch.accumulator = Some(batch.expected_accumulator(prev_accumulator.as_ref()));
+    batch.sign(&key);
+
+    let (code, Json(resp)) = store_submitted_batch(state, &"ingest".into(), batch, None).await;
+    if code.is_success() {
+        Ok(())
+    } else {
+        Err(resp.message)
+    }
+}
+
+/// Loads the per-source signing key, generating it on first use.
+async fn source_key(pool: &SqlitePool, source: &str) -> Result<SigningKey, String> {
+    let fresh = generate_keypair();
+    sqlx::query("INSERT OR IGNORE INTO ingest_keys (source_name, signing_key, created_at) VALUES (?1, ?2, ?3)")
+        .bind(source)
+        .bind(fresh.to_bytes().to_vec())
+        .bind(now_unix())
+        .execute(pool)
+        .await
+        .map_err(|e| format!("failed to store ingest key: {e}"))?;
+
+    let stored: Vec<u8> =
+        sqlx::query_scalar("SELECT signing_key FROM ingest_keys WHERE source_name = ?1")
+            .bind(source)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| format!("failed to load ingest key: {e}"))?;
+    let bytes: [u8; 32] = stored
+        .try_into()
+        .map_err(|_| "stored ingest key is invalid".to_string())?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_failed_flush_keeps_only_the_newest_lines_past_the_cap() {
+        let ingest = IngestState::new(IngestConfig {
+            token: None,
+            batch_lines: 100,
+            max_body_bytes: 1024,
+            max_buffered_lines: 3,
+        });
+        let lines = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(ingest.push("app", lines(&["a", "b"])), Some(2));
+        let flushing = ingest.take("app");
+        // Posted while the flush was in flight.
+        assert_eq!(ingest.push("app", lines(&["c", "d"])), Some(2));
+        assert_eq!(ingest.restore("app", flushing), 1);
+        assert_eq!(ingest.take("app"), lines(&["b", "c", "d"]));
+    }
+}
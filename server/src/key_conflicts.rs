@@ -0,0 +1,94 @@
+//! One public key bound to several agent IDs, usually a copied state dir:
+//! two hosts then sign interleaved chains with the same identity.
+//!
+//! Always audited (startup log, `/metrics`, `GET /admin/key-conflicts`);
+//! only refused at registration, auto-registration and rotation when
+//! `UNIQUE_AGENT_KEYS` is set. Revoked agents can no longer sign, so their
+//! keys are left out.
+
+use crate::AppState;
+use common::hex::hex_encode;
+use serde::Serialize;
+use sqlx::{Row, SqliteConnection, SqlitePool};
+
+#[derive(Debug, Serialize)]
+pub struct KeyConflict {
+    #[serde(serialize_with = "crate::serialize_hex")]
+    pub public_key: Vec<u8>,
+    /// Sorted.
+    pub agent_ids: Vec<String>,
+}
+
+/// Every key currently bound to more than one unrevoked agent.
+pub async fn key_conflicts(pool: &SqlitePool) -> Result<Vec<KeyConflict>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT public_key, agent_id FROM agents
+        WHERE revoked_at IS NULL AND public_key IN (
+            SELECT public_key FROM agents WHERE revoked_at IS NULL
+            GROUP BY public_key HAVING COUNT(*) > 1
+        )
+        ORDER BY public_key, agent_id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut conflicts: Vec<KeyConflict> = Vec::new();
+    for row in rows {
+        let public_key: Vec<u8> = row.get("public_key");
+        let agent_id: String = row.get("agent_id");
+        match conflicts.last_mut() {
+            Some(last) if last.public_key == public_key => last.agent_ids.push(agent_id),
+            _ => conflicts.push(KeyConflict {
+                public_key,
+                agent_ids: vec![agent_id],
+            }),
+        }
+    }
+    Ok(conflicts)
+}
+
+/// Another unrevoked agent already holding `public_key`, if any. Callers run
+/// this after their own write, inside the same transaction, so a concurrent
+/// registration of the same key cannot slip past both checks.
+pub async fn other_owner(
+    conn: &mut SqliteConnection,
+    public_key: &[u8],
+    agent_id: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT agent_id FROM agents WHERE public_key = ?1 AND agent_id != ?2 AND revoked_at IS NULL ORDER BY agent_id LIMIT 1",
+    )
+    .bind(public_key)
+    .bind(agent_id)
+    .fetch_optional(conn)
+    .await
+}
+
+pub fn conflict_message(owner: &str) -> String {
+    format!("public key is already registered to agent '{owner}'")
+}
+
+/// Startup audit: one line per shared key, whatever the policy.
+pub async fn log_conflicts(pool: &SqlitePool) {
+    let Ok(conflicts) = key_conflicts(pool).await else {
+        return;
+    };
+    for conflict in conflicts {
+        let prefix = hex_encode(conflict.public_key.get(..4).unwrap_or(&conflict.public_key));
+        println!(
+            "[key-conflict] key {prefix}… is shared by agents {}",
+            conflict.agent_ids.join(", ")
+        );
+    }
+}
+
+/// `/metrics` line counting keys shared by more than one agent.
+pub async fn render_metrics(state: &AppState) -> String {
+    let shared = key_conflicts(&state.pool)
+        .await
+        .map(|conflicts| conflicts.len())
+        .unwrap_or(0);
+    format!("logchain_agent_key_conflicts {shared}\n")
+}
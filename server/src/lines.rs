@@ -0,0 +1,196 @@
+//! `GET /logs/:agent_id/:seq/:line_idx`: one stored line by its address
+//! (see [`common::address`]), with the batch around it and its place in the
+//! hash chain. The batch is found through the `(agent_id, epoch, seq)`
+//! unique index and its logs are decoded once to slice out the line.
+//!
+//! Errors carry `{"error", "message"}`, `error` being one of
+//! `invalid_address` (400), `batch_not_found` (404), `line_out_of_range`
+//! (404, with the batch's `line_count`), `batch_archived` (410: the logs were
+//! tiered to the blob store and the blob cannot be read here) or `internal`.
+
+use crate::{AppState, BATCH_READ_COLUMNS, TIMESTAMP_MS_EXPR, parse_stored_logs, tiering};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use common::address::LineAddress;
+use common::serde_util::is_zero;
+use serde::Serialize;
+use sqlx::Row;
+
+#[derive(Debug, Serialize)]
+pub struct AddressedLine {
+    address: String,
+    line: String,
+    /// The line is a redaction marker; see [`crate::redactions`].
+    redacted: bool,
+    batch: LineBatch,
+}
+
+/// The batch a line belongs to: enough to find it, and to check its link.
+#[derive(Debug, Serialize)]
+struct LineBatch {
+    id: i64,
+    agent_id: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    epoch: u64,
+    seq: u64,
+    line_count: usize,
+    timestamp_ms: u64,
+    received_at_ms: u64,
+    prev_hash: [u8; 32],
+    hash: [u8; 32],
+    accumulator: Option<[u8; 32]>,
+}
+
+#[derive(Debug)]
+pub enum LineError {
+    InvalidAddress(String),
+    BatchNotFound(String),
+    OutOfRange { address: String, line_count: usize },
+    Archived(String),
+    Internal,
+}
+
+impl IntoResponse for LineError {
+    fn into_response(self) -> Response {
+        let (status, error, message, line_count) = match self {
+            LineError::InvalidAddress(msg) => {
+                (StatusCode::BAD_REQUEST, "invalid_address", msg, None)
+            }
+            LineError::BatchNotFound(address) => (
+                StatusCode::NOT_FOUND,
+                "batch_not_found",
+                format!("no batch stored at {address}"),
+                None,
+            ),
+            LineError::OutOfRange {
+                address,
+                line_count,
+            } => (
+                StatusCode::NOT_FOUND,
+                "line_out_of_range",
+                format!("{address} is past the batch's {line_count} lines"),
+                Some(line_count),
+            ),
+            LineError::Archived(reason) => (
+                StatusCode::GONE,
+                "batch_archived",
+                format!(
+                    "the batch's logs were moved to the blob store and cannot be read: {reason}"
+                ),
+                None,
+            ),
+            LineError::Internal => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                "failed to read the batch".to_string(),
+                None,
+            ),
+        };
+        let mut body = serde_json::json!({ "error": error, "message": message });
+        if let Some(count) = line_count {
+            body["line_count"] = count.into();
+        }
+        (status, Json(body)).into_response()
+    }
+}
+
+pub async fn handler_line(
+    State(state): State<AppState>,
+    Path((agent_id, position, line_idx)): Path<(String, String, String)>,
+) -> Result<Json<AddressedLine>, LineError> {
+    let address: LineAddress = format!("{agent_id}/{position}/{line_idx}")
+        .parse()
+        .map_err(LineError::InvalidAddress)?;
+    line(&state, &address).await.map(Json)
+}
+
+pub async fn line(state: &AppState, address: &LineAddress) -> Result<AddressedLine, LineError> {
+    let row = sqlx::query(&format!(
+        "SELECT {BATCH_READ_COLUMNS}, {TIMESTAMP_MS_EXPR} AS timestamp_ms FROM batches \
+         WHERE agent_id = ?1 AND epoch = ?2 AND seq = ?3"
+    ))
+    .bind(&address.agent_id)
+    .bind(address.epoch as i64)
+    .bind(address.seq as i64)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|_| LineError::Internal)?;
+    let Some(row) = row else {
+        return Err(LineError::BatchNotFound(format!(
+            "{}/{}",
+            address.agent_id,
+            address.position()
+        )));
+    };
+
+    let json = match tiering::compressed_logs(&row) {
+        Ok(Some(blob)) => {
+            let mut json = String::new();
+            state
+                .decompressor
+                .row_logs(&row, &blob, &mut json)
+                .map_err(|_| LineError::Internal)?;
+            json
+        }
+        Ok(None) => row.get("logs"),
+        Err(err) => return Err(LineError::Archived(err.to_string())),
+    };
+    let (mut logs, _) = parse_stored_logs(&json).map_err(|_| LineError::Internal)?;
+    let line_count = logs.len();
+    if address.line_idx >= line_count {
+        return Err(LineError::OutOfRange {
+            address: address.to_string(),
+            line_count,
+        });
+    }
+
+    let bytes = |column: &str| -> Result<[u8; 32], LineError> {
+        row.get::<Vec<u8>, _>(column)
+            .try_into()
+            .map_err(|_| LineError::Internal)
+    };
+    let received_at_ms = row
+        .get::<Option<i64>, _>("received_at_ms")
+        .unwrap_or_else(|| row.get::<i64, _>("received_at") * 1000);
+    Ok(AddressedLine {
+        address: address.to_string(),
+        line: logs.swap_remove(address.line_idx),
+        redacted: crate::redactions::stored_redacted(&row).contains(&address.line_idx),
+        batch: LineBatch {
+            id: row.get("id"),
+            agent_id: address.agent_id.clone(),
+            epoch: address.epoch,
+            seq: address.seq,
+            line_count,
+            timestamp_ms: row.get::<i64, _>("timestamp_ms") as u64,
+            received_at_ms: received_at_ms as u64,
+            prev_hash: bytes("prev_hash")?,
+            hash: bytes("hash")?,
+            accumulator: crate::stored_accumulator(&row),
+        },
+    })
+}
+
+/// Addresses of the lines of `logs` that `keep` accepts.
+pub fn matching(
+    address_of: impl Fn(usize) -> LineAddress,
+    logs: &[String],
+    keep: impl Fn(&str) -> bool,
+) -> Vec<String> {
+    logs.iter()
+        .enumerate()
+        .filter(|(_, line)| keep(line))
+        .map(|(idx, _)| address_of(idx).to_string())
+        .collect()
+}
+
+/// Whether `line` contains `substring`, compared as `log_substring`'s `LIKE`
+/// does: ASCII letters in either case.
+pub fn contains(line: &str, substring: &str) -> bool {
+    line.to_ascii_lowercase()
+        .contains(&substring.to_ascii_lowercase())
+}
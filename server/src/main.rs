@@ -1,98 +1,611 @@
+use auth::{AuthContext, Scope, scoped};
 use axum::{
+    Extension, Json, Router,
+    body::{Body, Bytes},
     extract::{ConnectInfo, Path, Query, State},
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    http::{HeaderMap, HeaderName, StatusCode, header},
+    response::{IntoResponse, Response},
     routing::{get, post},
-    Json, Router,
 };
-use common::batch::LogBatch;
+use common::address::LineAddress;
+use common::batch::{
+    BATCH_VERSION_V1, BatchKind, CURRENT_BATCH_VERSION, EpochStart, GapRecord, LogBatch,
+};
+use common::compat::Capabilities;
+use common::export::{ExportFormat, ParquetCompression, render_lines};
+use common::export_header::{self, ExportHeader};
+use common::hex::{hex_decode_fixed, hex_digit, hex_encode};
+#[cfg(feature = "parquet")]
+use common::parquet_export::ParquetExporter;
+use common::receipt::Receipt;
+use common::rotation::registration_message;
+use common::serde_util::is_zero;
+use common::wire::WireFormat;
 use ed25519_dalek::{Signature, VerifyingKey};
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
 use serde::{Deserialize, Serialize};
-use sqlx::{QueryBuilder, Row, Sqlite, SqlitePool, Transaction};
+use serde_json::value::RawValue;
+use sqlx::{Connection, QueryBuilder, Row, Sqlite, SqlitePool, Transaction};
+use std::collections::HashSet;
+use std::env;
 use std::io::{Read, Write};
 use std::net::SocketAddr;
-use std::env;
-use std::collections::HashMap;
-use std::time::{Duration as StdDuration, Instant};
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
 use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use tokio::sync::Semaphore;
 use tokio::time::{self, Duration};
-use tokio::sync::Mutex;
-use std::sync::Arc;
+
+mod ack;
+mod admin;
+mod anomaly;
+mod auth;
+mod checkpoint_cache;
+#[cfg(feature = "dashboard")]
+mod dashboard;
+mod decompress;
+mod dicts;
+mod drift;
+mod fields;
+mod forks;
+mod fsck;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod histogram;
+mod idempotency;
+mod ingest;
+mod key_conflicts;
+mod lines;
+mod maintenance;
+mod metrics;
+mod rate_limit;
+mod receipts;
+mod redactions;
+mod request_id;
+mod retention;
+mod rotation;
+mod sessions;
+mod stale;
+mod storage;
+mod summaries;
+mod tiering;
+mod timeout;
+mod tsa;
+mod watermark;
+
+use ingest::{IngestConfig, IngestState};
+use metrics::{Metrics, labeled};
+use rate_limit::{LimitGroup, LimitKey, RateAlgo, RateLimiter, RateLimits, RouteLimit};
+use storage::{
+    StorageFault, StorageHealth, agent_storage_error, internal_or_storage_error, storage_error,
+};
 
 #[derive(Clone)]
 struct AppState {
     pool: SqlitePool,
     require_registration: bool,
-    rate_limiter: Arc<RateLimiter>,
+    /// Per-route-group limiters; see [`rate_limit`].
+    rate_limits: Arc<RateLimits>,
+    auth_failures: Arc<RateLimiter>,
     auth_token: Option<String>,
+    verify_only: bool,
+    store_raw_body: bool,
+    /// `STORE_CLIENT_INFO`: keep each submit's `User-Agent` and TLS fingerprint.
+    store_client_info: bool,
+    /// `TLS_FINGERPRINT_HEADER`: where the TLS-terminating proxy puts the
+    /// client's fingerprint; none is recorded without it.
+    tls_fingerprint_header: Option<HeaderName>,
+    /// `LOG_REQUESTS`: one JSON line per HTTP request; see [`request_id`].
+    log_requests: bool,
+    metrics: Arc<Metrics>,
+    /// The last storage fault, for `/readyz`; see [`storage`].
+    storage: Arc<StorageHealth>,
+    ingest: Arc<IngestState>,
+    // Caps concurrent signature checks on the blocking pool.
+    verify_workers: Arc<Semaphore>,
+    compression_level: Compression,
+    // Logs JSON shorter than this is stored plaintext only.
+    compression_min_bytes: usize,
+    /// The zstd dictionary new logs are compressed with, if any; see [`dicts`].
+    compression_dict: Arc<dicts::CurrentDict>,
+    /// Caps what a stored blob may inflate to; see [`decompress`].
+    decompressor: decompress::Decompressor,
+    // Per-agent storage series on /metrics; one pair per agent, so optional.
+    agent_size_metrics: bool,
+    admin_token: Option<String>,
+    snapshot_path: Option<String>,
+    /// Where old compressed copies go with `BLOB_TIER_STORE`; see [`tiering`].
+    blob_store: Option<tiering::BlobStore>,
+    clock_drift_alert_ms: u64,
+    /// Default `/agents/stale` threshold and the one behind `logchain_agents_stale`.
+    stale_agent_secs: u64,
+    /// Per-agent submit statistics; `None` unless `ANOMALY_THRESHOLD` is set.
+    anomaly: Option<Arc<anomaly::AnomalyTracker>>,
+    /// `UNIQUE_AGENT_KEYS`: refuse a key another agent already holds.
+    unique_agent_keys: bool,
+    /// Which `POST /agents/rotate` requests are taken; see [`rotation`].
+    rotation: rotation::RotationPolicy,
+    /// `ACCEPT_GAP_MARKERS`: store signed gap markers instead of refusing them.
+    accept_gap_markers: bool,
+    /// `ACCEPT_AFTER_CLOSE`: store batches that follow a chain close instead
+    /// of refusing them.
+    accept_after_close: bool,
+    /// Background `/admin/fsck` jobs.
+    fsck: Arc<fsck::FsckJobs>,
+    /// `WATERMARK_PATH`: per-agent high-water marks kept outside the
+    /// database; see [`watermark`].
+    watermarks: Option<Arc<watermark::Watermarks>>,
+    /// `/batches/checkpoints` from memory; see [`checkpoint_cache`].
+    checkpoints: Arc<checkpoint_cache::CheckpointCache>,
+    /// `Idempotency-Key` on registration and rotation; see [`idempotency`].
+    idempotency: Arc<idempotency::Idempotency>,
+    /// Signs the receipt of each stored batch with the active server key.
+    receipts: Arc<receipts::ReceiptSigner>,
+    /// Per-route-group request limits; see [`timeout`].
+    timeouts: timeout::Timeouts,
+    /// Refuses submits, registration and rotation; see [`maintenance`].
+    maintenance: Arc<maintenance::Maintenance>,
+    /// `SUBMIT_ACK_MODE`: how a stored batch commits; see [`ack`].
+    ack_mode: ack::AckMode,
 }
 
 #[derive(Serialize)]
 struct SubmitResponse {
     status: String,
     message: String,
+    /// Server clock on accepted batches, so agents can spot their own skew.
+    /// Left off errors so every rejection body stays identical.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_time_ms: Option<u64>,
+    /// The batch's receipt, on stores and on resends of a stored batch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receipt: Option<Receipt>,
+    /// Hex hash of the different batch already stored at the submitted
+    /// position, on a `seq_conflict` that is a clash rather than a gap.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stored_hash: Option<String>,
+    /// `durable` or `fast` on a stored batch: the guarantee behind the 201.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ack: Option<&'static str>,
+    /// On a rejection, its machine-readable category; see [`rejection_code`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+}
+
+impl SubmitResponse {
+    fn new(status: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            status: status.into(),
+            message: message.into(),
+            server_time_ms: None,
+            receipt: None,
+            stored_hash: None,
+            ack: None,
+            code: None,
+        }
+    }
+
+    fn accepted(status: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            server_time_ms: Some(now_unix_ms() as u64),
+            ..Self::new(status, message)
+        }
+    }
 }
 
 #[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
 struct QueryBatch {
     id: i64,
     batch: LogBatch,
     hash: [u8; 32],
+    /// Server arrival time in unix milliseconds; strictly increasing across rows.
+    received_at: u64,
+    /// Lines replaced by their redaction marker; see [`redactions`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    redacted: Vec<usize>,
 }
 
-#[derive(Debug, Deserialize)]
+/// [`QueryBatch`] as `/batches` and the JSON export send it: the same JSON,
+/// built without decoding the stored logs into lines only to encode them
+/// again. Paths that verify or render batches keep the typed [`LogBatch`].
+#[derive(Serialize)]
+struct RawQueryBatch {
+    id: i64,
+    batch: RawLogBatch,
+    hash: [u8; 32],
+    received_at: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    redacted: Vec<usize>,
+    /// With `log_substring` or structured filters: the addresses of the
+    /// lines that satisfy them all.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matches: Option<Vec<String>>,
+}
+
+/// [`LogBatch`]'s fields in its serialized order, with `logs` embedded as
+/// stored and the signature and key as their stored bytes. Keys are not
+/// decoded here; the integrity check and fsck report rows whose key is bad.
+#[derive(Serialize)]
+struct RawLogBatch {
+    prev_hash: [u8; 32],
+    logs: Box<RawValue>,
+    timestamp: u64,
+    agent_id: String,
+    seq: u64,
+    signature: Vec<u8>,
+    public_key: [u8; 32],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lines_read: Option<u64>,
+    #[serde(skip_serializing_if = "is_v1")]
+    version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    accumulator: Option<[u8; 32]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gap: Option<GapRecord>,
+    #[serde(skip_serializing_if = "is_zero")]
+    epoch: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    epoch_start: Option<EpochStart>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kind: Option<BatchKind>,
+}
+
+fn is_v1(version: &u32) -> bool {
+    *version == BATCH_VERSION_V1
+}
+
+/// Columns the batch readers use: everything but the archived raw body, the
+/// plaintext logs only where there is no compressed copy to serve, and the
+/// stub of a compressed copy moved to the blob store (see [`tiering`]).
+const BATCH_READ_COLUMNS: &str = "id, agent_id, seq, prev_hash, hash, CASE WHEN logs_compressed IS NULL AND NOT EXISTS (SELECT 1 FROM blob_locations WHERE batch_id = batches.id) THEN logs END AS logs, logs_compressed, (SELECT location FROM blob_locations WHERE batch_id = batches.id) AS blob_location, (SELECT blob_sha256 FROM blob_locations WHERE batch_id = batches.id) AS blob_sha256, timestamp, signature, public_key, received_at, received_at_ms, lines_read, batch_version, accumulator, gap_from, gap_to, gap_reason, epoch, epoch_prev_seq, session_id, session_boot_ms, closed_reason, (SELECT group_concat(line_idx) FROM redactions WHERE batch_id = batches.id) AS redacted_lines";
+
+#[derive(Debug, Default, Deserialize)]
 struct ListParams {
     agent_id: Option<String>,
+    /// Only batches of this epoch; with `since_seq`, addresses seqs within it.
+    epoch: Option<u64>,
     since_seq: Option<u64>,
     limit: Option<u64>,
     offset: Option<u64>,
+    /// Agent timestamp bounds in unix seconds (inclusive), for any batch version.
     since_timestamp: Option<u64>,
     until_timestamp: Option<u64>,
+    /// Agent timestamp bounds in unix milliseconds (inclusive); take precedence
+    /// over the second-based bounds.
+    since_ts_ms: Option<u64>,
+    until_ts_ms: Option<u64>,
     log_substring: Option<String>,
+    /// Exclusive lower bound on server arrival time (unix ms) for incremental pulls.
+    since_received_at: Option<u64>,
+    /// Inclusive upper bound on server arrival time (unix ms): only what the
+    /// server held at that moment.
+    as_of_received_at: Option<u64>,
+    /// Hex the stored hash starts with; at least [`MIN_HASH_PREFIX_HEX`] digits.
+    hash_prefix: Option<String>,
+    /// Structured lines whose `level` is this, in any ASCII case; see [`fields`].
+    level: Option<String>,
+    /// `level` and the `field.<key>=<value>` parameters, which serde cannot
+    /// name in advance; filled in by the handler.
+    #[serde(skip)]
+    structured: fields::Filters,
 }
 
-#[derive(Debug, Deserialize)]
+/// Shortest `hash_prefix` accepted: 8 hex digits narrow the store to about
+/// one batch in four billion.
+const MIN_HASH_PREFIX_HEX: usize = 8;
+
+/// The blob range `[lower, upper)` holding every 32-byte hash that starts
+/// with `prefix`; `upper` is `None` when the prefix is all `f`. An odd
+/// digit count fills the low half of the last byte with zeros.
+fn hash_prefix_range(prefix: &str) -> Result<(Vec<u8>, Option<Vec<u8>>), String> {
+    if prefix.len() < MIN_HASH_PREFIX_HEX || prefix.len() > 64 {
+        return Err(format!(
+            "hash_prefix must be {MIN_HASH_PREFIX_HEX} to 64 hex digits"
+        ));
+    }
+    let nibbles = prefix
+        .bytes()
+        .map(|c| hex_digit(c).ok_or("hash_prefix must be hex"))
+        .collect::<Result<Vec<u8>, _>>()?;
+    let pack = |nibbles: &[u8]| -> Vec<u8> {
+        nibbles
+            .chunks(2)
+            .map(|pair| (pair[0] << 4) | pair.get(1).copied().unwrap_or(0))
+            .collect()
+    };
+
+    // The upper bound is the prefix plus one, carried from the last digit.
+    let mut next = nibbles.clone();
+    while let Some(last) = next.pop() {
+        if last < 0xf {
+            next.push(last + 1);
+            break;
+        }
+    }
+    let upper = (!next.is_empty()).then(|| pack(&next));
+    Ok((pack(&nibbles), upper))
+}
+
+#[derive(Debug, Default, Deserialize)]
 struct ExportParams {
     since_id: Option<i64>,
     limit: Option<u64>,
+    since_received_at: Option<u64>,
+    /// See [`ListParams::as_of_received_at`].
+    as_of_received_at: Option<u64>,
+    /// `json` (default), `ndjson`, `syslog` (RFC 5424), `cef` or `parquet`;
+    /// all but `json` stream.
+    format: Option<ExportFormat>,
+    /// Parquet codec, `snappy` (default) or `zstd`.
+    compression: Option<ParquetCompression>,
+    /// Put a signed [`ExportHeader`] ahead of the rows: the first ndjson
+    /// line, or beside `batches` in a JSON object. Other formats refuse it.
+    #[serde(default)]
+    header: bool,
+}
+
+/// A `format=json` export with `header=true`.
+#[derive(Serialize)]
+struct HeadedExport {
+    export_header: ExportHeader,
+    batches: Vec<RawQueryBatch>,
 }
 
+/// Arrival time in ms; rows stored before `received_at_ms` existed fall back to
+/// their second-resolution `received_at`, so they count as arriving at the
+/// start of their second. Kept identical to the index expression.
+const RECEIVED_AT_MS_EXPR: &str = "COALESCE(received_at_ms, received_at * 1000)";
+
+/// Agent timestamp in ms. v1 rows stored seconds and are converted here, at query
+/// time, because stored rows are immutable. Kept identical to the index expression.
+const TIMESTAMP_MS_EXPR: &str =
+    "(CASE WHEN COALESCE(batch_version, 1) >= 2 THEN timestamp ELSE timestamp * 1000 END)";
+
+/// Row metadata for `/batches/meta`; timestamps are normalized to unix ms.
 #[derive(Serialize)]
+struct BatchMeta {
+    id: i64,
+    agent_id: String,
+    seq: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    epoch: u64,
+    hash: [u8; 32],
+    timestamp_ms: u64,
+    received_at: u64,
+    lines_read: Option<u64>,
+    /// Uncompressed logs JSON bytes.
+    logs_size: Option<u64>,
+    /// Gzip blob bytes; `None` when the logs were stored plaintext only.
+    logs_compressed_size: Option<u64>,
+    accumulator: Option<[u8; 32]>,
+    /// Set when anomaly scoring was on and the agent past its warm-up.
+    anomaly_score: Option<f64>,
+    /// The submitting client's `User-Agent` and TLS fingerprint, when
+    /// `STORE_CLIENT_INFO` was on.
+    user_agent: Option<String>,
+    tls_fingerprint: Option<String>,
+    /// Set on gap markers: the seqs this row declares lost.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gap: Option<GapRecord>,
+    /// Set on the first batch of an epoch after the first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    epoch_start: Option<EpochStart>,
+    /// Set on session starts; see [`sessions`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kind: Option<BatchKind>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 struct AgentCheckpoint {
     agent_id: String,
+    /// Epoch of the last batch; `last_seq` counts within it.
+    last_epoch: u64,
     last_seq: u64,
     last_hash: [u8; 32],
     count: u64,
+    /// Accumulator of the last batch, if it carries one.
+    last_accumulator: Option<[u8; 32]>,
+}
+
+/// A checkpoint as it stood at `as_of_received_at`, with the row id and
+/// arrival of its head batch. That batch's receipt (`/batches/:id/receipt`)
+/// was signed when it arrived, so it vouches for the head from then.
+#[derive(Debug, Serialize)]
+struct CheckpointAsOf {
+    #[serde(flatten)]
+    checkpoint: AgentCheckpoint,
+    batch_id: i64,
+    received_at_ms: u64,
+}
+
+/// Generic message for every authn/authz/registration failure on `/submit`, so
+/// probers cannot tell a bad token from an unknown or mismatched agent.
+const FORBIDDEN_MESSAGE: &str = "forbidden";
+
+/// Where a submission came from, kept on its `batches` row or its
+/// `rejections` entry.
+#[derive(Debug, Default)]
+struct Provenance {
+    /// Peer address, or `ingest` for server-side ingestion.
+    source: String,
+    /// Only with `STORE_CLIENT_INFO`; `None` when the client sent none.
+    user_agent: Option<String>,
+    /// Only with `STORE_CLIENT_INFO` and `TLS_FINGERPRINT_HEADER`.
+    tls_fingerprint: Option<String>,
+    /// The request's `X-Request-Id`; see [`request_id`].
+    request_id: Option<String>,
+}
+
+/// Longest client-supplied provenance value kept; the rest is cut off.
+const MAX_CLIENT_INFO_LEN: usize = 256;
+
+impl Provenance {
+    /// A request from `addr`. Header values that are not visible ASCII are
+    /// treated as missing. HTTP requests always carry an id by now; a gRPC
+    /// call without a well-formed one gets a new id.
+    fn of_request(state: &AppState, addr: SocketAddr, headers: &HeaderMap) -> Self {
+        let value = |name: &HeaderName| {
+            let value = headers.get(name)?.to_str().ok()?.trim();
+            (!value.is_empty()).then(|| value[..value.len().min(MAX_CLIENT_INFO_LEN)].to_string())
+        };
+        let keep = state.store_client_info;
+        Self {
+            source: addr.to_string(),
+            user_agent: value(&header::USER_AGENT).filter(|_| keep),
+            tls_fingerprint: state
+                .tls_fingerprint_header
+                .as_ref()
+                .and_then(value)
+                .filter(|_| keep),
+            request_id: Some(request_id::of(headers).unwrap_or_else(common::request_id::generate)),
+        }
+    }
+}
+
+impl From<&str> for Provenance {
+    fn from(source: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            ..Self::default()
+        }
+    }
+}
+
+/// Logs a rejection with its detailed reason and appends it to the `rejections`
+/// audit table. Callers must not hold a transaction, since this writes via the pool.
+async fn record_rejection(
+    state: &AppState,
+    agent: Option<&str>,
+    category: &str,
+    reason: &str,
+    from: &Provenance,
+) {
+    let agent_label = agent.unwrap_or("-");
+    let request = from.request_id.as_deref().unwrap_or("-");
+    if state.verify_only {
+        // Verify-only servers may sit on a read replica; log but never write.
+        eprintln!(
+            "[verify-only] submit would be rejected for agent {} [{}]: {} (request {})",
+            agent_label, category, reason, request
+        );
+        return;
+    }
+
+    eprintln!(
+        "submit rejected for agent {} [{}]: {} (request {})",
+        agent_label, category, reason, request
+    );
+    let res = sqlx::query(
+        "INSERT INTO rejections (agent_id, category, reason, source, created_at, user_agent, tls_fingerprint, request_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )
+    .bind(agent)
+    .bind(category)
+    .bind(reason)
+    .bind(&from.source)
+    .bind(now_unix())
+    .bind(&from.user_agent)
+    .bind(&from.tls_fingerprint)
+    .bind(&from.request_id)
+    .execute(&state.pool)
+    .await;
+    if let Err(err) = res {
+        eprintln!("failed to record rejection: {err}");
+    }
+}
+
+/// Full metric name for a submit outcome. Verify-only servers report under a
+/// separate `logchain_verify_only_` family so dry runs never look like storage.
+fn submit_metric(state: &AppState, name: &str) -> String {
+    if state.verify_only {
+        format!("logchain_verify_only_{}", name)
+    } else {
+        format!("logchain_{}", name)
+    }
+}
+
+/// Builds a rejection response and counts it under `category`; in verify-only
+/// mode the status reports `would_reject:<reason>` so callers never confuse it
+/// with a real rejection.
+fn submit_error(
+    state: &AppState,
+    code: StatusCode,
+    category: &str,
+    message: impl Into<String>,
+) -> (StatusCode, Json<SubmitResponse>) {
+    state.metrics.inc(&labeled(
+        &submit_metric(state, "submit_rejected_total"),
+        "reason",
+        category,
+    ));
+    let message = message.into();
+    let status = if state.verify_only {
+        format!("would_reject:{}", message)
+    } else {
+        "error".to_string()
+    };
+    let mut response = SubmitResponse::new(status, message);
+    response.code = Some(rejection_code(category).to_string());
+    (code, Json(response))
 }
 
-fn log_submit_error(agent: &str, reason: &str) {
-    eprintln!("submit rejected for agent {}: {}", agent, reason);
+/// The `code` a rejection carries, so agents can tell a batch no resend
+/// will fix from a passing fault: its category, except that the forbidden
+/// ones share `forbidden`, as they share [`FORBIDDEN_MESSAGE`].
+fn rejection_code(category: &str) -> &str {
+    match category {
+        "unauthorized" | "agent_key" => "forbidden",
+        category => category,
+    }
 }
 
 fn valid_auth(headers: &HeaderMap, expected: &str) -> bool {
-    if let Some(hv) = headers.get("authorization") {
-        if let Ok(v) = hv.to_str() {
-            let pref = "Bearer ";
-            if let Some(rest) = v.strip_prefix(pref) {
-                return rest == expected;
-            }
+    if let Some(hv) = headers.get("authorization")
+        && let Ok(v) = hv.to_str()
+    {
+        let pref = "Bearer ";
+        if let Some(rest) = v.strip_prefix(pref) {
+            // Constant-time so response timing doesn't leak how much of the token matched.
+            return rest.as_bytes().ct_eq(expected.as_bytes()).into();
         }
     }
     false
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct RegisterRequest {
     agent_id: String,
     public_key_hex: String,
 }
 
+/// Most entries one `POST /agents/register/bulk` takes.
+const MAX_BULK_REGISTRATIONS: usize = 1000;
+
 #[derive(Debug, Deserialize)]
-struct RotateRequest {
+struct BulkRegisterEntry {
+    agent_id: String,
+    public_key_hex: String,
+    /// Signature over [`registration_message`] by the key being registered.
+    #[serde(default)]
+    proof_signature_hex: Option<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+struct BulkRegisterResult {
     agent_id: String,
-    new_public_key_hex: String,
-    auth_signature_hex: String,
+    /// `registered`, `already_registered`, `conflict`, `revoked`, `invalid`,
+    /// or `not_attempted` when another entry was invalid.
+    status: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct BulkRegisterResponse {
+    status: String,
+    message: String,
+    registered: usize,
+    results: Vec<BulkRegisterResult>,
 }
 
 #[derive(Serialize)]
@@ -101,12 +614,48 @@ struct AgentResponse {
     message: String,
 }
 
+fn agent_auth_error(err: auth::AuthError) -> (StatusCode, Json<AgentResponse>) {
+    (
+        err.status(),
+        Json(AgentResponse {
+            status: "error".into(),
+            message: err.message().into(),
+        }),
+    )
+}
+
 #[tokio::main]
 async fn main() {
+    if env::args().any(|arg| arg == "--version" || arg == "-V") {
+        println!(
+            "{}",
+            Capabilities::current(env!("CARGO_PKG_VERSION")).describe("server")
+        );
+        return;
+    }
     let require_registration = std::env::var("REQUIRE_AGENT_REGISTRATION")
         .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
         .unwrap_or(false);
 
+    let verify_only = env::var("VERIFY_ONLY")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+
+    let store_raw_body = env::var("STORE_RAW_BODY")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+
+    let store_client_info = env::var("STORE_CLIENT_INFO")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    let tls_fingerprint_header = env::var("TLS_FINGERPRINT_HEADER").ok().map(|name| {
+        HeaderName::try_from(name.trim())
+            .unwrap_or_else(|_| panic!("invalid TLS_FINGERPRINT_HEADER: {name}"))
+    });
+    let log_requests = env::var("LOG_REQUESTS")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+
     let max_req_per_window = env::var("RATE_LIMIT_MAX")
         .ok()
         .and_then(|v| v.parse::<u32>().ok())
@@ -115,100 +664,260 @@ async fn main() {
         .ok()
         .and_then(|v| v.parse::<u64>().ok())
         .unwrap_or(60);
+    let submit_algo = match env::var("RATE_LIMIT_ALGO") {
+        Ok(algo) => RateAlgo::parse(&algo, max_req_per_window)
+            .unwrap_or_else(|err| panic!("invalid RATE_LIMIT_ALGO: {err}")),
+        Err(_) => RateAlgo::FixedWindow,
+    };
+    let submit_algo = match (submit_algo, env::var("RATE_LIMIT_BURST")) {
+        (RateAlgo::TokenBucket { .. }, Ok(burst)) => RateAlgo::TokenBucket {
+            burst: burst
+                .parse::<u32>()
+                .ok()
+                .filter(|&burst| burst > 0)
+                .unwrap_or_else(|| panic!("invalid RATE_LIMIT_BURST: {burst}")),
+        },
+        (algo, _) => algo,
+    };
+
+    // /submit keeps RATE_LIMIT_MAX per window for each agent; registration
+    // and the admin API are tight; reads are open unless RATE_LIMITS says so.
+    let mut rate_limits = RateLimits::configure(
+        [
+            (
+                LimitGroup::Submit,
+                RouteLimit {
+                    max: max_req_per_window,
+                    window_secs,
+                    key: LimitKey::Agent,
+                    algo: submit_algo,
+                },
+            ),
+            (
+                LimitGroup::Admin,
+                RouteLimit {
+                    max: 20,
+                    window_secs: 60,
+                    key: LimitKey::Ip,
+                    algo: RateAlgo::FixedWindow,
+                },
+            ),
+        ],
+        &env::var("RATE_LIMITS").unwrap_or_default(),
+    )
+    .unwrap_or_else(|err| panic!("invalid RATE_LIMITS: {err}"));
+    for group in LimitGroup::ALL {
+        match rate_limits.limit(group) {
+            Some(limit) => println!("Rate limit {}: {limit}", group.as_str()),
+            None => println!("Rate limit {}: off", group.as_str()),
+        }
+    }
+    rate_limits.persist_every(
+        env::var("RATE_LIMIT_PERSIST_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0),
+    );
+    let rate_limits = Arc::new(rate_limits);
 
-    let rate_limiter = Arc::new(RateLimiter::new(
-        max_req_per_window,
+    // Failed auth attempts get their own, much smaller budget per client IP.
+    let auth_failure_max = env::var("AUTH_FAILURE_LIMIT_MAX")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(10);
+    let auth_failures = Arc::new(RateLimiter::new(
+        auth_failure_max,
         StdDuration::from_secs(window_secs),
     ));
 
     let auth_token = env::var("SUBMIT_BEARER_TOKEN").ok();
+    let admin_token = env::var("ADMIN_BEARER_TOKEN").ok();
+    let snapshot_path = env::var("SQLITE_BACKUP_PATH").ok();
 
-    let db_url = env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite://logchain.db".to_string());
-    let pool = SqlitePool::connect(&db_url)
-        .await
-        .unwrap();
+    let ingest_config = IngestConfig {
+        token: env::var("INGEST_BEARER_TOKEN").ok(),
+        batch_lines: env::var("INGEST_BATCH_LINES")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(100),
+        max_body_bytes: env::var("INGEST_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(1024 * 1024),
+        max_buffered_lines: env::var("INGEST_MAX_BUFFERED_LINES")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|&max| max > 0)
+            .unwrap_or(ingest::DEFAULT_MAX_BUFFERED_LINES),
+    };
+    let ingest_flush_secs = env::var("INGEST_FLUSH_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(5);
 
-    configure_sqlite(&pool).await;
+    let compression_level = env::var("COMPRESSION_LEVEL")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .filter(|&level| level <= 9)
+        .map(Compression::new)
+        .unwrap_or_default();
+    let compression_min_bytes = env::var("COMPRESSION_MIN_BYTES")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(256);
+    let decompressor = decompress::Decompressor::from_env();
 
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS batches (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            agent_id TEXT NOT NULL,
-            seq INTEGER NOT NULL,
-            prev_hash BLOB NOT NULL,
-            hash BLOB NOT NULL,
-            logs TEXT NOT NULL,
-            logs_compressed BLOB,
-            timestamp INTEGER NOT NULL,
-            signature BLOB NOT NULL,
-            public_key BLOB NOT NULL,
-            received_at INTEGER NOT NULL DEFAULT 0,
-            source TEXT
-        );
-        "#,
-    )
-    .execute(&pool)
-    .await
-    .unwrap();
+    let clock_drift_alert_ms = env::var("CLOCK_DRIFT_ALERT_MS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(60_000);
 
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS agents (
-            agent_id TEXT PRIMARY KEY,
-            public_key BLOB NOT NULL,
-            created_at INTEGER NOT NULL
-        );
-        "#,
-    )
-    .execute(&pool)
-    .await
-    .unwrap();
+    let stale_agent_secs = env::var("STALE_AGENT_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(300);
 
-    ensure_column(&pool, "batches", "received_at", "INTEGER NOT NULL DEFAULT 0").await;
-    ensure_column(&pool, "batches", "source", "TEXT").await;
-    ensure_column(&pool, "batches", "logs_compressed", "BLOB").await;
-    ensure_append_only_triggers(&pool).await;
+    let anomaly = env::var("ANOMALY_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|t| *t > 0.0)
+        .map(|threshold| {
+            println!("Anomaly scoring on: threshold {threshold}");
+            Arc::new(anomaly::AnomalyTracker::new(threshold))
+        });
 
-    sqlx::query(
-        r#"
-        CREATE UNIQUE INDEX IF NOT EXISTS idx_agent_seq
-        ON batches (agent_id, seq);
-        "#,
-    )
-    .execute(&pool)
-    .await
-    .unwrap();
+    let unique_agent_keys = env::var("UNIQUE_AGENT_KEYS")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
 
-    sqlx::query(
-        r#"
-        CREATE UNIQUE INDEX IF NOT EXISTS idx_agent_hash
-        ON batches (agent_id, hash);
-        "#,
-    )
-    .execute(&pool)
-    .await
-    .unwrap();
+    let idempotency_ttl_secs = env::var("IDEMPOTENCY_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(idempotency::DEFAULT_TTL_SECS);
 
-    sqlx::query(
-        r#"
-        CREATE INDEX IF NOT EXISTS idx_batches_agent_ts
-        ON batches (agent_id, timestamp);
-        "#,
-    )
-    .execute(&pool)
-    .await
-    .unwrap();
+    let accept_gap_markers = env::var("ACCEPT_GAP_MARKERS")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    if accept_gap_markers {
+        println!("Accepting signed gap markers; agents may declare lost seqs");
+    }
 
-    sqlx::query(
-        r#"
-        CREATE INDEX IF NOT EXISTS idx_batches_ts
-        ON batches (timestamp);
-        "#,
-    )
-    .execute(&pool)
-    .await
-    .unwrap();
+    let accept_after_close = env::var("ACCEPT_AFTER_CLOSE")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    if accept_after_close {
+        println!("Accepting batches after a chain close; closed agents may reopen their chains");
+    }
+
+    let agent_size_metrics = env::var("AGENT_SIZE_METRICS")
+        .map(|v| !(v == "0" || v.eq_ignore_ascii_case("false")))
+        .unwrap_or(true);
+
+    let fsck_chunk_rows = env::var("FSCK_CHUNK_ROWS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(fsck::DEFAULT_CHUNK_ROWS);
+
+    let verify_workers = env::var("VERIFY_WORKERS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4)
+        });
+
+    let ephemeral = env::args().any(|arg| arg == "--ephemeral")
+        || env::var("EPHEMERAL")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+    let db_url = if ephemeral {
+        EPHEMERAL_DATABASE_URL.to_string()
+    } else {
+        env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite://logchain.db".to_string())
+    };
+    if is_memory_url(&db_url) {
+        println!(
+            "EPHEMERAL: in-memory database ({db_url}) on a single pooled connection; everything is lost on exit"
+        );
+    }
+    let ack_mode = ack::AckMode::from_env().unwrap_or_else(|err| panic!("{err}"));
+    let pool = connect_pool(&db_url, ack_mode).await.unwrap();
+    if ack_mode == ack::AckMode::Fast {
+        let interval = ack::sync_interval_from_env().unwrap_or_else(|err| panic!("{err}"));
+        ack::spawn_syncer(pool.clone(), interval);
+        println!(
+            "SUBMIT_ACK_MODE=fast: submits are acknowledged before the WAL is fsynced; a power loss can lose up to {interval:?} of acknowledged batches"
+        );
+    }
+
+    init_schema(&pool).await;
+    match checkpoint_cache::reconcile(&pool).await {
+        Ok(repaired) if repaired.is_empty() => {}
+        // Every agent after an upgrade from a database without the table.
+        Ok(repaired) => eprintln!(
+            "[checkpoints] repaired {} checkpoint(s) that disagreed with batches",
+            repaired.len()
+        ),
+        Err(err) => panic!("failed to reconcile checkpoints_cache: {err}"),
+    }
+    // Loaded with or without the task, so no blob outlives its dictionary,
+    // and before `--fsck`, which reads every blob.
+    let zstd_level = compression_level.level() as i32;
+    let newest_dict = dicts::load(&pool, decompressor.decoders(), zstd_level)
+        .await
+        .unwrap_or_else(|err| panic!("cannot load compression_dicts: {err}"));
+    if env::args().any(|arg| arg == "--fsck") {
+        run_fsck(&pool, &decompressor, fsck_chunk_rows).await;
+    }
+    key_conflicts::log_conflicts(&pool).await;
+
+    let metrics = Arc::new(Metrics::new());
+    let storage = Arc::new(StorageHealth::new(env::var("ALERT_WEBHOOK_URL").ok()));
+
+    let watermarks = match env::var("WATERMARK_PATH") {
+        Ok(path) => {
+            let accept_rollback = env::args().any(|arg| arg == "--accept-rollback")
+                || env::var("ACCEPT_ROLLBACK")
+                    .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                    .unwrap_or(false);
+            let watermarks = watermark::Watermarks::open(path.into(), &pool, accept_rollback)
+                .await
+                .unwrap_or_else(|err| panic!("WATERMARK_PATH: {err}"));
+            let watermarks = Arc::new(watermarks);
+            if watermarks.behind().is_empty() {
+                let flush_secs = env::var("WATERMARK_FLUSH_SECS")
+                    .ok()
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .filter(|&n| n > 0)
+                    .unwrap_or(watermark::DEFAULT_FLUSH_SECS);
+                tokio::spawn(watermark::run(
+                    watermarks.clone(),
+                    Duration::from_secs(flush_secs),
+                ));
+                println!(
+                    "High-water marks in {} (flushed every {flush_secs}s)",
+                    watermarks.path().display()
+                );
+            } else {
+                eprintln!(
+                    "[watermark] ROLLBACK SUSPECTED: the database is behind {} for {} agent(s); \
+                     submits are refused until the server restarts with --accept-rollback",
+                    watermarks.path().display(),
+                    watermarks.behind().len()
+                );
+                for behind in watermarks.behind() {
+                    eprintln!(
+                        "[watermark]   {}: watermark {:?}, database {:?}",
+                        behind.agent_id, behind.watermark, behind.database
+                    );
+                }
+            }
+            Some(watermarks)
+        }
+        Err(_) => None,
+    };
 
     if let Ok(backup_path) = std::env::var("SQLITE_BACKUP_PATH") {
         let interval_secs = std::env::var("SQLITE_BACKUP_INTERVAL_SECS")
@@ -217,12 +926,19 @@ async fn main() {
             .unwrap_or(300);
         let pool_clone = pool.clone();
         let backup_path_task = backup_path.clone();
+        let (metrics, storage) = (metrics.clone(), storage.clone());
         tokio::spawn(async move {
             let mut ticker = time::interval(Duration::from_secs(interval_secs));
             loop {
                 ticker.tick().await;
-                if let Err(err) = snapshot_database(&pool_clone, &backup_path_task).await {
-                    eprintln!("Failed to snapshot database: {err}");
+                match snapshot_database(&pool_clone, &backup_path_task).await {
+                    Ok(_) => storage.recovered("snapshot"),
+                    Err(err) => {
+                        eprintln!("Failed to snapshot database: {err}");
+                        if let Some(fault) = StorageFault::of(&err) {
+                            storage.fault(&metrics, fault, "snapshot");
+                        }
+                    }
                 }
             }
         });
@@ -232,884 +948,10156 @@ async fn main() {
         );
     }
 
-    let state = AppState {
-        pool,
-        require_registration,
-        rate_limiter,
-        auth_token,
-    };
-
-    let app = Router::new()
-        .route("/submit", post(handler_submit_batch))
-        .route("/agents/register", post(handler_register_agent))
-        .route("/agents/rotate", post(handler_rotate_agent))
-        .route("/batches", get(handler_get_all))
-        .route("/batches/checkpoints", get(handler_checkpoints))
-        .route("/batches/export", get(handler_export))
-        .route("/batches/:id", get(handler_get_one))
-        .with_state(state);
+    let retention_policies = env::var("RETENTION_POLICIES")
+        .map(|v| retention::RetentionPolicy::parse_list(&v))
+        .unwrap_or(Ok(Vec::new()))
+        .unwrap_or_else(|err| panic!("invalid RETENTION_POLICIES: {err}"));
+    if !retention_policies.is_empty() {
+        let interval_secs = env::var("RETENTION_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(3600);
+        let chunk_rows = env::var("RETENTION_CHUNK_ROWS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(retention::DEFAULT_CHUNK_ROWS);
+        for policy in &retention_policies {
+            println!("Retention every {interval_secs}s: {policy}");
+        }
+        tokio::spawn(retention::run(
+            pool.clone(),
+            metrics.clone(),
+            retention_policies,
+            Duration::from_secs(interval_secs),
+            chunk_rows,
+        ));
+    }
+
+    if !verify_only {
+        let after_days = env::var("SUMMARY_AFTER_DAYS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(summaries::DEFAULT_AFTER_DAYS);
+        let interval_secs = env::var("SUMMARY_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(3600);
+        tokio::spawn(summaries::run(
+            pool.clone(),
+            after_days,
+            Duration::from_secs(interval_secs),
+        ));
+    }
+
+    let tsa_config = tsa::TsaConfig::from_env().unwrap_or_else(|err| panic!("{err}"));
+    if let Some(config) = tsa_config.filter(|_| !verify_only) {
+        println!(
+            "Timestamping up to {} batches per token at {} every {}s",
+            config.max_batches,
+            config.url,
+            config.interval.as_secs()
+        );
+        tokio::spawn(tsa::run(pool.clone(), metrics.clone(), config));
+    }
+
+    let compression_dict = Arc::new(dicts::CurrentDict::default());
+    let dict_config = dicts::DictConfig::from_env().unwrap_or_else(|err| panic!("{err}"));
+    if let Some(config) = dict_config.filter(|_| !verify_only) {
+        println!(
+            "Training a zstd dictionary of up to {} bytes on the last {} batches every {}s",
+            config.dict_bytes,
+            config.sample_batches,
+            config.interval.as_secs()
+        );
+        if let Some(dict) = newest_dict {
+            println!(
+                "Compressing new batches with dictionary v{} (zstd id {})",
+                dict.version, dict.dict_id
+            );
+            compression_dict.set(dict);
+        }
+        tokio::spawn(dicts::run(
+            pool.clone(),
+            decompressor.decoders().clone(),
+            compression_dict.clone(),
+            metrics.clone(),
+            config,
+            zstd_level,
+        ));
+    }
+
+    let blob_store = env::var("BLOB_TIER_STORE")
+        .ok()
+        .map(|spec| tiering::BlobStore::parse(&spec))
+        .transpose()
+        .unwrap_or_else(|err| panic!("invalid BLOB_TIER_STORE: {err}"));
+    if let Some(store) = blob_store.as_ref().filter(|_| !verify_only) {
+        let after_days = env::var("BLOB_TIER_AFTER_DAYS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(tiering::DEFAULT_AFTER_DAYS);
+        let interval_secs = env::var("BLOB_TIER_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(3600);
+        let chunk_rows = env::var("BLOB_TIER_CHUNK_ROWS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(tiering::DEFAULT_CHUNK_ROWS);
+        println!(
+            "Tiering compressed logs older than {after_days} days to {} every {interval_secs}s",
+            store.dir().display()
+        );
+        tokio::spawn(tiering::run(
+            pool.clone(),
+            store.clone(),
+            after_days,
+            Duration::from_secs(interval_secs),
+            chunk_rows,
+        ));
+    }
+
+    if verify_only {
+        println!("VERIFY-ONLY mode: submissions are validated but never stored");
+    }
+
+    let checkpoint_cache_max_age_ms = env::var("CHECKPOINT_CACHE_MAX_AGE_MS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(checkpoint_cache::DEFAULT_MAX_AGE_MS);
+
+    let timeouts = timeout::Timeouts::from_env().unwrap_or_else(|err| panic!("{err}"));
+    println!("Request timeouts: {}", timeouts.describe());
+    let maintenance = maintenance::Maintenance::from_env().unwrap_or_else(|err| panic!("{err}"));
+    if maintenance.is_on() {
+        println!("Starting in maintenance mode: submits, registration and rotation answer 503");
+    }
+
+    let receipts = receipts::ReceiptSigner::load(&pool)
+        .await
+        .unwrap_or_else(|err| panic!("failed to load the server receipt key: {err}"));
+
+    let state = AppState {
+        pool,
+        require_registration,
+        rate_limits,
+        auth_failures,
+        auth_token,
+        verify_only,
+        store_raw_body,
+        store_client_info,
+        tls_fingerprint_header,
+        log_requests,
+        metrics,
+        storage,
+        ingest: Arc::new(IngestState::new(ingest_config)),
+        verify_workers: Arc::new(Semaphore::new(verify_workers)),
+        compression_level,
+        compression_min_bytes,
+        compression_dict,
+        decompressor,
+        agent_size_metrics,
+        admin_token,
+        snapshot_path,
+        blob_store,
+        clock_drift_alert_ms,
+        stale_agent_secs,
+        anomaly,
+        unique_agent_keys,
+        rotation: rotation::RotationPolicy::from_env(),
+        accept_gap_markers,
+        accept_after_close,
+        fsck: Arc::new(fsck::FsckJobs::new(fsck_chunk_rows)),
+        watermarks,
+        checkpoints: Arc::new(checkpoint_cache::CheckpointCache::new(
+            Duration::from_millis(checkpoint_cache_max_age_ms),
+        )),
+        idempotency: Arc::new(idempotency::Idempotency::new(idempotency_ttl_secs)),
+        receipts: Arc::new(receipts),
+        timeouts,
+        maintenance: Arc::new(maintenance),
+        ack_mode,
+    };
+
+    let denied = rate_limit::load_denylist(&state)
+        .await
+        .unwrap_or_else(|err| panic!("failed to load the rate limit denylist: {err}"));
+    if denied > 0 {
+        println!("Rate limit denylist: {denied} entries");
+    }
+    let persist_secs = state.rate_limits.persist_secs();
+    if persist_secs > 0 {
+        let restored = rate_limit::restore(&state, now_unix_ms())
+            .await
+            .unwrap_or_else(|err| panic!("failed to restore rate limit buckets: {err}"));
+        println!("Rate limit buckets: restored {restored}, saved every {persist_secs}s");
+        tokio::spawn(rate_limit::persist(
+            state.clone(),
+            Duration::from_secs(persist_secs),
+        ));
+    }
+
+    if state.ingest.config.token.is_some() {
+        let flush_state = state.clone();
+        tokio::spawn(async move {
+            let mut ticker = time::interval(Duration::from_secs(ingest_flush_secs));
+            loop {
+                ticker.tick().await;
+                ingest::flush_all(&flush_state).await;
+            }
+        });
+        println!(
+            "Ingestion enabled at /ingest/:source_name (flush every {}s or {} lines)",
+            ingest_flush_secs, state.ingest.config.batch_lines
+        );
+    }
+
+    if let Ok(grpc_addr) = env::var("GRPC_ADDR") {
+        let grpc_addr: SocketAddr = grpc_addr
+            .parse()
+            .unwrap_or_else(|_| panic!("invalid GRPC_ADDR: {grpc_addr}"));
+        #[cfg(feature = "grpc")]
+        tokio::spawn(grpc::serve(state.clone(), grpc_addr));
+        #[cfg(not(feature = "grpc"))]
+        eprintln!("GRPC_ADDR={grpc_addr} ignored: server built without the grpc feature");
+    }
+
+    let app = build_router(state);
+
+    let bind_addr = env::var("SERVER_ADDR").unwrap_or_else(|_| "127.0.0.1:3000".to_string());
+    let addr: SocketAddr = bind_addr
+        .parse()
+        .unwrap_or_else(|_| SocketAddr::from(([127, 0, 0, 1], 3000)));
+    println!("Server listening on {}", addr);
+
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+/// Creates tables, columns, indexes, and triggers; safe to run on every start.
+async fn init_schema(pool: &SqlitePool) {
+    configure_sqlite(pool).await;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS batches (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            agent_id TEXT NOT NULL,
+            seq INTEGER NOT NULL,
+            prev_hash BLOB NOT NULL,
+            hash BLOB NOT NULL,
+            logs TEXT NOT NULL,
+            logs_compressed BLOB,
+            timestamp INTEGER NOT NULL,
+            signature BLOB NOT NULL,
+            public_key BLOB NOT NULL,
+            received_at INTEGER NOT NULL DEFAULT 0,
+            source TEXT
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS agents (
+            agent_id TEXT PRIMARY KEY,
+            public_key BLOB NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    // Keeps the `UNIQUE_AGENT_KEYS` lookup and the conflict audit cheap.
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_agents_public_key ON agents (public_key)")
+        .execute(pool)
+        .await
+        .unwrap();
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS ingest_keys (
+            source_name TEXT PRIMARY KEY,
+            signing_key BLOB NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    // API tokens minted via `/admin/tokens`; only the hash is kept.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS api_tokens (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            tenant TEXT NOT NULL,
+            token_sha256 BLOB NOT NULL UNIQUE,
+            created_at INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    // Which key may sign which positions: from (valid_from_epoch,
+    // valid_from_seq) up to, not including, (valid_until_epoch,
+    // valid_until_seq), open-ended for the current key.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS agent_keys (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            agent_id TEXT NOT NULL,
+            public_key BLOB NOT NULL,
+            valid_from_seq INTEGER NOT NULL,
+            valid_until_seq INTEGER,
+            created_at INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS rejections (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            agent_id TEXT,
+            category TEXT NOT NULL,
+            reason TEXT NOT NULL,
+            source TEXT,
+            created_at INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    // Audit trail of maintenance deletions (retention); append-only itself.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS maintenance_events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            kind TEXT NOT NULL,
+            table_name TEXT NOT NULL,
+            rows INTEGER NOT NULL,
+            detail TEXT,
+            created_at INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    // Each agent's head, kept with every submit so checkpoint reads need not
+    // group `batches`; see `checkpoint_cache`.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS checkpoints_cache (
+            agent_id TEXT PRIMARY KEY,
+            last_epoch INTEGER NOT NULL,
+            last_seq INTEGER NOT NULL,
+            last_hash BLOB NOT NULL,
+            last_accumulator BLOB,
+            count INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    // Server signing keys for receipts, kept after retirement so every
+    // receipt stays verifiable.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS server_keys (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            public_key BLOB NOT NULL,
+            signing_key BLOB NOT NULL,
+            created_at_ms INTEGER NOT NULL,
+            retired_at_ms INTEGER
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    // One receipt per stored batch, written in the batch's transaction.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS receipts (
+            batch_id INTEGER PRIMARY KEY,
+            hash BLOB NOT NULL,
+            key_id INTEGER NOT NULL,
+            signature BLOB NOT NULL,
+            issued_at_ms INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    // Receipts the store no longer matches, acknowledged as lost data; see
+    // `forks`. Each keeps the receipt whole so it can be re-verified.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS forks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            agent_id TEXT NOT NULL,
+            seq INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            receipt_hash BLOB NOT NULL,
+            stored_hash BLOB,
+            receipt_batch_id INTEGER NOT NULL,
+            key_id INTEGER NOT NULL,
+            signature BLOB NOT NULL,
+            issued_at_ms INTEGER NOT NULL,
+            note TEXT,
+            recorded_at_ms INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_forks_receipt ON forks (agent_id, seq, receipt_hash)",
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    // What stays of a day's batches once their content is archived; see
+    // `summaries`.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS daily_summaries (
+            agent_id TEXT NOT NULL,
+            day TEXT NOT NULL,
+            batches INTEGER NOT NULL,
+            lines INTEGER NOT NULL,
+            min_seq INTEGER NOT NULL,
+            max_seq INTEGER NOT NULL,
+            head_hash BLOB NOT NULL,
+            merkle_root BLOB NOT NULL,
+            generated_at_ms INTEGER NOT NULL,
+            PRIMARY KEY (agent_id, day)
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    // Stubs of compressed copies moved to the blob store; see `tiering`.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS blob_locations (
+            batch_id INTEGER PRIMARY KEY,
+            location TEXT NOT NULL,
+            blob_sha256 BLOB NOT NULL,
+            size INTEGER NOT NULL,
+            tiered_at_ms INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    // Lines whose content was removed, signed by the server key; see `redactions`.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS redactions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            batch_id INTEGER NOT NULL,
+            line_idx INTEGER NOT NULL,
+            commitment BLOB NOT NULL,
+            authorized_by TEXT NOT NULL,
+            redacted_at_ms INTEGER NOT NULL,
+            key_id INTEGER NOT NULL,
+            signature BLOB NOT NULL,
+            UNIQUE (batch_id, line_idx)
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    // RFC 3161 tokens, each over a Merkle tree of batch hashes, and every
+    // batch's leaf in one; see `tsa`.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS tsa_tokens (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            tsa_url TEXT NOT NULL,
+            root BLOB NOT NULL,
+            leaf_count INTEGER NOT NULL,
+            token BLOB NOT NULL,
+            gen_time TEXT NOT NULL,
+            stamped_at_ms INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS tsa_leaves (
+            batch_id INTEGER PRIMARY KEY,
+            token_id INTEGER NOT NULL,
+            leaf_index INTEGER NOT NULL,
+            hash BLOB NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_tsa_leaves_token ON tsa_leaves (token_id, leaf_index)",
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    // Nonces of accepted rotations, kept until their requests expire.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS rotation_nonces (
+            agent_id TEXT NOT NULL,
+            nonce TEXT NOT NULL,
+            expires_at INTEGER NOT NULL,
+            PRIMARY KEY (agent_id, nonce)
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    // Responses kept for `Idempotency-Key` repeats; see `idempotency`.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS idempotency_keys (
+            endpoint TEXT NOT NULL,
+            key TEXT NOT NULL,
+            request_sha256 BLOB NOT NULL,
+            status INTEGER NOT NULL,
+            body BLOB NOT NULL,
+            created_at_ms INTEGER NOT NULL,
+            PRIMARY KEY (endpoint, key)
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_idempotency_keys_created ON idempotency_keys (created_at_ms)")
+        .execute(pool)
+        .await
+        .unwrap();
+    // Rate-limit buckets saved with `RATE_LIMIT_PERSIST_SECS`, and the
+    // denylist; see `rate_limit`.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS rate_limit_buckets (
+            limiter TEXT NOT NULL,
+            key TEXT NOT NULL,
+            algo TEXT NOT NULL,
+            started_at_ms INTEGER NOT NULL,
+            count REAL NOT NULL,
+            PRIMARY KEY (limiter, key)
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS rate_limit_denylist (
+            entry TEXT PRIMARY KEY,
+            reason TEXT,
+            created_at_ms INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    // zstd dictionaries the stored logs may be compressed with; the id is
+    // the dictionary's version. See `dicts`.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS compression_dicts (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            dict_id INTEGER NOT NULL UNIQUE,
+            dict BLOB NOT NULL,
+            sample_batches INTEGER NOT NULL,
+            sample_bytes INTEGER NOT NULL,
+            created_at_ms INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    ensure_column(pool, "batches", "received_at", "INTEGER NOT NULL DEFAULT 0").await;
+    ensure_column(pool, "batches", "source", "TEXT").await;
+    ensure_column(pool, "batches", "logs_compressed", "BLOB").await;
+    ensure_column(pool, "batches", "raw_body", "BLOB").await;
+    ensure_column(pool, "batches", "raw_content_type", "TEXT").await;
+    ensure_column(pool, "batches", "lines_read", "INTEGER").await;
+    ensure_column(pool, "batches", "received_at_ms", "INTEGER").await;
+    ensure_column(pool, "batches", "batch_version", "INTEGER").await;
+    ensure_column(
+        pool,
+        "agents",
+        "rotation_counter",
+        "INTEGER NOT NULL DEFAULT 0",
+    )
+    .await;
+    ensure_column(pool, "agents", "revoked_at", "INTEGER").await;
+    ensure_column(
+        pool,
+        "agent_keys",
+        "valid_from_epoch",
+        "INTEGER NOT NULL DEFAULT 0",
+    )
+    .await;
+    ensure_column(
+        pool,
+        "agent_keys",
+        "valid_until_epoch",
+        "INTEGER NOT NULL DEFAULT 0",
+    )
+    .await;
+    ensure_column(pool, "batches", "logs_size", "INTEGER").await;
+    ensure_column(pool, "batches", "logs_compressed_size", "INTEGER").await;
+    ensure_column(pool, "batches", "accumulator", "BLOB").await;
+    ensure_column(pool, "batches", "anomaly_score", "REAL").await;
+    ensure_column(pool, "batches", "gap_from", "INTEGER").await;
+    ensure_column(pool, "batches", "gap_to", "INTEGER").await;
+    ensure_column(pool, "batches", "gap_reason", "TEXT").await;
+    ensure_column(pool, "batches", "epoch", "INTEGER NOT NULL DEFAULT 0").await;
+    ensure_column(pool, "batches", "epoch_prev_seq", "INTEGER").await;
+    ensure_column(pool, "batches", "session_id", "TEXT").await;
+    ensure_column(pool, "batches", "session_boot_ms", "INTEGER").await;
+    ensure_column(pool, "batches", "closed_reason", "TEXT").await;
+    ensure_column(pool, "forks", "epoch", "INTEGER NOT NULL DEFAULT 0").await;
+    ensure_column(pool, "batches", "user_agent", "TEXT").await;
+    ensure_column(pool, "batches", "tls_fingerprint", "TEXT").await;
+    ensure_column(pool, "rejections", "user_agent", "TEXT").await;
+    ensure_column(pool, "rejections", "tls_fingerprint", "TEXT").await;
+    ensure_column(pool, "rejections", "request_id", "TEXT").await;
+    ensure_column(pool, "receipts", "request_id", "TEXT").await;
+    // Tokens minted before scopes existed were `/submit` tokens.
+    ensure_column(
+        pool,
+        "api_tokens",
+        "scopes",
+        "TEXT NOT NULL DEFAULT 'submit'",
+    )
+    .await;
+    ensure_column(pool, "api_tokens", "agent_id", "TEXT").await;
+    ensure_column(pool, "api_tokens", "expires_at", "INTEGER").await;
+    ensure_column(pool, "api_tokens", "revoked_at", "INTEGER").await;
+    backfill_payload_sizes(pool).await;
+    ensure_append_only_triggers(pool).await;
+    backfill_key_history(pool).await;
+
+    // Seqs restart in every epoch, so a position is unique per epoch. The
+    // index replaces `idx_agent_seq`, which stores before epochs had.
+    sqlx::query(
+        r#"
+        CREATE UNIQUE INDEX IF NOT EXISTS idx_agent_epoch_seq
+        ON batches (agent_id, epoch, seq);
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    sqlx::query("DROP INDEX IF EXISTS idx_agent_seq")
+        .execute(pool)
+        .await
+        .unwrap();
+
+    sqlx::query(
+        r#"
+        CREATE UNIQUE INDEX IF NOT EXISTS idx_agent_hash
+        ON batches (agent_id, hash);
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_batches_agent_ts
+        ON batches (agent_id, timestamp);
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    // `hash_prefix` lookups.
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_batches_hash ON batches (hash)")
+        .execute(pool)
+        .await
+        .unwrap();
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_batches_ts
+        ON batches (timestamp);
+        "#,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    sqlx::query(&format!(
+        "CREATE INDEX IF NOT EXISTS idx_batches_received_ms ON batches ({RECEIVED_AT_MS_EXPR})"
+    ))
+    .execute(pool)
+    .await
+    .unwrap();
+
+    sqlx::query(&format!(
+        "CREATE INDEX IF NOT EXISTS idx_batches_ts_ms ON batches ({TIMESTAMP_MS_EXPR})"
+    ))
+    .execute(pool)
+    .await
+    .unwrap();
+
+    sqlx::query(&format!(
+        "CREATE INDEX IF NOT EXISTS idx_batches_agent_ts_ms ON batches (agent_id, {TIMESTAMP_MS_EXPR})"
+    ))
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+/// Routes not wrapped in `scoped` check their scope in the handler: submit,
+/// register/rotate (agent binding) and admin (their own error bodies).
+/// `/ingest` has its own `INGEST_BEARER_TOKEN`.
+fn build_router(state: AppState) -> Router {
+    let paused = axum::middleware::from_fn_with_state(state.clone(), maintenance::middleware);
+    let submits = Router::new()
+        .route("/submit", post(handler_submit_batch))
+        .route("/ingest/:source_name", post(ingest::handler_ingest))
+        .layer(paused.clone());
+    let registration = Router::new()
+        .route("/agents/register", post(handler_register_agent))
+        .route("/agents/register/bulk", post(handler_register_agents_bulk))
+        .route("/agents/rotate", post(rotation::handler_rotate_agent))
+        .layer(paused);
+    let admin = Router::new()
+        .merge(registration)
+        .route("/admin/snapshot", post(admin::handler_snapshot))
+        .route(
+            "/admin/integrity-check",
+            post(admin::handler_integrity_check),
+        )
+        .route("/admin/rejections", get(admin::handler_rejections))
+        .route(
+            "/admin/agents/:agent_id/revoke",
+            post(admin::handler_revoke_agent),
+        )
+        .route("/admin/tokens", post(admin::handler_create_token))
+        .route(
+            "/admin/tokens/:id/revoke",
+            post(admin::handler_revoke_token),
+        )
+        .route("/admin/key-conflicts", get(admin::handler_key_conflicts))
+        .route(
+            "/admin/fsck",
+            get(admin::handler_fsck_jobs).post(admin::handler_start_fsck),
+        )
+        .route("/admin/fsck/:id", get(admin::handler_fsck_status))
+        .route("/admin/fsck/:id/abort", post(admin::handler_abort_fsck))
+        .route(
+            "/admin/server-keys/rotate",
+            post(admin::handler_rotate_server_key),
+        )
+        .route(
+            "/admin/maintenance",
+            get(admin::handler_maintenance).post(admin::handler_set_maintenance),
+        )
+        .route(
+            "/admin/forks",
+            get(admin::handler_forks).post(admin::handler_record_forks),
+        )
+        .route("/admin/redactions", post(admin::handler_redact))
+        .route("/admin/ratelimit", get(admin::handler_rate_limits))
+        .route("/admin/ratelimit/deny", post(admin::handler_deny))
+        .route("/admin/ratelimit/deny/remove", post(admin::handler_undeny));
+    let reads = Router::new()
+        // Open, for load balancers and orchestrators.
+        .route("/readyz", get(storage::handler_readyz))
+        .route("/version", get(handler_version))
+        .route(
+            "/agents/status",
+            scoped(Scope::Read, get(drift::handler_agent_status)),
+        )
+        .route(
+            "/agents/stale",
+            scoped(Scope::Read, get(stale::handler_stale_agents)),
+        )
+        .route(
+            "/agents/anomaly",
+            scoped(Scope::Read, get(anomaly::handler_anomaly_stats)),
+        )
+        .route(
+            "/agents/:agent_id/keys",
+            scoped(Scope::Read, get(handler_agent_keys)),
+        )
+        .route(
+            "/agents/:agent_id/sessions",
+            scoped(Scope::Read, get(sessions::handler_sessions)),
+        )
+        .route("/batches", scoped(Scope::Read, get(handler_get_all)))
+        .route(
+            "/logs/:agent_id/:seq/:line_idx",
+            scoped(Scope::Read, get(lines::handler_line)),
+        )
+        .route(
+            "/batches/checkpoints",
+            scoped(Scope::Read, get(handler_checkpoints)),
+        )
+        .route(
+            "/batches/histogram",
+            scoped(Scope::Read, get(histogram::handler_histogram)),
+        )
+        .route("/batches/meta", scoped(Scope::Read, get(handler_get_meta)))
+        .route(
+            "/batches/latest",
+            scoped(Scope::Read, get(handler_get_latest)),
+        )
+        .route("/batches/:id", scoped(Scope::Read, get(handler_get_one)))
+        .route(
+            "/batches/:id/raw",
+            scoped(Scope::Read, get(handler_get_raw)),
+        )
+        .route(
+            "/batches/:id/logs",
+            scoped(Scope::Read, get(handler_get_logs)),
+        )
+        .route(
+            "/batches/:id/receipt",
+            scoped(Scope::Read, get(receipts::handler_get_receipt)),
+        )
+        .route(
+            "/batches/:id/tsa",
+            scoped(Scope::Read, get(tsa::handler_get_tsa)),
+        )
+        .route(
+            "/batches/:id/redactions",
+            scoped(Scope::Read, get(redactions::handler_redactions)),
+        )
+        .route(
+            "/server-keys",
+            scoped(Scope::Read, get(receipts::handler_server_keys)),
+        )
+        .route(
+            "/summaries",
+            scoped(Scope::Read, get(summaries::handler_summaries)),
+        )
+        .route("/metrics", scoped(Scope::Read, get(handler_metrics)));
+    // Open: the page carries no data, and its fetches are checked as usual.
+    #[cfg(feature = "dashboard")]
+    let reads = reads.route("/dashboard", get(dashboard::handler_dashboard));
+    let export = Router::new().route(
+        "/batches/export",
+        scoped(Scope::Export, get(handler_export)),
+    );
+
+    // Innermost, so throttling and auth do not count against a route's time.
+    let timeouts = state.timeouts;
+    Router::new()
+        .merge(timeout::limit(submits, timeouts.submit))
+        .merge(timeout::limit(admin, timeouts.admin))
+        .merge(timeout::limit(reads, timeouts.read))
+        .merge(timeout::limit_chunks(
+            timeout::limit(export, timeouts.export),
+            timeouts.export_chunk,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            auth::middleware,
+        ))
+        // Outermost, so a throttled request costs no token lookup.
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::middleware,
+        ))
+        // Outermost of all, so throttled and refused requests carry an id too.
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            request_id::middleware,
+        ))
+        .with_state(state)
+}
+
+/// `GET /version`: the batch versions, hash schemes and upload encodings
+/// this server takes, for clients to check before sending; see
+/// [`common::compat`]. Open, like `/readyz`.
+async fn handler_version() -> Json<Capabilities> {
+    Json(Capabilities::current(env!("CARGO_PKG_VERSION")))
+}
+
+/* ----------------------- SUBMIT BATCH ----------------------- */
+
+async fn handler_submit_batch(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(auth): Extension<AuthContext>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let from = Provenance::of_request(&state, addr, &headers);
+    if let Err(response) = admit_submitter(&state, addr, &from, &auth).await {
+        return response;
+    }
+
+    // A gzipped upload is archived decoded: the encoding is transport only.
+    let body = match decode_submit_body(&headers, body) {
+        Ok(body) => body,
+        Err((status, message)) => return submit_error(&state, status, "malformed", message),
+    };
+
+    // Parse from the raw bytes ourselves so the exact submitted body can be
+    // archived: JSON, or MessagePack when the Content-Type says so.
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    let format = WireFormat::of_content_type(content_type);
+    let batch: LogBatch = match format.decode(&body) {
+        Ok(batch) => batch,
+        Err(err) => return submit_error(&state, StatusCode::BAD_REQUEST, "malformed", err),
+    };
+
+    let raw_content_type = content_type.unwrap_or(format.content_type()).to_string();
+
+    submit_parsed(
+        &state,
+        addr,
+        &from,
+        &auth,
+        batch,
+        Some((&body, raw_content_type)),
+    )
+    .await
+}
+
+/// Largest `/submit` body once decoded; axum's default limit for a plain one.
+const MAX_SUBMIT_BODY_BYTES: u64 = 2 * 1024 * 1024;
+
+/// The `/submit` body with its `Content-Encoding` undone: none, `identity`
+/// or `gzip`. Agents gzip only uploads that shrink enough to be worth it.
+fn decode_submit_body(headers: &HeaderMap, body: Bytes) -> Result<Bytes, (StatusCode, String)> {
+    let encoding = headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_ascii_lowercase());
+    match encoding.as_deref() {
+        None | Some("identity") => Ok(body),
+        Some("gzip") => {
+            let mut out = Vec::new();
+            GzDecoder::new(&body[..])
+                .take(MAX_SUBMIT_BODY_BYTES + 1)
+                .read_to_end(&mut out)
+                .map_err(|err| (StatusCode::BAD_REQUEST, format!("invalid gzip body: {err}")))?;
+            if out.len() as u64 > MAX_SUBMIT_BODY_BYTES {
+                return Err((
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("decoded body exceeds {MAX_SUBMIT_BODY_BYTES} bytes"),
+                ));
+            }
+            Ok(Bytes::from(out))
+        }
+        Some(other) => Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("unsupported Content-Encoding {other:?}"),
+        )),
+    }
+}
+
+/// The auth-failure throttle and the submit scope, checked before the batch
+/// is parsed. Shared by `/submit` and the gRPC `Submit`; the route's rate
+/// limit was applied before either.
+async fn admit_submitter(
+    state: &AppState,
+    addr: SocketAddr,
+    from: &Provenance,
+    auth: &AuthContext,
+) -> Result<(), (StatusCode, Json<SubmitResponse>)> {
+    let client_ip = addr.ip().to_string();
+    if state.auth_failures.is_exhausted(&client_ip).await {
+        return Err(submit_error(
+            state,
+            StatusCode::TOO_MANY_REQUESTS,
+            "auth_rate_limited",
+            "too many failed authentication attempts",
+        ));
+    }
+
+    if auth.require(Scope::Submit).is_err() {
+        state.auth_failures.allow(&client_ip).await;
+        record_rejection(
+            state,
+            None,
+            "unauthorized",
+            "missing or invalid bearer token",
+            from,
+        )
+        .await;
+        return Err(submit_error(
+            state,
+            StatusCode::FORBIDDEN,
+            "unauthorized",
+            FORBIDDEN_MESSAGE,
+        ));
+    }
+    Ok(())
+}
+
+/// The rest of a submit once the batch is parsed: reserved agent IDs, the
+/// token's agent binding, then [`store_submitted_batch`]. Every 403 counts
+/// against the client's auth-failure budget.
+async fn submit_parsed(
+    state: &AppState,
+    addr: SocketAddr,
+    from: &Provenance,
+    auth: &AuthContext,
+    batch: LogBatch,
+    raw: Option<(&[u8], String)>,
+) -> (StatusCode, Json<SubmitResponse>) {
+    if batch.agent_id.starts_with(ingest::AGENT_PREFIX) {
+        record_rejection(
+            state,
+            Some(&batch.agent_id),
+            "reserved_agent_id",
+            "reserved agent_id prefix",
+            from,
+        )
+        .await;
+        return submit_error(
+            state,
+            StatusCode::BAD_REQUEST,
+            "reserved_agent_id",
+            format!(
+                "agent_id prefix '{}' is reserved for server-side ingestion",
+                ingest::AGENT_PREFIX
+            ),
+        );
+    }
+
+    let client_ip = addr.ip().to_string();
+    if auth.require_agent(Scope::Submit, &batch.agent_id).is_err() {
+        state.auth_failures.allow(&client_ip).await;
+        record_rejection(
+            state,
+            Some(&batch.agent_id),
+            "unauthorized",
+            "token is bound to another agent",
+            from,
+        )
+        .await;
+        return submit_error(
+            state,
+            StatusCode::FORBIDDEN,
+            "unauthorized",
+            FORBIDDEN_MESSAGE,
+        );
+    }
+
+    let response = store_submitted_batch(state, from, batch, raw).await;
+    if response.0 == StatusCode::FORBIDDEN {
+        state.auth_failures.allow(&client_ip).await;
+    }
+    response
+}
+
+/// Runs signature verification and hashing on the blocking pool, at most
+/// `VERIFY_WORKERS` at a time, so a burst of submits cannot stall the reactor.
+/// Returns the batch with its hash, or `None` for the hash if the signature is bad.
+async fn verify_off_reactor(
+    state: &AppState,
+    batch: LogBatch,
+) -> Result<(LogBatch, Option<[u8; 32]>), String> {
+    let _permit = state
+        .verify_workers
+        .clone()
+        .acquire_owned()
+        .await
+        .map_err(|e| format!("verify pool closed: {e}"))?;
+    tokio::task::spawn_blocking(move || {
+        let hash = batch.verify().then(|| batch.compute_hash());
+        (batch, hash)
+    })
+    .await
+    .map_err(|e| format!("verify task failed: {e}"))
+}
+
+/// Shared validation + storage pipeline behind `/submit` and server-side ingestion.
+/// `raw` carries the exact request body and content type when there is one.
+async fn store_submitted_batch(
+    state: &AppState,
+    from: &Provenance,
+    batch: LogBatch,
+    raw: Option<(&[u8], String)>,
+) -> (StatusCode, Json<SubmitResponse>) {
+    if let Some(watermarks) = state.watermarks.as_ref().filter(|w| !w.behind().is_empty()) {
+        // Not written to `rejections`: the database is the suspect copy.
+        let msg = format!(
+            "rollback suspected: the database is behind its high-water marks for {} agent(s)",
+            watermarks.behind().len()
+        );
+        return submit_error(
+            state,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "rollback_suspected",
+            msg,
+        );
+    }
+    if !(BATCH_VERSION_V1..=CURRENT_BATCH_VERSION).contains(&batch.version) {
+        let msg = format!(
+            "unsupported batch version {}; server accepts {}..={}",
+            batch.version, BATCH_VERSION_V1, CURRENT_BATCH_VERSION
+        );
+        record_rejection(
+            state,
+            Some(&batch.agent_id),
+            "unsupported_version",
+            &msg,
+            from,
+        )
+        .await;
+        return submit_error(state, StatusCode::BAD_REQUEST, "unsupported_version", msg);
+    }
+
+    let (batch, verified_hash) = match verify_off_reactor(state, batch).await {
+        Ok(result) => result,
+        Err(msg) => {
+            return submit_error(state, StatusCode::INTERNAL_SERVER_ERROR, "internal", msg);
+        }
+    };
+    let Some(computed_hash) = verified_hash else {
+        record_rejection(
+            state,
+            Some(&batch.agent_id),
+            "invalid_signature",
+            "invalid signature",
+            from,
+        )
+        .await;
+        return submit_error(
+            state,
+            StatusCode::BAD_REQUEST,
+            "invalid_signature",
+            "invalid signature",
+        );
+    };
+    let logs_json = canonical_logs_json(&batch.logs);
+    let logs_compressed = match compress_json(
+        &logs_json,
+        state.compression_level,
+        state.compression_min_bytes,
+        state.compression_dict.get().as_deref(),
+    ) {
+        Ok(data) => {
+            record_compression(state, logs_json.len(), data.as_deref());
+            data
+        }
+        Err(err) => {
+            return submit_error(
+                state,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                format!("failed to compress logs: {err}"),
+            );
+        }
+    };
+
+    let logs_size = logs_json.len() as i64;
+    let logs_compressed_size = logs_compressed.as_ref().map(|blob| blob.len() as i64);
+
+    let (raw_body, raw_content_type) = match raw {
+        Some((body, content_type)) if state.store_raw_body => {
+            match compress_bytes(body, state.compression_level) {
+                Ok(data) => {
+                    state.metrics.add(
+                        &submit_metric(state, "raw_body_stored_bytes_total"),
+                        data.len() as u64,
+                    );
+                    (Some(data), Some(content_type))
+                }
+                Err(err) => {
+                    return submit_error(
+                        state,
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "internal",
+                        format!("failed to compress raw body: {err}"),
+                    );
+                }
+            }
+        }
+        _ => (None, None),
+    };
+
+    // The transaction borrows this connection, so every path that goes on
+    // to use the pool drops it first.
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return internal_or_storage_error(state, err, "failed to acquire a connection"),
+    };
+    if let Err(err) = state.ack_mode.lower(&mut conn).await {
+        return internal_or_storage_error(state, err, "failed to set the ack mode");
+    }
+    let mut tx = match conn.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return internal_or_storage_error(state, err, "failed to start transaction"),
+    };
+    if !state.verify_only
+        && let Err(err) = take_write_lock(&mut tx).await
+    {
+        return internal_or_storage_error(state, err, "failed to lock for write");
+    }
+
+    // Ensure agent key is trusted/registered before accepting.
+    if let Err(rejection) = ensure_agent_key(state, &mut tx, &batch).await {
+        drop(tx);
+        drop(conn);
+        return match rejection {
+            AgentKeyRejection::Forbidden(reason) => {
+                record_rejection(state, Some(&batch.agent_id), "agent_key", &reason, from).await;
+                submit_error(state, StatusCode::FORBIDDEN, "agent_key", FORBIDDEN_MESSAGE)
+            }
+            AgentKeyRejection::Internal(reason) => {
+                record_rejection(state, Some(&batch.agent_id), "internal", &reason, from).await;
+                submit_error(state, StatusCode::INTERNAL_SERVER_ERROR, "internal", reason)
+            }
+            AgentKeyRejection::Storage(fault) => storage_error(state, fault),
+        };
+    }
+
+    // Identical resends (same agent + content hash) are benign retries, so they
+    // are checked before chain validation and reported as success.
+    let duplicate = sqlx::query_scalar::<_, i64>(
+        "SELECT id FROM batches WHERE agent_id = ?1 AND hash = ?2 LIMIT 1",
+    )
+    .bind(&batch.agent_id)
+    .bind(computed_hash.to_vec())
+    .fetch_optional(tx.as_mut())
+    .await;
+
+    let duplicate = match duplicate {
+        Ok(v) => v,
+        Err(_) => {
+            drop(tx);
+            drop(conn);
+            record_rejection(
+                state,
+                Some(&batch.agent_id),
+                "internal",
+                "duplicate check failed",
+                from,
+            )
+            .await;
+            return submit_error(
+                state,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                "failed to check duplicates",
+            );
+        }
+    };
+
+    if let Some(id) = duplicate {
+        return duplicate_resend(state, tx.as_mut(), &batch, id, from).await;
+    }
+
+    // Validate hash chain + ordering for this agent.
+    if let Err(rejection) = validate_chain(
+        &mut tx,
+        &batch,
+        &computed_hash,
+        state.accept_gap_markers,
+        state.accept_after_close,
+    )
+    .await
+    {
+        if let ChainRejection::SeqTaken(stored_hash) = rejection {
+            drop(tx);
+            drop(conn);
+            return seq_clash(state, &batch, &computed_hash, &stored_hash, from).await;
+        }
+        let category = rejection.category();
+        let (code, msg) = rejection.into_response_parts();
+        drop(tx);
+        drop(conn);
+        record_rejection(state, Some(&batch.agent_id), category, &msg, from).await;
+        return submit_error(state, code, category, msg);
+    }
+
+    if state.verify_only {
+        // Every check passed; report what would happen without persisting anything.
+        let _ = tx.rollback().await;
+        state
+            .metrics
+            .inc(&submit_metric(state, "submit_accepted_total"));
+        println!(
+            "[verify-only] batch seq {} for agent {} would be stored",
+            batch.seq, batch.agent_id
+        );
+        return (
+            StatusCode::OK,
+            Json(SubmitResponse::accepted(
+                "would_store",
+                "batch passed validation; not stored (verify-only mode)",
+            )),
+        );
+    }
+
+    let received_ms = now_unix_ms();
+    let anomaly_score = state
+        .anomaly
+        .as_ref()
+        .and_then(|tracker| tracker.score(&batch.agent_id, logs_size as u64, received_ms));
+
+    let session_columns = sessions::columns(&batch);
+    let insert_res = sqlx::query(
+        r#"
+        INSERT INTO batches (agent_id, seq, prev_hash, hash, logs, logs_compressed, timestamp, signature, public_key, received_at, source, raw_body, raw_content_type, lines_read, batch_version, logs_size, logs_compressed_size, accumulator, anomaly_score, gap_from, gap_to, gap_reason, user_agent, tls_fingerprint, epoch, epoch_prev_seq, session_id, session_boot_ms, closed_reason, received_at_ms)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30,
+            -- Strictly increasing so `since_received_at` pulls never skip or repeat rows
+            -- that land in the same millisecond; evaluated under the write lock.
+            MAX(?15, COALESCE((SELECT MAX(received_at_ms) FROM batches), 0) + 1))
+        "#,
+    )
+    .bind(&batch.agent_id)
+    .bind(batch.seq as i64)
+    .bind(batch.prev_hash.to_vec())
+    .bind(computed_hash.to_vec())
+    .bind(logs_json) // keep plaintext for search/filter, compressed for space
+    .bind(logs_compressed)
+    .bind(batch.timestamp as i64)
+    .bind(batch.signature.to_bytes().to_vec())
+    .bind(batch.public_key.to_bytes().to_vec())
+    .bind(now_unix())
+    .bind(&from.source)
+    .bind(&raw_body)
+    .bind(raw_content_type)
+    .bind(batch.lines_read.map(|v| v as i64))
+    .bind(received_ms)
+    .bind(batch.version as i64)
+    .bind(logs_size)
+    .bind(logs_compressed_size)
+    .bind(batch.accumulator.map(|acc| acc.to_vec()))
+    .bind(anomaly_score)
+    .bind(batch.gap.as_ref().map(|gap| gap.missing_from as i64))
+    .bind(batch.gap.as_ref().map(|gap| gap.missing_to as i64))
+    .bind(batch.gap.as_ref().map(|gap| gap.reason.as_str()))
+    .bind(&from.user_agent)
+    .bind(&from.tls_fingerprint)
+    .bind(batch.epoch as i64)
+    .bind(batch.epoch_start.as_ref().map(|start| start.previous_last_seq as i64))
+    .bind(session_columns.0)
+    .bind(session_columns.1)
+    .bind(batch.closed_reason())
+    .execute(tx.as_mut())
+    .await;
+
+    let batch_id = match insert_res {
+        Ok(done) => done.last_insert_rowid(),
+        Err(e) => {
+            if let sqlx::Error::Database(db) = &e
+                && db.is_unique_violation()
+            {
+                // Another submit claimed the position after our checks; settle
+                // against the row that won.
+                drop(tx);
+                drop(conn);
+                return lost_insert_race(state, &batch, &computed_hash, from).await;
+            }
+            return internal_or_storage_error(state, e, "failed to store batch");
+        }
+    };
+
+    // Issued in the same transaction: no batch is stored without a receipt.
+    let receipt = match state
+        .receipts
+        .issue(
+            tx.as_mut(),
+            batch_id,
+            &batch.agent_id,
+            batch.position(),
+            computed_hash,
+            from.request_id.as_deref(),
+        )
+        .await
+    {
+        Ok(receipt) => receipt,
+        Err(e) => return internal_or_storage_error(state, e, "failed to issue receipt"),
+    };
+    if let Err(e) = checkpoint_cache::record(tx.as_mut(), &batch, computed_hash).await {
+        return internal_or_storage_error(state, e, "failed to update checkpoint");
+    }
+
+    if let Err(e) = tx.commit().await {
+        return internal_or_storage_error(state, e, "failed to commit batch");
+    }
+    drop(conn);
+    state.storage.recovered("submit");
+    state.checkpoints.stored(&batch, computed_hash);
+    if let Some(watermarks) = &state.watermarks {
+        // The stored `received_at_ms` is at least this, so the mark never overshoots.
+        watermarks.advance(
+            &batch.agent_id,
+            watermark::Mark {
+                id: batch_id,
+                received_at_ms: received_ms,
+            },
+        );
+    }
+    state
+        .metrics
+        .inc(&submit_metric(state, "submit_accepted_total"));
+
+    if let Some(gap) = &batch.gap {
+        state
+            .metrics
+            .inc(&submit_metric(state, "submit_gap_markers_total"));
+        eprintln!(
+            "agent {} declared seqs {}..={} lost: {}",
+            batch.agent_id, gap.missing_from, gap.missing_to, gap.reason
+        );
+    }
+    if let Some(BatchKind::SessionStart { session_id, .. }) = &batch.kind {
+        state
+            .metrics
+            .inc(&submit_metric(state, "submit_session_starts_total"));
+        println!(
+            "agent {} started session {session_id} at seq {}",
+            batch.agent_id, batch.seq
+        );
+    }
+    if let Some(reason) = batch.closed_reason() {
+        state
+            .metrics
+            .inc(&submit_metric(state, "submit_chain_closes_total"));
+        println!(
+            "agent {} closed its chain at seq {}: {reason}",
+            batch.agent_id, batch.seq
+        );
+    }
+
+    if let Some(tracker) = &state.anomaly {
+        tracker.record(
+            &batch.agent_id,
+            logs_size as u64,
+            received_ms,
+            anomaly_score,
+        );
+        if let Some(score) = anomaly_score.filter(|score| tracker.exceeds(*score)) {
+            state.metrics.inc("logchain_submit_anomalies_total");
+            eprintln!(
+                "[anomaly] agent {} seq {} scored {score:.1} ({} bytes)",
+                batch.agent_id, batch.seq, logs_size
+            );
+        }
+    }
+
+    (
+        StatusCode::CREATED,
+        Json(SubmitResponse {
+            receipt: Some(receipt),
+            ack: Some(state.ack_mode.as_str()),
+            ..SubmitResponse::accepted("ok", "batch stored")
+        }),
+    )
+}
+
+/* ----------------------- REGISTER / ROTATE AGENT KEYS ----------------------- */
+
+async fn handler_register_agent(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    headers: HeaderMap,
+    Json(req): Json<RegisterRequest>,
+) -> Response {
+    if let Err(err) = auth.require_agent(Scope::Register, &req.agent_id) {
+        return agent_auth_error(err).into_response();
+    }
+    idempotency::once(
+        &state,
+        "register",
+        &headers,
+        &req,
+        register_agent(&state, &req),
+    )
+    .await
+}
+
+/// `POST /agents/register` for an authorized caller.
+async fn register_agent(
+    state: &AppState,
+    req: &RegisterRequest,
+) -> (StatusCode, Json<AgentResponse>) {
+    if req.agent_id.starts_with(ingest::AGENT_PREFIX) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(AgentResponse {
+                status: "error".into(),
+                message: "agent_id prefix is reserved for server-side ingestion".into(),
+            }),
+        );
+    }
+
+    let pk = match parse_hex_public_key(&req.public_key_hex) {
+        Ok(pk) => pk,
+        Err(msg) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(AgentResponse {
+                    status: "error".into(),
+                    message: msg,
+                }),
+            )
+        }
+    };
+
+    let existing =
+        match sqlx::query("SELECT public_key, revoked_at FROM agents WHERE agent_id = ?1")
+            .bind(&req.agent_id)
+            .fetch_optional(&state.pool)
+            .await
+        {
+            Ok(existing) => existing,
+            Err(err) => {
+                return agent_storage_error(state, "register", err, "failed to look up the agent");
+            }
+        };
+
+    if let Some(row) = existing {
+        if row.get::<Option<i64>, _>("revoked_at").is_some() {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(AgentResponse {
+                    status: "error".into(),
+                    message: "agent has been revoked".into(),
+                }),
+            );
+        }
+        let stored: Vec<u8> = row.get("public_key");
+        if stored == pk.to_bytes() {
+            return (
+                StatusCode::OK,
+                Json(AgentResponse {
+                    status: "ok".into(),
+                    message: "agent already registered with this key".into(),
+                }),
+            );
+        } else {
+            return (
+                StatusCode::CONFLICT,
+                Json(AgentResponse {
+                    status: "error".into(),
+                    message: "agent ID already registered with a different key".into(),
+                }),
+            );
+        }
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(tx) => tx,
+        Err(err) => {
+            return agent_storage_error(state, "register", err, "failed to start transaction");
+        }
+    };
+    let owner = match insert_agent(tx.as_mut(), &req.agent_id, &pk, state.unique_agent_keys).await {
+        Ok(owner) => owner,
+        Err(err) => {
+            return agent_storage_error(state, "register", err, "failed to register the agent");
+        }
+    };
+    if let Some(owner) = owner {
+        drop(tx);
+        return (
+            StatusCode::CONFLICT,
+            Json(AgentResponse {
+                status: "error".into(),
+                message: key_conflicts::conflict_message(&owner),
+            }),
+        );
+    }
+    if let Err(err) = tx.commit().await {
+        return agent_storage_error(state, "register", err, "failed to commit the registration");
+    }
+    state.storage.recovered("register");
+
+    (
+        StatusCode::CREATED,
+        Json(AgentResponse {
+            status: "ok".into(),
+            message: "agent registered".into(),
+        }),
+    )
+}
+
+/// Inserts the agent and its first key window; with `unique_keys`, also the
+/// other agent already holding `pk`, if any, so the caller can roll back.
+async fn insert_agent(
+    conn: &mut sqlx::SqliteConnection,
+    agent_id: &str,
+    pk: &VerifyingKey,
+    unique_keys: bool,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query("INSERT INTO agents (agent_id, public_key, created_at) VALUES (?1, ?2, ?3)")
+        .bind(agent_id)
+        .bind(pk.to_bytes().to_vec())
+        .bind(now_unix())
+        .execute(&mut *conn)
+        .await?;
+    record_key(conn, agent_id, pk.as_bytes(), (0, 1)).await?;
+    if unique_keys {
+        key_conflicts::other_owner(conn, pk.as_bytes(), agent_id).await
+    } else {
+        Ok(None)
+    }
+}
+
+/// `POST /agents/register/bulk`: every entry is validated before any is
+/// inserted, then all are registered in one transaction, each with its own
+/// outcome. A conflict or a revoked agent fails only its own entry.
+async fn handler_register_agents_bulk(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Json(entries): Json<Vec<BulkRegisterEntry>>,
+) -> (StatusCode, Json<BulkRegisterResponse>) {
+    if entries.len() > MAX_BULK_REGISTRATIONS {
+        return bulk_register_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("at most {MAX_BULK_REGISTRATIONS} entries per request"),
+            Vec::new(),
+        );
+    }
+
+    let mut keys = Vec::with_capacity(entries.len());
+    let mut invalid = Vec::new();
+    let mut seen = HashSet::new();
+    for (i, entry) in entries.iter().enumerate() {
+        match validate_bulk_entry(&auth, entry, &mut seen) {
+            Ok(pk) => keys.push(pk),
+            Err(msg) => invalid.push((i, msg)),
+        }
+    }
+    if !invalid.is_empty() {
+        let mut results: Vec<BulkRegisterResult> = entries
+            .iter()
+            .map(|entry| BulkRegisterResult {
+                agent_id: entry.agent_id.clone(),
+                status: "not_attempted",
+                message: "not registered: another entry is invalid".into(),
+            })
+            .collect();
+        let count = invalid.len();
+        for (i, msg) in invalid {
+            results[i].status = "invalid";
+            results[i].message = msg;
+        }
+        return bulk_register_response(
+            StatusCode::BAD_REQUEST,
+            format!("{count} invalid entries; nothing registered"),
+            results,
+        );
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return bulk_register_failed(e),
+    };
+    let mut results = Vec::with_capacity(entries.len());
+    for (entry, pk) in entries.iter().zip(keys) {
+        let (status, message) = match register_in(&mut tx, &state, &entry.agent_id, &pk).await {
+            Ok(outcome) => outcome,
+            Err(e) => return bulk_register_failed(e),
+        };
+        results.push(BulkRegisterResult {
+            agent_id: entry.agent_id.clone(),
+            status,
+            message,
+        });
+    }
+    if let Err(e) = tx.commit().await {
+        return bulk_register_failed(e);
+    }
+
+    let registered = results.iter().filter(|r| r.status == "registered").count();
+    let message = format!("{registered} of {} agents registered", results.len());
+    bulk_register_response(StatusCode::OK, message, results)
+}
+
+/// The parsed key of a bulk entry that may be registered, or why not.
+/// `seen` catches an agent listed twice in one request.
+fn validate_bulk_entry(
+    auth: &AuthContext,
+    entry: &BulkRegisterEntry,
+    seen: &mut HashSet<String>,
+) -> Result<VerifyingKey, String> {
+    auth.require_agent(Scope::Register, &entry.agent_id)
+        .map_err(|err| err.message().to_string())?;
+    if entry.agent_id.is_empty() {
+        return Err("empty agent_id".into());
+    }
+    if entry.agent_id.starts_with(ingest::AGENT_PREFIX) {
+        return Err("agent_id prefix is reserved for server-side ingestion".into());
+    }
+    let pk = parse_hex_public_key(&entry.public_key_hex)?;
+    if let Some(proof) = &entry.proof_signature_hex {
+        let sig = parse_hex_signature(proof)?;
+        let message = registration_message(&entry.agent_id, &entry.public_key_hex);
+        pk.verify_strict(&message, &sig)
+            .map_err(|_| "proof signature does not verify with public_key_hex".to_string())?;
+    }
+    if !seen.insert(entry.agent_id.clone()) {
+        return Err("agent_id listed more than once".into());
+    }
+    Ok(pk)
+}
+
+/// One bulk entry inside the shared transaction, with the same outcomes as
+/// `POST /agents/register`.
+async fn register_in(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    state: &AppState,
+    agent_id: &str,
+    pk: &VerifyingKey,
+) -> Result<(&'static str, String), sqlx::Error> {
+    let existing = sqlx::query("SELECT public_key, revoked_at FROM agents WHERE agent_id = ?1")
+        .bind(agent_id)
+        .fetch_optional(tx.as_mut())
+        .await?;
+    if let Some(row) = existing {
+        if row.get::<Option<i64>, _>("revoked_at").is_some() {
+            return Ok(("revoked", "agent has been revoked".into()));
+        }
+        let stored: Vec<u8> = row.get("public_key");
+        return Ok(if stored == pk.to_bytes() {
+            (
+                "already_registered",
+                "agent already registered with this key".into(),
+            )
+        } else {
+            (
+                "conflict",
+                "agent ID already registered with a different key".into(),
+            )
+        });
+    }
+    if state.unique_agent_keys
+        && let Some(owner) =
+            key_conflicts::other_owner(tx.as_mut(), pk.as_bytes(), agent_id).await?
+    {
+        return Ok(("conflict", key_conflicts::conflict_message(&owner)));
+    }
+
+    sqlx::query("INSERT INTO agents (agent_id, public_key, created_at) VALUES (?1, ?2, ?3)")
+        .bind(agent_id)
+        .bind(pk.to_bytes().to_vec())
+        .bind(now_unix())
+        .execute(tx.as_mut())
+        .await?;
+    record_key(tx.as_mut(), agent_id, pk.as_bytes(), (0, 1)).await?;
+    Ok(("registered", "agent registered".into()))
+}
+
+fn bulk_register_response(
+    code: StatusCode,
+    message: String,
+    results: Vec<BulkRegisterResult>,
+) -> (StatusCode, Json<BulkRegisterResponse>) {
+    let registered = results.iter().filter(|r| r.status == "registered").count();
+    let status = if code.is_success() { "ok" } else { "error" };
+    (
+        code,
+        Json(BulkRegisterResponse {
+            status: status.into(),
+            message,
+            registered,
+            results,
+        }),
+    )
+}
+
+fn bulk_register_failed(err: sqlx::Error) -> (StatusCode, Json<BulkRegisterResponse>) {
+    eprintln!("bulk registration failed: {err}");
+    bulk_register_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "failed to register agents; nothing registered".into(),
+        Vec::new(),
+    )
+}
+
+/* ----------------------- GET /batches ----------------------- */
+
+/// [`ListParams`] with its structured filters parsed from the whole query;
+/// 400 for a malformed `field.` key.
+fn with_structured(
+    mut params: ListParams,
+    query: &[(String, String)],
+) -> Result<ListParams, StatusCode> {
+    params.structured = fields::Filters::parse(params.level.as_deref(), query)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok(params)
+}
+
+async fn handler_get_all(
+    State(state): State<AppState>,
+    Query(params): Query<ListParams>,
+    Query(query): Query<Vec<(String, String)>>,
+) -> Result<Json<Vec<RawQueryBatch>>, StatusCode> {
+    let params = with_structured(params, &query)?;
+    let select = format!("SELECT {BATCH_READ_COLUMNS} FROM batches");
+    let rows = list_query(&select, &params)?
+        .build()
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut batches = rows_to_raw_batches(&state.decompressor, rows)?;
+    if params.log_substring.is_some() || !params.structured.is_empty() {
+        let substring = params.log_substring.as_deref();
+        for found in &mut batches {
+            let logs: Vec<String> = serde_json::from_str(found.batch.logs.get())
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let position = (found.batch.epoch, found.batch.seq);
+            let agent_id = &found.batch.agent_id;
+            found.matches = Some(lines::matching(
+                |idx| LineAddress::new(agent_id, position, idx),
+                &logs,
+                |line| {
+                    substring.is_none_or(|substring| lines::contains(line, substring))
+                        && params.structured.matches(line)
+                },
+            ));
+        }
+    }
+    Ok(Json(batches))
+}
+
+/// `GET /batches/meta`: the same filters as `/batches`, but per-row metadata
+/// and payload sizes instead of log content.
+async fn handler_get_meta(
+    State(state): State<AppState>,
+    Query(params): Query<ListParams>,
+    Query(query): Query<Vec<(String, String)>>,
+) -> Result<Json<Vec<BatchMeta>>, StatusCode> {
+    let params = with_structured(params, &query)?;
+    let select = format!("SELECT {} FROM batches", meta_columns());
+    let rows = list_query(&select, &params)?
+        .build()
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let results = rows.iter().map(row_to_meta).collect::<Result<_, _>>()?;
+    Ok(Json(results))
+}
+
+/// The columns [`row_to_meta`] reads.
+fn meta_columns() -> String {
+    format!(
+        "id, agent_id, seq, epoch, epoch_prev_seq, hash, {TIMESTAMP_MS_EXPR} AS timestamp_ms, {RECEIVED_AT_MS_EXPR} AS received_at_ms, lines_read, logs_size, logs_compressed_size, accumulator, anomaly_score, user_agent, tls_fingerprint, gap_from, gap_to, gap_reason, session_id, session_boot_ms, closed_reason"
+    )
+}
+
+fn row_to_meta(row: &sqlx::sqlite::SqliteRow) -> Result<BatchMeta, StatusCode> {
+    let hash: Vec<u8> = row.get("hash");
+    Ok(BatchMeta {
+        id: row.get("id"),
+        agent_id: row.get("agent_id"),
+        seq: row.get::<i64, _>("seq") as u64,
+        epoch: row.get::<i64, _>("epoch") as u64,
+        hash: hash
+            .try_into()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        timestamp_ms: row.get::<i64, _>("timestamp_ms") as u64,
+        received_at: row.get::<i64, _>("received_at_ms") as u64,
+        lines_read: row.get::<Option<i64>, _>("lines_read").map(|v| v as u64),
+        logs_size: row.get::<Option<i64>, _>("logs_size").map(|v| v as u64),
+        logs_compressed_size: row
+            .get::<Option<i64>, _>("logs_compressed_size")
+            .map(|v| v as u64),
+        accumulator: stored_accumulator(row),
+        anomaly_score: row.get("anomaly_score"),
+        user_agent: row.get("user_agent"),
+        tls_fingerprint: row.get("tls_fingerprint"),
+        gap: stored_gap(row),
+        epoch_start: stored_epoch_start(row),
+        kind: sessions::stored_kind(row),
+    })
+}
+
+#[derive(Deserialize, Default)]
+struct LatestParams {
+    /// Only this agent's batches.
+    agent_id: Option<String>,
+    /// How many, capped at [`MAX_LATEST`]; [`DEFAULT_LATEST`] without it.
+    limit: Option<u64>,
+    /// Whole batches, logs included, as `/batches` sends them, instead of
+    /// [`BatchMeta`].
+    #[serde(default)]
+    logs: bool,
+    /// See [`ListParams::as_of_received_at`].
+    as_of_received_at: Option<u64>,
+}
+
+const DEFAULT_LATEST: u64 = 100;
+const MAX_LATEST: u64 = 1000;
+
+/// `GET /batches/latest`: the batches that arrived last, across all agents
+/// or one, newest first. Ordered by `received_at` in ms, then id, which the
+/// `idx_batches_received_ms` index serves without a scan.
+async fn handler_get_latest(
+    State(state): State<AppState>,
+    Query(params): Query<LatestParams>,
+) -> Result<Response, StatusCode> {
+    let limit = params.limit.unwrap_or(DEFAULT_LATEST).clamp(1, MAX_LATEST);
+    let columns = if params.logs {
+        BATCH_READ_COLUMNS.to_string()
+    } else {
+        meta_columns()
+    };
+    let mut builder =
+        QueryBuilder::<Sqlite>::new(format!("SELECT {columns} FROM batches WHERE 1 = 1"));
+    if let Some(agent_id) = &params.agent_id {
+        builder.push(" AND agent_id = ");
+        builder.push_bind(agent_id);
+    }
+    if let Some(ms) = params.as_of_received_at {
+        builder.push(format!(" AND {RECEIVED_AT_MS_EXPR} <= "));
+        builder.push_bind(ms as i64);
+    }
+    builder.push(format!(
+        " ORDER BY {RECEIVED_AT_MS_EXPR} DESC, id DESC LIMIT "
+    ));
+    builder.push_bind(limit as i64);
+    let rows = builder
+        .build()
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if params.logs {
+        return Ok(Json(rows_to_raw_batches(&state.decompressor, rows)?).into_response());
+    }
+    let results: Vec<BatchMeta> = rows.iter().map(row_to_meta).collect::<Result<_, _>>()?;
+    Ok(Json(results).into_response())
+}
+
+/// Builds `select` + the `/batches` filters, ordering and paging; 400 for a
+/// malformed `hash_prefix`.
+fn list_query<'a>(
+    select: &str,
+    params: &'a ListParams,
+) -> Result<QueryBuilder<'a, Sqlite>, StatusCode> {
+    let hash_range = params
+        .hash_prefix
+        .as_deref()
+        .map(hash_prefix_range)
+        .transpose()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let mut builder = QueryBuilder::new(select);
+    let mut first_clause = true;
+
+    let since_ms = params
+        .since_ts_ms
+        .or(params.since_timestamp.map(|s| s.saturating_mul(1000)));
+    let until_ms = params.until_ts_ms.or(params
+        .until_timestamp
+        .map(|s| s.saturating_mul(1000).saturating_add(999)));
+
+    if params.agent_id.is_some()
+        || params.epoch.is_some()
+        || params.since_seq.is_some()
+        || since_ms.is_some()
+        || until_ms.is_some()
+        || params.log_substring.is_some()
+        || params.since_received_at.is_some()
+        || params.as_of_received_at.is_some()
+        || hash_range.is_some()
+        || !params.structured.is_empty()
+    {
+        builder.push(" WHERE ");
+    }
+
+    if let Some(agent) = &params.agent_id {
+        if !first_clause {
+            builder.push(" AND ");
+        }
+        builder.push("agent_id = ");
+        builder.push_bind(agent);
+        first_clause = false;
+    }
+
+    if let Some(epoch) = params.epoch {
+        if !first_clause {
+            builder.push(" AND ");
+        }
+        builder.push("epoch = ");
+        builder.push_bind(epoch as i64);
+        first_clause = false;
+    }
+
+    if let Some(seq) = params.since_seq {
+        if !first_clause {
+            builder.push(" AND ");
+        }
+        builder.push("seq >= ");
+        builder.push_bind(seq as i64);
+        first_clause = false;
+    }
+
+    if let Some(ms) = since_ms {
+        if !first_clause {
+            builder.push(" AND ");
+        }
+        builder.push(format!("{TIMESTAMP_MS_EXPR} >= "));
+        builder.push_bind(ms as i64);
+        first_clause = false;
+    }
+
+    if let Some(ms) = until_ms {
+        if !first_clause {
+            builder.push(" AND ");
+        }
+        builder.push(format!("{TIMESTAMP_MS_EXPR} <= "));
+        builder.push_bind(ms as i64);
+        first_clause = false;
+    }
+
+    if let Some(sub) = &params.log_substring {
+        if !first_clause {
+            builder.push(" AND ");
+        }
+        builder.push("logs LIKE ");
+        builder.push_bind(format!("%{}%", sub));
+        first_clause = false;
+    }
+
+    if !params.structured.is_empty() {
+        if !first_clause {
+            builder.push(" AND ");
+        }
+        params.structured.push_sql(&mut builder);
+        first_clause = false;
+    }
+
+    if let Some(ms) = params.since_received_at {
+        if !first_clause {
+            builder.push(" AND ");
+        }
+        builder.push(format!("{RECEIVED_AT_MS_EXPR} > "));
+        builder.push_bind(ms as i64);
+        first_clause = false;
+    }
+
+    if let Some(ms) = params.as_of_received_at {
+        if !first_clause {
+            builder.push(" AND ");
+        }
+        builder.push(format!("{RECEIVED_AT_MS_EXPR} <= "));
+        builder.push_bind(ms as i64);
+        first_clause = false;
+    }
+
+    // A range on the blob itself, so `idx_batches_hash` answers it.
+    if let Some((lower, upper)) = hash_range {
+        if !first_clause {
+            builder.push(" AND ");
+        }
+        builder.push("hash >= ");
+        builder.push_bind(lower);
+        if let Some(upper) = upper {
+            builder.push(" AND hash < ");
+            builder.push_bind(upper);
+        }
+    }
+
+    builder.push(" ORDER BY agent_id ASC, epoch ASC, seq ASC");
+
+    if let Some(limit) = params.limit {
+        builder.push(" LIMIT ");
+        builder.push_bind(limit as i64);
+    }
+    if let Some(offset) = params.offset {
+        builder.push(" OFFSET ");
+        builder.push_bind(offset as i64);
+    }
+
+    Ok(builder)
+}
+
+/* ----------------------- EXPORT /batches/export ----------------------- */
+
+/// Rows fetched per query while streaming a non-JSON export.
+const EXPORT_PAGE_ROWS: u64 = 500;
+
+async fn handler_export(
+    State(state): State<AppState>,
+    Query(params): Query<ExportParams>,
+) -> Result<Response, StatusCode> {
+    let format = params.format.unwrap_or_default();
+    let header = match format {
+        _ if !params.header => None,
+        ExportFormat::Json | ExportFormat::Ndjson => Some(state.receipts.export_header().await),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+    if format == ExportFormat::Json {
+        let rows = export_rows(&state.pool, &params, params.since_id, params.limit).await?;
+        let batches = rows_to_raw_batches(&state.decompressor, rows)?;
+        return Ok(match header {
+            Some(export_header) => Json(HeadedExport {
+                export_header,
+                batches,
+            })
+            .into_response(),
+            None => Json(batches).into_response(),
+        });
+    }
+
+    let encoder = ExportEncoder::new(format, params.compression.unwrap_or_default())?;
+    let content_type = match format {
+        ExportFormat::Ndjson => "application/x-ndjson",
+        ExportFormat::Parquet => "application/vnd.apache.parquet",
+        _ => "text/plain; charset=utf-8",
+    };
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    tokio::spawn(stream_export(
+        state.pool.clone(),
+        state.decompressor.clone(),
+        params,
+        header,
+        encoder,
+        tx,
+    ));
+    let body = Body::from_stream(futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }));
+
+    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+/// Turns export pages into body chunks for one streamed format.
+enum ExportEncoder {
+    /// ndjson, syslog and cef: each page renders independently.
+    Text(ExportFormat),
+    /// Row groups go out as they fill; the footer comes with `finish`.
+    #[cfg(feature = "parquet")]
+    Parquet(Box<ParquetExporter<Vec<u8>>>),
+}
+
+impl ExportEncoder {
+    /// Fails with 501 for `parquet` when the server was built without it.
+    fn new(format: ExportFormat, compression: ParquetCompression) -> Result<Self, StatusCode> {
+        match format {
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => ParquetExporter::new(Vec::new(), compression)
+                .map(|exporter| Self::Parquet(Box::new(exporter)))
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR),
+            #[cfg(not(feature = "parquet"))]
+            ExportFormat::Parquet => {
+                let _ = compression;
+                Err(StatusCode::NOT_IMPLEMENTED)
+            }
+            _ => Ok(Self::Text(format)),
+        }
+    }
+
+    fn encode_page(&mut self, rows: &[QueryBatch]) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Text(format) => {
+                let mut chunk = String::new();
+                for entry in rows {
+                    match render_lines(*format, &entry.batch, &entry.hash) {
+                        Some(lines) => chunk.push_str(&lines),
+                        None => {
+                            chunk.push_str(&serde_json::to_string(entry).unwrap());
+                            chunk.push('\n');
+                        }
+                    }
+                }
+                Ok(chunk.into_bytes())
+            }
+            #[cfg(feature = "parquet")]
+            Self::Parquet(exporter) => {
+                for entry in rows {
+                    exporter.push(entry.id, &entry.batch, &entry.hash, entry.received_at)?;
+                }
+                Ok(std::mem::take(exporter.output_mut()))
+            }
+        }
+    }
+
+    fn finish(self) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Text(_) => Ok(Vec::new()),
+            #[cfg(feature = "parquet")]
+            Self::Parquet(exporter) => exporter.finish(),
+        }
+    }
+}
+
+/// Pages through the export by id so memory stays bounded regardless of size.
+/// Stops as soon as the client disconnects; a query or encoding failure
+/// aborts the body.
+async fn stream_export(
+    pool: SqlitePool,
+    decompressor: decompress::Decompressor,
+    params: ExportParams,
+    header: Option<ExportHeader>,
+    mut encoder: ExportEncoder,
+    tx: tokio::sync::mpsc::Sender<Result<Bytes, std::io::Error>>,
+) {
+    if let Some(header) = header {
+        let mut line = serde_json::json!({ export_header::HEADER_KEY: header }).to_string();
+        line.push('\n');
+        if tx.send(Ok(Bytes::from(line))).await.is_err() {
+            return;
+        }
+    }
+    let mut after_id = params.since_id;
+    let mut remaining = params.limit;
+
+    loop {
+        let page = remaining.map_or(EXPORT_PAGE_ROWS, |r| r.min(EXPORT_PAGE_ROWS));
+        if page == 0 {
+            break;
+        }
+
+        let rows =
+            match fetch_export_page(&pool, &decompressor, &params, after_id, Some(page)).await {
+                Ok(rows) => rows,
+                Err(_) => {
+                    let _ = tx
+                        .send(Err(std::io::Error::other("export query failed")))
+                        .await;
+                    return;
+                }
+            };
+
+        let chunk = match encoder.encode_page(&rows) {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                let _ = tx.send(Err(std::io::Error::other(err))).await;
+                return;
+            }
+        };
+        if !chunk.is_empty() && tx.send(Ok(Bytes::from(chunk))).await.is_err() {
+            return;
+        }
+
+        if (rows.len() as u64) < page {
+            break;
+        }
+        after_id = rows.last().map(|r| r.id);
+        if let Some(r) = remaining.as_mut() {
+            *r -= rows.len() as u64;
+        }
+    }
+
+    match encoder.finish() {
+        Ok(tail) if !tail.is_empty() => {
+            let _ = tx.send(Ok(Bytes::from(tail))).await;
+        }
+        Ok(_) => {}
+        Err(err) => {
+            let _ = tx.send(Err(std::io::Error::other(err))).await;
+        }
+    }
+}
+
+/// One export query: rows after `after_id` matching `params`' filters, in id order.
+async fn fetch_export_page(
+    pool: &SqlitePool,
+    decompressor: &decompress::Decompressor,
+    params: &ExportParams,
+    after_id: Option<i64>,
+    limit: Option<u64>,
+) -> Result<Vec<QueryBatch>, StatusCode> {
+    export_rows(pool, params, after_id, limit)
+        .await?
+        .into_iter()
+        .map(|row| row_to_query_batch(decompressor, row))
+        .collect()
+}
+
+async fn export_rows(
+    pool: &SqlitePool,
+    params: &ExportParams,
+    after_id: Option<i64>,
+    limit: Option<u64>,
+) -> Result<Vec<sqlx::sqlite::SqliteRow>, StatusCode> {
+    let mut builder = QueryBuilder::new(format!("SELECT {BATCH_READ_COLUMNS} FROM batches"));
+
+    builder.push(" WHERE 1 = 1");
+
+    if let Some(since_id) = after_id {
+        builder.push(" AND id > ");
+        builder.push_bind(since_id);
+    }
+
+    if let Some(ms) = params.since_received_at {
+        builder.push(format!(" AND {RECEIVED_AT_MS_EXPR} > "));
+        builder.push_bind(ms as i64);
+    }
+
+    if let Some(ms) = params.as_of_received_at {
+        builder.push(format!(" AND {RECEIVED_AT_MS_EXPR} <= "));
+        builder.push_bind(ms as i64);
+    }
+
+    builder.push(" ORDER BY id ASC");
+
+    if let Some(limit) = limit {
+        builder.push(" LIMIT ");
+        builder.push_bind(limit as i64);
+    }
+
+    builder
+        .build()
+        .fetch_all(pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/* ----------------------- CHECKPOINTS /batches/checkpoints ----------------------- */
+
+#[derive(Deserialize, Default)]
+struct CheckpointParams {
+    /// Only this agent's checkpoint.
+    agent_id: Option<String>,
+    /// Only agents ordered after this agent_id: the last one of the previous page.
+    after: Option<String>,
+    /// Page size, capped at [`MAX_CHECKPOINT_PAGE`].
+    limit: Option<u64>,
+    /// The heads as they stood at this arrival time (unix ms, inclusive);
+    /// see [`checkpoint_cache::as_of`].
+    as_of_received_at: Option<u64>,
+}
+
+const MAX_CHECKPOINT_PAGE: u64 = 1000;
+
+/// Without parameters every agent's checkpoint, from memory while fresh;
+/// with any, one page of at most [`MAX_CHECKPOINT_PAGE`] read straight from
+/// `checkpoints_cache`. With `as_of_received_at`, that page as of then, each
+/// with its head row.
+async fn handler_checkpoints(
+    State(state): State<AppState>,
+    Query(params): Query<CheckpointParams>,
+) -> Result<Response, StatusCode> {
+    if let Some(as_of_ms) = params.as_of_received_at {
+        let limit = params
+            .limit
+            .unwrap_or(MAX_CHECKPOINT_PAGE)
+            .min(MAX_CHECKPOINT_PAGE);
+        return checkpoint_cache::as_of(
+            &state.pool,
+            as_of_ms,
+            params.agent_id.as_deref(),
+            params.after.as_deref(),
+            Some(limit),
+        )
+        .await
+        .map(|checkpoints| Json(checkpoints).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    let paged = params.agent_id.is_some() || params.after.is_some() || params.limit.is_some();
+    let checkpoints = if paged {
+        let limit = params
+            .limit
+            .unwrap_or(MAX_CHECKPOINT_PAGE)
+            .min(MAX_CHECKPOINT_PAGE);
+        checkpoint_cache::read(
+            &state.pool,
+            params.agent_id.as_deref(),
+            params.after.as_deref(),
+            Some(limit),
+        )
+        .await
+    } else {
+        state.checkpoints.get(&state.pool).await
+    };
+    checkpoints
+        .map(|checkpoints| Json(checkpoints).into_response())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Last position, hash and accumulator per agent, ordered by agent_id, from
+/// `checkpoints_cache`; behind `/batches/checkpoints` and the gRPC
+/// `Checkpoints`, through [`checkpoint_cache`].
+async fn load_checkpoints(pool: &SqlitePool) -> Result<Vec<AgentCheckpoint>, sqlx::Error> {
+    checkpoint_cache::read(pool, None, None, None).await
+}
+
+/* ----------------------- GET /batches/:id ----------------------- */
+
+async fn handler_get_one(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<QueryBatch>, StatusCode> {
+    let row = sqlx::query(&format!(
+        "SELECT {BATCH_READ_COLUMNS} FROM batches WHERE id = ?1"
+    ))
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let row = match row {
+        Some(r) => r,
+        None => return Err(StatusCode::NOT_FOUND),
+    };
+
+    Ok(Json(row_to_query_batch(&state.decompressor, row)?))
+}
+
+/* ----------------------- GET /batches/:id/raw ----------------------- */
+
+async fn handler_get_raw(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let row = sqlx::query("SELECT raw_body, raw_content_type FROM batches WHERE id = ?1")
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    // Rows stored before raw capture was enabled have nothing to serve.
+    let compressed: Option<Vec<u8>> = row.get("raw_body");
+    let compressed = compressed.ok_or(StatusCode::NOT_FOUND)?;
+    let content_type: Option<String> = row.get("raw_content_type");
+    let body = state
+        .decompressor
+        .bytes(&compressed)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            content_type.unwrap_or_else(|| "application/json".to_string()),
+        )],
+        body,
+    ))
+}
+
+/* ----------------------- GET /batches/:id/logs ----------------------- */
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogsFormat {
+    #[default]
+    Text,
+    Gzip,
+}
+
+#[derive(Debug, Deserialize)]
+struct LogsParams {
+    #[serde(default)]
+    format: LogsFormat,
+}
+
+/// The batch's lines as a download, each terminated by a newline. `gzip` is
+/// that same text compressed: the stored blob holds the JSON array, not the
+/// text, so it cannot be passed through as is.
+async fn handler_get_logs(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(params): Query<LogsParams>,
+) -> Result<Response, StatusCode> {
+    let row = sqlx::query(&format!(
+        "SELECT {BATCH_READ_COLUMNS} FROM batches WHERE id = ?1"
+    ))
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    let compressed =
+        tiering::compressed_logs(&row).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let json = match compressed {
+        Some(blob) => {
+            let mut json = String::new();
+            state
+                .decompressor
+                .row_logs(&row, &blob, &mut json)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            json
+        }
+        None => row.get("logs"),
+    };
+    let (lines, _) = parse_stored_logs(&json).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let text: String = lines.iter().map(|line| format!("{line}\n")).collect();
+
+    let (content_type, extension, body) = match params.format {
+        LogsFormat::Text => ("text/plain; charset=utf-8", "log", text.into_bytes()),
+        LogsFormat::Gzip => (
+            "application/gzip",
+            "log.gz",
+            compress_bytes(text.as_bytes(), Compression::fast())
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        ),
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"batch-{id}.{extension}\""),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/* ----------------------- GET /metrics ----------------------- */
+
+async fn handler_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let mut body = state.metrics.render();
+    body.push_str(&drift::render_metrics(&state).await);
+    body.push_str(&stale::render_metrics(&state).await);
+    body.push_str(&key_conflicts::render_metrics(&state).await);
+    body.push_str(&watermark::render_metrics(&state));
+    body.push_str(&maintenance::render_metrics(&state));
+    if !state.agent_size_metrics {
+        return ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body);
+    }
+
+    // Storage attribution comes from the table so it survives restarts; an
+    // archived raw body (STORE_RAW_BODY) is stored bytes too.
+    let per_agent = sqlx::query(
+        "SELECT agent_id, COALESCE(SUM(logs_size), 0) AS logs, COALESCE(SUM(COALESCE(logs_compressed_size, logs_size) + COALESCE(LENGTH(raw_body), 0)), 0) AS stored FROM batches GROUP BY agent_id",
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or_default();
+    for row in per_agent {
+        let agent: String = row.get("agent_id");
+        body.push_str(&format!(
+            "{} {}\n",
+            labeled("logchain_agent_logs_bytes", "agent_id", &agent),
+            row.get::<i64, _>("logs")
+        ));
+        body.push_str(&format!(
+            "{} {}\n",
+            labeled("logchain_agent_stored_bytes", "agent_id", &agent),
+            row.get::<i64, _>("stored")
+        ));
+    }
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/* ----------------------- Helper: Convert DB row → LogBatch ----------------------- */
+
+fn row_to_query_batch(
+    decompressor: &decompress::Decompressor,
+    row: sqlx::sqlite::SqliteRow,
+) -> Result<QueryBatch, StatusCode> {
+    use std::convert::TryInto;
+
+    let id: i64 = row.get("id");
+    let agent_id: String = row.get("agent_id");
+    let seq: i64 = row.get("seq");
+    let prev_hash: Vec<u8> = row.get("prev_hash");
+    let hash_vec: Vec<u8> = row.get("hash");
+    // NULL for batches kept plaintext (below COMPRESSION_MIN_BYTES).
+    let compressed =
+        tiering::compressed_logs(&row).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let logs_json: String = if let Some(blob) = compressed {
+        let mut json = String::new();
+        decompressor
+            .row_logs(&row, &blob, &mut json)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        json
+    } else {
+        row.get("logs")
+    };
+    let timestamp: i64 = row.get("timestamp");
+    let received_at_ms: Option<i64> = row.get("received_at_ms");
+    let received_at = received_at_ms.unwrap_or_else(|| row.get::<i64, _>("received_at") * 1000);
+    let signature_vec: Vec<u8> = row.get("signature");
+    let public_key_vec: Vec<u8> = row.get("public_key");
+
+    let (logs, _canonical) =
+        parse_stored_logs(&logs_json).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Convert signature
+    let sig_bytes: [u8; 64] = signature_vec
+        .try_into()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let signature = Signature::from_bytes(&sig_bytes);
+
+    // Convert public key
+    let pk_bytes: [u8; 32] = public_key_vec
+        .try_into()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let public_key = VerifyingKey::from_bytes(&pk_bytes)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Convert hashes
+    let prev_hash_bytes: [u8; 32] = prev_hash
+        .try_into()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let hash: [u8; 32] = hash_vec
+        .try_into()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let batch = LogBatch {
+        prev_hash: prev_hash_bytes,
+        logs,
+        timestamp: timestamp as u64,
+        agent_id,
+        seq: seq as u64,
+        signature,
+        public_key,
+        lines_read: row.get::<Option<i64>, _>("lines_read").map(|v| v as u64),
+        version: row
+            .get::<Option<i64>, _>("batch_version")
+            .map(|v| v as u32)
+            .unwrap_or(BATCH_VERSION_V1),
+        accumulator: stored_accumulator(&row),
+        gap: stored_gap(&row),
+        epoch: row.get::<i64, _>("epoch") as u64,
+        epoch_start: stored_epoch_start(&row),
+        kind: sessions::stored_kind(&row),
+    };
+
+    Ok(QueryBatch {
+        id,
+        batch,
+        hash,
+        received_at: received_at as u64,
+        redacted: redactions::stored_redacted(&row),
+    })
+}
+
+/// [`RawQueryBatch`]es for `rows`, decompressing each into one reused buffer.
+fn rows_to_raw_batches(
+    decompressor: &decompress::Decompressor,
+    rows: Vec<sqlx::sqlite::SqliteRow>,
+) -> Result<Vec<RawQueryBatch>, StatusCode> {
+    let mut scratch = String::new();
+    rows.iter()
+        .map(|row| row_to_raw_query_batch(decompressor, row, &mut scratch))
+        .collect()
+}
+
+/// The response-only counterpart of [`row_to_query_batch`]. The logs are
+/// still checked to be a list of strings, so a row that would fail there
+/// fails here too; only the lines are never materialized.
+fn row_to_raw_query_batch(
+    decompressor: &decompress::Decompressor,
+    row: &sqlx::sqlite::SqliteRow,
+    scratch: &mut String,
+) -> Result<RawQueryBatch, StatusCode> {
+    let internal = |_| StatusCode::INTERNAL_SERVER_ERROR;
+    let compressed =
+        tiering::compressed_logs(row).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let logs = match compressed {
+        Some(blob) => {
+            decompressor
+                .row_logs(row, &blob, scratch)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            serde_json::from_str::<&RawValue>(scratch)
+                .map_err(internal)?
+                .to_owned()
+        }
+        None => RawValue::from_string(row.get("logs")).map_err(internal)?,
+    };
+    serde_json::from_str::<LinesShape>(logs.get()).map_err(internal)?;
+
+    let received_at = row
+        .get::<Option<i64>, _>("received_at_ms")
+        .unwrap_or_else(|| row.get::<i64, _>("received_at") * 1000);
+    let signature: Vec<u8> = row.get("signature");
+    if signature.len() != 64 {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    let bytes = |column: &str| -> Result<[u8; 32], StatusCode> {
+        row.get::<Vec<u8>, _>(column)
+            .try_into()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    };
+
+    Ok(RawQueryBatch {
+        id: row.get("id"),
+        batch: RawLogBatch {
+            prev_hash: bytes("prev_hash")?,
+            logs,
+            timestamp: row.get::<i64, _>("timestamp") as u64,
+            agent_id: row.get("agent_id"),
+            seq: row.get::<i64, _>("seq") as u64,
+            signature,
+            public_key: bytes("public_key")?,
+            lines_read: row.get::<Option<i64>, _>("lines_read").map(|v| v as u64),
+            version: row
+                .get::<Option<i64>, _>("batch_version")
+                .map(|v| v as u32)
+                .unwrap_or(BATCH_VERSION_V1),
+            accumulator: stored_accumulator(row),
+            gap: stored_gap(row),
+            epoch: row.get::<i64, _>("epoch") as u64,
+            epoch_start: stored_epoch_start(row),
+            kind: sessions::stored_kind(row),
+        },
+        hash: bytes("hash")?,
+        received_at: received_at as u64,
+        redacted: redactions::stored_redacted(row),
+        matches: None,
+    })
+}
+
+/// Deserializes from a JSON list of strings without keeping the strings.
+struct LinesShape;
+struct LineShape;
+
+impl<'de> Deserialize<'de> for LinesShape {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(LinesShape)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for LinesShape {
+    type Value = LinesShape;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a list of log lines")
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self, A::Error> {
+        while seq.next_element::<LineShape>()?.is_some() {}
+        Ok(LinesShape)
+    }
+}
+
+impl<'de> Deserialize<'de> for LineShape {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(LineShape)
+    }
+}
+
+impl serde::de::Visitor<'_> for LineShape {
+    type Value = LineShape;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a log line")
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<Self, E> {
+        Ok(LineShape)
+    }
+}
+
+/// Why a batch failed chain validation. Each variant has its own log/metric
+/// category so seq races and broken linkage are distinguishable from each other.
+enum ChainRejection {
+    SeqConflict(String),
+    /// A different batch is already stored at this position: the same key
+    /// running twice, e.g. on a cloned VM. Carries the stored hash.
+    SeqTaken([u8; 32]),
+    PrevHashMismatch(String),
+    AccumulatorMismatch(String),
+    /// A gap marker while `ACCEPT_GAP_MARKERS` is off, or a malformed one.
+    GapRefused(String),
+    /// Malformed epoch fields, or an epoch that does not follow the chain's.
+    EpochMismatch(String),
+    /// A [`BatchKind`] the batch does not have the shape for.
+    MalformedKind(String),
+    /// The agent closed its chain and `ACCEPT_AFTER_CLOSE` is off.
+    ChainClosed(String),
+    Internal(String),
+}
+
+impl ChainRejection {
+    fn category(&self) -> &'static str {
+        match self {
+            ChainRejection::SeqConflict(_) | ChainRejection::SeqTaken(_) => "seq_conflict",
+            ChainRejection::PrevHashMismatch(_) => "prev_hash_mismatch",
+            ChainRejection::AccumulatorMismatch(_) => "accumulator_mismatch",
+            ChainRejection::GapRefused(_) => "gap_refused",
+            ChainRejection::EpochMismatch(_) => "epoch_mismatch",
+            ChainRejection::MalformedKind(_) => "malformed_kind",
+            ChainRejection::ChainClosed(_) => "chain_closed",
+            ChainRejection::Internal(_) => "internal",
+        }
+    }
+
+    fn into_response_parts(self) -> (StatusCode, String) {
+        match self {
+            ChainRejection::SeqConflict(msg)
+            | ChainRejection::PrevHashMismatch(msg)
+            | ChainRejection::AccumulatorMismatch(msg)
+            | ChainRejection::GapRefused(msg)
+            | ChainRejection::EpochMismatch(msg)
+            | ChainRejection::ChainClosed(msg) => (StatusCode::CONFLICT, msg),
+            ChainRejection::SeqTaken(stored) => (StatusCode::CONFLICT, seq_taken_message(&stored)),
+            ChainRejection::MalformedKind(msg) => (StatusCode::BAD_REQUEST, msg),
+            ChainRejection::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
+        }
+    }
+}
+
+/// Starts the transaction with a write that changes nothing, so it holds the
+/// database write lock before its first read. Concurrent submits then run
+/// their duplicate check, chain validation and insert one after another,
+/// each seeing what the previous one stored.
+async fn take_write_lock(tx: &mut Transaction<'_, Sqlite>) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE agents SET agent_id = agent_id WHERE 0")
+        .execute(tx.as_mut())
+        .await
+        .map(|_| ())
+}
+
+/// The hash stored for `agent_id` at `(epoch, seq)`, if any.
+async fn stored_hash_at(
+    conn: &mut sqlx::SqliteConnection,
+    agent_id: &str,
+    (epoch, seq): (u64, u64),
+) -> Result<Option<[u8; 32]>, sqlx::Error> {
+    let hash: Option<Vec<u8>> = sqlx::query_scalar(
+        "SELECT hash FROM batches WHERE agent_id = ?1 AND epoch = ?2 AND seq = ?3",
+    )
+    .bind(agent_id)
+    .bind(epoch as i64)
+    .bind(seq as i64)
+    .fetch_optional(conn)
+    .await?;
+    Ok(hash.and_then(|hash| hash.try_into().ok()))
+}
+
+fn seq_taken_message(stored: &[u8; 32]) -> String {
+    format!(
+        "a different batch is already stored at this seq (hash {}); another agent may be sending with the same key",
+        hex_encode(stored)
+    )
+}
+
+/// The answer to a resend of batch `id`: success, with the receipt issued
+/// for the first copy, as it was issued.
+async fn duplicate_resend(
+    state: &AppState,
+    conn: &mut sqlx::SqliteConnection,
+    batch: &LogBatch,
+    id: i64,
+    from: &Provenance,
+) -> (StatusCode, Json<SubmitResponse>) {
+    state
+        .metrics
+        .inc(&submit_metric(state, "submit_duplicate_resends_total"));
+    println!(
+        "duplicate resend of seq {} for agent {}; already stored (request {})",
+        batch.seq,
+        batch.agent_id,
+        from.request_id.as_deref().unwrap_or("-")
+    );
+    let receipt = receipts::fetch(conn, id).await.ok().flatten();
+    (
+        StatusCode::OK,
+        Json(SubmitResponse {
+            receipt,
+            ..SubmitResponse::accepted("duplicate", "batch already stored")
+        }),
+    )
+}
+
+/// A different batch holds the position `batch` claims: reported loudly,
+/// counted apart from ordinary seq conflicts, and answered with the stored
+/// hash so the sender can tell it is not alone on its key.
+async fn seq_clash(
+    state: &AppState,
+    batch: &LogBatch,
+    computed_hash: &[u8; 32],
+    stored_hash: &[u8; 32],
+    from: &Provenance,
+) -> (StatusCode, Json<SubmitResponse>) {
+    state
+        .metrics
+        .inc(&submit_metric(state, "submit_seq_clashes_total"));
+    eprintln!(
+        "[seq-clash] agent {} {} is stored as {} but was sent again as {}; is the key running on two hosts?",
+        batch.agent_id,
+        position_label(batch.position()),
+        hex_encode(stored_hash),
+        hex_encode(computed_hash)
+    );
+    let message = seq_taken_message(stored_hash);
+    record_rejection(state, Some(&batch.agent_id), "seq_conflict", &message, from).await;
+    let (code, Json(response)) = submit_error(state, StatusCode::CONFLICT, "seq_conflict", message);
+    (
+        code,
+        Json(SubmitResponse {
+            stored_hash: Some(hex_encode(stored_hash)),
+            ..response
+        }),
+    )
+}
+
+/// Settles an insert that hit a unique index after passing every check:
+/// a duplicate resend if the winning row is this batch, a clash otherwise.
+async fn lost_insert_race(
+    state: &AppState,
+    batch: &LogBatch,
+    computed_hash: &[u8; 32],
+    from: &Provenance,
+) -> (StatusCode, Json<SubmitResponse>) {
+    let winner = sqlx::query(
+        "SELECT id, hash FROM batches WHERE agent_id = ?1 AND ((epoch = ?2 AND seq = ?3) OR hash = ?4) LIMIT 1",
+    )
+    .bind(&batch.agent_id)
+    .bind(batch.epoch as i64)
+    .bind(batch.seq as i64)
+    .bind(computed_hash.to_vec())
+    .fetch_optional(&state.pool)
+    .await;
+    match winner {
+        Ok(Some(row)) => {
+            let stored: Vec<u8> = row.get("hash");
+            match <[u8; 32]>::try_from(stored) {
+                Ok(stored) if stored == *computed_hash => match state.pool.acquire().await {
+                    Ok(mut conn) => {
+                        duplicate_resend(state, &mut conn, batch, row.get("id"), from).await
+                    }
+                    Err(err) => {
+                        internal_or_storage_error(state, err, "failed to re-read the stored batch")
+                    }
+                },
+                Ok(stored) => seq_clash(state, batch, computed_hash, &stored, from).await,
+                Err(_) => submit_error(
+                    state,
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal",
+                    "bad stored hash",
+                ),
+            }
+        }
+        Ok(None) => submit_error(
+            state,
+            StatusCode::CONFLICT,
+            "seq_conflict",
+            "duplicate batch for agent",
+        ),
+        Err(err) => internal_or_storage_error(state, err, "failed to re-read the stored batch"),
+    }
+}
+
+async fn validate_chain(
+    tx: &mut Transaction<'_, Sqlite>,
+    batch: &LogBatch,
+    computed_hash: &[u8; 32],
+    accept_gap_markers: bool,
+    accept_after_close: bool,
+) -> Result<(), ChainRejection> {
+    use std::convert::TryInto;
+
+    // A marker skips seqs, so it is judged before the seq checks below, which
+    // then hold it to the seq right before its declared range.
+    if batch.gap.is_some() {
+        if !accept_gap_markers {
+            return Err(ChainRejection::GapRefused(
+                "gap markers are not accepted by this server".into(),
+            ));
+        }
+        batch.check_gap().map_err(ChainRejection::GapRefused)?;
+    }
+    batch.check_epoch().map_err(ChainRejection::EpochMismatch)?;
+    batch.check_kind().map_err(ChainRejection::MalformedKind)?;
+
+    let last_row = sqlx::query(
+        "SELECT epoch, seq, hash, accumulator, closed_reason FROM batches WHERE agent_id = ?1 ORDER BY epoch DESC, seq DESC LIMIT 1",
+    )
+    .bind(&batch.agent_id)
+    .fetch_optional(tx.as_mut())
+    .await
+    .map_err(|_| ChainRejection::Internal("failed to check chain state".into()))?;
+
+    let previous_accumulator = last_row.as_ref().and_then(stored_accumulator);
+
+    match last_row {
+        None => {
+            if batch.epoch != 0 {
+                return Err(ChainRejection::EpochMismatch(format!(
+                    "first batch for agent must be in epoch 0, got epoch {}",
+                    batch.epoch
+                )));
+            }
+            if batch.linked_seq() != 0 {
+                return Err(ChainRejection::SeqConflict(
+                    "first batch for agent must have seq=1".into(),
+                ));
+            }
+            if batch.prev_hash != [0u8; 32] {
+                return Err(ChainRejection::PrevHashMismatch(
+                    "first batch prev_hash must be all zeros".into(),
+                ));
+            }
+        }
+        Some(row) => {
+            let last_epoch = row.get::<i64, _>("epoch") as u64;
+            let last_seq: i64 = row.get("seq");
+            let last_hash_vec: Vec<u8> = row.get("hash");
+            let last_hash: [u8; 32] = last_hash_vec
+                .try_into()
+                .map_err(|_| ChainRejection::Internal("bad stored hash".into()))?;
+
+            if let Some(reason) = row.get::<Option<String>, _>("closed_reason")
+                && !accept_after_close
+            {
+                return Err(ChainRejection::ChainClosed(format!(
+                    "agent closed its chain at seq {last_seq}: {reason}"
+                )));
+            }
+
+            // An epoch start opens the next epoch and links to the last seq
+            // of this one; every other batch stays in this epoch.
+            match &batch.epoch_start {
+                Some(_) if batch.epoch != last_epoch + 1 => {
+                    return Err(ChainRejection::EpochMismatch(format!(
+                        "epoch start must open epoch {}, got epoch {}",
+                        last_epoch + 1,
+                        batch.epoch
+                    )));
+                }
+                Some(start) if start.previous_last_seq != last_seq as u64 => {
+                    return Err(ChainRejection::SeqConflict(format!(
+                        "epoch start must follow the last seq of epoch {last_epoch}: expected {last_seq}, got {}",
+                        start.previous_last_seq
+                    )));
+                }
+                None if batch.epoch != last_epoch => {
+                    return Err(ChainRejection::EpochMismatch(format!(
+                        "batch must continue epoch {last_epoch}, got epoch {}",
+                        batch.epoch
+                    )));
+                }
+                _ => {}
+            }
+
+            if batch.epoch_start.is_none() && batch.linked_seq() != last_seq as u64 {
+                if batch.gap.is_none()
+                    && let Some(stored) =
+                        stored_hash_at(tx.as_mut(), &batch.agent_id, batch.position())
+                            .await
+                            .map_err(|_| {
+                                ChainRejection::Internal("failed to check chain state".into())
+                            })?
+                {
+                    return Err(ChainRejection::SeqTaken(stored));
+                }
+                return Err(ChainRejection::SeqConflict(match &batch.gap {
+                    Some(gap) => format!(
+                        "gap must start right after the last seq: expected {}, got {}",
+                        last_seq + 1,
+                        gap.missing_from
+                    ),
+                    None => format!(
+                        "seq must increment: expected {}, got {}",
+                        last_seq + 1,
+                        batch.seq
+                    ),
+                }));
+            }
+
+            if batch.prev_hash != last_hash {
+                return Err(ChainRejection::PrevHashMismatch(
+                    "prev_hash does not match last hash".into(),
+                ));
+            }
+        }
+    }
+
+    // Once a chain carries accumulators every later batch must, so one cannot
+    // be dropped to hide a break.
+    match (&batch.accumulator, &previous_accumulator) {
+        (Some(acc), previous) if *acc != batch.expected_accumulator(previous.as_ref()) => {
+            return Err(ChainRejection::AccumulatorMismatch(
+                "accumulator does not extend the previous batch".into(),
+            ));
+        }
+        (None, Some(_)) => {
+            return Err(ChainRejection::AccumulatorMismatch(
+                "accumulator missing; previous batch carries one".into(),
+            ));
+        }
+        _ => {}
+    }
+
+    if batch.compute_hash() != *computed_hash {
+        return Err(ChainRejection::Internal("hash mismatch".into()));
+    }
+
+    Ok(())
+}
+
+fn stored_accumulator(row: &sqlx::sqlite::SqliteRow) -> Option<[u8; 32]> {
+    row.try_get::<Option<Vec<u8>>, _>("accumulator")
+        .ok()
+        .flatten()
+        .and_then(|v| v.try_into().ok())
+}
+
+fn stored_epoch_start(row: &sqlx::sqlite::SqliteRow) -> Option<EpochStart> {
+    let previous_last_seq = row
+        .try_get::<Option<i64>, _>("epoch_prev_seq")
+        .ok()
+        .flatten()?;
+    Some(EpochStart {
+        previous_last_seq: previous_last_seq as u64,
+    })
+}
+
+fn stored_gap(row: &sqlx::sqlite::SqliteRow) -> Option<GapRecord> {
+    let from = row.try_get::<Option<i64>, _>("gap_from").ok().flatten()?;
+    let to = row.try_get::<Option<i64>, _>("gap_to").ok().flatten()?;
+    Some(GapRecord {
+        missing_from: from as u64,
+        missing_to: to as u64,
+        reason: row
+            .try_get::<Option<String>, _>("gap_reason")
+            .ok()
+            .flatten()
+            .unwrap_or_default(),
+    })
+}
+
+/// Why a batch's key was refused. `Forbidden` reasons are only logged and
+/// audited; clients always see [`FORBIDDEN_MESSAGE`].
+enum AgentKeyRejection {
+    Forbidden(String),
+    Internal(String),
+    Storage(StorageFault),
+}
+
+impl AgentKeyRejection {
+    /// Maps a database error to `Storage` when it is a storage fault, and to
+    /// `Internal(reason)` otherwise.
+    fn internal(reason: &'static str) -> impl FnOnce(sqlx::Error) -> Self {
+        move |err| match StorageFault::of(&err) {
+            Some(fault) => AgentKeyRejection::Storage(fault),
+            None => AgentKeyRejection::Internal(reason.into()),
+        }
+    }
+}
+
+async fn ensure_agent_key(
+    state: &AppState,
+    tx: &mut Transaction<'_, Sqlite>,
+    batch: &LogBatch,
+) -> Result<(), AgentKeyRejection> {
+    // Verify-only servers may point at a read replica; never write the registry.
+    let auto_register = !state.require_registration && !state.verify_only;
+
+    if auto_register {
+        // Runs first so the transaction takes the write lock before reading:
+        // of two concurrent first submits for a new agent, the first key wins
+        // and the other waits, then is checked against it like any submit.
+        let inserted = sqlx::query(
+            "INSERT OR IGNORE INTO agents (agent_id, public_key, created_at) VALUES (?1, ?2, ?3)",
+        )
+        .bind(&batch.agent_id)
+        .bind(batch.public_key.to_bytes().to_vec())
+        .bind(now_unix())
+        .execute(tx.as_mut())
+        .await
+        .map_err(AgentKeyRejection::internal(
+            "failed to auto-register agent key",
+        ))?;
+
+        if inserted.rows_affected() == 1 {
+            if state.unique_agent_keys
+                && let Some(owner) = key_conflicts::other_owner(
+                    tx.as_mut(),
+                    batch.public_key.as_bytes(),
+                    &batch.agent_id,
+                )
+                .await
+                .map_err(AgentKeyRejection::internal("failed to check key owners"))?
+            {
+                return Err(AgentKeyRejection::Forbidden(
+                    key_conflicts::conflict_message(&owner),
+                ));
+            }
+            record_key(
+                tx.as_mut(),
+                &batch.agent_id,
+                batch.public_key.as_bytes(),
+                (0, 1),
+            )
+            .await
+            .map_err(AgentKeyRejection::internal(
+                "failed to record agent key history",
+            ))?;
+            return Ok(());
+        }
+    }
+
+    let Some(row) = read_agent_row(tx, &batch.agent_id).await? else {
+        if state.require_registration {
+            return Err(AgentKeyRejection::Forbidden(
+                "agent not registered; register key before sending batches".into(),
+            ));
+        }
+        if state.verify_only {
+            return Ok(());
+        }
+        return Err(AgentKeyRejection::Internal(
+            "agent registry row missing after auto-register".into(),
+        ));
+    };
+    if row.get::<Option<i64>, _>("revoked_at").is_some() {
+        return Err(AgentKeyRejection::Forbidden(
+            "agent has been revoked".into(),
+        ));
+    }
+    let windows = load_key_windows(tx.as_mut(), &batch.agent_id)
+        .await
+        .map_err(AgentKeyRejection::internal("failed to load key history"))?;
+    let authorized = if windows.is_empty() {
+        // Agents from before key history existed: only the current key.
+        Some(row.get::<Vec<u8>, _>("public_key"))
+    } else {
+        key_valid_at(&windows, batch.position()).map(|w| w.public_key.clone())
+    };
+    if authorized.as_deref() != Some(batch.public_key.as_bytes().as_slice()) {
+        return Err(AgentKeyRejection::Forbidden(format!(
+            "public key does not match a key registered for agent at {}",
+            position_label(batch.position())
+        )));
+    }
+
+    Ok(())
+}
+
+async fn read_agent_row(
+    tx: &mut Transaction<'_, Sqlite>,
+    agent_id: &str,
+) -> Result<Option<sqlx::sqlite::SqliteRow>, AgentKeyRejection> {
+    sqlx::query("SELECT public_key, revoked_at FROM agents WHERE agent_id = ?1")
+        .bind(agent_id)
+        .fetch_optional(tx.as_mut())
+        .await
+        .map_err(AgentKeyRejection::internal(
+            "failed to check agent registry",
+        ))
+}
+
+/* ----------------------- Agent key history ----------------------- */
+
+/// Bounds are positions, `(epoch, seq)`; the epochs are omitted while 0.
+#[derive(Serialize)]
+struct KeyWindow {
+    #[serde(serialize_with = "serialize_hex")]
+    public_key: Vec<u8>,
+    #[serde(skip_serializing_if = "is_zero")]
+    valid_from_epoch: u64,
+    valid_from_seq: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    valid_until_epoch: u64,
+    /// Exclusive; `None` for the current key.
+    valid_until_seq: Option<u64>,
+}
+
+impl KeyWindow {
+    fn covers(&self, position: (u64, u64)) -> bool {
+        (self.valid_from_epoch, self.valid_from_seq) <= position
+            && self
+                .valid_until_seq
+                .is_none_or(|until| position < (self.valid_until_epoch, until))
+    }
+}
+
+/// `seq N`, or `epoch E seq N` past the first epoch, for messages.
+pub(crate) fn position_label((epoch, seq): (u64, u64)) -> String {
+    match epoch {
+        0 => format!("seq {seq}"),
+        _ => format!("epoch {epoch} seq {seq}"),
+    }
+}
+
+fn serialize_hex<S: serde::Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&hex_encode(bytes))
+}
+
+fn key_valid_at(windows: &[KeyWindow], position: (u64, u64)) -> Option<&KeyWindow> {
+    windows.iter().find(|w| w.covers(position))
+}
+
+async fn load_key_windows(
+    conn: &mut sqlx::SqliteConnection,
+    agent_id: &str,
+) -> Result<Vec<KeyWindow>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT public_key, valid_from_epoch, valid_from_seq, valid_until_epoch, valid_until_seq FROM agent_keys WHERE agent_id = ?1 ORDER BY valid_from_epoch, valid_from_seq, id",
+    )
+    .bind(agent_id)
+    .fetch_all(conn)
+    .await?;
+
+    Ok(rows.iter().map(row_to_key_window).collect())
+}
+
+fn row_to_key_window(row: &sqlx::sqlite::SqliteRow) -> KeyWindow {
+    KeyWindow {
+        public_key: row.get("public_key"),
+        valid_from_epoch: row.get::<i64, _>("valid_from_epoch") as u64,
+        valid_from_seq: row.get::<i64, _>("valid_from_seq") as u64,
+        valid_until_epoch: row.get::<i64, _>("valid_until_epoch") as u64,
+        valid_until_seq: row
+            .get::<Option<i64>, _>("valid_until_seq")
+            .map(|v| v as u64),
+    }
+}
+
+/// Where the agent's next batch goes: right after its last one, or `(0, 1)`
+/// before its first. A batch opening the next epoch sorts later still, so a
+/// window starting here covers it too.
+async fn next_position(
+    conn: &mut sqlx::SqliteConnection,
+    agent_id: &str,
+) -> Result<(u64, u64), sqlx::Error> {
+    let last: Option<(i64, i64)> = sqlx::query_as(
+        "SELECT epoch, seq FROM batches WHERE agent_id = ?1 ORDER BY epoch DESC, seq DESC LIMIT 1",
+    )
+    .bind(agent_id)
+    .fetch_optional(conn)
+    .await?;
+    Ok(last.map_or((0, 1), |(epoch, seq)| (epoch as u64, seq as u64 + 1)))
+}
+
+/// Ends the agent's open-ended window right before `until`.
+async fn close_key_window(
+    conn: &mut sqlx::SqliteConnection,
+    agent_id: &str,
+    (epoch, seq): (u64, u64),
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE agent_keys SET valid_until_epoch = ?1, valid_until_seq = ?2 WHERE agent_id = ?3 AND valid_until_seq IS NULL",
+    )
+    .bind(epoch as i64)
+    .bind(seq as i64)
+    .bind(agent_id)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Opens an open-ended validity window for `public_key` starting at `from`.
+async fn record_key(
+    conn: &mut sqlx::SqliteConnection,
+    agent_id: &str,
+    public_key: &[u8],
+    (from_epoch, from_seq): (u64, u64),
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO agent_keys (agent_id, public_key, valid_from_epoch, valid_from_seq, valid_until_seq, created_at) VALUES (?1, ?2, ?3, ?4, NULL, ?5)",
+    )
+    .bind(agent_id)
+    .bind(public_key.to_vec())
+    .bind(from_epoch as i64)
+    .bind(from_seq as i64)
+    .bind(now_unix())
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Reconstructs key history for agents registered before it was tracked: each
+/// key stored in their batches covers the seqs it signed, in order, and the
+/// registered key stays open-ended.
+async fn backfill_key_history(pool: &SqlitePool) {
+    let agents = sqlx::query(
+        "SELECT agent_id, public_key FROM agents WHERE agent_id NOT IN (SELECT agent_id FROM agent_keys)",
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap();
+
+    for agent in agents {
+        let agent_id: String = agent.get("agent_id");
+        let current: Vec<u8> = agent.get("public_key");
+        let used = sqlx::query(
+            "SELECT public_key, MIN(seq) AS first_seq, MAX(seq) AS last_seq FROM batches WHERE agent_id = ?1 GROUP BY public_key ORDER BY first_seq",
+        )
+        .bind(&agent_id)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+
+        let mut windows: Vec<(Vec<u8>, i64, Option<i64>)> = Vec::new();
+        for (i, row) in used.iter().enumerate() {
+            let key: Vec<u8> = row.get("public_key");
+            let from = if i == 0 { 1 } else { row.get("first_seq") };
+            let until = match used.get(i + 1) {
+                Some(next) => Some(next.get::<i64, _>("first_seq")),
+                None if key == current => None,
+                None => Some(row.get::<i64, _>("last_seq") + 1),
+            };
+            windows.push((key, from, until));
+        }
+        match windows.last() {
+            Some((_, _, None)) => {}
+            Some((_, _, Some(until))) => windows.push((current, *until, None)),
+            None => windows.push((current, 1, None)),
+        }
+
+        for (key, from, until) in windows {
+            sqlx::query(
+                "INSERT INTO agent_keys (agent_id, public_key, valid_from_seq, valid_until_seq, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )
+            .bind(&agent_id)
+            .bind(key)
+            .bind(from)
+            .bind(until)
+            .bind(now_unix())
+            .execute(pool)
+            .await
+            .unwrap();
+        }
+    }
+}
+
+/* ----------------------- GET /agents/:agent_id/keys ----------------------- */
+
+async fn handler_agent_keys(
+    State(state): State<AppState>,
+    Path(agent_id): Path<String>,
+) -> Result<Json<Vec<KeyWindow>>, StatusCode> {
+    let mut conn = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let windows = load_key_windows(&mut conn, &agent_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if windows.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(Json(windows))
+}
+
+fn parse_hex_public_key(hex: &str) -> Result<VerifyingKey, String> {
+    let bytes = hex_decode_fixed::<32>(hex).map_err(|err| err.to_string())?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| "invalid public key bytes".into())
+}
+
+fn parse_hex_signature(hex: &str) -> Result<Signature, String> {
+    let bytes = hex_decode_fixed::<64>(hex).map_err(|err| err.to_string())?;
+    Ok(Signature::from_bytes(&bytes))
+}
+
+/// Gzips the logs JSON, or returns `None` when it is shorter than `min_bytes`:
+/// the gzip header alone would make tiny batches larger than their plaintext.
+/// The one encoding of `logs` the server stores: a compact JSON array of the
+/// lines in signed order, escaped by serde_json's fixed rules (only `"`, `\`
+/// and control characters; other UTF-8 kept as is). Lines are stored verbatim:
+/// a structured (JSON) line keeps the agent's key order and spacing, because
+/// the signature covers its exact bytes and any re-ordering would break it.
+fn canonical_logs_json(logs: &[String]) -> String {
+    serde_json::to_string(logs).expect("a list of strings always serializes")
+}
+
+/// Lines from a stored `logs` column, and whether the column held exactly
+/// their canonical encoding. The lines, and so the signature check, do not
+/// depend on that; a non-canonical column means it was written by something
+/// other than the server, which the admin integrity check reports.
+fn parse_stored_logs(stored: &str) -> Result<(Vec<String>, bool), String> {
+    let logs: Vec<String> = serde_json::from_str(stored).map_err(|e| e.to_string())?;
+    let canonical = canonical_logs_json(&logs) == stored;
+    Ok((logs, canonical))
+}
+
+/// Gzip, or zstd with `dict` once [`dicts`] has trained one.
+fn compress_json(
+    data: &str,
+    level: Compression,
+    min_bytes: usize,
+    dict: Option<&dicts::Dict>,
+) -> Result<Option<Vec<u8>>, String> {
+    if data.len() < min_bytes {
+        return Ok(None);
+    }
+    match dict {
+        Some(dict) => dict.compress(data.as_bytes()).map(Some),
+        None => compress_bytes(data.as_bytes(), level).map(Some),
+    }
+}
+
+/// Feeds the per-batch compression ratio into byte counters; the ratio over any
+/// window is `stored / plain`. Batches kept plaintext count as stored == plain.
+/// Verify-only servers count under their own family (see [`submit_metric`]).
+fn record_compression(state: &AppState, plain: usize, compressed: Option<&[u8]>) {
+    state.metrics.add(
+        &submit_metric(state, "logs_plain_bytes_total"),
+        plain as u64,
+    );
+    let stored = submit_metric(state, "logs_stored_bytes_total");
+    match compressed {
+        Some(blob) => state.metrics.add(&stored, blob.len() as u64),
+        None => {
+            state.metrics.add(&stored, plain as u64);
+            state
+                .metrics
+                .inc(&submit_metric(state, "logs_uncompressed_batches_total"));
+        }
+    }
+}
+
+fn compress_bytes(data: &[u8], level: Compression) -> Result<Vec<u8>, String> {
+    let mut encoder = GzEncoder::new(Vec::new(), level);
+    encoder.write_all(data).map_err(|e| e.to_string())?;
+    encoder.finish().map_err(|e| e.to_string())
+}
+
+const EPHEMERAL_DATABASE_URL: &str = "sqlite::memory:";
+
+/// `sqlite::memory:`, `sqlite://:memory:` or any URL with `mode=memory`.
+fn is_memory_url(url: &str) -> bool {
+    url.contains(":memory:") || url.contains("mode=memory")
+}
+
+/// An in-memory database lives only as long as a connection to it, and sqlx
+/// reaps idle connections, so a default pool eventually hands out a fresh,
+/// empty database. Memory URLs therefore get one connection that is never
+/// recycled; every request sees the same schema and data.
+async fn connect_pool(url: &str, ack_mode: ack::AckMode) -> Result<SqlitePool, sqlx::Error> {
+    let options = ack_mode.pool_options();
+    if !is_memory_url(url) {
+        return options.connect(url).await;
+    }
+    options
+        .max_connections(1)
+        .min_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect(url)
+        .await
+}
+
+async fn configure_sqlite(pool: &SqlitePool) {
+    // WAL improves durability and allows concurrent readers.
+    let _ = sqlx::query("PRAGMA journal_mode=WAL").execute(pool).await;
+    let _ = sqlx::query("PRAGMA synchronous=FULL").execute(pool).await;
+}
+
+async fn snapshot_database(pool: &SqlitePool, path: &str) -> Result<(), sqlx::Error> {
+    let escaped = path.replace('\'', "''");
+    let vacuum_sql = format!("VACUUM INTO '{escaped}'");
+    sqlx::query(&vacuum_sql).execute(pool).await.map(|_| ())
+}
+
+async fn ensure_column(pool: &SqlitePool, table: &str, column: &str, definition: &str) {
+    let sql = format!(
+        "SELECT 1 FROM pragma_table_info('{table}') WHERE name = ?1"
+    );
+    let exists: Option<(i64,)> = sqlx::query_as(&sql)
+        .bind(column)
+        .fetch_optional(pool)
+        .await
+        .ok()
+        .flatten();
+
+    if exists.is_some() {
+        return;
+    }
+
+    let alter = format!("ALTER TABLE {table} ADD COLUMN {column} {definition}");
+    let _ = sqlx::query(&alter).execute(pool).await;
+}
+
+/// Fills `logs_size` / `logs_compressed_size` for rows stored before they
+/// existed. The sizes are derived from the stored blobs and touch no hashed
+/// content; the update trigger is dropped here and recreated right after by
+/// `ensure_append_only_triggers`.
+async fn backfill_payload_sizes(pool: &SqlitePool) {
+    let _ = sqlx::query("DROP TRIGGER IF EXISTS batches_no_update")
+        .execute(pool)
+        .await;
+    sqlx::query(
+        "UPDATE batches SET logs_size = LENGTH(CAST(logs AS BLOB)), logs_compressed_size = LENGTH(logs_compressed) WHERE logs_size IS NULL",
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+async fn ensure_append_only_triggers(pool: &SqlitePool) {
+    for (name, event) in [
+        ("maintenance_events_no_update", "UPDATE"),
+        ("maintenance_events_no_delete", "DELETE"),
+    ] {
+        sqlx::query(&format!(
+            "CREATE TRIGGER IF NOT EXISTS {name} BEFORE {event} ON maintenance_events \
+             BEGIN SELECT RAISE(ABORT, 'append-only: maintenance events are kept'); END;"
+        ))
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    // Receipts are issued once and never rewritten, so an issued_at cannot
+    // be moved. The insert check also catches INSERT OR REPLACE, whose
+    // implicit delete skips delete triggers.
+    for (name, event, when) in [
+        (
+            "receipts_issued_once",
+            "INSERT",
+            "WHEN EXISTS (SELECT 1 FROM receipts WHERE batch_id = NEW.batch_id)",
+        ),
+        ("receipts_no_update", "UPDATE", ""),
+        ("receipts_no_delete", "DELETE", ""),
+    ] {
+        sqlx::query(&format!(
+            "CREATE TRIGGER IF NOT EXISTS {name} BEFORE {event} ON receipts {when} \
+             BEGIN SELECT RAISE(ABORT, 'append-only: receipts are issued once'); END;"
+        ))
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+    // Forks are acknowledged once and kept. A receipt uploaded again is
+    // skipped rather than refused, so a report can be resent whole.
+    sqlx::query(
+        "CREATE TRIGGER IF NOT EXISTS forks_recorded_once BEFORE INSERT ON forks \
+         WHEN EXISTS (SELECT 1 FROM forks WHERE agent_id = NEW.agent_id AND seq = NEW.seq AND receipt_hash = NEW.receipt_hash) \
+         BEGIN SELECT RAISE(IGNORE); END;",
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    for (name, event) in [("forks_no_update", "UPDATE"), ("forks_no_delete", "DELETE")] {
+        sqlx::query(&format!(
+            "CREATE TRIGGER IF NOT EXISTS {name} BEFORE {event} ON forks \
+             BEGIN SELECT RAISE(ABORT, 'append-only: forks are kept'); END;"
+        ))
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+    // A blob compressed with a dictionary is unreadable without it.
+    for (name, event) in [
+        ("compression_dicts_no_update", "UPDATE"),
+        ("compression_dicts_no_delete", "DELETE"),
+    ] {
+        sqlx::query(&format!(
+            "CREATE TRIGGER IF NOT EXISTS {name} BEFORE {event} ON compression_dicts \
+             BEGIN SELECT RAISE(ABORT, 'append-only: compression dictionaries are immutable'); END;"
+        ))
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+    // Summaries are written once per agent and day and kept.
+    sqlx::query(
+        "CREATE TRIGGER IF NOT EXISTS daily_summaries_written_once BEFORE INSERT ON daily_summaries \
+         WHEN EXISTS (SELECT 1 FROM daily_summaries WHERE agent_id = NEW.agent_id AND day = NEW.day) \
+         BEGIN SELECT RAISE(IGNORE); END;",
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    for (name, event) in [
+        ("daily_summaries_no_update", "UPDATE"),
+        ("daily_summaries_no_delete", "DELETE"),
+    ] {
+        sqlx::query(&format!(
+            "CREATE TRIGGER IF NOT EXISTS {name} BEFORE {event} ON daily_summaries \
+             BEGIN SELECT RAISE(ABORT, 'append-only: daily summaries are kept'); END;"
+        ))
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+    // Whatever archives batch content one day must summarize its day first.
+    sqlx::query(
+        "CREATE TRIGGER IF NOT EXISTS batches_delete_needs_summary BEFORE DELETE ON batches \
+         WHEN NOT EXISTS (SELECT 1 FROM daily_summaries WHERE agent_id = OLD.agent_id \
+             AND day = strftime('%Y-%m-%d', COALESCE(OLD.received_at_ms, OLD.received_at * 1000) / 1000, 'unixepoch')) \
+         BEGIN SELECT RAISE(ABORT, 'batch day has no daily summary'); END;",
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    // Server keys are only ever retired, once.
+    for (name, event, when) in [
+        (
+            "server_keys_retire_only",
+            "UPDATE",
+            "WHEN OLD.retired_at_ms IS NOT NULL OR NEW.id IS NOT OLD.id \
+             OR NEW.public_key IS NOT OLD.public_key OR NEW.signing_key IS NOT OLD.signing_key \
+             OR NEW.created_at_ms IS NOT OLD.created_at_ms",
+        ),
+        ("server_keys_no_delete", "DELETE", ""),
+    ] {
+        sqlx::query(&format!(
+            "CREATE TRIGGER IF NOT EXISTS {name} BEFORE {event} ON server_keys {when} \
+             BEGIN SELECT RAISE(ABORT, 'append-only: server keys are only retired'); END;"
+        ))
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    // Stubs are written once and kept, like the blobs they point to.
+    for (name, event) in [
+        ("blob_locations_no_update", "UPDATE"),
+        ("blob_locations_no_delete", "DELETE"),
+    ] {
+        sqlx::query(&format!(
+            "CREATE TRIGGER IF NOT EXISTS {name} BEFORE {event} ON blob_locations \
+             BEGIN SELECT RAISE(ABORT, 'append-only: blob locations are kept'); END;"
+        ))
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    // Redactions are recorded once and kept.
+    for (name, event) in [
+        ("redactions_no_update", "UPDATE"),
+        ("redactions_no_delete", "DELETE"),
+    ] {
+        sqlx::query(&format!(
+            "CREATE TRIGGER IF NOT EXISTS {name} BEFORE {event} ON redactions \
+             BEGIN SELECT RAISE(ABORT, 'append-only: redactions are kept'); END;"
+        ))
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    // Timestamps are kept like the batches they prove.
+    for table in ["tsa_tokens", "tsa_leaves"] {
+        for event in ["UPDATE", "DELETE"] {
+            sqlx::query(&format!(
+                "CREATE TRIGGER IF NOT EXISTS {table}_no_{} BEFORE {event} ON {table} \
+                 BEGIN SELECT RAISE(ABORT, 'append-only: timestamps are kept'); END;",
+                event.to_lowercase()
+            ))
+            .execute(pool)
+            .await
+            .unwrap();
+        }
+    }
+
+    // Block updates/deletes to enforce append-only. Two updates are allowed,
+    // with every other column, whatever columns exist by now, left as it
+    // was. Tiering clears `logs_compressed` once its stub is recorded. A
+    // redaction rewrites the stored copies of the logs, and drops the raw
+    // body, once its record is in `redactions`.
+    let columns: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info('batches')")
+        .fetch_all(pool)
+        .await
+        .unwrap();
+    let unchanged_but = |rewritten: &[&str]| -> String {
+        columns
+            .iter()
+            .filter(|column| !rewritten.contains(&column.as_str()))
+            .map(|column| format!(" AND NEW.{column} IS OLD.{column}"))
+            .collect()
+    };
+    let unchanged = unchanged_but(&["logs_compressed"]);
+    let redacted = unchanged_but(&[
+        "logs",
+        "logs_compressed",
+        "logs_size",
+        "logs_compressed_size",
+        "raw_body",
+        "raw_content_type",
+    ]);
+    let _ = sqlx::query("DROP TRIGGER IF EXISTS batches_no_update")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("DROP TRIGGER IF EXISTS batches_no_delete")
+        .execute(pool)
+        .await;
+    let _ = sqlx::query("DROP TRIGGER IF EXISTS batches_enforce_seq")
+        .execute(pool)
+        .await;
+
+    sqlx::query(&format!(
+        r#"
+        CREATE TRIGGER batches_no_update
+        BEFORE UPDATE ON batches
+        WHEN NOT (OLD.logs_compressed IS NOT NULL AND NEW.logs_compressed IS NULL
+            AND EXISTS (SELECT 1 FROM blob_locations WHERE batch_id = OLD.id){unchanged})
+        AND NOT (EXISTS (SELECT 1 FROM redactions WHERE batch_id = OLD.id){redacted})
+        BEGIN
+            SELECT RAISE(ABORT, 'append-only: updates forbidden');
+        END;
+        "#,
+    ))
+    .execute(pool)
+    .await
+    .ok();
+
+    sqlx::query(
+        r#"
+        CREATE TRIGGER batches_no_delete
+        BEFORE DELETE ON batches
+        BEGIN
+            SELECT RAISE(ABORT, 'append-only: deletes forbidden');
+        END;
+        "#,
+    )
+    .execute(pool)
+    .await
+    .ok();
+
+    // Enforce monotonic seq and hash linkage per agent even if someone bypasses the API.
+    sqlx::query(
+        r#"
+        CREATE TRIGGER batches_enforce_seq
+        BEFORE INSERT ON batches
+        BEGIN
+            -- A gap marker must end right before its own seq.
+            SELECT
+                CASE
+                    WHEN NEW.gap_from IS NOT NULL AND NEW.gap_to IS NOT NEW.seq - 1 THEN
+                        RAISE(ABORT, 'append-only: gap must end before its marker')
+                END;
+            -- Detect last state for this agent. A gap marker links to the seq
+            -- before its range instead of the one before its own, and an
+            -- epoch's first batch to the last seq of the epoch before.
+            SELECT
+                CASE
+                    WHEN (SELECT COUNT(*) FROM batches WHERE agent_id = NEW.agent_id) = 0 THEN
+                        CASE
+                            WHEN NEW.epoch != 0 THEN
+                                RAISE(ABORT, 'append-only: first epoch must be 0')
+                            WHEN COALESCE(NEW.gap_from, NEW.seq) != 1 THEN
+                                RAISE(ABORT, 'append-only: first seq must be 1')
+                            WHEN NEW.prev_hash != zeroblob(32) THEN
+                                RAISE(ABORT, 'append-only: first prev_hash must be zero')
+                        END
+                    WHEN NEW.epoch_prev_seq IS NOT NULL THEN
+                        CASE
+                            WHEN NEW.seq != 1 THEN
+                                RAISE(ABORT, 'append-only: epoch must start at seq 1')
+                            WHEN NEW.epoch != (SELECT epoch + 1 FROM batches WHERE agent_id = NEW.agent_id ORDER BY epoch DESC, seq DESC LIMIT 1) THEN
+                                RAISE(ABORT, 'append-only: non-contiguous epoch')
+                            WHEN NEW.epoch_prev_seq != (SELECT seq FROM batches WHERE agent_id = NEW.agent_id ORDER BY epoch DESC, seq DESC LIMIT 1) THEN
+                                RAISE(ABORT, 'append-only: epoch start does not follow the last seq')
+                            WHEN NEW.prev_hash != (SELECT hash FROM batches WHERE agent_id = NEW.agent_id ORDER BY epoch DESC, seq DESC LIMIT 1) THEN
+                                RAISE(ABORT, 'append-only: prev_hash mismatch')
+                        END
+                    ELSE
+                        CASE
+                            WHEN NEW.epoch != (SELECT epoch FROM batches WHERE agent_id = NEW.agent_id ORDER BY epoch DESC, seq DESC LIMIT 1) THEN
+                                RAISE(ABORT, 'append-only: non-contiguous epoch')
+                            WHEN COALESCE(NEW.gap_from, NEW.seq) != (SELECT seq + 1 FROM batches WHERE agent_id = NEW.agent_id ORDER BY epoch DESC, seq DESC LIMIT 1) THEN
+                                RAISE(ABORT, 'append-only: non-contiguous seq')
+                            WHEN NEW.prev_hash != (SELECT hash FROM batches WHERE agent_id = NEW.agent_id ORDER BY epoch DESC, seq DESC LIMIT 1) THEN
+                                RAISE(ABORT, 'append-only: prev_hash mismatch')
+                        END
+                END;
+        END;
+        "#,
+    )
+    .execute(pool)
+    .await
+    .ok();
+}
+
+/// `server --fsck`: checks the whole store, prints the report as JSON and
+/// exits 0 when clean, 1 on discrepancies. Ctrl-C stops the scan between
+/// chunks and prints what was found so far, exiting 2.
+async fn run_fsck(
+    pool: &SqlitePool,
+    decompressor: &decompress::Decompressor,
+    chunk_rows: u64,
+) -> ! {
+    let abort = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let on_signal = abort.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("[fsck] interrupted; stopping after the current chunk");
+            on_signal.store(true, std::sync::atomic::Ordering::Relaxed);
+        }
+    });
+
+    let report = std::sync::Mutex::new(fsck::FsckReport::default());
+    if let Err(err) = fsck::scan(pool, decompressor, chunk_rows, &abort, &report).await {
+        eprintln!("[fsck] scan failed: {err}");
+        std::process::exit(2);
+    }
+    let report = report.into_inner().unwrap();
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    eprintln!(
+        "[fsck] {} rows checked, {} discrepancies{}",
+        report.rows_checked,
+        report.discrepancies.len(),
+        if report.complete { "" } else { " (incomplete)" }
+    );
+    std::process::exit(match (report.complete, report.ok()) {
+        (false, _) => 2,
+        (true, true) => 0,
+        (true, false) => 1,
+    })
+}
+
+fn now_unix() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn now_unix_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::Response;
+    use common::batch::generate_keypair;
+    use common::rotation::{RotateRequest, rotation_envelope, rotation_message};
+    use common::testkit::ChainSimulator;
+    use decompress::Decompressor;
+    use ed25519_dalek::{Signer, SigningKey};
+    use rotation::{consume_rotation_nonce, handler_rotate_agent};
+    use sqlx::ConnectOptions;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn test_state() -> AppState {
+        let pool = connect_pool(EPHEMERAL_DATABASE_URL, ack::AckMode::Durable)
+            .await
+            .unwrap();
+        state_with_pool(pool).await
+    }
+
+    /// A WAL database file with several connections, so transactions really
+    /// run concurrently (the in-memory pool serializes them).
+    async fn file_state(name: &str) -> AppState {
+        let path = std::env::temp_dir().join(format!("logchain-{name}-{}.db", std::process::id()));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+        let pool = SqlitePoolOptions::new()
+            .max_connections(4)
+            .connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        configure_sqlite(&pool).await;
+        state_with_pool(pool).await
+    }
+
+    async fn state_with_pool(pool: SqlitePool) -> AppState {
+        init_schema(&pool).await;
+        let receipts = Arc::new(receipts::ReceiptSigner::load(&pool).await.unwrap());
+
+        AppState {
+            pool,
+            require_registration: false,
+            rate_limits: Arc::new(RateLimits::new([(
+                LimitGroup::Submit,
+                RouteLimit {
+                    max: 1000,
+                    window_secs: 60,
+                    key: LimitKey::Agent,
+                    algo: RateAlgo::FixedWindow,
+                },
+            )])),
+            auth_failures: Arc::new(RateLimiter::new(3, StdDuration::from_secs(60))),
+            auth_token: None,
+            verify_only: false,
+            store_raw_body: false,
+            store_client_info: false,
+            tls_fingerprint_header: None,
+            log_requests: false,
+            metrics: Arc::new(Metrics::new()),
+            storage: Arc::new(StorageHealth::default()),
+            ingest: Arc::new(IngestState::new(IngestConfig {
+                token: Some("ingest-secret".into()),
+                batch_lines: 100,
+                max_body_bytes: 1024,
+                max_buffered_lines: ingest::DEFAULT_MAX_BUFFERED_LINES,
+            })),
+            verify_workers: Arc::new(Semaphore::new(2)),
+            compression_level: Compression::default(),
+            compression_min_bytes: 64,
+            compression_dict: Arc::default(),
+            decompressor: Decompressor::default(),
+            agent_size_metrics: true,
+            admin_token: Some("admin-secret".into()),
+            snapshot_path: None,
+            blob_store: None,
+            clock_drift_alert_ms: 60_000,
+            stale_agent_secs: 300,
+            anomaly: None,
+            unique_agent_keys: false,
+            rotation: rotation::RotationPolicy::default(),
+            accept_gap_markers: false,
+            accept_after_close: false,
+            fsck: Arc::new(fsck::FsckJobs::new(fsck::DEFAULT_CHUNK_ROWS)),
+            watermarks: None,
+            checkpoints: Arc::new(checkpoint_cache::CheckpointCache::new(
+                StdDuration::from_millis(checkpoint_cache::DEFAULT_MAX_AGE_MS),
+            )),
+            idempotency: Arc::new(idempotency::Idempotency::new(idempotency::DEFAULT_TTL_SECS)),
+            receipts,
+            timeouts: timeout::Timeouts::default(),
+            maintenance: Arc::default(),
+            ack_mode: ack::AckMode::Durable,
+        }
+    }
+
+    fn signed_batch(key: &SigningKey, seq: u64, prev_hash: [u8; 32], line: &str) -> LogBatch {
+        let mut batch = LogBatch {
+            prev_hash,
+            logs: vec![line.to_string()],
+            timestamp: 1_700_000_000 + seq,
+            agent_id: "agent-test".into(),
+            seq,
+            signature: Signature::from_bytes(&[0u8; 64]),
+            public_key: key.verifying_key(),
+            lines_read: None,
+            version: BATCH_VERSION_V1,
+            accumulator: None,
+            gap: None,
+            epoch: 0,
+            epoch_start: None,
+            kind: None,
+        };
+        batch.sign(key);
+        batch
+    }
+
+    /// `len` batches of one simulated agent, `sim-agent-000`, from `seed`.
+    fn simulated_chain(seed: u64, len: u64) -> Vec<LogBatch> {
+        let mut sim = ChainSimulator::new(seed, 1);
+        (0..len).map(|_| sim.next_batch(0)).collect()
+    }
+
+    async fn submit(state: &AppState, batch: &LogBatch) -> Response {
+        submit_with(state, HeaderMap::new(), batch).await
+    }
+
+    async fn submit_with(state: &AppState, headers: HeaderMap, batch: &LogBatch) -> Response {
+        handler_submit_batch(
+            State(state.clone()),
+            ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 9000))),
+            authed(state, headers.clone()).await,
+            headers,
+            Bytes::from(serde_json::to_vec(batch).unwrap()),
+        )
+        .await
+        .into_response()
+    }
+
+    async fn body_text(resp: Response) -> String {
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", format!("Bearer {token}").parse().unwrap());
+        headers
+    }
+
+    /// What the auth middleware attaches for a request with `headers`.
+    async fn authed(state: &AppState, headers: HeaderMap) -> Extension<AuthContext> {
+        Extension(auth::resolve(state, &headers).await)
+    }
+
+    fn rejected(state: &AppState, reason: &str) -> u64 {
+        state
+            .metrics
+            .get(&labeled("logchain_submit_rejected_total", "reason", reason))
+    }
+
+    #[tokio::test]
+    async fn json_and_msgpack_submits_store_the_same_batches() {
+        use common::testutil::build_chain;
+
+        let state = AppState {
+            store_raw_body: true,
+            ..test_state().await
+        };
+        let key = generate_keypair();
+        let chain = build_chain(&key, "agent-w", 2);
+        let send = |format: WireFormat, body: Vec<u8>| {
+            let state = state.clone();
+            async move {
+                let mut headers = HeaderMap::new();
+                headers.insert(header::CONTENT_TYPE, format.content_type().parse().unwrap());
+                handler_submit_batch(
+                    State(state.clone()),
+                    ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 9000))),
+                    authed(&state, headers.clone()).await,
+                    headers,
+                    Bytes::from(body),
+                )
+                .await
+                .into_response()
+            }
+        };
+
+        let msgpack = WireFormat::Msgpack.encode(&chain[1]).unwrap();
+        let resp = send(
+            WireFormat::Json,
+            WireFormat::Json.encode(&chain[0]).unwrap(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(
+            send(WireFormat::Msgpack, msgpack.clone()).await.status(),
+            StatusCode::CREATED
+        );
+        let stored = list(&state, ListParams::default()).await;
+        assert_eq!(stored.len(), 2);
+        for (stored, sent) in stored.iter().zip(&chain) {
+            assert!(stored.batch.verify());
+            assert_eq!(stored.batch.compute_hash(), sent.compute_hash());
+        }
+
+        // The archived body is the one sent, under its own content type.
+        let raw = route(&state, "GET", "/batches/2/raw", None, Vec::new(), 1).await;
+        assert_eq!(raw.headers()[header::CONTENT_TYPE], "application/msgpack");
+        let body = axum::body::to_bytes(raw.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body.to_vec(), msgpack);
+
+        let garbled = send(WireFormat::Msgpack, b"{\"seq\":1}".to_vec()).await;
+        assert_eq!(garbled.status(), StatusCode::BAD_REQUEST);
+        assert!(
+            body_text(garbled)
+                .await
+                .contains("invalid batch MessagePack")
+        );
+    }
+
+    #[tokio::test]
+    async fn identical_resend_is_success_and_counted_as_duplicate() {
+        let state = test_state().await;
+        let key = generate_keypair();
+        let first = signed_batch(&key, 1, [0u8; 32], "a");
+
+        assert_eq!(submit(&state, &first).await.status(), StatusCode::CREATED);
+        assert_eq!(submit(&state, &first).await.status(), StatusCode::OK);
+
+        assert_eq!(state.metrics.get("logchain_submit_accepted_total"), 1);
+        assert_eq!(
+            state.metrics.get("logchain_submit_duplicate_resends_total"),
+            1
+        );
+        assert_eq!(rejected(&state, "seq_conflict"), 0);
+    }
+
+    #[tokio::test]
+    async fn different_content_at_used_seq_counts_as_seq_conflict() {
+        let state = test_state().await;
+        let key = generate_keypair();
+        let first = signed_batch(&key, 1, [0u8; 32], "a");
+        let clash = signed_batch(&key, 1, [0u8; 32], "b");
+
+        submit(&state, &first).await;
+        assert_eq!(submit(&state, &clash).await.status(), StatusCode::CONFLICT);
+
+        assert_eq!(rejected(&state, "seq_conflict"), 1);
+        assert_eq!(rejected(&state, "prev_hash_mismatch"), 0);
+        assert_eq!(
+            state.metrics.get("logchain_submit_duplicate_resends_total"),
+            0
+        );
+    }
+
+    async fn body_json(resp: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    async fn table_rows(state: &AppState, table: &str) -> i64 {
+        sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+            .fetch_one(&state.pool)
+            .await
+            .unwrap()
+    }
+
+    /// Asserts a verify-only server wrote nothing and counted only under
+    /// the `logchain_verify_only_` family.
+    async fn assert_nothing_stored(state: &AppState) {
+        for table in ["batches", "rejections", "agents"] {
+            assert_eq!(table_rows(state, table).await, 0, "{table}");
+        }
+        for line in state.metrics.render().lines() {
+            assert!(line.starts_with("logchain_verify_only_"), "{line}");
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_only_reports_a_would_store_without_storing() {
+        let state = AppState {
+            verify_only: true,
+            ..test_state().await
+        };
+        let key = generate_keypair();
+
+        let resp = submit(&state, &signed_batch(&key, 1, [0u8; 32], "a")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body_json(resp).await["status"], "would_store");
+
+        assert_nothing_stored(&state).await;
+        assert_eq!(
+            state
+                .metrics
+                .get("logchain_verify_only_submit_accepted_total"),
+            1
+        );
+        assert!(
+            state
+                .metrics
+                .get("logchain_verify_only_logs_plain_bytes_total")
+                > 0
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_only_reports_a_would_reject_without_storing() {
+        let state = AppState {
+            verify_only: true,
+            ..test_state().await
+        };
+        let key = generate_keypair();
+        let mut forged = signed_batch(&key, 1, [0u8; 32], "a");
+        forged.logs = vec!["b".into()];
+
+        let resp = submit(&state, &forged).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body_json(resp).await["status"],
+            "would_reject:invalid signature"
+        );
+
+        assert_nothing_stored(&state).await;
+        let series = labeled(
+            "logchain_verify_only_submit_rejected_total",
+            "reason",
+            "invalid_signature",
+        );
+        assert_eq!(state.metrics.get(&series), 1);
+    }
+
+    #[tokio::test]
+    async fn wrong_prev_hash_counts_as_prev_hash_mismatch() {
+        let state = test_state().await;
+        let key = generate_keypair();
+        let first = signed_batch(&key, 1, [0u8; 32], "a");
+        let broken = signed_batch(&key, 2, [9u8; 32], "b");
+
+        submit(&state, &first).await;
+        assert_eq!(submit(&state, &broken).await.status(), StatusCode::CONFLICT);
+
+        assert_eq!(rejected(&state, "prev_hash_mismatch"), 1);
+        assert_eq!(rejected(&state, "seq_conflict"), 0);
+    }
+
+    async fn ingest(state: &AppState, token: &str, body: &str) -> StatusCode {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", format!("Bearer {token}").parse().unwrap());
+        let (code, _) = ingest::handler_ingest(
+            State(state.clone()),
+            Path("app".to_string()),
+            headers,
+            Bytes::from(body.to_string()),
+        )
+        .await;
+        code
+    }
+
+    #[tokio::test]
+    async fn ingested_lines_form_a_server_signed_chain() {
+        let state = test_state().await;
+        assert_eq!(
+            ingest(&state, "ingest-secret", r#"["one","two"]"#).await,
+            StatusCode::ACCEPTED
+        );
+        ingest::flush_all(&state).await;
+        assert_eq!(
+            ingest(&state, "ingest-secret", "\"three\"\n{\"k\":1}\n").await,
+            StatusCode::ACCEPTED
+        );
+        ingest::flush_all(&state).await;
+
+        let batches = list(
+            &state,
+            ListParams {
+                agent_id: Some("ingest:app".into()),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(batches.len(), 2);
+        assert_eq!(
+            batches[1].batch.logs,
+            vec!["three".to_string(), r#"{"k":1}"#.to_string()]
+        );
+        assert_eq!(batches[1].batch.prev_hash, batches[0].hash);
+
+        // The trust anchor is the server: every batch is signed by the key it holds.
+        let stored: Vec<u8> =
+            sqlx::query_scalar("SELECT signing_key FROM ingest_keys WHERE source_name = 'app'")
+                .fetch_one(&state.pool)
+                .await
+                .unwrap();
+        let server_key = SigningKey::from_bytes(&stored.try_into().unwrap());
+        for entry in &batches {
+            assert!(entry.batch.verify());
+            assert_eq!(entry.batch.public_key, server_key.verifying_key());
+        }
+    }
+
+    #[tokio::test]
+    async fn ingest_buffers_stop_growing_while_flushes_fail() {
+        let state = AppState {
+            ingest: Arc::new(IngestState::new(IngestConfig {
+                token: Some("ingest-secret".into()),
+                batch_lines: 100,
+                max_body_bytes: 1024,
+                max_buffered_lines: 3,
+            })),
+            ..file_state("ingest-full").await
+        };
+        let path =
+            std::env::temp_dir().join(format!("logchain-ingest-full-{}.db", std::process::id()));
+        let broken = AppState {
+            pool: SqlitePoolOptions::new()
+                .connect(&format!("sqlite://{}?mode=ro", path.display()))
+                .await
+                .unwrap(),
+            ..state.clone()
+        };
+
+        // The flush fails and keeps its lines for the next one.
+        assert_eq!(
+            ingest(&broken, "ingest-secret", r#"["a","b"]"#).await,
+            StatusCode::ACCEPTED
+        );
+        ingest::flush_all(&broken).await;
+        // Past the cap a post is refused whole; up to it, it still fits.
+        assert_eq!(
+            ingest(&broken, "ingest-secret", r#"["c","d"]"#).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            ingest(&broken, "ingest-secret", r#"["c"]"#).await,
+            StatusCode::ACCEPTED
+        );
+        assert_eq!(
+            ingest(&broken, "ingest-secret", r#"["w","x","y","z"]"#).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        ingest::flush_all(&broken).await;
+        assert_eq!(state.metrics.get("logchain_ingest_lines_dropped_total"), 0);
+
+        // Once storage takes writes again, nothing accepted was lost.
+        ingest::flush_all(&state).await;
+        let batches = list(
+            &state,
+            ListParams {
+                agent_id: Some("ingest:app".into()),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].batch.logs, ["a", "b", "c"]);
+        assert_eq!(
+            ingest(&state, "ingest-secret", r#"["c","d"]"#).await,
+            StatusCode::ACCEPTED
+        );
+    }
+
+    #[tokio::test]
+    async fn ingest_requires_token_and_reserves_prefix() {
+        let state = test_state().await;
+        assert_eq!(
+            ingest(&state, "wrong", r#"["x"]"#).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        // A producer cannot impersonate a server-signed chain through /submit.
+        let key = generate_keypair();
+        let mut forged = signed_batch(&key, 1, [0u8; 32], "x");
+        forged.agent_id = "ingest:app".into();
+        forged.sign(&key);
+        assert_eq!(
+            submit(&state, &forged).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(rejected(&state, "reserved_agent_id"), 1);
+    }
+
+    #[tokio::test]
+    async fn authz_failures_are_indistinguishable_externally() {
+        let mut state = test_state().await;
+        state.require_registration = true;
+        state.auth_token = Some("secret".into());
+
+        let registered = generate_keypair();
+        sqlx::query(
+            "INSERT INTO agents (agent_id, public_key, created_at) VALUES ('agent-test', ?1, 0)",
+        )
+        .bind(registered.verifying_key().to_bytes().to_vec())
+        .execute(&state.pool)
+        .await
+        .unwrap();
+
+        let good = signed_batch(&registered, 1, [0u8; 32], "a");
+        let wrong_key = signed_batch(&generate_keypair(), 1, [0u8; 32], "a");
+        let mut unknown = good.clone();
+        unknown.agent_id = "agent-unknown".into();
+        unknown.sign(&registered);
+
+        let bad_token = submit_with(&state, bearer("nope"), &good).await;
+        let mismatch = submit_with(&state, bearer("secret"), &wrong_key).await;
+        let unregistered = submit_with(&state, bearer("secret"), &unknown).await;
+
+        let mut bodies = Vec::new();
+        for resp in [bad_token, mismatch, unregistered] {
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            bodies.push(body_text(resp).await);
+        }
+        assert_eq!(bodies[0], bodies[1]);
+        assert_eq!(bodies[1], bodies[2]);
+
+        // Detailed reasons are still kept server-side.
+        let reasons: Vec<String> = sqlx::query_scalar("SELECT reason FROM rejections ORDER BY id")
+            .fetch_all(&state.pool)
+            .await
+            .unwrap();
+        assert_eq!(reasons.len(), 3);
+        assert!(reasons[1].contains("does not match"));
+        assert!(reasons[2].contains("not registered"));
+    }
+
+    #[tokio::test]
+    async fn repeated_auth_failures_are_throttled() {
+        let mut state = test_state().await;
+        state.auth_token = Some("secret".into());
+        let batch = signed_batch(&generate_keypair(), 1, [0u8; 32], "a");
+
+        for _ in 0..3 {
+            let resp = submit_with(&state, bearer("nope"), &batch).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        }
+        // Even the right token is refused until the failure window passes.
+        let resp = submit_with(&state, bearer("secret"), &batch).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    /// `/batches` as a client reads it: the response parsed back into typed batches.
+    async fn list(state: &AppState, params: ListParams) -> Vec<QueryBatch> {
+        let Json(rows) = handler_get_all(State(state.clone()), Query(params), Query(Vec::new()))
+            .await
+            .unwrap();
+        serde_json::from_slice(&serde_json::to_vec(&rows).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn latest_lists_interleaved_agents_newest_arrival_first() {
+        let state = test_state().await;
+        let (alpha, beta) = (generate_keypair(), generate_keypair());
+        let mut prev: std::collections::HashMap<&str, [u8; 32]> = Default::default();
+        let arrivals = [
+            ("alpha", 1),
+            ("beta", 1),
+            ("beta", 2),
+            ("alpha", 2),
+            ("beta", 3),
+            ("alpha", 3),
+        ];
+        for (agent, seq) in arrivals {
+            let key = if agent == "alpha" { &alpha } else { &beta };
+            let mut batch = signed_batch(
+                key,
+                seq,
+                prev.get(agent).copied().unwrap_or([0u8; 32]),
+                &format!("{agent} {seq}"),
+            );
+            batch.agent_id = agent.into();
+            batch.sign(key);
+            prev.insert(agent, batch.compute_hash());
+            assert_eq!(submit(&state, &batch).await.status(), StatusCode::CREATED);
+        }
+
+        let latest = async |query: &str| -> Vec<serde_json::Value> {
+            let resp = route(
+                &state,
+                "GET",
+                &format!("/batches/latest{query}"),
+                None,
+                Vec::new(),
+                1,
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            serde_json::from_str(&body_text(resp).await).unwrap()
+        };
+        let positions = |rows: &[serde_json::Value]| -> Vec<(String, u64)> {
+            rows.iter()
+                .map(|row| {
+                    (
+                        row["agent_id"].as_str().unwrap().to_string(),
+                        row["seq"].as_u64().unwrap(),
+                    )
+                })
+                .collect()
+        };
+        let expected = |pairs: &[(&str, u64)]| -> Vec<(String, u64)> {
+            pairs
+                .iter()
+                .map(|(agent, seq)| (agent.to_string(), *seq))
+                .collect()
+        };
+
+        let rows = latest("?limit=4").await;
+        assert_eq!(
+            positions(&rows),
+            expected(&[("alpha", 3), ("beta", 3), ("alpha", 2), ("beta", 2)])
+        );
+        let stamps: Vec<u64> = rows
+            .iter()
+            .map(|row| row["received_at"].as_u64().unwrap())
+            .collect();
+        assert!(stamps.windows(2).all(|w| w[0] > w[1]), "{stamps:?}");
+        assert!(rows[0].get("logs").is_none() && rows[0].get("batch").is_none());
+
+        let rows = latest("?agent_id=beta").await;
+        assert_eq!(
+            positions(&rows),
+            expected(&[("beta", 3), ("beta", 2), ("beta", 1)])
+        );
+        // An oversized limit is capped, not refused.
+        assert_eq!(latest("?limit=1000000").await.len(), arrivals.len());
+        assert_eq!(latest("").await.len(), arrivals.len());
+
+        let full = latest("?limit=1&logs=true").await;
+        assert_eq!(full.len(), 1);
+        assert_eq!(full[0]["batch"]["agent_id"], "alpha");
+        assert_eq!(full[0]["batch"]["logs"], serde_json::json!(["alpha 3"]));
+    }
+
+    #[tokio::test]
+    async fn since_received_at_pulls_each_row_exactly_once() {
+        let state = test_state().await;
+        // Back-to-back submits usually land in the same millisecond.
+        for batch in simulated_chain(4, 4) {
+            assert_eq!(submit(&state, &batch).await.status(), StatusCode::CREATED);
+        }
+
+        let all = list(&state, ListParams::default()).await;
+        let stamps: Vec<u64> = all.iter().map(|b| b.received_at).collect();
+        assert!(
+            stamps.windows(2).all(|w| w[0] < w[1]),
+            "arrival stamps must be unique: {stamps:?}"
+        );
+
+        // Pull incrementally from the boundary of the second row.
+        let rest = list(
+            &state,
+            ListParams {
+                since_received_at: Some(stamps[1]),
+                ..Default::default()
+            },
+        )
+        .await;
+        let seqs: Vec<u64> = rest.iter().map(|b| b.batch.seq).collect();
+        assert_eq!(seqs, vec![3, 4]);
+
+        let exported: Vec<serde_json::Value> = serde_json::from_str(
+            &export(
+                &state,
+                ExportParams {
+                    since_received_at: Some(stamps[3]),
+                    ..Default::default()
+                },
+            )
+            .await,
+        )
+        .unwrap();
+        assert!(
+            exported.is_empty(),
+            "pulling from the newest stamp returns nothing"
+        );
+    }
+
+    #[tokio::test]
+    async fn as_of_received_at_shows_the_store_as_it_stood() {
+        let state = test_state().await;
+        let (alpha, beta) = (
+            SigningKey::from_bytes(&[71; 32]),
+            SigningKey::from_bytes(&[72; 32]),
+        );
+        let mut chains: std::collections::HashMap<&str, Vec<LogBatch>> = Default::default();
+        for (agent, seq) in [
+            ("alpha", 1),
+            ("alpha", 2),
+            ("beta", 1),
+            ("alpha", 3),
+            ("beta", 2),
+            ("alpha", 4),
+        ] {
+            let key = if agent == "alpha" { &alpha } else { &beta };
+            let chain = chains.entry(agent).or_default();
+            let prev = chain.last().map_or([0u8; 32], LogBatch::compute_hash);
+            let mut batch = signed_batch(key, seq, prev, &format!("{agent} {seq}"));
+            batch.agent_id = agent.into();
+            batch.sign(key);
+            assert_eq!(submit(&state, &batch).await.status(), StatusCode::CREATED);
+            chain.push(batch);
+        }
+        // alpha 2 and beta 1 arrive in the same millisecond; alpha 4 is a
+        // row from before millisecond stamps, in second 12.
+        sqlx::query("DROP TRIGGER batches_no_update")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        for (agent, seq, at) in [
+            ("alpha", 1, 10_000),
+            ("alpha", 2, 11_000),
+            ("beta", 1, 11_000),
+            ("alpha", 3, 11_999),
+            ("beta", 2, 12_000),
+        ] {
+            sqlx::query("UPDATE batches SET received_at_ms = ?1 WHERE agent_id = ?2 AND seq = ?3")
+                .bind(at)
+                .bind(agent)
+                .bind(seq)
+                .execute(&state.pool)
+                .await
+                .unwrap();
+        }
+        sqlx::query("UPDATE batches SET received_at_ms = NULL, received_at = 12 WHERE agent_id = 'alpha' AND seq = 4")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+
+        let get = async |uri: String| -> serde_json::Value {
+            let resp = route(&state, "GET", &uri, None, Vec::new(), 1).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{uri}");
+            serde_json::from_str(&body_text(resp).await).unwrap()
+        };
+        let heads = async |as_of: u64| -> Vec<(String, u64, u64)> {
+            let checkpoints = get(format!("/batches/checkpoints?as_of_received_at={as_of}")).await;
+            checkpoints
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|cp| {
+                    let agent = cp["agent_id"].as_str().unwrap();
+                    let chain = &chains[agent];
+                    let seq = cp["last_seq"].as_u64().unwrap();
+                    let head = &chain[seq as usize - 1];
+                    assert_eq!(cp["last_hash"], serde_json::json!(head.compute_hash()));
+                    assert_eq!(cp["last_accumulator"], serde_json::json!(head.accumulator));
+                    (agent.to_string(), seq, cp["count"].as_u64().unwrap())
+                })
+                .collect()
+        };
+        let expected = |heads: &[(&str, u64, u64)]| -> Vec<(String, u64, u64)> {
+            heads
+                .iter()
+                .map(|(agent, seq, count)| (agent.to_string(), *seq, *count))
+                .collect()
+        };
+
+        assert!(heads(9_999).await.is_empty());
+        assert_eq!(heads(10_999).await, expected(&[("alpha", 1, 1)]));
+        assert_eq!(
+            heads(11_000).await,
+            expected(&[("alpha", 2, 2), ("beta", 1, 1)])
+        );
+        assert_eq!(
+            heads(11_999).await,
+            expected(&[("alpha", 3, 3), ("beta", 1, 1)])
+        );
+        // The second-resolution row counts from the start of its second.
+        assert_eq!(
+            heads(12_000).await,
+            expected(&[("alpha", 4, 4), ("beta", 2, 2)])
+        );
+        let live = state.checkpoints.get(&state.pool).await.unwrap();
+        let now = heads(u64::MAX >> 1).await;
+        assert_eq!(now.len(), live.len());
+        for (head, live) in now.iter().zip(&live) {
+            assert_eq!(
+                (head.0.as_str(), head.1, head.2),
+                (live.agent_id.as_str(), live.last_seq, live.count)
+            );
+        }
+
+        // The head row's receipt vouches for it from when it arrived.
+        let paged = get("/batches/checkpoints?as_of_received_at=11999&agent_id=alpha".into()).await;
+        assert_eq!(paged.as_array().unwrap().len(), 1);
+        assert_eq!(paged[0]["received_at_ms"], 11_999);
+        let receipt = get(format!("/batches/{}/receipt", paged[0]["batch_id"])).await;
+        assert_eq!(
+            (receipt["seq"].as_u64(), &receipt["hash"]),
+            (Some(3), &paged[0]["last_hash"])
+        );
+        let page = get("/batches/checkpoints?as_of_received_at=12000&after=alpha".into()).await;
+        assert_eq!(page[0]["agent_id"], "beta");
+        assert_eq!(page.as_array().unwrap().len(), 1);
+
+        // The reads that list rows honor it the same way.
+        let count = |rows: serde_json::Value| rows.as_array().unwrap().len();
+        assert_eq!(
+            count(get("/batches?as_of_received_at=11000".into()).await),
+            3
+        );
+        assert_eq!(
+            count(get("/batches/meta?as_of_received_at=10999".into()).await),
+            1
+        );
+        assert_eq!(
+            count(get("/batches?agent_id=alpha&as_of_received_at=12000".into()).await),
+            4
+        );
+        assert_eq!(
+            count(get("/batches/export?as_of_received_at=11999".into()).await),
+            4
+        );
+        let latest = get("/batches/latest?as_of_received_at=11999&limit=2".into()).await;
+        assert_eq!(
+            (&latest[0]["agent_id"], &latest[0]["seq"]),
+            (&serde_json::json!("alpha"), &serde_json::json!(3))
+        );
+        assert_eq!(latest[1]["received_at"], 11_000);
+        assert_eq!(
+            count(get("/batches?since_received_at=10000&as_of_received_at=11000".into()).await),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn timestamp_filters_normalize_v1_seconds_and_v2_millis() {
+        let state = test_state().await;
+        let key = generate_keypair();
+
+        // v1 row: 1_700_000_001 seconds.
+        let v1 = signed_batch(&key, 1, [0u8; 32], "old");
+        // v2 row: 1_700_000_001_500 ms, half a second later.
+        let mut v2 = signed_batch(&key, 2, v1.compute_hash(), "new");
+        v2.version = CURRENT_BATCH_VERSION;
+        v2.timestamp = 1_700_000_001_500;
+        v2.sign(&key);
+        submit(&state, &v1).await;
+        assert_eq!(submit(&state, &v2).await.status(), StatusCode::CREATED);
+
+        let seqs = |rows: Vec<QueryBatch>| rows.iter().map(|b| b.batch.seq).collect::<Vec<_>>();
+        let by_ms = list(
+            &state,
+            ListParams {
+                since_ts_ms: Some(1_700_000_001_200),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(seqs(by_ms), vec![2]);
+
+        // Second-based bounds cover the whole second for both versions.
+        let by_secs = list(
+            &state,
+            ListParams {
+                since_timestamp: Some(1_700_000_001),
+                until_timestamp: Some(1_700_000_001),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(seqs(by_secs), vec![1, 2]);
+
+        let back = list(&state, ListParams::default()).await;
+        assert_eq!(back[1].batch.version, CURRENT_BATCH_VERSION);
+        assert!(back[1].batch.verify());
+    }
+
+    #[tokio::test]
+    async fn concurrent_submits_share_the_verify_pool() {
+        let state = test_state().await;
+        let key = generate_keypair();
+
+        let mut tampered = signed_batch(&key, 1, [0u8; 32], "line");
+        tampered.logs.push("injected".into());
+        let resp = submit(&state, &tampered).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // More submits than verify workers; each must still complete.
+        let mut handles = Vec::new();
+        for i in 0..8 {
+            let state = state.clone();
+            handles.push(tokio::spawn(async move {
+                let key = generate_keypair();
+                let mut batch = signed_batch(&key, 1, [0u8; 32], "line");
+                batch.agent_id = format!("agent-{i}");
+                batch.sign(&key);
+                submit(&state, &batch).await.status()
+            }));
+        }
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), StatusCode::CREATED);
+        }
+        assert_eq!(state.verify_workers.available_permits(), 2);
+    }
+
+    /// Tail latency of concurrent submits' signature checks run inline on the
+    /// reactor versus through [`verify_off_reactor`], and of a 1 ms timer
+    /// sharing the reactor with them:
+    /// `cargo test -p server verify_pool_tail_latency --release -- --ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore]
+    async fn verify_pool_tail_latency() {
+        fn p99(mut samples: Vec<StdDuration>) -> StdDuration {
+            samples.sort();
+            samples[samples.len() * 99 / 100]
+        }
+
+        let key = generate_keypair();
+        let batch = signed_batch(&key, 1, [0u8; 32], &"GET /api/v1/items 200 ".repeat(20_000));
+        let (clients, per_client) = (16, 25);
+        for offload in [false, true] {
+            let state = AppState {
+                verify_workers: Arc::new(Semaphore::new(4)),
+                ..test_state().await
+            };
+            let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let ticker = {
+                let done = done.clone();
+                tokio::spawn(async move {
+                    let mut late = Vec::new();
+                    while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                        let start = std::time::Instant::now();
+                        tokio::time::sleep(StdDuration::from_millis(1)).await;
+                        late.push(start.elapsed().saturating_sub(StdDuration::from_millis(1)));
+                    }
+                    late
+                })
+            };
+            let submits: Vec<_> = (0..clients)
+                .map(|_| {
+                    let (state, batch) = (state.clone(), batch.clone());
+                    tokio::spawn(async move {
+                        let mut latencies = Vec::new();
+                        for _ in 0..per_client {
+                            let start = std::time::Instant::now();
+                            let hash = if offload {
+                                verify_off_reactor(&state, batch.clone()).await.unwrap().1
+                            } else {
+                                batch.verify().then(|| batch.compute_hash())
+                            };
+                            assert!(hash.is_some());
+                            // The database write that follows.
+                            tokio::task::yield_now().await;
+                            latencies.push(start.elapsed());
+                        }
+                        latencies
+                    })
+                })
+                .collect();
+            let mut latencies = Vec::new();
+            for submit in submits {
+                latencies.extend(submit.await.unwrap());
+            }
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+            let late = ticker.await.unwrap();
+            println!(
+                "{}: {} submits p99 {:?}; {} timer ticks p99 late {:?}",
+                if offload { "spawn_blocking" } else { "inline" },
+                latencies.len(),
+                p99(latencies),
+                late.len(),
+                p99(late)
+            );
+        }
+    }
+
+    async fn export(state: &AppState, params: ExportParams) -> String {
+        let resp = handler_export(State(state.clone()), Query(params))
+            .await
+            .unwrap();
+        body_text(resp).await
+    }
+
+    #[tokio::test]
+    async fn export_streams_syslog_cef_and_ndjson() {
+        let state = test_state().await;
+        let key = generate_keypair();
+        let first = signed_batch(&key, 1, [0u8; 32], "hello");
+        let second = signed_batch(&key, 2, first.compute_hash(), "world");
+        submit(&state, &first).await;
+        submit(&state, &second).await;
+
+        let syslog = export(
+            &state,
+            ExportParams {
+                format: Some(ExportFormat::Syslog),
+                ..Default::default()
+            },
+        )
+        .await;
+        let frames: Vec<&str> = syslog.lines().collect();
+        assert_eq!(frames.len(), 2);
+        assert!(frames[1].starts_with("<14>1 "));
+        assert!(frames[1].contains("agent_id=\"agent-test\" seq=\"2\" line=\"0\""));
+        assert!(frames[1].ends_with("] world"));
+
+        let cef = export(
+            &state,
+            ExportParams {
+                format: Some(ExportFormat::Cef),
+                since_id: Some(1),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(cef.lines().count(), 1);
+        assert!(cef.starts_with("CEF:0|logchain|"));
+        assert!(cef.trim_end().ends_with("msg=world"));
+
+        let ndjson = export(
+            &state,
+            ExportParams {
+                format: Some(ExportFormat::Ndjson),
+                limit: Some(1),
+                ..Default::default()
+            },
+        )
+        .await;
+        let rows: Vec<serde_json::Value> = ndjson
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["batch"]["seq"], 1);
+    }
+
+    #[tokio::test]
+    async fn exports_can_lead_with_a_signed_header() {
+        let state = test_state().await;
+        let key = generate_keypair();
+        let first = signed_batch(&key, 1, [0u8; 32], "hello");
+        let second = signed_batch(&key, 2, first.compute_hash(), "world");
+        submit(&state, &first).await;
+        submit(&state, &second).await;
+        let server_key = receipts::server_keys(&state.pool).await.unwrap().remove(0);
+        let headed = |format| ExportParams {
+            format: Some(format),
+            header: true,
+            ..Default::default()
+        };
+
+        let json: serde_json::Value =
+            serde_json::from_str(&export(&state, headed(ExportFormat::Json)).await).unwrap();
+        let header: ExportHeader = serde_json::from_value(json["export_header"].clone()).unwrap();
+        assert_eq!(header.validate(), Ok(()));
+        assert_eq!(header.key_id, server_key.id);
+        assert_eq!(
+            header.server_public_key.as_bytes().as_slice(),
+            server_key.public_key
+        );
+        let caps = Capabilities::current(env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            (&header.batch_versions, &header.hash_schemes),
+            (&caps.batch_versions, &caps.hash_schemes)
+        );
+        assert_eq!(json["batches"].as_array().unwrap().len(), 2);
+
+        let ndjson = export(&state, headed(ExportFormat::Ndjson)).await;
+        let lines: Vec<serde_json::Value> = ndjson
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        let header: ExportHeader =
+            serde_json::from_value(lines[0]["export_header"].clone()).unwrap();
+        assert_eq!(header.validate(), Ok(()));
+        assert_eq!(lines[1]["batch"]["seq"], 1);
+
+        // Record formats have nowhere to put it.
+        let refused =
+            handler_export(State(state.clone()), Query(headed(ExportFormat::Syslog))).await;
+        assert_eq!(refused.unwrap_err(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn export_keeps_each_agents_seq_increasing_when_interleaved() {
+        let state = test_state().await;
+        let agents = ["agent-a", "agent-b", "agent-c"];
+        let keys: Vec<SigningKey> = agents.iter().map(|_| generate_keypair()).collect();
+        let mut prev_hashes = [[0u8; 32]; 3];
+        let mut seqs = [0u64; 3];
+        // Uneven rounds so ids interleave differently per agent.
+        for i in [0, 1, 0, 2, 2, 1, 0, 2, 1, 1, 0] {
+            seqs[i] += 1;
+            let mut batch = signed_batch(&keys[i], seqs[i], prev_hashes[i], "x");
+            batch.agent_id = agents[i].into();
+            batch.sign(&keys[i]);
+            prev_hashes[i] = batch.compute_hash();
+            assert_eq!(submit(&state, &batch).await.status(), StatusCode::CREATED);
+        }
+
+        let rows: Vec<serde_json::Value> =
+            serde_json::from_str(&export(&state, ExportParams::default()).await).unwrap();
+        assert_eq!(rows.len(), 11);
+        for (agent, expected) in agents.iter().zip(seqs) {
+            let per_agent: Vec<u64> = rows
+                .iter()
+                .filter(|row| row["batch"]["agent_id"] == *agent)
+                .map(|row| row["batch"]["seq"].as_u64().unwrap())
+                .collect();
+            assert_eq!(per_agent, (1..=expected).collect::<Vec<_>>(), "{agent}");
+        }
+
+        // Id order can only follow seq order because nothing can insert a
+        // lower seq later: the append-only trigger refuses it.
+        let err = sqlx::query(
+            "INSERT INTO batches (agent_id, seq, prev_hash, hash, logs, timestamp, signature, public_key) \
+             VALUES ('agent-a', 2, zeroblob(32), zeroblob(32), '[]', 0, zeroblob(64), zeroblob(32))",
+        )
+        .execute(&state.pool)
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("append-only"), "{err}");
+    }
+
+    #[cfg(feature = "parquet")]
+    #[tokio::test]
+    async fn export_streams_parquet_rows_per_line() {
+        use arrow_array::cast::AsArray;
+        use arrow_array::types::{Int64Type, TimestampMillisecondType, UInt32Type};
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let state = test_state().await;
+        let key = generate_keypair();
+        let first = signed_batch(&key, 1, [0u8; 32], "hello");
+        let mut second = signed_batch(&key, 2, first.compute_hash(), "world");
+        second.logs.push("again".into());
+        second.sign(&key);
+        submit(&state, &first).await;
+        submit(&state, &second).await;
+        let source: Vec<serde_json::Value> =
+            serde_json::from_str(&export(&state, ExportParams::default()).await).unwrap();
+
+        let resp = handler_export(
+            State(state.clone()),
+            Query(ExportParams {
+                format: Some(ExportFormat::Parquet),
+                compression: Some(ParquetCompression::Zstd),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "application/vnd.apache.parquet"
+        );
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(body).unwrap();
+        let codec = reader.metadata().row_group(0).column(0).compression();
+        assert!(matches!(codec, parquet::basic::Compression::ZSTD(_)));
+        let rows = reader.build().unwrap().next().unwrap().unwrap();
+        assert_eq!(rows.num_rows(), 3);
+
+        let batch_ids = rows.column(0).as_primitive::<Int64Type>();
+        let line_idx = rows.column(3).as_primitive::<UInt32Type>();
+        let received = rows.column(5).as_primitive::<TimestampMillisecondType>();
+        let lines = rows.column(6).as_string::<i32>();
+        let hashes = rows.column(7).as_fixed_size_binary();
+        let expected = [
+            (0, 0, "hello", &first),
+            (1, 0, "world", &second),
+            (1, 1, "again", &second),
+        ];
+        for (row, (src, idx, line, batch)) in expected.into_iter().enumerate() {
+            assert_eq!(batch_ids.value(row), source[src]["id"].as_i64().unwrap());
+            assert_eq!(
+                received.value(row),
+                source[src]["received_at"].as_i64().unwrap()
+            );
+            assert_eq!(line_idx.value(row), idx);
+            assert_eq!(lines.value(row), line);
+            assert_eq!(hashes.value(row), batch.compute_hash());
+        }
+    }
+
+    async fn register(state: &AppState, agent_id: &str, key: &SigningKey) -> StatusCode {
+        handler_register_agent(
+            State(state.clone()),
+            authed(state, HeaderMap::new()).await,
+            HeaderMap::new(),
+            Json(RegisterRequest {
+                agent_id: agent_id.into(),
+                public_key_hex: hex_encode(&key.verifying_key().to_bytes()),
+            }),
+        )
+        .await
+        .into_response()
+        .status()
+    }
+
+    fn rotation(
+        agent_id: &str,
+        current: &SigningKey,
+        new: &SigningKey,
+        counter: u64,
+    ) -> RotateRequest {
+        rotation_at(agent_id, current, new, counter, Some(now_unix() as u64))
+    }
+
+    fn rotation_at(
+        agent_id: &str,
+        current: &SigningKey,
+        new: &SigningKey,
+        counter: u64,
+        timestamp: Option<u64>,
+    ) -> RotateRequest {
+        if let Some(ts) = timestamp {
+            return RotateRequest::signed(current, agent_id, &new.verifying_key(), counter, ts);
+        }
+        let new_public_key_hex = hex_encode(&new.verifying_key().to_bytes());
+        let message = rotation_message(agent_id, &new_public_key_hex, counter, None);
+        RotateRequest {
+            agent_id: agent_id.into(),
+            new_public_key_hex,
+            counter,
+            timestamp,
+            nonce: None,
+            auth_signature_hex: hex_encode(&current.sign(&message).to_bytes()),
+        }
+    }
+
+    /// A rotation signed with `nonce` instead of a fresh one.
+    fn rotation_with_nonce(
+        current: &SigningKey,
+        new: &SigningKey,
+        counter: u64,
+        nonce: &str,
+    ) -> RotateRequest {
+        let mut req = rotation("agent-nonce", current, new, counter);
+        let envelope = rotation_envelope(
+            "agent-nonce",
+            &new.verifying_key(),
+            counter,
+            req.timestamp.unwrap(),
+            Some(nonce),
+        );
+        req.nonce = Some(nonce.into());
+        req.auth_signature_hex = hex_encode(&current.sign(&envelope).to_bytes());
+        req
+    }
+
+    async fn rotate(state: &AppState, req: RotateRequest) -> StatusCode {
+        handler_rotate_agent(
+            State(state.clone()),
+            authed(state, HeaderMap::new()).await,
+            HeaderMap::new(),
+            Json(req),
+        )
+        .await
+        .into_response()
+        .status()
+    }
+
+    #[tokio::test]
+    async fn idempotency_keys_replay_the_first_response_without_running_again() {
+        let state = test_state().await;
+        let keyed = |key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(idempotency::HEADER, key.parse().unwrap());
+            headers
+        };
+        let register_with = async |key: &str, agent_id: &str, pk: &SigningKey| {
+            let req = RegisterRequest {
+                agent_id: agent_id.into(),
+                public_key_hex: hex_encode(&pk.verifying_key().to_bytes()),
+            };
+            handler_register_agent(
+                State(state.clone()),
+                authed(&state, HeaderMap::new()).await,
+                keyed(key),
+                Json(req),
+            )
+            .await
+        };
+        let rotate_with = async |key: &str, req: RotateRequest| {
+            handler_rotate_agent(
+                State(state.clone()),
+                authed(&state, HeaderMap::new()).await,
+                keyed(key),
+                Json(req),
+            )
+            .await
+        };
+        let replayed = |resp: &Response| resp.headers().get(idempotency::REPLAYED_HEADER).is_some();
+        let (k1, k2) = (generate_keypair(), generate_keypair());
+
+        let first = register_with("reg-1", "agent-idem", &k1).await;
+        assert_eq!(
+            (first.status(), replayed(&first)),
+            (StatusCode::CREATED, false)
+        );
+        let first = body_text(first).await;
+        // Run again it would answer 200 "already registered".
+        let again = register_with("reg-1", "agent-idem", &k1).await;
+        assert_eq!(
+            (again.status(), replayed(&again)),
+            (StatusCode::CREATED, true)
+        );
+        assert_eq!(body_text(again).await, first);
+        let reused = register_with("reg-1", "agent-other", &k1).await;
+        assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            register_with("reg-2", "agent-idem", &k1).await.status(),
+            StatusCode::OK
+        );
+
+        // A rotation retried after it went through gets its answer back,
+        // instead of a 401 for a signature by the key it replaced, and the
+        // key moves only once.
+        let rotation = rotation("agent-idem", &k1, &k2, 1);
+        let resend = || rotation.clone();
+        let rotated = rotate_with("rot-1", resend()).await;
+        assert_eq!(
+            (rotated.status(), replayed(&rotated)),
+            (StatusCode::OK, false)
+        );
+        let retried = rotate_with("rot-1", resend()).await;
+        assert_eq!(
+            (retried.status(), replayed(&retried)),
+            (StatusCode::OK, true)
+        );
+        assert!(body_text(retried).await.contains("rotation counter 1"));
+        assert_eq!(
+            rotate(&state, resend()).await,
+            StatusCode::UNAUTHORIZED,
+            "without the key it runs, and fails"
+        );
+        // The same key on another endpoint is another key.
+        assert_eq!(
+            rotate_with("reg-1", resend()).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let counter: i64 =
+            sqlx::query_scalar("SELECT rotation_counter FROM agents WHERE agent_id = 'agent-idem'")
+                .fetch_one(&state.pool)
+                .await
+                .unwrap();
+        assert_eq!(counter, 1);
+        assert_eq!(state.metrics.get("logchain_idempotent_replays_total"), 2);
+
+        // Concurrent repeats: one runs, the other is refused or replayed.
+        let next = rotation_at("agent-idem", &k2, &k1, 2, Some(now_unix() as u64));
+        let (a, b) = tokio::join!(
+            rotate_with("rot-2", next.clone()),
+            rotate_with("rot-2", next.clone())
+        );
+        let mut statuses = [(a.status(), replayed(&a)), (b.status(), replayed(&b))];
+        statuses.sort_by_key(|(status, replayed)| (status.as_u16(), *replayed));
+        assert!(
+            statuses == [(StatusCode::OK, false), (StatusCode::OK, true)]
+                || statuses == [(StatusCode::OK, false), (StatusCode::CONFLICT, false)],
+            "{statuses:?}"
+        );
+
+        // Past the TTL a key is forgotten, and the request runs again: k1
+        // signs for the agent again, so its spent counter refuses it.
+        sqlx::query("UPDATE idempotency_keys SET created_at_ms = 0")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        let expired = rotate_with("rot-1", resend()).await;
+        assert_eq!(
+            (expired.status(), replayed(&expired)),
+            (StatusCode::CONFLICT, false)
+        );
+        assert_eq!(
+            register_with(&"k".repeat(256), "agent-idem", &k1)
+                .await
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn replayed_rotation_cannot_roll_key_back() {
+        let state = test_state().await;
+        let k1 = generate_keypair();
+        let k2 = generate_keypair();
+        assert_eq!(
+            register(&state, "agent-rot", &k1).await,
+            StatusCode::CREATED
+        );
+
+        let captured = rotation("agent-rot", &k1, &k2, 1);
+        let replay = rotation("agent-rot", &k1, &k2, 1);
+        assert_eq!(rotate(&state, captured).await, StatusCode::OK);
+        // Legitimate rotation back to k1.
+        assert_eq!(
+            rotate(&state, rotation("agent-rot", &k2, &k1, 2)).await,
+            StatusCode::OK
+        );
+
+        // The old request is validly signed by the current key again, but its
+        // counter is spent.
+        assert_eq!(rotate(&state, replay).await, StatusCode::CONFLICT);
+        let stored: Vec<u8> =
+            sqlx::query_scalar("SELECT public_key FROM agents WHERE agent_id = 'agent-rot'")
+                .fetch_one(&state.pool)
+                .await
+                .unwrap();
+        assert_eq!(stored, k1.verifying_key().to_bytes());
+    }
 
-    let bind_addr = env::var("SERVER_ADDR").unwrap_or_else(|_| "127.0.0.1:3000".to_string());
-    let addr: SocketAddr = bind_addr
-        .parse()
-        .unwrap_or_else(|_| SocketAddr::from(([127, 0, 0, 1], 3000)));
-    println!("Server listening on {}", addr);
+    #[tokio::test]
+    async fn rotation_counter_must_be_next_and_signed() {
+        let state = test_state().await;
+        let k1 = generate_keypair();
+        let k2 = generate_keypair();
+        register(&state, "agent-rot", &k1).await;
 
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
-        .await
-        .unwrap();
-}
+        assert_eq!(
+            rotate(&state, rotation("agent-rot", &k1, &k2, 5)).await,
+            StatusCode::CONFLICT
+        );
 
-/* ----------------------- SUBMIT BATCH ----------------------- */
+        // Counter bumped after signing: signature no longer covers it.
+        let mut tampered = rotation("agent-rot", &k1, &k2, 1);
+        tampered.counter = 2;
+        assert_eq!(rotate(&state, tampered).await, StatusCode::UNAUTHORIZED);
 
-async fn handler_submit_batch(
-    State(state): State<AppState>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    headers: HeaderMap,
-    Json(batch): Json<LogBatch>,
-) -> impl IntoResponse {
-    if !state.rate_limiter.allow(&addr.to_string()).await {
-        return (
-            StatusCode::TOO_MANY_REQUESTS,
-            Json(SubmitResponse {
-                status: "error".into(),
-                message: "rate limit exceeded".into(),
-            }),
+        assert_eq!(
+            rotate(&state, rotation("agent-rot", &k1, &k2, 1)).await,
+            StatusCode::OK
         );
     }
 
-    if let Some(expected) = &state.auth_token {
-        if !valid_auth(&headers, expected) {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(SubmitResponse {
-                    status: "error".into(),
-                    message: "missing or invalid auth".into(),
-                }),
+    #[tokio::test]
+    async fn rotations_are_signed_in_the_envelope_or_as_v2() {
+        let state = test_state().await;
+        let (k1, k2) = (generate_keypair(), generate_keypair());
+        register(&state, "agent-env", &k1).await;
+        let ts = now_unix() as u64;
+
+        // The same fields in an envelope of another type do not verify.
+        let mut confused = rotation_at("agent-env", &k1, &k2, 1, Some(ts));
+        let payload = common::rotation::RotationPayload {
+            agent_id: "agent-env".into(),
+            new_public_key: k2.verifying_key(),
+            counter: 1,
+            timestamp: ts,
+            nonce: confused.nonce.clone(),
+        };
+        let signature = common::signing::sign_envelope(&k1, "registration", &payload).unwrap();
+        confused.auth_signature_hex = hex_encode(&signature.to_bytes());
+        assert_eq!(rotate(&state, confused).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            rotate(&state, rotation("agent-env", &k1, &k2, 1)).await,
+            StatusCode::OK
+        );
+
+        // A client still signing the v2 string is refused, without using up
+        // the counter, unless ROTATION_ALLOW_V2_STRING is set.
+        let mut v2 = rotation("agent-env", &k2, &k1, 2);
+        let stamp = v2.timestamp.zip(v2.nonce.as_deref());
+        let message = rotation_message("agent-env", &v2.new_public_key_hex, 2, stamp);
+        v2.auth_signature_hex = hex_encode(&k2.sign(&message).to_bytes());
+        assert_eq!(rotate(&state, v2.clone()).await, StatusCode::BAD_REQUEST);
+        let state = AppState {
+            rotation: rotation::RotationPolicy {
+                allow_v2_string: true,
+                ..state.rotation.clone()
+            },
+            ..state
+        };
+        assert_eq!(rotate(&state, v2).await, StatusCode::OK);
+        // Timestamped requests must carry their nonce.
+        let mut bare = rotation("agent-env", &k1, &k2, 3);
+        bare.nonce = None;
+        assert_eq!(rotate(&state, bare).await, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn a_resent_rotation_is_refused_by_its_nonce() {
+        let state = test_state().await;
+        let (k1, k2, k3) = (generate_keypair(), generate_keypair(), generate_keypair());
+        register(&state, "agent-nonce", &k1).await;
+        let rotate_body = |req: RotateRequest| {
+            let state = state.clone();
+            async move {
+                let resp = handler_rotate_agent(
+                    State(state.clone()),
+                    authed(&state, HeaderMap::new()).await,
+                    HeaderMap::new(),
+                    Json(req),
+                )
+                .await;
+                (resp.status(), body_text(resp).await)
+            }
+        };
+
+        // A request turned away for its counter leaves its nonce unspent.
+        let nonce = "00112233445566778899aabbccddeeff";
+        let (status, _) = rotate_body(rotation_with_nonce(&k1, &k2, 5, nonce)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let first = rotation_with_nonce(&k1, &k2, 1, nonce);
+        assert_eq!(rotate_body(first.clone()).await.0, StatusCode::OK);
+
+        // Once the agent is back on k1, the captured request verifies again;
+        // sent byte for byte, its nonce is spent.
+        assert_eq!(
+            rotate(&state, rotation("agent-nonce", &k2, &k1, 2)).await,
+            StatusCode::OK
+        );
+        let (status, body) = rotate_body(first).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body.contains("nonce already used"), "{body}");
+        // So it is for a new request that reuses it, counter and all correct.
+        let (status, body) = rotate_body(rotation_with_nonce(&k1, &k3, 3, nonce)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body.contains("nonce already used"), "{body}");
+        assert_eq!(
+            rotate(&state, rotation("agent-nonce", &k1, &k3, 3)).await,
+            StatusCode::OK
+        );
+
+        let kept: Vec<String> =
+            sqlx::query_scalar("SELECT nonce FROM rotation_nonces WHERE agent_id = 'agent-nonce'")
+                .fetch_all(&state.pool)
+                .await
+                .unwrap();
+        assert_eq!(kept.len(), 3);
+        assert!(kept.contains(&nonce.to_string()));
+        // Once its request has expired, a nonce is forgotten.
+        sqlx::query("UPDATE rotation_nonces SET expires_at = 0")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        let mut conn = state.pool.acquire().await.unwrap();
+        assert!(
+            consume_rotation_nonce(&mut conn, "agent-nonce", nonce, i64::MAX)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn compression_threshold_boundary() {
+        let level = Compression::default();
+        let at = "x".repeat(64);
+
+        assert_eq!(compress_json(&at[..63], level, 64, None).unwrap(), None);
+        let blob = compress_json(&at, level, 64, None)
+            .unwrap()
+            .expect("compressed at threshold");
+        assert_eq!(Decompressor::default().json(&blob).unwrap(), at);
+        assert!(compress_json("", level, 0, None).unwrap().is_some());
+    }
+
+    #[test]
+    fn blobs_inflating_past_the_limit_are_refused() {
+        let bomb = compress_bytes(&vec![b' '; 4 << 20], Compression::best()).unwrap();
+        assert!(bomb.len() < 8 << 10, "{} bytes", bomb.len());
+
+        let mut out = "left over".to_string();
+        let err = Decompressor::new(1 << 20)
+            .json_into(&bomb, &mut out)
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "gzip blob inflates past MAX_DECOMPRESSED_BYTES (1048576 bytes)"
+        );
+        Decompressor::new(4 << 20)
+            .json_into(&bomb, &mut out)
+            .unwrap();
+        assert_eq!(out.len(), 4 << 20);
+        assert!(Decompressor::new((4 << 20) - 1).bytes(&bomb).is_err());
+    }
+
+    #[tokio::test]
+    async fn small_batches_are_stored_plaintext_and_read_back() {
+        let state = test_state().await;
+        let key = generate_keypair();
+        let small = signed_batch(&key, 1, [0u8; 32], "tiny");
+        let large = signed_batch(
+            &key,
+            2,
+            small.compute_hash(),
+            &"a fairly repetitive line ".repeat(8),
+        );
+        submit(&state, &small).await;
+        submit(&state, &large).await;
+
+        let compressed: Vec<Option<Vec<u8>>> =
+            sqlx::query_scalar("SELECT logs_compressed FROM batches ORDER BY seq")
+                .fetch_all(&state.pool)
+                .await
+                .unwrap();
+        assert!(compressed[0].is_none());
+        assert!(compressed[1].is_some());
+
+        let rows = list(&state, ListParams::default()).await;
+        assert_eq!(rows[0].batch.logs, small.logs);
+        assert_eq!(rows[1].batch.logs, large.logs);
+        assert_eq!(
+            state
+                .metrics
+                .get("logchain_logs_uncompressed_batches_total"),
+            1
+        );
+        assert!(
+            state.metrics.get("logchain_logs_stored_bytes_total")
+                < state.metrics.get("logchain_logs_plain_bytes_total")
+        );
+    }
+
+    /// Micro-benchmark for picking `COMPRESSION_LEVEL` / `COMPRESSION_MIN_BYTES`:
+    /// `cargo test -p server compression_tradeoff -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn compression_tradeoff() {
+        let line = "2024-01-01T00:00:00Z INFO request handled path=/api/v1/items status=200 ms=12";
+        for lines in [1usize, 5, 50, 500] {
+            let json = serde_json::to_string(&vec![line; lines]).unwrap();
+            for level in [1u32, 6, 9] {
+                let rounds = 200;
+                let start = std::time::Instant::now();
+                let mut size = 0;
+                for _ in 0..rounds {
+                    size = compress_bytes(json.as_bytes(), Compression::new(level))
+                        .unwrap()
+                        .len();
+                }
+                println!(
+                    "lines={lines:>3} plain={:>6}B level={level} gzip={size:>6}B ratio={:.2} {:>8.1}us/batch",
+                    json.len(),
+                    size as f64 / json.len() as f64,
+                    start.elapsed().as_secs_f64() * 1e6 / rounds as f64,
+                );
+            }
+        }
+    }
+
+    /// Five short access-log lines, as a busy agent with a small batch
+    /// size sends them.
+    fn access_lines(batch: u64, path: &str) -> Vec<String> {
+        (0..5)
+            .map(|n| {
+                format!(
+                    "2024-01-01T00:{:02}:{:02}Z INFO GET {path}/{} status=200 ms={}",
+                    batch % 60,
+                    n * 7 % 60,
+                    batch * 5 + n,
+                    (batch + n) % 23
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn dictionary_compressed_logs_stay_readable_across_dictionaries() {
+        let state = AppState {
+            compression_min_bytes: 0,
+            ..test_state().await
+        };
+        let key = generate_keypair();
+        let mut prev = [0u8; 32];
+        let mut seq = 0;
+        let mut send = async |path: &str, count: u64| {
+            for _ in 0..count {
+                seq += 1;
+                let mut batch = signed_batch(&key, seq, prev, "");
+                batch.logs = access_lines(seq, path);
+                batch.sign(&key);
+                prev = batch.compute_hash();
+                assert_eq!(submit(&state, &batch).await.status(), StatusCode::CREATED);
+            }
+        };
+        let config = dicts::DictConfig {
+            interval: StdDuration::from_secs(60),
+            sample_batches: 1000,
+            dict_bytes: 4096,
+        };
+        let level = state.compression_level.level() as i32;
+
+        send("/api/v1/items", dicts::MIN_SAMPLES as u64 - 1).await;
+        assert!(
+            dicts::train(&state.pool, state.decompressor.decoders(), &config, level)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        send("/api/v1/items", 1).await;
+        let first = dicts::train(&state.pool, state.decompressor.decoders(), &config, level)
+            .await
+            .unwrap()
+            .expect("trained");
+        assert_eq!(first.version, 1);
+        // The same samples train the same dictionary, which is not stored twice.
+        assert!(
+            dicts::train(&state.pool, state.decompressor.decoders(), &config, level)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        state.compression_dict.set(first.clone());
+        send("/api/v1/items", 5).await;
+        send("/checkout/cart", dicts::MIN_SAMPLES as u64).await;
+        let second = dicts::train(&state.pool, state.decompressor.decoders(), &config, level)
+            .await
+            .unwrap()
+            .expect("trained");
+        assert_ne!(second.dict_id, first.dict_id);
+        state.compression_dict.set(second.clone());
+        send("/checkout/cart", 5).await;
+
+        let rows: Vec<(i64, Vec<u8>)> =
+            sqlx::query_as("SELECT seq, logs_compressed FROM batches ORDER BY seq")
+                .fetch_all(&state.pool)
+                .await
+                .unwrap();
+        let frame_dict =
+            |blob: &[u8]| zstd::zstd_safe::get_dict_id_from_frame(blob).map(|id| id.get());
+        for (seq, blob) in &rows {
+            let expected = match *seq as u64 {
+                ..=100 => None,
+                101..=205 => Some(first.dict_id),
+                _ => Some(second.dict_id),
+            };
+            assert_eq!(dicts::is_zstd(blob), expected.is_some(), "seq {seq}");
+            assert_eq!(frame_dict(blob), expected, "seq {seq}");
+            let logs: Vec<String> =
+                serde_json::from_str(&state.decompressor.json(blob).unwrap()).unwrap();
+            assert_eq!(logs.len(), 5);
+        }
+        // Short batches shrink far more against a dictionary.
+        let json = canonical_logs_json(&access_lines(210, "/checkout/cart"));
+        let gzip = compress_bytes(json.as_bytes(), state.compression_level)
+            .unwrap()
+            .len();
+        let dict = rows[209].1.len();
+        assert!(dict * 3 < gzip * 2, "{dict} vs {gzip} bytes");
+
+        let listed = list(
+            &state,
+            ListParams {
+                limit: Some(1000),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(listed.len(), rows.len());
+        assert!(listed.iter().all(|row| row.batch.verify()));
+        assert_eq!(listed[150].batch.logs, access_lines(151, "/checkout/cart"));
+
+        for sql in [
+            "UPDATE compression_dicts SET dict = x'00'",
+            "DELETE FROM compression_dicts",
+        ] {
+            let err = sqlx::query(sql).execute(&state.pool).await.unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains("compression dictionaries are immutable"),
+                "{err}"
             );
         }
+        // A restart reads every dictionary back and resumes with the newest.
+        let restarted = Decompressor::default();
+        assert!(
+            restarted
+                .json(&rows[209].1)
+                .unwrap_err()
+                .contains("not in compression_dicts")
+        );
+        let reloaded = dicts::load(&state.pool, restarted.decoders(), level)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((reloaded.version, reloaded.dict_id), (2, second.dict_id));
+        assert_eq!(
+            restarted.json(&rows[209].1).unwrap(),
+            state.decompressor.json(&rows[209].1).unwrap()
+        );
     }
 
-    if !batch.verify() {
-        log_submit_error(&batch.agent_id, "invalid signature");
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(SubmitResponse {
-                status: "error".into(),
-                message: "invalid signature".into(),
-            }),
+    /// Stored size of small batches under gzip and under a trained zstd
+    /// dictionary, on lines like [`access_lines`] from a few services:
+    /// `cargo test -p server --release dictionary_tradeoff -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn dictionary_tradeoff() {
+        let paths = [
+            "/api/v1/items",
+            "/api/v1/orders",
+            "/checkout/cart",
+            "/auth/session",
+        ];
+        let corpus: Vec<String> = (0..4000u64)
+            .map(|n| canonical_logs_json(&access_lines(n, paths[(n % 4) as usize])))
+            .collect();
+        let (training, stored) = corpus.split_at(2000);
+        let raw = zstd::dict::from_samples(training, dicts::DEFAULT_DICT_BYTES).unwrap();
+        let plain: usize = stored.iter().map(String::len).sum();
+        for level in [1i32, 3, 6] {
+            let gzip: usize = stored
+                .iter()
+                .map(|json| {
+                    compress_bytes(json.as_bytes(), Compression::new(level as u32))
+                        .unwrap()
+                        .len()
+                })
+                .sum();
+            let mut compressor = zstd::bulk::Compressor::with_dictionary(level, &raw).unwrap();
+            let start = std::time::Instant::now();
+            let dict: usize = stored
+                .iter()
+                .map(|json| compressor.compress(json.as_bytes()).unwrap().len())
+                .sum();
+            let per_batch = start.elapsed() / stored.len() as u32;
+            println!(
+                "level={level} plain={plain}B gzip={gzip}B ({:.2}) zstd+dict={dict}B ({:.2}) {:.1}x better, {per_batch:?}/batch",
+                gzip as f64 / plain as f64,
+                dict as f64 / plain as f64,
+                gzip as f64 / dict as f64,
+            );
+        }
+    }
+
+    fn signed_by(key: &SigningKey, seq: u64, prev: [u8; 32]) -> LogBatch {
+        let mut batch = signed_batch(key, seq, prev, &format!("line {seq}"));
+        batch.agent_id = "agent-rot".into();
+        batch.sign(key);
+        batch
+    }
+
+    #[tokio::test]
+    async fn rotation_mid_chain_keeps_old_batches_authorized() {
+        let state = test_state().await;
+        let k1 = generate_keypair();
+        let k2 = generate_keypair();
+        let attacker = generate_keypair();
+        register(&state, "agent-rot", &k1).await;
+
+        let b1 = signed_by(&k1, 1, [0u8; 32]);
+        let b2 = signed_by(&k1, 2, b1.compute_hash());
+        assert_eq!(submit(&state, &b1).await.status(), StatusCode::CREATED);
+        assert_eq!(submit(&state, &b2).await.status(), StatusCode::CREATED);
+        assert_eq!(
+            rotate(&state, rotation("agent-rot", &k1, &k2, 1)).await,
+            StatusCode::OK
+        );
+
+        let b3 = signed_by(&k2, 3, b2.compute_hash());
+        assert_eq!(submit(&state, &b3).await.status(), StatusCode::CREATED);
+        // A resend of a pre-rotation batch is still signed by a key valid for its seq.
+        assert_eq!(submit(&state, &b2).await.status(), StatusCode::OK);
+
+        // A self-consistent batch under a key never registered for the agent.
+        let foreign = signed_by(&attacker, 4, b3.compute_hash());
+        assert_eq!(
+            submit(&state, &foreign).await.status(),
+            StatusCode::FORBIDDEN
         );
+        // The old key cannot sign new seqs after rotation.
+        let stale = signed_by(&k1, 4, b3.compute_hash());
+        assert_eq!(submit(&state, &stale).await.status(), StatusCode::FORBIDDEN);
+
+        let Json(windows) = handler_agent_keys(State(state.clone()), Path("agent-rot".into()))
+            .await
+            .unwrap();
+        let spans: Vec<(u64, Option<u64>)> = windows
+            .iter()
+            .map(|w| (w.valid_from_seq, w.valid_until_seq))
+            .collect();
+        assert_eq!(spans, vec![(1, Some(3)), (3, None)]);
+        assert_eq!(windows[1].public_key, k2.verifying_key().to_bytes());
     }
 
-    let computed_hash = batch.compute_hash();
-    let logs_json = serde_json::to_string(&batch.logs).unwrap();
-    let logs_compressed = match compress_json(&logs_json) {
-        Ok(data) => data,
-        Err(err) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(SubmitResponse {
-                    status: "error".into(),
-                    message: format!("failed to compress logs: {err}"),
-                }),
+    #[tokio::test]
+    async fn key_history_is_backfilled_from_stored_batches() {
+        let state = test_state().await;
+        let k1 = generate_keypair();
+        let k2 = generate_keypair();
+        let b1 = signed_by(&k1, 1, [0u8; 32]);
+        let b2 = signed_by(&k1, 2, b1.compute_hash());
+        submit(&state, &b1).await;
+        submit(&state, &b2).await;
+
+        // Simulate a database from before key history: a rotation applied
+        // directly to the registry and a batch under the new key.
+        sqlx::query("DELETE FROM agent_keys")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE agents SET public_key = ?1")
+            .bind(k2.verifying_key().to_bytes().to_vec())
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        submit(&state, &signed_by(&k2, 3, b2.compute_hash())).await;
+        backfill_key_history(&state.pool).await;
+
+        let mut conn = state.pool.acquire().await.unwrap();
+        let windows = load_key_windows(&mut conn, "agent-rot").await.unwrap();
+        let spans: Vec<(u64, Option<u64>)> = windows
+            .iter()
+            .map(|w| (w.valid_from_seq, w.valid_until_seq))
+            .collect();
+        assert_eq!(spans, vec![(1, Some(3)), (3, None)]);
+    }
+
+    #[tokio::test]
+    async fn payload_sizes_are_stored_reported_and_backfilled() {
+        let state = test_state().await;
+        let key = generate_keypair();
+        let small = signed_batch(&key, 1, [0u8; 32], "tiny");
+        let large = signed_batch(&key, 2, small.compute_hash(), &"repetitive ".repeat(20));
+        submit(&state, &small).await;
+        submit(&state, &large).await;
+
+        let Json(meta) = handler_get_meta(
+            State(state.clone()),
+            Query(ListParams::default()),
+            Query(Vec::new()),
+        )
+        .await
+        .unwrap();
+        let small_json = serde_json::to_string(&small.logs).unwrap().len() as u64;
+        assert_eq!(meta[0].logs_size, Some(small_json));
+        assert_eq!(meta[0].logs_compressed_size, None);
+        let large_compressed = meta[1].logs_compressed_size.unwrap();
+        assert!(large_compressed < meta[1].logs_size.unwrap());
+
+        let metrics = body_text(handler_metrics(State(state.clone())).await.into_response()).await;
+        assert!(metrics.contains(&format!(
+            "logchain_agent_stored_bytes{{agent_id=\"agent-test\"}} {}",
+            small_json + large_compressed
+        )));
+
+        // Rows from before the columns existed get sizes from their blobs.
+        sqlx::query("DROP TRIGGER batches_no_update")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE batches SET logs_size = NULL, logs_compressed_size = NULL")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        init_schema(&state.pool).await;
+        let Json(again) = handler_get_meta(
+            State(state.clone()),
+            Query(ListParams::default()),
+            Query(Vec::new()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(again[0].logs_size, Some(small_json));
+        assert_eq!(again[1].logs_compressed_size, Some(large_compressed));
+        let blocked = sqlx::query("UPDATE batches SET logs_size = 0")
+            .execute(&state.pool)
+            .await;
+        assert!(blocked.is_err(), "append-only trigger is restored");
+    }
+
+    #[tokio::test]
+    async fn admin_api_requires_admin_token() {
+        let mut state = test_state().await;
+        let denied = admin::handler_integrity_check(
+            State(state.clone()),
+            authed(&state, bearer("wrong")).await,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(denied.0, StatusCode::UNAUTHORIZED);
+
+        state.admin_token = None;
+        let disabled = admin::handler_integrity_check(
+            State(state.clone()),
+            authed(&state, bearer("admin-secret")).await,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(disabled.0, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn bulk_registration_reports_each_entry_and_validates_all_first() {
+        async fn bulk(
+            state: &AppState,
+            entries: serde_json::Value,
+        ) -> (StatusCode, serde_json::Value) {
+            let resp = route(
+                state,
+                "POST",
+                "/agents/register/bulk",
+                None,
+                serde_json::to_vec(&entries).unwrap(),
+                1,
+            )
+            .await;
+            let status = resp.status();
+            (
+                status,
+                serde_json::from_str(&body_text(resp).await).unwrap(),
             )
         }
-    };
+        fn entry(agent_id: &str, key: &SigningKey, proof: bool) -> serde_json::Value {
+            let public_key_hex = hex_encode(&key.verifying_key().to_bytes());
+            let mut entry =
+                serde_json::json!({"agent_id": agent_id, "public_key_hex": public_key_hex});
+            if proof {
+                let sig = key.sign(&registration_message(agent_id, &public_key_hex));
+                entry["proof_signature_hex"] = hex_encode(&sig.to_bytes()).into();
+            }
+            entry
+        }
 
-    let mut tx = state.pool.begin().await.unwrap();
+        let state = test_state().await;
+        let (known, other) = (
+            SigningKey::from_bytes(&[67; 32]),
+            SigningKey::from_bytes(&[68; 32]),
+        );
+        assert_eq!(
+            register(&state, "bulk-known", &known).await,
+            StatusCode::CREATED
+        );
 
-    // Ensure agent key is trusted/registered before accepting.
-    if let Err(msg) = ensure_agent_key(&state, &mut tx, &batch).await {
-        log_submit_error(&batch.agent_id, &msg);
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(SubmitResponse {
-                status: "error".into(),
-                message: msg,
-            }),
+        // One bad entry, and nothing is registered.
+        let bad_proof = serde_json::json!({
+            "agent_id": "bulk-b",
+            "public_key_hex": hex_encode(&other.verifying_key().to_bytes()),
+            "proof_signature_hex": hex_encode(&known.sign(b"register:bulk-b:x").to_bytes()),
+        });
+        let (code, body) = bulk(
+            &state,
+            serde_json::json!([entry("bulk-a", &other, true), bad_proof]),
+        )
+        .await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body["registered"], 0);
+        assert_eq!(body["results"][0]["status"], "not_attempted");
+        assert_eq!(body["results"][1]["status"], "invalid");
+        assert_eq!(
+            register(&state, "bulk-a", &known).await,
+            StatusCode::CREATED
+        );
+
+        let (code, body) = bulk(
+            &state,
+            serde_json::json!([
+                entry("bulk-c", &other, true),
+                entry("bulk-known", &known, false),
+                entry("bulk-a", &other, false),
+                entry("bulk-d", &other, false),
+            ]),
+        )
+        .await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["registered"], 2);
+        let statuses: Vec<&str> = body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["status"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            statuses,
+            ["registered", "already_registered", "conflict", "registered"]
+        );
+        let keys: Vec<(i64,)> = sqlx::query_as(
+            "SELECT COUNT(*) FROM agent_keys WHERE agent_id IN ('bulk-c', 'bulk-d')",
+        )
+        .fetch_all(&state.pool)
+        .await
+        .unwrap();
+        assert_eq!(keys[0].0, 2);
+
+        // Listed twice, or too many, is refused outright.
+        let twice = serde_json::json!([
+            entry("bulk-e", &other, false),
+            entry("bulk-e", &other, false)
+        ]);
+        assert_eq!(bulk(&state, twice).await.0, StatusCode::BAD_REQUEST);
+        let many: Vec<_> = (0..=MAX_BULK_REGISTRATIONS)
+            .map(|i| entry(&format!("bulk-{i}"), &other, false))
+            .collect();
+        assert_eq!(
+            bulk(&state, many.into()).await.0,
+            StatusCode::PAYLOAD_TOO_LARGE
         );
     }
 
-    // Validate hash chain + ordering for this agent.
-    if let Err(msg) = validate_chain(&mut tx, &batch, &computed_hash).await {
-        log_submit_error(&batch.agent_id, &msg);
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(SubmitResponse {
-                status: "error".into(),
-                message: msg,
-            }),
+    #[tokio::test]
+    async fn revoked_agent_cannot_submit_or_rotate() {
+        let state = test_state().await;
+        let key = generate_keypair();
+        let b1 = signed_batch(&key, 1, [0u8; 32], "a");
+        submit(&state, &b1).await;
+
+        let Json(revoked) = admin::handler_revoke_agent(
+            State(state.clone()),
+            authed(&state, bearer("admin-secret")).await,
+            Path("agent-test".into()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(revoked).unwrap()["revoked_from_seq"],
+            2
+        );
+
+        let b2 = signed_batch(&key, 2, b1.compute_hash(), "b");
+        assert_eq!(submit(&state, &b2).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            rotate(&state, rotation("agent-test", &key, &generate_keypair(), 1)).await,
+            StatusCode::FORBIDDEN
         );
+
+        let Json(report) = admin::handler_integrity_check(
+            State(state.clone()),
+            authed(&state, bearer("admin-secret")).await,
+        )
+        .await
+        .unwrap();
+        let report = serde_json::to_value(report).unwrap();
+        assert_eq!(report["ok"], true);
     }
 
-    // Deduplicate by hash per agent to drop resends.
-    let duplicate = sqlx::query_scalar::<_, i64>(
-        "SELECT id FROM batches WHERE agent_id = ?1 AND hash = ?2 LIMIT 1",
-    )
-    .bind(&batch.agent_id)
-    .bind(computed_hash.to_vec())
-    .fetch_optional(tx.as_mut())
-    .await;
+    #[tokio::test]
+    async fn minted_tokens_gate_submit() {
+        let state = test_state().await;
+        let Json(minted) = admin::handler_create_token(
+            State(state.clone()),
+            authed(&state, bearer("admin-secret")).await,
+            Json(serde_json::from_value(serde_json::json!({"tenant": "site-a"})).unwrap()),
+        )
+        .await
+        .unwrap();
+        let token = serde_json::to_value(minted).unwrap()["token"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let key = generate_keypair();
+        let batch = signed_batch(&key, 1, [0u8; 32], "a");
+        // Minting a token closes the previously open /submit.
+        assert_eq!(submit(&state, &batch).await.status(), StatusCode::FORBIDDEN);
+        let resp = submit_with(&state, bearer(&token), &batch).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+    }
 
-    let duplicate = match duplicate {
-        Ok(v) => v,
-        Err(_) => {
-            log_submit_error(&batch.agent_id, "duplicate check failed");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(SubmitResponse {
-                    status: "error".into(),
-                    message: "failed to check duplicates".into(),
-                }),
-            );
-        }
-    };
+    #[tokio::test]
+    async fn concurrent_first_submits_resolve_deterministically() {
+        // Same key twice (an agent retrying its first batch): both succeed.
+        let state = file_state("race-same").await;
+        let key = generate_keypair();
+        let batch = signed_batch(&key, 1, [0u8; 32], "first");
+        let (a, b) = tokio::join!(submit(&state, &batch), submit(&state, &batch));
+        let mut codes = [a.status(), b.status()];
+        codes.sort();
+        assert_eq!(codes, [StatusCode::OK, StatusCode::CREATED]);
+
+        // Two different keys claiming a new agent: the first wins, the other is refused.
+        let state = file_state("race-diff").await;
+        let batch_a = signed_batch(&generate_keypair(), 1, [0u8; 32], "a");
+        let batch_b = signed_batch(&generate_keypair(), 1, [0u8; 32], "b");
+        let (a, b) = tokio::join!(submit(&state, &batch_a), submit(&state, &batch_b));
+        let mut codes = [a.status(), b.status()];
+        codes.sort();
+        assert_eq!(codes, [StatusCode::CREATED, StatusCode::FORBIDDEN]);
+
+        let winner = if a.status() == StatusCode::CREATED {
+            &batch_a
+        } else {
+            &batch_b
+        };
+        let stored: Vec<u8> =
+            sqlx::query_scalar("SELECT public_key FROM agents WHERE agent_id = ?1")
+                .bind("agent-test")
+                .fetch_one(&state.pool)
+                .await
+                .unwrap();
+        assert_eq!(stored, winner.public_key.to_bytes().to_vec());
+    }
 
-    if duplicate.is_some() {
-        log_submit_error(&batch.agent_id, "duplicate batch content for agent");
-        return (
-            StatusCode::CONFLICT,
-            Json(SubmitResponse {
-                status: "error".into(),
-                message: "duplicate batch content for agent".into(),
-            }),
+    #[tokio::test]
+    async fn accumulator_must_extend_previous_batch() {
+        let state = test_state().await;
+        let key = generate_keypair();
+        let accumulated = |seq, prev_hash, previous: Option<[u8; 32]>, line| {
+            let mut batch = signed_batch(&key, seq, prev_hash, line);
+            batch.accumulator = Some(batch.expected_accumulator(previous.as_ref()));
+            batch.sign(&key);
+            batch
+        };
+
+        let b1 = accumulated(1, [0u8; 32], None, "a");
+        let b2 = accumulated(2, b1.compute_hash(), b1.accumulator, "b");
+        assert_eq!(submit(&state, &b1).await.status(), StatusCode::CREATED);
+        assert_eq!(submit(&state, &b2).await.status(), StatusCode::CREATED);
+
+        // Skips b2's accumulator, as if b2 never existed.
+        let wrong = accumulated(3, b2.compute_hash(), b1.accumulator, "c");
+        assert_eq!(submit(&state, &wrong).await.status(), StatusCode::CONFLICT);
+        let dropped = signed_batch(&key, 3, b2.compute_hash(), "c");
+        assert_eq!(
+            submit(&state, &dropped).await.status(),
+            StatusCode::CONFLICT
         );
+        assert_eq!(rejected(&state, "accumulator_mismatch"), 2);
+
+        let checkpoints = state.checkpoints.get(&state.pool).await.unwrap();
+        assert_eq!(checkpoints[0].last_accumulator, b2.accumulator);
+        let stored = list(&state, ListParams::default()).await;
+        assert_eq!(stored[1].batch.accumulator, b2.accumulator);
+        assert!(stored[1].batch.verify());
     }
 
-    let insert_res = sqlx::query(
-        r#"
-        INSERT INTO batches (agent_id, seq, prev_hash, hash, logs, logs_compressed, timestamp, signature, public_key, received_at, source)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
-        "#,
-    )
-    .bind(&batch.agent_id)
-    .bind(batch.seq as i64)
-    .bind(batch.prev_hash.to_vec())
-    .bind(computed_hash.to_vec())
-    .bind(logs_json) // keep plaintext for search/filter, compressed for space
-    .bind(logs_compressed)
-    .bind(batch.timestamp as i64)
-    .bind(batch.signature.to_bytes().to_vec())
-    .bind(batch.public_key.to_bytes().to_vec())
-    .bind(now_unix())
-    .bind(addr.to_string())
-    .execute(tx.as_mut())
-    .await;
+    #[tokio::test]
+    async fn clock_drift_is_measured_not_enforced() {
+        let state = test_state().await;
+        let stamped = |agent: &str, offset_ms: i64, count: u64| {
+            let key = generate_keypair();
+            let mut prev_hash = [0u8; 32];
+            (1..=count)
+                .map(|seq| {
+                    let mut batch = signed_batch(&key, seq, prev_hash, "x");
+                    batch.agent_id = agent.into();
+                    batch.version = common::batch::BATCH_VERSION_V2;
+                    batch.timestamp = (now_unix_ms() + offset_ms) as u64;
+                    batch.sign(&key);
+                    prev_hash = batch.compute_hash();
+                    batch
+                })
+                .collect::<Vec<_>>()
+        };
 
-    if let Err(e) = insert_res {
-        if let sqlx::Error::Database(db) = &e {
-            if db.is_unique_violation() {
-                return (
-                    StatusCode::CONFLICT,
-                    Json(SubmitResponse {
-                        status: "error".into(),
-                        message: "duplicate batch for agent".into(),
-                    }),
-                );
-            }
+        // Two minutes behind: still stored, with the server's clock in the reply.
+        for batch in stamped("behind", -120_000, 3) {
+            let resp = submit(&state, &batch).await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+            let body: serde_json::Value = serde_json::from_str(&body_text(resp).await).unwrap();
+            assert!(body["server_time_ms"].as_u64().unwrap() >= batch.timestamp + 120_000);
         }
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(SubmitResponse {
-                status: "error".into(),
-                message: format!("failed to store batch: {}", e),
-            }),
-        );
+        for batch in stamped("ahead", 1_000, 2) {
+            assert_eq!(submit(&state, &batch).await.status(), StatusCode::CREATED);
+        }
+
+        let Json(statuses) = drift::handler_agent_status(State(state.clone()))
+            .await
+            .unwrap();
+        let statuses = serde_json::to_value(statuses).unwrap();
+        let ahead = &statuses[0];
+        let behind = &statuses[1];
+        assert_eq!(behind["agent_id"], "behind");
+        assert_eq!(behind["last_seq"], 3);
+        assert_eq!(behind["drift_samples"], 3);
+        let drift = behind["clock_drift_ms"].as_i64().unwrap();
+        assert!((120_000..125_000).contains(&drift), "drift {drift}");
+        assert_eq!(behind["drift_exceeded"], true);
+
+        let drift = ahead["clock_drift_ms"].as_i64().unwrap();
+        assert!((-1_000..4_000).contains(&drift), "drift {drift}");
+        assert_eq!(ahead["drift_exceeded"], false);
+
+        let metrics = body_text(handler_metrics(State(state.clone())).await.into_response()).await;
+        assert!(metrics.contains("logchain_agents_clock_drift_exceeded 1\n"));
+        assert!(metrics.contains("logchain_agent_clock_drift_ms{agent_id=\"behind\"}"));
+        assert!(!metrics.contains("logchain_agent_clock_drift_ms{agent_id=\"ahead\"}"));
     }
 
-    tx.commit().await.unwrap();
+    #[tokio::test]
+    async fn ephemeral_database_is_shared_across_requests_and_connections() {
+        assert!(is_memory_url("sqlite::memory:"));
+        assert!(is_memory_url("sqlite://demo?mode=memory&cache=shared"));
+        assert!(!is_memory_url("sqlite://logchain.db"));
+
+        let state = test_state().await;
+        let key = generate_keypair();
+        let first = signed_batch(&key, 1, [0u8; 32], "one");
+        assert_eq!(submit(&state, &first).await.status(), StatusCode::CREATED);
+        // The second batch only chains if the first is visible.
+        let second = signed_batch(&key, 2, first.compute_hash(), "two");
+        assert_eq!(submit(&state, &second).await.status(), StatusCode::CREATED);
+
+        // A connection opened outside the pool attaches to the same database.
+        let mut other = state.pool.connect_options().connect().await.unwrap();
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM batches")
+            .fetch_one(&mut other)
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+
+        // Each ephemeral pool is its own database.
+        let fresh = connect_pool(EPHEMERAL_DATABASE_URL, ack::AckMode::Durable)
+            .await
+            .unwrap();
+        let tables: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE name = 'batches'")
+                .fetch_one(&fresh)
+                .await
+                .unwrap();
+        assert_eq!(tables, 0);
+    }
 
-    (
-        StatusCode::CREATED,
-        Json(SubmitResponse {
-            status: "ok".into(),
-            message: "batch stored".into(),
-        }),
-    )
-}
+    #[tokio::test]
+    async fn stale_agents_are_judged_by_arrival_time() {
+        let state = test_state().await;
+        let quiet = generate_keypair();
+        let revoked = generate_keypair();
+        let mut a = signed_batch(&quiet, 1, [0u8; 32], "a");
+        // An agent clock far in the future must not make it look alive.
+        a.timestamp = 4_000_000_000;
+        a.sign(&quiet);
+        let mut b = signed_batch(&revoked, 1, [0u8; 32], "b");
+        b.agent_id = "agent-revoked".into();
+        b.sign(&revoked);
+        assert_eq!(submit(&state, &a).await.status(), StatusCode::CREATED);
+        assert_eq!(submit(&state, &b).await.status(), StatusCode::CREATED);
+        let Json(_) = admin::handler_revoke_agent(
+            State(state.clone()),
+            authed(&state, bearer("admin-secret")).await,
+            Path("agent-revoked".into()),
+        )
+        .await
+        .unwrap();
 
-/* ----------------------- REGISTER / ROTATE AGENT KEYS ----------------------- */
+        let Json(now) = stale::handler_stale_agents(
+            State(state.clone()),
+            Query(serde_json::from_value(serde_json::json!({})).unwrap()),
+        )
+        .await
+        .unwrap();
+        assert!(now.is_empty());
+
+        let later = now_unix_ms() + 120_000;
+        let stale = stale::stale_agents(&state.pool, 60, later).await.unwrap();
+        let stale = serde_json::to_value(stale).unwrap();
+        assert_eq!(stale.as_array().unwrap().len(), 1);
+        assert_eq!(stale[0]["agent_id"], "agent-test");
+        let silent = stale[0]["silent_for_secs"].as_u64().unwrap();
+        assert!((120..125).contains(&silent), "{silent}");
+        assert!(
+            stale::stale_agents(&state.pool, 600, later)
+                .await
+                .unwrap()
+                .is_empty()
+        );
 
-async fn handler_register_agent(
-    State(state): State<AppState>,
-    Json(req): Json<RegisterRequest>,
-) -> impl IntoResponse {
-    let pk = match parse_hex_public_key(&req.public_key_hex) {
-        Ok(pk) => pk,
-        Err(msg) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(AgentResponse {
-                    status: "error".into(),
-                    message: msg,
-                }),
+        let resp = handler_metrics(State(state.clone())).await.into_response();
+        assert!(body_text(resp).await.contains("logchain_agents_stale 0\n"));
+    }
+
+    #[tokio::test]
+    async fn structured_logs_round_trip_byte_identical() {
+        let state = test_state().await;
+        let key = generate_keypair();
+        let structured = [
+            r#"{"z":1,"a":{"y":[1,2.50,"x"],"b":null}}"#,
+            r#"{ "spaced" : true }"#,
+            "tab\tquote\" backslash\\ caf\u{e9} \u{1F600} nul\u{0}",
+        ];
+        let mut short = signed_batch(&key, 1, [0u8; 32], structured[0]);
+        short.logs = structured[..2].iter().map(|l| l.to_string()).collect();
+        short.sign(&key);
+        // Long enough to be stored compressed (test threshold is 64 bytes).
+        let mut long = signed_batch(&key, 2, short.compute_hash(), "");
+        long.logs = structured
+            .iter()
+            .cycle()
+            .take(30)
+            .map(|l| l.to_string())
+            .collect();
+        long.sign(&key);
+        assert_eq!(submit(&state, &short).await.status(), StatusCode::CREATED);
+        assert_eq!(submit(&state, &long).await.status(), StatusCode::CREATED);
+
+        for (id, source) in [(1, &short), (2, &long)] {
+            let row = sqlx::query("SELECT logs, logs_compressed FROM batches WHERE id = ?1")
+                .bind(id)
+                .fetch_one(&state.pool)
+                .await
+                .unwrap();
+            let stored = match row.get::<Option<Vec<u8>>, _>("logs_compressed") {
+                Some(blob) => state.decompressor.json(&blob).unwrap(),
+                None => row.get("logs"),
+            };
+            assert_eq!(stored, canonical_logs_json(&source.logs));
+
+            let Json(loaded) = handler_get_one(State(state.clone()), Path(id))
+                .await
+                .unwrap();
+            assert_eq!(loaded.batch.logs, source.logs);
+            assert_eq!(canonical_logs_json(&loaded.batch.logs), stored);
+            assert!(loaded.batch.verify());
+            assert_eq!(loaded.batch.compute_hash(), loaded.hash);
+        }
+        // The object keys were not sorted or re-spaced.
+        assert_eq!(
+            canonical_logs_json(&short.logs),
+            format!(
+                "[{},{}]",
+                serde_json::to_string(structured[0]).unwrap(),
+                serde_json::to_string(structured[1]).unwrap()
             )
+        );
+
+        let Json(report) = admin::handler_integrity_check(
+            State(state.clone()),
+            authed(&state, bearer("admin-secret")).await,
+        )
+        .await
+        .unwrap();
+        let report = serde_json::to_value(report).unwrap();
+        assert_eq!(report["logs_issues"], serde_json::json!([]));
+        assert_eq!(report["ok"], true);
+
+        assert!(parse_stored_logs(r#"["a","b"]"#).unwrap().1);
+        let (spaced, canonical) = parse_stored_logs(r#"[ "a", "b" ]"#).unwrap();
+        assert_eq!(spaced, vec!["a", "b"]);
+        assert!(!canonical);
+        assert!(!parse_stored_logs(r#"["caf\u00e9"]"#).unwrap().1);
+        assert!(parse_stored_logs(r#"{"a":1}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn anomaly_scores_are_stored_and_counted_but_never_reject() {
+        let mut state = test_state().await;
+        assert_eq!(
+            anomaly::handler_anomaly_stats(State(state.clone()))
+                .await
+                .unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        let tracker = Arc::new(anomaly::AnomalyTracker::new(4.0));
+        state.anomaly = Some(tracker.clone());
+
+        // A history of small batches every ten seconds, ending ten seconds ago.
+        let key = generate_keypair();
+        let agent_id = signed_batch(&key, 1, [0u8; 32], "x").agent_id;
+        let start = now_unix_ms() - 10_000 * anomaly::WARMUP_BATCHES as i64;
+        for i in 0..anomaly::WARMUP_BATCHES as i64 {
+            tracker.record(&agent_id, 5, start + i * 10_000, None);
+        }
+
+        let normal = signed_batch(&key, 1, [0u8; 32], "x");
+        assert_eq!(submit(&state, &normal).await.status(), StatusCode::CREATED);
+        let huge = signed_batch(&key, 2, normal.compute_hash(), &"y".repeat(100_000));
+        assert_eq!(submit(&state, &huge).await.status(), StatusCode::CREATED);
+
+        let scores: Vec<Option<f64>> =
+            sqlx::query_scalar("SELECT anomaly_score FROM batches ORDER BY seq")
+                .fetch_all(&state.pool)
+                .await
+                .unwrap();
+        let (normal_score, huge_score) = (scores[0].unwrap(), scores[1].unwrap());
+        assert!(normal_score < 4.0, "normal batch scored {normal_score}");
+        assert!(huge_score > 4.0, "huge batch scored {huge_score}");
+        assert_eq!(state.metrics.get("logchain_submit_anomalies_total"), 1);
+
+        let Json(stats) = anomaly::handler_anomaly_stats(State(state.clone()))
+            .await
+            .unwrap();
+        let stats = serde_json::to_value(stats).unwrap();
+        assert_eq!(stats[0]["batches"], anomaly::WARMUP_BATCHES + 2);
+        assert_eq!(stats[0]["last_score"], huge_score);
+    }
+
+    #[tokio::test]
+    async fn every_scope_guards_exactly_its_endpoints() {
+        use tower::ServiceExt;
+
+        let mut state = test_state().await;
+        state.auth_token = Some("submit-secret".into());
+        let next_ip = Arc::new(std::sync::atomic::AtomicU8::new(1));
+        let call = |state: AppState, token: Option<String>, scope: Scope| {
+            let next_ip = next_ip.clone();
+            async move {
+                let agent_id = format!(
+                    "matrix-{}",
+                    next_ip.load(std::sync::atomic::Ordering::Relaxed)
+                );
+                let key = generate_keypair();
+                let (method, uri, body) = match scope {
+                    Scope::Submit => {
+                        let mut batch = signed_batch(&key, 1, [0u8; 32], "x");
+                        batch.agent_id = agent_id;
+                        batch.sign(&key);
+                        ("POST", "/submit", serde_json::to_vec(&batch).unwrap())
+                    }
+                    Scope::Read => ("GET", "/batches", Vec::new()),
+                    Scope::Export => ("GET", "/batches/export", Vec::new()),
+                    Scope::Admin => ("GET", "/admin/rejections", Vec::new()),
+                    Scope::Register => {
+                        let req = serde_json::json!({
+                            "agent_id": agent_id,
+                            "public_key_hex": hex_encode(&key.verifying_key().to_bytes()),
+                        });
+                        (
+                            "POST",
+                            "/agents/register",
+                            serde_json::to_vec(&req).unwrap(),
+                        )
+                    }
+                };
+                let mut request = axum::http::Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header("content-type", "application/json");
+                if let Some(token) = token {
+                    request = request.header("authorization", format!("Bearer {token}"));
+                }
+                let mut request = request.body(Body::from(body)).unwrap();
+                // A fresh client per call keeps the auth-failure throttle out of the matrix.
+                let ip = next_ip.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                request
+                    .extensions_mut()
+                    .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, ip], 9000))));
+                build_router(state).oneshot(request).await.unwrap().status()
+            }
+        };
+
+        // No minted tokens yet: only submit and admin have a (bootstrap) credential.
+        for scope in Scope::ALL {
+            let status = call(state.clone(), None, scope).await;
+            match scope {
+                Scope::Submit => assert_eq!(status, StatusCode::FORBIDDEN),
+                Scope::Admin => assert_eq!(status, StatusCode::UNAUTHORIZED),
+                _ => assert!(status.is_success(), "anonymous {scope:?}: {status}"),
+            }
+        }
+
+        let mint = |body: serde_json::Value| {
+            let state = state.clone();
+            async move {
+                admin::handler_create_token(
+                    State(state.clone()),
+                    authed(&state, bearer("admin-secret")).await,
+                    Json(serde_json::from_value(body).unwrap()),
+                )
+                .await
+                .map(|Json(minted)| serde_json::to_value(minted).unwrap())
+                .map_err(|(status, _)| status)
+            }
+        };
+        let mut holders = vec![
+            ("submit-secret".to_string(), vec![Scope::Submit]),
+            (
+                "admin-secret".to_string(),
+                vec![Scope::Admin, Scope::Read, Scope::Export, Scope::Register],
+            ),
+        ];
+        for scope in Scope::ALL {
+            let minted = mint(serde_json::json!({"tenant": "t", "scopes": [scope]}))
+                .await
+                .unwrap();
+            holders.push((minted["token"].as_str().unwrap().to_string(), vec![scope]));
+        }
+
+        for scope in Scope::ALL {
+            let status = call(state.clone(), None, scope).await;
+            let expected = match scope {
+                Scope::Submit => StatusCode::FORBIDDEN,
+                _ => StatusCode::UNAUTHORIZED,
+            };
+            assert_eq!(status, expected, "anonymous {scope:?}");
+            for (token, granted) in &holders {
+                let status = call(state.clone(), Some(token.clone()), scope).await;
+                if granted.contains(&scope) {
+                    assert!(status.is_success(), "{granted:?} on {scope:?}: {status}");
+                } else {
+                    assert_eq!(status, StatusCode::FORBIDDEN, "{granted:?} on {scope:?}");
+                }
+            }
         }
-    };
 
-    let existing = sqlx::query("SELECT public_key FROM agents WHERE agent_id = ?1")
-        .bind(&req.agent_id)
-        .fetch_optional(&state.pool)
+        // Expired and revoked tokens count as no token.
+        let expired =
+            mint(serde_json::json!({"tenant": "t", "scopes": ["read"], "expires_in_secs": 0}))
+                .await
+                .unwrap();
+        let token = expired["token"].as_str().unwrap().to_string();
+        assert_eq!(
+            call(state.clone(), Some(token), Scope::Read).await,
+            StatusCode::UNAUTHORIZED
+        );
+        let read_token = holders[3].0.clone();
+        let revoke = |id: i64| {
+            let state = state.clone();
+            async move {
+                admin::handler_revoke_token(
+                    State(state.clone()),
+                    authed(&state, bearer("admin-secret")).await,
+                    Path(id),
+                )
+                .await
+                .map(|_| ())
+                .map_err(|(status, _)| status)
+            }
+        };
+        let read_id: i64 = sqlx::query_scalar(
+            "SELECT id FROM api_tokens WHERE scopes = 'read' ORDER BY id LIMIT 1",
+        )
+        .fetch_one(&state.pool)
         .await
         .unwrap();
+        assert_eq!(revoke(read_id).await, Ok(()));
+        assert_eq!(revoke(read_id).await, Err(StatusCode::NOT_FOUND));
+        assert_eq!(
+            call(state.clone(), Some(read_token), Scope::Read).await,
+            StatusCode::UNAUTHORIZED
+        );
 
-    if let Some(row) = existing {
-        let stored: Vec<u8> = row.get("public_key");
-        if stored == pk.to_bytes() {
-            return (
-                StatusCode::OK,
-                Json(AgentResponse {
-                    status: "ok".into(),
-                    message: "agent already registered with this key".into(),
-                }),
+        // Agent-bound tokens act for their agent only, and never read.
+        let bound =
+            mint(serde_json::json!({"tenant": "t", "scopes": ["submit"], "agent_id": "bound"}))
+                .await
+                .unwrap();
+        assert_eq!(
+            mint(serde_json::json!({"tenant": "t", "scopes": ["read"], "agent_id": "bound"})).await,
+            Err(StatusCode::BAD_REQUEST)
+        );
+        let bound_auth = authed(&state, bearer(bound["token"].as_str().unwrap())).await;
+        let key = generate_keypair();
+        let mut own = signed_batch(&key, 1, [0u8; 32], "x");
+        own.agent_id = "bound".into();
+        own.sign(&key);
+        let other = signed_batch(&key, 1, [0u8; 32], "x");
+        for (batch, expected) in [(&other, StatusCode::FORBIDDEN), (&own, StatusCode::CREATED)] {
+            let resp = handler_submit_batch(
+                State(state.clone()),
+                ConnectInfo(SocketAddr::from(([10, 0, 1, 1], 9000))),
+                bound_auth.clone(),
+                HeaderMap::new(),
+                Bytes::from(serde_json::to_vec(batch).unwrap()),
+            )
+            .await
+            .into_response();
+            assert_eq!(resp.status(), expected, "{}", batch.agent_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_logs_download_as_text_or_gzip() {
+        let state = test_state().await;
+        let key = generate_keypair();
+        let short = signed_batch(&key, 1, [0u8; 32], "one");
+        let mut long = signed_batch(&key, 2, short.compute_hash(), "two");
+        long.logs.push("x".repeat(200));
+        long.sign(&key);
+        for batch in [&short, &long] {
+            assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
+        }
+
+        let get = |id: i64, format: &str| {
+            let state = state.clone();
+            let params: LogsParams =
+                serde_json::from_value(serde_json::json!({ "format": format })).unwrap();
+            async move { handler_get_logs(State(state), Path(id), Query(params)).await }
+        };
+        // Row 1 is stored plaintext, row 2 compressed; both read back the same way.
+        for (id, batch) in [(1, &short), (2, &long)] {
+            let expected: String = batch.logs.iter().map(|l| format!("{l}\n")).collect();
+            let resp = get(id, "text").await.unwrap();
+            assert_eq!(
+                resp.headers()[header::CONTENT_TYPE],
+                "text/plain; charset=utf-8"
             );
-        } else {
-            return (
-                StatusCode::CONFLICT,
-                Json(AgentResponse {
-                    status: "error".into(),
-                    message: "agent ID already registered with a different key".into(),
-                }),
+            assert_eq!(body_text(resp).await, expected);
+
+            let resp = get(id, "gzip").await.unwrap();
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/gzip");
+            assert_eq!(
+                resp.headers()[header::CONTENT_DISPOSITION],
+                format!("attachment; filename=\"batch-{id}.log.gz\"")
             );
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(state.decompressor.json(&bytes).unwrap(), expected);
         }
+        assert_eq!(get(99, "text").await.unwrap_err(), StatusCode::NOT_FOUND);
     }
 
-    sqlx::query("INSERT INTO agents (agent_id, public_key, created_at) VALUES (?1, ?2, ?3)")
-        .bind(&req.agent_id)
-        .bind(pk.to_bytes().to_vec())
-        .bind(now_unix())
-        .execute(&state.pool)
+    #[tokio::test]
+    async fn unique_agent_keys_refuses_a_key_held_by_another_agent() {
+        // Off: a shared key is stored, then reported by the audit.
+        let state = test_state().await;
+        let shared = generate_keypair();
+        assert_eq!(
+            register(&state, "host-a", &shared).await,
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            register(&state, "host-b", &shared).await,
+            StatusCode::CREATED
+        );
+        let Json(conflicts) = admin::handler_key_conflicts(
+            State(state.clone()),
+            authed(&state, bearer("admin-secret")).await,
+        )
         .await
         .unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].agent_ids, ["host-a", "host-b"]);
+        assert!(
+            key_conflicts::render_metrics(&state)
+                .await
+                .contains("logchain_agent_key_conflicts 1")
+        );
 
-    (
-        StatusCode::CREATED,
-        Json(AgentResponse {
-            status: "ok".into(),
-            message: "agent registered".into(),
-        }),
-    )
-}
-
-async fn handler_rotate_agent(
-    State(state): State<AppState>,
-    Json(req): Json<RotateRequest>,
-) -> impl IntoResponse {
-    let Some(row) = sqlx::query("SELECT public_key FROM agents WHERE agent_id = ?1")
-        .bind(&req.agent_id)
-        .fetch_optional(&state.pool)
+        let state = AppState {
+            unique_agent_keys: true,
+            ..test_state().await
+        };
+        assert_eq!(
+            register(&state, "host-a", &shared).await,
+            StatusCode::CREATED
+        );
+        assert_eq!(register(&state, "host-a", &shared).await, StatusCode::OK);
+
+        // Registration names the agent holding the key.
+        let resp = handler_register_agent(
+            State(state.clone()),
+            authed(&state, HeaderMap::new()).await,
+            HeaderMap::new(),
+            Json(RegisterRequest {
+                agent_id: "host-b".into(),
+                public_key_hex: hex_encode(&shared.verifying_key().to_bytes()),
+            }),
+        )
         .await
-        .unwrap() else {
-            return (
-                StatusCode::NOT_FOUND,
-                Json(AgentResponse {
-                    status: "error".into(),
-                    message: "agent not registered".into(),
-                }),
+        .into_response();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert!(body_text(resp).await.contains("agent 'host-a'"));
+
+        // Auto-registration: refused like any key rejection, reason audited.
+        let mut batch = signed_batch(&shared, 1, [0u8; 32], "copied state dir");
+        batch.agent_id = "host-c".into();
+        batch.sign(&shared);
+        assert_eq!(submit(&state, &batch).await.status(), StatusCode::FORBIDDEN);
+        let reason: String =
+            sqlx::query_scalar("SELECT reason FROM rejections WHERE agent_id = 'host-c'")
+                .fetch_one(&state.pool)
+                .await
+                .unwrap();
+        assert!(reason.contains("agent 'host-a'"), "{reason}");
+
+        // Rotation onto the held key is refused and leaves the counter unused.
+        let own = generate_keypair();
+        assert_eq!(register(&state, "host-d", &own).await, StatusCode::CREATED);
+        assert_eq!(
+            rotate(&state, rotation("host-d", &own, &shared, 1)).await,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            rotate(&state, rotation("host-d", &own, &generate_keypair(), 1)).await,
+            StatusCode::OK
+        );
+
+        let agents: Vec<String> =
+            sqlx::query_scalar("SELECT agent_id FROM agents ORDER BY agent_id")
+                .fetch_all(&state.pool)
+                .await
+                .unwrap();
+        assert_eq!(agents, ["host-a", "host-d"]);
+        assert!(
+            key_conflicts::key_conflicts(&state.pool)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn withheld_or_unversioned_rotations_are_refused() {
+        let state = test_state().await;
+        let k1 = generate_keypair();
+        let k2 = generate_keypair();
+        assert_eq!(
+            register(&state, "agent-rot", &k1).await,
+            StatusCode::CREATED
+        );
+        let now = now_unix() as u64;
+
+        // Captured and held back past the window, or dated ahead of it.
+        for ts in [now - 360, now + 360] {
+            assert_eq!(
+                rotate(&state, rotation_at("agent-rot", &k1, &k2, 1, Some(ts))).await,
+                StatusCode::CONFLICT
             );
-        };
+        }
+        // Re-dating a captured request breaks its signature.
+        let mut redated = rotation_at("agent-rot", &k1, &k2, 1, Some(now - 360));
+        redated.timestamp = Some(now);
+        assert_eq!(rotate(&state, redated).await, StatusCode::UNAUTHORIZED);
+        // v1 carries no timestamp and is off unless ROTATION_ALLOW_V1 is set.
+        assert_eq!(
+            rotate(&state, rotation_at("agent-rot", &k1, &k2, 1, None)).await,
+            StatusCode::BAD_REQUEST
+        );
 
-    let stored: Vec<u8> = row.get("public_key");
-    let current_pk = match stored.try_into() {
-        Ok(bytes) => match VerifyingKey::from_bytes(&bytes) {
-            Ok(pk) => pk,
-            Err(_) => {
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(AgentResponse {
-                        status: "error".into(),
-                        message: "stored public key is invalid".into(),
-                    }),
-                )
-            }
-        },
-        Err(_) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(AgentResponse {
-                    status: "error".into(),
-                    message: "stored public key is invalid".into(),
-                }),
+        // None of the refusals consumed counter 1; a fresh request uses it once.
+        let fresh = rotation("agent-rot", &k1, &k2, 1);
+        let replay = rotation_at("agent-rot", &k1, &k2, 1, fresh.timestamp);
+        assert_eq!(rotate(&state, fresh).await, StatusCode::OK);
+        // Inside the window, a replay fails on the retired key's signature.
+        assert_eq!(
+            rotate(
+                &state,
+                rotation_at("agent-rot", &k1, &k2, 1, replay.timestamp)
             )
-        }
-    };
+            .await,
+            StatusCode::UNAUTHORIZED
+        );
 
-    let new_pk = match parse_hex_public_key(&req.new_public_key_hex) {
-        Ok(pk) => pk,
-        Err(msg) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(AgentResponse {
-                    status: "error".into(),
-                    message: msg,
-                }),
+        let state = AppState {
+            rotation: rotation::RotationPolicy {
+                allow_v1: true,
+                ..state.rotation.clone()
+            },
+            ..state
+        };
+        assert_eq!(
+            rotate(&state, rotation_at("agent-rot", &k2, &k1, 2, None)).await,
+            StatusCode::OK
+        );
+        // Back on k1, the captured request verifies and is still fresh; only
+        // the counter stops it from handing the agent to k2 again.
+        assert_eq!(rotate(&state, replay).await, StatusCode::CONFLICT);
+    }
+
+    /// Writes rows straight into `batches`, as someone with the database file
+    /// could; the seq trigger that would refuse them is dropped first.
+    async fn store_directly(state: &AppState, chain: &[LogBatch], hashes: &[[u8; 32]]) {
+        sqlx::query("DROP TRIGGER IF EXISTS batches_enforce_seq")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        for (batch, hash) in chain.iter().zip(hashes) {
+            sqlx::query(
+                "INSERT INTO batches (agent_id, seq, prev_hash, hash, logs, timestamp, signature, public_key, batch_version, accumulator) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
             )
+            .bind(&batch.agent_id)
+            .bind(batch.seq as i64)
+            .bind(batch.prev_hash.to_vec())
+            .bind(hash.to_vec())
+            .bind(canonical_logs_json(&batch.logs))
+            .bind(batch.timestamp as i64)
+            .bind(batch.signature.to_bytes().to_vec())
+            .bind(batch.public_key.to_bytes().to_vec())
+            .bind(batch.version as i64)
+            .bind(batch.accumulator.map(|a| a.to_vec()))
+            .execute(&state.pool)
+            .await
+            .unwrap();
         }
-    };
+    }
 
-    let sig = match parse_hex_signature(&req.auth_signature_hex) {
-        Ok(sig) => sig,
-        Err(msg) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(AgentResponse {
-                    status: "error".into(),
-                    message: msg,
-                }),
+    #[tokio::test]
+    async fn integrity_scan_reports_every_tamper() {
+        use common::testutil::{
+            build_chain, chain_hashes, drop_seq, flip_log_line, mutate_hash, reorder, resign_with,
+        };
+
+        let state = test_state().await;
+        let mut expected = Vec::new();
+        for agent in ["clean", "flip", "reorder", "drop", "resign", "hash"] {
+            let key = generate_keypair();
+            let mut chain = build_chain(&key, agent, 4);
+            let mut hashes = chain_hashes(&chain);
+            record_key(
+                state.pool.acquire().await.unwrap().as_mut(),
+                agent,
+                key.verifying_key().as_bytes(),
+                (0, 1),
             )
+            .await
+            .unwrap();
+            match agent {
+                "flip" => {
+                    flip_log_line(&mut chain, 2);
+                    expected.push((
+                        "content_issues",
+                        agent,
+                        2,
+                        "stored hash does not match contents",
+                    ));
+                }
+                "reorder" => {
+                    reorder(&mut chain, 2, 3, &key);
+                    hashes = chain_hashes(&chain);
+                    for seq in 2..=4 {
+                        expected.push(("broken_links", agent, seq, "prev_hash mismatch"));
+                    }
+                }
+                "drop" => {
+                    drop_seq(&mut chain, 3);
+                    hashes.remove(2);
+                    expected.push(("broken_links", agent, 4, "missing previous seq"));
+                }
+                "resign" => {
+                    // The hash does not cover the key, so the links still hold.
+                    resign_with(&mut chain, 3, &generate_keypair());
+                    expected.push((
+                        "content_issues",
+                        agent,
+                        3,
+                        "signed by a key outside its validity window",
+                    ));
+                }
+                "hash" => {
+                    mutate_hash(&mut hashes[1]);
+                    expected.push((
+                        "content_issues",
+                        agent,
+                        2,
+                        "stored hash does not match contents",
+                    ));
+                    expected.push(("broken_links", agent, 3, "prev_hash mismatch"));
+                }
+                _ => {}
+            }
+            store_directly(&state, &chain, &hashes).await;
         }
-    };
 
-    let rotation_message =
-        format!("rotate:{}:{}", req.agent_id, req.new_public_key_hex).into_bytes();
+        let Json(report) = admin::handler_integrity_check(
+            State(state.clone()),
+            authed(&state, bearer("admin-secret")).await,
+        )
+        .await
+        .unwrap();
+        let report = serde_json::to_value(report).unwrap();
+        assert_eq!(report["ok"], false);
+        assert_eq!(report["logs_issues"], serde_json::json!([]));
+        let mut found: Vec<(&str, String, u64, String)> = ["broken_links", "content_issues"]
+            .into_iter()
+            .flat_map(|field| {
+                report[field].as_array().unwrap().iter().map(move |issue| {
+                    (
+                        field,
+                        issue["agent_id"].as_str().unwrap().to_string(),
+                        issue["seq"].as_u64().unwrap(),
+                        issue["reason"].as_str().unwrap().to_string(),
+                    )
+                })
+            })
+            .collect();
+        let mut expected: Vec<(&str, String, u64, String)> = expected
+            .into_iter()
+            .map(|(field, agent, seq, reason)| (field, agent.to_string(), seq, reason.to_string()))
+            .collect();
+        found.sort();
+        expected.sort();
+        assert_eq!(found, expected);
+    }
 
-    if current_pk
-        .verify_strict(&rotation_message, &sig)
-        .is_err()
-    {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(AgentResponse {
-                status: "error".into(),
-                message: "rotation signature invalid".into(),
-            }),
+    #[tokio::test]
+    async fn gap_markers_need_the_policy_and_keep_the_chain_whole() {
+        use common::testutil::{append_gap, build_chain, extend_chain};
+
+        let state = test_state().await;
+        let key = generate_keypair();
+        let mut chain = build_chain(&key, "agent-gap", 2);
+        append_gap(&mut chain, &key, 3, "agent restarted without its state");
+        extend_chain(&mut chain, &key, 1);
+        let marker = chain[2].clone();
+        assert_eq!(marker.seq, 6);
+
+        for batch in &chain[..2] {
+            assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
+        }
+        let refused = submit(&state, &marker).await;
+        assert_eq!(refused.status(), StatusCode::CONFLICT);
+        assert!(body_text(refused).await.contains("not accepted"));
+        assert_eq!(rejected(&state, "gap_refused"), 1);
+
+        let state = AppState {
+            accept_gap_markers: true,
+            accept_after_close: false,
+            ..state
+        };
+        let mut smuggled = marker.clone();
+        smuggled.logs.push("hidden".into());
+        smuggled.sign(&key);
+        assert_eq!(
+            submit(&state, &smuggled).await.status(),
+            StatusCode::CONFLICT
         );
-    }
+        assert_eq!(rejected(&state, "gap_refused"), 2);
+        // Declaring a stored seq lost is a seq conflict, signed or not.
+        let mut overlapping = marker.clone();
+        overlapping.gap.as_mut().unwrap().missing_from = 2;
+        overlapping.sign(&key);
+        let conflict = submit(&state, &overlapping).await;
+        assert_eq!(conflict.status(), StatusCode::CONFLICT);
+        assert!(body_text(conflict).await.contains("expected 3, got 2"));
+
+        assert_eq!(submit(&state, &marker).await.status(), StatusCode::CREATED);
+        assert_eq!(
+            submit(&state, &chain[3]).await.status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(state.metrics.get("logchain_submit_gap_markers_total"), 1);
 
-    sqlx::query("UPDATE agents SET public_key = ?1 WHERE agent_id = ?2")
-        .bind(new_pk.to_bytes().to_vec())
-        .bind(&req.agent_id)
-        .execute(&state.pool)
+        let stored = list(&state, ListParams::default()).await;
+        assert_eq!(
+            stored.iter().map(|b| b.batch.seq).collect::<Vec<_>>(),
+            [1, 2, 6, 7]
+        );
+        assert_eq!(stored[2].batch.gap, marker.gap);
+        assert!(stored[2].batch.verify());
+        let Json(report) = admin::handler_integrity_check(
+            State(state.clone()),
+            authed(&state, bearer("admin-secret")).await,
+        )
         .await
         .unwrap();
+        let report = serde_json::to_value(report).unwrap();
+        assert_eq!(report["ok"], true, "{report}");
+
+        // The trigger holds a marker to its range even past the API.
+        let err = sqlx::query(
+            "INSERT INTO batches (agent_id, seq, prev_hash, hash, logs, timestamp, signature, public_key, gap_from, gap_to) \
+             VALUES ('agent-gap', 10, ?1, ?2, '[]', 0, x'00', x'00', 8, 8)",
+        )
+        .bind(chain[3].compute_hash().to_vec())
+        .bind(vec![0xaa; 32])
+        .execute(&state.pool)
+        .await
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("gap must end before its marker"),
+            "{err}"
+        );
+    }
 
-    (
-        StatusCode::OK,
-        Json(AgentResponse {
-            status: "ok".into(),
-            message: "agent key rotated".into(),
-        }),
-    )
-}
+    #[tokio::test]
+    async fn session_starts_are_stored_and_listed_per_agent() {
+        use common::testutil::{append_session_start, build_chain, extend_chain};
+
+        let state = test_state().await;
+        let key = generate_keypair();
+        // Two runs: one that found the chain with a batch in it, and a restart.
+        let mut chain = build_chain(&key, "agent-s", 1);
+        append_session_start(&mut chain, &key, "session-one");
+        extend_chain(&mut chain, &key, 2);
+        append_session_start(&mut chain, &key, "session-two");
+        extend_chain(&mut chain, &key, 1);
+
+        let mut smuggled = chain[1].clone();
+        smuggled.logs.push("hidden".into());
+        smuggled.sign(&key);
+        assert_eq!(chain[0].seq, 1);
+        assert_eq!(
+            submit(&state, &chain[0]).await.status(),
+            StatusCode::CREATED
+        );
+        let refused = submit(&state, &smuggled).await;
+        assert_eq!(refused.status(), StatusCode::BAD_REQUEST);
+        assert!(
+            body_text(refused)
+                .await
+                .contains("session start must carry no logs")
+        );
+        assert_eq!(rejected(&state, "malformed_kind"), 1);
 
-/* ----------------------- GET /batches ----------------------- */
+        for batch in &chain[1..] {
+            assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
+        }
+        assert_eq!(state.metrics.get("logchain_submit_session_starts_total"), 2);
+
+        let stored = list(&state, ListParams::default()).await;
+        assert_eq!(stored[1].batch.kind, chain[1].kind);
+        assert!(stored[1].batch.verify());
+        assert_eq!(stored.iter().filter(|b| b.batch.kind.is_some()).count(), 2);
+
+        let resp = route(
+            &state,
+            "GET",
+            "/agents/agent-s/sessions",
+            None,
+            Vec::new(),
+            1,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let sessions: Vec<serde_json::Value> =
+            serde_json::from_str(&body_text(resp).await).unwrap();
+        let boundaries: Vec<_> = sessions
+            .iter()
+            .map(|s| {
+                (
+                    s["seq"].as_u64().unwrap(),
+                    s["session_id"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(boundaries, [(2, "session-one"), (5, "session-two")]);
+        assert_eq!(sessions[0]["boot_time_ms"], chain[1].timestamp);
+        assert_eq!(
+            sessions[0]["hash"],
+            serde_json::json!(chain[1].compute_hash())
+        );
 
-async fn handler_get_all(
-    State(state): State<AppState>,
-    Query(params): Query<ListParams>,
-) -> Result<Json<Vec<QueryBatch>>, StatusCode> {
-    let mut builder = QueryBuilder::new("SELECT * FROM batches");
-    let mut first_clause = true;
+        let other = route(
+            &state,
+            "GET",
+            "/agents/agent-none/sessions",
+            None,
+            Vec::new(),
+            1,
+        )
+        .await;
+        assert_eq!(body_text(other).await, "[]");
+    }
 
-    if params.agent_id.is_some()
-        || params.since_seq.is_some()
-        || params.since_timestamp.is_some()
-        || params.until_timestamp.is_some()
-        || params.log_substring.is_some()
-    {
-        builder.push(" WHERE ");
+    #[tokio::test]
+    async fn submits_after_a_chain_close_are_refused_unless_accepted() {
+        use common::testutil::{append_closed, build_chain, extend_chain};
+
+        let state = test_state().await;
+        let key = generate_keypair();
+        let mut chain = build_chain(&key, "agent-c", 2);
+        append_closed(&mut chain, &key, "host decommissioned");
+        extend_chain(&mut chain, &key, 1);
+        for batch in &chain[..3] {
+            assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
+        }
+        assert_eq!(state.metrics.get("logchain_submit_chain_closes_total"), 1);
+        let stored = list(&state, ListParams::default()).await;
+        assert_eq!(stored[2].batch.closed_reason(), Some("host decommissioned"));
+        assert!(stored[2].batch.verify());
+
+        // A retry of the close itself is still a benign resend.
+        assert_eq!(submit(&state, &chain[2]).await.status(), StatusCode::OK);
+        let refused = submit(&state, &chain[3]).await;
+        assert_eq!(refused.status(), StatusCode::CONFLICT);
+        assert!(
+            body_text(refused)
+                .await
+                .contains("agent closed its chain at seq 3: host decommissioned")
+        );
+        assert_eq!(rejected(&state, "chain_closed"), 1);
+
+        let lenient = AppState {
+            accept_after_close: true,
+            ..state
+        };
+        assert_eq!(
+            submit(&lenient, &chain[3]).await.status(),
+            StatusCode::CREATED
+        );
     }
 
-    if let Some(agent) = &params.agent_id {
-        if !first_clause {
-            builder.push(" AND ");
+    #[tokio::test]
+    async fn the_producer_library_chains_onto_a_live_server() {
+        use common::producer::{Client, FileStateStore, Producer, ProducerError};
+
+        let state = test_state().await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = build_router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let state_path =
+            std::env::temp_dir().join(format!("producer-live-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&state_path);
+
+        let key = generate_keypair();
+        let mut producer = Producer::new(key.clone(), "embedded-1", Client::new(&url))
+            .with_state_store(FileStateStore::new(&state_path))
+            .with_batch_size(2);
+        let mut sent = Vec::new();
+        for n in 0..5 {
+            sent.extend(producer.log(format!("line {n}")).await.unwrap());
         }
-        builder.push("agent_id = ");
-        builder.push_bind(agent);
-        first_clause = false;
+        assert_eq!(producer.buffered(), 1);
+        sent.extend(producer.flush().await.unwrap());
+        assert_eq!(
+            sent.iter().map(|s| (s.seq, s.lines)).collect::<Vec<_>>(),
+            [(1, 2), (2, 2), (3, 1)]
+        );
+        assert!(
+            sent.iter()
+                .all(|s| s.receipt.as_ref().is_some_and(|r| r.hash == s.hash))
+        );
+
+        // Another producer with the same key, synced before the first one
+        // goes on, hits a conflict, resyncs and keeps its lines.
+        let mut stale =
+            Producer::new(key.clone(), "embedded-1", Client::new(&url)).with_batch_size(10);
+        stale.log("early").await.unwrap();
+        assert_eq!(stale.flush().await.unwrap().map(|s| s.seq), Some(4));
+        producer.log("from the first").await.unwrap();
+        let err = producer.log("one more").await.unwrap_err();
+        assert!(
+            matches!(err, ProducerError::Rejected { status: 409, .. }),
+            "{err}"
+        );
+        assert_eq!(producer.buffered(), 2);
+        assert_eq!(producer.flush().await.unwrap().map(|s| s.seq), Some(5));
+
+        // The file store carries the chain across a restart.
+        drop(producer);
+        let mut restarted = Producer::new(key, "embedded-1", Client::new(&url))
+            .with_state_store(FileStateStore::new(&state_path));
+        restarted.log("after restart").await.unwrap();
+        assert_eq!(restarted.flush().await.unwrap().map(|s| s.seq), Some(6));
+
+        let mut impostor = Producer::new(generate_keypair(), "embedded-1", Client::new(&url));
+        impostor.log("not mine").await.unwrap();
+        let err = impostor.flush().await.unwrap_err();
+        assert!(
+            matches!(err, ProducerError::Rejected { status: 403, .. }),
+            "{err}"
+        );
+
+        let stored = list(&state, ListParams::default()).await;
+        assert_eq!(stored.len(), 6);
+        assert!(stored.iter().all(|b| b.batch.verify()));
+        for pair in stored.windows(2) {
+            assert_eq!(pair[1].batch.prev_hash, pair[0].batch.compute_hash());
+        }
+        std::fs::remove_file(&state_path).unwrap();
     }
 
-    if let Some(seq) = params.since_seq {
-        if !first_clause {
-            builder.push(" AND ");
+    #[tokio::test]
+    async fn lines_are_addressed_by_agent_seq_and_index() {
+        let state = test_state().await;
+        let key = generate_keypair();
+        let mut first = signed_batch(&key, 1, [0u8; 32], "unused");
+        first.logs = (0..4)
+            .map(|i| format!("GET /item/{i} {}", "x".repeat(400)))
+            .collect();
+        first.logs[2] = "ERROR disk full".into();
+        first.sign(&key);
+        let mut second = signed_batch(&key, 2, first.compute_hash(), "error again");
+        second.logs.insert(0, "fine".into());
+        second.sign(&key);
+        for batch in [&first, &second] {
+            assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
         }
-        builder.push("seq >= ");
-        builder.push_bind(seq as i64);
-        first_clause = false;
+        async fn get(state: &AppState, uri: &str) -> (StatusCode, serde_json::Value) {
+            let resp = route(state, "GET", uri, None, Vec::new(), 1).await;
+            let status = resp.status();
+            (
+                status,
+                serde_json::from_str(&body_text(resp).await).unwrap(),
+            )
+        }
+
+        let (status, found) = get(&state, "/logs/agent-test/1/2").await;
+        assert_eq!(status, StatusCode::OK, "{found}");
+        assert_eq!(found["address"], "agent-test/1/2");
+        assert_eq!(found["line"], "ERROR disk full");
+        assert_eq!(found["batch"]["line_count"], 4);
+        assert_eq!(
+            found["batch"]["hash"],
+            serde_json::json!(first.compute_hash())
+        );
+        assert_eq!(
+            found["batch"]["prev_hash"],
+            serde_json::json!([0u8; 32].to_vec())
+        );
+        let (_, found) = get(&state, "/logs/agent-test/0:2/1").await;
+        assert_eq!(found["line"], "error again");
+
+        // Search results carry the address of every matching line.
+        let resp = route(
+            &state,
+            "GET",
+            "/batches?log_substring=error",
+            None,
+            Vec::new(),
+            1,
+        )
+        .await;
+        let hits: Vec<serde_json::Value> = serde_json::from_str(&body_text(resp).await).unwrap();
+        let matches: Vec<_> = hits.iter().map(|hit| hit["matches"].clone()).collect();
+        assert_eq!(
+            matches,
+            [
+                serde_json::json!(["agent-test/1/2"]),
+                serde_json::json!(["agent-test/2/1"])
+            ]
+        );
+        let resp = route(&state, "GET", "/batches", None, Vec::new(), 1).await;
+        assert!(!body_text(resp).await.contains("matches"));
+
+        for (uri, status, error) in [
+            (
+                "/logs/agent-test/1/4",
+                StatusCode::NOT_FOUND,
+                "line_out_of_range",
+            ),
+            (
+                "/logs/agent-test/3/0",
+                StatusCode::NOT_FOUND,
+                "batch_not_found",
+            ),
+            (
+                "/logs/agent-test/1:1/0",
+                StatusCode::NOT_FOUND,
+                "batch_not_found",
+            ),
+            (
+                "/logs/agent-test/one/0",
+                StatusCode::BAD_REQUEST,
+                "invalid_address",
+            ),
+        ] {
+            let (got, body) = get(&state, uri).await;
+            assert_eq!(
+                (got, body["error"].as_str().unwrap()),
+                (status, error),
+                "{uri}: {body}"
+            );
+        }
+        let (_, past) = get(&state, "/logs/agent-test/1/4").await;
+        assert_eq!(past["line_count"], 4);
+
+        // Logs tiered away to a blob that is gone cannot be sliced.
+        let dir = std::env::temp_dir().join(format!("logchain-lines-{}", std::process::id()));
+        let store = tiering::BlobStore::parse(dir.to_str().unwrap()).unwrap();
+        assert_eq!(
+            tiering::tier(&state.pool, &store, now_unix_ms() + 1, 10).await,
+            Ok(1)
+        );
+        assert_eq!(
+            get(&state, "/logs/agent-test/1/2").await.1["line"],
+            "ERROR disk full"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        let (status, gone) = get(&state, "/logs/agent-test/1/2").await;
+        assert_eq!(
+            (status, gone["error"].as_str().unwrap()),
+            (StatusCode::GONE, "batch_archived")
+        );
+        assert_eq!(
+            get(&state, "/logs/agent-test/2/1").await.0,
+            StatusCode::OK,
+            "kept plaintext"
+        );
     }
 
-    if let Some(ts) = params.since_timestamp {
-        if !first_clause {
-            builder.push(" AND ");
+    #[tokio::test]
+    async fn submits_update_the_cached_checkpoints() {
+        use common::testutil::{build_chain, extend_chain, start_epoch};
+
+        async fn checkpoints(state: &AppState) -> Vec<serde_json::Value> {
+            let resp = route(state, "GET", "/batches/checkpoints", None, Vec::new(), 1).await;
+            serde_json::from_str(&body_text(resp).await).unwrap()
         }
-        builder.push("timestamp >= ");
-        builder.push_bind(ts as i64);
-        first_clause = false;
+
+        let state = test_state().await;
+        let key = SigningKey::from_bytes(&[69; 32]);
+        let mut chain = build_chain(&key, "agent-cached", 2);
+        for batch in &chain {
+            assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
+        }
+        assert_eq!(checkpoints(&state).await[0]["count"], 2);
+
+        // Served from memory: an empty database behind it changes nothing.
+        let empty = connect_pool(EPHEMERAL_DATABASE_URL, ack::AckMode::Durable)
+            .await
+            .unwrap();
+        init_schema(&empty).await;
+        assert_eq!(state.checkpoints.get(&empty).await.unwrap().len(), 1);
+
+        // Each submit moves its agent's entry, across an epoch start too.
+        extend_chain(&mut chain, &key, 1);
+        start_epoch(&mut chain, &key);
+        let other = build_chain(&key, "agent-cached-2", 1);
+        for batch in chain[2..].iter().chain(&other) {
+            assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
+        }
+        let cached = state.checkpoints.get(&empty).await.unwrap();
+        let fresh = load_checkpoints(&state.pool).await.unwrap();
+        assert_eq!(
+            serde_json::to_value(&cached).unwrap(),
+            serde_json::to_value(&fresh).unwrap()
+        );
+        assert_eq!(
+            (cached[0].last_epoch, cached[0].last_seq, cached[0].count),
+            (1, 1, 4)
+        );
+        assert_eq!(cached[0].last_hash, chain[3].compute_hash());
+        assert_eq!(cached[1].agent_id, "agent-cached-2");
+
+        // A batch that does not extend the cached head drops the cache.
+        let stray = build_chain(&key, "agent-cached", 6).pop().unwrap();
+        state.checkpoints.stored(&stray, stray.compute_hash());
+        assert!(state.checkpoints.get(&empty).await.unwrap().is_empty());
+
+        // Without a staleness budget every read goes to the database.
+        let uncached = checkpoint_cache::CheckpointCache::new(StdDuration::ZERO);
+        assert_eq!(uncached.get(&state.pool).await.unwrap().len(), 2);
+        assert!(uncached.get(&empty).await.unwrap().is_empty());
     }
 
-    if let Some(ts) = params.until_timestamp {
-        if !first_clause {
-            builder.push(" AND ");
+    #[tokio::test]
+    async fn checkpoints_page_filter_and_reconcile_with_batches() {
+        use common::testutil::build_chain;
+
+        async fn page(state: &AppState, query: &str) -> Vec<String> {
+            let uri = format!("/batches/checkpoints?{query}");
+            let resp = route(state, "GET", &uri, None, Vec::new(), 1).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body: Vec<serde_json::Value> =
+                serde_json::from_str(&body_text(resp).await).unwrap();
+            body.iter()
+                .map(|cp| cp["agent_id"].as_str().unwrap().to_string())
+                .collect()
         }
-        builder.push("timestamp <= ");
-        builder.push_bind(ts as i64);
-        first_clause = false;
+
+        let state = test_state().await;
+        let key = SigningKey::from_bytes(&[70; 32]);
+        for agent in ["agent-c", "agent-a", "agent-b"] {
+            for batch in &build_chain(&key, agent, 2) {
+                assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
+            }
+        }
+        let mut tx = state.pool.begin().await.unwrap();
+        let scanned = checkpoint_cache::scan(&mut tx).await.unwrap();
+        drop(tx);
+        assert_eq!(load_checkpoints(&state.pool).await.unwrap(), scanned);
+
+        assert_eq!(page(&state, "limit=2").await, ["agent-a", "agent-b"]);
+        assert_eq!(page(&state, "after=agent-b&limit=2").await, ["agent-c"]);
+        assert_eq!(page(&state, "agent_id=agent-b").await, ["agent-b"]);
+        assert!(page(&state, "agent_id=agent-z").await.is_empty());
+        assert_eq!(page(&state, "limit=100000").await.len(), 3);
+
+        // Writes around the submit path: a batch inserted directly, a stale
+        // entry and one for an agent without batches.
+        let extra = build_chain(&key, "agent-a", 3).pop().unwrap();
+        sqlx::query(
+            "INSERT INTO batches (agent_id, seq, prev_hash, hash, logs, timestamp, signature, public_key) \
+             VALUES ('agent-a', 3, ?1, ?2, '[]', 0, x'00', x'00')",
+        )
+        .bind(extra.prev_hash.to_vec())
+        .bind(extra.compute_hash().to_vec())
+        .execute(&state.pool)
+        .await
+        .unwrap();
+        sqlx::query("UPDATE checkpoints_cache SET count = 7 WHERE agent_id = 'agent-c'")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO checkpoints_cache (agent_id, last_epoch, last_seq, last_hash, count) \
+             VALUES ('agent-gone', 0, 1, zeroblob(32), 1)",
+        )
+        .execute(&state.pool)
+        .await
+        .unwrap();
+
+        let repaired = checkpoint_cache::reconcile(&state.pool).await.unwrap();
+        assert_eq!(repaired, ["agent-a", "agent-c", "agent-gone"]);
+        let checkpoints = load_checkpoints(&state.pool).await.unwrap();
+        assert_eq!((checkpoints[0].last_seq, checkpoints[0].count), (3, 3));
+        assert_eq!(checkpoints[0].last_hash, extra.compute_hash());
+        assert_eq!(checkpoints[2].count, 2);
+        assert_eq!(checkpoints.len(), 3);
+        assert!(
+            checkpoint_cache::reconcile(&state.pool)
+                .await
+                .unwrap()
+                .is_empty()
+        );
     }
 
-    if let Some(sub) = &params.log_substring {
-        if !first_clause {
-            builder.push(" AND ");
+    /// Checkpoint reads against `batches` tables of growing size: the cached
+    /// table stays flat while grouping `batches` grows with it.
+    /// `cargo test -p server --release checkpoint_read_scaling -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn checkpoint_read_scaling() {
+        let state = test_state().await;
+        // Rows are written in bulk without chain links.
+        sqlx::query("DROP TRIGGER batches_enforce_seq")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        let mut rows = 0u64;
+        for target in [10_000u64, 100_000, 1_000_000] {
+            sqlx::query(
+                "WITH RECURSIVE n(i) AS (SELECT ?1 UNION ALL SELECT i + 1 FROM n WHERE i < ?2) \
+                 INSERT INTO batches (agent_id, seq, prev_hash, hash, logs, timestamp, signature, public_key) \
+                 SELECT 'agent-' || (i % 100), i, zeroblob(32), randomblob(32), '[]', 0, x'00', x'00' FROM n",
+            )
+            .bind(rows as i64 + 1)
+            .bind(target as i64)
+            .execute(&state.pool)
+            .await
+            .unwrap();
+            rows = target;
+            let start = std::time::Instant::now();
+            checkpoint_cache::reconcile(&state.pool).await.unwrap();
+            let reconcile = start.elapsed();
+
+            let rounds = 20;
+            let start = std::time::Instant::now();
+            for _ in 0..rounds {
+                let one = checkpoint_cache::read(&state.pool, Some("agent-42"), None, None)
+                    .await
+                    .unwrap();
+                assert_eq!(one.len(), 1);
+                assert_eq!(
+                    checkpoint_cache::read(&state.pool, None, None, None)
+                        .await
+                        .unwrap()
+                        .len(),
+                    100
+                );
+            }
+            let cached = start.elapsed() / rounds;
+            let start = std::time::Instant::now();
+            for _ in 0..rounds {
+                let mut conn = state.pool.acquire().await.unwrap();
+                assert_eq!(checkpoint_cache::scan(&mut conn).await.unwrap().len(), 100);
+            }
+            let scanned = start.elapsed() / rounds;
+            println!(
+                "{rows} batches: checkpoints_cache {cached:?}, grouping batches {scanned:?}, startup reconcile {reconcile:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn epochs_restart_seqs_and_link_to_the_previous_epoch() {
+        use common::testutil::{build_chain, extend_chain, start_epoch};
+
+        let state = test_state().await;
+        let (k1, k2) = (generate_keypair(), generate_keypair());
+        register(&state, "agent-rot", &k1).await;
+        let mut chain = build_chain(&k1, "agent-rot", 2);
+        start_epoch(&mut chain, &k1);
+        for batch in &chain {
+            assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
+        }
+        let checkpoints = load_checkpoints(&state.pool).await.unwrap();
+        assert_eq!((checkpoints[0].last_epoch, checkpoints[0].last_seq), (1, 1));
+
+        // Rotating in epoch 1 bounds the old key by position, not seq alone.
+        assert_eq!(
+            rotate(&state, rotation("agent-rot", &k1, &k2, 1)).await,
+            StatusCode::OK
+        );
+        extend_chain(&mut chain, &k2, 1);
+        let stored = submit(&state, &chain[3]).await;
+        assert_eq!(stored.status(), StatusCode::CREATED);
+        let body: serde_json::Value = serde_json::from_str(&body_text(stored).await).unwrap();
+        let receipt: Receipt = serde_json::from_value(body["receipt"].clone()).unwrap();
+        assert_eq!(receipt.position(), (1, 2));
+        let Json(windows) = handler_agent_keys(State(state.clone()), Path("agent-rot".into()))
+            .await
+            .unwrap();
+        let spans: Vec<_> = windows
+            .iter()
+            .map(|w| {
+                (
+                    w.valid_from_epoch,
+                    w.valid_from_seq,
+                    w.valid_until_epoch,
+                    w.valid_until_seq,
+                )
+            })
+            .collect();
+        assert_eq!(spans, vec![(0, 1, 1, Some(2)), (1, 2, 0, None)]);
+
+        // An epoch start must open the next epoch right after the last seq.
+        let mut skipping = chain.clone();
+        start_epoch(&mut skipping, &k2);
+        let mut skipping = skipping.pop().unwrap();
+        skipping.epoch = 3;
+        skipping.sign(&k2);
+        let refused = submit(&state, &skipping).await;
+        assert_eq!(refused.status(), StatusCode::CONFLICT);
+        assert!(
+            body_text(refused)
+                .await
+                .contains("epoch start must open epoch 2, got epoch 3")
+        );
+        let mut early = chain.clone();
+        start_epoch(&mut early, &k2);
+        let mut early = early.pop().unwrap();
+        early.epoch_start.as_mut().unwrap().previous_last_seq = 1;
+        early.sign(&k2);
+        let refused = submit(&state, &early).await;
+        assert_eq!(refused.status(), StatusCode::CONFLICT);
+        assert!(
+            body_text(refused)
+                .await
+                .contains("epoch 1: expected 2, got 1")
+        );
+        // Nor can a closed epoch grow, even under a key valid there.
+        let mut reopened = chain[..2].to_vec();
+        extend_chain(&mut reopened, &k1, 1);
+        let refused = submit(&state, &reopened[2]).await;
+        assert_eq!(refused.status(), StatusCode::CONFLICT);
+        assert!(
+            body_text(refused)
+                .await
+                .contains("batch must continue epoch 1, got epoch 0")
+        );
+        assert_eq!(rejected(&state, "epoch_mismatch"), 2);
+
+        let stored = list(&state, ListParams::default()).await;
+        let positions: Vec<_> = stored.iter().map(|b| b.batch.position()).collect();
+        assert_eq!(positions, [(0, 1), (0, 2), (1, 1), (1, 2)]);
+        assert_eq!(stored[2].batch.epoch_start, chain[2].epoch_start);
+        assert!(
+            stored
+                .iter()
+                .zip(&chain)
+                .all(|(row, sent)| row.hash == sent.compute_hash())
+        );
+        let epoch_one = list(
+            &state,
+            ListParams {
+                epoch: Some(1),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(epoch_one.len(), 2);
+        let Json(report) = admin::handler_integrity_check(
+            State(state.clone()),
+            authed(&state, bearer("admin-secret")).await,
+        )
+        .await
+        .unwrap();
+        let report = serde_json::to_value(report).unwrap();
+        assert_eq!(report["ok"], true, "{report}");
+
+        // The trigger holds epochs to the same rules past the API.
+        for (epoch, seq, prev_seq, message) in [
+            (1, 4, None, "non-contiguous seq"),
+            (2, 2, Some(2), "epoch must start at seq 1"),
+            (3, 1, Some(2), "non-contiguous epoch"),
+            (2, 1, Some(1), "epoch start does not follow the last seq"),
+        ] {
+            let err = sqlx::query(
+                "INSERT INTO batches (agent_id, epoch, seq, epoch_prev_seq, prev_hash, hash, logs, timestamp, signature, public_key) \
+                 VALUES ('agent-rot', ?1, ?2, ?3, ?4, ?5, '[]', 0, x'00', x'00')",
+            )
+            .bind(epoch)
+            .bind(seq)
+            .bind(prev_seq)
+            .bind(chain[3].compute_hash().to_vec())
+            .bind(vec![0xaa; 32])
+            .execute(&state.pool)
+            .await
+            .unwrap_err();
+            assert!(err.to_string().contains(message), "{err}");
         }
-        builder.push("logs LIKE ");
-        builder.push_bind(format!("%{}%", sub));
     }
 
-    builder.push(" ORDER BY agent_id ASC, seq ASC");
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn grpc_submit_shares_checks_and_storage_with_http() {
+        use common::grpc::proto;
+        use common::grpc::proto::log_chain_server::LogChain;
+        use futures_util::StreamExt;
+        use tonic::{Code, Request};
+
+        let mut state = test_state().await;
+        state.auth_token = Some("secret".into());
+        let service = grpc::GrpcService::new(state.clone());
+        let authorized = |batch: &LogBatch| {
+            let mut request = Request::new(proto::LogBatch::from(batch));
+            request
+                .metadata_mut()
+                .insert("authorization", "Bearer secret".parse().unwrap());
+            request
+        };
 
-    if let Some(limit) = params.limit {
-        builder.push(" LIMIT ");
-        builder.push_bind(limit as i64);
+        let key = generate_keypair();
+        let first = signed_batch(&key, 1, [0u8; 32], "one");
+        let denied = service
+            .submit(Request::new(proto::LogBatch::from(&first)))
+            .await
+            .unwrap_err();
+        assert_eq!(denied.code(), Code::PermissionDenied);
+        assert_eq!(denied.message(), FORBIDDEN_MESSAGE);
+
+        let stored = service
+            .submit(authorized(&first))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(stored.status, "ok");
+        assert!(stored.server_time_ms.is_some());
+        // The HTTP path sees the gRPC batch as already stored.
+        let resend = submit_with(&state, bearer("secret"), &first).await;
+        assert_eq!(resend.status(), StatusCode::OK);
+        assert!(body_text(resend).await.contains("duplicate"));
+
+        let gap = signed_batch(&key, 3, first.compute_hash(), "three");
+        let err = service.submit(authorized(&gap)).await.unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition);
+
+        let mut malformed = authorized(&first);
+        malformed.get_mut().signature.truncate(10);
+        let err = service.submit(malformed).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        assert_eq!(
+            err.message(),
+            "invalid batch: signature must be 64 bytes, got 10"
+        );
+
+        let second = signed_batch(&key, 2, first.compute_hash(), "two");
+        let resp = submit_with(&state, bearer("secret"), &second).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let checkpoints: Vec<proto::AgentCheckpoint> = service
+            .checkpoints(Request::new(proto::CheckpointsRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(checkpoints.len(), 1);
+        assert_eq!(checkpoints[0].agent_id, "agent-test");
+        assert_eq!(checkpoints[0].last_seq, 2);
+        assert_eq!(checkpoints[0].count, 2);
+        assert_eq!(checkpoints[0].last_hash, second.compute_hash().to_vec());
     }
-    if let Some(offset) = params.offset {
-        builder.push(" OFFSET ");
-        builder.push_bind(offset as i64);
+
+    /// One request through the full router, from `10.1.0.<ip>`.
+    async fn route(
+        state: &AppState,
+        method: &str,
+        uri: &str,
+        token: Option<&str>,
+        body: Vec<u8>,
+        ip: u8,
+    ) -> Response {
+        use tower::ServiceExt;
+
+        let mut request = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json");
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {token}"));
+        }
+        let mut request = request.body(Body::from(body)).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([10, 1, 0, ip], 9000))));
+        build_router(state.clone()).oneshot(request).await.unwrap()
     }
 
-    let rows = builder
-        .build()
-        .fetch_all(&state.pool)
+    #[tokio::test]
+    async fn slow_requests_time_out_but_streams_only_when_they_stall() {
+        let mut state = test_state().await;
+        // A broad substring filter over a large table, without chain checks
+        // so the rows go in fast.
+        sqlx::query("DROP TRIGGER batches_enforce_seq")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200000) \
+             INSERT INTO batches (agent_id, seq, prev_hash, hash, logs, timestamp, signature, public_key) \
+             SELECT 'agent-slow', i, zeroblob(32), randomblob(32), \
+                 '[\"' || printf('%0200d', i) || '\"]', 0, x'00', x'00' FROM n",
+        )
+        .execute(&state.pool)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let mut results = Vec::new();
-
-    for row in rows {
-        results.push(row_to_query_batch(row)?);
+        .unwrap();
+        let slow = "/batches?log_substring=never-logged";
+
+        state.timeouts.read = Some(StdDuration::from_millis(1));
+        let resp = route(&state, "GET", slow, None, Vec::new(), 1).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        // Other groups keep their own limits.
+        let resp = route(&state, "POST", "/submit", None, b"{}".to_vec(), 1).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        state.timeouts.read = None;
+        let resp = route(&state, "GET", slow, None, Vec::new(), 1).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body_text(resp).await, "[]");
+
+        // A stream is bounded per chunk: steady chunks run past the request
+        // limit, a stall is cut off.
+        async fn stream(gap_ms: u64) -> Result<Bytes, axum::Error> {
+            let handler = move || async move {
+                Body::from_stream(futures_util::stream::unfold(0, move |i| async move {
+                    if i == 5 {
+                        return None;
+                    }
+                    tokio::time::sleep(StdDuration::from_millis(gap_ms)).await;
+                    Some((
+                        Ok::<_, std::io::Error>(Bytes::from(format!("chunk {i}\n"))),
+                        i + 1,
+                    ))
+                }))
+            };
+            let routes = Router::new().route("/stream", get(handler));
+            let app = timeout::limit_chunks(
+                timeout::limit(routes, Some(StdDuration::from_millis(50))),
+                Some(StdDuration::from_millis(150)),
+            );
+            let request = axum::http::Request::builder()
+                .uri("/stream")
+                .body(Body::empty())
+                .unwrap();
+            let resp = tower::ServiceExt::oneshot(app, request).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            axum::body::to_bytes(resp.into_body(), usize::MAX).await
+        }
+        assert_eq!(
+            stream(40)
+                .await
+                .unwrap()
+                .iter()
+                .filter(|&&b| b == b'\n')
+                .count(),
+            5
+        );
+        assert!(stream(400).await.is_err());
     }
 
-    Ok(Json(results))
-}
+    #[tokio::test]
+    async fn maintenance_mode_refuses_writes_and_keeps_reads() {
+        let state = test_state().await;
+        let key = generate_keypair();
+        let batch = |seq, prev| serde_json::to_vec(&signed_batch(&key, seq, prev, "line")).unwrap();
+        let first = signed_batch(&key, 1, [0u8; 32], "line");
+        let stored = route(&state, "POST", "/submit", None, batch(1, [0u8; 32]), 1).await;
+        assert_eq!(stored.status(), StatusCode::CREATED);
+        let set = |enabled: bool| {
+            let state = state.clone();
+            async move {
+                let body = serde_json::to_vec(&serde_json::json!({ "enabled": enabled })).unwrap();
+                route(
+                    &state,
+                    "POST",
+                    "/admin/maintenance",
+                    Some("admin-secret"),
+                    body,
+                    1,
+                )
+                .await
+            }
+        };
 
-/* ----------------------- EXPORT /batches/export ----------------------- */
+        // Only an admin can turn it on.
+        let anonymous = route(
+            &state,
+            "POST",
+            "/admin/maintenance",
+            None,
+            br#"{"enabled":true}"#.to_vec(),
+            1,
+        )
+        .await;
+        assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+        let on = set(true).await;
+        assert_eq!(on.status(), StatusCode::OK);
+        let on: serde_json::Value = serde_json::from_str(&body_text(on).await).unwrap();
+        assert_eq!(on["enabled"], true);
+        assert_eq!(
+            on["retry_after_secs"],
+            maintenance::DEFAULT_RETRY_AFTER_SECS
+        );
 
-async fn handler_export(
-    State(state): State<AppState>,
-    Query(params): Query<ExportParams>,
-) -> Result<Json<Vec<QueryBatch>>, StatusCode> {
-    let mut builder = QueryBuilder::new("SELECT * FROM batches");
+        let second = batch(2, first.compute_hash());
+        let registration = serde_json::to_vec(&serde_json::json!({
+            "agent_id": "agent-new",
+            "public_key_hex": hex_encode(&generate_keypair().verifying_key().to_bytes()),
+        }))
+        .unwrap();
+        for (uri, body) in [
+            ("/submit", second.clone()),
+            ("/agents/register", registration.clone()),
+            ("/agents/register/bulk", b"[]".to_vec()),
+            ("/agents/rotate", b"{}".to_vec()),
+            ("/ingest/nginx", b"line".to_vec()),
+        ] {
+            let resp = route(&state, "POST", uri, None, body, 2).await;
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE, "{uri}");
+            assert_eq!(resp.headers()[header::RETRY_AFTER], "30", "{uri}");
+            let body: serde_json::Value = serde_json::from_str(&body_text(resp).await).unwrap();
+            assert_eq!(body["message"], maintenance::MESSAGE, "{uri}");
+        }
+        assert_eq!(
+            state
+                .metrics
+                .get(r#"logchain_submit_rejected_total{reason="maintenance"}"#),
+            1
+        );
 
-    if let Some(since_id) = params.since_id {
-        builder.push(" WHERE id > ");
-        builder.push_bind(since_id);
+        // Reads keep serving and report the mode.
+        let batches = route(&state, "GET", "/batches", None, Vec::new(), 3).await;
+        assert_eq!(batches.status(), StatusCode::OK);
+        let ready = route(&state, "GET", "/readyz", None, Vec::new(), 3).await;
+        assert_eq!(ready.status(), StatusCode::OK);
+        let ready: serde_json::Value = serde_json::from_str(&body_text(ready).await).unwrap();
+        assert_eq!(ready["ready"], true);
+        assert_eq!(ready["maintenance"], true);
+        let metrics = body_text(route(&state, "GET", "/metrics", None, Vec::new(), 3).await).await;
+        assert!(metrics.contains("logchain_maintenance_mode 1\n"));
+        let status = route(
+            &state,
+            "GET",
+            "/admin/maintenance",
+            Some("admin-secret"),
+            Vec::new(),
+            3,
+        )
+        .await;
+        assert!(body_text(status).await.contains(r#""enabled":true"#));
+
+        // Cleared at runtime, the refused writes go through.
+        assert_eq!(set(false).await.status(), StatusCode::OK);
+        let resent = route(&state, "POST", "/submit", None, second, 4).await;
+        assert_eq!(resent.status(), StatusCode::CREATED);
+        let registered = route(&state, "POST", "/agents/register", None, registration, 4).await;
+        assert_eq!(registered.status(), StatusCode::CREATED);
+        let metrics = body_text(route(&state, "GET", "/metrics", None, Vec::new(), 4).await).await;
+        assert!(metrics.contains("logchain_maintenance_mode 0\n"));
     }
 
-    builder.push(" ORDER BY id ASC");
+    #[tokio::test]
+    async fn each_route_group_is_limited_on_its_own() {
+        let mut state = test_state().await;
+        let limit = |max, key| RouteLimit {
+            max,
+            window_secs: 60,
+            key,
+            algo: RateAlgo::FixedWindow,
+        };
+        state.rate_limits = Arc::new(RateLimits::new([
+            (LimitGroup::Submit, limit(2, LimitKey::Agent)),
+            (LimitGroup::Read, limit(3, LimitKey::Token)),
+            (LimitGroup::Admin, limit(1, LimitKey::Ip)),
+        ]));
+        let limited = |group: &str| {
+            state
+                .metrics
+                .get(&labeled("logchain_rate_limited_total", "limiter", group))
+        };
+        let submit_body = |batch: &LogBatch| serde_json::to_vec(batch).unwrap();
+
+        // Submit, per agent: agent-test's third batch is refused with the
+        // usual /submit body; another agent on the same IP goes through.
+        let key = generate_keypair();
+        let mut prev = [0u8; 32];
+        for seq in 1..=3 {
+            let batch = signed_batch(&key, seq, prev, "line");
+            prev = batch.compute_hash();
+            let resp = route(&state, "POST", "/submit", None, submit_body(&batch), 1).await;
+            if seq <= 2 {
+                assert_eq!(resp.status(), StatusCode::CREATED);
+            } else {
+                assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+                assert!(body_text(resp).await.contains("rate limit exceeded"));
+            }
+        }
+        let other_key = generate_keypair();
+        let other_batch = |seq, prev| {
+            let mut batch = signed_batch(&other_key, seq, prev, "line");
+            batch.agent_id = "agent-other".into();
+            batch.sign(&other_key);
+            batch
+        };
+        let other = other_batch(1, [0u8; 32]);
+        let resp = route(&state, "POST", "/submit", None, submit_body(&other), 1).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(limited("submit"), 1);
+        assert_eq!(
+            state.metrics.get(&labeled(
+                "logchain_submit_rejected_total",
+                "reason",
+                "rate_limited"
+            )),
+            1
+        );
 
-    if let Some(limit) = params.limit {
-        builder.push(" LIMIT ");
-        builder.push_bind(limit as i64);
+        // Reads, per token: anonymous calls share the IP's bucket, a token
+        // has its own.
+        for n in 1..=4 {
+            let status = route(&state, "GET", "/batches", None, Vec::new(), 1)
+                .await
+                .status();
+            if n <= 3 {
+                assert_eq!(status, StatusCode::OK, "read {n}");
+            } else {
+                assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "read {n}");
+            }
+        }
+        let resp = route(
+            &state,
+            "GET",
+            "/batches",
+            Some("admin-secret"),
+            Vec::new(),
+            1,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(limited("read"), 1);
+
+        // Registration, per IP, whatever the read bucket says.
+        let register = |agent_id: &str| {
+            let key = generate_keypair();
+            serde_json::to_vec(&serde_json::json!({
+                "agent_id": agent_id,
+                "public_key_hex": hex_encode(&key.verifying_key().to_bytes()),
+            }))
+            .unwrap()
+        };
+        let first = route(
+            &state,
+            "POST",
+            "/agents/register",
+            None,
+            register("reg-1"),
+            1,
+        )
+        .await;
+        assert!(first.status().is_success(), "{}", first.status());
+        let second = route(
+            &state,
+            "POST",
+            "/agents/register",
+            None,
+            register("reg-2"),
+            1,
+        )
+        .await;
+        assert_eq!(second.status(), StatusCode::TOO_MANY_REQUESTS);
+        let elsewhere = route(
+            &state,
+            "POST",
+            "/agents/register",
+            None,
+            register("reg-3"),
+            2,
+        )
+        .await;
+        assert!(elsewhere.status().is_success(), "{}", elsewhere.status());
+        assert_eq!(limited("admin"), 1);
+
+        // None of that was charged to agent-other's submit budget.
+        let next = other_batch(2, other.compute_hash());
+        let resp = route(&state, "POST", "/submit", None, submit_body(&next), 1).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
     }
 
-    let rows = builder
-        .build()
-        .fetch_all(&state.pool)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let mut results = Vec::new();
+    #[tokio::test]
+    async fn rate_limit_buckets_and_the_denylist_survive_a_restart() {
+        let limits = || {
+            Arc::new(RateLimits::new([(
+                LimitGroup::Read,
+                RouteLimit {
+                    max: 2,
+                    window_secs: 60,
+                    key: LimitKey::Ip,
+                    algo: RateAlgo::FixedWindow,
+                },
+            )]))
+        };
+        let mut state = test_state().await;
+        state.rate_limits = limits();
+        let read = async |state: &AppState, ip| {
+            route(state, "GET", "/batches", None, Vec::new(), ip)
+                .await
+                .status()
+        };
+        let admin = async |state: &AppState, uri: &str, body: serde_json::Value, ip| {
+            let resp = route(
+                state,
+                "POST",
+                uri,
+                Some("admin-secret"),
+                body.to_string().into_bytes(),
+                ip,
+            )
+            .await;
+            (resp.status(), body_text(resp).await)
+        };
+        for _ in 0..2 {
+            assert_eq!(read(&state, 1).await, StatusCode::OK);
+        }
+        assert_eq!(read(&state, 1).await, StatusCode::TOO_MANY_REQUESTS);
+        let (status, _) = admin(
+            &state,
+            "/admin/ratelimit/deny",
+            serde_json::json!({ "entry": "10.1.0.7" }),
+            9,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = admin(
+            &state,
+            "/admin/ratelimit/deny",
+            serde_json::json!({ "entry": "agent:agent-test", "reason": "flooding" }),
+            9,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = admin(
+            &state,
+            "/admin/ratelimit/deny",
+            serde_json::json!({ "entry": "10.1.0.0/99" }),
+            9,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(rate_limit::save(&state).await.unwrap(), 1);
+
+        // A restart on the same database: fresh limiters, loaded back as
+        // they stood `downtime_ms` after the save.
+        let restart = async |downtime_ms: i64| {
+            let mut restarted = state_with_pool(state.pool.clone()).await;
+            restarted.rate_limits = limits();
+            assert_eq!(rate_limit::load_denylist(&restarted).await.unwrap(), 2);
+            rate_limit::restore(&restarted, now_unix_ms() + downtime_ms)
+                .await
+                .unwrap();
+            restarted
+        };
+        let restarted = restart(1_000).await;
+        assert_eq!(read(&restarted, 1).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(read(&restarted, 2).await, StatusCode::OK);
+        assert_eq!(read(&restarted, 7).await, StatusCode::FORBIDDEN);
+        let batch = signed_batch(&generate_keypair(), 1, [0u8; 32], "a");
+        let resp = route(
+            &restarted,
+            "POST",
+            "/submit",
+            None,
+            serde_json::to_vec(&batch).unwrap(),
+            2,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(body_text(resp).await.contains(rate_limit::DENIED_MESSAGE));
+        assert_eq!(
+            restarted.metrics.get(&labeled(
+                "logchain_submit_rejected_total",
+                "reason",
+                "denied"
+            )),
+            1
+        );
+        assert_eq!(restarted.metrics.get("logchain_denied_total"), 2);
+
+        let resp = route(
+            &restarted,
+            "GET",
+            "/admin/ratelimit",
+            Some("admin-secret"),
+            Vec::new(),
+            9,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let shown: serde_json::Value = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(shown["restored_at_startup"], 1);
+        let read_limiter = shown["limiters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|limiter| limiter["name"] == "read")
+            .unwrap();
+        assert_eq!(read_limiter["limit"], "2 per 60s per ip");
+        assert_eq!(read_limiter["active_buckets"], 2);
+        let entries: Vec<_> = shown["denylist"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| (entry["entry"].as_str().unwrap(), entry["reason"].as_str()))
+            .collect();
+        assert_eq!(
+            entries,
+            [("10.1.0.7", None), ("agent:agent-test", Some("flooding"))]
+        );
 
-    for row in rows {
-        results.push(row_to_query_batch(row)?);
+        // A window that ran out during the downtime is not restored.
+        let restarted = restart(61_000).await;
+        assert_eq!(read(&restarted, 1).await, StatusCode::OK);
+
+        // Even a denylisted admin can take an entry off.
+        let (status, _) = admin(
+            &restarted,
+            "/admin/ratelimit/deny",
+            serde_json::json!({ "entry": "10.1.0.0/24" }),
+            9,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(read(&restarted, 3).await, StatusCode::FORBIDDEN);
+        for entry in ["10.1.0.0/24", "10.1.0.7/32"] {
+            let (status, _) = admin(
+                &restarted,
+                "/admin/ratelimit/deny/remove",
+                serde_json::json!({ "entry": entry }),
+                9,
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK, "{entry}");
+        }
+        let (status, _) = admin(
+            &restarted,
+            "/admin/ratelimit/deny/remove",
+            serde_json::json!({ "entry": "10.1.0.7" }),
+            9,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(read(&restarted, 7).await, StatusCode::OK);
+        let reloaded = state_with_pool(state.pool.clone()).await;
+        assert_eq!(rate_limit::load_denylist(&reloaded).await.unwrap(), 1);
     }
 
-    Ok(Json(results))
-}
-
-/* ----------------------- CHECKPOINTS /batches/checkpoints ----------------------- */
+    #[tokio::test]
+    async fn fsck_reports_each_damaged_row_and_can_be_aborted() {
+        use common::testutil::build_chain;
 
-async fn handler_checkpoints(State(state): State<AppState>) -> Result<Json<Vec<AgentCheckpoint>>, StatusCode> {
-    let rows = sqlx::query(
-        r#"
-        SELECT
-            agent_id,
-            MAX(seq) AS last_seq,
-            COUNT(*) AS count,
-            (SELECT hash FROM batches b2 WHERE b2.agent_id = b.agent_id ORDER BY seq DESC LIMIT 1) AS last_hash
-        FROM batches b
-        GROUP BY agent_id
-        "#,
-    )
-    .fetch_all(&state.pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        async fn json(resp: Response) -> serde_json::Value {
+            serde_json::from_str(&body_text(resp).await).unwrap()
+        }
+        async fn finished(state: &AppState, job: &str) -> serde_json::Value {
+            loop {
+                let status = json(
+                    route(
+                        state,
+                        "GET",
+                        &format!("/admin/fsck/{job}"),
+                        Some("admin-secret"),
+                        Vec::new(),
+                        1,
+                    )
+                    .await,
+                )
+                .await;
+                if status["state"] != "running" {
+                    return status;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }
 
-    let mut checkpoints = Vec::new();
-    for row in rows {
-        let agent_id: String = row.get("agent_id");
-        let last_seq: i64 = row.get("last_seq");
-        let count: i64 = row.get("count");
-        let last_hash_vec: Vec<u8> = row.get("last_hash");
-        let last_hash: [u8; 32] = last_hash_vec
-            .try_into()
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let state = test_state().await;
+        let key = generate_keypair();
+        let chain = build_chain(&key, "agent-fsck", 8);
+        for batch in &chain {
+            assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
+        }
+        let start = |chunk_rows: u64| {
+            let state = state.clone();
+            async move {
+                route(
+                    &state,
+                    "POST",
+                    &format!("/admin/fsck?chunk_rows={chunk_rows}"),
+                    Some("admin-secret"),
+                    Vec::new(),
+                    1,
+                )
+                .await
+            }
+        };
 
-        checkpoints.push(AgentCheckpoint {
-            agent_id,
-            last_seq: last_seq as u64,
-            last_hash,
-            count: count as u64,
-        });
+        let clean = start(3).await;
+        assert_eq!(clean.status(), StatusCode::ACCEPTED);
+        let job = json(clean).await["job_id"].as_str().unwrap().to_string();
+        let status = finished(&state, &job).await;
+        assert_eq!(status["state"], "done");
+        assert_eq!(status["report"]["rows_checked"], 8);
+        assert_eq!(status["report"]["discrepancies"], serde_json::json!([]));
+
+        for trigger in [
+            "batches_no_update",
+            "batches_no_delete",
+            "batches_delete_needs_summary",
+            "batches_enforce_seq",
+        ] {
+            sqlx::query(&format!("DROP TRIGGER {trigger}"))
+                .execute(&state.pool)
+                .await
+                .unwrap();
+        }
+        let other = canonical_logs_json(&["rewritten".to_string()]);
+        let tampers: [(&str, Vec<u8>); 6] = [
+            (
+                "UPDATE batches SET logs_compressed = ?1 WHERE seq = 2",
+                compress_bytes(other.as_bytes(), Compression::fast()).unwrap(),
+            ),
+            ("UPDATE batches SET hash = ?1 WHERE seq = 3", vec![0xaa; 32]),
+            (
+                "UPDATE batches SET public_key = ?1 WHERE seq = 4",
+                vec![1, 2, 3],
+            ),
+            (
+                "UPDATE batches SET logs = CAST(?1 AS TEXT) WHERE seq = 5",
+                b"not json".to_vec(),
+            ),
+            (
+                "DELETE FROM batches WHERE seq = 6 AND ?1 IS NOT NULL",
+                Vec::new(),
+            ),
+            (
+                "DELETE FROM batches WHERE seq = 8 AND ?1 IS NOT NULL",
+                Vec::new(),
+            ),
+        ];
+        for (sql, value) in tampers {
+            sqlx::query(sql)
+                .bind(value)
+                .execute(&state.pool)
+                .await
+                .unwrap();
+        }
+        sqlx::query("DROP INDEX idx_agent_epoch_seq")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO batches (agent_id, seq, prev_hash, hash, logs, timestamp, signature, public_key) SELECT agent_id, seq, prev_hash, randomblob(32), logs, timestamp, signature, public_key FROM batches WHERE seq = 7")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+
+        let job = json(start(2).await).await["job_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let busy = start(2).await;
+        assert_eq!(busy.status(), StatusCode::CONFLICT);
+        assert!(body_text(busy).await.contains(&job));
+        let status = finished(&state, &job).await;
+        assert_eq!(status["state"], "done");
+        let found: Vec<(Option<i64>, String)> = status["report"]["discrepancies"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|d| (d["id"].as_i64(), d["kind"].as_str().unwrap().to_string()))
+            .collect();
+        let expected: Vec<(Option<i64>, String)> = [
+            (Some(2), "compressed_mismatch"),
+            // Reads serve the compressed copy, so the batch they return changed.
+            (Some(2), "hash_mismatch"),
+            (Some(3), "hash_mismatch"),
+            (Some(3), "receipt_mismatch"),
+            (Some(4), "bad_public_key"),
+            (Some(5), "logs_unreadable"),
+            (Some(7), "id_gap"),
+            // Row 8 is gone and the copy of seq 7 took id 9.
+            (Some(9), "id_gap"),
+            (Some(9), "seq_order"),
+            (Some(9), "hash_mismatch"),
+            (Some(9), "missing_receipt"),
+            (None, "missing_index"),
+        ]
+        .into_iter()
+        .map(|(id, kind)| (id, kind.to_string()))
+        .collect();
+        assert_eq!(found, expected);
+        assert_eq!(status["report"]["rows_checked"], 7);
+
+        // Aborted before its first chunk: nothing checked, not complete.
+        let job = json(start(1).await).await["job_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let abort = route(
+            &state,
+            "POST",
+            &format!("/admin/fsck/{job}/abort"),
+            Some("admin-secret"),
+            Vec::new(),
+            1,
+        )
+        .await;
+        assert_eq!(abort.status(), StatusCode::OK);
+        let status = finished(&state, &job).await;
+        assert_eq!(status["state"], "aborted");
+        assert_eq!(status["report"]["complete"], false);
+
+        let unknown = route(
+            &state,
+            "GET",
+            "/admin/fsck/nope",
+            Some("admin-secret"),
+            Vec::new(),
+            1,
+        )
+        .await;
+        assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+        let anonymous = route(&state, "POST", "/admin/fsck", None, Vec::new(), 1).await;
+        assert!(anonymous.status().is_client_error());
     }
 
-    Ok(Json(checkpoints))
-}
-
-/* ----------------------- GET /batches/:id ----------------------- */
+    #[tokio::test]
+    async fn receipts_are_issued_once_and_served_verbatim() {
+        use common::receipt::Receipt;
+
+        async fn receipt(state: &AppState, id: i64) -> Response {
+            route(
+                state,
+                "GET",
+                &format!("/batches/{id}/receipt"),
+                None,
+                Vec::new(),
+                1,
+            )
+            .await
+        }
+        async fn keys(state: &AppState) -> Vec<serde_json::Value> {
+            let resp = route(state, "GET", "/server-keys", None, Vec::new(), 1).await;
+            serde_json::from_str(&body_text(resp).await).unwrap()
+        }
+        fn verifying_key(key: &serde_json::Value) -> VerifyingKey {
+            parse_hex_public_key(key["public_key"].as_str().unwrap()).unwrap()
+        }
 
-async fn handler_get_one(
-    State(state): State<AppState>,
-    Path(id): Path<i64>,
-) -> Result<Json<QueryBatch>, StatusCode> {
-    let row = sqlx::query("SELECT * FROM batches WHERE id = ?1")
-        .bind(id)
-        .fetch_optional(&state.pool)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let state = test_state().await;
+        let key = generate_keypair();
+        let first = signed_batch(&key, 1, [0u8; 32], "one");
+        let stored = submit(&state, &first).await;
+        assert_eq!(stored.status(), StatusCode::CREATED);
+        let body: serde_json::Value = serde_json::from_str(&body_text(stored).await).unwrap();
+        let issued: Receipt = serde_json::from_value(body["receipt"].clone()).unwrap();
+        assert_eq!(issued.batch_id, 1);
+        assert_eq!(issued.hash, first.compute_hash());
+        let old_keys = keys(&state).await;
+        assert_eq!(old_keys.len(), 1);
+        assert!(issued.verify(&verifying_key(&old_keys[0])));
+
+        // Re-fetched, resent and after a rotation: always the receipt issued
+        // with the batch, still signed by the key that was active then.
+        let rotated = route(
+            &state,
+            "POST",
+            "/admin/server-keys/rotate",
+            Some("admin-secret"),
+            Vec::new(),
+            1,
+        )
+        .await;
+        assert_eq!(rotated.status(), StatusCode::OK);
+        let resent = submit(&state, &first).await;
+        assert_eq!(resent.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body_text(resent).await).unwrap();
+        assert_eq!(
+            serde_json::from_value::<Receipt>(body["receipt"].clone()).unwrap(),
+            issued
+        );
+        let fetched: Receipt =
+            serde_json::from_str(&body_text(receipt(&state, 1).await).await).unwrap();
+        assert_eq!(fetched, issued);
+
+        let keys = keys(&state).await;
+        assert_eq!(keys.len(), 2);
+        assert!(keys[0]["retired_at_ms"].is_i64());
+        assert!(keys[1]["retired_at_ms"].is_null());
+        let second = signed_batch(&key, 2, first.compute_hash(), "two");
+        assert_eq!(submit(&state, &second).await.status(), StatusCode::CREATED);
+        let newer: Receipt =
+            serde_json::from_str(&body_text(receipt(&state, 2).await).await).unwrap();
+        assert_eq!(newer.key_id, keys[1]["id"].as_i64().unwrap());
+        assert!(newer.verify(&verifying_key(&keys[1])));
+        assert!(!newer.verify(&verifying_key(&keys[0])));
+
+        // No second receipt and no rewrite, whichever way it is attempted,
+        // so issued_at cannot be backdated.
+        let mut conn = state.pool.acquire().await.unwrap();
+        let reissue = state
+            .receipts
+            .issue(
+                &mut conn,
+                1,
+                &first.agent_id,
+                (0, 1),
+                first.compute_hash(),
+                None,
+            )
+            .await;
+        assert!(
+            reissue
+                .unwrap_err()
+                .to_string()
+                .contains("receipts are issued once")
+        );
+        drop(conn);
+        for sql in [
+            "UPDATE receipts SET issued_at_ms = issued_at_ms - 1000 WHERE batch_id = 1",
+            "DELETE FROM receipts WHERE batch_id = 1",
+            "INSERT OR REPLACE INTO receipts (batch_id, hash, key_id, signature, issued_at_ms) \
+             SELECT batch_id, hash, key_id, signature, 0 FROM receipts WHERE batch_id = 1",
+            "UPDATE server_keys SET retired_at_ms = NULL WHERE id = 1",
+            "DELETE FROM server_keys WHERE id = 1",
+        ] {
+            let err = sqlx::query(sql).execute(&state.pool).await.unwrap_err();
+            assert!(err.to_string().contains("append-only"), "{sql}: {err}");
+        }
+        assert_eq!(fetch_receipt(&state, 1).await, Some(issued));
+
+        assert_eq!(receipt(&state, 99).await.status(), StatusCode::NOT_FOUND);
+        let anonymous = route(
+            &state,
+            "POST",
+            "/admin/server-keys/rotate",
+            None,
+            Vec::new(),
+            1,
+        )
+        .await;
+        assert!(anonymous.status().is_client_error());
+    }
 
-    let row = match row {
-        Some(r) => r,
-        None => return Err(StatusCode::NOT_FOUND),
-    };
+    async fn fetch_receipt(state: &AppState, id: i64) -> Option<Receipt> {
+        let mut conn = state.pool.acquire().await.unwrap();
+        receipts::fetch(&mut conn, id).await.unwrap()
+    }
 
-    Ok(Json(row_to_query_batch(row)?))
-}
+    #[tokio::test]
+    async fn hash_prefix_finds_batches_by_the_start_of_their_hash() {
+        let state = test_state().await;
+        let chain = simulated_chain(6, 6);
+        for batch in &chain {
+            assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
+        }
+        let hashes = common::testutil::chain_hashes(&chain);
+        let hex = |hash: &[u8; 32]| hex_encode(hash);
+        let by_prefix = |prefix: String| {
+            let state = state.clone();
+            async move {
+                handler_get_all(
+                    State(state),
+                    Query(ListParams {
+                        hash_prefix: Some(prefix),
+                        ..ListParams::default()
+                    }),
+                    Query(Vec::new()),
+                )
+                .await
+                .map(|Json(rows)| rows.into_iter().map(|row| row.hash).collect::<Vec<_>>())
+            }
+        };
 
-/* ----------------------- Helper: Convert DB row → LogBatch ----------------------- */
+        let target = hashes[3];
+        for len in [8, 9, 64] {
+            assert_eq!(
+                by_prefix(hex(&target)[..len].to_string()).await,
+                Ok(vec![target])
+            );
+        }
+        assert_eq!(
+            by_prefix(hex(&target)[..10].to_uppercase()).await,
+            Ok(vec![target])
+        );
+        let mut unused = hex(&target);
+        unused.replace_range(
+            ..8,
+            if unused.starts_with('0') {
+                "10000000"
+            } else {
+                "00000000"
+            },
+        );
+        assert!(
+            !hashes
+                .iter()
+                .any(|hash| hex(hash).starts_with(&unused[..8]))
+        );
+        assert_eq!(by_prefix(unused[..8].to_string()).await, Ok(vec![]));
 
-fn row_to_query_batch(row: sqlx::sqlite::SqliteRow) -> Result<QueryBatch, StatusCode> {
-    use std::convert::TryInto;
+        for bad in ["abc", "zzzzzzzz", &"a".repeat(65)] {
+            assert_eq!(
+                by_prefix(bad.to_string()).await,
+                Err(StatusCode::BAD_REQUEST),
+                "{bad}"
+            );
+        }
 
-    let id: i64 = row.get("id");
-    let agent_id: String = row.get("agent_id");
-    let seq: i64 = row.get("seq");
-    let prev_hash: Vec<u8> = row.get("prev_hash");
-    let hash_vec: Vec<u8> = row.get("hash");
-    let compressed: Option<Vec<u8>> = row.try_get("logs_compressed").ok();
-    let logs_json: String = if let Some(blob) = compressed {
-        decompress_json(&blob).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-    } else {
-        row.get("logs")
-    };
-    let timestamp: i64 = row.get("timestamp");
-    let signature_vec: Vec<u8> = row.get("signature");
-    let public_key_vec: Vec<u8> = row.get("public_key");
+        // Odd lengths and carries at the top end of the range.
+        assert_eq!(
+            hash_prefix_range("12345678f").unwrap(),
+            (
+                vec![0x12, 0x34, 0x56, 0x78, 0xf0],
+                Some(vec![0x12, 0x34, 0x56, 0x79])
+            )
+        );
+        assert_eq!(
+            hash_prefix_range("1fffffff").unwrap(),
+            (vec![0x1f, 0xff, 0xff, 0xff], Some(vec![0x20]))
+        );
+        assert_eq!(hash_prefix_range("ffffffff").unwrap().1, None);
+    }
 
-    let logs: Vec<String> = serde_json::from_str(&logs_json)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    /// Every stored row of `state`, read as the listing paths read them.
+    async fn read_rows(state: &AppState) -> Vec<sqlx::sqlite::SqliteRow> {
+        sqlx::query(&format!(
+            "SELECT {BATCH_READ_COLUMNS} FROM batches ORDER BY id"
+        ))
+        .fetch_all(&state.pool)
+        .await
+        .unwrap()
+    }
 
-    // Convert signature
-    let sig_bytes: [u8; 64] = signature_vec
-        .try_into()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    #[tokio::test]
+    async fn raw_listing_sends_the_typed_encoding_byte_for_byte() {
+        use common::testutil::{append_gap, build_chain};
 
-    let signature = Signature::from_bytes(&sig_bytes);
+        let state = AppState {
+            accept_gap_markers: true,
+            accept_after_close: false,
+            ..test_state().await
+        };
+        let key = generate_keypair();
+        // v1 rows, one kept plaintext and one compressed, with escapes.
+        let short = signed_batch(&key, 1, [0u8; 32], "short");
+        let mut long = signed_batch(&key, 2, short.compute_hash(), "");
+        long.logs = vec![
+            "tab\there \"quoted\" \u{e9}\u{1f600} \\ back".repeat(8),
+            String::new(),
+        ];
+        long.lines_read = Some(40);
+        long.sign(&key);
+        // v2 rows with accumulators, then a gap marker.
+        let mut chain = build_chain(&key, "agent-raw", 2);
+        append_gap(&mut chain, &key, 3, "disk lost");
+        for batch in [&short, &long].into_iter().chain(&chain) {
+            assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
+        }
 
-    // Convert public key
-    let pk_bytes: [u8; 32] = public_key_vec
-        .try_into()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let mut scratch = String::new();
+        let rows = read_rows(&state).await;
+        assert_eq!(rows.len(), 5);
+        for row in rows {
+            let raw = serde_json::to_string(
+                &row_to_raw_query_batch(&state.decompressor, &row, &mut scratch).unwrap(),
+            )
+            .unwrap();
+            let typed =
+                serde_json::to_string(&row_to_query_batch(&state.decompressor, row).unwrap())
+                    .unwrap();
+            assert_eq!(raw, typed);
+        }
+        let compressed: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM batches WHERE logs_compressed IS NOT NULL")
+                .fetch_one(&state.pool)
+                .await
+                .unwrap();
+        assert!(compressed > 0);
+
+        // Logs that are not a list of lines fail both ways.
+        sqlx::query("DROP TRIGGER batches_no_update")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE batches SET logs = '[\"a\", 1]' WHERE id = 1")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        let row = read_rows(&state).await.remove(0);
+        assert_eq!(
+            row_to_raw_query_batch(&state.decompressor, &row, &mut scratch).err(),
+            Some(StatusCode::INTERNAL_SERVER_ERROR)
+        );
+        assert_eq!(
+            row_to_query_batch(&state.decompressor, row).err(),
+            Some(StatusCode::INTERNAL_SERVER_ERROR)
+        );
+    }
 
-    let public_key = VerifyingKey::from_bytes(&pk_bytes)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    /// Decode and encode time of a 10k-row `/batches` page, typed and raw.
+    /// `cargo test -p server --release listing_throughput -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn listing_throughput() {
+        let state = test_state().await;
+        let mut sim = ChainSimulator::new(10_000, 1)
+            .line_len(70..=90)
+            .lines_per_batch(20..=20);
+        for _ in 0..10_000 {
+            assert_eq!(
+                submit(&state, &sim.next_batch(0)).await.status(),
+                StatusCode::CREATED
+            );
+        }
 
-    // Convert hashes
-    let prev_hash_bytes: [u8; 32] = prev_hash
-        .try_into()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let rounds = 5;
+        let mut bytes = (0, 0);
+        let start = std::time::Instant::now();
+        for _ in 0..rounds {
+            let typed: Vec<QueryBatch> = read_rows(&state)
+                .await
+                .into_iter()
+                .map(|row| row_to_query_batch(&state.decompressor, row).unwrap())
+                .collect();
+            bytes.0 = serde_json::to_vec(&typed).unwrap().len();
+        }
+        let typed = start.elapsed() / rounds;
+        let start = std::time::Instant::now();
+        for _ in 0..rounds {
+            let raw = rows_to_raw_batches(&state.decompressor, read_rows(&state).await).unwrap();
+            bytes.1 = serde_json::to_vec(&raw).unwrap().len();
+        }
+        let raw = start.elapsed() / rounds;
+        assert_eq!(bytes.0, bytes.1);
+        println!(
+            "10000 rows, {} bytes: typed {typed:?}, raw {raw:?} ({:.2}x)",
+            bytes.0,
+            typed.as_secs_f64() / raw.as_secs_f64()
+        );
+    }
 
-    let hash: [u8; 32] = hash_vec
-        .try_into()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    #[tokio::test]
+    async fn raw_bodies_are_served_byte_exact_and_counted_as_stored() {
+        let state = AppState {
+            store_raw_body: true,
+            ..test_state().await
+        };
+        let key = generate_keypair();
+        let batch = signed_batch(&key, 1, [0u8; 32], "tiny");
+        // Pretty-printed, so re-serializing the parsed batch could not match.
+        let sent = serde_json::to_vec_pretty(&batch).unwrap();
+        let resp = handler_submit_batch(
+            State(state.clone()),
+            ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 9000))),
+            authed(&state, HeaderMap::new()).await,
+            HeaderMap::new(),
+            Bytes::from(sent.clone()),
+        )
+        .await
+        .into_response();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let raw = route(&state, "GET", "/batches/1/raw", None, Vec::new(), 1).await;
+        assert_eq!(raw.status(), StatusCode::OK);
+        assert_eq!(raw.headers()[header::CONTENT_TYPE], "application/json");
+        let body = axum::body::to_bytes(raw.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body.to_vec(), sent);
+        let parsed: LogBatch = serde_json::from_slice(&body).unwrap();
+        assert_eq!(parsed.compute_hash(), batch.compute_hash());
+
+        let blob: i64 = sqlx::query_scalar("SELECT LENGTH(raw_body) FROM batches WHERE id = 1")
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            state.metrics.get("logchain_raw_body_stored_bytes_total"),
+            blob as u64
+        );
+        let logs = serde_json::to_string(&batch.logs).unwrap().len() as i64;
+        let metrics = body_text(handler_metrics(State(state.clone())).await.into_response()).await;
+        assert!(metrics.contains(&format!(
+            "logchain_agent_stored_bytes{{agent_id=\"agent-test\"}} {}",
+            logs + blob
+        )));
+    }
 
-    let batch = LogBatch {
-        prev_hash: prev_hash_bytes,
-        logs,
-        timestamp: timestamp as u64,
-        agent_id,
-        seq: seq as u64,
-        signature,
-        public_key,
-    };
+    #[tokio::test]
+    async fn gzipped_submits_are_decoded_and_archived_decoded() {
+        let state = AppState {
+            store_raw_body: true,
+            ..test_state().await
+        };
+        let key = generate_keypair();
+        let batch = signed_batch(&key, 1, [0u8; 32], &"repeated line ".repeat(50));
+        let json = serde_json::to_vec(&batch).unwrap();
+        let post = |encoding: &'static str, body: Vec<u8>| {
+            let state = state.clone();
+            async move {
+                let mut headers = HeaderMap::new();
+                headers.insert(header::CONTENT_ENCODING, encoding.parse().unwrap());
+                handler_submit_batch(
+                    State(state.clone()),
+                    ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 9000))),
+                    authed(&state, headers.clone()).await,
+                    headers,
+                    Bytes::from(body),
+                )
+                .await
+                .into_response()
+                .status()
+            }
+        };
 
-    Ok(QueryBatch { id, batch, hash })
-}
+        assert_eq!(
+            post("br", json.clone()).await,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(post("gzip", json.clone()).await, StatusCode::BAD_REQUEST);
+        let gzipped = compress_bytes(&json, Compression::default()).unwrap();
+        assert!(gzipped.len() < json.len());
+        assert_eq!(post("gzip", gzipped).await, StatusCode::CREATED);
+        let raw = handler_get_raw(State(state.clone()), Path(1))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(body_text(raw).await.into_bytes(), json);
+
+        let bomb = compress_bytes(
+            &vec![b' '; MAX_SUBMIT_BODY_BYTES as usize + 1],
+            Compression::best(),
+        )
+        .unwrap();
+        assert_eq!(post("GZIP", bomb).await, StatusCode::PAYLOAD_TOO_LARGE);
+    }
 
-async fn validate_chain(
-    tx: &mut Transaction<'_, Sqlite>,
-    batch: &LogBatch,
-    computed_hash: &[u8; 32],
-) -> Result<(), String> {
-    use std::convert::TryInto;
+    #[tokio::test]
+    async fn receipts_of_batches_lost_to_a_restore_are_recorded_as_forks() {
+        use common::receipt::Receipt;
 
-    let last_row = sqlx::query(
-        "SELECT seq, hash FROM batches WHERE agent_id = ?1 ORDER BY seq DESC LIMIT 1",
-    )
-    .bind(&batch.agent_id)
-    .fetch_optional(tx.as_mut())
-    .await
-    .map_err(|_| "failed to check chain state".to_string())?;
+        async fn receipt_of(state: &AppState, batch: &LogBatch) -> Receipt {
+            let resp = submit(state, batch).await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+            let body: serde_json::Value = serde_json::from_str(&body_text(resp).await).unwrap();
+            serde_json::from_value(body["receipt"].clone()).unwrap()
+        }
+        async fn report(state: &AppState, receipts: &[Receipt]) -> Vec<serde_json::Value> {
+            let body = serde_json::json!({ "receipts": receipts, "note": "restored from nightly" });
+            let resp = route(
+                state,
+                "POST",
+                "/admin/forks",
+                Some("admin-secret"),
+                body.to_string().into_bytes(),
+                1,
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            serde_json::from_str(&body_text(resp).await).unwrap()
+        }
 
-    match last_row {
-        None => {
-            if batch.seq != 1 {
-                return Err("first batch for agent must have seq=1".into());
+        // Seqs 1-3 are in the snapshot. Seqs 4-6 are acknowledged after it,
+        // seq 6 under a server key the snapshot never saw.
+        let live = file_state("forks-live").await;
+        let key = generate_keypair();
+        let mut chain = Vec::new();
+        let mut receipts = Vec::new();
+        let mut prev = [0u8; 32];
+        for seq in 1..=6 {
+            if seq == 6 {
+                live.receipts.rotate(&live.pool).await.unwrap();
             }
-            if batch.prev_hash != [0u8; 32] {
-                return Err("first batch prev_hash must be all zeros".into());
+            let batch = signed_batch(&key, seq, prev, &format!("line {seq}"));
+            prev = batch.compute_hash();
+            receipts.push(receipt_of(&live, &batch).await);
+            chain.push(batch);
+            if seq == 3 {
+                let path =
+                    std::env::temp_dir().join(format!("logchain-forks-{}.db", std::process::id()));
+                let _ = std::fs::remove_file(&path);
+                snapshot_database(&live.pool, &path.display().to_string())
+                    .await
+                    .unwrap();
             }
         }
-        Some(row) => {
-            let last_seq: i64 = row.get("seq");
-            let last_hash_vec: Vec<u8> = row.get("hash");
-            let last_hash: [u8; 32] = last_hash_vec
-                .try_into()
-                .map_err(|_| "bad stored hash".to_string())?;
 
-            if batch.seq != (last_seq as u64) + 1 {
-                return Err(format!(
-                    "seq must increment: expected {}, got {}",
-                    last_seq + 1,
-                    batch.seq
-                ));
-            }
+        // The restored server lost seqs 4-6; the agent resyncs and sends a
+        // different seq 4 on top of seq 3.
+        let path = std::env::temp_dir().join(format!("logchain-forks-{}.db", std::process::id()));
+        let pool = connect_pool(
+            &format!("sqlite://{}", path.display()),
+            ack::AckMode::Durable,
+        )
+        .await
+        .unwrap();
+        let restored = state_with_pool(pool).await;
+        let resent = signed_batch(&key, 4, chain[2].compute_hash(), "line 4 after restore");
+        receipt_of(&restored, &resent).await;
+
+        let mut forged = receipts[0].clone();
+        forged.issued_at_ms += 1;
+        let mut uploaded = receipts.clone();
+        uploaded.push(forged);
+        let checks = report(&restored, &uploaded).await;
+        let statuses: Vec<&str> = checks
+            .iter()
+            .map(|c| c["status"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                "intact",
+                "intact",
+                "intact",
+                "hash_differs",
+                "missing",
+                "unverifiable",
+                "unverifiable"
+            ]
+        );
+        assert!(
+            checks[5]["detail"]
+                .as_str()
+                .unwrap()
+                .contains("unknown server key")
+        );
+        assert!(
+            checks[6]["detail"]
+                .as_str()
+                .unwrap()
+                .contains("signature does not verify")
+        );
+        let recorded: Vec<bool> = checks.iter().map(|c| c["fork_id"].is_i64()).collect();
+        assert_eq!(recorded, [false, false, false, true, true, false, false]);
+
+        // Re-uploading a recorded receipt returns its fork, not a second row.
+        let again = report(&restored, &receipts[3..4]).await;
+        assert_eq!(again[0]["fork_id"], checks[3]["fork_id"]);
+
+        let resp = route(
+            &restored,
+            "GET",
+            "/admin/forks?agent_id=agent-test",
+            Some("admin-secret"),
+            Vec::new(),
+            1,
+        )
+        .await;
+        let forks: Vec<serde_json::Value> = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(forks.len(), 2);
+        assert_eq!(forks[0]["kind"], "hash_differs");
+        assert_eq!(forks[0]["note"], "restored from nightly");
+        let kept: Receipt = serde_json::from_value(forks[0]["receipt"].clone()).unwrap();
+        assert_eq!(kept, receipts[3]);
+        let stored: [u8; 32] = serde_json::from_value(forks[0]["stored_hash"].clone()).unwrap();
+        assert_eq!(stored, resent.compute_hash());
+        assert_eq!(forks[1]["kind"], "missing");
+        assert!(forks[1]["stored_hash"].is_null());
+
+        for sql in ["UPDATE forks SET note = NULL", "DELETE FROM forks"] {
+            let err = sqlx::query(sql).execute(&restored.pool).await.unwrap_err();
+            assert!(err.to_string().contains("forks are kept"), "{sql}");
+        }
+        let anonymous = route(&restored, "GET", "/admin/forks", None, Vec::new(), 1).await;
+        assert!(anonymous.status().is_client_error());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn histogram_buckets_batches_by_arrival() {
+        let state = test_state().await;
+        let key = SigningKey::from_bytes(&[61; 32]);
+        let mut prev = [0u8; 32];
+        for seq in 1..=3 {
+            let batch = signed_batch(&key, seq, prev, "line");
+            prev = batch.compute_hash();
+            assert_eq!(submit(&state, &batch).await.status(), StatusCode::CREATED);
+        }
+        // Spread the arrivals over three buckets, leaving two empty.
+        sqlx::query("DROP TRIGGER batches_no_update")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        for (seq, at) in [(1, 10_500), (2, 10_900), (3, 13_100)] {
+            sqlx::query("UPDATE batches SET received_at_ms = ?1 WHERE seq = ?2")
+                .bind(at)
+                .bind(seq)
+                .execute(&state.pool)
+                .await
+                .unwrap();
+        }
+        let size: i64 = sqlx::query_scalar("SELECT logs_size FROM batches WHERE seq = 1")
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+
+        let buckets = histogram::histogram(&state.pool, 10_000, 1_000, 13_999)
+            .await
+            .unwrap();
+        let counts: Vec<(i64, u64)> = buckets
+            .iter()
+            .map(|b| {
+                let b = serde_json::to_value(b).unwrap();
+                (
+                    b["start_ms"].as_i64().unwrap(),
+                    b["batches"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(counts, [(10_000, 2), (11_000, 0), (12_000, 0), (13_000, 1)]);
+        assert_eq!(
+            serde_json::to_value(&buckets[0]).unwrap()["log_bytes"],
+            2 * size
+        );
+
+        let resp = route(
+            &state,
+            "GET",
+            "/batches/histogram?bucket_secs=0",
+            None,
+            Vec::new(),
+            1,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = route(
+            &state,
+            "GET",
+            "/batches/histogram?since_ms=0&bucket_secs=60",
+            None,
+            Vec::new(),
+            1,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = route(&state, "GET", "/batches/histogram", None, Vec::new(), 1).await;
+        let day: Vec<serde_json::Value> = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(day.len(), 25);
+    }
 
-            if batch.prev_hash != last_hash {
-                return Err("prev_hash does not match last hash".into());
-            }
+    #[cfg(feature = "dashboard")]
+    #[tokio::test]
+    async fn dashboard_serves_and_every_endpoint_it_reads_answers() {
+        let state = test_state().await;
+        let key = SigningKey::from_bytes(&[62; 32]);
+        let batch = signed_batch(&key, 1, [0u8; 32], "hello");
+        assert_eq!(submit(&state, &batch).await.status(), StatusCode::CREATED);
+        let started = route(
+            &state,
+            "POST",
+            "/admin/fsck",
+            Some("admin-secret"),
+            Vec::new(),
+            1,
+        )
+        .await;
+        assert_eq!(started.status(), StatusCode::ACCEPTED);
+
+        let resp = route(&state, "GET", "/dashboard", None, Vec::new(), 1).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(
+            resp.headers()["content-type"]
+                .to_str()
+                .unwrap()
+                .starts_with("text/html")
+        );
+        let page = body_text(resp).await;
+
+        let paths: Vec<&str> = page
+            .split("get(\"")
+            .skip(1)
+            .map(|rest| &rest[..rest.find('"').unwrap()])
+            .collect();
+        assert!(paths.len() >= 6, "{paths:?}");
+        for path in paths {
+            assert!(path.starts_with('/'), "{path} is not same-origin");
+            let resp = route(&state, "GET", path, Some("admin-secret"), Vec::new(), 1).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{path}");
+            let body: serde_json::Value = serde_json::from_str(&body_text(resp).await).unwrap();
+            assert!(body.is_array(), "{path}");
         }
     }
 
-    if batch.compute_hash() != *computed_hash {
-        return Err("hash mismatch".into());
+    #[tokio::test]
+    async fn client_info_is_kept_on_rows_and_rejections_when_enabled() {
+        let state = AppState {
+            store_client_info: true,
+            tls_fingerprint_header: Some(HeaderName::from_static("x-ja3-hash")),
+            ..test_state().await
+        };
+        let key = SigningKey::from_bytes(&[63; 32]);
+        let client = |agent: &str, fingerprint: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::USER_AGENT, agent.parse().unwrap());
+            headers.insert("x-ja3-hash", fingerprint.parse().unwrap());
+            headers
+        };
+
+        let first = signed_batch(&key, 1, [0u8; 32], "one");
+        let resp = submit_with(
+            &state,
+            client("logchain-agent/1.2", "e7d705a3286e19ea42f587b344ee6865"),
+            &first,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        // Missing headers are stored as missing.
+        let second = signed_batch(&key, 2, first.compute_hash(), "two");
+        assert_eq!(submit(&state, &second).await.status(), StatusCode::CREATED);
+
+        let resp = route(
+            &state,
+            "GET",
+            "/batches/meta?agent_id=agent-test",
+            None,
+            Vec::new(),
+            1,
+        )
+        .await;
+        let meta: Vec<serde_json::Value> = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(meta[0]["user_agent"], "logchain-agent/1.2");
+        assert_eq!(
+            meta[0]["tls_fingerprint"],
+            "e7d705a3286e19ea42f587b344ee6865"
+        );
+        assert!(meta[1]["user_agent"].is_null());
+        assert!(meta[1]["tls_fingerprint"].is_null());
+
+        // A different client build with a forged batch lands in the audit.
+        let mut forged = signed_batch(&key, 3, second.compute_hash(), "three");
+        forged.logs.push("tampered".into());
+        let resp = submit_with(&state, client("curl/8.5.0", &"f".repeat(300)), &forged).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = route(
+            &state,
+            "GET",
+            "/admin/rejections",
+            Some("admin-secret"),
+            Vec::new(),
+            1,
+        )
+        .await;
+        let rejections: Vec<serde_json::Value> =
+            serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(rejections[0]["category"], "invalid_signature");
+        assert_eq!(rejections[0]["user_agent"], "curl/8.5.0");
+        assert_eq!(
+            rejections[0]["tls_fingerprint"].as_str().unwrap().len(),
+            MAX_CLIENT_INFO_LEN
+        );
+
+        // Off by default: nothing client-supplied is kept.
+        let state = test_state().await;
+        let resp = submit_with(&state, client("logchain-agent/1.2", "e7d705a3"), &first).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let row = sqlx::query("SELECT source, user_agent, tls_fingerprint FROM batches")
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<String, _>("source"), "127.0.0.1:9000");
+        assert_eq!(row.get::<Option<String>, _>("user_agent"), None);
+        assert_eq!(row.get::<Option<String>, _>("tls_fingerprint"), None);
     }
 
-    Ok(())
-}
+    #[tokio::test]
+    async fn rejections_carry_a_machine_readable_code() {
+        let state = test_state().await;
+        let key = SigningKey::from_bytes(&[64; 32]);
+        let first = signed_batch(&key, 1, [0u8; 32], "one");
+        let resp = submit(&state, &first).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert!(!body_text(resp).await.contains("\"code\""));
+
+        let mut forged = signed_batch(&key, 2, first.compute_hash(), "two");
+        forged.logs.push("tampered".into());
+        let body: serde_json::Value =
+            serde_json::from_str(&body_text(submit(&state, &forged).await).await).unwrap();
+        assert_eq!(body["code"], "invalid_signature");
+        let unlinked = signed_batch(&key, 2, [7u8; 32], "two");
+        let body: serde_json::Value =
+            serde_json::from_str(&body_text(submit(&state, &unlinked).await).await).unwrap();
+        assert_eq!(body["code"], "prev_hash_mismatch");
+        assert_eq!(rejection_code("agent_key"), rejection_code("unauthorized"));
+    }
 
-async fn ensure_agent_key(
-    state: &AppState,
-    tx: &mut Transaction<'_, Sqlite>,
-    batch: &LogBatch,
-) -> Result<(), String> {
-    let existing = sqlx::query("SELECT public_key FROM agents WHERE agent_id = ?1")
-        .bind(&batch.agent_id)
-        .fetch_optional(tx.as_mut())
-        .await
-        .map_err(|_| "failed to check agent registry".to_string())?;
+    #[tokio::test]
+    async fn past_days_are_summarized_once_and_guard_deletes() {
+        use common::summary::{DAY_MS, DailySummary, merkle_root};
 
-    match existing {
-        Some(row) => {
-            let stored: Vec<u8> = row.get("public_key");
-            if stored != batch.public_key.to_bytes() {
-                return Err("public key does not match registered agent key".into());
-            }
+        let state = test_state().await;
+        let mut sim = ChainSimulator::new(64, 1).lines_per_batch(1..=1);
+        let batches: Vec<LogBatch> = (0..4).map(|_| sim.next_batch(0)).collect();
+        for batch in &batches {
+            assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
+        }
+        // Seqs 1-3 arrive on 1970-01-02, seq 4 on 1970-01-04.
+        sqlx::query("DROP TRIGGER batches_no_update")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        for (seq, at) in [
+            (1, DAY_MS + 10),
+            (2, DAY_MS + 20),
+            (3, 2 * DAY_MS - 1),
+            (4, 3 * DAY_MS + 5),
+        ] {
+            sqlx::query("UPDATE batches SET received_at_ms = ?1 WHERE seq = ?2")
+                .bind(at)
+                .bind(seq)
+                .execute(&state.pool)
+                .await
+                .unwrap();
         }
-        None => {
-            if state.require_registration {
-                return Err("agent not registered; register key before sending batches".into());
-            }
 
-            sqlx::query("INSERT INTO agents (agent_id, public_key, created_at) VALUES (?1, ?2, ?3)")
-                .bind(&batch.agent_id)
-                .bind(batch.public_key.to_bytes().to_vec())
-                .bind(now_unix())
-                .execute(tx.as_mut())
+        // Only days that have ended by the cutoff.
+        assert_eq!(
+            summaries::generate(&state.pool, 4 * DAY_MS - 1)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            summaries::generate(&state.pool, 4 * DAY_MS - 1)
                 .await
-                .map_err(|_| "failed to auto-register agent key".to_string())?;
+                .unwrap(),
+            0
+        );
+        let resp = route(
+            &state,
+            "GET",
+            "/summaries?agent_id=sim-agent-000",
+            None,
+            Vec::new(),
+            1,
+        )
+        .await;
+        let listed: Vec<DailySummary> = serde_json::from_str(&body_text(resp).await).unwrap();
+        let hashes: Vec<[u8; 32]> = batches.iter().map(LogBatch::compute_hash).collect();
+        assert_eq!(
+            listed,
+            [DailySummary {
+                agent_id: "sim-agent-000".into(),
+                day: "1970-01-02".into(),
+                batches: 3,
+                lines: 3,
+                min_seq: 1,
+                max_seq: 3,
+                head_hash: hashes[2],
+                merkle_root: merkle_root(&hashes[..3]),
+            }]
+        );
+        assert_eq!(
+            summaries::generate(&state.pool, 4 * DAY_MS).await.unwrap(),
+            1
+        );
+        let resp = route(
+            &state,
+            "GET",
+            "/summaries?since_day=1970-01-03",
+            None,
+            Vec::new(),
+            1,
+        )
+        .await;
+        let listed: Vec<DailySummary> = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].day, "1970-01-04");
+
+        for sql in [
+            "UPDATE daily_summaries SET lines = 0",
+            "DELETE FROM daily_summaries",
+        ] {
+            let err = sqlx::query(sql).execute(&state.pool).await.unwrap_err();
+            assert!(
+                err.to_string().contains("daily summaries are kept"),
+                "{sql}"
+            );
         }
+        // With the blanket delete ban lifted, as an archiver would, only
+        // summarized days can go.
+        sqlx::query("DROP TRIGGER batches_no_delete")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM batches WHERE seq <= 3")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        let fresh = test_state().await;
+        assert_eq!(
+            submit(&fresh, &batches[0]).await.status(),
+            StatusCode::CREATED
+        );
+        sqlx::query("DROP TRIGGER batches_no_delete")
+            .execute(&fresh.pool)
+            .await
+            .unwrap();
+        let err = sqlx::query("DELETE FROM batches")
+            .execute(&fresh.pool)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("no daily summary"));
     }
 
-    Ok(())
-}
+    #[tokio::test]
+    async fn request_ids_are_adopted_echoed_and_recorded() {
+        use tower::ServiceExt;
+
+        let state = test_state().await;
+        let key = SigningKey::from_bytes(&[64; 32]);
+        let first = signed_batch(&key, 1, [0u8; 32], "one");
+        let send = |batch: &LogBatch, id: Option<&str>| {
+            let mut request = axum::http::Request::builder()
+                .method("POST")
+                .uri("/submit")
+                .header("content-type", "application/json");
+            if let Some(id) = id {
+                request = request.header("x-request-id", id);
+            }
+            let mut request = request
+                .body(Body::from(serde_json::to_vec(batch).unwrap()))
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([10, 2, 0, 1], 9000))));
+            build_router(state.clone()).oneshot(request)
+        };
+        let echoed = |resp: &Response| resp.headers()["x-request-id"].to_str().unwrap().to_string();
+
+        let stored = send(&first, Some("agent-7f3a.1")).await.unwrap();
+        assert_eq!(stored.status(), StatusCode::CREATED);
+        assert_eq!(echoed(&stored), "agent-7f3a.1");
+        let kept: Option<String> = sqlx::query_scalar("SELECT request_id FROM receipts")
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+        assert_eq!(kept.as_deref(), Some("agent-7f3a.1"));
+
+        // A malformed id is replaced, and a missing one generated.
+        let conflicting = signed_batch(&key, 1, [0u8; 32], "other");
+        let refused = send(&conflicting, Some("two words")).await.unwrap();
+        assert_eq!(refused.status(), StatusCode::CONFLICT);
+        let generated = echoed(&refused);
+        assert_ne!(generated, "two words");
+        assert!(common::request_id::is_well_formed(&generated));
+        let anonymous = send(&conflicting, None).await.unwrap();
+        assert_eq!(echoed(&anonymous).len(), 36);
+
+        let resp = route(
+            &state,
+            "GET",
+            "/admin/rejections",
+            Some("admin-secret"),
+            Vec::new(),
+            1,
+        )
+        .await;
+        let rejections: serde_json::Value = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(rejections[1]["request_id"], generated.as_str());
+        assert_eq!(rejections[0]["request_id"], echoed(&anonymous).as_str());
+
+        let line: serde_json::Value = serde_json::from_str(&request_id::log_line(
+            "agent-7f3a.1",
+            "POST",
+            "/submit",
+            201,
+            30_012,
+        ))
+        .unwrap();
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "request_id": "agent-7f3a.1",
+                "method": "POST",
+                "path": "/submit",
+                "status": 201,
+                "duration_ms": 30_012,
+            })
+        );
+    }
 
-fn parse_hex_public_key(hex: &str) -> Result<VerifyingKey, String> {
-    let bytes = parse_hex_bytes::<32>(hex)?;
-    VerifyingKey::from_bytes(&bytes).map_err(|_| "invalid public key bytes".into())
-}
+    #[tokio::test]
+    async fn read_only_storage_answers_503_and_fails_readiness_until_a_write() {
+        let state = file_state("read-only").await;
+        let key = SigningKey::from_bytes(&[65; 32]);
+        let first = signed_batch(&key, 1, [0u8; 32], "one");
+        assert_eq!(submit(&state, &first).await.status(), StatusCode::CREATED);
+        let ready = route(&state, "GET", "/readyz", None, Vec::new(), 1).await;
+        assert_eq!(ready.status(), StatusCode::OK);
+
+        // The same database opened read-only, as after a remount.
+        let path =
+            std::env::temp_dir().join(format!("logchain-read-only-{}.db", std::process::id()));
+        let read_only = SqlitePoolOptions::new()
+            .connect(&format!("sqlite://{}?mode=ro", path.display()))
+            .await
+            .unwrap();
+        let broken = AppState {
+            pool: read_only,
+            ..state.clone()
+        };
+        let second = signed_batch(&key, 2, first.compute_hash(), "two");
+        let resp = submit(&broken, &second).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body_text(resp).await.contains("storage read-only"));
+        assert_eq!(
+            state
+                .metrics
+                .get(r#"logchain_storage_faults_total{kind="storage_read_only"}"#),
+            1
+        );
+        assert_eq!(
+            state
+                .metrics
+                .get(r#"logchain_submit_rejected_total{reason="storage_read_only"}"#),
+            1
+        );
+        let resp = route(&state, "GET", "/readyz", None, Vec::new(), 1).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let readiness: serde_json::Value = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(readiness["ready"], false);
+        assert_eq!(readiness["storage_faults"][0]["during"], "submit");
+        assert_eq!(readiness["storage_faults"][0]["fault"], "read_only");
+
+        // Once writes work again, the next stored batch clears it.
+        assert_eq!(submit(&state, &second).await.status(), StatusCode::CREATED);
+        let ready = route(&state, "GET", "/readyz", None, Vec::new(), 1).await;
+        assert_eq!(ready.status(), StatusCode::OK);
+    }
 
-fn parse_hex_signature(hex: &str) -> Result<Signature, String> {
-    let bytes = parse_hex_bytes::<64>(hex)?;
-    Ok(Signature::from_bytes(&bytes))
-}
+    #[tokio::test]
+    async fn the_first_storage_fault_of_each_kind_posts_to_the_alert_webhook() {
+        let alerts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let hook = Router::new().route(
+            "/",
+            post({
+                let alerts = alerts.clone();
+                move |Json(alert): Json<serde_json::Value>| async move {
+                    alerts.lock().unwrap().push(alert);
+                    StatusCode::NO_CONTENT
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, hook).await.unwrap() });
+
+        let state = AppState {
+            storage: Arc::new(StorageHealth::new(Some(format!("http://{addr}/")))),
+            ..file_state("alert-webhook").await
+        };
+        let key = generate_keypair();
+        assert_eq!(
+            register(&state, "agent-alert", &key).await,
+            StatusCode::CREATED
+        );
+        let path =
+            std::env::temp_dir().join(format!("logchain-alert-webhook-{}.db", std::process::id()));
+        let broken = AppState {
+            pool: SqlitePoolOptions::new()
+                .connect(&format!("sqlite://{}?mode=ro", path.display()))
+                .await
+                .unwrap(),
+            ..state.clone()
+        };
 
-fn parse_hex_bytes<const N: usize>(hex: &str) -> Result<[u8; N], String> {
-    if hex.len() != N * 2 {
-        return Err(format!("expected {} hex chars", N * 2));
+        // Three failed submits and a failed registration: two runs of faults.
+        let batch = signed_batch(&key, 1, [0u8; 32], "one");
+        for _ in 0..3 {
+            assert_eq!(
+                submit(&broken, &batch).await.status(),
+                StatusCode::SERVICE_UNAVAILABLE
+            );
+        }
+        assert_eq!(
+            register(&broken, "agent-alert-2", &key).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        for _ in 0..100 {
+            if alerts.lock().unwrap().len() >= 2 {
+                break;
+            }
+            tokio::time::sleep(StdDuration::from_millis(20)).await;
+        }
+        let mut alerts = alerts.lock().unwrap().clone();
+        alerts.sort_by_key(|alert| alert["during"].to_string());
+        assert_eq!(alerts.len(), 2, "{alerts:?}");
+        assert_eq!(alerts[0]["event"], "storage_fault");
+        assert_eq!(alerts[0]["during"], "register");
+        assert_eq!(alerts[1]["during"], "submit");
+        assert_eq!(alerts[1]["fault"], "read_only");
+        assert_eq!(alerts[1]["category"], "storage_read_only");
+        assert!(alerts[1]["since_ms"].as_i64().unwrap() > 0);
     }
 
-    let mut out = [0u8; N];
-    for (i, chunk) in hex.as_bytes().chunks(2).enumerate() {
-        let high = hex_val(chunk[0])?;
-        let low = hex_val(chunk[1])?;
-        out[i] = (high << 4) | low;
+    #[tokio::test]
+    async fn registry_writes_on_read_only_storage_answer_503_instead_of_panicking() {
+        let state = file_state("read-only-registry").await;
+        let (k1, k2) = (generate_keypair(), generate_keypair());
+        assert_eq!(register(&state, "agent-ro", &k1).await, StatusCode::CREATED);
+
+        let path = std::env::temp_dir().join(format!(
+            "logchain-read-only-registry-{}.db",
+            std::process::id()
+        ));
+        let broken = AppState {
+            pool: SqlitePoolOptions::new()
+                .connect(&format!("sqlite://{}?mode=ro", path.display()))
+                .await
+                .unwrap(),
+            ..state.clone()
+        };
+        let ts = Some(now_unix() as u64);
+        let resp = handler_rotate_agent(
+            State(broken.clone()),
+            authed(&broken, HeaderMap::new()).await,
+            HeaderMap::new(),
+            Json(rotation_at("agent-ro", &k1, &k2, 1, ts)),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body_text(resp).await.contains("storage read-only"));
+        assert_eq!(
+            register(&broken, "agent-ro-2", &k2).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            state
+                .metrics
+                .get(r#"logchain_storage_faults_total{kind="storage_read_only"}"#),
+            2
+        );
+        let resp = route(&state, "GET", "/readyz", None, Vec::new(), 1).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Nothing was half-applied; the same rotation goes through once writable.
+        assert_eq!(
+            rotate(&state, rotation_at("agent-ro", &k1, &k2, 1, ts)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            register(&state, "agent-ro-2", &k2).await,
+            StatusCode::CREATED
+        );
+        let ready = route(&state, "GET", "/readyz", None, Vec::new(), 1).await;
+        assert_eq!(ready.status(), StatusCode::OK);
     }
-    Ok(out)
-}
 
-fn hex_val(b: u8) -> Result<u8, String> {
-    match b {
-        b'0'..=b'9' => Ok(b - b'0'),
-        b'a'..=b'f' => Ok(10 + (b - b'a')),
-        b'A'..=b'F' => Ok(10 + (b - b'A')),
-        _ => Err("invalid hex".into()),
+    #[tokio::test]
+    async fn a_database_behind_its_watermarks_refuses_submits_until_accepted() {
+        let path =
+            std::env::temp_dir().join(format!("logchain-watermark-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let key = SigningKey::from_bytes(&[66; 32]);
+        let first = signed_batch(&key, 1, [0u8; 32], "one");
+        let second = signed_batch(&key, 2, first.compute_hash(), "two");
+        let third = signed_batch(&key, 3, second.compute_hash(), "three");
+
+        // The live server stores two batches and flushes its marks.
+        let live = test_state().await;
+        let marks = watermark::Watermarks::open(path.clone(), &live.pool, false)
+            .await
+            .unwrap();
+        let live = AppState {
+            watermarks: Some(Arc::new(marks)),
+            ..live
+        };
+        for batch in [&first, &second] {
+            assert_eq!(submit(&live, batch).await.status(), StatusCode::CREATED);
+        }
+        live.watermarks.as_ref().unwrap().flush().unwrap();
+
+        // A backup taken after the first batch is restored.
+        let restored = test_state().await;
+        assert_eq!(
+            submit(&restored, &first).await.status(),
+            StatusCode::CREATED
+        );
+        let marks = watermark::Watermarks::open(path.clone(), &restored.pool, false)
+            .await
+            .unwrap();
+        assert_eq!(marks.behind().len(), 1);
+        assert_eq!(marks.behind()[0].agent_id, "agent-test");
+        assert_eq!(marks.behind()[0].watermark.id, 2);
+        assert_eq!(marks.behind()[0].database.map(|mark| mark.id), Some(1));
+        let suspect = AppState {
+            watermarks: Some(Arc::new(marks)),
+            ..restored.clone()
+        };
+        let resp = submit(&suspect, &second).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body_text(resp).await.contains("rollback suspected"));
+        assert_eq!(
+            suspect
+                .metrics
+                .get(r#"logchain_submit_rejected_total{reason="rollback_suspected"}"#),
+            1
+        );
+        assert!(watermark::render_metrics(&suspect).contains("logchain_rollback_suspected 1\n"));
+        let resp = route(&suspect, "GET", "/readyz", None, Vec::new(), 1).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let readiness: serde_json::Value = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(readiness["rollback_suspected"][0]["watermark"]["id"], 2);
+
+        // Restarting again changes nothing: the file kept the evidence.
+        let again = watermark::Watermarks::open(path.clone(), &restored.pool, false)
+            .await
+            .unwrap();
+        assert_eq!(again.behind().len(), 1);
+
+        // `--accept-rollback` records the discrepancy and takes submits again.
+        let marks = watermark::Watermarks::open(path.clone(), &restored.pool, true)
+            .await
+            .unwrap();
+        assert!(marks.behind().is_empty());
+        let (kind, rows): (String, i64) = sqlx::query_as(
+            "SELECT kind, rows FROM maintenance_events WHERE kind = 'rollback_accepted'",
+        )
+        .fetch_one(&restored.pool)
+        .await
+        .unwrap();
+        assert_eq!((kind.as_str(), rows), ("rollback_accepted", 1));
+        let accepted = AppState {
+            watermarks: Some(Arc::new(marks)),
+            ..restored
+        };
+        assert!(watermark::render_metrics(&accepted).contains("logchain_rollback_suspected 0\n"));
+        for batch in [&second, &third] {
+            assert_eq!(submit(&accepted, batch).await.status(), StatusCode::CREATED);
+        }
+        let ready = route(&accepted, "GET", "/readyz", None, Vec::new(), 1).await;
+        assert_eq!(ready.status(), StatusCode::OK);
+        let _ = std::fs::remove_file(&path);
     }
-}
 
-fn compress_json(data: &str) -> Result<Vec<u8>, String> {
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-    encoder
-        .write_all(data.as_bytes())
-        .map_err(|e| e.to_string())?;
-    encoder.finish().map_err(|e| e.to_string())
-}
+    #[tokio::test]
+    async fn simulated_fleets_are_stored_until_their_injected_fault() {
+        use common::testkit::Fault;
 
-fn decompress_json(bytes: &[u8]) -> Result<String, String> {
-    let mut decoder = GzDecoder::new(bytes);
-    let mut out = String::new();
-    decoder
-        .read_to_string(&mut out)
-        .map_err(|e| e.to_string())?;
-    Ok(out)
-}
+        let mut sim = ChainSimulator::new(188, 3);
+        let state = test_state().await;
+        for batch in sim.run(4) {
+            assert_eq!(submit(&state, &batch).await.status(), StatusCode::CREATED);
+        }
 
-async fn configure_sqlite(pool: &SqlitePool) {
-    // WAL improves durability and allows concurrent readers.
-    let _ = sqlx::query("PRAGMA journal_mode=WAL").execute(pool).await;
-    let _ = sqlx::query("PRAGMA synchronous=FULL").execute(pool).await;
-}
+        for fault in [
+            Fault::BitFlip,
+            Fault::SeqGap,
+            Fault::WrongPrevHash,
+            Fault::ForeignSignature,
+        ] {
+            let mut sim = ChainSimulator::new(188, 3);
+            let mut batches = sim.run(4);
+            let injected = sim.inject(&mut batches, fault);
+            let state = test_state().await;
+            for batch in &batches {
+                // Everything the faulty agent sends from the fault on is refused.
+                let refused = batch.agent_id == injected.agent_id && batch.seq >= injected.seq;
+                let status = submit(&state, batch).await.status();
+                assert_eq!(
+                    status == StatusCode::CREATED,
+                    !refused,
+                    "{injected}: seq {} got {status}",
+                    batch.seq
+                );
+            }
+        }
+    }
 
-async fn snapshot_database(pool: &SqlitePool, path: &str) -> Result<(), String> {
-    let escaped = path.replace('\'', "''");
-    let vacuum_sql = format!("VACUUM INTO '{escaped}'");
-    sqlx::query(&vacuum_sql)
-        .execute(pool)
-        .await
-        .map(|_| ())
-        .map_err(|e| e.to_string())
-}
+    #[tokio::test]
+    async fn tiered_rows_read_back_the_same_and_fsck_checks_their_stubs() {
+        use std::sync::Mutex;
+        use std::sync::atomic::AtomicBool;
 
-async fn ensure_column(pool: &SqlitePool, table: &str, column: &str, definition: &str) {
-    let sql = format!(
-        "SELECT 1 FROM pragma_table_info('{table}') WHERE name = ?1"
-    );
-    let exists: Option<(i64,)> = sqlx::query_as(&sql)
-        .bind(column)
-        .fetch_optional(pool)
-        .await
-        .ok()
-        .flatten();
+        async fn read_all(state: &AppState) -> Vec<(StatusCode, String)> {
+            let mut out = Vec::new();
+            for uri in ["/batches", "/batches/export?format=json", "/batches/2/logs"] {
+                let resp = route(state, "GET", uri, None, Vec::new(), 1).await;
+                out.push((resp.status(), body_text(resp).await));
+            }
+            out
+        }
+        async fn fsck_kinds(state: &AppState) -> Vec<(Option<i64>, &'static str)> {
+            let report = Mutex::new(fsck::FsckReport::default());
+            fsck::scan(
+                &state.pool,
+                &state.decompressor,
+                100,
+                &AtomicBool::new(false),
+                &report,
+            )
+            .await
+            .unwrap();
+            let report = report.into_inner().unwrap();
+            report
+                .discrepancies
+                .iter()
+                .map(|d| (d.id, d.kind))
+                .collect()
+        }
 
-    if exists.is_some() {
-        return;
+        let dir = std::env::temp_dir().join(format!("logchain-tiered-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = tiering::BlobStore::parse(dir.to_str().unwrap()).unwrap();
+        let state = test_state().await;
+        let mut sim = ChainSimulator::new(189, 2).line_len(300..=400);
+        for batch in sim.run(3) {
+            assert_eq!(submit(&state, &batch).await.status(), StatusCode::CREATED);
+        }
+        let before = read_all(&state).await;
+        assert!(
+            before.iter().all(|(status, _)| *status == StatusCode::OK),
+            "{before:?}"
+        );
+        // Without a stub the compressed copy cannot be cleared.
+        assert!(
+            sqlx::query("UPDATE batches SET logs_compressed = NULL WHERE id = 1")
+                .execute(&state.pool)
+                .await
+                .is_err()
+        );
+
+        // Four rows per transaction: two chunks.
+        assert_eq!(
+            tiering::tier(&state.pool, &store, now_unix_ms() + 1, 4).await,
+            Ok(6)
+        );
+        assert_eq!(
+            tiering::tier(&state.pool, &store, now_unix_ms() + 1, 4).await,
+            Ok(0)
+        );
+        let in_row: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM batches WHERE logs_compressed IS NOT NULL")
+                .fetch_one(&state.pool)
+                .await
+                .unwrap();
+        assert_eq!(in_row, 0);
+        assert_eq!(read_all(&state).await, before);
+        assert_eq!(fsck_kinds(&state).await, []);
+
+        // Tiering is the only update a row takes, and stubs are kept.
+        for tamper in [
+            "UPDATE batches SET seq = 99 WHERE id = 1",
+            "UPDATE batches SET logs = '[]', logs_compressed = NULL WHERE id = 1",
+            "UPDATE blob_locations SET location = '/elsewhere' WHERE batch_id = 1",
+            "DELETE FROM blob_locations WHERE batch_id = 1",
+        ] {
+            assert!(
+                sqlx::query(tamper).execute(&state.pool).await.is_err(),
+                "{tamper}"
+            );
+        }
+
+        let location: String =
+            sqlx::query_scalar("SELECT location FROM blob_locations WHERE batch_id = 2")
+                .fetch_one(&state.pool)
+                .await
+                .unwrap();
+        std::fs::write(&location, b"not the blob").unwrap();
+        assert_eq!(
+            fsck_kinds(&state).await,
+            [(Some(2), "blob_digest_mismatch")]
+        );
+        let resp = route(&state, "GET", "/batches/2/logs", None, Vec::new(), 1).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        std::fs::remove_file(&location).unwrap();
+        assert_eq!(fsck_kinds(&state).await, [(Some(2), "blob_unreadable")]);
+        let _ = std::fs::remove_dir_all(&dir);
     }
 
-    let alter = format!(
-        "ALTER TABLE {table} ADD COLUMN {column} {definition}"
-    );
-    let _ = sqlx::query(&alter).execute(pool).await;
-}
+    #[tokio::test]
+    async fn redaction_hides_a_line_but_cannot_alter_any() {
+        use common::batch::BATCH_VERSION_V3;
+        use common::redaction::{Redaction, marker};
+
+        async fn redact(
+            state: &AppState,
+            token: Option<&str>,
+            batch_id: i64,
+            line_idx: usize,
+        ) -> Response {
+            let body = serde_json::to_vec(
+                &serde_json::json!({ "batch_id": batch_id, "line_idx": line_idx }),
+            )
+            .unwrap();
+            route(state, "POST", "/admin/redactions", token, body, 1).await
+        }
+        async fn content_issues(state: &AppState) -> Vec<serde_json::Value> {
+            let resp = route(
+                state,
+                "POST",
+                "/admin/integrity-check",
+                Some("admin-secret"),
+                Vec::new(),
+                1,
+            )
+            .await;
+            let report: serde_json::Value = serde_json::from_str(&body_text(resp).await).unwrap();
+            report["content_issues"].as_array().unwrap().clone()
+        }
 
-async fn ensure_append_only_triggers(pool: &SqlitePool) {
-    // Block updates/deletes to enforce append-only.
-    let _ = sqlx::query("DROP TRIGGER IF EXISTS batches_no_update").execute(pool).await;
-    let _ = sqlx::query("DROP TRIGGER IF EXISTS batches_no_delete").execute(pool).await;
-    let _ = sqlx::query("DROP TRIGGER IF EXISTS batches_enforce_seq").execute(pool).await;
+        let state = test_state().await;
+        let key = generate_keypair();
+        let first = signed_batch(&key, 1, [0u8; 32], "user alice@example.com logged in");
+        assert_eq!(submit(&state, &first).await.status(), StatusCode::CREATED);
+        let mut second = signed_batch(
+            &key,
+            2,
+            first.compute_hash(),
+            "user bob@example.com logged in",
+        );
+        second.version = BATCH_VERSION_V3;
+        second
+            .logs
+            .push(format!("GET /health 200 {}", "ok ".repeat(30)));
+        second.sign(&key);
+        assert_eq!(submit(&state, &second).await.status(), StatusCode::CREATED);
+
+        // Admins only, v3 only, and only lines that exist.
+        assert_eq!(
+            redact(&state, None, 2, 0).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            redact(&state, Some("admin-secret"), 1, 0).await.status(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            redact(&state, Some("admin-secret"), 2, 2).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            redact(&state, Some("admin-secret"), 9, 0).await.status(),
+            StatusCode::NOT_FOUND
+        );
 
-    sqlx::query(
-        r#"
-        CREATE TRIGGER batches_no_update
-        BEFORE UPDATE ON batches
-        BEGIN
-            SELECT RAISE(ABORT, 'append-only: updates forbidden');
-        END;
-        "#,
-    )
-    .execute(pool)
-    .await
-    .ok();
+        let resp = redact(&state, Some("admin-secret"), 2, 0).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let redaction: Redaction = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(
+            (redaction.batch_id, redaction.seq, redaction.line_idx),
+            (2, 2, 0)
+        );
+        assert_eq!(redaction.authorized_by, "bootstrap");
+        assert_eq!(redaction.commitment, second.line_leaf(0, &second.logs[0]));
+        let keys = route(&state, "GET", "/server-keys", None, Vec::new(), 1).await;
+        let keys: Vec<serde_json::Value> = serde_json::from_str(&body_text(keys).await).unwrap();
+        assert!(
+            redaction
+                .verify(&parse_hex_public_key(keys[0]["public_key"].as_str().unwrap()).unwrap())
+        );
+        assert_eq!(
+            redact(&state, Some("admin-secret"), 2, 0).await.status(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(state.metrics.get("logchain_redactions_total"), 1);
+
+        // The line is gone from every copy of the row, and what is served
+        // still verifies against the agent's signature.
+        let row = sqlx::query("SELECT logs, logs_compressed, raw_body FROM batches WHERE id = 2")
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+        let logs: Option<String> = row.get("logs");
+        assert!(!logs.unwrap_or_default().contains("bob@"));
+        let compressed: Option<Vec<u8>> = row.get("logs_compressed");
+        let mut inflated = String::new();
+        if let Some(blob) = compressed {
+            state.decompressor.json_into(&blob, &mut inflated).unwrap();
+        }
+        assert!(!inflated.contains("bob@"));
+        assert_eq!(row.get::<Option<Vec<u8>>, _>("raw_body"), None);
+
+        let served = list(
+            &state,
+            ListParams {
+                agent_id: Some("agent-test".into()),
+                ..Default::default()
+            },
+        )
+        .await;
+        let served = &served.iter().find(|b| b.batch.seq == 2).unwrap();
+        assert_eq!(served.redacted, [0]);
+        assert_eq!(served.batch.logs[0], marker(&redaction.commitment));
+        assert_eq!(served.batch.logs[1], second.logs[1]);
+        assert!(served.batch.verify_redacted(&served.redacted));
+        assert!(!served.batch.verify());
+        let listed = route(&state, "GET", "/batches/2/redactions", None, Vec::new(), 1).await;
+        assert_eq!(
+            serde_json::from_str::<Vec<Redaction>>(&body_text(listed).await).unwrap(),
+            [redaction]
+        );
+        let none = route(&state, "GET", "/batches/1/redactions", None, Vec::new(), 1).await;
+        assert_eq!(body_text(none).await, "[]");
+        assert!(content_issues(&state).await.is_empty());
+
+        // The row's identity stays frozen, and a rewrite of a line that was
+        // not redacted gets past the trigger but not the hash.
+        let moved = sqlx::query("UPDATE batches SET seq = 7 WHERE id = 2")
+            .execute(&state.pool)
+            .await;
+        assert!(moved.is_err());
+        let mut altered = served.batch.logs.clone();
+        altered[1] = "GET /health 500".into();
+        let altered = canonical_logs_json(&altered);
+        sqlx::query(
+            "UPDATE batches SET logs = ?1, logs_compressed = NULL, logs_size = ?2 WHERE id = 2",
+        )
+        .bind(&altered)
+        .bind(altered.len() as i64)
+        .execute(&state.pool)
+        .await
+        .unwrap();
+        let issues = content_issues(&state).await;
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0]["seq"], 2);
+    }
 
-    sqlx::query(
-        r#"
-        CREATE TRIGGER batches_no_delete
-        BEFORE DELETE ON batches
-        BEGIN
-            SELECT RAISE(ABORT, 'append-only: deletes forbidden');
-        END;
-        "#,
-    )
-    .execute(pool)
-    .await
-    .ok();
+    #[tokio::test]
+    async fn version_advertises_exactly_what_submit_accepts() {
+        let state = test_state().await;
+        let resp = route(&state, "GET", "/version", None, Vec::new(), 1).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let caps: Capabilities = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(caps, Capabilities::current(env!("CARGO_PKG_VERSION")));
+        assert!(common::compat::check_reader(&caps, "CLI").is_ok());
+
+        // Every advertised version is stored, the next one is refused.
+        let key = generate_keypair();
+        let mut prev_hash = [0u8; 32];
+        for (seq, version) in caps.batch_versions.iter().enumerate() {
+            let mut batch = signed_batch(&key, seq as u64 + 1, prev_hash, "line");
+            batch.version = *version;
+            batch.sign(&key);
+            assert_eq!(
+                submit(&state, &batch).await.status(),
+                StatusCode::CREATED,
+                "v{version}"
+            );
+            prev_hash = batch.compute_hash();
+        }
+        let mut unknown = signed_batch(
+            &key,
+            caps.batch_versions.len() as u64 + 1,
+            prev_hash,
+            "line",
+        );
+        unknown.version = caps.batch_versions.iter().max().unwrap() + 1;
+        unknown.sign(&key);
+        let resp = submit(&state, &unknown).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(body_text(resp).await.contains("unsupported batch version"));
+    }
 
-    // Enforce monotonic seq and hash linkage per agent even if someone bypasses the API.
-    sqlx::query(
-        r#"
-        CREATE TRIGGER batches_enforce_seq
-        BEFORE INSERT ON batches
-        BEGIN
-            -- Detect last state for this agent.
-            SELECT
-                CASE
-                    WHEN (SELECT COUNT(*) FROM batches WHERE agent_id = NEW.agent_id) = 0 THEN
-                        CASE
-                            WHEN NEW.seq != 1 THEN
-                                RAISE(ABORT, 'append-only: first seq must be 1')
-                            WHEN NEW.prev_hash != zeroblob(32) THEN
-                                RAISE(ABORT, 'append-only: first prev_hash must be zero')
-                        END
-                    ELSE
-                        CASE
-                            WHEN NEW.seq != (SELECT seq + 1 FROM batches WHERE agent_id = NEW.agent_id ORDER BY seq DESC LIMIT 1) THEN
-                                RAISE(ABORT, 'append-only: non-contiguous seq')
-                            WHEN NEW.prev_hash != (SELECT hash FROM batches WHERE agent_id = NEW.agent_id ORDER BY seq DESC LIMIT 1) THEN
-                                RAISE(ABORT, 'append-only: prev_hash mismatch')
-                        END
-                END;
-        END;
-        "#,
-    )
-    .execute(pool)
-    .await
-    .ok();
-}
+    #[tokio::test]
+    async fn concurrent_submits_at_one_seq_store_one_batch_and_answer_each_caller() {
+        // Registered up front, so nothing but the submit itself serializes
+        // the race: two copies of one agent (a cloned VM) sharing its key.
+        let mut state = file_state("race-clash").await;
+        state.require_registration = true;
+        let key = generate_keypair();
+        assert_eq!(
+            register(&state, "agent-test", &key).await,
+            StatusCode::CREATED
+        );
 
-fn now_unix() -> i64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|d| d.as_secs() as i64)
-        .unwrap_or(0)
-}
+        let mut prev = [0u8; 32];
+        let mut clashes = 0;
+        for seq in 1..=6 {
+            let mine = signed_batch(&key, seq, prev, &format!("mine-{seq}"));
+            let theirs = signed_batch(&key, seq, prev, &format!("theirs-{seq}"));
+            let sent = [&mine, &theirs, &mine, &theirs];
+            let answers = futures_util::future::join_all(sent.iter().map(|batch| async {
+                let resp = submit(&state, batch).await;
+                let status = resp.status();
+                (
+                    status,
+                    serde_json::from_str::<serde_json::Value>(&body_text(resp).await).unwrap(),
+                )
+            }))
+            .await;
+
+            let created: Vec<usize> = (0..4)
+                .filter(|i| answers[*i].0 == StatusCode::CREATED)
+                .collect();
+            assert_eq!(created.len(), 1, "seq {seq}: {answers:?}");
+            let winner = sent[created[0]];
+            let won = hex_encode(&winner.compute_hash());
+            for ((status, body), batch) in answers.iter().zip(sent) {
+                if batch.compute_hash() == winner.compute_hash() {
+                    assert!(
+                        *status == StatusCode::CREATED || body["status"] == "duplicate",
+                        "{body}"
+                    );
+                    assert!(body["receipt"].is_object(), "{body}");
+                } else {
+                    assert_eq!(*status, StatusCode::CONFLICT, "{body}");
+                    assert_eq!(body["stored_hash"], won.as_str());
+                    clashes += 1;
+                }
+            }
+            prev = winner.compute_hash();
+        }
 
-struct RateLimiter {
-    max: u32,
-    window: StdDuration,
-    buckets: Mutex<HashMap<String, (Instant, u32)>>,
-}
+        let per_seq: Vec<(i64, i64)> =
+            sqlx::query_as("SELECT seq, COUNT(*) FROM batches GROUP BY seq ORDER BY seq")
+                .fetch_all(&state.pool)
+                .await
+                .unwrap();
+        assert_eq!(per_seq, (1..=6).map(|seq| (seq, 1)).collect::<Vec<_>>());
+        assert_eq!(
+            state.metrics.get("logchain_submit_seq_clashes_total"),
+            clashes
+        );
+        assert_eq!(
+            state.metrics.get("logchain_submit_duplicate_resends_total"),
+            6
+        );
+        assert_eq!(rejected(&state, "seq_conflict"), clashes);
+    }
 
-impl RateLimiter {
-    fn new(max: u32, window: StdDuration) -> Self {
-        Self {
-            max,
-            window,
-            buckets: Mutex::new(HashMap::new()),
+    #[tokio::test]
+    async fn batches_filter_on_level_and_custom_fields_of_structured_lines() {
+        let state = test_state().await;
+        let key = generate_keypair();
+        let plain = signed_batch(&key, 1, [0u8; 32], "level=ERROR service=api");
+        let mut api = signed_batch(
+            &key,
+            2,
+            plain.compute_hash(),
+            r#"{"level":"info","service":"api","status":200}"#,
+        );
+        api.logs
+            .push(r#"{"level":"ERROR","service":"api","status":500,"retry":false}"#.into());
+        api.sign(&key);
+        let mut db = signed_batch(
+            &key,
+            3,
+            api.compute_hash(),
+            r#"{"level":"error","service":"db"}"#,
+        );
+        db.logs.push("not json at all".into());
+        db.sign(&key);
+        for batch in [&plain, &api, &db] {
+            assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
+        }
+        async fn get(state: &AppState, uri: &str) -> (StatusCode, Vec<(u64, serde_json::Value)>) {
+            let resp = route(state, "GET", uri, None, Vec::new(), 1).await;
+            let status = resp.status();
+            if status != StatusCode::OK {
+                return (status, Vec::new());
+            }
+            let rows: Vec<serde_json::Value> =
+                serde_json::from_str(&body_text(resp).await).unwrap();
+            let found = rows
+                .iter()
+                .map(|row| {
+                    (
+                        row["batch"]["seq"].as_u64().unwrap(),
+                        row["matches"].clone(),
+                    )
+                })
+                .collect();
+            (status, found)
         }
+        let json = |v| serde_json::json!(v);
+
+        // Plain text that merely reads like fields never matches.
+        let (_, found) = get(&state, "/batches?level=error").await;
+        assert_eq!(
+            found,
+            [(2, json(["agent-test/2/1"])), (3, json(["agent-test/3/0"]))]
+        );
+        let (_, found) = get(&state, "/batches?level=error&field.service=api").await;
+        assert_eq!(found, [(2, json(["agent-test/2/1"]))]);
+        // Every filter must hold on one line, not across the batch.
+        let (_, found) = get(&state, "/batches?level=info&field.status=500").await;
+        assert!(found.is_empty(), "{found:?}");
+        let (_, found) = get(&state, "/batches?field.status=500&field.retry=false").await;
+        assert_eq!(found, [(2, json(["agent-test/2/1"]))]);
+        let (_, found) = get(&state, "/batches?field.service=db&log_substring=ERROR").await;
+        assert_eq!(found, [(3, json(["agent-test/3/0"]))]);
+        // Paging counts matching batches only.
+        let (_, found) = get(&state, "/batches?level=ERROR&limit=1&offset=1").await;
+        assert_eq!(found, [(3, json(["agent-test/3/0"]))]);
+        let resp = route(
+            &state,
+            "GET",
+            "/batches/meta?field.service=api",
+            None,
+            Vec::new(),
+            1,
+        )
+        .await;
+        let meta: Vec<serde_json::Value> = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(
+            meta.iter()
+                .map(|m| m["seq"].as_u64().unwrap())
+                .collect::<Vec<_>>(),
+            [2]
+        );
+
+        assert_eq!(
+            get(&state, "/batches?field.=x").await.0,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            get(&state, "/batches?field.a%22b=x").await.0,
+            StatusCode::BAD_REQUEST
+        );
     }
 
-    async fn allow(&self, key: &str) -> bool {
-        let mut guard = self.buckets.lock().await;
-        let now = Instant::now();
-        let entry = guard.entry(key.to_string()).or_insert((now, 0));
+    #[tokio::test]
+    async fn stored_batches_report_the_ack_mode_they_committed_under() {
+        let mut state = file_state("ack-mode").await;
+        let key = generate_keypair();
+        let first = signed_batch(&key, 1, [0u8; 32], "a");
+        let body = |text: String| serde_json::from_str::<serde_json::Value>(&text).unwrap();
+
+        let resp = submit(&state, &first).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(body(body_text(resp).await)["ack"], "durable");
+
+        state.ack_mode = ack::AckMode::Fast;
+        let second = signed_batch(&key, 2, first.compute_hash(), "b");
+        let resp = submit(&state, &second).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(body(body_text(resp).await)["ack"], "fast");
+        // A resend stored nothing, so it promises nothing new.
+        let resp = submit(&state, &second).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(body(body_text(resp).await).get("ack").is_none());
+        assert_eq!(list(&state, ListParams::default()).await.len(), 2);
+    }
 
-        if now.duration_since(entry.0) > self.window {
-            *entry = (now, 0);
-        }
+    #[tokio::test]
+    async fn batches_are_stamped_in_merkle_trees_without_waiting_on_the_tsa() {
+        use common::tsa::{TsaStamp, TstInfo, request_fields, tst_info_der, unsigned_response};
+
+        // A TSA that grants everything, until told to refuse.
+        let refuse = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let tsa_app = Router::new().route(
+            "/",
+            post({
+                let refuse = refuse.clone();
+                move |body: Bytes| async move {
+                    if refuse.load(std::sync::atomic::Ordering::SeqCst) {
+                        return Err(StatusCode::SERVICE_UNAVAILABLE);
+                    }
+                    let (imprint, nonce) = request_fields(&body).unwrap();
+                    Ok(unsigned_response(&tst_info_der(
+                        &imprint,
+                        nonce,
+                        "20261017120000Z",
+                    )))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, tsa_app).await.unwrap() });
+        let config = tsa::TsaConfig {
+            url: format!("http://{addr}/"),
+            interval: StdDuration::from_secs(60),
+            max_batches: 2,
+            timeout: StdDuration::from_secs(5),
+        };
+        let client = reqwest::Client::new();
 
-        if entry.1 >= self.max {
-            return false;
+        let state = test_state().await;
+        let chain = simulated_chain(65, 4);
+        for batch in &chain[..3] {
+            assert_eq!(submit(&state, batch).await.status(), StatusCode::CREATED);
         }
+        let hashes = common::testutil::chain_hashes(&chain);
+        let resp = route(&state, "GET", "/batches/1/tsa", None, Vec::new(), 1).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // Two per token: batches 1-2 share one, batch 3 gets the next.
+        assert_eq!(
+            tsa::stamp_pending(&state.pool, &client, &config).await,
+            Ok(2)
+        );
+        assert_eq!(
+            tsa::stamp_pending(&state.pool, &client, &config).await,
+            Ok(1)
+        );
+        assert_eq!(
+            tsa::stamp_pending(&state.pool, &client, &config).await,
+            Ok(0)
+        );
+        let resp = route(&state, "GET", "/batches/2/tsa", None, Vec::new(), 1).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let stamp: TsaStamp = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(
+            (stamp.token_id, stamp.leaf_index, stamp.leaf_count),
+            (1, 1, 2)
+        );
+        assert_eq!(stamp.gen_time, "2026-10-17T12:00:00Z");
+        let imprint = stamp.check_inclusion(&hashes[1]).unwrap();
+        let token = common::hex::hex_decode(&stamp.token).unwrap();
+        assert_eq!(TstInfo::from_token(&token).unwrap().imprint, imprint);
+        let third = tsa::stamp_of(&state.pool, 3).await.unwrap().unwrap();
+        assert_eq!((third.token_id, third.leaf_count), (2, 1));
+        assert!(third.check_inclusion(&hashes[2]).is_ok());
+
+        // A failing TSA leaves submits alone and the batch for next time.
+        refuse.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(
+            submit(&state, &chain[3]).await.status(),
+            StatusCode::CREATED
+        );
+        let err = tsa::stamp_pending(&state.pool, &client, &config)
+            .await
+            .unwrap_err();
+        assert!(err.contains("503"), "{err}");
+        refuse.store(false, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(
+            tsa::stamp_pending(&state.pool, &client, &config).await,
+            Ok(1)
+        );
 
-        entry.1 += 1;
-        true
+        // Stamps are kept.
+        assert!(
+            sqlx::query("DELETE FROM tsa_leaves")
+                .execute(&state.pool)
+                .await
+                .is_err()
+        );
     }
 }
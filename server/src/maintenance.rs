@@ -0,0 +1,103 @@
+//! Maintenance mode: while it is on, everything that writes agents or
+//! batches (`/submit`, `/ingest/*`, the gRPC `Submit`, registration and
+//! rotation) answers 503 `maintenance` with a `Retry-After`, and reads keep
+//! serving. It starts from `MAINTENANCE=1` and is toggled at runtime with
+//! `POST /admin/maintenance`; the state shows on `/readyz` and as
+//! `logchain_maintenance_mode` on `/metrics`.
+//!
+//! Agents treat the 503 like a rate limit: they hold the batch, wait out
+//! `Retry-After` (`MAINTENANCE_RETRY_AFTER_SECS`, default 30) and try again
+//! without spending a retry.
+
+use crate::{AppState, submit_error};
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const DEFAULT_RETRY_AFTER_SECS: u64 = 30;
+
+pub const MESSAGE: &str = "server is in maintenance mode; retry later";
+
+#[derive(Debug)]
+pub struct Maintenance {
+    on: AtomicBool,
+    retry_after_secs: u64,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self::new(false, DEFAULT_RETRY_AFTER_SECS)
+    }
+}
+
+impl Maintenance {
+    pub fn new(on: bool, retry_after_secs: u64) -> Self {
+        Self {
+            on: AtomicBool::new(on),
+            retry_after_secs,
+        }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        let on = env::var("MAINTENANCE")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        let retry_after_secs = match env::var("MAINTENANCE_RETRY_AFTER_SECS") {
+            Ok(value) => value.parse().map_err(|_| {
+                format!("MAINTENANCE_RETRY_AFTER_SECS must be a number of seconds, got '{value}'")
+            })?,
+            Err(_) => DEFAULT_RETRY_AFTER_SECS,
+        };
+        Ok(Self::new(on, retry_after_secs))
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on.load(Ordering::Relaxed)
+    }
+
+    /// Turns the mode on or off; returns whether it was on.
+    pub fn set(&self, on: bool) -> bool {
+        self.on.swap(on, Ordering::Relaxed)
+    }
+
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after_secs
+    }
+}
+
+/// Refuses every request to the routes it wraps while maintenance is on.
+/// Submits get the usual submit body and count as rejected `maintenance`.
+pub async fn middleware(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if !state.maintenance.is_on() {
+        return next.run(request).await;
+    }
+    let mut response = if request.uri().path() == "/submit" {
+        submit_error(
+            &state,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "maintenance",
+            MESSAGE,
+        )
+        .into_response()
+    } else {
+        let body = serde_json::json!({ "status": "error", "message": MESSAGE });
+        (StatusCode::SERVICE_UNAVAILABLE, axum::Json(body)).into_response()
+    };
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::from(state.maintenance.retry_after_secs()),
+    );
+    response
+}
+
+pub fn render_metrics(state: &AppState) -> String {
+    format!(
+        "logchain_maintenance_mode {}\n",
+        u8::from(state.maintenance.is_on())
+    )
+}
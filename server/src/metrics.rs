@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Minimal in-process metrics registry rendered in Prometheus text format.
+///
+/// Series are keyed by their full name including labels, e.g.
+/// `logchain_submit_rejected_total{reason="seq_conflict"}`.
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn inc(&self, series: &str) {
+        self.add(series, 1);
+    }
+
+    pub fn add(&self, series: &str, value: u64) {
+        let mut guard = self.counters.lock().unwrap();
+        *guard.entry(series.to_string()).or_insert(0) += value;
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, series: &str) -> u64 {
+        let guard = self.counters.lock().unwrap();
+        guard.get(series).copied().unwrap_or(0)
+    }
+
+    /// Renders every series, one per line, sorted by name.
+    pub fn render(&self) -> String {
+        let guard = self.counters.lock().unwrap();
+        let mut out = String::new();
+        for (series, value) in guard.iter() {
+            out.push_str(&format!("{} {}\n", series, value));
+        }
+        out
+    }
+}
+
+/// Formats `name{label="value"}`, escaping the value per the exposition format.
+pub fn labeled(name: &str, label: &str, value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("{}{{{}=\"{}\"}}", name, label, escaped)
+}
@@ -0,0 +1,1081 @@
+//! Per-route rate limits: a small registry of named limiters, one per route
+//! group, each with its own budget and key. A single router middleware
+//! applies them, so handlers never check limits themselves; the gRPC service
+//! calls [`RateLimits::admit`] with the same groups.
+//!
+//! Configured with `RATE_LIMITS`, e.g.
+//! `read:max=600:window_secs=60:key=token,admin:max=5:window_secs=3600`.
+//! A group left out keeps its default and `<group>:off` removes its limit.
+//! `algo=token_bucket` (with an optional `burst=N`) swaps a group's fixed
+//! window for a bucket refilled at `max` per `window_secs`.
+//!
+//! Buckets live in memory. With `RATE_LIMIT_PERSIST_SECS` they are also
+//! saved to `rate_limit_buckets` that often and reloaded at startup, so a
+//! restart does not hand every client a fresh allowance. Downtime counts
+//! against the saved windows: a window that ran out while the server was
+//! down is not restored, and a token bucket refills for the time it missed.
+//!
+//! The denylist (`rate_limit_denylist`, kept through `/admin/ratelimit/deny`)
+//! refuses an agent id, an IP or a CIDR with 403 whatever its budget, on
+//! every route but `/admin/ratelimit*`, so an operator cannot lock
+//! themselves out of undoing an entry.
+
+use crate::{AppState, Metrics, labeled, now_unix_ms, submit_error};
+use axum::{
+    Json,
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use common::hex::hex_encode;
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Bodies buffered to find an `agent_id`; axum's default request body limit,
+/// so nothing a handler would accept is refused here.
+const PEEK_LIMIT_BYTES: usize = 2 * 1024 * 1024;
+
+pub const DENIED_MESSAGE: &str = "refused by the server's denylist";
+
+/// How a limiter spends its budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateAlgo {
+    /// `max` requests per window, counted from a key's first request. Up to
+    /// `2 * max` can pass in quick succession across a window boundary.
+    FixedWindow,
+    /// Holds up to `burst` tokens, refilled continuously at `max` per window;
+    /// each request takes one.
+    TokenBucket { burst: u32 },
+}
+
+impl RateAlgo {
+    /// `fixed_window` or `token_bucket`; a token bucket holds `max` tokens
+    /// until a burst is set.
+    pub fn parse(value: &str, max: u32) -> Result<Self, String> {
+        match value {
+            "fixed_window" => Ok(RateAlgo::FixedWindow),
+            "token_bucket" => Ok(RateAlgo::TokenBucket { burst: max }),
+            other => Err(format!(
+                "unknown rate limit algo '{other}'; use fixed_window or token_bucket"
+            )),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            RateAlgo::FixedWindow => "fixed_window",
+            RateAlgo::TokenBucket { .. } => "token_bucket",
+        }
+    }
+}
+
+pub struct RateLimiter {
+    max: u32,
+    window: Duration,
+    algo: RateAlgo,
+    /// Per key: the window start and requests counted, or for a token bucket
+    /// the last refill and tokens left.
+    buckets: Mutex<HashMap<String, (Instant, f64)>>,
+}
+
+impl RateLimiter {
+    pub fn new(max: u32, window: Duration) -> Self {
+        Self::with_algo(max, window, RateAlgo::FixedWindow)
+    }
+
+    pub fn with_algo(max: u32, window: Duration, algo: RateAlgo) -> Self {
+        Self {
+            max,
+            window,
+            algo,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn allow(&self, key: &str) -> bool {
+        self.allow_at(key, Instant::now()).await
+    }
+
+    async fn allow_at(&self, key: &str, now: Instant) -> bool {
+        let mut guard = self.buckets.lock().await;
+        match self.algo {
+            RateAlgo::FixedWindow => {
+                let entry = guard.entry(key.to_string()).or_insert((now, 0.0));
+                if now.duration_since(entry.0) > self.window {
+                    *entry = (now, 0.0);
+                }
+                if entry.1 >= f64::from(self.max) {
+                    return false;
+                }
+                entry.1 += 1.0;
+                true
+            }
+            RateAlgo::TokenBucket { burst } => {
+                let entry = guard
+                    .entry(key.to_string())
+                    .or_insert((now, f64::from(burst)));
+                *entry = (now, self.refilled(*entry, burst, now));
+                if entry.1 < 1.0 {
+                    return false;
+                }
+                entry.1 -= 1.0;
+                true
+            }
+        }
+    }
+
+    /// Tokens in a bucket last refilled at `since` with `tokens` left.
+    fn refilled(&self, (since, tokens): (Instant, f64), burst: u32, now: Instant) -> f64 {
+        let per_sec = f64::from(self.max) / self.window.as_secs_f64();
+        (tokens + now.duration_since(since).as_secs_f64() * per_sec).min(f64::from(burst))
+    }
+
+    /// Reports whether `key` has used up its budget without consuming a slot.
+    pub async fn is_exhausted(&self, key: &str) -> bool {
+        let guard = self.buckets.lock().await;
+        let Some(&entry) = guard.get(key) else {
+            return false;
+        };
+        let now = Instant::now();
+        match self.algo {
+            RateAlgo::FixedWindow => {
+                now.duration_since(entry.0) <= self.window && entry.1 >= f64::from(self.max)
+            }
+            RateAlgo::TokenBucket { burst } => self.refilled(entry, burst, now) < 1.0,
+        }
+    }
+
+    /// The buckets still holding anything back: per key, how long ago its
+    /// window started (or its bucket was refilled) and the count (or tokens
+    /// left). A lapsed window or a full bucket is as good as none.
+    async fn snapshot(&self, now: Instant) -> Vec<(String, Duration, f64)> {
+        let guard = self.buckets.lock().await;
+        guard
+            .iter()
+            .filter(|&(_, &entry)| match self.algo {
+                RateAlgo::FixedWindow => now.duration_since(entry.0) <= self.window,
+                RateAlgo::TokenBucket { burst } => {
+                    self.refilled(entry, burst, now) < f64::from(burst)
+                }
+            })
+            .map(|(key, &(since, count))| (key.clone(), now.duration_since(since), count))
+            .collect()
+    }
+
+    /// Puts back buckets from [`Self::snapshot`], their ages including the
+    /// downtime; returns how many still counted.
+    async fn restore(&self, entries: Vec<(String, Duration, f64)>, now: Instant) -> usize {
+        let mut guard = self.buckets.lock().await;
+        let mut restored = 0;
+        for (key, age, count) in entries {
+            let entry = match self.algo {
+                RateAlgo::FixedWindow if age > self.window => continue,
+                // Near boot the clock cannot go back that far; the window
+                // then starts now, which only errs on the strict side.
+                RateAlgo::FixedWindow => (now.checked_sub(age).unwrap_or(now), count),
+                RateAlgo::TokenBucket { burst } => {
+                    let tokens = self.refilled((now, count), burst, now + age);
+                    if tokens >= f64::from(burst) {
+                        continue;
+                    }
+                    (now, tokens)
+                }
+            };
+            guard.insert(key, entry);
+            restored += 1;
+        }
+        restored
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LimitGroup {
+    /// `/submit`, `/ingest/*` and gRPC `Submit`.
+    Submit,
+    /// `/agents/register`, `/agents/rotate` and `/admin/*`.
+    Admin,
+    /// Everything else: the `/batches` and `/agents` reads, `/metrics`, gRPC
+    /// `Checkpoints`.
+    Read,
+}
+
+impl LimitGroup {
+    pub const ALL: [LimitGroup; 3] = [LimitGroup::Submit, LimitGroup::Admin, LimitGroup::Read];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LimitGroup::Submit => "submit",
+            LimitGroup::Admin => "admin",
+            LimitGroup::Read => "read",
+        }
+    }
+
+    pub fn for_path(path: &str) -> Self {
+        if path == "/submit" || path.starts_with("/ingest/") {
+            LimitGroup::Submit
+        } else if path == "/agents/register"
+            || path == "/agents/register/bulk"
+            || path == "/agents/rotate"
+            || path.starts_with("/admin/")
+        {
+            LimitGroup::Admin
+        } else {
+            LimitGroup::Read
+        }
+    }
+
+    fn parse(name: &str) -> Result<Self, String> {
+        Self::ALL
+            .into_iter()
+            .find(|group| group.as_str() == name)
+            .ok_or_else(|| format!("unknown rate limit group '{name}'"))
+    }
+}
+
+/// What a limiter counts requests by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKey {
+    /// The `agent_id` of the JSON body, or the client IP without one.
+    Agent,
+    /// The bearer token, or the client IP without one.
+    Token,
+    Ip,
+}
+
+impl LimitKey {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "agent" => Ok(LimitKey::Agent),
+            "token" => Ok(LimitKey::Token),
+            "ip" => Ok(LimitKey::Ip),
+            other => Err(format!(
+                "unknown rate limit key '{other}'; use agent, token or ip"
+            )),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            LimitKey::Agent => "agent",
+            LimitKey::Token => "token",
+            LimitKey::Ip => "ip",
+        }
+    }
+}
+
+/// Who a request comes from, as far as the limiters care.
+pub struct Caller<'a> {
+    pub ip: IpAddr,
+    pub token: Option<&'a str>,
+    pub agent_id: Option<&'a str>,
+}
+
+impl Caller<'_> {
+    fn key(&self, key: LimitKey) -> String {
+        match (key, self.agent_id, self.token) {
+            (LimitKey::Agent, Some(agent_id), _) => format!("agent:{agent_id}"),
+            // Hashed so the bucket map never holds a usable token.
+            (LimitKey::Token, _, Some(token)) => {
+                let hash = hex_encode(&crate::auth::token_hash(token)[..16]);
+                format!("token:{hash}")
+            }
+            _ => format!("ip:{}", self.ip),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouteLimit {
+    pub max: u32,
+    pub window_secs: u64,
+    pub key: LimitKey,
+    pub algo: RateAlgo,
+}
+
+impl fmt::Display for RouteLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} per {}s per {}",
+            self.max,
+            self.window_secs,
+            self.key.as_str()
+        )?;
+        if let RateAlgo::TokenBucket { burst } = self.algo {
+            write!(f, ", token bucket of {burst}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `group:max=N:window_secs=S:key=K:algo=A:burst=B` (settings
+/// optional, starting from `current`) or `group:off`.
+fn parse_spec(
+    spec: &str,
+    current: impl Fn(LimitGroup) -> Option<RouteLimit>,
+) -> Result<(LimitGroup, Option<RouteLimit>), String> {
+    let mut parts = spec.trim().split(':');
+    let group = LimitGroup::parse(parts.next().unwrap_or_default())?;
+    let mut limit = current(group).unwrap_or(RouteLimit {
+        max: 0,
+        window_secs: 60,
+        key: LimitKey::Ip,
+        algo: RateAlgo::FixedWindow,
+    });
+    let mut max_set = limit.max > 0;
+    let mut algo = None;
+    let mut burst_set = false;
+    let mut burst = match limit.algo {
+        RateAlgo::TokenBucket { burst } => Some(burst),
+        RateAlgo::FixedWindow => None,
+    };
+    for part in parts {
+        if part == "off" {
+            return Ok((group, None));
+        }
+        let (key, value) = part
+            .split_once('=')
+            .ok_or_else(|| format!("expected key=value in rate limit, got '{part}'"))?;
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("invalid number '{value}' for {key}"))
+        };
+        match key {
+            "max" => {
+                limit.max =
+                    u32::try_from(number()?).map_err(|_| format!("max {value} is too large"))?;
+                max_set = true;
+            }
+            "window_secs" => limit.window_secs = number()?.max(1),
+            "key" => limit.key = LimitKey::parse(value)?,
+            "algo" => algo = Some(value),
+            "burst" => {
+                burst = Some(
+                    u32::try_from(number()?).map_err(|_| format!("burst {value} is too large"))?,
+                );
+                burst_set = true;
+            }
+            other => return Err(format!("unknown rate limit setting '{other}'")),
+        }
+    }
+    if !max_set {
+        return Err(format!("rate limit for {} sets no max", group.as_str()));
+    }
+    if limit.max == 0 {
+        return Err(format!(
+            "max=0 would refuse every request; use {}:off",
+            group.as_str()
+        ));
+    }
+    // A burst alone implies the token bucket.
+    match (algo, burst_set) {
+        (Some(algo), _) => limit.algo = RateAlgo::parse(algo, limit.max)?,
+        (None, true) => limit.algo = RateAlgo::TokenBucket { burst: limit.max },
+        (None, false) => {}
+    }
+    match &mut limit.algo {
+        RateAlgo::FixedWindow if burst_set => {
+            return Err("burst only applies to algo=token_bucket".into());
+        }
+        RateAlgo::FixedWindow => {}
+        RateAlgo::TokenBucket { burst: capacity } => {
+            *capacity = burst.unwrap_or(limit.max);
+            if *capacity == 0 {
+                return Err(format!(
+                    "burst=0 would refuse every request; use {}:off",
+                    group.as_str()
+                ));
+            }
+        }
+    }
+    Ok((group, Some(limit)))
+}
+
+/// A denylist entry: `agent:<id>`, an IP, or a CIDR such as `10.0.0.0/8`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Deny {
+    Agent(String),
+    /// The network address, its host bits cleared, and the prefix length.
+    Net(IpAddr, u8),
+}
+
+impl Deny {
+    pub fn parse(entry: &str) -> Result<Self, String> {
+        let entry = entry.trim();
+        if let Some(agent_id) = entry.strip_prefix("agent:") {
+            if agent_id.is_empty() {
+                return Err("agent: needs an agent id".into());
+            }
+            return Ok(Deny::Agent(agent_id.to_string()));
+        }
+        let (addr, prefix) = match entry.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (entry, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("'{entry}' is not agent:<id>, an IP address or a CIDR"))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|&prefix| prefix <= bits)
+                .ok_or_else(|| format!("invalid prefix length in '{entry}'"))?,
+            None => bits,
+        };
+        Ok(Deny::Net(network(addr, prefix), prefix))
+    }
+
+    fn matches(&self, caller: &Caller<'_>) -> bool {
+        match self {
+            Deny::Agent(agent_id) => caller.agent_id == Some(agent_id.as_str()),
+            Deny::Net(addr, prefix) => {
+                let ip = caller.ip.to_canonical();
+                ip.is_ipv4() == addr.is_ipv4() && network(ip, *prefix) == *addr
+            }
+        }
+    }
+}
+
+impl fmt::Display for Deny {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Deny::Agent(agent_id) => write!(f, "agent:{agent_id}"),
+            Deny::Net(addr, 32 | 128) => write!(f, "{addr}"),
+            Deny::Net(addr, prefix) => write!(f, "{addr}/{prefix}"),
+        }
+    }
+}
+
+/// `addr` with all but its first `prefix` bits cleared.
+fn network(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DenyEntry {
+    pub entry: String,
+    #[serde(skip)]
+    deny: Deny,
+    pub reason: Option<String>,
+    pub created_at_ms: i64,
+}
+
+pub struct RateLimits {
+    limiters: HashMap<LimitGroup, (RouteLimit, RateLimiter)>,
+    denylist: RwLock<Vec<DenyEntry>>,
+    /// `RATE_LIMIT_PERSIST_SECS`; 0 keeps buckets in memory only.
+    persist_secs: u64,
+    /// When [`save`] last succeeded; 0 before it has.
+    saved_at_ms: AtomicI64,
+    /// Buckets [`restore`] put back at startup.
+    restored: AtomicUsize,
+}
+
+impl RateLimits {
+    pub fn new(limits: impl IntoIterator<Item = (LimitGroup, RouteLimit)>) -> Self {
+        let limiters = limits
+            .into_iter()
+            .map(|(group, limit)| {
+                let window = Duration::from_secs(limit.window_secs);
+                let limiter = RateLimiter::with_algo(limit.max, window, limit.algo);
+                (group, (limit, limiter))
+            })
+            .collect();
+        Self {
+            limiters,
+            denylist: RwLock::default(),
+            persist_secs: 0,
+            saved_at_ms: AtomicI64::new(0),
+            restored: AtomicUsize::new(0),
+        }
+    }
+
+    /// Saves the buckets every `secs`; see [`persist`].
+    pub fn persist_every(&mut self, secs: u64) {
+        self.persist_secs = secs;
+    }
+
+    pub fn persist_secs(&self) -> u64 {
+        self.persist_secs
+    }
+
+    /// `defaults` overridden by the comma-separated specs of `RATE_LIMITS`.
+    pub fn configure(
+        defaults: impl IntoIterator<Item = (LimitGroup, RouteLimit)>,
+        specs: &str,
+    ) -> Result<Self, String> {
+        let mut limits: HashMap<LimitGroup, RouteLimit> = defaults.into_iter().collect();
+        for spec in specs.split(',').filter(|spec| !spec.trim().is_empty()) {
+            let (group, limit) = parse_spec(spec, |group| limits.get(&group).copied())?;
+            match limit {
+                Some(limit) => limits.insert(group, limit),
+                None => limits.remove(&group),
+            };
+        }
+        Ok(Self::new(limits))
+    }
+
+    pub fn limit(&self, group: LimitGroup) -> Option<RouteLimit> {
+        self.limiters.get(&group).map(|(limit, _)| *limit)
+    }
+
+    /// Counts one request against `group`; a refusal is counted in
+    /// `logchain_rate_limited_total{limiter=...}`.
+    pub async fn admit(&self, metrics: &Metrics, group: LimitGroup, caller: &Caller<'_>) -> bool {
+        let Some((limit, limiter)) = self.limiters.get(&group) else {
+            return true;
+        };
+        if limiter.allow(&caller.key(limit.key)).await {
+            return true;
+        }
+        metrics.inc(&labeled(
+            "logchain_rate_limited_total",
+            "limiter",
+            group.as_str(),
+        ));
+        false
+    }
+
+    /// Whether the denylist refuses `caller`; a refusal is counted in
+    /// `logchain_denied_total`.
+    pub fn denies(&self, metrics: &Metrics, caller: &Caller<'_>) -> bool {
+        let denied = self
+            .denylist
+            .read()
+            .unwrap()
+            .iter()
+            .any(|entry| entry.deny.matches(caller));
+        if denied {
+            metrics.inc("logchain_denied_total");
+        }
+        denied
+    }
+
+    fn has_denylist(&self) -> bool {
+        !self.denylist.read().unwrap().is_empty()
+    }
+
+    /// Whether any entry names an agent, which takes the request body.
+    fn denies_agents(&self) -> bool {
+        self.denylist
+            .read()
+            .unwrap()
+            .iter()
+            .any(|entry| matches!(entry.deny, Deny::Agent(_)))
+    }
+
+    fn put_denied(&self, entry: DenyEntry) {
+        let mut denylist = self.denylist.write().unwrap();
+        denylist.retain(|existing| existing.deny != entry.deny);
+        denylist.push(entry);
+    }
+}
+
+/// Every limiter whose buckets are kept, by the name they are saved under:
+/// the route groups and the failed-auth limiter.
+fn persisted(state: &AppState) -> Vec<(&'static str, &RateLimiter)> {
+    let mut limiters: Vec<_> = LimitGroup::ALL
+        .into_iter()
+        .filter_map(|group| {
+            let (_, limiter) = state.rate_limits.limiters.get(&group)?;
+            Some((group.as_str(), limiter))
+        })
+        .collect();
+    limiters.push(("auth_failures", &*state.auth_failures));
+    limiters
+}
+
+/// Replaces the saved buckets with the live ones; returns how many.
+pub async fn save(state: &AppState) -> Result<usize, sqlx::Error> {
+    let now = Instant::now();
+    let now_ms = now_unix_ms();
+    let mut rows = Vec::new();
+    for (name, limiter) in persisted(state) {
+        for (key, age, count) in limiter.snapshot(now).await {
+            rows.push((
+                name,
+                limiter.algo.as_str(),
+                key,
+                now_ms - age.as_millis() as i64,
+                count,
+            ));
+        }
+    }
+    let mut tx = state.pool.begin().await?;
+    sqlx::query("DELETE FROM rate_limit_buckets")
+        .execute(&mut *tx)
+        .await?;
+    for (limiter, algo, key, started_at_ms, count) in &rows {
+        sqlx::query(
+            "INSERT INTO rate_limit_buckets (limiter, key, algo, started_at_ms, count) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )
+        .bind(limiter)
+        .bind(key)
+        .bind(algo)
+        .bind(started_at_ms)
+        .bind(count)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    state
+        .rate_limits
+        .saved_at_ms
+        .store(now_ms, Ordering::Relaxed);
+    Ok(rows.len())
+}
+
+/// Loads the saved buckets as they stand at `now_ms`; returns how many
+/// still count. Rows of a limiter since turned off or given another algo
+/// are skipped.
+pub async fn restore(state: &AppState, now_ms: i64) -> Result<usize, sqlx::Error> {
+    let rows =
+        sqlx::query("SELECT limiter, key, algo, started_at_ms, count FROM rate_limit_buckets")
+            .fetch_all(&state.pool)
+            .await?;
+    let now = Instant::now();
+    let mut restored = 0;
+    for (name, limiter) in persisted(state) {
+        let entries = rows
+            .iter()
+            .filter(|row| {
+                row.get::<String, _>("limiter") == name
+                    && row.get::<String, _>("algo") == limiter.algo.as_str()
+            })
+            .map(|row| {
+                let age_ms = now_ms.saturating_sub(row.get("started_at_ms")).max(0);
+                (
+                    row.get("key"),
+                    Duration::from_millis(age_ms as u64),
+                    row.get("count"),
+                )
+            })
+            .collect();
+        restored += limiter.restore(entries, now).await;
+    }
+    state
+        .rate_limits
+        .restored
+        .store(restored, Ordering::Relaxed);
+    Ok(restored)
+}
+
+/// Saves the buckets every `interval`; up to one interval of counting is
+/// lost when the server stops.
+pub async fn persist(state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if let Err(err) = save(&state).await {
+            eprintln!("[rate_limit] saving the limiter state failed: {err}");
+        }
+    }
+}
+
+/// Loads `rate_limit_denylist`; returns how many entries it holds.
+pub async fn load_denylist(state: &AppState) -> Result<usize, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT entry, reason, created_at_ms FROM rate_limit_denylist ORDER BY created_at_ms",
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let mut loaded = 0;
+    for row in rows {
+        let entry: String = row.get("entry");
+        match Deny::parse(&entry) {
+            Ok(deny) => {
+                state.rate_limits.put_denied(DenyEntry {
+                    entry,
+                    deny,
+                    reason: row.get("reason"),
+                    created_at_ms: row.get("created_at_ms"),
+                });
+                loaded += 1;
+            }
+            Err(err) => eprintln!("[rate_limit] skipping denylist entry: {err}"),
+        }
+    }
+    Ok(loaded)
+}
+
+/// Adds `deny` to the denylist, or replaces its reason.
+pub async fn deny(
+    state: &AppState,
+    deny: Deny,
+    reason: Option<String>,
+) -> Result<DenyEntry, sqlx::Error> {
+    let entry = DenyEntry {
+        entry: deny.to_string(),
+        deny,
+        reason,
+        created_at_ms: now_unix_ms(),
+    };
+    sqlx::query(
+        "INSERT OR REPLACE INTO rate_limit_denylist (entry, reason, created_at_ms) VALUES (?1, ?2, ?3)",
+    )
+    .bind(&entry.entry)
+    .bind(&entry.reason)
+    .bind(entry.created_at_ms)
+    .execute(&state.pool)
+    .await?;
+    state.rate_limits.put_denied(entry.clone());
+    Ok(entry)
+}
+
+/// Takes `deny` off the denylist; returns whether it was on it.
+pub async fn undeny(state: &AppState, deny: &Deny) -> Result<bool, sqlx::Error> {
+    let removed = sqlx::query("DELETE FROM rate_limit_denylist WHERE entry = ?1")
+        .bind(deny.to_string())
+        .execute(&state.pool)
+        .await?
+        .rows_affected()
+        > 0;
+    state
+        .rate_limits
+        .denylist
+        .write()
+        .unwrap()
+        .retain(|entry| entry.deny != *deny);
+    Ok(removed)
+}
+
+#[derive(Debug, Serialize)]
+pub struct LimiterStatus {
+    pub name: &'static str,
+    pub limit: String,
+    /// Keys with part of their budget spent.
+    pub active_buckets: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RateLimitStatus {
+    pub limiters: Vec<LimiterStatus>,
+    /// `RATE_LIMIT_PERSIST_SECS`; absent while buckets are memory only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub persist_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub saved_at_ms: Option<i64>,
+    pub restored_at_startup: usize,
+    pub denylist: Vec<DenyEntry>,
+}
+
+/// What `GET /admin/ratelimit` shows.
+pub async fn status(state: &AppState) -> RateLimitStatus {
+    let now = Instant::now();
+    let limits = &state.rate_limits;
+    let mut limiters = Vec::new();
+    for (name, limiter) in persisted(state) {
+        let limit = match limits
+            .limiters
+            .iter()
+            .find(|(group, _)| group.as_str() == name)
+        {
+            Some((_, (limit, _))) => limit.to_string(),
+            None => format!("{} per {}s per ip", limiter.max, limiter.window.as_secs()),
+        };
+        limiters.push(LimiterStatus {
+            name,
+            limit,
+            active_buckets: limiter.snapshot(now).await.len(),
+        });
+    }
+    let saved_at_ms = limits.saved_at_ms.load(Ordering::Relaxed);
+    RateLimitStatus {
+        limiters,
+        persist_secs: (limits.persist_secs > 0).then_some(limits.persist_secs),
+        saved_at_ms: (saved_at_ms > 0).then_some(saved_at_ms),
+        restored_at_startup: limits.restored.load(Ordering::Relaxed),
+        denylist: limits.denylist.read().unwrap().clone(),
+    }
+}
+
+#[derive(Deserialize)]
+struct AgentField {
+    agent_id: String,
+}
+
+/// Applies the denylist, then the limiter of the request's route group.
+/// `/submit` keeps its own rejection body and `submit_rejected_total` reason.
+pub async fn middleware(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let path = request.uri().path().to_string();
+    let group = LimitGroup::for_path(&path);
+    let limit = state.rate_limits.limit(group);
+    let denylisted = state.rate_limits.has_denylist() && !path.starts_with("/admin/ratelimit");
+    if limit.is_none() && !denylisted {
+        return next.run(request).await;
+    }
+
+    let ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |info| info.0.ip());
+    let token = request
+        .headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_string);
+
+    // Only the agent key and agent entries need the body; it is buffered and
+    // handed on as is.
+    let peek = limit.is_some_and(|limit| limit.key == LimitKey::Agent)
+        || (denylisted && state.rate_limits.denies_agents());
+    let (request, agent_id) = if peek {
+        let (parts, body) = request.into_parts();
+        let Ok(bytes) = axum::body::to_bytes(body, PEEK_LIMIT_BYTES).await else {
+            return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+        };
+        let agent_id = serde_json::from_slice::<AgentField>(&bytes)
+            .ok()
+            .map(|field| field.agent_id);
+        (Request::from_parts(parts, Body::from(bytes)), agent_id)
+    } else {
+        (request, None)
+    };
+
+    let caller = Caller {
+        ip,
+        token: token.as_deref(),
+        agent_id: agent_id.as_deref(),
+    };
+    if denylisted && state.rate_limits.denies(&state.metrics, &caller) {
+        if path == "/submit" {
+            return submit_error(&state, StatusCode::FORBIDDEN, "denied", DENIED_MESSAGE)
+                .into_response();
+        }
+        let body = serde_json::json!({ "status": "error", "message": DENIED_MESSAGE });
+        return (StatusCode::FORBIDDEN, Json(body)).into_response();
+    }
+    if state
+        .rate_limits
+        .admit(&state.metrics, group, &caller)
+        .await
+    {
+        return next.run(request).await;
+    }
+    if path == "/submit" {
+        return submit_error(
+            &state,
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            "rate limit exceeded",
+        )
+        .into_response();
+    }
+    let body = serde_json::json!({ "status": "error", "message": "rate limit exceeded" });
+    (StatusCode::TOO_MANY_REQUESTS, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specs_override_defaults_per_group() {
+        let submit = RouteLimit {
+            max: 200,
+            window_secs: 60,
+            key: LimitKey::Agent,
+            algo: RateAlgo::FixedWindow,
+        };
+        let limits = RateLimits::configure(
+            [(LimitGroup::Submit, submit)],
+            "submit:key=ip, read:max=5:window_secs=10:key=token:algo=token_bucket,admin:max=1",
+        )
+        .unwrap();
+        assert_eq!(
+            limits.limit(LimitGroup::Submit),
+            Some(RouteLimit {
+                key: LimitKey::Ip,
+                ..submit
+            })
+        );
+        assert_eq!(
+            limits.limit(LimitGroup::Read).unwrap().to_string(),
+            "5 per 10s per token, token bucket of 5"
+        );
+        assert_eq!(
+            limits.limit(LimitGroup::Admin).unwrap().to_string(),
+            "1 per 60s per ip"
+        );
+
+        let burst =
+            RateLimits::configure([(LimitGroup::Submit, submit)], "submit:burst=20").unwrap();
+        assert_eq!(
+            burst.limit(LimitGroup::Submit).unwrap().algo,
+            RateAlgo::TokenBucket { burst: 20 }
+        );
+
+        let off = RateLimits::configure([(LimitGroup::Submit, submit)], "submit:off").unwrap();
+        assert_eq!(off.limit(LimitGroup::Submit), None);
+
+        for bad in [
+            "batches:max=1",
+            "read:window_secs=5",
+            "read:max=0",
+            "read:max=x",
+            "read:key=user",
+            "read:max=5:algo=leaky",
+            "read:max=5:burst=0",
+            "read:max=5:algo=fixed_window:burst=9",
+        ] {
+            assert!(RateLimits::configure([], bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn keys_fall_back_to_the_client_ip() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let anonymous = Caller {
+            ip,
+            token: None,
+            agent_id: None,
+        };
+        for key in [LimitKey::Agent, LimitKey::Token, LimitKey::Ip] {
+            assert_eq!(anonymous.key(key), "ip:10.0.0.1");
+        }
+        let agent = Caller {
+            agent_id: Some("a1"),
+            token: Some("t"),
+            ..anonymous
+        };
+        assert_eq!(agent.key(LimitKey::Agent), "agent:a1");
+        assert!(agent.key(LimitKey::Token).starts_with("token:"));
+        assert_eq!(LimitGroup::for_path("/ingest/web"), LimitGroup::Submit);
+        assert_eq!(LimitGroup::for_path("/admin/tokens"), LimitGroup::Admin);
+        assert_eq!(LimitGroup::for_path("/agents/stale"), LimitGroup::Read);
+    }
+
+    async fn passed(limiter: &RateLimiter, now: Instant, tries: u32) -> u32 {
+        let mut passed = 0;
+        for _ in 0..tries {
+            passed += u32::from(limiter.allow_at("k", now).await);
+        }
+        passed
+    }
+
+    #[tokio::test]
+    async fn token_bucket_prevents_the_window_boundary_burst() {
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // One request opens the window; the rest of the budget goes just
+        // before it ends and all of it again just after.
+        let fixed = RateLimiter::new(10, window);
+        assert_eq!(passed(&fixed, at(0), 1).await, 1);
+        assert_eq!(passed(&fixed, at(59), 20).await, 9);
+        assert_eq!(passed(&fixed, at(61), 20).await, 10);
+
+        // The same traffic against a bucket of 10 refilled at 10 per 60s:
+        // at most the burst passes around the boundary.
+        let bucket = RateLimiter::with_algo(10, window, RateAlgo::TokenBucket { burst: 10 });
+        assert_eq!(passed(&bucket, at(0), 1).await, 1);
+        assert_eq!(passed(&bucket, at(59), 20).await, 10);
+        assert_eq!(passed(&bucket, at(61), 20).await, 0);
+        // Refill is steady: one token per 6s, never beyond the burst.
+        assert_eq!(passed(&bucket, at(67), 20).await, 1);
+        assert_eq!(passed(&bucket, at(1000), 20).await, 10);
+
+        let small = RateLimiter::with_algo(10, window, RateAlgo::TokenBucket { burst: 2 });
+        assert_eq!(passed(&small, at(0), 20).await, 2);
+        assert!(small.is_exhausted("k").await);
+    }
+
+    #[tokio::test]
+    async fn restored_buckets_count_the_downtime() {
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+        let fixed = RateLimiter::new(10, window);
+        assert_eq!(passed(&fixed, start, 10).await, 10);
+        let bucket = RateLimiter::with_algo(10, window, RateAlgo::TokenBucket { burst: 10 });
+        assert_eq!(passed(&bucket, start, 10).await, 10);
+        // Spent 20s before the snapshot.
+        let saved = start + Duration::from_secs(20);
+        let (fixed_saved, bucket_saved) =
+            (fixed.snapshot(saved).await, bucket.snapshot(saved).await);
+        assert_eq!(fixed_saved[0].1, Duration::from_secs(20));
+
+        // Down for 30s: the window has 10s left, and the bucket has refilled
+        // for 50s at one token per 6s.
+        let down = |entries: &Vec<(String, Duration, f64)>, secs| {
+            entries
+                .iter()
+                .map(|(key, age, count)| (key.clone(), *age + Duration::from_secs(secs), *count))
+                .collect::<Vec<_>>()
+        };
+        let now = Instant::now();
+        let restarted = RateLimiter::new(10, window);
+        assert_eq!(restarted.restore(down(&fixed_saved, 30), now).await, 1);
+        assert_eq!(passed(&restarted, now, 5).await, 0);
+        assert_eq!(
+            passed(&restarted, now + Duration::from_secs(11), 5).await,
+            5
+        );
+        let restarted = RateLimiter::with_algo(10, window, RateAlgo::TokenBucket { burst: 10 });
+        assert_eq!(restarted.restore(down(&bucket_saved, 30), now).await, 1);
+        assert_eq!(passed(&restarted, now, 10).await, 8);
+
+        // Down past the window, or long enough to refill: nothing to restore.
+        assert_eq!(
+            RateLimiter::new(10, window)
+                .restore(down(&fixed_saved, 41), now)
+                .await,
+            0
+        );
+        let full = RateLimiter::with_algo(10, window, RateAlgo::TokenBucket { burst: 10 });
+        assert_eq!(full.restore(down(&bucket_saved, 40), now).await, 0);
+    }
+
+    #[test]
+    fn deny_entries_match_agents_ips_and_networks() {
+        let caller = |ip: &str, agent_id| Caller {
+            ip: ip.parse().unwrap(),
+            token: None,
+            agent_id,
+        };
+        let net = Deny::parse(" 10.1.2.3/16").unwrap();
+        assert_eq!(net.to_string(), "10.1.0.0/16");
+        assert!(net.matches(&caller("10.1.200.9", None)));
+        assert!(net.matches(&caller("::ffff:10.1.0.1", None)));
+        assert!(!net.matches(&caller("10.2.0.1", None)));
+        let ip = Deny::parse("2001:db8::1").unwrap();
+        assert_eq!(ip, Deny::parse("2001:db8::1/128").unwrap());
+        assert!(ip.matches(&caller("2001:db8::1", None)));
+        assert!(!ip.matches(&caller("2001:db8::2", None)));
+        assert!(
+            Deny::parse("0.0.0.0/0")
+                .unwrap()
+                .matches(&caller("192.0.2.1", None))
+        );
+        let agent = Deny::parse("agent:a1").unwrap();
+        assert!(agent.matches(&caller("192.0.2.1", Some("a1"))));
+        assert!(!agent.matches(&caller("192.0.2.1", None)));
+        for bad in ["agent:", "10.0.0.0/33", "example.com", "10.0.0.0/x"] {
+            assert!(Deny::parse(bad).is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Submission receipts: the server's signed acknowledgment of each stored
+//! batch. A receipt is written to `receipts` in the transaction that stores
+//! its batch and served verbatim from then on by `GET /batches/:id/receipt`,
+//! so an agent that lost its copy can fetch it again and an auditor can check
+//! that every stored batch was acknowledged.
+//!
+//! Receipts are signed with the active key of `server_keys`. Rotating it
+//! (`POST /admin/server-keys/rotate`) retires the old key without deleting
+//! it; `GET /server-keys` lists the whole history, so a receipt verifies with
+//! the key that was active when it was issued.
+
+use crate::{AppState, now_unix_ms, serialize_hex};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use common::batch::generate_keypair;
+use common::compat::Capabilities;
+use common::export_header::ExportHeader;
+use common::receipt::Receipt;
+use common::redaction::Redaction;
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use serde::Serialize;
+use sqlx::{Row, SqliteConnection, SqlitePool};
+use tokio::sync::RwLock;
+
+/// One entry of the server key history. The signing half never leaves the
+/// database.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerKey {
+    pub id: i64,
+    #[serde(serialize_with = "serialize_hex")]
+    pub public_key: Vec<u8>,
+    pub created_at_ms: i64,
+    /// `None` for the active key.
+    pub retired_at_ms: Option<i64>,
+}
+
+impl ServerKey {
+    /// Whether this key was the one signing receipts at `at_ms`.
+    pub fn active_at(&self, at_ms: i64) -> bool {
+        self.created_at_ms <= at_ms && self.retired_at_ms.is_none_or(|retired| at_ms <= retired)
+    }
+
+    pub fn verifying_key(&self) -> Option<VerifyingKey> {
+        let bytes = <[u8; 32]>::try_from(self.public_key.as_slice()).ok()?;
+        VerifyingKey::from_bytes(&bytes).ok()
+    }
+}
+
+/// Holds the active server key and signs receipts with it.
+pub struct ReceiptSigner {
+    current: RwLock<(i64, SigningKey)>,
+}
+
+impl ReceiptSigner {
+    /// Loads the active key, creating the first one on a new database.
+    pub async fn load(pool: &SqlitePool) -> Result<Self, sqlx::Error> {
+        let active = sqlx::query(
+            "SELECT id, signing_key FROM server_keys WHERE retired_at_ms IS NULL ORDER BY id DESC LIMIT 1",
+        )
+        .fetch_optional(pool)
+        .await?;
+        let current = match active {
+            Some(row) => {
+                let bytes: Vec<u8> = row.get("signing_key");
+                let bytes = <[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| {
+                    sqlx::Error::Decode("server signing key is not 32 bytes".into())
+                })?;
+                (row.get("id"), SigningKey::from_bytes(&bytes))
+            }
+            None => {
+                let mut conn = pool.acquire().await?;
+                insert_key(&mut conn).await?
+            }
+        };
+        Ok(Self {
+            current: RwLock::new(current),
+        })
+    }
+
+    /// Retires the active key and starts signing with a new one. Receipts
+    /// wait for the swap, so none is stamped after its key's retirement.
+    pub async fn rotate(&self, pool: &SqlitePool) -> Result<ServerKey, sqlx::Error> {
+        let mut current = self.current.write().await;
+        let mut tx = pool.begin().await?;
+        sqlx::query("UPDATE server_keys SET retired_at_ms = ?1 WHERE retired_at_ms IS NULL")
+            .bind(now_unix_ms())
+            .execute(tx.as_mut())
+            .await?;
+        let (id, key) = insert_key(tx.as_mut()).await?;
+        tx.commit().await?;
+        *current = (id, key);
+        drop(current);
+        let keys = server_keys(pool).await?;
+        Ok(keys
+            .into_iter()
+            .find(|key| key.id == id)
+            .expect("key just stored"))
+    }
+
+    /// Signs `redaction` with the active key, as it signs receipts.
+    pub async fn sign_redaction(&self, redaction: &mut Redaction) {
+        let current = self.current.read().await;
+        let (key_id, key) = &*current;
+        redaction.sign(key, *key_id);
+    }
+
+    /// A header for an export starting now, declaring what this build
+    /// stores and signed with the active key; see [`common::export_header`].
+    pub async fn export_header(&self) -> ExportHeader {
+        let caps = Capabilities::current(env!("CARGO_PKG_VERSION"));
+        let current = self.current.read().await;
+        let (key_id, key) = &*current;
+        ExportHeader::issue(
+            key,
+            *key_id,
+            caps.batch_versions,
+            caps.hash_schemes,
+            &caps.server_version,
+            now_unix_ms() as u64,
+        )
+    }
+
+    /// Signs and stores the receipt of batch `batch_id` on `conn`, the
+    /// connection of the transaction storing the batch. A batch that already
+    /// has a receipt is refused by the `receipts_issued_once` trigger.
+    /// `request_id` is kept on the row for tracing; it is not signed.
+    pub async fn issue(
+        &self,
+        conn: &mut SqliteConnection,
+        batch_id: i64,
+        agent_id: &str,
+        position: (u64, u64),
+        hash: [u8; 32],
+        request_id: Option<&str>,
+    ) -> Result<Receipt, sqlx::Error> {
+        let receipt = {
+            let current = self.current.read().await;
+            let (key_id, key) = &*current;
+            Receipt::issue(
+                key,
+                *key_id,
+                batch_id,
+                agent_id,
+                position,
+                hash,
+                now_unix_ms() as u64,
+            )
+        };
+        sqlx::query(
+            "INSERT INTO receipts (batch_id, hash, key_id, signature, issued_at_ms, request_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )
+        .bind(receipt.batch_id)
+        .bind(receipt.hash.to_vec())
+        .bind(receipt.key_id)
+        .bind(receipt.signature.to_bytes().to_vec())
+        .bind(receipt.issued_at_ms as i64)
+        .bind(request_id)
+        .execute(conn)
+        .await?;
+        Ok(receipt)
+    }
+}
+
+async fn insert_key(conn: &mut SqliteConnection) -> Result<(i64, SigningKey), sqlx::Error> {
+    let key = generate_keypair();
+    let id = sqlx::query(
+        "INSERT INTO server_keys (public_key, signing_key, created_at_ms) VALUES (?1, ?2, ?3)",
+    )
+    .bind(key.verifying_key().to_bytes().to_vec())
+    .bind(key.to_bytes().to_vec())
+    .bind(now_unix_ms())
+    .execute(conn)
+    .await?
+    .last_insert_rowid();
+    Ok((id, key))
+}
+
+pub async fn server_keys(pool: &SqlitePool) -> Result<Vec<ServerKey>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT id, public_key, created_at_ms, retired_at_ms FROM server_keys ORDER BY id",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| ServerKey {
+            id: row.get("id"),
+            public_key: row.get("public_key"),
+            created_at_ms: row.get("created_at_ms"),
+            retired_at_ms: row.get("retired_at_ms"),
+        })
+        .collect())
+}
+
+/// The stored receipt of batch `batch_id`, rebuilt from its row exactly as it
+/// was issued.
+pub async fn fetch(
+    conn: &mut SqliteConnection,
+    batch_id: i64,
+) -> Result<Option<Receipt>, sqlx::Error> {
+    let row = sqlx::query(
+        "SELECT r.hash, r.key_id, r.signature, r.issued_at_ms, b.agent_id, b.epoch, b.seq \
+         FROM receipts r JOIN batches b ON b.id = r.batch_id WHERE r.batch_id = ?1",
+    )
+    .bind(batch_id)
+    .fetch_optional(conn)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let hash: Vec<u8> = row.get("hash");
+    let signature: Vec<u8> = row.get("signature");
+    let undecodable = || sqlx::Error::Decode(format!("receipt of batch {batch_id}").into());
+    Ok(Some(Receipt {
+        batch_id,
+        agent_id: row.get("agent_id"),
+        seq: row.get::<i64, _>("seq") as u64,
+        epoch: row.get::<i64, _>("epoch") as u64,
+        hash: hash.try_into().map_err(|_| undecodable())?,
+        issued_at_ms: row.get::<i64, _>("issued_at_ms") as u64,
+        key_id: row.get("key_id"),
+        signature: Signature::from_slice(&signature).map_err(|_| undecodable())?,
+    }))
+}
+
+/* ----------------------- GET /batches/:id/receipt ----------------------- */
+
+/// 404 for an unknown batch and for one stored before receipts existed.
+pub async fn handler_get_receipt(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Receipt>, StatusCode> {
+    let mut conn = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    fetch(&mut conn, id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/* ----------------------- GET /server-keys ----------------------- */
+
+pub async fn handler_server_keys(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ServerKey>>, StatusCode> {
+    server_keys(&state.pool)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
@@ -0,0 +1,252 @@
+//! Redactions (see [`common::redaction`]): `POST /admin/redactions` removes
+//! the content of one stored line. The line is replaced by its marker in the
+//! row's `logs` and gzip copy, the raw request body (which holds the line
+//! too) is dropped, and the signed record goes to the append-only
+//! `redactions` table, whose row is what lets the `batches_no_update`
+//! trigger accept the rewrite.
+//!
+//! Rows whose gzip copy was tiered are refused: their blob is kept, content
+//! addressed, and would still hold the line. Snapshots and exports made
+//! before the redaction hold it as well.
+
+use crate::{
+    AppState, BATCH_READ_COLUMNS, canonical_logs_json, compress_json, now_unix_ms, position_label,
+    row_to_query_batch,
+};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use common::batch::BATCH_VERSION_V3;
+use common::redaction::{Redaction, marker};
+use ed25519_dalek::Signature;
+use sqlx::{Row, SqliteConnection, sqlite::SqliteRow};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum RedactError {
+    BatchNotFound,
+    OutOfRange {
+        line_count: usize,
+    },
+    AlreadyRedacted,
+    /// Before v3 a batch hashes its lines together.
+    NotRedactable(u32),
+    Tiered,
+    /// The stored row no longer matches its hash, so it cannot be vouched
+    /// for after the line is gone.
+    Unverifiable,
+    Internal(String),
+}
+
+impl RedactError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RedactError::BatchNotFound | RedactError::OutOfRange { .. } => StatusCode::NOT_FOUND,
+            RedactError::AlreadyRedacted
+            | RedactError::NotRedactable(_)
+            | RedactError::Tiered
+            | RedactError::Unverifiable => StatusCode::CONFLICT,
+            RedactError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for RedactError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RedactError::BatchNotFound => f.write_str("no such batch"),
+            RedactError::OutOfRange { line_count } => {
+                write!(f, "line index past the batch's {line_count} lines")
+            }
+            RedactError::AlreadyRedacted => f.write_str("line already redacted"),
+            RedactError::NotRedactable(version) => write!(
+                f,
+                "a v{version} batch hashes its lines together; only v3 batches can have a line redacted"
+            ),
+            RedactError::Tiered => f.write_str(
+                "the batch's gzip copy was tiered to the blob store, which keeps it; not redacted",
+            ),
+            RedactError::Unverifiable => {
+                f.write_str("stored batch does not match its hash; run the integrity check")
+            }
+            RedactError::Internal(err) => f.write_str(err),
+        }
+    }
+}
+
+impl From<sqlx::Error> for RedactError {
+    fn from(err: sqlx::Error) -> Self {
+        RedactError::Internal(err.to_string())
+    }
+}
+
+/// The lines of a row read with [`BATCH_READ_COLUMNS`] that hold a marker,
+/// in order.
+pub fn stored_redacted(row: &SqliteRow) -> Vec<usize> {
+    let list: Option<String> = row.try_get("redacted_lines").ok().flatten();
+    let mut lines: Vec<usize> = list
+        .iter()
+        .flat_map(|list| list.split(','))
+        .filter_map(|idx| idx.parse().ok())
+        .collect();
+    lines.sort_unstable();
+    lines
+}
+
+/// Redacts line `line_idx` of batch `batch_id`, recording `authorized_by`.
+pub async fn redact(
+    state: &AppState,
+    batch_id: i64,
+    line_idx: usize,
+    authorized_by: &str,
+) -> Result<Redaction, RedactError> {
+    let mut tx = state.pool.begin().await?;
+    let row = sqlx::query(&format!(
+        "SELECT {BATCH_READ_COLUMNS} FROM batches WHERE id = ?1"
+    ))
+    .bind(batch_id)
+    .fetch_optional(tx.as_mut())
+    .await?
+    .ok_or(RedactError::BatchNotFound)?;
+    if row.try_get::<Option<String>, _>("blob_location")?.is_some() {
+        return Err(RedactError::Tiered);
+    }
+    let stored = row_to_query_batch(&state.decompressor, row)
+        .map_err(|_| RedactError::Internal("row does not decode into a batch".into()))?;
+    let mut batch = stored.batch;
+    if batch.version < BATCH_VERSION_V3 {
+        return Err(RedactError::NotRedactable(batch.version));
+    }
+    let Some(line) = batch.logs.get(line_idx) else {
+        return Err(RedactError::OutOfRange {
+            line_count: batch.logs.len(),
+        });
+    };
+    if stored.redacted.contains(&line_idx) {
+        return Err(RedactError::AlreadyRedacted);
+    }
+
+    let commitment = batch.line_leaf(line_idx, line);
+    batch.logs[line_idx] = marker(&commitment);
+    let mut redacted = stored.redacted;
+    redacted.push(line_idx);
+    if batch.compute_hash_redacted(&redacted) != Ok(stored.hash) {
+        return Err(RedactError::Unverifiable);
+    }
+
+    let mut redaction = Redaction {
+        batch_id,
+        agent_id: batch.agent_id.clone(),
+        epoch: batch.epoch,
+        seq: batch.seq,
+        line_idx,
+        commitment,
+        authorized_by: authorized_by.to_string(),
+        redacted_at_ms: now_unix_ms() as u64,
+        key_id: 0,
+        signature: Signature::from_bytes(&[0u8; 64]),
+    };
+    state.receipts.sign_redaction(&mut redaction).await;
+    sqlx::query(
+        "INSERT INTO redactions (batch_id, line_idx, commitment, authorized_by, redacted_at_ms, key_id, signature) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )
+    .bind(batch_id)
+    .bind(line_idx as i64)
+    .bind(commitment.to_vec())
+    .bind(authorized_by)
+    .bind(redaction.redacted_at_ms as i64)
+    .bind(redaction.key_id)
+    .bind(redaction.signature.to_bytes().to_vec())
+    .execute(tx.as_mut())
+    .await?;
+
+    let logs_json = canonical_logs_json(&batch.logs);
+    let logs_compressed = compress_json(
+        &logs_json,
+        state.compression_level,
+        state.compression_min_bytes,
+        state.compression_dict.get().as_deref(),
+    )
+    .map_err(RedactError::Internal)?;
+    sqlx::query(
+        "UPDATE batches SET logs = ?1, logs_compressed = ?2, logs_size = ?3, logs_compressed_size = ?4, \
+         raw_body = NULL, raw_content_type = NULL WHERE id = ?5",
+    )
+    .bind(&logs_json)
+    .bind(&logs_compressed)
+    .bind(logs_json.len() as i64)
+    .bind(logs_compressed.as_ref().map(|blob| blob.len() as i64))
+    .bind(batch_id)
+    .execute(tx.as_mut())
+    .await?;
+    tx.commit().await?;
+
+    state.metrics.inc("logchain_redactions_total");
+    println!(
+        "[redaction] line {line_idx} of batch {batch_id} ({} {}) redacted by {authorized_by}",
+        batch.agent_id,
+        position_label(batch.position())
+    );
+    Ok(redaction)
+}
+
+/// `GET /batches/:id/redactions`: the batch's redactions by line, each as
+/// signed. Empty for a batch with none, 404 for an unknown id.
+pub async fn handler_redactions(
+    State(state): State<AppState>,
+    Path(batch_id): Path<i64>,
+) -> Result<Json<Vec<Redaction>>, StatusCode> {
+    let mut conn = state
+        .pool
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let exists = sqlx::query("SELECT 1 FROM batches WHERE id = ?1")
+        .bind(batch_id)
+        .fetch_optional(conn.as_mut())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if exists.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    redactions(&mut conn, batch_id)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn redactions(
+    conn: &mut SqliteConnection,
+    batch_id: i64,
+) -> Result<Vec<Redaction>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT r.line_idx, r.commitment, r.authorized_by, r.redacted_at_ms, r.key_id, r.signature, \
+         b.agent_id, b.epoch, b.seq \
+         FROM redactions r JOIN batches b ON b.id = r.batch_id WHERE r.batch_id = ?1 ORDER BY r.line_idx",
+    )
+    .bind(batch_id)
+    .fetch_all(conn)
+    .await?;
+    let undecodable = || sqlx::Error::Decode(format!("redaction of batch {batch_id}").into());
+    rows.iter()
+        .map(|row| {
+            let commitment: Vec<u8> = row.try_get("commitment")?;
+            let signature: Vec<u8> = row.try_get("signature")?;
+            Ok(Redaction {
+                batch_id,
+                agent_id: row.try_get("agent_id")?,
+                epoch: row.try_get::<i64, _>("epoch")? as u64,
+                seq: row.try_get::<i64, _>("seq")? as u64,
+                line_idx: row.try_get::<i64, _>("line_idx")? as usize,
+                commitment: commitment.try_into().map_err(|_| undecodable())?,
+                authorized_by: row.try_get("authorized_by")?,
+                redacted_at_ms: row.try_get::<i64, _>("redacted_at_ms")? as u64,
+                key_id: row.try_get("key_id")?,
+                signature: Signature::from_slice(&signature).map_err(|_| undecodable())?,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,58 @@
+//! `X-Request-Id` on every HTTP request. The outermost middleware adopts a
+//! well-formed incoming id (see [`common::request_id::is_well_formed`]) and
+//! generates one otherwise, hands it on in the request's header, and echoes
+//! it on the response. Handlers read it from the header into
+//! [`crate::Provenance`], which records it on rejections and receipts. With
+//! `LOG_REQUESTS` each request also gets one JSON log line carrying it.
+
+use crate::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use common::request_id::{self, HEADER};
+use std::time::Instant;
+
+/// The id `headers` carry if it is well-formed.
+pub fn of(headers: &HeaderMap) -> Option<String> {
+    let id = headers.get(HEADER)?.to_str().ok()?;
+    request_id::is_well_formed(id).then(|| id.to_string())
+}
+
+/// The `LOG_REQUESTS` line for a request that took `duration_ms`.
+pub fn log_line(id: &str, method: &str, path: &str, status: u16, duration_ms: u128) -> String {
+    serde_json::json!({
+        "request_id": id,
+        "method": method,
+        "path": path,
+        "status": status,
+        "duration_ms": duration_ms as u64,
+    })
+    .to_string()
+}
+
+pub async fn middleware(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let id = of(request.headers()).unwrap_or_else(request_id::generate);
+    let value = HeaderValue::from_str(&id).expect("request ids are visible ASCII");
+    request.headers_mut().insert(HEADER, value.clone());
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let started = Instant::now();
+
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(HEADER, value);
+    if state.log_requests {
+        let status = response.status().as_u16();
+        println!(
+            "{}",
+            log_line(&id, &method, &path, status, started.elapsed().as_millis())
+        );
+    }
+    response
+}
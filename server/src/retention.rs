@@ -0,0 +1,314 @@
+//! Retention for auxiliary tables: a periodic task deletes their oldest rows
+//! past a row cap or an age limit. Only allowlisted tables can be pruned; the
+//! chain tables are never touched, whatever the configuration says.
+
+use crate::{Metrics, labeled, now_unix};
+use sqlx::SqlitePool;
+use std::time::Duration;
+
+/// Tables the retention task may delete from, with the unix-seconds column
+/// their age is measured by. Every one has an `id` primary key in insertion
+/// order. Add a table here only if losing its old rows cannot break a chain.
+const PRUNABLE_TABLES: &[(&str, &str)] = &[("rejections", "created_at")];
+
+/// Rows deleted per statement; each chunk is its own short write transaction.
+pub const DEFAULT_CHUNK_ROWS: u64 = 1000;
+/// Pause between chunks so submits can take the write lock in between.
+const CHUNK_PAUSE: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    table: &'static str,
+    age_column: &'static str,
+    max_rows: Option<u64>,
+    max_age_secs: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// Parses `table:max_rows=N:max_age_secs=S`, either limit optional.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut parts = spec.trim().split(':');
+        let name = parts.next().unwrap_or_default();
+        let (table, age_column) = prunable(name)?;
+        let mut policy = Self {
+            table,
+            age_column,
+            max_rows: None,
+            max_age_secs: None,
+        };
+        for part in parts {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value in retention policy, got '{part}'"))?;
+            let value: u64 = value
+                .parse()
+                .map_err(|_| format!("invalid number '{value}' for {key}"))?;
+            match key {
+                "max_rows" => policy.max_rows = Some(value),
+                "max_age_secs" => policy.max_age_secs = Some(value),
+                other => return Err(format!("unknown retention setting '{other}'")),
+            }
+        }
+        if policy.max_rows.is_none() && policy.max_age_secs.is_none() {
+            return Err(format!("retention policy for {name} sets no limit"));
+        }
+        Ok(policy)
+    }
+
+    /// Comma-separated policies, e.g. `RETENTION_POLICIES`.
+    pub fn parse_list(specs: &str) -> Result<Vec<Self>, String> {
+        specs
+            .split(',')
+            .filter(|spec| !spec.trim().is_empty())
+            .map(Self::parse)
+            .collect()
+    }
+}
+
+impl std::fmt::Display for RetentionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.table)?;
+        if let Some(rows) = self.max_rows {
+            write!(f, " max_rows={rows}")?;
+        }
+        if let Some(secs) = self.max_age_secs {
+            write!(f, " max_age_secs={secs}")?;
+        }
+        Ok(())
+    }
+}
+
+fn prunable(table: &str) -> Result<(&'static str, &'static str), String> {
+    PRUNABLE_TABLES
+        .iter()
+        .find(|(name, _)| *name == table)
+        .copied()
+        .ok_or_else(|| format!("table '{table}' is not prunable"))
+}
+
+/// Applies `policy` once and returns the number of rows deleted. Deletions
+/// are recorded in `maintenance_events` and counted in `metrics`.
+pub async fn enforce(
+    pool: &SqlitePool,
+    metrics: &Metrics,
+    policy: &RetentionPolicy,
+    chunk_rows: u64,
+    now: i64,
+) -> Result<u64, String> {
+    // Checked again here so a policy built by hand cannot reach other tables;
+    // the table name is interpolated into SQL only after this.
+    let (table, age_column) = prunable(policy.table)?;
+    let chunk_rows = chunk_rows.max(1) as i64;
+    // Oldest rows first, older than `?1`, at most `?2` per statement.
+    let sql = format!(
+        "DELETE FROM {table} WHERE id IN \
+         (SELECT id FROM {table} WHERE {age_column} < ?1 ORDER BY id LIMIT ?2)"
+    );
+    let mut deleted = 0u64;
+
+    if let Some(max_age_secs) = policy.max_age_secs {
+        let cutoff = now.saturating_sub(max_age_secs as i64);
+        deleted += delete_in_chunks(pool, &sql, cutoff, chunk_rows, u64::MAX).await?;
+    }
+
+    if let Some(max_rows) = policy.max_rows {
+        let count: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+            .fetch_one(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        let excess = (count as u64).saturating_sub(max_rows);
+        deleted += delete_in_chunks(pool, &sql, i64::MAX, chunk_rows, excess).await?;
+    }
+
+    if deleted > 0 {
+        metrics.add(
+            &labeled("logchain_retention_deleted_rows_total", "table", table),
+            deleted,
+        );
+        sqlx::query(
+            "INSERT INTO maintenance_events (kind, table_name, rows, detail, created_at) \
+             VALUES ('retention', ?1, ?2, ?3, ?4)",
+        )
+        .bind(table)
+        .bind(deleted as i64)
+        .bind(policy.to_string())
+        .bind(now)
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        println!("[retention] deleted {deleted} rows from {table} ({policy})");
+    }
+    Ok(deleted)
+}
+
+/// Runs `sql` (binding `?1` = `cutoff`, `?2` = chunk size) until it deletes
+/// fewer rows than asked for or `limit` rows are gone.
+async fn delete_in_chunks(
+    pool: &SqlitePool,
+    sql: &str,
+    cutoff: i64,
+    chunk_rows: i64,
+    limit: u64,
+) -> Result<u64, String> {
+    let mut deleted = 0u64;
+    while deleted < limit {
+        let want = chunk_rows.min((limit - deleted).min(i64::MAX as u64) as i64);
+        let result = sqlx::query(sql)
+            .bind(cutoff)
+            .bind(want)
+            .execute(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        deleted += result.rows_affected();
+        if (result.rows_affected() as i64) < want {
+            break;
+        }
+        tokio::time::sleep(CHUNK_PAUSE).await;
+    }
+    Ok(deleted)
+}
+
+/// The periodic maintenance task.
+pub async fn run(
+    pool: SqlitePool,
+    metrics: std::sync::Arc<Metrics>,
+    policies: Vec<RetentionPolicy>,
+    interval: Duration,
+    chunk_rows: u64,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        for policy in &policies {
+            if let Err(err) = enforce(&pool, &metrics, policy, chunk_rows, now_unix()).await {
+                eprintln!("[retention] {policy}: {err}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_policies_for_allowlisted_tables_only() {
+        let policies =
+            RetentionPolicy::parse_list("rejections:max_rows=100:max_age_secs=3600").unwrap();
+        assert_eq!(policies[0].max_rows, Some(100));
+        assert_eq!(policies[0].max_age_secs, Some(3600));
+        assert_eq!(RetentionPolicy::parse_list("").unwrap(), vec![]);
+
+        for spec in [
+            "batches:max_rows=1",
+            "agents:max_age_secs=1",
+            "maintenance_events:max_rows=1",
+            "rejections; DROP TABLE batches:max_rows=1",
+        ] {
+            let err = RetentionPolicy::parse(spec).unwrap_err();
+            assert!(err.contains("not prunable"), "{spec}: {err}");
+        }
+        assert!(RetentionPolicy::parse("rejections").is_err());
+        assert!(RetentionPolicy::parse("rejections:max_rows=-1").is_err());
+        assert!(RetentionPolicy::parse("rejections:keep=1").is_err());
+    }
+
+    async fn pool() -> SqlitePool {
+        let pool = crate::connect_pool(crate::EPHEMERAL_DATABASE_URL, crate::ack::AckMode::Durable)
+            .await
+            .unwrap();
+        crate::init_schema(&pool).await;
+        pool
+    }
+
+    async fn count(pool: &SqlitePool, table: &str) -> i64 {
+        sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn prunes_oldest_rejections_in_chunks_and_logs_it() {
+        let pool = pool().await;
+        for created_at in 0..25 {
+            sqlx::query(
+                "INSERT INTO rejections (category, reason, created_at) VALUES ('x', 'y', ?1)",
+            )
+            .bind(1_000 + created_at)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+        let metrics = Metrics::new();
+
+        // 10 rows are older than 1_010; then 5 more go to get down to 10.
+        let policy = RetentionPolicy::parse("rejections:max_rows=10:max_age_secs=90").unwrap();
+        let deleted = enforce(&pool, &metrics, &policy, 3, 1_100).await.unwrap();
+        assert_eq!(deleted, 15);
+        let oldest: i64 = sqlx::query_scalar("SELECT MIN(created_at) FROM rejections")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!((count(&pool, "rejections").await, oldest), (10, 1_015));
+        assert_eq!(
+            metrics.get("logchain_retention_deleted_rows_total{table=\"rejections\"}"),
+            15
+        );
+
+        let (rows, detail): (i64, String) =
+            sqlx::query_as("SELECT rows, detail FROM maintenance_events WHERE kind = 'retention'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(rows, 15);
+        assert_eq!(detail, "rejections max_rows=10 max_age_secs=90");
+        // Nothing left to do: no second event.
+        assert_eq!(enforce(&pool, &metrics, &policy, 3, 1_100).await, Ok(0));
+        assert_eq!(count(&pool, "maintenance_events").await, 1);
+    }
+
+    #[tokio::test]
+    async fn never_touches_the_chain_even_if_misconfigured() {
+        let pool = pool().await;
+        sqlx::query(
+            "INSERT INTO batches (agent_id, seq, prev_hash, hash, logs, timestamp, signature, public_key) \
+             VALUES ('a', 1, zeroblob(32), zeroblob(32), '[]', 0, zeroblob(64), zeroblob(32))",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // A policy smuggled past `parse` is still refused before any SQL runs.
+        let policy = RetentionPolicy {
+            table: "batches",
+            age_column: "received_at",
+            max_rows: Some(0),
+            max_age_secs: Some(0),
+        };
+        let err = enforce(&pool, &Metrics::new(), &policy, 10, i64::MAX)
+            .await
+            .unwrap_err();
+        assert!(err.contains("not prunable"), "{err}");
+        assert_eq!(count(&pool, "batches").await, 1);
+
+        // And the append-only triggers still hold on both tables.
+        let err = sqlx::query("DELETE FROM batches")
+            .execute(&pool)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("append-only"), "{err}");
+        sqlx::query(
+            "INSERT INTO maintenance_events (kind, table_name, rows, created_at) \
+             VALUES ('retention', 'rejections', 1, 0)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let err = sqlx::query("DELETE FROM maintenance_events")
+            .execute(&pool)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("append-only"), "{err}");
+    }
+}
@@ -0,0 +1,403 @@
+//! `POST /agents/rotate`: the agent's current key hands it over to a new
+//! one.
+//!
+//! The current key signs the rotation envelope (see
+//! [`common::rotation::rotation_envelope`]) with a counter, a timestamp and a
+//! nonce. The counter must be the next one, the timestamp within
+//! `ROTATION_MAX_AGE_SECS` and the nonce unused, so a captured request can
+//! be neither replayed nor held back for later. The string forms that came
+//! before the envelope are deprecated: v1 (no timestamp) is refused unless
+//! `ROTATION_ALLOW_V1` is set, the v2 string unless
+//! `ROTATION_ALLOW_V2_STRING` is.
+
+use crate::auth::{AuthContext, Scope};
+use crate::storage::agent_storage_error;
+use crate::{
+    AgentResponse, AppState, agent_auth_error, close_key_window, idempotency, key_conflicts,
+    next_position, now_unix, parse_hex_public_key, parse_hex_signature, record_key,
+};
+use axum::{
+    Extension, Json,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use common::hex::hex_decode_fixed;
+use common::rotation::{NONCE_BYTES, RotateRequest, rotation_envelope, rotation_message};
+use ed25519_dalek::VerifyingKey;
+use sqlx::Row;
+use std::env;
+
+/// Default for `ROTATION_MAX_AGE_SECS`.
+pub const DEFAULT_MAX_AGE_SECS: u64 = 300;
+
+/// Which rotation requests the server takes, kept on [`AppState`].
+#[derive(Debug, Clone)]
+pub struct RotationPolicy {
+    /// How far a rotation's signed timestamp may be from the server clock.
+    pub max_age_secs: u64,
+    /// `ROTATION_ALLOW_V1`: deprecated rotations signed without a timestamp.
+    pub allow_v1: bool,
+    /// `ROTATION_ALLOW_V2_STRING`: deprecated rotations signed as the v2
+    /// string instead of the envelope.
+    pub allow_v2_string: bool,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        Self {
+            max_age_secs: DEFAULT_MAX_AGE_SECS,
+            allow_v1: false,
+            allow_v2_string: false,
+        }
+    }
+}
+
+impl RotationPolicy {
+    pub fn from_env() -> Self {
+        let flag = |name: &str| {
+            env::var(name)
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false)
+        };
+        Self {
+            max_age_secs: env::var("ROTATION_MAX_AGE_SECS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(DEFAULT_MAX_AGE_SECS),
+            allow_v1: flag("ROTATION_ALLOW_V1"),
+            allow_v2_string: flag("ROTATION_ALLOW_V2_STRING"),
+        }
+    }
+}
+
+pub async fn handler_rotate_agent(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    headers: HeaderMap,
+    Json(req): Json<RotateRequest>,
+) -> Response {
+    if let Err(err) = auth.require_agent(Scope::Register, &req.agent_id) {
+        return agent_auth_error(err).into_response();
+    }
+    idempotency::once(&state, "rotate", &headers, &req, rotate_agent(&state, &req)).await
+}
+
+/// `POST /agents/rotate` for an authorized caller.
+async fn rotate_agent(state: &AppState, req: &RotateRequest) -> (StatusCode, Json<AgentResponse>) {
+    let row = sqlx::query(
+        "SELECT public_key, rotation_counter, revoked_at FROM agents WHERE agent_id = ?1",
+    )
+    .bind(&req.agent_id)
+    .fetch_optional(&state.pool)
+    .await;
+    let row = match row {
+        Ok(Some(row)) => row,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(AgentResponse {
+                    status: "error".into(),
+                    message: "agent not registered".into(),
+                }),
+            );
+        }
+        Err(err) => {
+            return agent_storage_error(state, "rotate", err, "failed to look up the agent");
+        }
+    };
+
+    if row.get::<Option<i64>, _>("revoked_at").is_some() {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(AgentResponse {
+                status: "error".into(),
+                message: "agent has been revoked".into(),
+            }),
+        );
+    }
+
+    let stored: Vec<u8> = row.get("public_key");
+    let last_counter: i64 = row.get("rotation_counter");
+    let current_pk = match stored.try_into() {
+        Ok(bytes) => match VerifyingKey::from_bytes(&bytes) {
+            Ok(pk) => pk,
+            Err(_) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(AgentResponse {
+                        status: "error".into(),
+                        message: "stored public key is invalid".into(),
+                    }),
+                );
+            }
+        },
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(AgentResponse {
+                    status: "error".into(),
+                    message: "stored public key is invalid".into(),
+                }),
+            );
+        }
+    };
+
+    let new_pk = match parse_hex_public_key(&req.new_public_key_hex) {
+        Ok(pk) => pk,
+        Err(msg) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(AgentResponse {
+                    status: "error".into(),
+                    message: msg,
+                }),
+            );
+        }
+    };
+
+    let sig = match parse_hex_signature(&req.auth_signature_hex) {
+        Ok(sig) => sig,
+        Err(msg) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(AgentResponse {
+                    status: "error".into(),
+                    message: msg,
+                }),
+            );
+        }
+    };
+
+    if req.timestamp.is_none() && !state.rotation.allow_v1 {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(AgentResponse {
+                status: "error".into(),
+                message: "rotation without a timestamp (v1) is no longer accepted; sign the rotation envelope (common::rotation::RotateRequest::signed) with a timestamp and a nonce".into(),
+            }),
+        );
+    }
+
+    let nonce = req.nonce.as_deref();
+    if req.timestamp.is_some()
+        && nonce.is_none_or(|nonce| hex_decode_fixed::<NONCE_BYTES>(nonce).is_err())
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(AgentResponse {
+                status: "error".into(),
+                message: format!(
+                    "a timestamped rotation needs a nonce of {NONCE_BYTES} bytes in hex"
+                ),
+            }),
+        );
+    }
+
+    // New clients sign the envelope; the strings it replaced verify only
+    // while their flag is set.
+    let enveloped = req.timestamp.is_some_and(|ts| {
+        let envelope = rotation_envelope(&req.agent_id, &new_pk, req.counter, ts, nonce);
+        current_pk.verify_strict(&envelope, &sig).is_ok()
+    });
+    if !enveloped {
+        let stamp = req.timestamp.zip(nonce);
+        let message = rotation_message(&req.agent_id, &req.new_public_key_hex, req.counter, stamp);
+        if current_pk.verify_strict(&message, &sig).is_err() {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(AgentResponse {
+                    status: "error".into(),
+                    message: "rotation signature invalid".into(),
+                }),
+            );
+        }
+        if stamp.is_some() && !state.rotation.allow_v2_string {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(AgentResponse {
+                    status: "error".into(),
+                    message: "rotation signed as the v2 string is no longer accepted; sign the rotation envelope (common::rotation::RotateRequest::signed)".into(),
+                }),
+            );
+        }
+        if stamp.is_some() {
+            println!(
+                "[deprecated] v2 string rotation for agent {} accepted because ROTATION_ALLOW_V2_STRING is set",
+                req.agent_id
+            );
+        }
+    }
+
+    // A request signed long ago was captured or held back; it must not stay
+    // usable until the next rotation.
+    match req.timestamp {
+        Some(ts) if now_unix().abs_diff(ts as i64) > state.rotation.max_age_secs => {
+            return (
+                StatusCode::CONFLICT,
+                Json(AgentResponse {
+                    status: "error".into(),
+                    message: format!(
+                        "rotation timestamp {ts} is outside the {}s window",
+                        state.rotation.max_age_secs
+                    ),
+                }),
+            );
+        }
+        Some(_) => {}
+        None => println!(
+            "[deprecated] v1 rotation for agent {} accepted because ROTATION_ALLOW_V1 is set",
+            req.agent_id
+        ),
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(tx) => tx,
+        Err(err) => {
+            return agent_storage_error(state, "rotate", err, "failed to start transaction");
+        }
+    };
+
+    // A signature alone is not enough: a replayed request is validly signed
+    // too. Its nonce was consumed with it, and stays so until its timestamp
+    // leaves the window.
+    if let (Some(ts), Some(nonce)) = (req.timestamp, nonce) {
+        let expires_at = ts as i64 + state.rotation.max_age_secs as i64;
+        match consume_rotation_nonce(tx.as_mut(), &req.agent_id, nonce, expires_at).await {
+            Ok(true) => {}
+            Ok(false) => {
+                drop(tx);
+                return (
+                    StatusCode::CONFLICT,
+                    Json(AgentResponse {
+                        status: "error".into(),
+                        message: "rotation nonce already used; sign a new request".into(),
+                    }),
+                );
+            }
+            Err(err) => {
+                return agent_storage_error(
+                    state,
+                    "rotate",
+                    err,
+                    "failed to record the rotation nonce",
+                );
+            }
+        }
+    }
+    let expected_counter = last_counter as u64 + 1;
+    if req.counter != expected_counter {
+        drop(tx);
+        return (
+            StatusCode::CONFLICT,
+            Json(AgentResponse {
+                status: "error".into(),
+                message: format!(
+                    "stale or reused rotation counter {}; expected {}",
+                    req.counter, expected_counter
+                ),
+            }),
+        );
+    }
+
+    // Compare-and-set so two concurrent rotations cannot both consume the counter.
+    let updated = sqlx::query(
+        "UPDATE agents SET public_key = ?1, rotation_counter = ?2 WHERE agent_id = ?3 AND rotation_counter = ?4",
+    )
+    .bind(new_pk.to_bytes().to_vec())
+    .bind(expected_counter as i64)
+    .bind(&req.agent_id)
+    .bind(last_counter)
+    .execute(tx.as_mut())
+    .await;
+    let updated = match updated {
+        Ok(updated) => updated,
+        Err(err) => {
+            return agent_storage_error(state, "rotate", err, "failed to rotate the agent key");
+        }
+    };
+
+    if updated.rows_affected() == 0 {
+        drop(tx);
+        return (
+            StatusCode::CONFLICT,
+            Json(AgentResponse {
+                status: "error".into(),
+                message: "rotation counter already used by a concurrent rotation".into(),
+            }),
+        );
+    }
+
+    let owner = if state.unique_agent_keys {
+        key_conflicts::other_owner(tx.as_mut(), new_pk.as_bytes(), &req.agent_id).await
+    } else {
+        Ok(None)
+    };
+    let owner = match owner {
+        Ok(owner) => owner,
+        Err(err) => {
+            return agent_storage_error(state, "rotate", err, "failed to check key ownership");
+        }
+    };
+    if let Some(owner) = owner {
+        drop(tx);
+        return (
+            StatusCode::CONFLICT,
+            Json(AgentResponse {
+                status: "error".into(),
+                message: key_conflicts::conflict_message(&owner),
+            }),
+        );
+    }
+
+    if let Err(err) = hand_over_key(tx.as_mut(), &req.agent_id, &new_pk).await {
+        return agent_storage_error(state, "rotate", err, "failed to record the key history");
+    }
+    if let Err(err) = tx.commit().await {
+        return agent_storage_error(state, "rotate", err, "failed to commit the rotation");
+    }
+    state.storage.recovered("rotate");
+
+    (
+        StatusCode::OK,
+        Json(AgentResponse {
+            status: "ok".into(),
+            message: format!("agent key rotated (rotation counter {})", expected_counter),
+        }),
+    )
+}
+
+/// Records `nonce` as used by `agent_id` until `expires_at` (unix seconds),
+/// first forgetting those past theirs; `false` if it is still recorded.
+pub async fn consume_rotation_nonce(
+    conn: &mut sqlx::SqliteConnection,
+    agent_id: &str,
+    nonce: &str,
+    expires_at: i64,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query("DELETE FROM rotation_nonces WHERE expires_at < ?1")
+        .bind(now_unix())
+        .execute(&mut *conn)
+        .await?;
+    let inserted = sqlx::query(
+        "INSERT INTO rotation_nonces (agent_id, nonce, expires_at) VALUES (?1, ?2, ?3) ON CONFLICT DO NOTHING",
+    )
+    .bind(agent_id)
+    .bind(nonce)
+    .bind(expires_at)
+    .execute(&mut *conn)
+    .await?;
+    Ok(inserted.rows_affected() == 1)
+}
+
+/// The old key keeps covering the seqs it already signed; the new key takes
+/// over from the next one.
+async fn hand_over_key(
+    conn: &mut sqlx::SqliteConnection,
+    agent_id: &str,
+    new_pk: &VerifyingKey,
+) -> Result<(), sqlx::Error> {
+    let next = next_position(conn, agent_id).await?;
+    close_key_window(conn, agent_id, next).await?;
+    record_key(conn, agent_id, new_pk.as_bytes(), next).await
+}
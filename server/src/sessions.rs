@@ -0,0 +1,102 @@
+//! Session boundaries: the signed session-start batches agents send with
+//! `--batch-header` when a run begins (see [`common::batch::BatchKind`]).
+//! Each one marks where a restart falls in the chain, so the batches between
+//! two markers are one run of the agent.
+
+use crate::{AppState, RECEIVED_AT_MS_EXPR};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use common::batch::{BatchKind, LogBatch};
+use common::serde_util::is_zero;
+use serde::Serialize;
+use sqlx::{Row, SqlitePool};
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct SessionStart {
+    /// Row id of the marker.
+    id: i64,
+    #[serde(skip_serializing_if = "is_zero")]
+    epoch: u64,
+    seq: u64,
+    session_id: String,
+    boot_time_ms: u64,
+    received_at_ms: u64,
+    hash: [u8; 32],
+}
+
+/// The columns a session start is stored in, for the batch insert.
+pub fn columns(batch: &LogBatch) -> (Option<&str>, Option<i64>) {
+    match &batch.kind {
+        Some(BatchKind::SessionStart {
+            session_id,
+            boot_time_ms,
+        }) => (Some(session_id), Some(*boot_time_ms as i64)),
+        Some(BatchKind::Closed { .. }) | None => (None, None),
+    }
+}
+
+/// The [`BatchKind`] a row was stored with. A chain close keeps its reason
+/// in `closed_reason`.
+pub fn stored_kind(row: &sqlx::sqlite::SqliteRow) -> Option<BatchKind> {
+    if let Some(reason) = row
+        .try_get::<Option<String>, _>("closed_reason")
+        .ok()
+        .flatten()
+    {
+        return Some(BatchKind::Closed { reason });
+    }
+    let session_id = row
+        .try_get::<Option<String>, _>("session_id")
+        .ok()
+        .flatten()?;
+    let boot_time_ms = row
+        .try_get::<Option<i64>, _>("session_boot_ms")
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    Some(BatchKind::SessionStart {
+        session_id,
+        boot_time_ms: boot_time_ms as u64,
+    })
+}
+
+/// `GET /agents/:agent_id/sessions`: the agent's session starts in chain
+/// order, oldest first. Empty for an agent that never sent one.
+pub async fn handler_sessions(
+    State(state): State<AppState>,
+    Path(agent_id): Path<String>,
+) -> Result<Json<Vec<SessionStart>>, StatusCode> {
+    sessions(&state.pool, &agent_id)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn sessions(pool: &SqlitePool, agent_id: &str) -> Result<Vec<SessionStart>, sqlx::Error> {
+    let rows = sqlx::query(&format!(
+        "SELECT id, epoch, seq, session_id, session_boot_ms, {RECEIVED_AT_MS_EXPR} AS received_at_ms, hash \
+         FROM batches WHERE agent_id = ?1 AND session_id IS NOT NULL ORDER BY epoch, seq"
+    ))
+    .bind(agent_id)
+    .fetch_all(pool)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            let hash: Vec<u8> = row.try_get("hash")?;
+            Ok(SessionStart {
+                id: row.try_get("id")?,
+                epoch: row.try_get::<i64, _>("epoch")? as u64,
+                seq: row.try_get::<i64, _>("seq")? as u64,
+                session_id: row.try_get("session_id")?,
+                boot_time_ms: row.try_get::<i64, _>("session_boot_ms")? as u64,
+                received_at_ms: row.try_get::<i64, _>("received_at_ms")? as u64,
+                hash: hash
+                    .try_into()
+                    .map_err(|_| sqlx::Error::Decode("stored hash is not 32 bytes".into()))?,
+            })
+        })
+        .collect()
+}
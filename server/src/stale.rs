@@ -0,0 +1,83 @@
+//! Fleet liveness: agents whose last batch arrived too long ago. Based on the
+//! server's arrival time, so an agent with a wrong clock is judged correctly.
+
+use crate::{AppState, RECEIVED_AT_MS_EXPR, now_unix_ms};
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqlitePool};
+
+#[derive(Debug, Deserialize)]
+pub struct StaleParams {
+    /// Seconds of silence after which an agent is stale; defaults to
+    /// `STALE_AGENT_SECS`.
+    threshold_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StaleAgent {
+    agent_id: String,
+    last_received_at_ms: u64,
+    silent_for_secs: u64,
+}
+
+/// `GET /agents/stale?threshold_secs=`
+pub async fn handler_stale_agents(
+    State(state): State<AppState>,
+    Query(params): Query<StaleParams>,
+) -> Result<Json<Vec<StaleAgent>>, StatusCode> {
+    let threshold_secs = params.threshold_secs.unwrap_or(state.stale_agent_secs);
+    stale_agents(&state.pool, threshold_secs, now_unix_ms())
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Agents with at least one batch whose newest arrival is more than
+/// `threshold_secs` before `now_ms`, longest silent first. Revoked agents are
+/// expected to go quiet and are left out.
+pub async fn stale_agents(
+    pool: &SqlitePool,
+    threshold_secs: u64,
+    now_ms: i64,
+) -> Result<Vec<StaleAgent>, sqlx::Error> {
+    let cutoff_ms = now_ms.saturating_sub(threshold_secs.saturating_mul(1000) as i64);
+    let rows = sqlx::query(&format!(
+        r#"
+        SELECT b.agent_id, MAX({RECEIVED_AT_MS_EXPR}) AS last_received_at_ms
+        FROM batches b
+        LEFT JOIN agents a ON a.agent_id = b.agent_id
+        WHERE a.revoked_at IS NULL
+        GROUP BY b.agent_id
+        HAVING last_received_at_ms < ?1
+        ORDER BY last_received_at_ms, b.agent_id
+        "#
+    ))
+    .bind(cutoff_ms)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let last_received_at_ms: i64 = row.get("last_received_at_ms");
+            StaleAgent {
+                agent_id: row.get("agent_id"),
+                last_received_at_ms: last_received_at_ms as u64,
+                silent_for_secs: (now_ms.saturating_sub(last_received_at_ms) / 1000) as u64,
+            }
+        })
+        .collect())
+}
+
+/// `/metrics` line counting agents stale at the `STALE_AGENT_SECS` threshold.
+pub async fn render_metrics(state: &AppState) -> String {
+    let stale = stale_agents(&state.pool, state.stale_agent_secs, now_unix_ms())
+        .await
+        .map(|agents| agents.len())
+        .unwrap_or(0);
+    format!("logchain_agents_stale {stale}\n")
+}
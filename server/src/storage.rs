@@ -0,0 +1,275 @@
+//! Storage faults: SQLite refusing writes because the disk is full
+//! (`SQLITE_FULL`), the database is read-only (`SQLITE_READONLY`) or the
+//! disk fails (`SQLITE_IOERR`). A submit that hits one answers 507 or 503
+//! with its own category instead of a bare 500, bumps
+//! `logchain_storage_faults_total{kind=...}`, and turns `/readyz` unhealthy
+//! until a submit is stored again. Snapshots, agent registrations and key
+//! rotations report their faults the same way, each cleared by the next of
+//! its kind that succeeds. A suspected rollback (see [`crate::watermark`])
+//! keeps it unhealthy until the server restarts.
+//!
+//! With `ALERT_WEBHOOK_URL` set, the first fault of each kind of write is
+//! also posted there as JSON (see [`Alert`]), once per run of faults, so
+//! nobody has to be watching `/readyz` to hear of a full disk.
+
+use crate::watermark::Behind;
+use crate::{AgentResponse, AppState, Metrics, SubmitResponse, labeled, now_unix_ms, submit_error};
+use axum::{Json, extract::State, http::StatusCode};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const SQLITE_READONLY: i64 = 8;
+const SQLITE_IOERR: i64 = 10;
+const SQLITE_FULL: i64 = 13;
+
+/// How long one webhook post may take.
+const ALERT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageFault {
+    Full,
+    ReadOnly,
+    Io,
+}
+
+impl StorageFault {
+    /// The fault behind `err`, if it is one. SQLite reports extended codes;
+    /// their low byte is the primary one.
+    pub fn of(err: &sqlx::Error) -> Option<Self> {
+        let sqlx::Error::Database(db) = err else {
+            return None;
+        };
+        let code: i64 = db.code()?.parse().ok()?;
+        match code & 0xff {
+            SQLITE_FULL => Some(StorageFault::Full),
+            SQLITE_READONLY => Some(StorageFault::ReadOnly),
+            SQLITE_IOERR => Some(StorageFault::Io),
+            _ => None,
+        }
+    }
+
+    /// 507 for a full disk, which needs space; 503 otherwise.
+    pub fn status(self) -> StatusCode {
+        match self {
+            StorageFault::Full => StatusCode::INSUFFICIENT_STORAGE,
+            StorageFault::ReadOnly | StorageFault::Io => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            StorageFault::Full => "storage full: the database cannot grow",
+            StorageFault::ReadOnly => "storage read-only: the database refuses writes",
+            StorageFault::Io => "storage I/O error: the database cannot be written",
+        }
+    }
+
+    /// The submit rejection category and metric label.
+    pub fn category(self) -> &'static str {
+        match self {
+            StorageFault::Full => "storage_full",
+            StorageFault::ReadOnly => "storage_read_only",
+            StorageFault::Io => "storage_io",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct FaultState {
+    /// What failed: `submit`, `snapshot`, `register` or `rotate`.
+    pub during: &'static str,
+    pub fault: StorageFault,
+    /// When the current run of faults began.
+    pub since_ms: i64,
+}
+
+/// What `ALERT_WEBHOOK_URL` receives when a run of faults begins.
+#[derive(Debug, Serialize)]
+pub struct Alert {
+    /// Always `storage_fault`.
+    pub event: &'static str,
+    #[serde(flatten)]
+    pub state: FaultState,
+    /// The submit rejection category, as in `logchain_storage_faults_total`.
+    pub category: &'static str,
+    pub message: &'static str,
+}
+
+/// Storage faults not yet followed by a success of the same kind of write.
+#[derive(Default)]
+pub struct StorageHealth {
+    faults: Mutex<BTreeMap<&'static str, FaultState>>,
+    alert_webhook: Option<String>,
+    client: reqwest::Client,
+}
+
+impl StorageHealth {
+    /// Posts the start of each run of faults to `alert_webhook`, if set.
+    pub fn new(alert_webhook: Option<String>) -> Self {
+        Self {
+            alert_webhook,
+            ..Self::default()
+        }
+    }
+
+    pub fn current(&self) -> Vec<FaultState> {
+        self.faults.lock().unwrap().values().copied().collect()
+    }
+
+    /// Records a fault during `during`; logs the first of a run.
+    pub fn fault(&self, metrics: &Metrics, fault: StorageFault, during: &'static str) {
+        metrics.inc(&labeled(
+            "logchain_storage_faults_total",
+            "kind",
+            fault.category(),
+        ));
+        let mut faults = self.faults.lock().unwrap();
+        match faults.get_mut(during) {
+            Some(known) => known.fault = fault,
+            None => {
+                eprintln!(
+                    "[storage] {during}: {}; /readyz reports unhealthy until a {during} succeeds",
+                    fault.message()
+                );
+                let state = FaultState {
+                    during,
+                    fault,
+                    since_ms: now_unix_ms(),
+                };
+                faults.insert(during, state);
+                self.alert(state);
+            }
+        }
+    }
+
+    /// Posts `state` to the webhook in the background; a failed post is
+    /// logged and not retried.
+    fn alert(&self, state: FaultState) {
+        let Some(url) = self.alert_webhook.clone() else {
+            return;
+        };
+        let alert = Alert {
+            event: "storage_fault",
+            state,
+            category: state.fault.category(),
+            message: state.fault.message(),
+        };
+        let request = self
+            .client
+            .post(url)
+            .timeout(ALERT_TIMEOUT)
+            .header("content-type", "application/json")
+            .body(serde_json::to_vec(&alert).expect("alerts serialize"));
+        tokio::spawn(async move {
+            let result = request
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status());
+            if let Err(err) = result {
+                eprintln!(
+                    "[storage] alert webhook failed for {}: {err}",
+                    alert.state.during
+                );
+            }
+        });
+    }
+
+    /// A write during `during` succeeded.
+    pub fn recovered(&self, during: &'static str) {
+        if self.faults.lock().unwrap().remove(during).is_some() {
+            println!("[storage] {during} succeeds again");
+        }
+    }
+}
+
+/// The submit answer to a storage fault. Nothing is written
+/// to the `rejections` table, which shares the failing database.
+pub fn storage_error(state: &AppState, fault: StorageFault) -> (StatusCode, Json<SubmitResponse>) {
+    state.storage.fault(&state.metrics, fault, "submit");
+    submit_error(state, fault.status(), fault.category(), fault.message())
+}
+
+/// `storage_error` for a storage fault, a 500 `internal` with `context` otherwise.
+pub fn internal_or_storage_error(
+    state: &AppState,
+    err: sqlx::Error,
+    context: &str,
+) -> (StatusCode, Json<SubmitResponse>) {
+    match StorageFault::of(&err) {
+        Some(fault) => storage_error(state, fault),
+        None => submit_error(
+            state,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            format!("{context}: {err}"),
+        ),
+    }
+}
+
+/// The registry's answer to a database error during `during` (`register` or
+/// `rotate`): a storage fault maps as it does for a submit (see
+/// [`internal_or_storage_error`]), anything else is a 500 with `context`.
+pub fn agent_storage_error(
+    state: &AppState,
+    during: &'static str,
+    err: sqlx::Error,
+    context: &str,
+) -> (StatusCode, Json<AgentResponse>) {
+    let (code, message) = match StorageFault::of(&err) {
+        Some(fault) => {
+            state.storage.fault(&state.metrics, fault, during);
+            (fault.status(), fault.message().to_string())
+        }
+        None => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("{context}: {err}"),
+        ),
+    };
+    (
+        code,
+        Json(AgentResponse {
+            status: "error".into(),
+            message,
+        }),
+    )
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    ready: bool,
+    storage_faults: Vec<FaultState>,
+    /// Agents the database is behind its high-water marks for.
+    rollback_suspected: Vec<Behind>,
+    /// Maintenance mode is on: reads serve, writes answer 503.
+    maintenance: bool,
+}
+
+/// `GET /readyz`: 200 while storage takes writes, 503 while a storage fault
+/// is outstanding or a rollback is suspected. Maintenance mode is reported
+/// but leaves the server ready, since reads keep serving.
+pub async fn handler_readyz(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let storage_faults = state.storage.current();
+    let rollback_suspected = state
+        .watermarks
+        .as_ref()
+        .map(|watermarks| watermarks.behind().to_vec())
+        .unwrap_or_default();
+    let ready = storage_faults.is_empty() && rollback_suspected.is_empty();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(Readiness {
+            ready,
+            storage_faults,
+            rollback_suspected,
+            maintenance: state.maintenance.is_on(),
+        }),
+    )
+}
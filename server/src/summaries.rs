@@ -0,0 +1,172 @@
+//! Daily summaries: per agent and UTC day of arrival, the batch count, line
+//! count, seq range, head hash and Merkle root (see [`common::summary`]).
+//! A periodic task summarizes each day once it is `SUMMARY_AFTER_DAYS` old,
+//! into the append-only `daily_summaries` table, so the existence and
+//! integrity of old batches stays provable after their content is archived.
+//! A trigger refuses to delete a batch whose day has no summary yet.
+
+use crate::{AppState, RECEIVED_AT_MS_EXPR, now_unix_ms};
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+};
+use common::summary::{DAY_MS, DailySummary, SummaryEntry, day_of, day_start_ms, day_start_of};
+use serde::Deserialize;
+use sqlx::{Row, SqlitePool};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+pub const DEFAULT_AFTER_DAYS: u64 = 1;
+
+#[derive(Debug, Deserialize)]
+pub struct SummaryParams {
+    agent_id: Option<String>,
+    /// `YYYY-MM-DD` bounds, inclusive.
+    since_day: Option<String>,
+    until_day: Option<String>,
+}
+
+/// `GET /summaries?agent_id=&since_day=&until_day=`, by day, then agent.
+pub async fn handler_summaries(
+    State(state): State<AppState>,
+    Query(params): Query<SummaryParams>,
+) -> Result<Json<Vec<DailySummary>>, StatusCode> {
+    list(&state.pool, &params)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn list(pool: &SqlitePool, params: &SummaryParams) -> Result<Vec<DailySummary>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT agent_id, day, batches, lines, min_seq, max_seq, head_hash, merkle_root \
+         FROM daily_summaries \
+         WHERE (?1 IS NULL OR agent_id = ?1) AND (?2 IS NULL OR day >= ?2) AND (?3 IS NULL OR day <= ?3) \
+         ORDER BY day, agent_id",
+    )
+    .bind(&params.agent_id)
+    .bind(&params.since_day)
+    .bind(&params.until_day)
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter()
+        .map(|row| {
+            let hash = |column: &str| {
+                <[u8; 32]>::try_from(row.get::<Vec<u8>, _>(column))
+                    .map_err(|_| sqlx::Error::Decode(format!("{column} is not 32 bytes").into()))
+            };
+            Ok(DailySummary {
+                agent_id: row.get("agent_id"),
+                day: row.get("day"),
+                batches: row.get::<i64, _>("batches") as u64,
+                lines: row.get::<i64, _>("lines") as u64,
+                min_seq: row.get::<i64, _>("min_seq") as u64,
+                max_seq: row.get::<i64, _>("max_seq") as u64,
+                head_hash: hash("head_hash")?,
+                merkle_root: hash("merkle_root")?,
+            })
+        })
+        .collect()
+}
+
+/// Summarizes every day that ended at or before `cutoff_ms` and has not been
+/// summarized yet, oldest first. Days end in arrival order, so everything
+/// before the newest summarized day is done already. Returns the number of
+/// summaries written.
+pub async fn generate(pool: &SqlitePool, cutoff_ms: i64) -> Result<u64, sqlx::Error> {
+    let summarized: Option<String> = sqlx::query_scalar("SELECT MAX(day) FROM daily_summaries")
+        .fetch_one(pool)
+        .await?;
+    let mut day_ms = match summarized {
+        Some(day) => {
+            let start = day_start_of(&day)
+                .ok_or_else(|| sqlx::Error::Decode(format!("summary day '{day}'").into()))?;
+            start + DAY_MS
+        }
+        None => {
+            let first: Option<i64> =
+                sqlx::query_scalar(&format!("SELECT MIN({RECEIVED_AT_MS_EXPR}) FROM batches"))
+                    .fetch_one(pool)
+                    .await?;
+            match first {
+                Some(first) => day_start_ms(first),
+                None => return Ok(0),
+            }
+        }
+    };
+
+    let mut written = 0;
+    while day_ms + DAY_MS <= cutoff_ms {
+        written += summarize_day(pool, day_ms).await?;
+        day_ms += DAY_MS;
+    }
+    Ok(written)
+}
+
+async fn summarize_day(pool: &SqlitePool, day_ms: i64) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query(&format!(
+        "SELECT agent_id, epoch, seq, hash, json_array_length(logs) AS lines FROM batches \
+         WHERE {RECEIVED_AT_MS_EXPR} >= ?1 AND {RECEIVED_AT_MS_EXPR} < ?2"
+    ))
+    .bind(day_ms)
+    .bind(day_ms + DAY_MS)
+    .fetch_all(pool)
+    .await?;
+    let mut by_agent: BTreeMap<String, Vec<SummaryEntry>> = BTreeMap::new();
+    for row in rows {
+        let hash: Vec<u8> = row.get("hash");
+        by_agent
+            .entry(row.get("agent_id"))
+            .or_default()
+            .push(SummaryEntry {
+                epoch: row.get::<i64, _>("epoch") as u64,
+                seq: row.get::<i64, _>("seq") as u64,
+                hash: hash
+                    .try_into()
+                    .map_err(|_| sqlx::Error::Decode("hash is not 32 bytes".into()))?,
+                lines: row.get::<i64, _>("lines") as u64,
+            });
+    }
+
+    let day = day_of(day_ms);
+    let mut tx = pool.begin().await?;
+    for (agent_id, entries) in &by_agent {
+        let Some(summary) = DailySummary::of(agent_id, &day, entries) else {
+            continue;
+        };
+        sqlx::query(
+            // `daily_summaries_written_once` skips a day summarized already.
+            "INSERT INTO daily_summaries \
+             (agent_id, day, batches, lines, min_seq, max_seq, head_hash, merkle_root, generated_at_ms) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )
+        .bind(&summary.agent_id)
+        .bind(&summary.day)
+        .bind(summary.batches as i64)
+        .bind(summary.lines as i64)
+        .bind(summary.min_seq as i64)
+        .bind(summary.max_seq as i64)
+        .bind(summary.head_hash.to_vec())
+        .bind(summary.merkle_root.to_vec())
+        .bind(now_unix_ms())
+        .execute(tx.as_mut())
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(by_agent.len() as u64)
+}
+
+/// The periodic summarizing task.
+pub async fn run(pool: SqlitePool, after_days: u64, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let cutoff_ms = now_unix_ms() - after_days as i64 * DAY_MS;
+        match generate(&pool, cutoff_ms).await {
+            Ok(0) => {}
+            Ok(written) => println!("[summaries] wrote {written} daily summaries"),
+            Err(err) => eprintln!("[summaries] {err}"),
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! Blob tiering: a periodic task moves the gzip copy (`logs_compressed`) of
+//! batches older than `BLOB_TIER_AFTER_DAYS` into a content-addressed blob
+//! store, a local directory keyed by batch hash. The row keeps everything
+//! else, plaintext `logs` included; the stub that finds the blob again is a
+//! row in the append-only `blob_locations` table, with the blob's SHA-256.
+//!
+//! The `batches_no_update` trigger allows this one change: clearing a row's
+//! `logs_compressed` once its stub exists, every other column unchanged.
+//! Readers go through [`compressed_logs`], which fetches a tiered blob and
+//! checks its digest, so tiered rows read like any other; fsck checks that
+//! every stub resolves. Blobs are never deleted, so a snapshot taken before
+//! or after tiering stays readable as long as the store is kept with it.
+
+use crate::{RECEIVED_AT_MS_EXPR, now_unix_ms};
+use common::hex::hex_encode;
+use common::summary::DAY_MS;
+use sha2::{Digest, Sha256};
+use sqlx::{Row, SqlitePool, sqlite::SqliteRow};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_AFTER_DAYS: u64 = 30;
+/// Rows moved per transaction.
+pub const DEFAULT_CHUNK_ROWS: u64 = 100;
+
+/// Where tiered blobs live: `<dir>/<first hash byte, hex>/<hash hex>.json.gz`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    /// `BLOB_TIER_STORE`: a directory, as a path or a `file://` URL. S3 has
+    /// no client in this build; a bucket mounted as a directory works.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let spec = spec.trim();
+        if spec.starts_with("s3://") {
+            return Err(
+                "S3 blob stores are not supported by this build; mount the bucket and give its directory"
+                    .into(),
+            );
+        }
+        let dir = spec.strip_prefix("file://").unwrap_or(spec);
+        if dir.is_empty() {
+            return Err("empty blob store directory".into());
+        }
+        Ok(Self {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path_of(&self, hash: &[u8]) -> PathBuf {
+        let hex = hex_encode(hash);
+        self.dir.join(&hex[..2]).join(format!("{hex}.json.gz"))
+    }
+
+    /// Stores `blob` under `hash` and returns where. A copy already there
+    /// with the same digest is kept; anything else there is replaced through
+    /// a synced temporary file, so a crash never leaves half a blob.
+    pub fn put(&self, hash: &[u8], blob: &[u8]) -> io::Result<PathBuf> {
+        let path = self.path_of(hash);
+        if fs::read(&path).is_ok_and(|existing| existing == blob) {
+            return Ok(path);
+        }
+        fs::create_dir_all(path.parent().expect("blob paths have a parent"))?;
+        let tmp = path.with_extension("gz.tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(blob)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(path)
+    }
+}
+
+/// Why a stub does not give back its blob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlobError {
+    Unreadable(String),
+    DigestMismatch,
+}
+
+impl BlobError {
+    /// The fsck discrepancy kind.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BlobError::Unreadable(_) => "blob_unreadable",
+            BlobError::DigestMismatch => "blob_digest_mismatch",
+        }
+    }
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobError::Unreadable(err) => write!(f, "blob unreadable: {err}"),
+            BlobError::DigestMismatch => f.write_str("blob does not match its recorded SHA-256"),
+        }
+    }
+}
+
+/// The blob at `location`, if it still has digest `sha256`.
+pub fn fetch(location: &str, sha256: &[u8]) -> Result<Vec<u8>, BlobError> {
+    let blob = fs::read(location).map_err(|err| BlobError::Unreadable(err.to_string()))?;
+    if Sha256::digest(&blob).as_slice() != sha256 {
+        return Err(BlobError::DigestMismatch);
+    }
+    Ok(blob)
+}
+
+/// The row's gzip copy of its logs: `logs_compressed`, or the blob its stub
+/// (`blob_location`, `blob_sha256`) points to. `None` for rows stored
+/// plaintext only.
+pub fn compressed_logs(row: &SqliteRow) -> Result<Option<Vec<u8>>, BlobError> {
+    if let Some(blob) = row
+        .try_get::<Option<Vec<u8>>, _>("logs_compressed")
+        .ok()
+        .flatten()
+    {
+        return Ok(Some(blob));
+    }
+    let Some(location) = row
+        .try_get::<Option<String>, _>("blob_location")
+        .ok()
+        .flatten()
+    else {
+        return Ok(None);
+    };
+    let sha256: Vec<u8> = row
+        .try_get("blob_sha256")
+        .map_err(|err| BlobError::Unreadable(err.to_string()))?;
+    fetch(&location, &sha256).map(Some)
+}
+
+/// Moves the gzip copy of every batch received before `cutoff_ms` to
+/// `store`, oldest first, `chunk_rows` per transaction. Returns the number
+/// of rows tiered.
+pub async fn tier(
+    pool: &SqlitePool,
+    store: &BlobStore,
+    cutoff_ms: i64,
+    chunk_rows: u64,
+) -> Result<u64, String> {
+    let chunk_rows = chunk_rows.max(1);
+    let mut tiered = 0;
+    loop {
+        let rows = sqlx::query(&format!(
+            "SELECT id, hash, logs_compressed FROM batches \
+             WHERE logs_compressed IS NOT NULL AND {RECEIVED_AT_MS_EXPR} < ?1 \
+             ORDER BY id LIMIT ?2"
+        ))
+        .bind(cutoff_ms)
+        .bind(chunk_rows as i64)
+        .fetch_all(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+        let count = rows.len() as u64;
+
+        // Blobs are written before their stubs: a crash in between leaves an
+        // unreferenced blob, which the next run writes again.
+        let mut stubs = Vec::with_capacity(rows.len());
+        for row in rows {
+            let id: i64 = row.get("id");
+            let hash: Vec<u8> = row.get("hash");
+            let blob: Vec<u8> = row.get("logs_compressed");
+            let location = store
+                .put(&hash, &blob)
+                .map_err(|err| format!("writing the blob of row {id}: {err}"))?;
+            stubs.push((id, location, Sha256::digest(&blob).to_vec(), blob.len()));
+        }
+
+        let mut tx = pool.begin().await.map_err(|err| err.to_string())?;
+        for (id, location, sha256, size) in &stubs {
+            sqlx::query(
+                "INSERT INTO blob_locations (batch_id, location, blob_sha256, size, tiered_at_ms) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )
+            .bind(id)
+            .bind(location.display().to_string())
+            .bind(sha256)
+            .bind(*size as i64)
+            .bind(now_unix_ms())
+            .execute(tx.as_mut())
+            .await
+            .map_err(|err| err.to_string())?;
+            sqlx::query("UPDATE batches SET logs_compressed = NULL WHERE id = ?1")
+                .bind(id)
+                .execute(tx.as_mut())
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        tx.commit().await.map_err(|err| err.to_string())?;
+        tiered += count;
+
+        if count < chunk_rows {
+            return Ok(tiered);
+        }
+    }
+}
+
+/// The periodic tiering task.
+pub async fn run(
+    pool: SqlitePool,
+    store: BlobStore,
+    after_days: u64,
+    interval: Duration,
+    chunk_rows: u64,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let cutoff_ms = now_unix_ms() - after_days as i64 * DAY_MS;
+        match tier(&pool, &store, cutoff_ms, chunk_rows).await {
+            Ok(0) => {}
+            Ok(moved) => println!(
+                "[tiering] moved {moved} compressed blobs to {}",
+                store.dir().display()
+            ),
+            Err(err) => eprintln!("[tiering] {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blobs_are_keyed_by_hash_and_checked_on_fetch() {
+        assert!(BlobStore::parse("s3://bucket/logs").is_err());
+        assert!(BlobStore::parse("").is_err());
+        let dir = std::env::temp_dir().join(format!("logchain-blobs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = BlobStore::parse(&format!("file://{}", dir.display())).unwrap();
+        assert_eq!(store.dir(), dir);
+
+        let hash = [0xab; 32];
+        let path = store.put(&hash, b"gzip bytes").unwrap();
+        assert!(path.starts_with(dir.join("ab")));
+        assert!(
+            path.to_str()
+                .unwrap()
+                .ends_with(&format!("{}.json.gz", "ab".repeat(32)))
+        );
+        assert_eq!(store.put(&hash, b"gzip bytes").unwrap(), path);
+
+        let location = path.to_str().unwrap();
+        let digest = Sha256::digest(b"gzip bytes");
+        assert_eq!(fetch(location, &digest).unwrap(), b"gzip bytes");
+        fs::write(&path, b"gzip bytez").unwrap();
+        assert_eq!(fetch(location, &digest), Err(BlobError::DigestMismatch));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            fetch(location, &digest).unwrap_err().kind(),
+            "blob_unreadable"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,106 @@
+//! Per-route request timeouts, so a pathological query (say `/batches` with
+//! a broad `log_substring` over a large store) cannot hold a connection open
+//! indefinitely. Routes are grouped like the rate limits, plus export:
+//!
+//! - `READ_TIMEOUT_MS` (default 30000): the `/batches` and `/agents` reads,
+//!   `/metrics`, `/readyz`, the dashboard.
+//! - `SUBMIT_TIMEOUT_MS` (default 30000): `/submit` and `/ingest/*`.
+//! - `ADMIN_TIMEOUT_MS` (default 300000): registration, rotation and
+//!   `/admin/*`, where snapshots and integrity checks take a while.
+//! - `EXPORT_TIMEOUT_MS` (default 120000): `/batches/export` until the
+//!   response starts, which for `format=json` is the whole body.
+//! - `EXPORT_CHUNK_TIMEOUT_MS` (default 30000): the longest wait for the
+//!   next chunk of a streamed export. A large export that keeps moving is
+//!   never cut off, however long it runs.
+//!
+//! A request over its limit gets 503 with an empty body; a stalled export
+//! stream is aborted. The handler is dropped either way, which rolls back
+//! an open transaction. `0` turns a limit off.
+
+use axum::Router;
+use axum::http::StatusCode;
+use std::env;
+use std::time::Duration;
+use tower_http::timeout::{ResponseBodyTimeoutLayer, TimeoutLayer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub read: Option<Duration>,
+    pub submit: Option<Duration>,
+    pub admin: Option<Duration>,
+    pub export: Option<Duration>,
+    pub export_chunk: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            read: Some(Duration::from_secs(30)),
+            submit: Some(Duration::from_secs(30)),
+            admin: Some(Duration::from_secs(300)),
+            export: Some(Duration::from_secs(120)),
+            export_chunk: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn from_env() -> Result<Self, String> {
+        let defaults = Self::default();
+        Ok(Self {
+            read: env_ms("READ_TIMEOUT_MS", defaults.read)?,
+            submit: env_ms("SUBMIT_TIMEOUT_MS", defaults.submit)?,
+            admin: env_ms("ADMIN_TIMEOUT_MS", defaults.admin)?,
+            export: env_ms("EXPORT_TIMEOUT_MS", defaults.export)?,
+            export_chunk: env_ms("EXPORT_CHUNK_TIMEOUT_MS", defaults.export_chunk)?,
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        let show = |limit: Option<Duration>| limit.map_or("off".to_string(), |d| format!("{d:?}"));
+        format!(
+            "read {}, submit {}, admin {}, export {} (per chunk {})",
+            show(self.read),
+            show(self.submit),
+            show(self.admin),
+            show(self.export),
+            show(self.export_chunk)
+        )
+    }
+}
+
+fn env_ms(name: &str, default: Option<Duration>) -> Result<Option<Duration>, String> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse::<u64>()
+            .map(|ms| (ms > 0).then(|| Duration::from_millis(ms)))
+            .map_err(|_| format!("{name} must be a number of milliseconds, got '{value}'")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// `routes` answering 503 once a request has run for `limit`.
+pub fn limit<S>(routes: Router<S>, limit: Option<Duration>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    match limit {
+        Some(limit) => routes.layer(TimeoutLayer::with_status_code(
+            StatusCode::SERVICE_UNAVAILABLE,
+            limit,
+        )),
+        None => routes,
+    }
+}
+
+/// `routes` whose response bodies abort when the next chunk takes longer
+/// than `limit`.
+pub fn limit_chunks<S>(routes: Router<S>, limit: Option<Duration>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    match limit {
+        Some(limit) => routes.layer(ResponseBodyTimeoutLayer::new(limit)),
+        None => routes,
+    }
+}
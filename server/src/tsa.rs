@@ -0,0 +1,237 @@
+//! RFC 3161 trusted timestamps, when `TSA_URL` names a Time Stamping
+//! Authority. Every `TSA_INTERVAL_SECS` (default 60) a task takes the
+//! batches stored since the last stamp, up to `TSA_MAX_BATCHES` (default
+//! 1000) in id order, and has the TSA sign the [`common::tsa::imprint`] of
+//! the Merkle root of their hashes. `TSA_MAX_BATCHES=1` gets a token per
+//! batch; larger values trade that for one TSA request per interval.
+//!
+//! The token goes into `tsa_tokens` and each batch's place in the tree into
+//! `tsa_leaves`, in one transaction, and `GET /batches/:id/tsa` serves the
+//! two as a [`TsaStamp`]. Leaves keep their hash, so a stamp still proves
+//! itself once its batch is archived.
+//!
+//! The task only reads `batches`, so a slow, failing or refusing TSA never
+//! holds up a submit: the batches wait for the next round, and each failure
+//! counts in `logchain_tsa_failures_total`.
+
+use crate::metrics::Metrics;
+use crate::{AppState, now_unix_ms};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use common::hex::hex_encode;
+use common::summary::{merkle_path, merkle_root};
+use common::tsa::{self, TsaStamp, TstInfo};
+use sqlx::{Row, SqlitePool};
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_BATCHES: usize = 1000;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct TsaConfig {
+    pub url: String,
+    pub interval: Duration,
+    pub max_batches: usize,
+    /// `TSA_TIMEOUT_SECS`: how long one TSA request may take.
+    pub timeout: Duration,
+}
+
+impl TsaConfig {
+    /// `None` without `TSA_URL`.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(url) = env::var("TSA_URL") else {
+            return Ok(None);
+        };
+        let positive = |name: &str| match env::var(name) {
+            Ok(value) => value
+                .parse::<u64>()
+                .ok()
+                .filter(|&n| n > 0)
+                .map(Some)
+                .ok_or_else(|| format!("{name} must be a positive number, got '{value}'")),
+            Err(_) => Ok(None),
+        };
+        Ok(Some(Self {
+            url,
+            interval: positive("TSA_INTERVAL_SECS")?.map_or(DEFAULT_INTERVAL, Duration::from_secs),
+            max_batches: positive("TSA_MAX_BATCHES")?.map_or(DEFAULT_MAX_BATCHES, |n| n as usize),
+            timeout: positive("TSA_TIMEOUT_SECS")?.map_or(DEFAULT_TIMEOUT, Duration::from_secs),
+        }))
+    }
+}
+
+/// Stamps the batches stored since the last stamp, up to
+/// `config.max_batches`. Returns how many were stamped.
+pub async fn stamp_pending(
+    pool: &SqlitePool,
+    client: &reqwest::Client,
+    config: &TsaConfig,
+) -> Result<usize, String> {
+    let rows = sqlx::query(
+        "SELECT id, hash FROM batches \
+         WHERE id > (SELECT COALESCE(MAX(batch_id), 0) FROM tsa_leaves) ORDER BY id LIMIT ?1",
+    )
+    .bind(config.max_batches as i64)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| err.to_string())?;
+    if rows.is_empty() {
+        return Ok(0);
+    }
+    let ids: Vec<i64> = rows.iter().map(|row| row.get("id")).collect();
+    let hashes = rows
+        .iter()
+        .map(|row| <[u8; 32]>::try_from(row.get::<Vec<u8>, _>("hash")))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| "batch hash is not 32 bytes".to_string())?;
+
+    let root = merkle_root(&hashes);
+    let imprint = tsa::imprint(&root);
+    let nonce = rand::random::<u64>();
+    let response = client
+        .post(&config.url)
+        .header("content-type", "application/timestamp-query")
+        .timeout(config.timeout)
+        .body(tsa::request(&imprint, nonce))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| format!("TSA request failed: {err}"))?
+        .bytes()
+        .await
+        .map_err(|err| format!("TSA response unreadable: {err}"))?;
+    let token = tsa::token_from_response(&response)?;
+    let info = TstInfo::from_token(&token)?;
+    if info.imprint != imprint {
+        return Err("TSA token imprints another hash".into());
+    }
+    if info.nonce != Some(nonce) {
+        return Err("TSA token does not echo the request nonce".into());
+    }
+
+    let mut tx = pool.begin().await.map_err(|err| err.to_string())?;
+    let token_id: i64 = sqlx::query_scalar(
+        "INSERT INTO tsa_tokens (tsa_url, root, leaf_count, token, gen_time, stamped_at_ms) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6) RETURNING id",
+    )
+    .bind(&config.url)
+    .bind(root.to_vec())
+    .bind(hashes.len() as i64)
+    .bind(&token)
+    .bind(&info.gen_time)
+    .bind(now_unix_ms())
+    .fetch_one(tx.as_mut())
+    .await
+    .map_err(|err| err.to_string())?;
+    for (index, (id, hash)) in ids.iter().zip(&hashes).enumerate() {
+        sqlx::query(
+            "INSERT INTO tsa_leaves (batch_id, token_id, leaf_index, hash) VALUES (?1, ?2, ?3, ?4)",
+        )
+        .bind(id)
+        .bind(token_id)
+        .bind(index as i64)
+        .bind(hash.to_vec())
+        .execute(tx.as_mut())
+        .await
+        .map_err(|err| err.to_string())?;
+    }
+    tx.commit().await.map_err(|err| err.to_string())?;
+    Ok(hashes.len())
+}
+
+/// The periodic stamping task. A full round is followed straight away by
+/// the next, so a backlog drains without waiting out the interval.
+pub async fn run(pool: SqlitePool, metrics: Arc<Metrics>, config: TsaConfig) {
+    let client = reqwest::Client::new();
+    let mut ticker = tokio::time::interval(config.interval);
+    loop {
+        ticker.tick().await;
+        loop {
+            let started = Instant::now();
+            match stamp_pending(&pool, &client, &config).await {
+                Ok(0) => break,
+                Ok(stamped) => {
+                    metrics.inc("logchain_tsa_tokens_total");
+                    metrics.add("logchain_tsa_stamped_batches_total", stamped as u64);
+                    metrics.add(
+                        "logchain_tsa_latency_ms_total",
+                        started.elapsed().as_millis() as u64,
+                    );
+                    if stamped < config.max_batches {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    metrics.inc("logchain_tsa_failures_total");
+                    eprintln!("[tsa] {err}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// A batch's stamp, with the audit path rebuilt from the token's leaves.
+pub async fn stamp_of(pool: &SqlitePool, batch_id: i64) -> Result<Option<TsaStamp>, sqlx::Error> {
+    let Some(token) = sqlx::query(
+        "SELECT t.id, t.tsa_url, t.root, t.leaf_count, t.token, t.gen_time, l.leaf_index \
+         FROM tsa_leaves l JOIN tsa_tokens t ON t.id = l.token_id WHERE l.batch_id = ?1",
+    )
+    .bind(batch_id)
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let token_id: i64 = token.get("id");
+    let leaves: Vec<Vec<u8>> =
+        sqlx::query_scalar("SELECT hash FROM tsa_leaves WHERE token_id = ?1 ORDER BY leaf_index")
+            .bind(token_id)
+            .fetch_all(pool)
+            .await?;
+    let hash32 = |bytes: Vec<u8>| {
+        <[u8; 32]>::try_from(bytes).map_err(|_| sqlx::Error::Decode("hash is not 32 bytes".into()))
+    };
+    let hashes = leaves
+        .into_iter()
+        .map(hash32)
+        .collect::<Result<Vec<_>, _>>()?;
+    let leaf_index = token.get::<i64, _>("leaf_index") as usize;
+    let leaf_count = token.get::<i64, _>("leaf_count") as usize;
+    if hashes.len() != leaf_count || leaf_index >= leaf_count {
+        return Err(sqlx::Error::Decode(
+            format!("token {token_id} has missing leaves").into(),
+        ));
+    }
+    Ok(Some(TsaStamp {
+        batch_id,
+        hash: hashes[leaf_index],
+        token_id,
+        leaf_index,
+        leaf_count,
+        path: merkle_path(&hashes, leaf_index),
+        root: hash32(token.get("root"))?,
+        gen_time: token.get("gen_time"),
+        tsa_url: token.get("tsa_url"),
+        token: hex_encode(&token.get::<Vec<u8>, _>("token")),
+    }))
+}
+
+/// `GET /batches/:id/tsa`. 404 for an unknown batch and for one not
+/// stamped (yet, or at all when `TSA_URL` is unset).
+pub async fn handler_get_tsa(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<TsaStamp>, StatusCode> {
+    stamp_of(&state.pool, id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
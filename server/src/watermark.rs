@@ -0,0 +1,238 @@
+//! Rollback detection: with `WATERMARK_PATH` set, the server keeps each
+//! agent's high-water mark (the latest stored batch id and `received_at_ms`)
+//! in a small JSON file of its own. Keep it outside the database's directory
+//! and whatever backs up or snapshots it: the point is that restoring an old
+//! copy of the database does not restore an old copy of the file.
+//!
+//! At startup every mark is compared with the database. A database behind
+//! any mark looks like a rollback, which would let agents re-submit seqs the
+//! server already acknowledged and hand out the same ids and `received_at`
+//! again. The server then starts refusing submits (503 `rollback_suspected`)
+//! and reports the agents behind on `/readyz` and `/metrics`; reads keep
+//! working for the investigation. A legitimate restore is acknowledged with
+//! `--accept-rollback` (or `ACCEPT_ROLLBACK=1`), which records the
+//! discrepancy in `maintenance_events` and moves the marks back to the
+//! database.
+//!
+//! Marks advance in memory with every stored batch and reach the file every
+//! `WATERMARK_FLUSH_SECS`, so batches stored within that window before a
+//! restore can go unnoticed.
+
+use crate::{AppState, RECEIVED_AT_MS_EXPR, now_unix};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+pub const DEFAULT_FLUSH_SECS: u64 = 1;
+
+/// The newest batch the server has stored for an agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mark {
+    pub id: i64,
+    pub received_at_ms: i64,
+}
+
+impl Mark {
+    fn max(self, other: Mark) -> Mark {
+        Mark {
+            id: self.id.max(other.id),
+            received_at_ms: self.received_at_ms.max(other.received_at_ms),
+        }
+    }
+}
+
+/// An agent whose newest batch in the database is older than its mark;
+/// `database` is `None` when the database holds none of its batches.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Behind {
+    pub agent_id: String,
+    pub watermark: Mark,
+    pub database: Option<Mark>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct WatermarkFile {
+    agents: BTreeMap<String, Mark>,
+}
+
+pub struct Watermarks {
+    path: PathBuf,
+    marks: Mutex<BTreeMap<String, Mark>>,
+    dirty: AtomicBool,
+    /// Fixed at startup: non-empty means submits are refused.
+    behind: Vec<Behind>,
+}
+
+impl Watermarks {
+    /// Loads the marks at `path` (none yet if the file is missing) and checks
+    /// the database against them. With `accept_rollback`, a database behind
+    /// is recorded as a `rollback_accepted` maintenance event and the marks
+    /// follow it; otherwise the agents behind are kept for [`Self::behind`].
+    pub async fn open(
+        path: PathBuf,
+        pool: &SqlitePool,
+        accept_rollback: bool,
+    ) -> Result<Self, String> {
+        let mut marks = read_file(&path)?;
+        let database = database_marks(pool)
+            .await
+            .map_err(|err| format!("failed to read the database's marks: {err}"))?;
+        let mut behind = compare(&marks, &database);
+
+        if !behind.is_empty() && accept_rollback {
+            let detail = serde_json::to_string(&behind).expect("marks serialize");
+            sqlx::query(
+                "INSERT INTO maintenance_events (kind, table_name, rows, detail, created_at) \
+                 VALUES ('rollback_accepted', 'batches', ?1, ?2, ?3)",
+            )
+            .bind(behind.len() as i64)
+            .bind(&detail)
+            .bind(now_unix())
+            .execute(pool)
+            .await
+            .map_err(|err| format!("failed to record the accepted rollback: {err}"))?;
+            eprintln!(
+                "[watermark] --accept-rollback: database behind {} for {} agent(s), accepted: {detail}",
+                path.display(),
+                behind.len()
+            );
+            for entry in behind.drain(..) {
+                match entry.database {
+                    Some(mark) => marks.insert(entry.agent_id, mark),
+                    None => marks.remove(&entry.agent_id),
+                };
+            }
+        }
+        if behind.is_empty() {
+            // Agents the file has not seen yet, or that it saw before a crash
+            // lost the last flush, start from the database.
+            for (agent, mark) in database {
+                let merged = marks.get(&agent).map_or(mark, |known| known.max(mark));
+                marks.insert(agent, merged);
+            }
+        }
+
+        let watermarks = Self {
+            path,
+            marks: Mutex::new(marks),
+            dirty: AtomicBool::new(true),
+            behind,
+        };
+        if watermarks.behind.is_empty() {
+            watermarks
+                .flush()
+                .map_err(|err| format!("failed to write {}: {err}", watermarks.path.display()))?;
+        }
+        Ok(watermarks)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Agents the database was found behind at startup.
+    pub fn behind(&self) -> &[Behind] {
+        &self.behind
+    }
+
+    /// A batch was stored for `agent`.
+    pub fn advance(&self, agent: &str, mark: Mark) {
+        let mut marks = self.marks.lock().unwrap();
+        let merged = marks.get(agent).map_or(mark, |known| known.max(mark));
+        marks.insert(agent.to_string(), merged);
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Writes the marks through a synced temporary file if they moved. Never
+    /// while a rollback is suspected, so the file keeps the evidence.
+    pub fn flush(&self) -> io::Result<()> {
+        if !self.behind.is_empty() || !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let file = WatermarkFile {
+            agents: self.marks.lock().unwrap().clone(),
+        };
+        let json = serde_json::to_vec_pretty(&file).expect("marks serialize");
+        let tmp = self.path.with_extension("tmp");
+        let written = fs::File::create(&tmp).and_then(|mut out| {
+            out.write_all(&json)?;
+            out.sync_all()
+        });
+        if let Err(err) = written.and_then(|_| fs::rename(&tmp, &self.path)) {
+            self.dirty.store(true, Ordering::Release);
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+fn read_file(path: &Path) -> Result<BTreeMap<String, Mark>, String> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice::<WatermarkFile>(&bytes)
+            .map(|file| file.agents)
+            .map_err(|err| format!("unreadable watermark file {}: {err}", path.display())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(err) => Err(format!("failed to read {}: {err}", path.display())),
+    }
+}
+
+async fn database_marks(pool: &SqlitePool) -> Result<BTreeMap<String, Mark>, sqlx::Error> {
+    let rows: Vec<(String, i64, i64)> = sqlx::query_as(&format!(
+        "SELECT agent_id, MAX(id), MAX({RECEIVED_AT_MS_EXPR}) FROM batches GROUP BY agent_id"
+    ))
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(agent, id, received_at_ms)| (agent, Mark { id, received_at_ms }))
+        .collect())
+}
+
+fn compare(marks: &BTreeMap<String, Mark>, database: &BTreeMap<String, Mark>) -> Vec<Behind> {
+    marks
+        .iter()
+        .filter_map(|(agent, &watermark)| {
+            let database = database.get(agent).copied();
+            let behind = database.is_none_or(|db| {
+                db.id < watermark.id || db.received_at_ms < watermark.received_at_ms
+            });
+            behind.then(|| Behind {
+                agent_id: agent.clone(),
+                watermark,
+                database,
+            })
+        })
+        .collect()
+}
+
+/// Flushes the marks every `interval`.
+pub async fn run(watermarks: std::sync::Arc<Watermarks>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(err) = watermarks.flush() {
+            eprintln!(
+                "[watermark] failed to write {}: {err}",
+                watermarks.path().display()
+            );
+        }
+    }
+}
+
+/// `/metrics` lines for the startup check; nothing without `WATERMARK_PATH`.
+pub fn render_metrics(state: &AppState) -> String {
+    let Some(watermarks) = &state.watermarks else {
+        return String::new();
+    };
+    let behind = watermarks.behind().len();
+    format!(
+        "logchain_rollback_suspected {}\nlogchain_rollback_agents_behind {behind}\n",
+        u8::from(behind > 0)
+    )
+}